@@ -16,7 +16,8 @@ pub struct DeferredRateLimiter<K>
 where
     K: Send + Sync,
 {
-    local_cache: Cache<K, Arc<AtomicU64>, hashbrown::hash_map::DefaultHashBuilder>,
+    /// counts by key and period number. a new period starts a new count even if the old one hasn't expired
+    local_cache: Cache<(K, u64), Arc<AtomicU64>, hashbrown::hash_map::DefaultHashBuilder>,
     prefix: String,
     rrl: RedisRateLimiter,
     /// if None, defers to the max on rrl
@@ -80,6 +81,9 @@ where
 
         let redis_key = format!("{}:{}", self.prefix, key);
 
+        // the ttl alone would let a count outlive its period. the clock decides when a period is over
+        let period_number = virtual_clock::unix_now().as_secs() / (self.rrl.period as u64).max(1);
+
         // TODO: i'm sure this could be a lot better. but race conditions make this hard to think through. brain needs sleep
         let local_key_count: Arc<AtomicU64> = {
             // clone things outside of the `async move`
@@ -89,7 +93,7 @@ where
 
             // set arc_deferred_rate_limit_result and return the coun
             self.local_cache
-                .get_with(key, async move {
+                .get_with((key, period_number), async move {
                     // we do not use the try operator here because we want to be okay with redis errors
                     let redis_count = match rrl
                         .throttle_label(&redis_key, Some(max_requests_per_period), count)
@@ -209,3 +213,51 @@ where
         }
     }
 }
+
+#[cfg(all(test, feature = "test-clock"))]
+mod tests {
+    use super::*;
+    use redis_rate_limiter::{DeadpoolRuntime, RedisConfig};
+
+    #[tokio::test]
+    async fn test_period_rollover() {
+        virtual_clock::pause();
+
+        // nothing listens here. the local counts are all there is
+        let pool = RedisConfig::from_url("redis://127.0.0.1:1")
+            .create_pool(Some(DeadpoolRuntime::Tokio1))
+            .unwrap();
+
+        let rrl = RedisRateLimiter::new("test", "test", 10, 60.0, pool);
+
+        let limiter = DeferredRateLimiter::<u64>::new(100, "test", rrl, None);
+
+        let mut allowed = 0;
+        let retry_at = loop {
+            match limiter.throttle(1, Some(5), 1).await.unwrap() {
+                DeferredRateLimitResult::Allowed => allowed += 1,
+                DeferredRateLimitResult::RetryAt(x) => break x,
+                DeferredRateLimitResult::RetryNever => unreachable!(),
+            }
+
+            assert!(allowed <= 10, "the limit was never hit");
+        };
+
+        // virtual time starts at the top of a minute
+        assert!(retry_at > Instant::now() + Duration::from_secs(59));
+
+        // still limited until the period is over
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(matches!(
+            limiter.throttle(1, Some(5), 1).await.unwrap(),
+            DeferredRateLimitResult::RetryAt(_)
+        ));
+
+        // a new period starts a new count
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(matches!(
+            limiter.throttle(1, Some(5), 1).await.unwrap(),
+            DeferredRateLimitResult::Allowed
+        ));
+    }
+}
@@ -1,17 +1,15 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
-use super::sea_orm_active_enums::Role;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
-#[sea_orm(table_name = "secondary_user")]
+#[sea_orm(table_name = "admin")]
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: u64,
+    #[sea_orm(unique)]
     pub user_id: u64,
-    pub description: Option<String>,
-    pub role: Role,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -2,13 +2,19 @@
 
 pub mod prelude;
 
+pub mod admin;
+pub mod audit_log;
+pub mod deposit;
 pub mod login;
+pub mod org;
+pub mod org_member;
 pub mod pending_login;
+pub mod private_tx;
 pub mod revert_log;
 pub mod rpc_accounting;
+pub mod rpc_accounting_rollup;
 pub mod rpc_key;
 pub mod sea_orm_active_enums;
-pub mod secondary_user;
 pub mod serialization;
 pub mod user;
 pub mod user_tier;
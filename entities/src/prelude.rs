@@ -1,10 +1,16 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.5
 
+pub use super::admin::Entity as Admin;
+pub use super::audit_log::Entity as AuditLog;
+pub use super::deposit::Entity as Deposit;
 pub use super::login::Entity as Login;
+pub use super::org::Entity as Org;
+pub use super::org_member::Entity as OrgMember;
 pub use super::pending_login::Entity as PendingLogin;
+pub use super::private_tx::Entity as PrivateTx;
 pub use super::revert_log::Entity as RevertLog;
 pub use super::rpc_accounting::Entity as RpcAccounting;
+pub use super::rpc_accounting_rollup::Entity as RpcAccountingRollup;
 pub use super::rpc_key::Entity as RpcKey;
-pub use super::secondary_user::Entity as SecondaryUser;
 pub use super::user::Entity as User;
 pub use super::user_tier::Entity as UserTier;
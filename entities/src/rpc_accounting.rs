@@ -40,6 +40,8 @@ pub struct Model {
     pub max_response_bytes: u64,
     pub archive_request: bool,
     pub origin: Option<String>,
+    pub websocket_request: bool,
+    pub sum_compute_units: u64,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -27,6 +27,24 @@ pub struct Model {
     pub allowed_user_agents: Option<String>,
     pub log_revert_chance: f64,
     pub log_level: LogLevel,
+    pub max_requests_per_period: Option<u64>,
+    pub max_concurrent_requests: Option<u32>,
+    pub limits_expire_at: Option<DateTimeUtc>,
+    pub last_used_at: Option<DateTimeUtc>,
+    pub last_used_ip: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub allowed_methods: Option<String>,
+    pub cache_latest: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub method_allowlist: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub method_denylist: Option<String>,
+    pub rebroadcast_blocks: Option<u32>,
+    pub pin_ips: Option<u32>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub pinned_ips: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub alert_webhook_url: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -3,9 +3,12 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize,
+)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "log_level")]
 pub enum LogLevel {
+    #[default]
     #[sea_orm(string_value = "none")]
     None,
     #[sea_orm(string_value = "aggregated")]
@@ -14,12 +17,6 @@ pub enum LogLevel {
     Detailed,
 }
 
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "method")]
 pub enum Method {
@@ -37,6 +34,6 @@ pub enum Role {
     Owner,
     #[sea_orm(string_value = "admin")]
     Admin,
-    #[sea_orm(string_value = "collaborator")]
-    Collaborator,
+    #[sea_orm(string_value = "viewer")]
+    Viewer,
 }
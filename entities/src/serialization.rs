@@ -1,5 +1,5 @@
 //! sea-orm types don't always serialize how we want. this helps that, though it won't help every case.
-use ethers::prelude::Address;
+use ethers::prelude::{Address, H256};
 use sea_orm::prelude::Uuid;
 use serde::{Serialize, Serializer};
 use std::convert::TryInto;
@@ -19,6 +19,15 @@ where
     x.serialize(s)
 }
 
+pub fn vec_as_h256<S>(x: &[u8], s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let x = H256::from_slice(x);
+
+    x.serialize(s)
+}
+
 pub fn uuid_as_ulid<S>(x: &Uuid, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
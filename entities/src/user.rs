@@ -15,16 +15,25 @@ pub struct Model {
     pub description: Option<String>,
     pub email: Option<String>,
     pub user_tier_id: u64,
+    pub balance: i64,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_one = "super::admin::Entity")]
+    Admin,
+    #[sea_orm(has_many = "super::audit_log::Entity")]
+    AuditLog,
+    #[sea_orm(has_many = "super::deposit::Entity")]
+    Deposit,
     #[sea_orm(has_many = "super::login::Entity")]
     Login,
+    #[sea_orm(has_one = "super::org::Entity")]
+    Org,
+    #[sea_orm(has_many = "super::org_member::Entity")]
+    OrgMember,
     #[sea_orm(has_many = "super::rpc_key::Entity")]
     RpcKey,
-    #[sea_orm(has_many = "super::secondary_user::Entity")]
-    SecondaryUser,
     #[sea_orm(
         belongs_to = "super::user_tier::Entity",
         from = "Column::UserTierId",
@@ -35,21 +44,45 @@ pub enum Relation {
     UserTier,
 }
 
+impl Related<super::admin::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Admin.def()
+    }
+}
+
+impl Related<super::audit_log::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AuditLog.def()
+    }
+}
+
+impl Related<super::deposit::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Deposit.def()
+    }
+}
+
 impl Related<super::login::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Login.def()
     }
 }
 
-impl Related<super::rpc_key::Entity> for Entity {
+impl Related<super::org::Entity> for Entity {
     fn to() -> RelationDef {
-        Relation::RpcKey.def()
+        Relation::Org.def()
+    }
+}
+
+impl Related<super::org_member::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::OrgMember.def()
     }
 }
 
-impl Related<super::secondary_user::Entity> for Entity {
+impl Related<super::rpc_key::Entity> for Entity {
     fn to() -> RelationDef {
-        Relation::SecondaryUser.def()
+        Relation::RpcKey.def()
     }
 }
 
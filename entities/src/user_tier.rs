@@ -11,6 +11,8 @@ pub struct Model {
     pub title: String,
     pub max_requests_per_period: Option<u64>,
     pub max_concurrent_requests: Option<u32>,
+    pub max_account_requests_per_period: Option<u64>,
+    pub max_account_bytes_per_period: Option<u64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
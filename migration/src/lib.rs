@@ -12,6 +12,22 @@ mod m20221101_222349_archive_request;
 mod m20221108_200345_save_anon_stats;
 mod m20221211_124002_request_method_privacy;
 mod m20221213_134158_move_login_into_database;
+mod m20221214_181533_rpc_key_limit_overrides;
+mod m20221215_092113_rpc_key_last_used;
+mod m20221215_203507_account_limits;
+mod m20221216_141505_orgs;
+mod m20221216_210127_audit_log;
+mod m20221217_093321_private_txs;
+mod m20221218_102341_rpc_key_allowed_methods;
+mod m20221218_155802_bandwidth_limits;
+mod m20221219_091537_websocket_stats;
+mod m20221219_160214_rpc_key_cache_latest;
+mod m20221220_084512_compute_units;
+mod m20221220_103107_rpc_key_method_lists;
+mod m20221220_152207_rpc_key_rebroadcast;
+mod m20221220_181016_rpc_accounting_rollups;
+mod m20221221_101530_rpc_key_ip_pinning;
+mod m20221222_093012_credits;
 
 pub struct Migrator;
 
@@ -31,6 +47,22 @@ impl MigratorTrait for Migrator {
             Box::new(m20221108_200345_save_anon_stats::Migration),
             Box::new(m20221211_124002_request_method_privacy::Migration),
             Box::new(m20221213_134158_move_login_into_database::Migration),
+            Box::new(m20221214_181533_rpc_key_limit_overrides::Migration),
+            Box::new(m20221215_092113_rpc_key_last_used::Migration),
+            Box::new(m20221215_203507_account_limits::Migration),
+            Box::new(m20221216_141505_orgs::Migration),
+            Box::new(m20221216_210127_audit_log::Migration),
+            Box::new(m20221217_093321_private_txs::Migration),
+            Box::new(m20221218_102341_rpc_key_allowed_methods::Migration),
+            Box::new(m20221218_155802_bandwidth_limits::Migration),
+            Box::new(m20221219_091537_websocket_stats::Migration),
+            Box::new(m20221219_160214_rpc_key_cache_latest::Migration),
+            Box::new(m20221220_084512_compute_units::Migration),
+            Box::new(m20221220_103107_rpc_key_method_lists::Migration),
+            Box::new(m20221220_152207_rpc_key_rebroadcast::Migration),
+            Box::new(m20221220_181016_rpc_accounting_rollups::Migration),
+            Box::new(m20221221_101530_rpc_key_ip_pinning::Migration),
+            Box::new(m20221222_093012_credits::Migration),
         ]
     }
 }
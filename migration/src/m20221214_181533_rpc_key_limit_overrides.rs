@@ -0,0 +1,94 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // admins can set custom limits on keys (and eventually do other things)
+        manager
+            .create_table(
+                Table::create()
+                    .table(Admin::Table)
+                    .col(
+                        ColumnDef::new(Admin::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(Admin::UserId)
+                            .big_unsigned()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(Admin::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // null means the key inherits the limits from the user's tier
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::MaxRequestsPerPeriod).big_unsigned())
+                    .add_column(ColumnDef::new(RpcKey::MaxConcurrentRequests).unsigned())
+                    .add_column(ColumnDef::new(RpcKey::LimitsExpireAt).timestamp())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_column(RpcKey::MaxRequestsPerPeriod)
+                    .drop_column(RpcKey::MaxConcurrentRequests)
+                    .drop_column(RpcKey::LimitsExpireAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Admin::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Admin {
+    Table,
+    Id,
+    UserId,
+}
+
+/// partial table
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+/// partial table
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    MaxRequestsPerPeriod,
+    MaxConcurrentRequests,
+    LimitsExpireAt,
+}
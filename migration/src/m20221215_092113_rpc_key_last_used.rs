@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // null means the key has not been used since we started tracking
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::LastUsedAt).timestamp())
+                    .add_column(ColumnDef::new(RpcKey::LastUsedIp).string())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_column(RpcKey::LastUsedAt)
+                    .drop_column(RpcKey::LastUsedIp)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// partial table
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    LastUsedAt,
+    LastUsedIp,
+}
@@ -0,0 +1,53 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // a limit on the sum of requests from all of a user's keys
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .add_column(
+                        ColumnDef::new(UserTier::MaxAccountRequestsPerPeriod).big_unsigned(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // rate limits used to be shared by all of a user's keys. keep that behavior for the existing tiers
+        let update_tiers = Query::update()
+            .table(UserTier::Table)
+            .value(
+                UserTier::MaxAccountRequestsPerPeriod,
+                Expr::col(UserTier::MaxRequestsPerPeriod),
+            )
+            .to_owned();
+
+        manager.exec_stmt(update_tiers).await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .drop_column(UserTier::MaxAccountRequestsPerPeriod)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// partial table
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    MaxRequestsPerPeriod,
+    MaxAccountRequestsPerPeriod,
+}
@@ -0,0 +1,226 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // an org shares one user's keys, stats, tier, and balance with other logins
+        manager
+            .create_table(
+                Table::create()
+                    .table(Org::Table)
+                    .col(
+                        ColumnDef::new(Org::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Org::Name).string().not_null())
+                    .col(
+                        ColumnDef::new(Org::UserId)
+                            .big_unsigned()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(Org::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(OrgMember::Table)
+                    .col(
+                        ColumnDef::new(OrgMember::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(OrgMember::OrgId).big_unsigned().not_null())
+                    .col(ColumnDef::new(OrgMember::UserId).big_unsigned().not_null())
+                    .col(
+                        ColumnDef::new(OrgMember::Role)
+                            .enumeration(
+                                Alias::new("role"),
+                                [
+                                    Alias::new("owner"),
+                                    Alias::new("admin"),
+                                    Alias::new("viewer"),
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(OrgMember::OrgId)
+                            .col(OrgMember::UserId)
+                            .unique(),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(OrgMember::OrgId)
+                            .to_tbl(Org::Table)
+                            .to_col(Org::Id),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(OrgMember::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // org_member replaces secondary_user. every user with secondary users gets an org and they become its members
+        let db_conn = manager.get_connection();
+        let db_backend = manager.get_database_backend();
+
+        for sql in [
+            // secondary users log in with their own address now. give the ones without a login a user
+            "INSERT INTO user (address, email)
+            SELECT address, MAX(email) FROM secondary_user
+            WHERE address NOT IN (SELECT address FROM user)
+            GROUP BY address",
+            "INSERT INTO org (name, user_id)
+            SELECT DISTINCT CONCAT('user ', user_id), user_id FROM secondary_user",
+            // collaborators become viewers. an address listed twice keeps its highest role
+            "INSERT INTO org_member (org_id, user_id, role)
+            SELECT org.id, user.id, ELT(MIN(FIELD(secondary_user.role, 'owner', 'admin', 'collaborator')), 'owner', 'admin', 'viewer')
+            FROM secondary_user
+            JOIN org ON org.user_id = secondary_user.user_id
+            JOIN user ON user.address = secondary_user.address
+            WHERE user.id != secondary_user.user_id
+            GROUP BY org.id, user.id",
+        ] {
+            db_conn
+                .execute(Statement::from_string(db_backend, sql.to_string()))
+                .await?;
+        }
+
+        manager
+            .drop_table(Table::drop().table(SecondaryUser::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(SecondaryUser::Table)
+                    .col(
+                        ColumnDef::new(SecondaryUser::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(SecondaryUser::UserId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(SecondaryUser::Address)
+                            .binary_len(20)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(SecondaryUser::Description).string())
+                    .col(ColumnDef::new(SecondaryUser::Email).string())
+                    .col(
+                        ColumnDef::new(SecondaryUser::Role)
+                            .enumeration(
+                                Alias::new("role"),
+                                [
+                                    Alias::new("owner"),
+                                    Alias::new("admin"),
+                                    Alias::new("collaborator"),
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .index(sea_query::Index::create().col(SecondaryUser::Address))
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(SecondaryUser::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // members go back to being secondary users of the org's user. org names are lost
+        let db_conn = manager.get_connection();
+        let db_backend = manager.get_database_backend();
+
+        let sql = "INSERT INTO secondary_user (user_id, address, email, role)
+            SELECT org.user_id, user.address, user.email, IF(org_member.role = 'viewer', 'collaborator', org_member.role)
+            FROM org_member
+            JOIN org ON org.id = org_member.org_id
+            JOIN user ON user.id = org_member.user_id";
+
+        db_conn
+            .execute(Statement::from_string(db_backend, sql.to_string()))
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(OrgMember::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Org::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Org {
+    Table,
+    Id,
+    Name,
+    UserId,
+}
+
+#[derive(Iden)]
+enum OrgMember {
+    Table,
+    Id,
+    OrgId,
+    UserId,
+    Role,
+}
+
+/// partial table
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum SecondaryUser {
+    Table,
+    Id,
+    UserId,
+    Address,
+    Description,
+    Email,
+    Role,
+}
@@ -0,0 +1,71 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // every admin and self-serve mutation gets a row here
+        manager
+            .create_table(
+                Table::create()
+                    .table(AuditLog::Table)
+                    .col(
+                        ColumnDef::new(AuditLog::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AuditLog::Timestamp).timestamp().not_null())
+                    .col(ColumnDef::new(AuditLog::UserId).big_unsigned())
+                    .col(ColumnDef::new(AuditLog::Action).string().not_null())
+                    .col(ColumnDef::new(AuditLog::TargetType).string().not_null())
+                    .col(ColumnDef::new(AuditLog::TargetId).big_unsigned())
+                    .col(ColumnDef::new(AuditLog::Before).json())
+                    .col(ColumnDef::new(AuditLog::After).json())
+                    .index(sea_query::Index::create().col(AuditLog::Timestamp))
+                    .index(
+                        sea_query::Index::create()
+                            .col(AuditLog::TargetType)
+                            .col(AuditLog::TargetId),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(AuditLog::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AuditLog::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum AuditLog {
+    Table,
+    Id,
+    Timestamp,
+    UserId,
+    Action,
+    TargetType,
+    TargetId,
+    Before,
+    After,
+}
+
+/// partial table
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
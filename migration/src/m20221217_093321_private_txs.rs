@@ -0,0 +1,60 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // transactions sent to the private relays that have not been seen on chain yet
+        manager
+            .create_table(
+                Table::create()
+                    .table(PrivateTx::Table)
+                    .col(
+                        ColumnDef::new(PrivateTx::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(PrivateTx::ChainId).big_unsigned().not_null())
+                    .col(ColumnDef::new(PrivateTx::TxHash).binary_len(32).not_null())
+                    .col(ColumnDef::new(PrivateTx::RawTx).text().not_null())
+                    .col(ColumnDef::new(PrivateTx::Relays).json().not_null())
+                    .col(
+                        ColumnDef::new(PrivateTx::FirstSeenBlock)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PrivateTx::Timestamp).timestamp().not_null())
+                    .index(
+                        sea_query::Index::create()
+                            .col(PrivateTx::ChainId)
+                            .col(PrivateTx::TxHash)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PrivateTx::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum PrivateTx {
+    Table,
+    Id,
+    ChainId,
+    TxHash,
+    RawTx,
+    Relays,
+    FirstSeenBlock,
+    Timestamp,
+}
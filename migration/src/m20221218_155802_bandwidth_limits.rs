@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // a limit on the sum of request and response bytes from all of a user's keys
+        // request counts badly underprice large responses like traces
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .add_column(ColumnDef::new(UserTier::MaxAccountBytesPerPeriod).big_unsigned())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .drop_column(UserTier::MaxAccountBytesPerPeriod)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// partial table
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    MaxAccountBytesPerPeriod,
+}
@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // websocket users were blended into the http stats
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcAccounting::Table)
+                    .add_column(
+                        ColumnDef::new(RpcAccounting::WebsocketRequest)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcAccounting::Table)
+                    .drop_column(RpcAccounting::WebsocketRequest)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// partial table
+#[derive(Iden)]
+enum RpcAccounting {
+    Table,
+    WebsocketRequest,
+}
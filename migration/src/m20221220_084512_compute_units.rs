@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // methods can cost more than 1. frontend_requests alone can't be used for billing anymore
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcAccounting::Table)
+                    .add_column(
+                        ColumnDef::new(RpcAccounting::SumComputeUnits)
+                            .big_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcAccounting::Table)
+                    .drop_column(RpcAccounting::SumComputeUnits)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// partial table
+#[derive(Iden)]
+enum RpcAccounting {
+    Table,
+    SumComputeUnits,
+}
@@ -0,0 +1,186 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // rpc_accounting rolled into longer periods. percentiles can't be combined, so only sums and maxes are kept
+        manager
+            .create_table(
+                Table::create()
+                    .table(RpcAccountingRollup::Table)
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(RpcAccountingRollup::RpcKeyId).big_unsigned())
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::ChainId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::PeriodSeconds)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::PeriodDatetime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(RpcAccountingRollup::Method).string())
+                    .col(ColumnDef::new(RpcAccountingRollup::Origin).string())
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::ErrorResponse)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::ArchiveRequest)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::WebsocketRequest)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::FrontendRequests)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::BackendRequests)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::CacheMisses)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::CacheHits)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::SumRequestBytes)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::MaxRequestBytes)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::SumResponseMillis)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::MaxResponseMillis)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::SumResponseBytes)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::MaxResponseBytes)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcAccountingRollup::SumComputeUnits)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(RpcAccountingRollup::ChainId)
+                            .col(RpcAccountingRollup::PeriodSeconds)
+                            .col(RpcAccountingRollup::PeriodDatetime),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(RpcAccountingRollup::RpcKeyId)
+                            .col(RpcAccountingRollup::PeriodDatetime),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // pruning and rolling up search raw stats by time
+        manager
+            .create_index(
+                sea_query::Index::create()
+                    .table(RpcAccounting::Table)
+                    .name("idx_rpc_accounting_chain_period")
+                    .col(RpcAccounting::ChainId)
+                    .col(RpcAccounting::PeriodDatetime)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                sea_query::Index::drop()
+                    .table(RpcAccounting::Table)
+                    .name("idx_rpc_accounting_chain_period")
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(RpcAccountingRollup::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcAccountingRollup {
+    Table,
+    Id,
+    RpcKeyId,
+    ChainId,
+    PeriodSeconds,
+    PeriodDatetime,
+    Method,
+    Origin,
+    ErrorResponse,
+    ArchiveRequest,
+    WebsocketRequest,
+    FrontendRequests,
+    BackendRequests,
+    CacheMisses,
+    CacheHits,
+    SumRequestBytes,
+    MaxRequestBytes,
+    SumResponseMillis,
+    MaxResponseMillis,
+    SumResponseBytes,
+    MaxResponseBytes,
+    SumComputeUnits,
+}
+
+/// partial table
+#[derive(Iden)]
+enum RpcAccounting {
+    Table,
+    ChainId,
+    PeriodDatetime,
+}
@@ -0,0 +1,45 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // keys can be pinned to the first few ips that use them. null does not pin
+        // pinned_ips is a comma separated list of the ips seen so far
+        // rejected ips are POSTed to alert_webhook_url
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::PinIps).unsigned())
+                    .add_column(ColumnDef::new(RpcKey::PinnedIps).text())
+                    .add_column(ColumnDef::new(RpcKey::AlertWebhookUrl).text())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_column(RpcKey::PinIps)
+                    .drop_column(RpcKey::PinnedIps)
+                    .drop_column(RpcKey::AlertWebhookUrl)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// partial table
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    PinIps,
+    PinnedIps,
+    AlertWebhookUrl,
+}
@@ -0,0 +1,114 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // prepaid credits. every key spends its user's balance
+        // signed because requests that are already in flight can take it a little below 0
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(
+                        ColumnDef::new(User::Balance)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // transfers to the deposit address that were credited to a user
+        // transfer_index is 0 for the transaction's value and log_index + 1 for token transfers
+        // the unique index keeps a transfer from being credited twice
+        manager
+            .create_table(
+                Table::create()
+                    .table(Deposit::Table)
+                    .col(
+                        ColumnDef::new(Deposit::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Deposit::ChainId).big_unsigned().not_null())
+                    .col(ColumnDef::new(Deposit::TxHash).binary_len(32).not_null())
+                    .col(
+                        ColumnDef::new(Deposit::TransferIndex)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Deposit::BlockNumber)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(Deposit::UserId).big_unsigned().not_null())
+                    // the zero address is the chain's native token
+                    .col(ColumnDef::new(Deposit::Token).binary_len(20).not_null())
+                    // a decimal string of the token's smallest unit
+                    .col(ColumnDef::new(Deposit::Amount).string().not_null())
+                    .col(ColumnDef::new(Deposit::Credits).big_integer().not_null())
+                    .col(ColumnDef::new(Deposit::Timestamp).timestamp().not_null())
+                    .index(
+                        sea_query::Index::create()
+                            .col(Deposit::ChainId)
+                            .col(Deposit::TxHash)
+                            .col(Deposit::TransferIndex)
+                            .unique(),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .from_col(Deposit::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Deposit::Table).to_owned())
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::Balance)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Deposit {
+    Table,
+    Id,
+    ChainId,
+    TxHash,
+    TransferIndex,
+    BlockNumber,
+    UserId,
+    Token,
+    Amount,
+    Credits,
+    Timestamp,
+}
+
+/// partial table
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    Balance,
+}
@@ -1,7 +1,6 @@
 //#![warn(missing_docs)]
 use anyhow::Context;
 use std::ops::Add;
-use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::{Duration, Instant};
 
 pub use deadpool_redis::redis;
@@ -46,22 +45,27 @@ impl RedisRateLimiter {
         }
     }
 
-    pub fn now_as_secs(&self) -> f32 {
+    /// f64 because an f32 can't tell apart the seconds of a unix timestamp
+    pub fn now_as_secs(&self) -> f64 {
         // TODO: if system time doesn't match redis, this won't work great
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("cannot tell the time")
-            .as_secs_f32()
+        virtual_clock::unix_now().as_secs_f64()
     }
 
-    pub fn period_id(&self, now_as_secs: f32) -> f32 {
-        (now_as_secs / self.period) % self.period
+    /// The period's number, wrapped to `0..period`. It is part of the redis key.
+    /// Older versions computed this with an f32 and no `floor`, so their keys changed every few seconds and
+    /// counts were lost within a period. Upgrading changes the keys once, so every count starts over.
+    pub fn period_id(&self, now_as_secs: f64) -> f64 {
+        let period = self.period as f64;
+
+        (now_as_secs / period).floor() % period
     }
 
-    pub fn next_period(&self, now_as_secs: f32) -> Instant {
-        let seconds_left_in_period = self.period - (now_as_secs % self.period);
+    pub fn next_period(&self, now_as_secs: f64) -> Instant {
+        let period = self.period as f64;
+
+        let seconds_left_in_period = period - (now_as_secs % period);
 
-        Instant::now().add(Duration::from_secs_f32(seconds_left_in_period))
+        Instant::now().add(Duration::from_secs_f64(seconds_left_in_period))
     }
 
     /// label might be an ip address or a rpc_key id.
@@ -127,3 +131,30 @@ impl RedisRateLimiter {
         self.throttle_label("", None, 1).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rrl(period: f32) -> RedisRateLimiter {
+        let pool = RedisConfig::from_url("redis://127.0.0.1:1")
+            .create_pool(Some(DeadpoolRuntime::Tokio1))
+            .unwrap();
+
+        RedisRateLimiter::new("test", "test", 10, period, pool)
+    }
+
+    #[tokio::test]
+    async fn test_period_id() {
+        let x = rrl(60.0);
+
+        // 2022-01-01 is minute 27_349_920
+        let start = 1_640_995_200.0;
+
+        assert_eq!(x.period_id(start), 0.0);
+        // the whole minute has the same id
+        assert_eq!(x.period_id(start + 59.9), 0.0);
+        assert_eq!(x.period_id(start + 60.0), 1.0);
+        assert_eq!(x.period_id(start + 61.0), 1.0);
+    }
+}
@@ -0,0 +1,98 @@
+//! The current time for anything time-based: rate limit periods, block ages, and expirations.
+//!
+//! With the `virtual` feature, time starts at a fixed moment and only moves with tokio's clock.
+//! Tests call `pause` and then `tokio::time::advance` to skip through rate limit periods, cooldowns,
+//! and health check intervals without sleeping. tokio's `Instant`, `sleep`, and `interval` follow the same clock.
+//!
+//! moka's cache TTLs use their own clock and still take real time.
+
+#[cfg(not(feature = "virtual"))]
+pub use real_time::*;
+
+#[cfg(feature = "virtual")]
+pub use virtual_time::*;
+
+#[cfg(not(feature = "virtual"))]
+mod real_time {
+    use chrono::{DateTime, Utc};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn unix_now() -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time machines don't exist")
+    }
+
+    pub fn utc_now() -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[cfg(feature = "virtual")]
+mod virtual_time {
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    /// 2022-01-01T00:00:00Z. Virtual time starts here so that every run sees the same times
+    pub const VIRTUAL_START: Duration = Duration::from_secs(1_640_995_200);
+
+    /// the tokio instant that is `VIRTUAL_START`
+    static START: Mutex<Option<Instant>> = Mutex::new(None);
+
+    /// Pause tokio's clock and restart virtual time at `VIRTUAL_START`. Call this at the top of each test.
+    /// Tests in one process share the clock, so run them with `--test-threads=1`.
+    pub fn pause() {
+        tokio::time::pause();
+
+        *START.lock().expect("clock lock poisoned") = Some(Instant::now());
+    }
+
+    pub fn unix_now() -> Duration {
+        let start = *START
+            .lock()
+            .expect("clock lock poisoned")
+            .get_or_insert_with(Instant::now);
+
+        VIRTUAL_START + Instant::now().saturating_duration_since(start)
+    }
+
+    pub fn utc_now() -> DateTime<Utc> {
+        let now = unix_now();
+
+        Utc.timestamp_opt(now.as_secs() as i64, now.subsec_nanos())
+            .single()
+            .expect("virtual time is always a valid timestamp")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[cfg(not(feature = "virtual"))]
+    #[test]
+    fn test_real_time() {
+        // after 2022-01-01
+        assert!(unix_now() > Duration::from_secs(1_640_995_200));
+        assert!((utc_now().timestamp() - unix_now().as_secs() as i64).abs() <= 1);
+    }
+
+    #[cfg(feature = "virtual")]
+    #[tokio::test]
+    async fn test_virtual_time() {
+        pause();
+
+        assert_eq!(unix_now(), VIRTUAL_START);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        assert_eq!(unix_now(), VIRTUAL_START + Duration::from_secs(60));
+        assert_eq!(
+            utc_now().timestamp(),
+            (VIRTUAL_START + Duration::from_secs(60)).as_secs() as i64
+        );
+    }
+}
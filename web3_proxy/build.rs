@@ -0,0 +1,40 @@
+//! Save information about the build so that `/status/version` can show exactly what is running.
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| x.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| x.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=WEB3_PROXY_GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=WEB3_PROXY_RUSTC_VERSION={}", rustc_version);
+    println!(
+        "cargo:rustc-env=WEB3_PROXY_BUILD_TIMESTAMP={}",
+        build_timestamp
+    );
+
+    // only rebuild the info when the commit changes
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+}
@@ -0,0 +1,17 @@
+//! Requests come straight from users. Parsing them must never panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use web3_proxy::jsonrpc::{JsonRpcRequest, JsonRpcRequestEnum};
+
+fuzz_target!(|data: &[u8]| {
+    // http requests
+    if let Ok(request) = serde_json::from_slice::<JsonRpcRequestEnum>(data) {
+        let _ = format!("{:?}", request);
+    }
+
+    // websocket requests
+    if let Ok(request) = serde_json::from_slice::<JsonRpcRequest>(data) {
+        let _ = request.num_bytes();
+    }
+});
@@ -0,0 +1,17 @@
+//! Responses come from backends that we do not control. Parsing them must never panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use web3_proxy::jsonrpc::JsonRpcForwardedResponse;
+use web3_proxy::rpcs::validation::validate_response;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = serde_json::from_slice::<JsonRpcForwardedResponse>(data) {
+        let _ = response.num_bytes();
+
+        if let Some(result) = response.result.as_ref() {
+            let _ = validate_response("eth_getBlockByNumber", result);
+            let _ = validate_response("eth_getLogs", result);
+        }
+    }
+});
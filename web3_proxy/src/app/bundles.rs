@@ -0,0 +1,164 @@
+//! Flashbots-style bundles (`eth_sendBundle`, `eth_callBundle`, `eth_cancelBundle`).
+//!
+//! Relays require every request to be signed in the `X-Flashbots-Signature` header.
+//! The proxy signs with its own key because re-serializing the body would break a client's signature.
+
+use super::private_txs::{RelayOutcome, RelayStatus};
+use super::Web3ProxyApp;
+use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::rpcs::connection::Web3Connection;
+use anyhow::Context;
+use ethers::prelude::{LocalWallet, Signer, H256};
+use ethers::utils::keccak256;
+use futures::future::join_all;
+use serde_json::json;
+use std::sync::Arc;
+
+/// `X-Flashbots-Signature` is "$address:$signature" where the signature is an EIP-191 signature of the body's hex keccak256.
+pub async fn flashbots_signature(signer: &LocalWallet, body: &[u8]) -> anyhow::Result<String> {
+    let body_hash = format!("{:?}", H256::from(keccak256(body)));
+
+    let signature = signer
+        .sign_message(body_hash)
+        .await
+        .context("signing bundle request")?;
+
+    Ok(format!("{:?}:0x{}", signer.address(), signature))
+}
+
+impl Web3ProxyApp {
+    /// Send a bundle request to the relays in the config's `bundles`.
+    /// `eth_callBundle` is a simulation so only the first relay to answer is used.
+    /// The other methods go to every relay and the outcomes are in the response's `data.relays`.
+    pub(super) async fn send_bundle_request(
+        &self,
+        request: &JsonRpcRequest,
+    ) -> anyhow::Result<JsonRpcForwardedResponse> {
+        let signer = self
+            .bundle_signer
+            .as_ref()
+            .context("bundles are not enabled on this proxy")?;
+
+        let relays = self.bundle_relays()?;
+
+        let body = serde_json::to_vec(request).context("serializing bundle request")?;
+
+        let signature = flashbots_signature(signer, &body).await?;
+
+        if request.method == "eth_callBundle" {
+            let mut last_err = None;
+
+            for relay in relays.iter() {
+                match send_to_relay(relay, &body, &signature, request).await {
+                    Ok(response) => return Ok(response),
+                    Err(err) => last_err = Some(err),
+                }
+            }
+
+            return Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no bundle relays")));
+        }
+
+        let results = join_all(
+            relays
+                .iter()
+                .map(|relay| send_to_relay(relay, &body, &signature, request)),
+        )
+        .await;
+
+        let mut accepted = None;
+        let mut rejected = None;
+        let mut outcomes = Vec::with_capacity(relays.len());
+
+        for (relay, result) in relays.iter().zip(results) {
+            let (status, error) = match result {
+                Ok(response) => match response.error.as_ref() {
+                    None => {
+                        accepted.get_or_insert(response);
+                        (RelayStatus::Accepted, None)
+                    }
+                    Some(err) => {
+                        let message = err.message.clone();
+                        rejected.get_or_insert(response);
+                        (RelayStatus::Rejected, Some(message))
+                    }
+                },
+                Err(err) => (RelayStatus::Failed, Some(format!("{:#}", err))),
+            };
+
+            outcomes.push(RelayOutcome {
+                relay: relay.name.clone(),
+                status,
+                error,
+            });
+        }
+
+        let mut response = accepted.or(rejected).unwrap_or_else(|| {
+            JsonRpcForwardedResponse::from_str(
+                "no bundle relays answered",
+                None,
+                Some(request.id.clone()),
+            )
+        });
+
+        response.add_data("relays", json!(outcomes));
+
+        Ok(response)
+    }
+
+    /// The http servers in `private_rpcs` that are named in the config's `bundles.relays`.
+    fn bundle_relays(&self) -> anyhow::Result<Vec<Arc<Web3Connection>>> {
+        let config = self
+            .config
+            .bundles
+            .as_ref()
+            .context("bundles are not enabled on this proxy")?;
+
+        let private_rpcs = self
+            .private_rpcs
+            .as_ref()
+            .context("bundles need private_rpcs")?;
+
+        let relays: Vec<_> = config
+            .relays
+            .iter()
+            .filter_map(|name| private_rpcs.get(name))
+            .filter(|x| x.http().is_some())
+            .collect();
+
+        if relays.is_empty() {
+            return Err(anyhow::anyhow!("no http bundle relays are available"));
+        }
+
+        Ok(relays)
+    }
+}
+
+/// POST the signed body directly. The provider can't add headers to a request
+/// TODO: this skips the relay's hard limit
+async fn send_to_relay(
+    relay: &Web3Connection,
+    body: &[u8],
+    signature: &str,
+    request: &JsonRpcRequest,
+) -> anyhow::Result<JsonRpcForwardedResponse> {
+    let (client, url) = relay.http().context("bundle relays must use http")?;
+
+    let mut response: JsonRpcForwardedResponse = client
+        .post(url)
+        .header("content-type", "application/json")
+        .header("X-Flashbots-Signature", signature)
+        .body(body.to_vec())
+        .send()
+        .await
+        .with_context(|| format!("sending bundle to {}", relay))?
+        .error_for_status()
+        .with_context(|| format!("bundle relay {} returned an error status", relay))?
+        .json()
+        .await
+        .with_context(|| format!("parsing bundle response from {}", relay))?;
+
+    // relays don't always echo the id
+    response.id = request.id.clone();
+
+    Ok(response)
+}
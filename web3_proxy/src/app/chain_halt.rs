@@ -0,0 +1,93 @@
+//! Safe mode for when the chain stops producing blocks.
+//!
+//! A halted chain looks healthy. Every server agrees on the same old head and reads get staler and staler.
+//! In safe mode, responses say how stale they are and transactions can be refused.
+
+use super::{AnyhowJoinHandle, Web3ProxyApp};
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+
+/// True if the head block is more than `missed_blocks` block times old.
+/// Block timestamps only have whole seconds, so a fresh block can look up to a second old
+fn is_halted(age: Duration, expected_block_time: Duration, missed_blocks: u64) -> bool {
+    let max_age_ms = expected_block_time.as_millis() as u64 * missed_blocks + 1_000;
+
+    age.as_millis() as u64 > max_age_ms
+}
+
+impl Web3ProxyApp {
+    /// The age in seconds of the consensus head if the chain looks halted.
+    /// None if blocks are arriving or if `chain_halt` is not configured.
+    pub fn chain_halted(&self) -> Option<u64> {
+        let config = self.config.chain_halt.as_ref()?;
+
+        let head_block = self.balanced_rpcs.head_block()?;
+
+        let age = head_block.lag_duration();
+
+        if is_halted(
+            age,
+            self.balanced_rpcs.expected_block_time,
+            config.missed_blocks,
+        ) {
+            Some(age.as_secs())
+        } else {
+            None
+        }
+    }
+
+    /// Alert when the chain halts and when it starts again.
+    pub fn spawn_chain_halt_watcher(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.chain_halt_watcher_loop())
+    }
+
+    async fn chain_halt_watcher_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = interval(Duration::from_secs(1));
+
+        let mut was_halted = false;
+
+        loop {
+            interval.tick().await;
+
+            let halted = self.chain_halted();
+
+            match (was_halted, halted) {
+                (false, Some(age)) => {
+                    error!(
+                        "chain halted! the head block is {} seconds old. entering safe mode",
+                        age
+                    );
+                }
+                (true, None) => {
+                    info!("chain is producing blocks again. leaving safe mode");
+                }
+                _ => {}
+            }
+
+            was_halted = halted.is_some();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_halted() {
+        let twelve_seconds = Duration::from_secs(12);
+
+        assert!(!is_halted(Duration::from_secs(13), twelve_seconds, 10));
+        assert!(is_halted(Duration::from_secs(130), twelve_seconds, 10));
+
+        // arbitrum makes a block every 500ms
+        let half_second = Duration::from_millis(500);
+
+        assert!(!is_halted(Duration::ZERO, half_second, 10));
+        assert!(!is_halted(Duration::from_secs(1), half_second, 10));
+        assert!(!is_halted(Duration::from_secs(5), half_second, 10));
+        assert!(is_halted(Duration::from_secs(7), half_second, 10));
+    }
+}
@@ -0,0 +1,761 @@
+//! Prepaid credits.
+//!
+//! Every key spends its user's balance. A request costs its `method_cost` and is refused once the balance is used up.
+//! Balances are kept in memory and the credits spent are saved to the database every `save_seconds`.
+//! Transfers to the `deposit_address` from a user's login address are credited once they are `confirmations` deep.
+
+use super::{AnyhowJoinHandle, Web3ProxyApp};
+use crate::config::CreditsConfig;
+use crate::external_auth::is_external_id;
+use crate::frontend::authorization::{Authorization, AuthorizationType};
+use crate::frontend::errors::FrontendErrorResponse;
+use crate::jsonrpc::JsonRpcRequest;
+use crate::rpcs::request::Traffic;
+use anyhow::Context;
+use chrono::Utc;
+use entities::{deposit, user};
+use ethers::prelude::{
+    Address, Block, Log, Transaction, TransactionReceipt, TxHash, H256, U256, U64,
+};
+use ethers::utils::keccak256;
+use hashbrown::{HashMap, HashSet};
+use http::StatusCode;
+use log::{error, info, warn};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+    TransactionTrait,
+};
+use migration::Expr;
+use moka::future::Cache;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::interval;
+
+/// Balances are loaded from the database again this often so that other proxies' spending shows up.
+const BALANCE_TTL_SECONDS: u64 = 60;
+
+/// The most blocks to search for deposits at once.
+const MAX_DEPOSIT_SCAN_BLOCKS: u64 = 100;
+
+/// Credit balances by user id.
+pub struct Balances {
+    cache: Cache<u64, Arc<AtomicI64>, hashbrown::hash_map::DefaultHashBuilder>,
+    /// credits spent since they were last saved
+    unsaved: Mutex<HashMap<u64, i64>>,
+    /// held while a balance is saved, deposited to, or loaded. otherwise a load between the database update and
+    /// the in-memory update would count the same credits twice.
+    /// always taken before the database touches the user's row so that the two locks can't deadlock
+    saving: AsyncMutex<()>,
+}
+
+impl Default for Balances {
+    fn default() -> Self {
+        Self {
+            cache: Cache::builder()
+                .time_to_live(Duration::from_secs(BALANCE_TTL_SECONDS))
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            unsaved: Default::default(),
+            saving: Default::default(),
+        }
+    }
+}
+
+impl Balances {
+    /// The cached balance. `load` reads the balance from the database on a miss.
+    async fn get_or_load<F>(&self, user_id: u64, load: F) -> anyhow::Result<Arc<AtomicI64>>
+    where
+        F: Future<Output = anyhow::Result<i64>>,
+    {
+        self.cache
+            .try_get_with(user_id, async move {
+                let _saving = self.saving.lock().await;
+
+                let balance = load.await?;
+
+                let unsaved = self
+                    .unsaved
+                    .lock()
+                    .get(&user_id)
+                    .copied()
+                    .unwrap_or_default();
+
+                Ok::<_, anyhow::Error>(Arc::new(AtomicI64::new(balance - unsaved)))
+            })
+            .await
+            .map_err(|err| anyhow::anyhow!(err))
+    }
+
+    fn spend(&self, user_id: u64, balance: &AtomicI64, credits: i64) {
+        // requests already in flight can take the balance a little below 0
+        balance.fetch_sub(credits, Ordering::AcqRel);
+
+        *self.unsaved.lock().entry(user_id).or_default() += credits;
+    }
+
+    /// `save` subtracts the spent credits in the database.
+    async fn save_spent<F>(&self, user_id: u64, credits: i64, save: F) -> anyhow::Result<()>
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let _saving = self.saving.lock().await;
+
+        save.await?;
+
+        // more may have been spent while this was saving
+        let mut unsaved = self.unsaved.lock();
+
+        if let Some(x) = unsaved.get_mut(&user_id) {
+            *x -= credits;
+
+            if *x == 0 {
+                unsaved.remove(&user_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `deposit` adds the credits in a database transaction. It returns None if they were already added.
+    async fn deposit<F, T>(
+        &self,
+        user_id: u64,
+        credits: i64,
+        deposit: F,
+    ) -> anyhow::Result<Option<T>>
+    where
+        F: Future<Output = anyhow::Result<Option<T>>>,
+    {
+        let _saving = self.saving.lock().await;
+
+        let deposited = deposit.await?;
+
+        if deposited.is_some() {
+            if let Some(balance) = self.cache.get(&user_id) {
+                balance.fetch_add(credits, Ordering::AcqRel);
+            }
+        }
+
+        Ok(deposited)
+    }
+}
+
+/// A transfer to the deposit address.
+#[derive(Debug, PartialEq)]
+struct DepositTransfer {
+    /// 0 for the transaction's value. log_index + 1 for token transfers
+    index: u64,
+    sender: Address,
+    /// the zero address is the native token
+    token: Address,
+    amount: U256,
+    credits: i64,
+}
+
+fn erc20_transfer_topic() -> H256 {
+    H256::from(keccak256("Transfer(address,address,uint256)"))
+}
+
+/// Whole credits for `amount` of a token's smallest unit. Fractions of a credit are dropped.
+fn credits_for(amount: U256, decimals: u8, credits_per_token: f64) -> i64 {
+    let amount: f64 = amount.to_string().parse().unwrap_or(f64::MAX);
+
+    (amount / 10f64.powi(decimals.into()) * credits_per_token) as i64
+}
+
+/// Transfers to the deposit address in a successful transaction.
+fn deposit_transfers(
+    config: &CreditsConfig,
+    tx: &Transaction,
+    receipt: &TransactionReceipt,
+) -> Vec<DepositTransfer> {
+    if receipt.status != Some(U64::one()) {
+        return vec![];
+    }
+
+    let mut transfers = vec![];
+
+    if let Some(credits_per_token) = config.credits_per_native_token {
+        if tx.to == Some(config.deposit_address) && !tx.value.is_zero() {
+            transfers.push(DepositTransfer {
+                index: 0,
+                sender: tx.from,
+                token: Address::zero(),
+                amount: tx.value,
+                credits: credits_for(tx.value, 18, credits_per_token),
+            });
+        }
+    }
+
+    let transfer_topic = erc20_transfer_topic();
+    let deposit_topic = H256::from(config.deposit_address);
+
+    for log in receipt.logs.iter() {
+        let token = match config.tokens.get(&log.address) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        // Transfer(address indexed from, address indexed to, uint256 value)
+        if log.topics.len() != 3
+            || log.topics[0] != transfer_topic
+            || log.topics[2] != deposit_topic
+            || log.data.len() != 32
+        {
+            continue;
+        }
+
+        let log_index = match log.log_index {
+            Some(x) => x.as_u64(),
+            None => continue,
+        };
+
+        let amount = U256::from_big_endian(&log.data);
+
+        if amount.is_zero() {
+            continue;
+        }
+
+        transfers.push(DepositTransfer {
+            index: log_index + 1,
+            sender: Address::from(log.topics[1]),
+            token: log.address,
+            amount,
+            credits: credits_for(amount, token.decimals, token.credits_per_token),
+        });
+    }
+
+    transfers
+}
+
+impl Web3ProxyApp {
+    /// Spend the user's credits on a request. Internal requests and requests without a key are free.
+    pub async fn spend_credits(
+        &self,
+        authorization: &Authorization,
+        credits: u64,
+    ) -> Result<(), FrontendErrorResponse> {
+        let config = match self.config.credits.as_ref() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if !matches!(
+            authorization.authorization_type,
+            AuthorizationType::Frontend
+        ) || authorization.checks.rpc_key_id.is_none()
+            || is_external_id(authorization.checks.user_id)
+        {
+            return Ok(());
+        }
+
+        if let Some(user_tier_title) = authorization.checks.user_tier_title.as_ref() {
+            if config.free_user_tiers.contains(user_tier_title) {
+                return Ok(());
+            }
+        }
+
+        let user_id = authorization.checks.user_id;
+
+        let balance = match self.user_balance(user_id).await {
+            Ok(x) => x,
+            Err(err) => {
+                // like the rate limiters, a database error lets the request through
+                warn!(
+                    "unable to load balance for user #{}. err={:?}",
+                    user_id, err
+                );
+                return Ok(());
+            }
+        };
+
+        if balance.load(Ordering::Acquire) <= 0 {
+            return Err(FrontendErrorResponse::StatusCode(
+                StatusCode::PAYMENT_REQUIRED,
+                "insufficient balance. deposit more credits to keep using this key".to_string(),
+                None,
+            ));
+        }
+
+        let credits = credits.try_into().unwrap_or(i64::MAX);
+
+        self.balances.spend(user_id, &balance, credits);
+
+        Ok(())
+    }
+
+    /// The user's balance minus the credits that are spent but not saved yet.
+    pub async fn user_balance(&self, user_id: u64) -> anyhow::Result<Arc<AtomicI64>> {
+        self.balances
+            .get_or_load(user_id, async move {
+                // the primary so that recently saved spending is included
+                let db_conn = self.db_conn().context("credits need a database")?;
+
+                let user = user::Entity::find_by_id(user_id)
+                    .one(&db_conn)
+                    .await?
+                    .context("unknown user")?;
+
+                Ok(user.balance)
+            })
+            .await
+    }
+
+    /// Save the credits spent since the last save.
+    pub fn spawn_credits_saver(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.credits_save_loop())
+    }
+
+    async fn credits_save_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let config = self.config.credits.as_ref().context("no credits config")?;
+        let db_conn = self.db_conn().context("credits need a database")?;
+
+        let mut interval = interval(Duration::from_secs(config.save_seconds));
+
+        loop {
+            interval.tick().await;
+
+            self.save_spent_credits(&db_conn).await;
+        }
+    }
+
+    async fn save_spent_credits(&self, db_conn: &DatabaseConnection) {
+        let unsaved: Vec<(u64, i64)> = self
+            .balances
+            .unsaved
+            .lock()
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .collect();
+
+        for (user_id, credits) in unsaved {
+            let save = async move {
+                user::Entity::update_many()
+                    .col_expr(
+                        user::Column::Balance,
+                        Expr::col(user::Column::Balance).sub(credits),
+                    )
+                    .filter(user::Column::Id.eq(user_id))
+                    .exec(db_conn)
+                    .await?;
+
+                Ok(())
+            };
+
+            if let Err(err) = self.balances.save_spent(user_id, credits, save).await {
+                error!(
+                    "Unable to save spent credits for user #{}! err={:?}",
+                    user_id, err
+                );
+            }
+        }
+    }
+
+    /// Credit deposits once they are confirmed.
+    pub fn spawn_deposit_watcher(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.deposit_watch_loop())
+    }
+
+    async fn deposit_watch_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let config = self.config.credits.as_ref().context("no credits config")?;
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        // the first block that hasn't been searched
+        let mut next_block = None;
+
+        let mut head_block_receiver = self.subscription_sources.head_block_receiver.clone();
+
+        while head_block_receiver.changed().await.is_ok() {
+            let head_block = head_block_receiver.borrow().clone();
+
+            let head_block_num = match head_block.number {
+                Some(x) => x.as_u64(),
+                None => continue,
+            };
+
+            // the head block is 1 deep
+            let confirmed_block_num =
+                (head_block_num + 1).saturating_sub(config.confirmations.max(1));
+
+            let mut from_block = *next_block
+                .get_or_insert_with(|| confirmed_block_num.saturating_sub(config.lookback_blocks));
+
+            while from_block <= confirmed_block_num {
+                let to_block = confirmed_block_num.min(from_block + MAX_DEPOSIT_SCAN_BLOCKS - 1);
+
+                if let Err(err) = self
+                    .scan_for_deposits(&authorization, config, from_block, to_block)
+                    .await
+                {
+                    // the next head block tries again
+                    warn!(
+                        "unable to scan blocks {}-{} for deposits. err={:?}",
+                        from_block, to_block, err
+                    );
+                    break;
+                }
+
+                from_block = to_block + 1;
+                next_block = Some(from_block);
+            }
+        }
+
+        Err(anyhow::anyhow!("head block sender dropped"))
+    }
+
+    async fn scan_for_deposits(
+        &self,
+        authorization: &Arc<Authorization>,
+        config: &CreditsConfig,
+        from_block: u64,
+        to_block: u64,
+    ) -> anyhow::Result<()> {
+        let mut tx_hashes = vec![];
+
+        if !config.tokens.is_empty() {
+            let logs: Vec<Log> = self
+                .credits_request(
+                    authorization,
+                    "eth_getLogs",
+                    json!([{
+                        "fromBlock": U64::from(from_block),
+                        "toBlock": U64::from(to_block),
+                        "address": config.tokens.keys().collect::<Vec<_>>(),
+                        "topics": [erc20_transfer_topic(), null, H256::from(config.deposit_address)],
+                    }]),
+                )
+                .await?;
+
+            tx_hashes.extend(logs.into_iter().filter_map(|x| x.transaction_hash));
+        }
+
+        if config.credits_per_native_token.is_some() {
+            for block_num in from_block..=to_block {
+                let block: Option<Block<Transaction>> = self
+                    .credits_request(
+                        authorization,
+                        "eth_getBlockByNumber",
+                        json!([U64::from(block_num), true]),
+                    )
+                    .await?;
+
+                let block = block.with_context(|| format!("no block {}", block_num))?;
+
+                tx_hashes.extend(
+                    block
+                        .transactions
+                        .into_iter()
+                        .filter(|x| x.to == Some(config.deposit_address) && !x.value.is_zero())
+                        .map(|x| x.hash),
+                );
+            }
+        }
+
+        // a transaction with several transfers is only checked once
+        let mut seen = HashSet::new();
+        tx_hashes.retain(|x| seen.insert(*x));
+
+        for tx_hash in tx_hashes {
+            if let Err(err) = self.credit_deposits(authorization, tx_hash).await {
+                warn!("unable to credit deposit {:?}. err={:?}", tx_hash, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Credit the transfers to the deposit address in a confirmed transaction.
+    /// Transfers that were already credited are skipped, so this is safe to call more than once.
+    pub async fn credit_deposits(
+        &self,
+        authorization: &Arc<Authorization>,
+        tx_hash: TxHash,
+    ) -> anyhow::Result<Vec<deposit::Model>> {
+        let config = self.config.credits.as_ref().context("no credits config")?;
+        let db_conn = self.db_conn().context("credits need a database")?;
+
+        let receipt: Option<TransactionReceipt> = self
+            .credits_request(authorization, "eth_getTransactionReceipt", json!([tx_hash]))
+            .await?;
+        let receipt = receipt.context("transaction is not in a block")?;
+
+        let block_num = receipt
+            .block_number
+            .context("receipt has no block number")?
+            .as_u64();
+
+        let head_block_num = self
+            .balanced_rpcs
+            .head_block_num()
+            .context("no head block")?
+            .as_u64();
+
+        if head_block_num + 1 < block_num + config.confirmations {
+            return Err(anyhow::anyhow!("transaction is not confirmed yet"));
+        }
+
+        let tx: Option<Transaction> = self
+            .credits_request(authorization, "eth_getTransactionByHash", json!([tx_hash]))
+            .await?;
+        let tx = tx.context("unknown transaction")?;
+
+        let mut credited = vec![];
+
+        for transfer in deposit_transfers(config, &tx, &receipt) {
+            let user = match user::Entity::find()
+                .filter(user::Column::Address.eq(transfer.sender.as_bytes()))
+                .one(&db_conn)
+                .await?
+            {
+                Some(x) => x,
+                None => {
+                    warn!(
+                        "deposit in {:?} is from {:?}, which is not a user",
+                        tx_hash, transfer.sender
+                    );
+                    continue;
+                }
+            };
+
+            let deposit = async {
+                let txn = db_conn.begin().await?;
+
+                let already_credited = deposit::Entity::find()
+                    .filter(deposit::Column::ChainId.eq(self.config.chain_id))
+                    .filter(deposit::Column::TxHash.eq(tx_hash.as_bytes()))
+                    .filter(deposit::Column::TransferIndex.eq(transfer.index))
+                    .one(&txn)
+                    .await?
+                    .is_some();
+
+                if already_credited {
+                    return Ok(None);
+                }
+
+                let new_deposit = deposit::ActiveModel {
+                    chain_id: sea_orm::Set(self.config.chain_id),
+                    tx_hash: sea_orm::Set(tx_hash.as_bytes().to_vec()),
+                    transfer_index: sea_orm::Set(transfer.index),
+                    block_number: sea_orm::Set(block_num),
+                    user_id: sea_orm::Set(user.id),
+                    token: sea_orm::Set(transfer.token.as_bytes().to_vec()),
+                    amount: sea_orm::Set(transfer.amount.to_string()),
+                    credits: sea_orm::Set(transfer.credits),
+                    timestamp: sea_orm::Set(Utc::now()),
+                    ..Default::default()
+                };
+
+                let new_deposit = new_deposit.insert(&txn).await?;
+
+                user::Entity::update_many()
+                    .col_expr(
+                        user::Column::Balance,
+                        Expr::col(user::Column::Balance).add(transfer.credits),
+                    )
+                    .filter(user::Column::Id.eq(user.id))
+                    .exec(&txn)
+                    .await?;
+
+                txn.commit().await?;
+
+                Ok(Some(new_deposit))
+            };
+
+            let new_deposit = match self
+                .balances
+                .deposit(user.id, transfer.credits, deposit)
+                .await?
+            {
+                Some(x) => x,
+                None => continue,
+            };
+
+            info!(
+                "credited {} to user #{} for deposit {:?}",
+                transfer.credits, user.id, tx_hash
+            );
+
+            credited.push(new_deposit);
+        }
+
+        Ok(credited)
+    }
+
+    async fn credits_request<R: DeserializeOwned>(
+        &self,
+        authorization: &Arc<Authorization>,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<R> {
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).expect("numbers should always be valid json"),
+            method: method.to_string(),
+            params: Some(params),
+        };
+
+        let response = self
+            .balanced_rpcs
+            .try_send_best_upstream_server(
+                self.allowed_lag,
+                authorization,
+                request,
+                None,
+                None,
+                Traffic::Request,
+            )
+            .await?;
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("{} failed: {}", method, err.message));
+        }
+
+        match response.result {
+            Some(result) => serde_json::from_str(result.get()).context("parsing result"),
+            None => serde_json::from_value(serde_json::Value::Null).context("parsing result"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DepositTokenConfig;
+
+    #[test]
+    fn test_deposit_transfers() {
+        let deposit_address = Address::from_low_u64_be(1);
+        let sender = Address::from_low_u64_be(2);
+        let token = Address::from_low_u64_be(3);
+
+        let config = CreditsConfig {
+            deposit_address,
+            credits_per_native_token: Some(1_000_000.0),
+            tokens: HashMap::from([(
+                token,
+                DepositTokenConfig {
+                    decimals: 6,
+                    credits_per_token: 100.0,
+                },
+            )]),
+            confirmations: 12,
+            lookback_blocks: 1_000,
+            save_seconds: 10,
+            free_user_tiers: vec![],
+        };
+
+        // 0.5 of the native token
+        let tx = Transaction {
+            from: sender,
+            to: Some(deposit_address),
+            value: U256::exp10(17) * 5,
+            ..Default::default()
+        };
+
+        // 2.5 of the token
+        let mut data = [0; 32];
+        U256::from(2_500_000).to_big_endian(&mut data);
+
+        let mut receipt = TransactionReceipt {
+            status: Some(U64::one()),
+            logs: vec![Log {
+                address: token,
+                topics: vec![
+                    erc20_transfer_topic(),
+                    H256::from(sender),
+                    H256::from(deposit_address),
+                ],
+                data: data.to_vec().into(),
+                log_index: Some(U256::from(4)),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            deposit_transfers(&config, &tx, &receipt),
+            vec![
+                DepositTransfer {
+                    index: 0,
+                    sender,
+                    token: Address::zero(),
+                    amount: U256::exp10(17) * 5,
+                    credits: 500_000,
+                },
+                DepositTransfer {
+                    index: 5,
+                    sender,
+                    token,
+                    amount: U256::from(2_500_000),
+                    credits: 250,
+                },
+            ]
+        );
+
+        // tokens that aren't in the config are ignored
+        receipt.logs[0].address = Address::from_low_u64_be(4);
+        assert_eq!(deposit_transfers(&config, &tx, &receipt).len(), 1);
+
+        // reverted transactions don't transfer anything
+        receipt.status = Some(U64::zero());
+        assert!(deposit_transfers(&config, &tx, &receipt).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deposit_and_save_at_once() {
+        let balances = Balances::default();
+
+        // the user's row. holding this lock is like a transaction holding the row lock
+        let row = AsyncMutex::new(100);
+
+        let load = || async { Ok(*row.lock().await) };
+
+        let balance = balances.get_or_load(1, load()).await.unwrap();
+
+        balances.spend(1, &balance, 10);
+        assert_eq!(balance.load(Ordering::Acquire), 90);
+
+        let deposit = balances.deposit(1, 50, async {
+            let mut row = row.lock().await;
+
+            // let the save run while this holds the row
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+
+            *row += 50;
+
+            Ok(Some(()))
+        });
+
+        let save = balances.save_spent(1, 10, async {
+            *row.lock().await -= 10;
+
+            Ok(())
+        });
+
+        let (deposited, saved) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(deposit, save)
+        })
+        .await
+        .expect("the deposit and the save deadlocked");
+
+        assert!(deposited.unwrap().is_some());
+        saved.unwrap();
+
+        assert_eq!(*row.lock().await, 140);
+        assert_eq!(balance.load(Ordering::Acquire), 140);
+
+        // loading again agrees with the cached balance
+        balances.cache.invalidate(&1).await;
+
+        let balance = balances.get_or_load(1, load()).await.unwrap();
+        assert_eq!(balance.load(Ordering::Acquire), 140);
+    }
+}
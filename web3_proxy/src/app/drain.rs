@@ -0,0 +1,91 @@
+//! The first half of a graceful shutdown.
+//!
+//! Draining stops accepting new connections and lets open requests and websockets finish.
+//! Once they are done (or the deadline passes), the shutdown_sender stops everything else and the stats are saved.
+
+use super::Web3ProxyApp;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+
+pub struct Drain {
+    /// true once draining has started
+    draining: watch::Sender<bool>,
+    /// hyper's graceful shutdown doesn't wait for upgraded connections, so websockets are counted here
+    websockets: Arc<AtomicUsize>,
+    websocket_closed: Arc<Notify>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            draining: watch::channel(false).0,
+            websockets: Default::default(),
+            websocket_closed: Default::default(),
+        }
+    }
+}
+
+/// Counts an open websocket until it is dropped.
+pub struct WebsocketGuard {
+    websockets: Arc<AtomicUsize>,
+    websocket_closed: Arc<Notify>,
+}
+
+impl Drop for WebsocketGuard {
+    fn drop(&mut self) {
+        self.websockets.fetch_sub(1, atomic::Ordering::AcqRel);
+        self.websocket_closed.notify_waiters();
+    }
+}
+
+impl Web3ProxyApp {
+    /// Stop accepting new connections. Returns false if the app was already draining.
+    pub fn start_draining(&self) -> bool {
+        !self.drain.draining.send_replace(true)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.drain.draining.borrow()
+    }
+
+    /// Completes once draining starts.
+    pub async fn draining(&self) {
+        let mut receiver = self.drain.draining.subscribe();
+
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                // the app owns the sender, so this can't happen while the app exists
+                return;
+            }
+        }
+    }
+
+    /// Hold the returned guard for as long as the websocket is open.
+    pub fn websocket_opened(&self) -> WebsocketGuard {
+        self.drain.websockets.fetch_add(1, atomic::Ordering::AcqRel);
+
+        WebsocketGuard {
+            websockets: self.drain.websockets.clone(),
+            websocket_closed: self.drain.websocket_closed.clone(),
+        }
+    }
+
+    pub fn open_websockets(&self) -> usize {
+        self.drain.websockets.load(atomic::Ordering::Acquire)
+    }
+
+    /// Completes once every websocket has closed.
+    pub async fn websockets_closed(&self) {
+        loop {
+            // subscribe before checking so that a close between the check and the await isn't missed
+            let closed = self.drain.websocket_closed.notified();
+
+            if self.open_websockets() == 0 {
+                return;
+            }
+
+            closed.await;
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! `eth_gasPrice` from a short cache so that every client sees the same price.
+//!
+//! A backend's price is clamped to our own view of the fee history. One backend with an odd mempool can't give an outlier.
+use super::Web3ProxyApp;
+use crate::config::GasPriceConfig;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::jsonrpc::JsonRpcRequest;
+use crate::rpcs::request::Traffic;
+use anyhow::Context;
+use ethers::types::{FeeHistory, H256, U256};
+use log::debug;
+use moka::future::Cache;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Gas prices by head block hash
+pub struct GasPriceCache {
+    cache: Cache<H256, U256, hashbrown::hash_map::DefaultHashBuilder>,
+    fee_history_blocks: u64,
+    max_spread: f64,
+}
+
+impl GasPriceCache {
+    pub fn new(config: &GasPriceConfig) -> Self {
+        Self {
+            cache: Cache::builder()
+                .max_capacity(16)
+                .time_to_live(Duration::from_millis(config.cache_ms))
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            fee_history_blocks: config.fee_history_blocks,
+            max_spread: config.max_spread,
+        }
+    }
+}
+
+/// The next block's base fee plus the median of the recent blocks' median tips.
+/// None if the chain doesn't have EIP-1559 fees.
+fn fee_history_reference(fee_history: &FeeHistory) -> Option<U256> {
+    let base_fee = *fee_history.base_fee_per_gas.last()?;
+
+    if base_fee.is_zero() {
+        return None;
+    }
+
+    let mut tips: Vec<U256> = fee_history
+        .reward
+        .iter()
+        .filter_map(|x| x.first().copied())
+        .collect();
+
+    tips.sort();
+
+    let tip = tips.get(tips.len() / 2).copied().unwrap_or_default();
+
+    Some(base_fee + tip)
+}
+
+/// Keep `price` within `max_spread` (0.5 = 50%) of `reference`
+fn clamp_gas_price(price: U256, reference: U256, max_spread: f64) -> U256 {
+    let spread_bps = (max_spread.max(0.0) * 10_000.0) as u64;
+
+    let min = reference * U256::from(10_000u64.saturating_sub(spread_bps)) / U256::from(10_000);
+    let max = reference * U256::from(10_000 + spread_bps) / U256::from(10_000);
+
+    price.clamp(min, max)
+}
+
+impl Web3ProxyApp {
+    /// The first request for each head block (or after `cache_ms`) asks a backend. Everyone else gets the same answer.
+    pub(super) async fn cached_gas_price(
+        &self,
+        gas_price_cache: &GasPriceCache,
+        authorization: &Arc<Authorization>,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> anyhow::Result<U256> {
+        let head_block_hash = self
+            .balanced_rpcs
+            .head_block_hash()
+            .context("no servers synced. unknown eth_gasPrice")?;
+
+        gas_price_cache
+            .cache
+            .try_get_with(head_block_hash, async {
+                let (price, fee_history) = tokio::join!(
+                    self.gas_price_request::<U256>(
+                        authorization,
+                        request_metadata,
+                        "eth_gasPrice",
+                        json!([]),
+                    ),
+                    self.gas_price_request::<FeeHistory>(
+                        authorization,
+                        request_metadata,
+                        "eth_feeHistory",
+                        json!([
+                            U256::from(gas_price_cache.fee_history_blocks),
+                            "latest",
+                            [50]
+                        ]),
+                    ),
+                );
+
+                let price = price?;
+
+                // the price is still useful without the fee history
+                let reference = match fee_history {
+                    Ok(x) => fee_history_reference(&x),
+                    Err(err) => {
+                        debug!("unable to get fee history for eth_gasPrice. err={:?}", err);
+                        None
+                    }
+                };
+
+                let clamped = match reference {
+                    Some(reference) => {
+                        clamp_gas_price(price, reference, gas_price_cache.max_spread)
+                    }
+                    None => price,
+                };
+
+                if clamped != price {
+                    debug!(
+                        "clamped eth_gasPrice from {} to {}. reference={:?}",
+                        price, clamped, reference
+                    );
+                }
+
+                Ok::<_, anyhow::Error>(clamped)
+            })
+            .await
+            .map_err(|err| anyhow::anyhow!(err))
+    }
+
+    async fn gas_price_request<R: serde::de::DeserializeOwned>(
+        &self,
+        authorization: &Arc<Authorization>,
+        request_metadata: &Arc<RequestMetadata>,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<R> {
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).expect("numbers should always be valid json"),
+            method: method.to_string(),
+            params: Some(params),
+        };
+
+        let response = self
+            .balanced_rpcs
+            .try_send_best_upstream_server(
+                self.allowed_lag,
+                authorization,
+                request,
+                Some(request_metadata),
+                None,
+                Traffic::Request,
+            )
+            .await?;
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("{} failed: {}", method, err.message));
+        }
+
+        let result = response.result.context("no result")?;
+
+        serde_json::from_str(result.get()).context("parsing result")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_history_reference() {
+        let fee_history = FeeHistory {
+            base_fee_per_gas: vec![90.into(), 100.into()],
+            gas_used_ratio: vec![0.5],
+            oldest_block: 1.into(),
+            reward: vec![vec![3.into()], vec![1.into()], vec![2.into()]],
+        };
+
+        assert_eq!(fee_history_reference(&fee_history), Some(102.into()));
+
+        // no EIP-1559
+        let fee_history = FeeHistory {
+            base_fee_per_gas: vec![0.into()],
+            gas_used_ratio: vec![],
+            oldest_block: 1.into(),
+            reward: vec![],
+        };
+
+        assert_eq!(fee_history_reference(&fee_history), None);
+    }
+
+    #[test]
+    fn test_clamp_gas_price() {
+        let reference = U256::from(100);
+
+        assert_eq!(clamp_gas_price(120.into(), reference, 0.5), 120.into());
+        assert_eq!(clamp_gas_price(1_000.into(), reference, 0.5), 150.into());
+        assert_eq!(clamp_gas_price(10.into(), reference, 0.5), 50.into());
+        // a spread over 100% can't go below 0
+        assert_eq!(clamp_gas_price(0.into(), reference, 2.0), 0.into());
+        assert_eq!(clamp_gas_price(1_000.into(), reference, 2.0), 300.into());
+    }
+}
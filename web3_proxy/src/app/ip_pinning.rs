@@ -0,0 +1,429 @@
+//! Limit rpc keys to known ips so that a leaked key is useless elsewhere.
+//!
+//! A key can list its `allowed_ips` or be pinned to the first `pin_ips` ips that use it.
+//! Pinned ips are saved in the database so that every proxy agrees on them.
+//! Rejected ips are POSTed to the key's `alert_webhook_url`.
+//! Users choose that url, so alerts are only ever sent to public addresses.
+
+use super::{AuthorizationChecks, Web3ProxyApp, APP_USER_AGENT};
+use crate::frontend::authorization::RpcSecretKey;
+use anyhow::Context;
+use chrono::Utc;
+use entities::rpc_key;
+use hyper::client::connect::dns::Name;
+use log::{debug, info, warn};
+use migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use migration::Expr;
+use moka::future::Cache;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::time::Duration;
+use ulid::Ulid;
+use url::Url;
+
+/// The same ip only alerts a key's webhook this often.
+const ALERT_EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// Pinning is optimistic. Give up if other requests keep changing the key's ips.
+const MAX_PIN_ATTEMPTS: usize = 3;
+
+pub struct IpPinAlerts {
+    http_client: reqwest::Client,
+    /// the id of the alert that was sent for each key and ip
+    recent: Cache<(NonZeroU64, IpAddr), Ulid, hashbrown::hash_map::DefaultHashBuilder>,
+}
+
+impl IpPinAlerts {
+    pub fn try_new() -> anyhow::Result<Self> {
+        // not the shared client. this one only connects to public addresses and never follows redirects
+        let http_client = reqwest::ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(10))
+            .user_agent(APP_USER_AGENT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()?;
+
+        let recent = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(ALERT_EVERY)
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Ok(Self {
+            http_client,
+            recent,
+        })
+    }
+}
+
+/// False for loopback, private, link-local (which includes cloud metadata), and other non-routable addresses.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // 0.0.0.0/8 and 240.0.0.0/4
+                || a == 0
+                || a >= 240
+                // carrier-grade nat
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(ip.into());
+            }
+
+            let first = ip.segments()[0];
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local
+                || (first & 0xfe00) == 0xfc00
+                // link-local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolve a host and keep only its public addresses.
+async fn public_addrs(host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("resolving {}", host))?
+        .collect();
+
+    // if any address is private, the host is not trusted at all
+    if addrs.is_empty() || !addrs.iter().all(|x| is_public_ip(x.ip())) {
+        return Err(anyhow::anyhow!(
+            "{} does not resolve to a public address",
+            host
+        ));
+    }
+
+    Ok(addrs)
+}
+
+/// Resolves hostnames for webhooks. Checking only when the url is saved isn't enough because dns can change later.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = public_addrs(name.as_str(), 0).await?;
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+
+            Ok(addrs)
+        })
+    }
+}
+
+/// Webhooks must be https and must go to a public address.
+pub async fn check_webhook_url(url: &str) -> anyhow::Result<()> {
+    let url = Url::parse(url).context("parsing alert_webhook_url")?;
+
+    if url.scheme() != "https" {
+        return Err(anyhow::anyhow!("alert_webhook_url must be https"));
+    }
+
+    match url.host() {
+        None => Err(anyhow::anyhow!("alert_webhook_url must have a host")),
+        Some(url::Host::Ipv4(ip)) if !is_public_ip(ip.into()) => Err(anyhow::anyhow!(
+            "alert_webhook_url must be a public address"
+        )),
+        Some(url::Host::Ipv6(ip)) if !is_public_ip(ip.into()) => Err(anyhow::anyhow!(
+            "alert_webhook_url must be a public address"
+        )),
+        Some(url::Host::Domain(host)) => {
+            public_addrs(host, url.port_or_known_default().unwrap_or(443)).await?;
+
+            Ok(())
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum PinDecision {
+    /// the ip is already pinned
+    Allow,
+    /// the key has room for another ip
+    Pin,
+    Reject,
+}
+
+fn pin_decision(pinned_ips: &[IpAddr], pin_ips: u32, ip: IpAddr) -> PinDecision {
+    if pinned_ips.contains(&ip) {
+        PinDecision::Allow
+    } else if pinned_ips.len() < pin_ips as usize {
+        PinDecision::Pin
+    } else {
+        PinDecision::Reject
+    }
+}
+
+/// A comma separated list of ips. None is empty
+pub fn parse_pinned_ips(pinned_ips: Option<&str>) -> anyhow::Result<Vec<IpAddr>> {
+    match pinned_ips {
+        None => Ok(vec![]),
+        Some(x) => x
+            .split(',')
+            .map(|x| {
+                x.trim()
+                    .parse()
+                    .with_context(|| format!("invalid pinned ip: {}", x))
+            })
+            .collect(),
+    }
+}
+
+fn join_pinned_ips(pinned_ips: &[IpAddr]) -> String {
+    pinned_ips
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl Web3ProxyApp {
+    /// Check the ip against the key's allowed and pinned ips. New ips are pinned until the key has `pin_ips` of them.
+    pub async fn check_key_ip(
+        &self,
+        rpc_secret_key: RpcSecretKey,
+        authorization_checks: &AuthorizationChecks,
+        ip: IpAddr,
+    ) -> anyhow::Result<()> {
+        let rpc_key_id = match authorization_checks.rpc_key_id {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(allowed_ips) = authorization_checks.allowed_ips.as_ref() {
+            if !allowed_ips.iter().any(|x| x.contains(&ip)) {
+                self.ip_rejected(rpc_key_id, authorization_checks, ip, "not in allowed_ips")
+                    .await;
+
+                return Err(anyhow::anyhow!("IP ({}) is not allowed!", ip));
+            }
+        }
+
+        let pin_ips = match authorization_checks.pin_ips {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let allowed = match pin_decision(&authorization_checks.pinned_ips, pin_ips, ip) {
+            PinDecision::Allow => true,
+            PinDecision::Reject => false,
+            PinDecision::Pin => {
+                // the cached checks might be stale. the database decides
+                let allowed = self.pin_ip(rpc_key_id, ip).await?;
+
+                let rpc_secret_key: Ulid = rpc_secret_key.into();
+
+                self.rpc_secret_key_cache.invalidate(&rpc_secret_key).await;
+                self.rpc_key_id_cache.invalidate(&rpc_key_id.get()).await;
+
+                allowed
+            }
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            self.ip_rejected(rpc_key_id, authorization_checks, ip, "not pinned")
+                .await;
+
+            Err(anyhow::anyhow!("IP ({}) is not pinned to this key!", ip))
+        }
+    }
+
+    /// Returns false if the key already has `pin_ips` other ips.
+    async fn pin_ip(&self, rpc_key_id: NonZeroU64, ip: IpAddr) -> anyhow::Result<bool> {
+        let db_conn = self.db_conn().context("pinning ips requires a database")?;
+
+        for _ in 0..MAX_PIN_ATTEMPTS {
+            let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id.get())
+                .one(&db_conn)
+                .await?
+                .context("rpc key not found")?;
+
+            let pin_ips = match rpc_key.pin_ips {
+                Some(x) => x,
+                // pinning was turned off since the key was cached
+                None => return Ok(true),
+            };
+
+            let mut pinned_ips = parse_pinned_ips(rpc_key.pinned_ips.as_deref())?;
+
+            match pin_decision(&pinned_ips, pin_ips, ip) {
+                PinDecision::Allow => return Ok(true),
+                PinDecision::Reject => return Ok(false),
+                PinDecision::Pin => pinned_ips.push(ip),
+            }
+
+            // only update if no other request pinned an ip since we read the key
+            let update = rpc_key::Entity::update_many()
+                .col_expr(
+                    rpc_key::Column::PinnedIps,
+                    Expr::value(join_pinned_ips(&pinned_ips)),
+                )
+                .filter(rpc_key::Column::Id.eq(rpc_key.id));
+
+            let update = match rpc_key.pinned_ips {
+                None => update.filter(rpc_key::Column::PinnedIps.is_null()),
+                Some(x) => update.filter(rpc_key::Column::PinnedIps.eq(x)),
+            };
+
+            if update.exec(&db_conn).await?.rows_affected == 1 {
+                info!("pinned rpc key #{} to {}", rpc_key_id, ip);
+
+                return Ok(true);
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "unable to pin rpc key #{}. its ips keep changing",
+            rpc_key_id
+        ))
+    }
+
+    /// Alert the key's webhook in the background. Each ip alerts at most once per `ALERT_EVERY`.
+    async fn ip_rejected(
+        &self,
+        rpc_key_id: NonZeroU64,
+        authorization_checks: &AuthorizationChecks,
+        ip: IpAddr,
+        reason: &'static str,
+    ) {
+        debug!("rpc key #{} rejected {}: {}", rpc_key_id, ip, reason);
+
+        let alert_webhook_url = match authorization_checks.alert_webhook_url.clone() {
+            Some(x) => x,
+            None => return,
+        };
+
+        let http_client = self.ip_pin_alerts.http_client.clone();
+
+        // get_with only runs one init per key. only the request whose id was inserted sends the alert
+        let alert_id = Ulid::new();
+
+        let sent_id = self
+            .ip_pin_alerts
+            .recent
+            .get_with((rpc_key_id, ip), async move { alert_id })
+            .await;
+
+        if sent_id != alert_id {
+            return;
+        }
+
+        // the url was checked when it was saved, but a literal ip in it wouldn't go through the resolver
+        if let Err(err) = check_webhook_url(&alert_webhook_url).await {
+            warn!(
+                "not alerting rpc key #{} about {}. err={:?}",
+                rpc_key_id, ip, err
+            );
+            return;
+        }
+
+        let body = json!({
+            "rpc_key_id": rpc_key_id,
+            "ip": ip,
+            "reason": reason,
+            "at": Utc::now().timestamp(),
+        });
+
+        tokio::spawn(async move {
+            let response = http_client
+                .post(&alert_webhook_url)
+                .json(&body)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status());
+
+            if let Err(err) = response {
+                warn!(
+                    "unable to alert rpc key #{} about {}. err={:?}",
+                    rpc_key_id, ip, err
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_decision() {
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let c: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(pin_decision(&[], 2, a), PinDecision::Pin);
+        assert_eq!(pin_decision(&[a], 2, a), PinDecision::Allow);
+        assert_eq!(pin_decision(&[a], 2, b), PinDecision::Pin);
+        assert_eq!(pin_decision(&[a, b], 2, c), PinDecision::Reject);
+
+        // lowering pin_ips keeps the ips that are already pinned
+        assert_eq!(pin_decision(&[a, b], 1, b), PinDecision::Allow);
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in ["1.1.1.1", "8.8.8.8", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_webhook_url() {
+        assert!(check_webhook_url("http://1.1.1.1/hook").await.is_err());
+        assert!(check_webhook_url("https://127.0.0.1/hook").await.is_err());
+        assert!(check_webhook_url("https://169.254.169.254/latest")
+            .await
+            .is_err());
+        assert!(check_webhook_url("https://[::1]/hook").await.is_err());
+        assert!(check_webhook_url("https://localhost/hook").await.is_err());
+        assert!(check_webhook_url("https://1.1.1.1/hook").await.is_ok());
+    }
+
+    #[test]
+    fn test_pinned_ips_round_trip() {
+        let ips = parse_pinned_ips(Some("10.0.0.1, 2001:db8::1")).unwrap();
+
+        assert_eq!(ips.len(), 2);
+        assert_eq!(join_pinned_ips(&ips), "10.0.0.1,2001:db8::1");
+        assert!(parse_pinned_ips(None).unwrap().is_empty());
+        assert!(parse_pinned_ips(Some("not an ip")).is_err());
+    }
+}
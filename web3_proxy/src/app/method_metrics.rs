@@ -0,0 +1,193 @@
+//! Prometheus counters for each JSON-RPC method: requests, errors, and response cache hits and misses.
+//!
+//! Method names come from clients, so only allowlisted or the first `metrics_max_methods` methods get their own label.
+//! A method only gets a label once a request for it was answered without a "method not found" or "method not allowed" error.
+//! Everything else is counted as "other".
+use crate::config::AppConfig;
+use hashbrown::{HashMap, HashSet};
+use parking_lot::RwLock;
+use serde::{Serialize, Serializer};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const OTHER_METHODS: &str = "other";
+
+/// Longer method names are counted as "other"
+const MAX_METHOD_LEN: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct MethodCounters {
+    pub requests: AtomicU64,
+    /// errors returned to the client, including json-rpc errors from the backends
+    pub errors: AtomicU64,
+    /// served from the response cache. coalesced requests for a response that was already being fetched count as hits
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct MethodMetrics {
+    /// empty = any method
+    allowlist: HashSet<String>,
+    max_methods: usize,
+    methods: RwLock<HashMap<String, Arc<MethodCounters>>>,
+    other: Arc<MethodCounters>,
+}
+
+/// serde_prometheus doesn't escape label values, so odd names are never used as labels
+fn is_label_safe(method: &str) -> bool {
+    !method.is_empty()
+        && method.len() <= MAX_METHOD_LEN
+        && method
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+impl MethodMetrics {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            allowlist: config.metrics_methods.iter().cloned().collect(),
+            max_methods: config.metrics_max_methods,
+            methods: Default::default(),
+            other: Default::default(),
+        }
+    }
+
+    /// The counters for this method, or the shared "other" counters if it doesn't have a label yet
+    pub fn get(&self, method: &str) -> Arc<MethodCounters> {
+        match self.methods.read().get(method) {
+            Some(x) => x.clone(),
+            None => self.other.clone(),
+        }
+    }
+
+    pub fn is_other(&self, counters: &Arc<MethodCounters>) -> bool {
+        Arc::ptr_eq(counters, &self.other)
+    }
+
+    /// Give this method its own counters. Only call this once a request for it was accepted and answered.
+    /// Junk method names from clients would otherwise fill `max_methods`
+    pub fn label(&self, method: &str) {
+        if !is_label_safe(method) || method == OTHER_METHODS {
+            return;
+        }
+
+        if !self.allowlist.is_empty() && !self.allowlist.contains(method) {
+            return;
+        }
+
+        let mut methods = self.methods.write();
+
+        // another request might have added it while we waited for the lock
+        if methods.contains_key(method) {
+            return;
+        }
+
+        // the allowlist already limits how many labels there can be
+        if self.allowlist.is_empty() && methods.len() >= self.max_methods {
+            return;
+        }
+
+        methods.insert(method.to_string(), Default::default());
+    }
+}
+
+/// `method_{counter}{method}`
+fn method_label<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct("<!<.|method==!<", value)
+}
+
+/// The counters' field names become metric names and the method becomes a label
+#[derive(Serialize)]
+struct LabeledCounters {
+    #[serde(serialize_with = "method_label")]
+    requests: u64,
+    #[serde(serialize_with = "method_label")]
+    errors: u64,
+    #[serde(serialize_with = "method_label")]
+    cache_hits: u64,
+    #[serde(serialize_with = "method_label")]
+    cache_misses: u64,
+}
+
+impl From<&MethodCounters> for LabeledCounters {
+    fn from(x: &MethodCounters) -> Self {
+        Self {
+            requests: x.requests.load(Ordering::Relaxed),
+            errors: x.errors.load(Ordering::Relaxed),
+            cache_hits: x.cache_hits.load(Ordering::Relaxed),
+            cache_misses: x.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Serialize for MethodMetrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let methods = self.methods.read();
+
+        let mut labeled: HashMap<&str, LabeledCounters> = methods
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_ref().into()))
+            .collect();
+
+        labeled.insert(OTHER_METHODS, self.other.as_ref().into());
+
+        labeled.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_metrics_cap() {
+        let config = AppConfig {
+            metrics_max_methods: 2,
+            ..Default::default()
+        };
+
+        let x = MethodMetrics::new(&config);
+
+        // nothing has a label until it is answered
+        assert!(x.is_other(&x.get("eth_call")));
+
+        for method in [
+            "eth_call",
+            "eth_getLogs",
+            "eth_blockNumber",
+            "eth_call\"} 1",
+            "other",
+        ] {
+            x.label(method);
+        }
+
+        let eth_call = x.get("eth_call");
+        let eth_get_logs = x.get("eth_getLogs");
+
+        assert!(Arc::ptr_eq(&eth_call, &x.get("eth_call")));
+        assert!(!Arc::ptr_eq(&eth_call, &eth_get_logs));
+        assert!(!x.is_other(&eth_call));
+
+        // past the cap, bad names, and our own name for the rest
+        assert!(x.is_other(&x.get("eth_blockNumber")));
+        assert!(x.is_other(&x.get("eth_call\"} 1")));
+        assert!(x.is_other(&x.get("other")));
+    }
+
+    #[test]
+    fn test_method_metrics_allowlist() {
+        let config = AppConfig {
+            metrics_methods: vec!["eth_call".to_string()],
+            ..Default::default()
+        };
+
+        let x = MethodMetrics::new(&config);
+
+        x.label("eth_call");
+        x.label("eth_getLogs");
+
+        assert!(!x.is_other(&x.get("eth_call")));
+        assert!(x.is_other(&x.get("eth_getLogs")));
+    }
+}
@@ -1,35 +1,82 @@
 // TODO: this file is way too big now. move things into other modules
+mod bundles;
+mod chain_halt;
+mod credits;
+mod drain;
+mod gas_price;
+mod ip_pinning;
+mod method_metrics;
+mod multicall;
+mod private_txs;
+mod probes;
+mod reload;
+mod simulation;
+mod stubs;
+mod tx_tracker;
 mod ws;
 
-use crate::app_stats::{ProxyResponseStat, StatEmitter, Web3ProxyStat};
+pub use credits::Balances;
+use drain::Drain;
+pub use drain::WebsocketGuard;
+use gas_price::GasPriceCache;
+pub use ip_pinning::{check_webhook_url, parse_pinned_ips, IpPinAlerts};
+use method_metrics::MethodMetrics;
+pub use multicall::{MulticallCall, MulticallResult, DEFAULT_MULTICALL_ADDRESS};
+use private_txs::is_already_known;
+pub use private_txs::{PendingPrivateTx, RelayLatencies};
+pub use reload::RpcsReloaded;
+pub use tx_tracker::{TrackedTx, TrackedTxState};
+use tx_tracker::{TxTracker, TxTrackerMetrics};
+pub use ws::{
+    SubscriptionId, SubscriptionKind, SubscriptionRegistry, SubscriptionSources,
+    WebsocketSubscriptions,
+};
+
+use crate::app_stats::{ProxyResponseStat, StatEmitter, StatEmitterHealth, StatSender};
 use crate::block_number::{block_needed, BlockNeeded};
-use crate::config::{AppConfig, TopConfig};
-use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::config::{
+    AnonymousAccess, AppConfig, ResponseCachePartitionConfig, StatQueueFull, TopConfig,
+};
+use crate::disk_cache::DiskCache;
+use crate::external_auth::ExternalAuthorizer;
+use crate::feature_flags::FeatureFlags;
+use crate::frontend::authorization::{
+    Authorization, AuthorizationType, ConcurrencyKey, RequestMetadata, RequestTransport,
+    RpcSecretKey,
+};
 use crate::frontend::errors::FrontendErrorResponse;
+use crate::influxdb::InfluxDb;
+use crate::ip_reputation::IpReputationChecker;
 use crate::jsonrpc::{
     JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest, JsonRpcRequestEnum,
 };
+use crate::rpcs::backend_metrics::{latency_openmetrics, BackendRpcMetrics};
 use crate::rpcs::blockchain::{ArcBlock, SavedBlock};
 use crate::rpcs::connection::Web3Connection;
-use crate::rpcs::connections::Web3Connections;
-use crate::rpcs::request::OpenRequestHandleMetrics;
+use crate::rpcs::connections::{is_method_not_found, Web3Connections};
+use crate::rpcs::costs::{sort_rpc_costs, Cost, RpcCosts};
+use crate::rpcs::queue::BackendQueueMetrics;
+use crate::rpcs::request::{OpenRequestHandleMetrics, Traffic};
 use crate::rpcs::transactions::TxStatus;
+use crate::telemetry;
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
 use axum::headers::{Origin, Referer, UserAgent};
-use chrono::Utc;
-use deferred_rate_limiter::DeferredRateLimiter;
+use chrono::{DateTime, Utc};
+use deferred_rate_limiter::{DeferredRateLimitResult, DeferredRateLimiter};
 use derive_more::From;
 use entities::sea_orm_active_enums::LogLevel;
 use entities::user;
 use ethers::core::utils::keccak256;
-use ethers::prelude::{Address, Block, Bytes, Transaction, TxHash, H256, U64};
+use ethers::prelude::{
+    Address, Block, Bytes, LocalWallet, Signer, Transaction, TxHash, H256, U256, U64,
+};
 use ethers::utils::rlp::{Decodable, Rlp};
-use futures::future::join_all;
-use futures::stream::{FuturesUnordered, StreamExt};
+use futures::stream::{self, FuturesUnordered, StreamExt};
 use hashbrown::{HashMap, HashSet};
+use http::StatusCode;
 use ipnet::IpNet;
-use log::{debug, error, info, trace, warn, Level};
+use log::{debug, error, info, trace, warn};
 use metered::{metered, ErrorCount, HitCount, ResponseTime, Throughput};
 use migration::sea_orm::{
     self, ConnectionTrait, Database, DatabaseConnection, EntityTrait, PaginatorTrait,
@@ -37,6 +84,9 @@ use migration::sea_orm::{
 use migration::sea_query::table::ColumnDef;
 use migration::{Alias, DbErr, Migrator, MigratorTrait, Table};
 use moka::future::Cache;
+use opentelemetry::trace::FutureExt as _;
+use opentelemetry::KeyValue;
+use parking_lot::RwLock;
 use redis_rate_limiter::redis::AsyncCommands;
 use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig, RedisPool, RedisRateLimiter};
 use serde::Serialize;
@@ -47,8 +97,9 @@ use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
 use std::num::NonZeroU64;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::{atomic, Arc};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, watch, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time::{sleep, timeout};
@@ -65,6 +116,91 @@ pub static APP_USER_AGENT: &str = concat!(
 /// TODO: allow customizing the request period?
 pub static REQUEST_PERIOD: u64 = 60;
 
+/// The error code returned when a request needs a block that every backend has pruned.
+pub static HISTORY_PRUNED_ERROR_CODE: i64 = -32001;
+
+/// The error code returned when none of the backends are at the head block. Retrying soon will probably work.
+pub static NO_SERVERS_SYNCED_ERROR_CODE: i64 = -32002;
+pub static NO_SERVERS_SYNCED_MESSAGE: &str = "proxy has no upstreams at head";
+
+/// A request needed a backend at the head block and there weren't any.
+/// Requests that can be answered from caches or locally don't return this.
+#[derive(Debug)]
+pub struct NoServersSynced;
+
+impl fmt::Display for NoServersSynced {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", NO_SERVERS_SYNCED_MESSAGE)
+    }
+}
+
+impl std::error::Error for NoServersSynced {}
+
+/// Keep `NoServersSynced` distinct so that clients get a 503 and can retry
+fn frontend_error(err: anyhow::Error) -> FrontendErrorResponse {
+    if err.is::<NoServersSynced>() {
+        FrontendErrorResponse::NoServersSynced
+    } else {
+        err.into()
+    }
+}
+
+/// The error code returned when the rpc key's owner does not allow the method.
+pub static METHOD_NOT_ALLOWED_ERROR_CODE: i64 = -32003;
+
+/// true if the method matches a pattern like "eth_call" or "debug_*"
+fn method_matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method,
+    }
+}
+
+/// true for `eth_getTransactionCount` params that ask for the "pending" block
+fn is_pending_nonce(params: &Option<serde_json::Value>) -> bool {
+    params
+        .as_ref()
+        .and_then(|x| x.get(1))
+        .and_then(|x| x.as_str())
+        == Some("pending")
+}
+
+/// true for `eth_getBlockByNumber` params that ask for the "latest" block without full transactions
+fn is_latest_block_request(params: &Option<serde_json::Value>) -> bool {
+    let params = match params.as_ref() {
+        Some(x) => x,
+        None => return false,
+    };
+
+    params.get(0).and_then(|x| x.as_str()) == Some("latest")
+        && matches!(params.get(1), None | Some(serde_json::Value::Bool(false)))
+}
+
+/// true if any param is the "latest" block tag. `eth_getLogs` style filter objects are checked too
+fn is_latest_tagged(params: &Option<serde_json::Value>) -> bool {
+    let params = match params.as_ref().and_then(|x| x.as_array()) {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let is_latest = |x: Option<&serde_json::Value>| x.and_then(|x| x.as_str()) == Some("latest");
+
+    params
+        .iter()
+        .any(|x| is_latest(Some(x)) || is_latest(x.get("fromBlock")) || is_latest(x.get("toBlock")))
+}
+
+/// the filter id that is the first param of `eth_getFilterChanges` and friends
+fn filter_id(params: &Option<serde_json::Value>) -> anyhow::Result<U256> {
+    params
+        .as_ref()
+        .and_then(|x| x.get(0))
+        .cloned()
+        .map(serde_json::from_value)
+        .context("filter id required")?
+        .context("invalid filter id")
+}
+
 #[derive(From)]
 struct ResponseCacheKey {
     // if none, this is cached until evicted
@@ -130,6 +266,50 @@ impl Hash for ResponseCacheKey {
 type ResponseCache =
     Cache<ResponseCacheKey, JsonRpcForwardedResponse, hashbrown::hash_map::DefaultHashBuilder>;
 
+/// responses can be very different in sizes, so this definitely needs a weigher
+// TODO: don't allow any response to be bigger than X% of the cache
+fn new_response_cache(max_bytes: u64) -> ResponseCache {
+    Cache::builder()
+        .max_capacity(max_bytes)
+        .weigher(|k: &ResponseCacheKey, v| {
+            // TODO: is this good?
+            if let Ok(v) = serde_json::to_string(v) {
+                let weight = k.weight() + v.len();
+
+                // the or in unwrap_or is probably never called
+                weight.try_into().unwrap_or(u32::MAX)
+            } else {
+                // this seems impossible
+                u32::MAX
+            }
+        })
+        .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default())
+}
+
+/// One cache for each configured partition, keyed by the user tiers that use it.
+/// Tiers in the same partition share a clone of the same cache.
+fn new_response_cache_partitions(
+    partitions: &HashMap<String, ResponseCachePartitionConfig>,
+) -> anyhow::Result<HashMap<String, ResponseCache>> {
+    let mut by_tier = HashMap::new();
+
+    for (name, partition) in partitions.iter() {
+        let cache = new_response_cache(partition.max_bytes);
+
+        for tier in partition.tiers.iter() {
+            if by_tier.insert(tier.clone(), cache.clone()).is_some() {
+                return Err(anyhow::anyhow!(
+                    "user tier {} is in more than one response cache partition. {} is one of them",
+                    tier,
+                    name
+                ));
+            }
+        }
+    }
+
+    Ok(by_tier)
+}
+
 pub type AnyhowJoinHandle<T> = JoinHandle<anyhow::Result<T>>;
 
 #[derive(Clone, Debug, Default, From)]
@@ -140,10 +320,18 @@ pub struct AuthorizationChecks {
     /// database id of the rpc key
     /// if this is None, then this request is being rate limited by ip
     pub rpc_key_id: Option<NonZeroU64>,
-    /// if None, allow unlimited queries. inherited from the user_tier
+    /// if None, allow unlimited queries. inherited from the user_tier unless the key has a custom limit
     pub max_requests_per_period: Option<u64>,
-    // if None, allow unlimited concurrent requests. inherited from the user_tier
+    // if None, allow unlimited concurrent requests. inherited from the user_tier unless the key has a custom limit
     pub max_concurrent_requests: Option<u32>,
+    /// if None, allow unlimited queries across all of the user's keys. inherited from the user_tier
+    pub max_account_requests_per_period: Option<u64>,
+    /// if None, allow unlimited request and response bytes across all of the user's keys. inherited from the user_tier
+    pub max_account_bytes_per_period: Option<u64>,
+    /// when the key's custom limits expire and the user_tier's limits apply again
+    pub limits_expire_at: Option<DateTime<Utc>>,
+    /// None for anonymous users
+    pub user_tier_title: Option<String>,
     /// if None, allow any Origin
     pub allowed_origins: Option<Vec<Origin>>,
     /// if None, allow any Referer
@@ -152,6 +340,23 @@ pub struct AuthorizationChecks {
     pub allowed_user_agents: Option<Vec<UserAgent>>,
     /// if None, allow any IP Address
     pub allowed_ips: Option<Vec<IpNet>>,
+    /// if set, the key is pinned to the first this many ips that use it
+    pub pin_ips: Option<u32>,
+    /// the ips the key is pinned to so far
+    pub pinned_ips: Vec<IpAddr>,
+    /// rejected ips are POSTed here
+    pub alert_webhook_url: Option<String>,
+    /// exceptions to the config's `denied_methods`. set by admins
+    pub allowed_methods: Option<Vec<String>>,
+    /// if None, allow any method that isn't denied. set by the key's owner
+    pub method_allowlist: Option<Vec<String>>,
+    /// if None, deny no methods. set by the key's owner
+    pub method_denylist: Option<Vec<String>>,
+    /// send transactions again if they are not mined after this many blocks. None = never
+    pub rebroadcast_blocks: Option<u32>,
+    /// "latest" reads skip the response cache and are sent to a server unchanged.
+    /// keys cache "latest" by default. they can opt out by setting `cache_latest` to false
+    pub latest_uncached: bool,
     pub log_level: LogLevel,
     /// Chance to save reverting eth_call, eth_estimateGas, and eth_sendRawTransaction to the database.
     /// TODO: f32 would be fine
@@ -178,33 +383,127 @@ pub struct Web3ProxyApp {
     pub balanced_rpcs: Arc<Web3Connections>,
     /// Send private requests (like eth_sendRawTransaction) to all these servers
     pub private_rpcs: Option<Arc<Web3Connections>>,
+    /// Send requests for pruned blocks to these servers
+    pub archive_rpcs: Option<Arc<Web3Connections>>,
+    /// signs requests to the bundle relays
+    bundle_signer: Option<LocalWallet>,
+    /// transactions sent to private_rpcs that have not been included yet. saved in the database
+    pub pending_private_txs: RwLock<HashMap<TxHash, PendingPrivateTx>>,
+    /// transactions sent through this proxy. followed until they are confirmed or dropped
+    tracked_txs: TxTracker,
     response_cache: ResponseCache,
+    /// separate caches for some user tiers, by tier title. other tiers use response_cache
+    response_cache_partitions: HashMap<String, ResponseCache>,
+    /// immutable responses that survive restarts. checked after response_cache
+    disk_cache: Option<Arc<DiskCache>>,
+    /// the channels that frontend subscriptions read from
     // don't drop this or the sender will stop working
-    // TODO: broadcast channel instead?
-    head_block_receiver: watch::Receiver<ArcBlock>,
-    pending_tx_sender: broadcast::Sender<TxStatus>,
+    subscription_sources: Arc<SubscriptionSources>,
+    /// every active frontend subscription
+    pub subscriptions: Arc<SubscriptionRegistry>,
+    /// rpc key ids sent here close every websocket that was authorized by that key
+    pub websocket_kick_sender: broadcast::Sender<NonZeroU64>,
+    /// set by `start_draining`. open connections are allowed to finish before the app shuts down
+    drain: Drain,
+    /// set the first time the readiness probe passes
+    started: AtomicBool,
+    /// the readiness probe's database and redis checks
+    probe_cache: probes::ProbeCache,
     pub config: AppConfig,
+    /// sha256 of the config file that the app was started with
+    pub config_hash: Option<String>,
+    /// when the app was started. used for uptime
+    pub start: Instant,
     pub allowed_lag: u64,
     pub db_conn: Option<sea_orm::DatabaseConnection>,
     pub db_replica: Option<DatabaseReplica>,
     /// prometheus metrics
     app_metrics: Arc<Web3ProxyAppMetrics>,
     open_request_handle_metrics: Arc<OpenRequestHandleMetrics>,
+    /// requests from users and requests from our own tools are counted separately
+    request_counts: RequestCounts,
+    /// requests, errors, and cache hits by method
+    method_metrics: MethodMetrics,
     /// store pending transactions that we've seen so that we don't send duplicates to subscribers
     pub pending_transactions: Cache<TxHash, TxStatus, hashbrown::hash_map::DefaultHashBuilder>,
     pub frontend_ip_rate_limiter: Option<DeferredRateLimiter<IpAddr>>,
+    /// optional checks that can reduce the limits for anonymous users
+    pub ip_reputation: Option<IpReputationChecker>,
+    /// alerts for ips that a key rejected
+    pub ip_pin_alerts: IpPinAlerts,
+    /// asked about rpc keys that aren't in the database
+    pub external_auth: Option<ExternalAuthorizer>,
+    /// risky behaviors that can be toggled at runtime
+    pub feature_flags: Arc<FeatureFlags>,
+    /// rate limit each rpc key
     pub frontend_registered_user_rate_limiter: Option<DeferredRateLimiter<u64>>,
+    /// rate limit the sum of all of a user's rpc keys
+    pub frontend_account_rate_limiter: Option<DeferredRateLimiter<u64>>,
+    /// rate limit the request and response bytes of all of a user's rpc keys
+    pub frontend_bandwidth_rate_limiter: Option<DeferredRateLimiter<u64>>,
     pub login_rate_limiter: Option<RedisRateLimiter>,
     pub vredis_pool: Option<RedisPool>,
     // TODO: this key should be our RpcSecretKey class, not Ulid
     pub rpc_secret_key_cache:
         Cache<Ulid, AuthorizationChecks, hashbrown::hash_map::DefaultHashBuilder>,
+    /// signed requests send the rpc key's id instead of the secret
+    pub rpc_key_id_cache: Cache<u64, Option<RpcSecretKey>, hashbrown::hash_map::DefaultHashBuilder>,
     pub registered_user_semaphores:
         Cache<NonZeroU64, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
     pub ip_semaphores: Cache<IpAddr, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
     pub bearer_token_semaphores:
         Cache<UserBearerToken, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
-    pub stat_sender: Option<flume::Sender<Web3ProxyStat>>,
+    /// transactions use this instead of the ip and user semaphores while it has permits
+    pub tx_semaphore: Arc<Semaphore>,
+    /// how many transactions each ip or user has in the priority lane
+    pub tx_client_semaphores:
+        Cache<ConcurrencyKey, Arc<Semaphore>, hashbrown::hash_map::DefaultHashBuilder>,
+    /// how long each relay takes to answer a transaction
+    pub relay_latencies: Arc<RelayLatencies>,
+    /// prepaid credits by user id. only used if `credits` is configured
+    pub balances: Balances,
+    /// only used if `gas_price` is configured
+    gas_price_cache: Option<GasPriceCache>,
+    pub stat_sender: Option<StatSender>,
+}
+
+/// Counts of frontend requests by `AuthorizationType` and by `RequestTransport`.
+#[derive(Default, Serialize)]
+struct RequestCounts {
+    frontend: AtomicU64,
+    internal: AtomicU64,
+    http: AtomicU64,
+    websocket: AtomicU64,
+    /// the client disconnected before we finished
+    abandoned: AtomicU64,
+}
+
+/// Counts a request as abandoned if it is dropped before `finish` is called.
+/// When a client disconnects, axum drops the handler's future and, with it, any upstream requests and semaphore permits.
+struct AbandonedRequestGuard<'a> {
+    counter: &'a AtomicU64,
+    finished: bool,
+}
+
+impl<'a> AbandonedRequestGuard<'a> {
+    fn new(counter: &'a AtomicU64) -> Self {
+        Self {
+            counter,
+            finished: false,
+        }
+    }
+
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for AbandonedRequestGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.counter.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
 }
 
 /// flatten a JoinError into an anyhow error
@@ -218,7 +517,6 @@ pub async fn flatten_handle<T>(handle: AnyhowJoinHandle<T>) -> anyhow::Result<T>
 }
 
 /// return the first error or okay if everything worked
-
 pub async fn flatten_handles<T>(
     mut handles: FuturesUnordered<AnyhowJoinHandle<T>>,
 ) -> anyhow::Result<()> {
@@ -343,6 +641,21 @@ impl Web3ProxyApp {
             );
         }
 
+        for (origin, template) in top_config.app.allowed_origin_redirect_public_urls.iter() {
+            handlebars::Template::compile(template)
+                .context(format!("bad redirect template for {}", origin))?;
+        }
+
+        if let Some(prefix) = &top_config.app.rpc_key_prefix {
+            assert!(
+                prefix.ends_with('_')
+                    && prefix
+                        .chars()
+                        .all(|x| x.is_ascii_alphanumeric() || x == '_'),
+                "rpc_key_prefix must only contain letters, numbers, and '_' and must end with '_'"
+            );
+        }
+
         if !top_config.extra.is_empty() {
             warn!(
                 "unknown TopConfig fields!: {:?}",
@@ -442,6 +755,24 @@ impl Web3ProxyApp {
         }
 
         let private_rpcs = top_config.private_rpcs.unwrap_or_default();
+        let archive_rpcs = top_config.archive_rpcs.unwrap_or_default();
+
+        // safety check on pricing tables. a typo would silently leave a provider unpriced
+        for (name, x) in balanced_rpcs
+            .iter()
+            .chain(private_rpcs.iter())
+            .chain(archive_rpcs.iter())
+        {
+            if let Some(pricing) = x.pricing.as_ref() {
+                if !top_config.app.provider_pricing.contains_key(pricing) {
+                    return Err(anyhow::anyhow!(
+                        "rpc {} uses pricing {:?}, but it is not in app.provider_pricing",
+                        name,
+                        pricing
+                    ));
+                }
+            }
+        }
 
         // these are safe to cancel
         let cancellable_handles = FuturesUnordered::new();
@@ -459,6 +790,24 @@ impl Web3ProxyApp {
                 .build()?,
         );
 
+        // optionally check the reputation of anonymous users
+        let ip_reputation = top_config
+            .app
+            .ip_reputation
+            .as_ref()
+            .map(|x| IpReputationChecker::try_new(x, http_client.clone()))
+            .transpose()?;
+
+        let ip_pin_alerts = IpPinAlerts::try_new()?;
+
+        // optionally bridge another system's rpc keys
+        let external_auth = top_config
+            .app
+            .external_auth
+            .as_ref()
+            .map(|x| ExternalAuthorizer::try_new(x, top_config.app.chain_id, http_client.clone()))
+            .transpose()?;
+
         // create a connection pool for redis
         // a failure to connect does NOT block the application from starting
         let vredis_pool = match top_config.app.volatile_redis_url.as_ref() {
@@ -497,15 +846,36 @@ impl Web3ProxyApp {
 
         // setup a channel for receiving stats (generally with a high cardinality, such as per-user)
         // we do this in a channel so we don't slow down our response to the users
-        let stat_sender = if let Some(db_conn) = db_conn.clone() {
-            let emitter_spawn =
-                StatEmitter::spawn(top_config.app.chain_id, db_conn, 60, shutdown_receiver)?;
+        // stats go to mysql, influxdb, or both
+        let influxdb = top_config
+            .influxdb
+            .as_ref()
+            .map(|x| {
+                InfluxDb::try_new(
+                    x,
+                    top_config.app.instance_name.clone(),
+                    http_client.clone().unwrap_or_default(),
+                )
+            })
+            .transpose()?;
+
+        let stat_sender = if db_conn.is_some() || influxdb.is_some() {
+            let emitter_spawn = StatEmitter::spawn(
+                top_config.app.chain_id,
+                db_conn.clone(),
+                influxdb,
+                60,
+                top_config.app.rpc_key_dormant_days,
+                top_config.app.stat_rollups.clone(),
+                top_config.app.stat_queue_max,
+                shutdown_receiver,
+            )?;
 
             important_background_handles.push(emitter_spawn.background_handle);
 
             Some(emitter_spawn.stat_sender)
         } else {
-            warn!("cannot store stats without a database connection");
+            warn!("cannot store stats without a database connection or influxdb");
 
             // TODO: subscribe to the shutdown_receiver here since the stat emitter isn't running?
 
@@ -525,9 +895,12 @@ impl Web3ProxyApp {
 
         // TODO: capacity from configs
         // all these are the same size, so no need for a weigher
-        // TODO: ttl on this? or is max_capacity fine?
+        // pending transactions are only useful briefly. they are either mined or dropped
         let pending_transactions = Cache::builder()
             .max_capacity(10_000)
+            .time_to_live(Duration::from_secs(
+                top_config.app.pending_tx_cache_seconds.unwrap_or(600),
+            ))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
         // keep 1GB of blocks in the cache
@@ -555,7 +928,10 @@ impl Web3ProxyApp {
             top_config.app.min_synced_rpcs,
             Some(pending_tx_sender.clone()),
             pending_transactions.clone(),
+            top_config.app.pending_tx_cache_seconds.is_some(),
             open_request_handle_metrics.clone(),
+            top_config.app.validate_responses,
+            top_config.app.backend_queue.clone(),
         )
         .await
         .context("spawning balanced rpcs")?;
@@ -576,7 +952,7 @@ impl Web3ProxyApp {
                 private_rpcs,
                 http_client.clone(),
                 vredis_pool.clone(),
-                block_map,
+                block_map.clone(),
                 // subscribing to new heads here won't work well. if they are fast, they might be ahead of balanced_rpcs
                 None,
                 0,
@@ -584,12 +960,15 @@ impl Web3ProxyApp {
                 // TODO: subscribe to pending transactions on the private rpcs? they seem to have low rate limits
                 None,
                 pending_transactions.clone(),
+                false,
                 open_request_handle_metrics.clone(),
+                top_config.app.validate_responses,
+                None,
             )
             .await
             .context("spawning private_rpcs")?;
 
-            if private_rpcs.conns.is_empty() {
+            if private_rpcs.conns.load().is_empty() {
                 None
             } else {
                 // save the handle to catch any errors
@@ -599,10 +978,69 @@ impl Web3ProxyApp {
             }
         };
 
+        // connect to the archive rpcs
+        // these are optional and only used when the balanced rpcs have pruned a block
+        let archive_rpcs = if archive_rpcs.is_empty() {
+            None
+        } else {
+            let (archive_rpcs, archive_handle) = Web3Connections::spawn(
+                top_config.app.chain_id,
+                db_conn.clone(),
+                archive_rpcs,
+                http_client.clone(),
+                vredis_pool.clone(),
+                block_map,
+                // the archive rpcs follow their own heads so that they know how far back they can serve.
+                // nothing watches this head. the balanced rpcs still decide the app's head block
+                Some(watch::channel(Arc::new(Block::default())).0),
+                0,
+                0,
+                None,
+                pending_transactions.clone(),
+                false,
+                open_request_handle_metrics.clone(),
+                top_config.app.validate_responses,
+                None,
+            )
+            .await
+            .context("spawning archive_rpcs")?;
+
+            if archive_rpcs.conns.load().is_empty() {
+                None
+            } else {
+                // save the handle to catch any errors
+                cancellable_handles.push(archive_handle);
+
+                Some(archive_rpcs)
+            }
+        };
+
+        if let Some(simulation) = top_config.app.simulation.as_ref() {
+            balanced_rpcs.track_method_support(&simulation.methods);
+
+            if let Some(archive_rpcs) = archive_rpcs.as_ref() {
+                archive_rpcs.track_method_support(&simulation.methods);
+            }
+        }
+
+        let feature_flags = Arc::new(FeatureFlags::new(
+            top_config.app.chain_id,
+            top_config.features.clone(),
+            vredis_pool.clone(),
+        ));
+
+        if let Err(err) = feature_flags.refresh().await {
+            warn!("unable to load feature flag overrides. err={:?}", err);
+        }
+
+        cancellable_handles.push(feature_flags.clone().spawn());
+
         // create rate limiters
         // these are optional. they require redis
         let mut frontend_ip_rate_limiter = None;
         let mut frontend_registered_user_rate_limiter = None;
+        let mut frontend_account_rate_limiter = None;
+        let mut frontend_bandwidth_rate_limiter = None;
         let mut login_rate_limiter = None;
 
         if let Some(redis_pool) = vredis_pool.as_ref() {
@@ -618,7 +1056,7 @@ impl Web3ProxyApp {
                 redis_pool.clone(),
             );
 
-            // these two rate limiters can share the base limiter
+            // these rate limiters can share the base limiter
             // these are deferred rate limiters because we don't want redis network requests on the hot path
             // TODO: take cache_size from config
             frontend_ip_rate_limiter = Some(DeferredRateLimiter::<IpAddr>::new(
@@ -628,7 +1066,22 @@ impl Web3ProxyApp {
                 None,
             ));
             frontend_registered_user_rate_limiter = Some(DeferredRateLimiter::<u64>::new(
-                10_000, "key", rpc_rrl, None,
+                10_000,
+                "key",
+                rpc_rrl.clone(),
+                None,
+            ));
+            frontend_account_rate_limiter = Some(DeferredRateLimiter::<u64>::new(
+                10_000,
+                "account",
+                rpc_rrl.clone(),
+                None,
+            ));
+            frontend_bandwidth_rate_limiter = Some(DeferredRateLimiter::<u64>::new(
+                10_000,
+                "bandwidth",
+                rpc_rrl,
+                None,
             ));
 
             login_rate_limiter = Some(RedisRateLimiter::new(
@@ -641,24 +1094,22 @@ impl Web3ProxyApp {
         }
 
         // keep 1GB of blocks in the cache
-        // responses can be very different in sizes, so this definitely needs a weigher
         // TODO: max_capacity from config
-        // TODO: don't allow any response to be bigger than X% of the cache
-        let response_cache = Cache::builder()
-            .max_capacity(1024 * 1024 * 1024)
-            .weigher(|k: &ResponseCacheKey, v| {
-                // TODO: is this good?
-                if let Ok(v) = serde_json::to_string(v) {
-                    let weight = k.weight() + v.len();
+        let response_cache = new_response_cache(1024 * 1024 * 1024);
 
-                    // the or in unwrap_or is probably never called
-                    weight.try_into().unwrap_or(u32::MAX)
-                } else {
-                    // this seems impossible
-                    u32::MAX
-                }
-            })
-            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+        let response_cache_partitions =
+            new_response_cache_partitions(&top_config.app.response_cache_partitions)?;
+
+        let disk_cache = match top_config.app.disk_cache.as_ref() {
+            Some(config) => {
+                let disk_cache = Arc::new(DiskCache::open(config)?);
+
+                cancellable_handles.push(disk_cache.clone().spawn_evictor());
+
+                Some(disk_cache)
+            }
+            None => None,
+        };
 
         // all the users are the same size, so no need for a weigher
         // if there is no database of users, there will be no keys and so this will be empty
@@ -668,6 +1119,10 @@ impl Web3ProxyApp {
             .max_capacity(10_000)
             .time_to_live(Duration::from_secs(600))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+        let rpc_key_id_cache = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(Duration::from_secs(600))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
         // create semaphores for concurrent connection limits
         // TODO: what should tti be for semaphores?
@@ -680,6 +1135,9 @@ impl Web3ProxyApp {
         let registered_user_semaphores = Cache::builder()
             .time_to_idle(Duration::from_secs(120))
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+        let tx_client_semaphores = Cache::builder()
+            .time_to_idle(Duration::from_secs(120))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
         // TODO: get this out of the toml instead
         let allowed_lag = match top_config.app.chain_id {
@@ -692,39 +1150,135 @@ impl Web3ProxyApp {
             }
         };
 
+        let subscription_sources = SubscriptionSources {
+            head_block_receiver,
+            pending_tx_sender,
+            reorg_sender: balanced_rpcs.reorg_sender.clone(),
+            balanced_rpcs: balanced_rpcs.clone(),
+        };
+
+        // this is a reputation key for the relays. it doesn't need funds
+        let bundle_signer = top_config
+            .app
+            .bundles
+            .as_ref()
+            .map(|x| x.signing_key.parse::<LocalWallet>())
+            .transpose()
+            .context("invalid bundles.signing_key")?
+            .map(|x| x.with_chain_id(top_config.app.chain_id));
+
+        // the config is moved into the app below
+        let tx_semaphore = Arc::new(Semaphore::new(top_config.app.max_concurrent_txs));
+
+        let gas_price_cache = top_config.app.gas_price.as_ref().map(GasPriceCache::new);
+
+        let method_metrics = MethodMetrics::new(&top_config.app);
+
         let app = Self {
             config: top_config.app,
+            config_hash: top_config.config_hash,
+            start: Instant::now(),
             allowed_lag,
             balanced_rpcs,
             private_rpcs,
+            archive_rpcs,
+            bundle_signer,
+            pending_private_txs: Default::default(),
+            tracked_txs: Default::default(),
             response_cache,
-            head_block_receiver,
-            pending_tx_sender,
+            response_cache_partitions,
+            disk_cache,
+            subscription_sources: Arc::new(subscription_sources),
+            subscriptions: Default::default(),
+            websocket_kick_sender: broadcast::channel(16).0,
+            drain: Default::default(),
+            started: Default::default(),
+            probe_cache: Default::default(),
             pending_transactions,
             frontend_ip_rate_limiter,
+            ip_reputation,
+            ip_pin_alerts,
+            external_auth,
+            feature_flags,
             frontend_registered_user_rate_limiter,
+            frontend_account_rate_limiter,
+            frontend_bandwidth_rate_limiter,
             login_rate_limiter,
             db_conn,
             db_replica,
             vredis_pool,
             app_metrics,
             open_request_handle_metrics,
+            request_counts: Default::default(),
+            method_metrics,
             rpc_secret_key_cache,
+            rpc_key_id_cache,
             bearer_token_semaphores,
             ip_semaphores,
             registered_user_semaphores,
+            tx_semaphore,
+            tx_client_semaphores,
+            relay_latencies: Default::default(),
+            balances: Default::default(),
+            gas_price_cache,
             stat_sender,
         };
 
         let app = Arc::new(app);
 
+        if app.private_rpcs.is_some() {
+            match app.load_private_txs().await {
+                Ok(x) => info!("loaded {} pending private txs", x),
+                Err(err) => warn!("unable to load pending private txs. err={:?}", err),
+            }
+
+            cancellable_handles.push(app.clone().spawn_private_tx_pruner());
+        }
+
+        cancellable_handles.push(app.clone().spawn_sync_watcher());
+
+        cancellable_handles.push(app.clone().spawn_tx_tracker());
+
+        if app.config.chain_halt.is_some() {
+            cancellable_handles.push(app.clone().spawn_chain_halt_watcher());
+        }
+
+        if app.config.credits.is_some() {
+            if app.db_conn.is_some() {
+                cancellable_handles.push(app.clone().spawn_credits_saver());
+                cancellable_handles.push(app.clone().spawn_deposit_watcher());
+            } else {
+                warn!("credits need a database. requests will be free");
+            }
+        }
+
         Ok((app, cancellable_handles, important_background_handles).into())
     }
 
+    /// Requests to every backend rpc and what they cost. Most expensive first.
+    pub async fn upstream_costs(&self) -> Vec<RpcCosts> {
+        let mut costs = vec![];
+
+        for (group, rpcs) in self.rpc_groups() {
+            costs.extend(rpcs.costs(group, &self.config.provider_pricing).await);
+        }
+
+        sort_rpc_costs(&mut costs);
+
+        costs
+    }
+
     pub async fn prometheus_metrics(&self) -> String {
-        let globals = HashMap::new();
-        // TODO: what globals? should this be the hostname or what?
-        // globals.insert("service", "web3_proxy");
+        // these labels let multi-chain fleets slice dashboards without relabeling in the scrape config
+        let chain_id = self.config.chain_id.to_string();
+
+        let mut globals = HashMap::new();
+        globals.insert("chain_id", chain_id.as_str());
+        globals.insert("instance", self.config.instance_name.as_str());
+        globals.insert(
+            "deployment_environment",
+            self.config.deployment_environment.as_str(),
+        );
 
         #[derive(Default, Serialize)]
         struct UserCount(i64);
@@ -874,25 +1428,91 @@ impl Web3ProxyApp {
         struct CombinedMetrics<'a> {
             app: &'a Web3ProxyAppMetrics,
             backend_rpc: &'a OpenRequestHandleMetrics,
+            backend_queue: &'a BackendQueueMetrics,
+            tx_tracking: &'a TxTrackerMetrics,
+            /// milliseconds from sending a transaction to each relay's answer
+            tx_relay_latency: &'a RelayLatencies,
+            requests: &'a RequestCounts,
+            /// requests, errors, and cache hits labeled by method
+            method: &'a MethodMetrics,
+            /// 0 unless the chain looks halted
+            chain_halted_seconds: u64,
+            /// queue depth, drops, and the last save of the stat loop
+            stats: Option<StatEmitterHealth<'a>>,
             recent_ip_counts: RecentCounts,
             recent_user_id_counts: RecentCounts,
             recent_tx_counts: RecentCounts,
             user_count: UserCount,
+            /// requests, compute units, and dollars by group and rpc
+            upstream_costs: HashMap<&'static str, HashMap<String, Cost>>,
+            /// latency histograms, error counts, and head block lag by group and rpc
+            backend: HashMap<&'static str, HashMap<String, BackendRpcMetrics>>,
+        }
+
+        let mut upstream_costs: HashMap<_, HashMap<_, _>> = HashMap::new();
+
+        for x in self.upstream_costs().await {
+            upstream_costs
+                .entry(x.group)
+                .or_default()
+                .insert(x.name, x.total);
         }
 
+        let consensus_head_num = self.balanced_rpcs.head_block_num();
+
+        let backend = self
+            .rpc_groups()
+            .into_iter()
+            .map(|(group, rpcs)| (group, rpcs.backend_metrics(consensus_head_num)))
+            .collect();
+
         let metrics = CombinedMetrics {
             app: &self.app_metrics,
             backend_rpc: &self.open_request_handle_metrics,
+            backend_queue: &self.balanced_rpcs.backend_queue.metrics,
+            tx_tracking: &self.tracked_txs.metrics,
+            tx_relay_latency: &self.relay_latencies,
+            requests: &self.request_counts,
+            method: &self.method_metrics,
+            chain_halted_seconds: self.chain_halted().unwrap_or(0),
+            stats: self.stat_sender.as_ref().map(|x| x.health()),
             recent_ip_counts,
             recent_user_id_counts,
             recent_tx_counts,
             user_count,
+            upstream_costs,
+            backend,
         };
 
         serde_prometheus::to_string(&metrics, Some("web3_proxy"), globals)
             .expect("prometheus metrics should always serialize")
     }
 
+    /// The backend latency histograms in the OpenMetrics format, with trace ids as exemplars.
+    /// These have the same names and labels as in `prometheus_metrics`.
+    pub fn latency_openmetrics(&self) -> String {
+        let chain_id = self.config.chain_id.to_string();
+
+        let labels = [
+            ("chain_id", chain_id.as_str()),
+            (
+                "deployment_environment",
+                self.config.deployment_environment.as_str(),
+            ),
+            ("instance", self.config.instance_name.as_str()),
+        ];
+
+        let consensus_head_num = self.balanced_rpcs.head_block_num();
+
+        let backend = self
+            .rpc_groups()
+            .into_iter()
+            .map(|(group, rpcs)| (group, rpcs.backend_metrics(consensus_head_num)))
+            .collect();
+
+        latency_openmetrics("web3_proxy", &labels, &backend)
+    }
+
     /// send the request or batch of requests to the approriate RPCs
     pub async fn proxy_web3_rpc(
         self: &Arc<Self>,
@@ -905,33 +1525,193 @@ impl Web3ProxyApp {
 
         // even though we have timeouts on the requests to our backend providers,
         // we need a timeout for the incoming request so that retries don't run forever
-        // TODO: take this as an optional argument. per user max? expiration time instead of duration?
-        let max_time = Duration::from_secs(120);
+        // TODO: per user max?
+        let max_time = self.request_timeout();
 
-        let response = match request {
+        // routing and backend requests are children of this span
+        let mut span_attributes = match &request {
             JsonRpcRequestEnum::Single(request) => {
-                let (response, rpcs) = timeout(
-                    max_time,
-                    self.proxy_web3_rpc_request(&authorization, request),
-                )
-                .await??;
+                vec![KeyValue::new("rpc.method", request.method.clone())]
+            }
+            JsonRpcRequestEnum::Batch(requests) => vec![KeyValue::new(
+                "web3_proxy.batch_size",
+                requests.len() as i64,
+            )],
+        };
+
+        if let Some(rpc_key_id) = authorization.checks.rpc_key_id {
+            span_attributes.push(KeyValue::new(
+                "web3_proxy.rpc_key_id",
+                rpc_key_id.get() as i64,
+            ));
+        }
+
+        let span_cx = telemetry::start_span("proxy_web3_rpc", span_attributes);
+
+        match authorization.authorization_type {
+            AuthorizationType::Frontend => &self.request_counts.frontend,
+            AuthorizationType::Internal => &self.request_counts.internal,
+        }
+        .fetch_add(1, atomic::Ordering::Relaxed);
 
-                (JsonRpcForwardedResponseEnum::Single(response), rpcs)
+        match authorization.transport {
+            RequestTransport::Http => &self.request_counts.http,
+            RequestTransport::Websocket => &self.request_counts.websocket,
+        }
+        .fetch_add(1, atomic::Ordering::Relaxed);
+
+        if authorization.checks.rpc_key_id.is_some()
+            && self.config.stat_queue_full == StatQueueFull::Reject
+            && self
+                .stat_sender
+                .as_ref()
+                .map(|x| x.is_full())
+                .unwrap_or(false)
+        {
+            // these requests are billed. refusing them is better than serving them for free
+            return Err(FrontendErrorResponse::StatusCode(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "stats are backed up. try again soon".to_string(),
+                None,
+            ));
+        }
+
+        if let JsonRpcRequestEnum::Batch(requests) = &request {
+            if requests.is_empty() {
+                return Err(FrontendErrorResponse::StatusCode(
+                    StatusCode::BAD_REQUEST,
+                    "empty batch".to_string(),
+                    None,
+                ));
             }
-            JsonRpcRequestEnum::Batch(requests) => {
-                let (responses, rpcs) = timeout(
-                    max_time,
-                    self.proxy_web3_rpc_requests(&authorization, requests),
-                )
-                .await??;
 
-                (JsonRpcForwardedResponseEnum::Batch(responses), rpcs)
+            if requests.len() > self.config.max_batch_size {
+                return Err(FrontendErrorResponse::StatusCode(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "batch of {} is too large. max is {}",
+                        requests.len(),
+                        self.config.max_batch_size
+                    ),
+                    None,
+                ));
+            }
+        }
+
+        // authorizing the request already counted it once. expensive methods count more
+        let compute_units = match &request {
+            JsonRpcRequestEnum::Single(request) => self.method_cost(&request.method),
+            JsonRpcRequestEnum::Batch(requests) => {
+                requests.iter().map(|x| self.method_cost(&x.method)).sum()
             }
         };
 
+        match self
+            .rate_limit_compute_units(&authorization, compute_units)
+            .await
+        {
+            DeferredRateLimitResult::Allowed => {}
+            DeferredRateLimitResult::RetryAt(retry_at) => {
+                return Err(FrontendErrorResponse::RateLimited(
+                    Box::new((*authorization).clone()),
+                    Some(retry_at),
+                ));
+            }
+            DeferredRateLimitResult::RetryNever => {
+                return Err(FrontendErrorResponse::RateLimited(
+                    Box::new((*authorization).clone()),
+                    None,
+                ));
+            }
+        }
+
+        self.spend_credits(&authorization, compute_units).await?;
+
+        // only measure the request if it will be charged. serializing is not free
+        let request_bytes =
+            authorization
+                .checks
+                .max_account_bytes_per_period
+                .map(|_| match &request {
+                    JsonRpcRequestEnum::Single(request) => request.num_bytes(),
+                    JsonRpcRequestEnum::Batch(requests) => {
+                        requests.iter().map(|x| x.num_bytes()).sum()
+                    }
+                });
+
+        let abandoned = AbandonedRequestGuard::new(&self.request_counts.abandoned);
+
+        let response = async {
+            let response = match request {
+                JsonRpcRequestEnum::Single(request) => {
+                    let (response, rpcs) = timeout(
+                        max_time,
+                        self.proxy_web3_rpc_request(&authorization, request),
+                    )
+                    .await?
+                    .map_err(frontend_error)?;
+
+                    (JsonRpcForwardedResponseEnum::Single(response), rpcs)
+                }
+                JsonRpcRequestEnum::Batch(requests) => {
+                    let (responses, rpcs) = timeout(
+                        max_time,
+                        self.proxy_web3_rpc_requests(&authorization, requests),
+                    )
+                    .await??;
+
+                    (JsonRpcForwardedResponseEnum::Batch(responses), rpcs)
+                }
+            };
+
+            Ok::<_, FrontendErrorResponse>(response)
+        }
+        .with_context(span_cx.clone())
+        .await;
+
+        if let Err(err) = &response {
+            telemetry::set_span_error(&span_cx, err);
+        }
+
+        // the client was still connected when we finished (even if we finished with an error)
+        abandoned.finish();
+
+        let mut response = response?;
+
+        if let Some(age) = self.chain_halted() {
+            // reads still work, but clients should know that they are getting old data
+            match &mut response.0 {
+                JsonRpcForwardedResponseEnum::Single(response) => {
+                    response.add_data("staleSeconds", json!(age))
+                }
+                JsonRpcForwardedResponseEnum::Batch(responses) => {
+                    for response in responses.iter_mut() {
+                        response.add_data("staleSeconds", json!(age));
+                    }
+                }
+            }
+        }
+
+        if let Some(request_bytes) = request_bytes {
+            let response_bytes = match &response.0 {
+                JsonRpcForwardedResponseEnum::Single(response) => response.num_bytes(),
+                JsonRpcForwardedResponseEnum::Batch(responses) => {
+                    responses.iter().map(|x| x.num_bytes()).sum()
+                }
+            };
+
+            self.charge_bandwidth(&authorization, (request_bytes + response_bytes) as u64)
+                .await;
+        }
+
         Ok(response)
     }
 
+    /// How long a client request may take, including retries.
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.request_timeout_seconds)
+    }
+
     /// cut up the request and send to potentually different servers
     /// TODO: make sure this isn't a problem
     async fn proxy_web3_rpc_requests(
@@ -942,33 +1722,128 @@ impl Web3ProxyApp {
         // TODO: we should probably change ethers-rs to support this directly. they pushed this off to v2 though
         let num_requests = requests.len();
 
-        // TODO: spawn so the requests go in parallel? need to think about rate limiting more if we do that
-        // TODO: improve flattening
-        let responses = join_all(
-            requests
-                .into_iter()
-                .map(|request| self.proxy_web3_rpc_request(authorization, request))
-                .collect::<Vec<_>>(),
-        )
-        .await;
+        // a batch doesn't get to run more requests at once than the user could run with separate requests
+        let max_concurrent = match authorization.checks.rpc_key_id {
+            Some(_) => authorization
+                .checks
+                .max_concurrent_requests
+                .map(|x| x as usize),
+            None => self.config.public_max_concurrent_requests,
+        }
+        .unwrap_or(num_requests)
+        .clamp(1, num_requests.max(1));
+
+        // every request in the batch past the first needs its own permit from the key's or ip's semaphore
+        let batch_permits = self.batch_permits(authorization, max_concurrent - 1);
+
+        let max_concurrent = match batch_permits.as_ref() {
+            Some(x) => x.len() + 1,
+            None => max_concurrent,
+        };
+
+        // `buffered` keeps the responses in the same order as the requests
+        let mut responses = stream::iter(requests)
+            .map(|request| async move {
+                let id = request.id.clone();
+
+                (
+                    id,
+                    self.proxy_web3_rpc_request(authorization, request).await,
+                )
+            })
+            .buffered(max_concurrent);
 
-        // TODO: i'm sure this could be done better with iterators
         // TODO: stream the response?
         let mut collected: Vec<JsonRpcForwardedResponse> = Vec::with_capacity(num_requests);
         let mut collected_rpcs: HashSet<Arc<Web3Connection>> = HashSet::new();
-        for response in responses {
-            // TODO: any way to attach the tried rpcs to the error? it is likely helpful
-            let (response, rpcs) = response?;
-
-            collected.push(response);
-            collected_rpcs.extend(rpcs.into_iter());
+        while let Some((id, response)) = responses.next().await {
+            match response {
+                Ok((response, rpcs)) => {
+                    collected.push(response);
+                    collected_rpcs.extend(rpcs);
+                }
+                Err(err) if err.is::<NoServersSynced>() => {
+                    collected.push(JsonRpcForwardedResponse::from_str(
+                        NO_SERVERS_SYNCED_MESSAGE,
+                        Some(NO_SERVERS_SYNCED_ERROR_CODE),
+                        Some(id),
+                    ));
+                }
+                Err(err) => {
+                    // one failed request doesn't fail the rest of the batch
+                    // TODO: any way to attach the tried rpcs to the error? it is likely helpful
+                    collected.push(JsonRpcForwardedResponse::from_anyhow_error(
+                        err,
+                        None,
+                        Some(id),
+                    ));
+                }
+            }
         }
 
+        drop(batch_permits);
+
         let collected_rpcs: Vec<_> = collected_rpcs.into_iter().collect();
 
         Ok((collected, collected_rpcs))
     }
 
+    /// How many requests `method` counts as. The longest matching pattern in the config's `method_costs` wins.
+    pub fn method_cost(&self, method: &str) -> u64 {
+        self.config
+            .method_costs
+            .iter()
+            .filter(|(pattern, _)| method_matches(pattern, method))
+            .max_by_key(|(pattern, _)| (!pattern.ends_with('*'), pattern.len()))
+            .map(|(_, cost)| *cost)
+            .unwrap_or(1)
+    }
+
+    /// Methods in the config's `denied_methods` are only allowed for keys that an admin has granted them to.
+    /// So are simulations when `simulation.require_grant` is set.
+    /// The key's owner can narrow the rest down with `method_allowlist` and `method_denylist`.
+    fn method_is_allowed(&self, authorization: &Authorization, method: &str) -> bool {
+        let checks = &authorization.checks;
+
+        // None if the key doesn't have the list
+        let listed = |patterns: &Option<Vec<String>>| {
+            patterns
+                .as_ref()
+                .map(|x| x.iter().any(|x| method_matches(x, method)))
+        };
+
+        let needs_grant = self
+            .config
+            .denied_methods
+            .iter()
+            .any(|x| method_matches(x, method))
+            || self.simulation_needs_grant(method);
+
+        if needs_grant && listed(&checks.allowed_methods) != Some(true) {
+            return false;
+        }
+
+        if listed(&checks.method_allowlist) == Some(false) {
+            return false;
+        }
+
+        listed(&checks.method_denylist) != Some(true)
+    }
+
+    /// With `read_only` anonymous access, only requests with a key can send transactions and bundles.
+    fn method_needs_key(&self, authorization: &Authorization, method: &str) -> bool {
+        self.config.anonymous_access == AnonymousAccess::ReadOnly
+            && matches!(
+                authorization.authorization_type,
+                AuthorizationType::Frontend
+            )
+            && authorization.checks.rpc_key_id.is_none()
+            && matches!(
+                method,
+                "eth_sendRawTransaction" | "eth_sendBundle" | "eth_cancelBundle"
+            )
+    }
+
     /// TODO: i don't think we want or need this. just use app.db_conn, or maybe app.db_conn.clone() or app.db_conn.as_ref()
     pub fn db_conn(&self) -> Option<DatabaseConnection> {
         self.db_conn.clone()
@@ -991,14 +1866,93 @@ impl Web3ProxyApp {
     }
 
     #[measure([ErrorCount, HitCount, ResponseTime, Throughput])]
+    /// Count the request and any error by method
     async fn proxy_web3_rpc_request(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: JsonRpcRequest,
+    ) -> anyhow::Result<(JsonRpcForwardedResponse, Vec<Arc<Web3Connection>>)> {
+        let counters = self.method_metrics.get(&request.method);
+
+        counters.requests.fetch_add(1, atomic::Ordering::Relaxed);
+
+        // only methods that we accept and that a backend knows get a label
+        let unlabeled_method = self
+            .method_metrics
+            .is_other(&counters)
+            .then(|| request.method.clone());
+
+        let response = self
+            .proxy_web3_rpc_request_uncounted(authorization, request)
+            .await;
+
+        let failed = match &response {
+            Ok((response, _)) => response.error.is_some(),
+            Err(_) => true,
+        };
+
+        if failed {
+            counters.errors.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        if let (Some(method), Ok((response, _))) = (unlabeled_method, &response) {
+            let unknown = response
+                .error
+                .as_ref()
+                .map(|x| is_method_not_found(x) || x.code == METHOD_NOT_ALLOWED_ERROR_CODE)
+                .unwrap_or(false);
+
+            if !unknown {
+                self.method_metrics.label(&method);
+            }
+        }
+
+        response
+    }
+
+    async fn proxy_web3_rpc_request_uncounted(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
         mut request: JsonRpcRequest,
     ) -> anyhow::Result<(JsonRpcForwardedResponse, Vec<Arc<Web3Connection>>)> {
         // trace!("Received request: {:?}", request);
 
-        let request_metadata = Arc::new(RequestMetadata::new(REQUEST_PERIOD, request.num_bytes())?);
+        if !self.method_is_allowed(authorization, &request.method) {
+            // a normal jsonrpc error so that the rest of a batch still runs
+            // TODO: client error stat
+            let response = JsonRpcForwardedResponse::from_string(
+                format!("method not allowed: {}", request.method),
+                Some(METHOD_NOT_ALLOWED_ERROR_CODE),
+                Some(request.id),
+            );
+
+            return Ok((response, vec![]));
+        }
+
+        if self.method_needs_key(authorization, &request.method) {
+            let response = JsonRpcForwardedResponse::from_string(
+                format!("an rpc key is required for {}", request.method),
+                Some(METHOD_NOT_ALLOWED_ERROR_CODE),
+                Some(request.id),
+            );
+
+            return Ok((response, vec![]));
+        }
+
+        if let Some(response) = self.method_stub(authorization, &request) {
+            // operator-defined responses never touch a backend. no stats on these
+            return Ok((response, vec![]));
+        }
+
+        // the backends get whatever time is left after queueing and choosing a server
+        let request_metadata = Arc::new(
+            RequestMetadata::new(REQUEST_PERIOD, request.num_bytes())?
+                .with_deadline(self.method_timeout(&request.method)),
+        );
+
+        request_metadata
+            .compute_units
+            .store(self.method_cost(&request.method), atomic::Ordering::Release);
 
         // save the id so we can attach it to the response
         // TODO: instead of cloning, take the id out?
@@ -1009,13 +1963,7 @@ impl Web3ProxyApp {
         // TODO: don't clone?
         let partial_response: serde_json::Value = match request_method.as_ref() {
             // lots of commands are blocked
-            method @ ("admin_addPeer"
-            | "admin_datadir"
-            | "admin_startRPC"
-            | "admin_startWS"
-            | "admin_stopRPC"
-            | "admin_stopWS"
-            | "db_getHex"
+            method @ ("db_getHex"
             | "db_getString"
             | "db_putHex"
             | "db_putString"
@@ -1049,20 +1997,6 @@ impl Web3ProxyApp {
             | "les_addBalance"
             | "les_setClientParams"
             | "les_setDefaultParams"
-            | "miner_setExtra"
-            | "miner_setGasPrice"
-            | "miner_start"
-            | "miner_stop"
-            | "miner_setEtherbase"
-            | "miner_setGasLimit"
-            | "personal_importRawKey"
-            | "personal_listAccounts"
-            | "personal_lockAccount"
-            | "personal_newAccount"
-            | "personal_unlockAccount"
-            | "personal_sendTransaction"
-            | "personal_sign"
-            | "personal_ecRecover"
             | "shh_addToGroup"
             | "shh_getFilterChanges"
             | "shh_getMessages"
@@ -1077,15 +2011,44 @@ impl Web3ProxyApp {
                 // TODO: proper error code
                 return Err(anyhow::anyhow!("method unsupported: {}", method));
             }
-            // TODO: implement these commands
-            method @ ("eth_getFilterChanges"
-            | "eth_getFilterLogs"
-            | "eth_newBlockFilter"
-            | "eth_newFilter"
-            | "eth_newPendingTransactionFilter"
-            | "eth_uninstallFilter") => {
-                // TODO: unsupported command stat
-                return Err(anyhow::anyhow!("not yet implemented: {}", method));
+            // filters are kept in the proxy so that polls can go to any server
+            "eth_newBlockFilter" => {
+                json!(self.balanced_rpcs.new_block_filter(authorization).await?)
+            }
+            "eth_newFilter" => {
+                json!(
+                    self.balanced_rpcs
+                        .new_log_filter(authorization, request.params.as_ref())
+                        .await?
+                )
+            }
+            "eth_newPendingTransactionFilter" => {
+                json!(
+                    self.balanced_rpcs
+                        .new_pending_transaction_filter(authorization)
+                        .await?
+                )
+            }
+            "eth_uninstallFilter" => {
+                let id = filter_id(&request.params)?;
+
+                json!(self.balanced_rpcs.uninstall_filter(id).await)
+            }
+            "eth_getFilterChanges" => {
+                // emit stats
+                let id = filter_id(&request.params)?;
+
+                self.balanced_rpcs
+                    .filter_changes(self.allowed_lag, authorization, &request_metadata, id)
+                    .await?
+            }
+            "eth_getFilterLogs" => {
+                // emit stats
+                let id = filter_id(&request.params)?;
+
+                self.balanced_rpcs
+                    .filter_logs(self.allowed_lag, authorization, &request_metadata, id)
+                    .await?
             }
             // some commands can use local data or caches
             "eth_accounts" => {
@@ -1099,9 +2062,7 @@ impl Web3ProxyApp {
                     }
                     None => {
                         // TODO: what does geth do if this happens?
-                        return Err(anyhow::anyhow!(
-                            "no servers synced. unknown eth_blockNumber"
-                        ));
+                        return Err(NoServersSynced.into());
                     }
                 }
             }
@@ -1152,6 +2113,16 @@ impl Web3ProxyApp {
             }
             */
             // TODO: eth_gasPrice that does awesome magic to predict the future
+            "eth_gasPrice" if self.gas_price_cache.is_some() => {
+                // emit stats
+                let gas_price_cache = self.gas_price_cache.as_ref().expect("checked above");
+
+                let gas_price = self
+                    .cached_gas_price(gas_price_cache, authorization, &request_metadata)
+                    .await?;
+
+                json!(gas_price)
+            }
             "eth_hashrate" => {
                 // no stats on this. its cheap
                 json!(U64::zero())
@@ -1160,31 +2131,92 @@ impl Web3ProxyApp {
                 // no stats on this. its cheap
                 json!(false)
             }
-            // TODO: eth_sendBundle (flashbots command)
+            "eth_getBlockByNumber" if is_latest_block_request(&request.params) => {
+                // emit stats
+                // every client behind the proxy sees the same head block as eth_blockNumber
+                let head_block = self.balanced_rpcs.full_head_block(authorization).await?;
+
+                json!(head_block)
+            }
+            "eth_getTransactionCount"
+                if self.config.aggregate_pending_nonce && is_pending_nonce(&request.params) =>
+            {
+                // emit stats
+                // backends have different mempools. a low answer makes bots reuse nonces
+                let address: Address = request
+                    .params
+                    .as_ref()
+                    .and_then(|x| x.get(0))
+                    .cloned()
+                    .map(serde_json::from_value)
+                    .context("address required")?
+                    .context("invalid address")?;
+
+                let nonce = self
+                    .balanced_rpcs
+                    .max_pending_nonce(authorization, Some(&request_metadata), address)
+                    .await?;
+
+                let nonce = match self.private_tx_next_nonce(address) {
+                    Some(private_nonce) => nonce.max(private_nonce),
+                    None => nonce,
+                };
+
+                json!(nonce)
+            }
+            // searchers can use the proxy as their only rpc
+            "eth_sendBundle" | "eth_callBundle" | "eth_cancelBundle" => {
+                let response = self.send_bundle_request(&request).await?;
+
+                return Ok((response, vec![]));
+            }
             // broadcast transactions to all private rpcs at once
             "eth_sendRawTransaction" => {
                 // emit stats
-                let private_rpcs = self.private_rpcs.as_ref().unwrap_or(&self.balanced_rpcs);
+                if let Some(age) = self.chain_halted() {
+                    if self.config.chain_halt.as_ref().map(|x| x.pause_txs) == Some(true) {
+                        // the transaction would sit in mempools until the chain starts again
+                        return Err(anyhow::anyhow!(
+                            "the chain looks halted. the head block is {} seconds old. transactions are paused",
+                            age
+                        ));
+                    }
+                }
 
-                // try_send_all_upstream_servers puts the request id into the response. no need to do that ourselves here.
-                let mut response = private_rpcs
-                    .try_send_all_upstream_servers(
-                        authorization,
-                        &request,
-                        Some(request_metadata.clone()),
-                        None,
-                        Level::Trace,
-                    )
-                    .await?;
+                let (mut response, relays) = match self.private_rpcs.as_ref() {
+                    Some(private_rpcs) => {
+                        self.send_private_raw_transaction(
+                            authorization,
+                            &request,
+                            &request_metadata,
+                            private_rpcs,
+                        )
+                        .await?
+                    }
+                    None => (
+                        self.send_public_raw_transaction(
+                            authorization,
+                            &request,
+                            &request_metadata,
+                        )
+                        .await?,
+                        vec![],
+                    ),
+                };
+
+                // save this before the params are consumed below
+                let raw_tx = request
+                    .params
+                    .as_ref()
+                    .and_then(|x| x.as_array())
+                    .and_then(|x| x.first())
+                    .and_then(|x| x.as_str())
+                    .and_then(|x| Bytes::from_str(x).ok());
 
                 // sometimes we get an error that the transaction is already known by our nodes,
                 // that's not really an error. Just return the hash like a successful response would.
                 if let Some(response_error) = response.error.as_ref() {
-                    if response_error.code == -32000
-                        && (response_error.message == "ALREADY_EXISTS: already known"
-                            || response_error.message
-                                == "INTERNAL_ERROR: existing tx with same hash")
-                    {
+                    if is_already_known(response_error) {
                         let params = request
                             .params
                             .context("there must be params if we got this far")?;
@@ -1192,7 +2224,7 @@ impl Web3ProxyApp {
                         let params = params
                             .as_array()
                             .context("there must be an array if we got this far")?
-                            .get(0)
+                            .first()
                             .context("there must be an item if we got this far")?
                             .as_str()
                             .context("there must be a string if we got this far")?;
@@ -1217,6 +2249,47 @@ impl Web3ProxyApp {
 
                 let rpcs = request_metadata.backend_requests.lock().clone();
 
+                let tx_hash = response
+                    .result
+                    .as_ref()
+                    .and_then(|x| serde_json::from_str::<TxHash>(x.get()).ok());
+
+                if let (Some(tx_hash), Some(raw_tx)) = (tx_hash, raw_tx.clone()) {
+                    let relay_outcomes = response
+                        .data
+                        .as_ref()
+                        .and_then(|x| x.get("relays"))
+                        .and_then(|x| serde_json::from_value(x.clone()).ok())
+                        .unwrap_or_default();
+
+                    let rebroadcast_blocks = authorization.checks.rebroadcast_blocks.map(u64::from);
+
+                    self.track_tx(tx_hash, raw_tx, relay_outcomes, rebroadcast_blocks);
+                }
+
+                // remember private transactions until they are included. this way a restart doesn't forget them
+                if !relays.is_empty() {
+                    if let (Some(tx_hash), Some(raw_tx)) = (tx_hash, raw_tx) {
+                        let tx = PendingPrivateTx {
+                            tx_hash,
+                            raw_tx,
+                            relays,
+                            first_seen_block: self
+                                .balanced_rpcs
+                                .head_block_num()
+                                .unwrap_or_default(),
+                        };
+
+                        let app = self.clone();
+
+                        tokio::spawn(async move {
+                            if let Err(err) = app.save_private_tx(tx).await {
+                                warn!("unable to save private tx. err={:?}", err);
+                            }
+                        });
+                    }
+                }
+
                 if let Some(salt) = self.config.public_recent_ips_salt.as_ref() {
                     if let Some(tx_hash) = response.result.clone() {
                         let now = Utc::now().timestamp();
@@ -1235,7 +2308,11 @@ impl Web3ProxyApp {
                                         format!("eth_sendRawTransaction:{}", app.config.chain_id);
 
                                     redis_conn
-                                        .zadd(recent_tx_hash_key, hashed_tx_hash.to_string(), now)
+                                        .zadd::<_, _, _, ()>(
+                                            recent_tx_hash_key,
+                                            hashed_tx_hash.to_string(),
+                                            now,
+                                        )
                                         .await?;
                                 }
                                 Ok(None) => {}
@@ -1317,23 +2394,28 @@ impl Web3ProxyApp {
                 // emit stats
 
                 // TODO: if no servers synced, wait for them to be synced?
-                let head_block = self
-                    .balanced_rpcs
-                    .head_block()
-                    .context("no servers synced")?;
+                // an old head is still fine for cache hits. the synced check happens before anything is sent upstream
+                let head_block = self.balanced_rpcs.head_block().ok_or(NoServersSynced)?;
 
                 // we do this check before checking caches because it might modify the request params
                 // TODO: add a stat for archive vs full since they should probably cost different
                 // TODO: this cache key can be rather large. is that okay?
-                let cache_key: Option<ResponseCacheKey> = match block_needed(
-                    authorization,
-                    method,
-                    request.params.as_mut(),
-                    head_block.number(),
-                    &self.balanced_rpcs,
-                )
-                .await?
-                {
+                let needed =
+                    if authorization.checks.latest_uncached && is_latest_tagged(&request.params) {
+                        // this key wants the freshest server's "latest", not our consensus head's. leave the params alone
+                        BlockNeeded::CacheNever
+                    } else {
+                        block_needed(
+                            authorization,
+                            method,
+                            request.params.as_mut(),
+                            head_block.number(),
+                            &self.balanced_rpcs,
+                        )
+                        .await?
+                    };
+
+                let cache_key: Option<ResponseCacheKey> = match needed {
                     BlockNeeded::CacheSuccessForever => Some(ResponseCacheKey {
                         block: None,
                         method: method.to_string(),
@@ -1371,33 +2453,118 @@ impl Web3ProxyApp {
                     }
                 };
 
-                let mut response = {
+                // backends have different mempools. remember which transaction this is in case the backend hasn't seen it yet
+                let pending_tx_hash: Option<TxHash> = if method == "eth_getTransactionByHash"
+                    && self.config.pending_tx_cache_seconds.is_some()
+                {
+                    request
+                        .params
+                        .as_ref()
+                        .and_then(|x| x.get(0))
+                        .cloned()
+                        .and_then(|x| serde_json::from_value(x).ok())
+                } else {
+                    None
+                };
+
+                // backends might have already forgotten a block that was orphaned by a reorg
+                let orphaned_block_hash: Option<H256> = if method == "eth_getBlockByHash"
+                    && request.params.as_ref().and_then(|x| x.get(1)) != Some(&json!(true))
+                {
+                    request
+                        .params
+                        .as_ref()
+                        .and_then(|x| x.get(0))
+                        .cloned()
+                        .and_then(|x| serde_json::from_value(x).ok())
+                } else {
+                    None
+                };
+
+                // the balanced rpcs might not go back far enough for this request
+                let request_block_number = cache_key
+                    .as_ref()
+                    .and_then(|x| x.block.as_ref())
+                    .map(|x| x.number());
+
+                let rpcs = match request_block_number {
+                    Some(request_block_number)
+                        if !self.balanced_rpcs.has_history(&request_block_number) =>
+                    {
+                        request_metadata
+                            .archive_request
+                            .store(true, atomic::Ordering::Relaxed);
+
+                        self.archive_rpcs.as_ref()
+                    }
+                    _ => Some(&self.balanced_rpcs),
+                };
+
+                let mut response = if let Some(rpcs) = rpcs {
                     let request_metadata = request_metadata.clone();
 
                     let authorization = authorization.clone();
 
                     if let Some(cache_key) = cache_key {
-                        let request_block_number = cache_key.block.as_ref().map(|x| x.number());
+                        // the disk cache only needs the params if it is enabled
+                        let disk_cache = self.disk_cache.as_ref().map(|x| {
+                            (
+                                x,
+                                DiskCache::key(method, request.params.as_ref()),
+                                request.params.clone(),
+                            )
+                        });
 
-                        self.response_cache
+                        let head_block_num = head_block.number();
+
+                        // only the request that fills the cache runs this future
+                        let cache_miss = AtomicBool::new(false);
+                        let cache_miss_ref = &cache_miss;
+
+                        let response = self
+                            .response_cache_partitions
+                            .get(authorization.user_tier())
+                            .unwrap_or(&self.response_cache)
                             .try_get_with(cache_key, async move {
+                                cache_miss_ref.store(true, atomic::Ordering::Relaxed);
+
+                                if let Some((disk_cache, disk_key, _)) = disk_cache.as_ref() {
+                                    if let Some(response) = disk_cache.get(*disk_key).await {
+                                        return Ok(response);
+                                    }
+                                }
+
+                                if !rpcs.synced() {
+                                    return Err(NoServersSynced.into());
+                                }
+
                                 // TODO: retry some failures automatically!
                                 // TODO: try private_rpcs if all the balanced_rpcs fail!
                                 // TODO: put the hash here instead?
-                                let mut response = self
-                                    .balanced_rpcs
+                                let mut response = rpcs
                                     .try_send_best_upstream_server(
                                         self.allowed_lag,
                                         &authorization,
                                         request,
                                         Some(&request_metadata),
                                         request_block_number.as_ref(),
+                                        Traffic::Request,
                                     )
                                     .await?;
 
                                 // discard their id by replacing it with an empty
                                 response.id = Default::default();
 
+                                if let Some((disk_cache, disk_key, params)) = disk_cache.as_ref() {
+                                    disk_cache.insert(
+                                        disk_key,
+                                        params.as_ref(),
+                                        request_block_number,
+                                        &response,
+                                        head_block_num,
+                                    );
+                                }
+
                                 // TODO: only cache the inner response
                                 Ok::<_, anyhow::Error>(response)
                             })
@@ -1405,27 +2572,88 @@ impl Web3ProxyApp {
                             // TODO: what is the best way to handle an Arc here?
                             .map_err(|err| {
                                 // TODO: emit a stat for an error
-                                anyhow::anyhow!(err)
+                                if err.is::<NoServersSynced>() {
+                                    NoServersSynced.into()
+                                } else {
+                                    anyhow::anyhow!(err)
+                                }
                             })
-                            .context("error while forwarding and caching response")?
+                            .context("error while forwarding and caching response")?;
+
+                        let counters = self.method_metrics.get(method);
+
+                        if cache_miss.load(atomic::Ordering::Relaxed) {
+                            counters
+                                .cache_misses
+                                .fetch_add(1, atomic::Ordering::Relaxed);
+                        } else {
+                            counters.cache_hits.fetch_add(1, atomic::Ordering::Relaxed);
+                        }
+
+                        response
                     } else {
-                        self.balanced_rpcs
-                            .try_send_best_upstream_server(
-                                self.allowed_lag,
-                                &authorization,
-                                request,
-                                Some(&request_metadata),
-                                None,
-                            )
-                            .await
-                            .context("error while forwarding response")?
+                        if !rpcs.synced() {
+                            return Err(NoServersSynced.into());
+                        }
+
+                        rpcs.try_send_best_upstream_server(
+                            self.allowed_lag,
+                            &authorization,
+                            request,
+                            Some(&request_metadata),
+                            None,
+                            Traffic::Request,
+                        )
+                        .await
+                        .context("error while forwarding response")?
                     }
+                } else {
+                    // no archive rpcs are configured. give a clear error instead of whatever a pruned node would say
+                    request_metadata
+                        .error_response
+                        .store(true, atomic::Ordering::Release);
+
+                    JsonRpcForwardedResponse::from_string(
+                        format!(
+                            "block {} is older than available history; archive access required",
+                            request_block_number.expect("rpcs are only None for old blocks")
+                        ),
+                        Some(HISTORY_PRUNED_ERROR_CODE),
+                        None,
+                    )
                 };
 
                 // since this data came likely out of a cache, the id is not going to match
                 // replace the id with our request's id.
                 response.id = request_id;
 
+                if let Some(pending_tx_hash) = pending_tx_hash {
+                    let is_null = response.result.as_ref().map(|x| x.get() == "null");
+
+                    if is_null == Some(true) {
+                        if let Some(TxStatus::Pending(tx)) =
+                            self.pending_transactions.get(&pending_tx_hash)
+                        {
+                            trace!("pending tx {:?} from the mempool cache", pending_tx_hash);
+
+                            response.result = Some(to_raw_value(&tx)?);
+                        }
+                    }
+                }
+
+                if let Some(orphaned_block_hash) = orphaned_block_hash {
+                    let is_null = response.result.as_ref().map(|x| x.get() == "null");
+
+                    if is_null == Some(true) {
+                        if let Some(block) = self.balanced_rpcs.orphaned_block(&orphaned_block_hash)
+                        {
+                            trace!("orphaned block {:?} from our cache", orphaned_block_hash);
+
+                            response.result = Some(to_raw_value(&block)?);
+                        }
+                    }
+                }
+
                 // TODO: DRY!
                 let rpcs = request_metadata.backend_requests.lock().clone();
 
@@ -0,0 +1,181 @@
+//! Many `eth_call`s aggregated through the Multicall3 contract
+
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::jsonrpc::JsonRpcRequest;
+use crate::rpcs::connection::Web3Connection;
+use anyhow::Context;
+use ethers::abi::{self, ParamType, Token};
+use ethers::prelude::{Address, Bytes};
+use ethers::utils::id;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::sync::Arc;
+use tokio::time::timeout;
+
+/// Multicall3 is deployed at the same address on almost every chain. <https://github.com/mds1/multicall>
+pub const DEFAULT_MULTICALL_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+
+/// How many calls to put in one `eth_call`. Larger chunks risk hitting the backend's gas cap.
+pub const MULTICALL_CHUNK_SIZE: usize = 200;
+
+/// One call for `multicall`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MulticallCall {
+    pub to: Address,
+    pub data: Bytes,
+}
+
+/// The result of one call. A failed call does not fail the others.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MulticallResult {
+    pub success: bool,
+    /// the return data or the revert data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Bytes>,
+    /// set if the call could not be made at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl MulticallResult {
+    fn from_error(err: &str) -> Self {
+        Self {
+            success: false,
+            data: None,
+            error: Some(err.to_string()),
+        }
+    }
+}
+
+impl Web3ProxyApp {
+    /// Execute many calls with as few upstream requests as possible.
+    /// Results are in the same order as the calls.
+    pub async fn multicall(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        calls: Vec<MulticallCall>,
+        block: serde_json::Value,
+    ) -> anyhow::Result<(Vec<MulticallResult>, Vec<Arc<Web3Connection>>)> {
+        let multicall_address = self.config.multicall_address;
+
+        let futures = calls
+            .chunks(MULTICALL_CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                self.multicall_chunk(authorization, multicall_address, i, chunk, &block)
+            });
+
+        // retries on the backends are not allowed to run forever
+        let chunk_results = timeout(self.request_timeout(), join_all(futures))
+            .await
+            .context("multicall timed out")?;
+
+        let mut results = Vec::with_capacity(calls.len());
+        let mut rpcs = vec![];
+
+        for (chunk, chunk_result) in calls.chunks(MULTICALL_CHUNK_SIZE).zip(chunk_results) {
+            match chunk_result {
+                Ok((chunk_results, chunk_rpcs)) => {
+                    results.extend(chunk_results);
+                    rpcs.extend(chunk_rpcs);
+                }
+                Err(err) => {
+                    // isolate the failure to this chunk
+                    let err = format!("{:#}", err);
+
+                    results.extend(chunk.iter().map(|_| MulticallResult::from_error(&err)));
+                }
+            }
+        }
+
+        Ok((results, rpcs))
+    }
+
+    async fn multicall_chunk(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        multicall_address: Address,
+        chunk_id: usize,
+        calls: &[MulticallCall],
+        block: &serde_json::Value,
+    ) -> anyhow::Result<(Vec<MulticallResult>, Vec<Arc<Web3Connection>>)> {
+        // aggregate3((address target, bool allowFailure, bytes callData)[] calls)
+        let calls_token = Token::Array(
+            calls
+                .iter()
+                .map(|x| {
+                    Token::Tuple(vec![
+                        Token::Address(x.to),
+                        Token::Bool(true),
+                        Token::Bytes(x.data.to_vec()),
+                    ])
+                })
+                .collect(),
+        );
+
+        let mut calldata = id("aggregate3((address,bool,bytes)[])").to_vec();
+        calldata.extend(abi::encode(&[calls_token]));
+
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(chunk_id)).expect("numbers should always be valid json"),
+            method: "eth_call".to_string(),
+            params: Some(json!([
+                { "to": multicall_address, "data": Bytes::from(calldata) },
+                block,
+            ])),
+        };
+
+        let (response, rpcs) = self.proxy_web3_rpc_request(authorization, request).await?;
+
+        if let Some(err) = response.error {
+            // the multicall itself failed. every call in this chunk failed with it
+            let results = calls
+                .iter()
+                .map(|_| MulticallResult::from_error(&err.message))
+                .collect();
+
+            return Ok((results, rpcs));
+        }
+
+        let result = response.result.context("no result in multicall response")?;
+
+        let result: Bytes =
+            serde_json::from_str(result.get()).context("multicall result is not hex")?;
+
+        // returns (bool success, bytes returnData)[]
+        let decoded = abi::decode(
+            &[ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Bool,
+                ParamType::Bytes,
+            ])))],
+            &result,
+        )
+        .context("decoding multicall result")?;
+
+        let decoded = match decoded.into_iter().next() {
+            Some(Token::Array(x)) if x.len() == calls.len() => x,
+            _ => return Err(anyhow::anyhow!("unexpected multicall result")),
+        };
+
+        let results = decoded
+            .into_iter()
+            .map(|x| match x {
+                Token::Tuple(x) => match x.as_slice() {
+                    [Token::Bool(success), Token::Bytes(data)] => MulticallResult {
+                        success: *success,
+                        data: Some(data.clone().into()),
+                        error: None,
+                    },
+                    _ => MulticallResult::from_error("unexpected multicall result"),
+                },
+                _ => MulticallResult::from_error("unexpected multicall result"),
+            })
+            .collect();
+
+        Ok((results, rpcs))
+    }
+}
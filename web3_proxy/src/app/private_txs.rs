@@ -0,0 +1,647 @@
+//! Transactions sent to the private relays.
+//!
+//! Transactions that have not been seen on chain yet are saved in the database so that they are not forgotten when the proxy restarts.
+
+use super::{AnyhowJoinHandle, Web3ProxyApp};
+use crate::config::RelayFailure;
+use crate::feature_flags::PRIVATE_FALLBACK;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::rpcs::connection::Web3Connection;
+use crate::rpcs::connections::Web3Connections;
+use crate::rpcs::request::Traffic;
+use anyhow::Context;
+use chrono::Utc;
+use entities::private_tx;
+use ethers::prelude::{Address, Bytes, Transaction, TxHash, U256, U64};
+use ethers::utils::rlp::{Decodable, Rlp};
+use futures::future::join;
+use futures::stream::{FuturesUnordered, StreamExt};
+use hashbrown::HashMap;
+use log::{debug, info, trace, warn, Level};
+use migration::sea_orm::{self, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout_at, Instant};
+
+/// how often to check if pending private transactions have been included in a block
+const PRIVATE_TX_CHECK_SECONDS: u64 = 30;
+
+/// A transaction that was sent to the private relays.
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingPrivateTx {
+    pub tx_hash: TxHash,
+    pub raw_tx: Bytes,
+    /// names of the relays that accepted the transaction
+    pub relays: Vec<String>,
+    /// the head block when the transaction was first sent
+    pub first_seen_block: U64,
+}
+
+/// What one private relay did with a transaction.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayStatus {
+    Accepted,
+    /// the relay answered with an error
+    Rejected,
+    /// the request to the relay failed or timed out
+    Failed,
+    /// the relay had not answered when we responded. it might still accept the transaction
+    Pending,
+}
+
+/// Included in `eth_sendRawTransaction` responses so that searchers can see which relays accepted their transaction.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RelayOutcome {
+    pub relay: String,
+    pub status: RelayStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The time from sending a transaction to one relay's answer.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RelayLatency {
+    pub count: u64,
+    pub total_millis: u64,
+    pub max_millis: u64,
+}
+
+/// Latency by relay for the prometheus endpoint. Sending to the balanced_rpcs is the "public" relay.
+#[derive(Debug, Default)]
+pub struct RelayLatencies(Mutex<HashMap<String, RelayLatency>>);
+
+impl RelayLatencies {
+    pub fn record(&self, relay: &str, latency: Duration) {
+        let millis = latency.as_millis() as u64;
+
+        let mut latencies = self.0.lock();
+
+        let x = latencies.entry_ref(relay).or_default();
+
+        x.count += 1;
+        x.total_millis += millis;
+        x.max_millis = x.max_millis.max(millis);
+    }
+}
+
+impl Serialize for RelayLatencies {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.lock().serialize(serializer)
+    }
+}
+
+/// Every relay's outcome and the responses worth returning.
+#[derive(Default)]
+struct RelayResponses {
+    outcomes: Vec<RelayOutcome>,
+    /// the first acceptance
+    accepted: Option<JsonRpcForwardedResponse>,
+    /// the first rejection
+    rejected: Option<JsonRpcForwardedResponse>,
+}
+
+/// Servers answer a transaction that they already have with one of these errors. That is not really a failure.
+pub fn is_already_known(error: &JsonRpcErrorData) -> bool {
+    error.code == -32000
+        && (error.message == "ALREADY_EXISTS: already known"
+            || error.message == "INTERNAL_ERROR: existing tx with same hash")
+}
+
+/// Wait for the relays until `min_relays` have accepted, then give the others `grace` to answer.
+/// Nothing is waited on past `deadline`.
+/// Relays that are still working keep going in the background. Their latency is recorded when they finish.
+async fn collect_relay_responses(
+    relays: Vec<(
+        Arc<Web3Connection>,
+        JoinHandle<anyhow::Result<JsonRpcForwardedResponse>>,
+    )>,
+    min_relays: usize,
+    grace: Duration,
+    deadline: Instant,
+    latencies: Arc<RelayLatencies>,
+) -> RelayResponses {
+    let start = Instant::now();
+
+    let mut responses = RelayResponses {
+        outcomes: relays
+            .iter()
+            .map(|(rpc, _)| RelayOutcome {
+                relay: rpc.name.clone(),
+                status: RelayStatus::Pending,
+                error: None,
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let mut pending: FuturesUnordered<_> = relays
+        .into_iter()
+        .enumerate()
+        .map(|(i, (rpc, handle))| {
+            let latencies = latencies.clone();
+
+            async move {
+                let response = handle.await;
+
+                latencies.record(&rpc.name, start.elapsed());
+
+                (i, rpc, response)
+            }
+        })
+        .collect();
+
+    let mut num_accepted = 0;
+    let mut deadline = deadline;
+    let mut grace_started = false;
+
+    loop {
+        let next = match timeout_at(deadline, pending.next()).await {
+            Ok(next) => next,
+            Err(_) => break,
+        };
+
+        let (i, rpc, response) = match next {
+            None => break,
+            Some(next) => next,
+        };
+
+        let outcome = &mut responses.outcomes[i];
+
+        match response {
+            Ok(Ok(response))
+                if response
+                    .error
+                    .as_ref()
+                    .map(is_already_known)
+                    .unwrap_or(true) =>
+            {
+                outcome.status = RelayStatus::Accepted;
+                num_accepted += 1;
+                responses.accepted.get_or_insert(response);
+            }
+            Ok(Ok(response)) => {
+                trace!("private relay {} rejected a tx: {:?}", rpc, response.error);
+                outcome.status = RelayStatus::Rejected;
+                outcome.error = response.error.as_ref().map(|x| x.message.clone());
+                responses.rejected.get_or_insert(response);
+            }
+            Ok(Err(err)) => {
+                debug!("private relay {} failed: {:?}", rpc, err);
+                outcome.status = RelayStatus::Failed;
+                outcome.error = Some(format!("{:#}", err));
+            }
+            Err(err) => {
+                warn!("private relay {} task failed: {:?}", rpc, err);
+                outcome.status = RelayStatus::Failed;
+                outcome.error = Some("internal error".to_string());
+            }
+        }
+
+        if !grace_started && num_accepted >= min_relays.max(1) {
+            grace_started = true;
+            deadline = deadline.min(Instant::now() + grace);
+        }
+    }
+
+    if !pending.is_empty() {
+        // the relays' tasks finish either way. this is only for their latency
+        tokio::spawn(async move { while pending.next().await.is_some() {} });
+    }
+
+    responses
+}
+
+impl Web3ProxyApp {
+    /// Send a raw transaction to every private relay and check that enough of them accepted it.
+    /// Every relay's outcome is put in the response's `data`.
+    /// Returns the response and the names of the relays that accepted the transaction.
+    pub async fn send_private_raw_transaction(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+        private_rpcs: &Web3Connections,
+    ) -> anyhow::Result<(JsonRpcForwardedResponse, Vec<String>)> {
+        let config = &self.config.private_txs;
+
+        let min_relays = config.min_relays.max(1);
+
+        let relay_timeout = config
+            .relay_timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| self.request_timeout());
+
+        // waiting for the relays' rate limits counts against the timeout too
+        let deadline = Instant::now() + relay_timeout;
+
+        let relays = timeout_at(
+            deadline,
+            private_rpcs.spawn_to_all_individually(
+                authorization,
+                request,
+                Some(request_metadata),
+                relay_timeout,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            warn!("timed out waiting for the private relays to be available");
+            vec![]
+        });
+
+        let relays_f = collect_relay_responses(
+            relays,
+            min_relays,
+            Duration::from_millis(config.relay_grace_ms),
+            deadline,
+            self.relay_latencies.clone(),
+        );
+
+        let (relays, public_response) = if config.also_public {
+            let public_f =
+                self.send_public_raw_transaction(authorization, request, request_metadata);
+
+            let (relays, public_response) = join(relays_f, public_f).await;
+
+            (relays, Some(public_response))
+        } else {
+            (relays_f.await, None)
+        };
+
+        let accepted: Vec<String> = relays
+            .outcomes
+            .iter()
+            .filter(|x| x.status == RelayStatus::Accepted)
+            .map(|x| x.relay.clone())
+            .collect();
+
+        let mut response = match relays.accepted {
+            Some(response) if accepted.len() >= min_relays => response,
+            _ => {
+                warn!(
+                    "{}/{} private relays accepted a tx",
+                    accepted.len(),
+                    min_relays
+                );
+
+                // admins can turn on the fallback without a deploy if the relays are having a bad day
+                let on_relay_failure = if self.feature_flags.is_enabled(PRIVATE_FALLBACK) {
+                    RelayFailure::Public
+                } else {
+                    config.on_relay_failure
+                };
+
+                match (public_response, on_relay_failure) {
+                    // the transaction is already public. that answer is the one that matters now
+                    (Some(public_response), _) => public_response?,
+                    (None, RelayFailure::Public) => {
+                        self.send_public_raw_transaction(authorization, request, request_metadata)
+                            .await?
+                    }
+                    (None, RelayFailure::Error) => match relays.rejected {
+                        Some(rejected) if accepted.is_empty() => rejected,
+                        _ => JsonRpcForwardedResponse::from_string(
+                            format!(
+                                "{}/{} private relays accepted the transaction",
+                                accepted.len(),
+                                min_relays
+                            ),
+                            None,
+                            Some(request.id.clone()),
+                        ),
+                    },
+                }
+            }
+        };
+
+        response.add_data("relays", json!(relays.outcomes));
+
+        Ok((response, accepted))
+    }
+
+    /// Send a raw transaction to every balanced rpc.
+    pub async fn send_public_raw_transaction(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> anyhow::Result<JsonRpcForwardedResponse> {
+        let start = Instant::now();
+
+        // try_send_all_upstream_servers puts the request id into the response. no need to do that ourselves here.
+        let response = self
+            .balanced_rpcs
+            .try_send_all_upstream_servers(
+                authorization,
+                request,
+                Some(request_metadata.clone()),
+                None,
+                Level::Trace,
+            )
+            .await;
+
+        self.relay_latencies.record("public", start.elapsed());
+
+        response
+    }
+
+    /// Remember a transaction sent to the private relays until it is included in a block.
+    pub async fn save_private_tx(&self, tx: PendingPrivateTx) -> anyhow::Result<()> {
+        if self.pending_private_txs.read().contains_key(&tx.tx_hash) {
+            // users resend transactions. the first relays and block are the interesting ones
+            return Ok(());
+        }
+
+        if let Some(db_conn) = self.db_conn() {
+            let exists = private_tx::Entity::find()
+                .filter(private_tx::Column::ChainId.eq(self.config.chain_id))
+                .filter(private_tx::Column::TxHash.eq(tx.tx_hash.as_bytes().to_vec()))
+                .one(&db_conn)
+                .await?
+                .is_some();
+
+            if !exists {
+                let row = private_tx::ActiveModel {
+                    chain_id: sea_orm::Set(self.config.chain_id),
+                    tx_hash: sea_orm::Set(tx.tx_hash.as_bytes().to_vec()),
+                    raw_tx: sea_orm::Set(tx.raw_tx.to_string()),
+                    relays: sea_orm::Set(json!(tx.relays)),
+                    first_seen_block: sea_orm::Set(tx.first_seen_block.as_u64()),
+                    timestamp: sea_orm::Set(Utc::now()),
+                    ..Default::default()
+                };
+
+                row.save(&db_conn)
+                    .await
+                    .context("failed saving private tx")?;
+            }
+        }
+
+        trace!("pending private tx: {:?}", tx.tx_hash);
+
+        self.pending_private_txs.write().insert(tx.tx_hash, tx);
+
+        Ok(())
+    }
+
+    /// Load the private transactions that were pending when the proxy last stopped.
+    pub async fn load_private_txs(&self) -> anyhow::Result<usize> {
+        let db_conn = match self.db_conn() {
+            None => return Ok(0),
+            Some(x) => x,
+        };
+
+        let rows = private_tx::Entity::find()
+            .filter(private_tx::Column::ChainId.eq(self.config.chain_id))
+            .all(&db_conn)
+            .await
+            .context("failed loading private txs")?;
+
+        let mut pending_private_txs = self.pending_private_txs.write();
+
+        for row in rows {
+            let relays = match serde_json::from_value(row.relays) {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("bad relays on private tx #{}: {:?}", row.id, err);
+                    vec![]
+                }
+            };
+
+            let raw_tx = match Bytes::from_str(&row.raw_tx) {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("bad raw_tx on private tx #{}: {:?}", row.id, err);
+                    continue;
+                }
+            };
+
+            let tx = PendingPrivateTx {
+                tx_hash: TxHash::from_slice(&row.tx_hash),
+                raw_tx,
+                relays,
+                first_seen_block: row.first_seen_block.into(),
+            };
+
+            pending_private_txs.insert(tx.tx_hash, tx);
+        }
+
+        Ok(pending_private_txs.len())
+    }
+
+    /// The next nonce for an address based on the transactions that we sent to the private relays.
+    /// Private transactions are not in the public mempools, so the backends don't know about them.
+    pub fn private_tx_next_nonce(&self, address: Address) -> Option<U256> {
+        self.pending_private_txs
+            .read()
+            .values()
+            .filter_map(|x| Transaction::decode(&Rlp::new(x.raw_tx.as_ref())).ok())
+            .filter(|x| x.from == address)
+            .map(|x| x.nonce + 1)
+            .max()
+    }
+
+    /// Check if a private transaction has been included in a block.
+    async fn private_tx_included(
+        &self,
+        authorization: &Arc<Authorization>,
+        tx_hash: TxHash,
+    ) -> anyhow::Result<bool> {
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).expect("numbers should always be valid json"),
+            method: "eth_getTransactionReceipt".to_string(),
+            params: Some(json!([tx_hash])),
+        };
+
+        let response = self
+            .balanced_rpcs
+            .try_send_best_upstream_server(
+                self.allowed_lag,
+                authorization,
+                request,
+                None,
+                None,
+                Traffic::Request,
+            )
+            .await?;
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("receipt error: {:?}", err));
+        }
+
+        Ok(matches!(response.result, Some(result) if result.get() != "null"))
+    }
+
+    /// Forget private transactions that have been included in a block or that are too old to ever be included.
+    async fn prune_private_txs(&self, authorization: &Arc<Authorization>) -> anyhow::Result<()> {
+        let head_block_num = self.balanced_rpcs.head_block_num().unwrap_or_default();
+
+        let forget_after_blocks = self.config.private_txs.forget_after_blocks;
+
+        let txs: Vec<(TxHash, U64)> = self
+            .pending_private_txs
+            .read()
+            .values()
+            .map(|x| (x.tx_hash, x.first_seen_block))
+            .collect();
+
+        let mut included = vec![];
+        let mut expired = vec![];
+
+        for (tx_hash, first_seen_block) in txs {
+            // one bad receipt should not keep the other transactions from being checked
+            match self.private_tx_included(authorization, tx_hash).await {
+                Ok(true) => {
+                    included.push(tx_hash);
+                    continue;
+                }
+                Ok(false) => {}
+                Err(err) => warn!("unable to check private tx {:?}. err={:?}", tx_hash, err),
+            }
+
+            if !head_block_num.is_zero()
+                && head_block_num.saturating_sub(first_seen_block) > forget_after_blocks.into()
+            {
+                expired.push(tx_hash);
+            }
+        }
+
+        if included.is_empty() && expired.is_empty() {
+            return Ok(());
+        }
+
+        let forgotten: Vec<TxHash> = included.iter().chain(expired.iter()).copied().collect();
+
+        if let Some(db_conn) = self.db_conn() {
+            private_tx::Entity::delete_many()
+                .filter(private_tx::Column::ChainId.eq(self.config.chain_id))
+                .filter(
+                    private_tx::Column::TxHash
+                        .is_in(forgotten.iter().map(|x| x.as_bytes().to_vec())),
+                )
+                .exec(&db_conn)
+                .await
+                .context("failed deleting forgotten private txs")?;
+        }
+
+        let mut pending_private_txs = self.pending_private_txs.write();
+
+        for tx_hash in forgotten.iter() {
+            pending_private_txs.remove(tx_hash);
+        }
+
+        info!(
+            "{} private txs included. {} expired. {} still pending",
+            included.len(),
+            expired.len(),
+            pending_private_txs.len()
+        );
+
+        Ok(())
+    }
+
+    /// Periodically forget private transactions that have been included in a block or expired.
+    pub fn spawn_private_tx_pruner(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.private_tx_prune_loop())
+    }
+
+    async fn private_tx_prune_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        let mut interval = interval(Duration::from_secs(PRIVATE_TX_CHECK_SECONDS));
+
+        loop {
+            interval.tick().await;
+
+            if self.pending_private_txs.read().is_empty() {
+                continue;
+            }
+
+            if let Err(err) = self.prune_private_txs(&authorization).await {
+                warn!("unable to prune private txs. err={:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted_response() -> JsonRpcForwardedResponse {
+        JsonRpcForwardedResponse::from_value(json!("0x00"), to_raw_value(&json!(1)).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_relays_stop_at_deadline() {
+        let relays = vec![
+            (
+                Arc::new(Web3Connection::mock("fast")),
+                tokio::spawn(async { Ok(accepted_response()) }),
+            ),
+            (
+                Arc::new(Web3Connection::mock("hung")),
+                tokio::spawn(futures::future::pending()),
+            ),
+        ];
+
+        let start = Instant::now();
+
+        let responses = collect_relay_responses(
+            relays,
+            2,
+            Duration::from_millis(10),
+            start + Duration::from_millis(200),
+            Default::default(),
+        )
+        .await;
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        assert_eq!(responses.outcomes[0].status, RelayStatus::Accepted);
+        assert_eq!(responses.outcomes[1].status, RelayStatus::Pending);
+        assert!(responses.accepted.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_relays_grace_after_min_relays() {
+        let relays = vec![
+            (
+                Arc::new(Web3Connection::mock("fast")),
+                tokio::spawn(async { Ok(accepted_response()) }),
+            ),
+            (
+                Arc::new(Web3Connection::mock("hung")),
+                tokio::spawn(futures::future::pending()),
+            ),
+        ];
+
+        let start = Instant::now();
+
+        let responses = collect_relay_responses(
+            relays,
+            1,
+            Duration::from_millis(10),
+            start + Duration::from_secs(5),
+            Default::default(),
+        )
+        .await;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(responses.outcomes[0].status, RelayStatus::Accepted);
+        assert_eq!(responses.outcomes[1].status, RelayStatus::Pending);
+    }
+}
@@ -0,0 +1,188 @@
+//! Checks for kubernetes' liveness, readiness, and startup probes.
+//!
+//! Liveness only means the process is answering. Readiness checks everything that requests need.
+use super::Web3ProxyApp;
+use crate::dry_run::ServiceReport;
+use anyhow::Context;
+use ethers::prelude::U64;
+use migration::sea_orm::{ConnectionTrait, Statement};
+use moka::future::Cache;
+use redis_rate_limiter::redis;
+use serde::Serialize;
+use std::sync::{atomic, Arc};
+use tokio::time::error::Elapsed;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Probes run every few seconds, so a slow dependency fails them instead of piling them up
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The readiness endpoints are public. Their database and redis checks are shared for this long
+const PROBE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// The last database and redis checks. Concurrent probes wait for one check instead of each running their own.
+pub struct ProbeCache(Cache<(), Arc<DependencyReports>, hashbrown::hash_map::DefaultHashBuilder>);
+
+impl Default for ProbeCache {
+    fn default() -> Self {
+        Self(
+            Cache::builder()
+                .max_capacity(1)
+                .time_to_live(PROBE_CACHE_TTL)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+        )
+    }
+}
+
+pub struct DependencyReports {
+    database: Option<ServiceReport>,
+    redis: Option<ServiceReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalancedRpcsReport {
+    pub ok: bool,
+    pub synced: usize,
+    pub min_synced_rpcs: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_block: Option<U64>,
+}
+
+/// Which dependency, if any, is keeping the proxy from serving requests.
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub draining: bool,
+    /// why the proxy isn't ready. empty when it is
+    pub problems: Vec<String>,
+    pub balanced_rpcs: BalancedRpcsReport,
+    /// None if there is no database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<ServiceReport>,
+    /// None if there is no redis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redis: Option<ServiceReport>,
+}
+
+fn service_report(start: Instant, result: Result<anyhow::Result<()>, Elapsed>) -> ServiceReport {
+    match result {
+        Ok(Ok(())) => ServiceReport {
+            ok: true,
+            latency_ms: Some(start.elapsed().as_millis() as u64),
+            ..Default::default()
+        },
+        Ok(Err(err)) => ServiceReport {
+            error: Some(format!("{:#}", err)),
+            ..Default::default()
+        },
+        Err(_) => ServiceReport {
+            error: Some("timed out".to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+impl Web3ProxyApp {
+    /// Ready once at least `min_synced_rpcs` balanced rpcs are synced and the database and redis answer.
+    /// Never ready while draining. The database and redis answers can be up to `PROBE_CACHE_TTL` old.
+    pub async fn readiness(&self) -> ReadinessReport {
+        let dependencies = self
+            .probe_cache
+            .0
+            .get_with((), async {
+                let (database, redis) = tokio::join!(self.probe_database(), self.probe_redis());
+
+                Arc::new(DependencyReports { database, redis })
+            })
+            .await;
+
+        let database = dependencies.database.clone();
+        let redis = dependencies.redis.clone();
+
+        let mut problems = vec![];
+
+        let draining = self.is_draining();
+
+        if draining {
+            problems.push("draining".to_string());
+        }
+
+        let synced = self.balanced_rpcs.num_synced_rpcs();
+        let min_synced_rpcs = self.config.min_synced_rpcs;
+
+        // without any synced rpcs there is nothing to serve, even if min_synced_rpcs is 0
+        let balanced_rpcs_ok = synced > 0 && synced >= min_synced_rpcs;
+
+        if !balanced_rpcs_ok {
+            problems.push(format!(
+                "only {}/{} balanced rpcs are synced",
+                synced, min_synced_rpcs
+            ));
+        }
+
+        if let Some(err) = database.as_ref().and_then(|x| x.error.as_ref()) {
+            problems.push(format!("database: {}", err));
+        }
+
+        if let Some(err) = redis.as_ref().and_then(|x| x.error.as_ref()) {
+            problems.push(format!("redis: {}", err));
+        }
+
+        let ready = problems.is_empty();
+
+        if ready {
+            self.started.store(true, atomic::Ordering::Relaxed);
+        }
+
+        ReadinessReport {
+            ready,
+            draining,
+            problems,
+            balanced_rpcs: BalancedRpcsReport {
+                ok: balanced_rpcs_ok,
+                synced,
+                min_synced_rpcs,
+                head_block: self.balanced_rpcs.head_block().map(|x| x.number()),
+            },
+            database,
+            redis,
+        }
+    }
+
+    /// True once the app has been ready. It stays true so that kubernetes moves on to the liveness probe.
+    pub fn started(&self) -> bool {
+        self.started.load(atomic::Ordering::Relaxed)
+    }
+
+    async fn probe_database(&self) -> Option<ServiceReport> {
+        let db_conn = self.db_conn.as_ref()?;
+
+        let start = Instant::now();
+
+        let f = async {
+            let statement =
+                Statement::from_string(db_conn.get_database_backend(), "SELECT 1".to_string());
+
+            db_conn.execute(statement).await?;
+
+            anyhow::Ok(())
+        };
+
+        Some(service_report(start, timeout(PROBE_TIMEOUT, f).await))
+    }
+
+    async fn probe_redis(&self) -> Option<ServiceReport> {
+        self.vredis_pool.as_ref()?;
+
+        let start = Instant::now();
+
+        let f = async {
+            let mut redis_conn = self.redis_conn().await?.context("no redis pool")?;
+
+            let _: String = redis::cmd("PING").query_async(&mut redis_conn).await?;
+
+            anyhow::Ok(())
+        };
+
+        Some(service_report(start, timeout(PROBE_TIMEOUT, f).await))
+    }
+}
@@ -0,0 +1,124 @@
+//! Reload the backend rpcs from a changed config without restarting.
+//!
+//! The `Web3Connections` themselves are kept, so the head block, caches, filters, and frontend subscriptions all carry over.
+
+use super::Web3ProxyApp;
+use crate::config::{TopConfig, Web3ConnectionConfig};
+use crate::rpcs::connections::Web3Connections;
+use crate::rpcs::reload::ConfigDiff;
+use anyhow::Context;
+use hashbrown::HashMap;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// What a reload changed in each group of rpcs.
+#[derive(Debug, Serialize)]
+pub struct RpcsReloaded {
+    pub balanced_rpcs: ConfigDiff,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_rpcs: Option<ConfigDiff>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_rpcs: Option<ConfigDiff>,
+}
+
+/// A running group of rpcs and the configs to apply to it.
+type GroupConfigs<'a> = (
+    &'a Arc<Web3Connections>,
+    HashMap<String, Web3ConnectionConfig>,
+);
+
+/// Groups that are optional are only spawned on startup if they have an enabled rpc.
+/// Adding or emptying one of these groups needs a restart.
+fn optional_group<'a>(
+    name: &str,
+    rpcs: Option<&'a Arc<Web3Connections>>,
+    configs: Option<HashMap<String, Web3ConnectionConfig>>,
+) -> anyhow::Result<Option<GroupConfigs<'a>>> {
+    let configs = configs.filter(|x| x.values().any(|x| !x.disabled));
+
+    match (rpcs, configs) {
+        (Some(rpcs), Some(configs)) => Ok(Some((rpcs, configs))),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err(anyhow::anyhow!(
+            "removing all of the {} needs a restart",
+            name
+        )),
+        (None, Some(_)) => Err(anyhow::anyhow!("adding {} needs a restart", name)),
+    }
+}
+
+impl Web3ProxyApp {
+    /// Every group of rpcs that is running. Private and archive rpcs are optional.
+    pub fn rpc_groups(&self) -> Vec<(&'static str, &Arc<Web3Connections>)> {
+        let mut groups = vec![("balanced", &self.balanced_rpcs)];
+
+        if let Some(x) = self.private_rpcs.as_ref() {
+            groups.push(("private", x));
+        }
+
+        if let Some(x) = self.archive_rpcs.as_ref() {
+            groups.push(("archive", x));
+        }
+
+        groups
+    }
+
+    /// "balanced", "private", or "archive"
+    pub fn rpc_group(&self, name: &str) -> Option<&Arc<Web3Connections>> {
+        self.rpc_groups()
+            .into_iter()
+            .find(|(x, _)| *x == name)
+            .map(|(_, x)| x)
+    }
+
+    /// Connect to added rpcs, disconnect from removed rpcs, and reconnect to changed rpcs.
+    /// Everything else in the config is ignored until a restart.
+    /// The groups themselves are kept, so frontend websockets and their subscriptions carry on with the new rpcs.
+    pub async fn reload_rpcs(&self, top_config: TopConfig) -> anyhow::Result<RpcsReloaded> {
+        if top_config.app.chain_id != self.config.chain_id {
+            return Err(anyhow::anyhow!("changing chain_id needs a restart"));
+        }
+
+        // check everything before changing anything
+        let private_rpcs = optional_group(
+            "private_rpcs",
+            self.private_rpcs.as_ref(),
+            top_config.private_rpcs,
+        )?;
+        let archive_rpcs = optional_group(
+            "archive_rpcs",
+            self.archive_rpcs.as_ref(),
+            top_config.archive_rpcs,
+        )?;
+
+        let balanced_rpcs = self
+            .balanced_rpcs
+            .apply_configs(top_config.balanced_rpcs)
+            .await
+            .context("reloading balanced_rpcs")?;
+
+        let private_rpcs = match private_rpcs {
+            None => None,
+            Some((rpcs, configs)) => Some(
+                rpcs.apply_configs(configs)
+                    .await
+                    .context("reloading private_rpcs")?,
+            ),
+        };
+
+        let archive_rpcs = match archive_rpcs {
+            None => None,
+            Some((rpcs, configs)) => Some(
+                rpcs.apply_configs(configs)
+                    .await
+                    .context("reloading archive_rpcs")?,
+            ),
+        };
+
+        Ok(RpcsReloaded {
+            balanced_rpcs,
+            private_rpcs,
+            archive_rpcs,
+        })
+    }
+}
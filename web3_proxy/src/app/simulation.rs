@@ -0,0 +1,36 @@
+//! `eth_simulateV1` and provider-specific simulations.
+//!
+//! Not every backend has these methods. The connections remember which servers answered "method not found" and skip them.
+use super::Web3ProxyApp;
+use std::time::Duration;
+
+impl Web3ProxyApp {
+    pub(super) fn is_simulation(&self, method: &str) -> bool {
+        self.config
+            .simulation
+            .as_ref()
+            .map(|x| x.methods.iter().any(|x| x == method))
+            .unwrap_or(false)
+    }
+
+    /// Simulations are only allowed for keys that an admin has granted them to
+    pub(super) fn simulation_needs_grant(&self, method: &str) -> bool {
+        self.is_simulation(method)
+            && self
+                .config
+                .simulation
+                .as_ref()
+                .map(|x| x.require_grant)
+                .unwrap_or(false)
+    }
+
+    /// How long a request for this method may take. Simulations can be expensive, so they usually get less time.
+    pub(super) fn method_timeout(&self, method: &str) -> Duration {
+        match self.config.simulation.as_ref() {
+            Some(simulation) if self.is_simulation(method) => self
+                .request_timeout()
+                .min(Duration::from_millis(simulation.timeout_ms)),
+            _ => self.request_timeout(),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Static responses from the config's `method_stubs`
+
+use super::Web3ProxyApp;
+use crate::config::{MethodStubConfig, MethodStubResponse};
+use crate::frontend::authorization::Authorization;
+use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
+
+impl MethodStubConfig {
+    /// true if this stub should answer the request
+    pub fn matches(&self, request: &JsonRpcRequest, rpc_key_id: Option<u64>) -> bool {
+        if self.method != request.method {
+            return false;
+        }
+
+        if !self.rpc_key_ids.is_empty()
+            && !rpc_key_id
+                .map(|x| self.rpc_key_ids.contains(&x))
+                .unwrap_or(false)
+        {
+            return false;
+        }
+
+        match &self.params {
+            None => true,
+            Some(expected) => {
+                let params = request.params.as_ref().and_then(|x| x.as_array());
+
+                expected
+                    .iter()
+                    .enumerate()
+                    .all(|(i, x)| params.and_then(|params| params.get(i)) == Some(x))
+            }
+        }
+    }
+}
+
+impl Web3ProxyApp {
+    /// The static response for the request if the config has one.
+    pub(super) fn method_stub(
+        &self,
+        authorization: &Authorization,
+        request: &JsonRpcRequest,
+    ) -> Option<JsonRpcForwardedResponse> {
+        let rpc_key_id = authorization.checks.rpc_key_id.map(|x| x.get());
+
+        let stub = self
+            .config
+            .method_stubs
+            .iter()
+            .find(|x| x.matches(request, rpc_key_id))?;
+
+        let response = match &stub.response {
+            MethodStubResponse::Result(result) => {
+                JsonRpcForwardedResponse::from_value(result.clone(), request.id.clone())
+            }
+            MethodStubResponse::Error(err) => {
+                let mut response = JsonRpcForwardedResponse::from_string(
+                    err.message.clone(),
+                    Some(err.code),
+                    Some(request.id.clone()),
+                );
+
+                if let Some(error) = response.error.as_mut() {
+                    error.data = err.data.clone();
+                }
+
+                response
+            }
+        };
+
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use serde_json::value::to_raw_value;
+
+    fn request(method: &str, params: serde_json::Value) -> JsonRpcRequest {
+        JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).unwrap(),
+            method: method.to_string(),
+            params: Some(params),
+        }
+    }
+
+    #[test]
+    fn test_stub_matches() {
+        let stub = MethodStubConfig {
+            method: "eth_getBalance".to_string(),
+            params: Some(vec![json!("0x0000000000000000000000000000000000000000")]),
+            rpc_key_ids: vec![],
+            response: MethodStubResponse::Result(json!("0x0")),
+        };
+
+        let zero = request(
+            "eth_getBalance",
+            json!(["0x0000000000000000000000000000000000000000", "latest"]),
+        );
+        let other = request(
+            "eth_getBalance",
+            json!(["0x0000000000000000000000000000000000000001", "latest"]),
+        );
+
+        assert!(stub.matches(&zero, None));
+        assert!(!stub.matches(&other, None));
+        assert!(!stub.matches(&request("eth_call", json!([])), None));
+
+        let keyed = MethodStubConfig {
+            params: None,
+            rpc_key_ids: vec![5],
+            ..stub
+        };
+
+        assert!(keyed.matches(&other, Some(5)));
+        assert!(!keyed.matches(&other, Some(6)));
+        assert!(!keyed.matches(&other, None));
+    }
+}
@@ -0,0 +1,431 @@
+//! The proxy's view of transactions sent with `eth_sendRawTransaction`.
+//!
+//! Each transaction is followed with the head block from pending to mined to confirmed or dropped.
+//! A reorg that removes a mined transaction's block sends it back to pending.
+//! Keys with `rebroadcast_blocks` have their pending transactions sent again.
+
+use super::private_txs::{is_already_known, RelayOutcome};
+use super::{AnyhowJoinHandle, Web3ProxyApp};
+use crate::frontend::authorization::Authorization;
+use crate::jsonrpc::JsonRpcRequest;
+use crate::rpcs::blockchain::ArcBlock;
+use crate::rpcs::request::Traffic;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ethers::prelude::{Bytes, TxHash, H256, U64};
+use futures::stream::{self, StreamExt};
+use hashbrown::HashMap;
+use log::{debug, trace, warn, Level};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+/// A rebroadcast that takes longer than this is counted as an error. The next block tries again
+const REBROADCAST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many tracked transactions are checked at once on each new head block
+const MAX_CONCURRENT_TX_CHECKS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackedTxState {
+    /// sent but not in a block yet
+    Pending,
+    /// in a block that is not deep enough to be confirmed
+    Mined,
+    Confirmed,
+    /// not in a block or a mempool long after it was sent
+    Dropped,
+}
+
+impl TrackedTxState {
+    /// confirmed and dropped transactions are no longer checked
+    fn is_final(&self) -> bool {
+        matches!(self, Self::Confirmed | Self::Dropped)
+    }
+}
+
+/// One transaction's lifecycle. Served at `/tx/:tx_hash` and by `proxy_tx` subscriptions.
+#[derive(Clone, Debug, Serialize)]
+pub struct TrackedTx {
+    pub tx_hash: TxHash,
+    pub state: TrackedTxState,
+    pub sent_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// the head block when the transaction was sent
+    pub first_seen_block: U64,
+    pub block_number: Option<U64>,
+    pub block_hash: Option<H256>,
+    /// how many blocks deep the transaction is. 1 when it is in the head block
+    pub confirmations: u64,
+    /// set if the transaction was sent to the private relays
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub relays: Vec<RelayOutcome>,
+    /// how many times the proxy sent the transaction again
+    pub rebroadcasts: u64,
+    /// the head block when the transaction was last sent
+    pub last_broadcast_block: U64,
+    #[serde(skip)]
+    raw_tx: Bytes,
+    /// from the key's `rebroadcast_blocks`
+    #[serde(skip)]
+    rebroadcast_blocks: Option<u64>,
+}
+
+/// Counters for the prometheus endpoint.
+#[derive(Debug, Default, Serialize)]
+pub struct TxTrackerMetrics {
+    /// pending transactions that were sent again
+    pub rebroadcasts: AtomicU64,
+    /// rebroadcasts that no backend or relay accepted
+    pub rebroadcast_errors: AtomicU64,
+}
+
+/// Transactions that are being followed and a channel for their changes.
+pub struct TxTracker {
+    txs: RwLock<HashMap<TxHash, TrackedTx>>,
+    /// every state change is sent here
+    pub sender: broadcast::Sender<TrackedTx>,
+    pub metrics: TxTrackerMetrics,
+}
+
+impl Default for TxTracker {
+    fn default() -> Self {
+        Self {
+            txs: Default::default(),
+            sender: broadcast::channel(1024).0,
+            metrics: Default::default(),
+        }
+    }
+}
+
+/// The parts of a receipt that we need.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TxReceipt {
+    block_number: U64,
+    block_hash: H256,
+}
+
+impl Web3ProxyApp {
+    /// The proxy's view of a transaction. None if it was not sent through this proxy or was forgotten.
+    pub fn tracked_tx(&self, tx_hash: &TxHash) -> Option<TrackedTx> {
+        self.tracked_txs.txs.read().get(tx_hash).cloned()
+    }
+
+    /// Start following a transaction that was just sent.
+    pub fn track_tx(
+        &self,
+        tx_hash: TxHash,
+        raw_tx: Bytes,
+        relays: Vec<RelayOutcome>,
+        rebroadcast_blocks: Option<u64>,
+    ) {
+        let now = virtual_clock::utc_now();
+
+        let head_block_num = self.balanced_rpcs.head_block_num().unwrap_or_default();
+
+        let tx = TrackedTx {
+            tx_hash,
+            state: TrackedTxState::Pending,
+            sent_at: now,
+            updated_at: now,
+            first_seen_block: head_block_num,
+            block_number: None,
+            block_hash: None,
+            confirmations: 0,
+            relays,
+            rebroadcasts: 0,
+            last_broadcast_block: head_block_num,
+            raw_tx,
+            rebroadcast_blocks,
+        };
+
+        {
+            let mut txs = self.tracked_txs.txs.write();
+
+            if let Some(existing) = txs.get(&tx_hash) {
+                // users resend transactions. keep following the first one
+                if !existing.state.is_final() {
+                    return;
+                }
+            }
+
+            if txs.len() >= self.config.tx_tracking.max_tracked {
+                let oldest = txs.values().min_by_key(|x| x.updated_at).map(|x| x.tx_hash);
+
+                if let Some(oldest) = oldest {
+                    txs.remove(&oldest);
+                }
+            }
+
+            txs.insert(tx_hash, tx.clone());
+        }
+
+        // no subscribers is fine
+        let _ = self.tracked_txs.sender.send(tx);
+    }
+
+    /// Follow tracked transactions with the head block.
+    pub fn spawn_tx_tracker(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.tx_tracker_loop())
+    }
+
+    async fn tx_tracker_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        let mut head_block_receiver = self.subscription_sources.head_block_receiver.clone();
+
+        while head_block_receiver.changed().await.is_ok() {
+            let head_block = head_block_receiver.borrow().clone();
+
+            if let Err(err) = self.update_tracked_txs(&authorization, head_block).await {
+                warn!("unable to update tracked txs. err={:?}", err);
+            }
+        }
+
+        Err(anyhow::anyhow!("head block sender dropped"))
+    }
+
+    async fn update_tracked_txs(
+        &self,
+        authorization: &Arc<Authorization>,
+        head_block: ArcBlock,
+    ) -> anyhow::Result<()> {
+        let head_block_num = match head_block.number {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let config = &self.config.tx_tracking;
+
+        let forget_before = virtual_clock::utc_now()
+            - chrono::Duration::seconds(config.forget_after_seconds as i64);
+
+        let active: Vec<TrackedTx> = {
+            let mut txs = self.tracked_txs.txs.write();
+
+            txs.retain(|_, x| !x.state.is_final() || x.updated_at > forget_before);
+
+            txs.values()
+                .filter(|x| !x.state.is_final())
+                .cloned()
+                .collect()
+        };
+
+        // one tx failing to update shouldn't stop the others
+        stream::iter(active)
+            .for_each_concurrent(MAX_CONCURRENT_TX_CHECKS, |tx| async move {
+                let tx_hash = tx.tx_hash;
+
+                if let Err(err) = self
+                    .update_tracked_tx(authorization, tx, head_block_num)
+                    .await
+                {
+                    warn!("unable to update tracked tx {:?}. err={:?}", tx_hash, err);
+                }
+            })
+            .await;
+
+        Ok(())
+    }
+
+    async fn update_tracked_tx(
+        &self,
+        authorization: &Arc<Authorization>,
+        mut tx: TrackedTx,
+        head_block_num: U64,
+    ) -> anyhow::Result<()> {
+        let config = &self.config.tx_tracking;
+
+        let old = (tx.state, tx.block_hash, tx.confirmations, tx.rebroadcasts);
+
+        if let Some(block_hash) = tx.block_hash {
+            let block_number = tx.block_number.context("mined txs have a block number")?;
+
+            let (canonical_hash, _) = self
+                .balanced_rpcs
+                .block_hash(authorization, &block_number)
+                .await?;
+
+            if canonical_hash != block_hash {
+                debug!("tracked tx {:?} was reorged out", tx.tx_hash);
+
+                tx.state = TrackedTxState::Pending;
+                tx.block_number = None;
+                tx.block_hash = None;
+                tx.confirmations = 0;
+            }
+        }
+
+        if tx.block_hash.is_none() {
+            if let Some(receipt) = self.tx_receipt(authorization, tx.tx_hash).await? {
+                tx.state = TrackedTxState::Mined;
+                tx.block_number = Some(receipt.block_number);
+                tx.block_hash = Some(receipt.block_hash);
+            } else if head_block_num.saturating_sub(tx.first_seen_block)
+                >= config.dropped_after_blocks.into()
+                && !self.tx_known(authorization, tx.tx_hash).await?
+            {
+                tx.state = TrackedTxState::Dropped;
+            }
+        }
+
+        if let Some(rebroadcast_blocks) = tx.rebroadcast_blocks {
+            if tx.state == TrackedTxState::Pending
+                && head_block_num.saturating_sub(tx.last_broadcast_block)
+                    >= rebroadcast_blocks.into()
+            {
+                self.rebroadcast_tx(authorization, &tx).await;
+
+                tx.rebroadcasts += 1;
+                tx.last_broadcast_block = head_block_num;
+            }
+        }
+
+        if let Some(block_number) = tx.block_number {
+            tx.confirmations = (head_block_num.saturating_sub(block_number) + 1).as_u64();
+
+            if tx.confirmations >= config.confirmations {
+                tx.state = TrackedTxState::Confirmed;
+            }
+        }
+
+        if old == (tx.state, tx.block_hash, tx.confirmations, tx.rebroadcasts) {
+            return Ok(());
+        }
+
+        trace!("tracked tx {:?} is {:?}", tx.tx_hash, tx.state);
+
+        tx.updated_at = virtual_clock::utc_now();
+
+        {
+            let mut txs = self.tracked_txs.txs.write();
+
+            // the tx might have been forgotten while we were checking it
+            match txs.get_mut(&tx.tx_hash) {
+                Some(x) => *x = tx.clone(),
+                None => return Ok(()),
+            }
+        }
+
+        let _ = self.tracked_txs.sender.send(tx);
+
+        Ok(())
+    }
+
+    /// Send a stuck transaction again. Private transactions only go to the private relays.
+    async fn rebroadcast_tx(&self, authorization: &Arc<Authorization>, tx: &TrackedTx) {
+        debug!("rebroadcasting tx {:?}", tx.tx_hash);
+
+        self.tracked_txs
+            .metrics
+            .rebroadcasts
+            .fetch_add(1, Ordering::Relaxed);
+
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).expect("numbers should always be valid json"),
+            method: "eth_sendRawTransaction".to_string(),
+            params: Some(json!([tx.raw_tx])),
+        };
+
+        let rpcs = match self.private_rpcs.as_ref() {
+            Some(private_rpcs) if !tx.relays.is_empty() => private_rpcs,
+            _ => &self.balanced_rpcs,
+        };
+
+        let response = timeout(
+            REBROADCAST_TIMEOUT,
+            rpcs.try_send_all_upstream_servers(authorization, &request, None, None, Level::Trace),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", REBROADCAST_TIMEOUT)));
+
+        let accepted = match response {
+            // servers that still have the transaction say so. that is fine
+            Ok(response) => response
+                .error
+                .as_ref()
+                .map(is_already_known)
+                .unwrap_or(true),
+            Err(err) => {
+                trace!("rebroadcast of {:?} failed: {:?}", tx.tx_hash, err);
+                false
+            }
+        };
+
+        if !accepted {
+            self.tracked_txs
+                .metrics
+                .rebroadcast_errors
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn tx_request(
+        &self,
+        authorization: &Arc<Authorization>,
+        method: &str,
+        tx_hash: TxHash,
+    ) -> anyhow::Result<serde_json::Value> {
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).expect("numbers should always be valid json"),
+            method: method.to_string(),
+            params: Some(json!([tx_hash])),
+        };
+
+        let response = self
+            .balanced_rpcs
+            .try_send_best_upstream_server(
+                self.allowed_lag,
+                authorization,
+                request,
+                None,
+                None,
+                Traffic::Request,
+            )
+            .await?;
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("{} failed: {}", method, err.message));
+        }
+
+        match response.result {
+            Some(result) => serde_json::from_str(result.get()).context("parsing result"),
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+
+    async fn tx_receipt(
+        &self,
+        authorization: &Arc<Authorization>,
+        tx_hash: TxHash,
+    ) -> anyhow::Result<Option<TxReceipt>> {
+        let receipt = self
+            .tx_request(authorization, "eth_getTransactionReceipt", tx_hash)
+            .await?;
+
+        serde_json::from_value(receipt).context("parsing receipt")
+    }
+
+    /// true if a backend still has the transaction in its mempool or a block
+    async fn tx_known(
+        &self,
+        authorization: &Arc<Authorization>,
+        tx_hash: TxHash,
+    ) -> anyhow::Result<bool> {
+        let tx = self
+            .tx_request(authorization, "eth_getTransactionByHash", tx_hash)
+            .await?;
+
+        Ok(!tx.is_null())
+    }
+}
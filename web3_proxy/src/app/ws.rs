@@ -1,33 +1,249 @@
 //! Websocket-specific functions for the Web3ProxyApp
 
-use super::{Web3ProxyApp, REQUEST_PERIOD};
-use crate::app_stats::ProxyResponseStat;
+use super::{
+    AnyhowJoinHandle, Web3ProxyApp, NO_SERVERS_SYNCED_ERROR_CODE, NO_SERVERS_SYNCED_MESSAGE,
+    REQUEST_PERIOD,
+};
+use crate::app_stats::{ProxyResponseStat, StatSender};
+use crate::config::SubscriptionIdFormat;
 use crate::frontend::authorization::{Authorization, RequestMetadata};
-use crate::jsonrpc::JsonRpcForwardedResponse;
 use crate::jsonrpc::JsonRpcRequest;
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse};
+use crate::rpcs::blockchain::{ArcBlock, Reorg, MAX_REORG_DEPTH};
+use crate::rpcs::connections::Web3Connections;
 use crate::rpcs::transactions::TxStatus;
 use anyhow::Context;
 use axum::extract::ws::Message;
-use ethers::prelude::U64;
+use ethers::prelude::{TxHash, H128, H256, U64};
 use futures::future::AbortHandle;
 use futures::future::Abortable;
 use futures::stream::StreamExt;
+use hashbrown::HashMap;
 use log::{trace, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
 use serde_json::json;
-use std::sync::atomic::{self, AtomicUsize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
+use thread_fast_rng::rand::{thread_rng, Rng};
+use tokio::sync::{broadcast, watch};
+use tokio::time::interval;
 use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use ulid::Ulid;
+
+/// The subscriptions that we serve from our own channels instead of forwarding to a backend.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SubscriptionKind {
+    NewHeads,
+    NewPendingTransactions,
+    NewPendingFullTransactions,
+    NewPendingRawTransactions,
+    /// follows the consensus head and asks a synced backend for each new block's logs
+    Logs,
+    /// not a standard subscription. sent when the proxy's consensus head re-roots
+    ProxyReorgs,
+    /// not a standard subscription. every change to a transaction tracked by the proxy
+    ProxyTx,
+}
+
+impl SubscriptionKind {
+    fn from_params(params: Option<&serde_json::Value>) -> Option<Self> {
+        // TODO: i think we need a stricter EthSubscribeRequest type that JsonRpcRequest can turn into
+        match params?.as_array()?.as_slice() {
+            [x] => match x.as_str()? {
+                "newHeads" => Some(Self::NewHeads),
+                "newPendingTransactions" => Some(Self::NewPendingTransactions),
+                "newPendingFullTransactions" => Some(Self::NewPendingFullTransactions),
+                "newPendingRawTransactions" => Some(Self::NewPendingRawTransactions),
+                "proxy_reorgs" => Some(Self::ProxyReorgs),
+                "logs" => Some(Self::Logs),
+                _ => None,
+            },
+            [x, _] if x.as_str() == Some("logs") => Some(Self::Logs),
+            [x, _] if x.as_str() == Some("proxy_tx") => Some(Self::ProxyTx),
+            _ => None,
+        }
+    }
+
+    /// The `address` and `topics` of a `logs` subscription. Anything else in the filter object is ignored.
+    fn log_filter(params: Option<&serde_json::Value>) -> anyhow::Result<Option<serde_json::Value>> {
+        let filter = match params.and_then(|x| x.get(1)) {
+            None => return Ok(None),
+            Some(x) => x.as_object().context("logs filter must be an object")?,
+        };
+
+        let filter: serde_json::Map<_, _> = ["address", "topics"]
+            .into_iter()
+            .filter_map(|key| filter.get(key).map(|x| (key.to_string(), x.clone())))
+            .collect();
+
+        Ok(Some(filter.into()))
+    }
+
+    /// The transaction hash of a `proxy_tx` subscription.
+    fn tx_hash(params: Option<&serde_json::Value>) -> anyhow::Result<TxHash> {
+        let tx_hash = params
+            .and_then(|x| x.get(1))
+            .cloned()
+            .context("proxy_tx needs a transaction hash")?;
+
+        serde_json::from_value(tx_hash).context("invalid transaction hash")
+    }
+
+    /// the method name used in stats
+    fn stat_method(&self) -> &'static str {
+        match self {
+            Self::NewHeads => "eth_subscription(newHeads)",
+            Self::NewPendingTransactions => "eth_subscription(newPendingTransactions)",
+            Self::NewPendingFullTransactions => "eth_subscription(newPendingFullTransactions)",
+            Self::NewPendingRawTransactions => "eth_subscription(newPendingRawTransactions)",
+            Self::Logs => "eth_subscription(logs)",
+            Self::ProxyReorgs => "eth_subscription(proxy_reorgs)",
+            Self::ProxyTx => "eth_subscription(proxy_tx)",
+        }
+    }
+}
+
+/// the standard json-rpc code for bad params
+static INVALID_PARAMS_ERROR_CODE: i64 = -32602;
+/// geth uses this code when `eth_unsubscribe` gets an id it doesn't know
+static SUBSCRIPTION_NOT_FOUND_ERROR_CODE: i64 = -32000;
+
+/// The id that a client uses for one of its subscriptions.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum SubscriptionId {
+    Random(H128),
+    Sequential(U64),
+}
+
+impl fmt::Display for SubscriptionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Random(x) => write!(f, "{:#x}", x),
+            Self::Sequential(x) => write!(f, "{:#x}", x),
+        }
+    }
+}
+
+/// The subscriptions on one websocket.
+/// Ids are only unique per websocket, so they are matched to the app's subscription keys here.
+#[derive(Default)]
+pub struct WebsocketSubscriptions {
+    /// keyed by the lowercase id so that unsubscribe matches however the client formatted it
+    ids: HashMap<String, Ulid>,
+    count: u64,
+}
+
+impl WebsocketSubscriptions {
+    /// An id that isn't already used on this websocket.
+    pub fn new_id(&mut self, format: SubscriptionIdFormat) -> SubscriptionId {
+        loop {
+            let id = match format {
+                // thread_rng is cryptographically secure. ids must not be guessable
+                SubscriptionIdFormat::Random => SubscriptionId::Random(H128(thread_rng().gen())),
+                SubscriptionIdFormat::Sequential => {
+                    self.count += 1;
+                    SubscriptionId::Sequential(self.count.into())
+                }
+            };
+
+            if !self.ids.contains_key(&id.to_string()) {
+                return id;
+            }
+        }
+    }
+
+    pub fn insert(&mut self, id: SubscriptionId, key: Ulid) {
+        self.ids.insert(id.to_string(), key);
+    }
+
+    /// Remove the subscription named by `eth_unsubscribe`'s params. They can be `["0x..."]` or just `"0x..."`.
+    /// The errors match geth's.
+    pub fn remove(&mut self, params: Option<&serde_json::Value>) -> Result<Ulid, JsonRpcErrorData> {
+        let id = params
+            .and_then(unsubscribe_id)
+            .ok_or_else(|| JsonRpcErrorData {
+                code: INVALID_PARAMS_ERROR_CODE,
+                message: "invalid params. expected a subscription id".to_string(),
+                data: None,
+            })?;
+
+        self.ids.remove(&id).ok_or_else(|| JsonRpcErrorData {
+            code: SUBSCRIPTION_NOT_FOUND_ERROR_CODE,
+            message: "subscription not found".to_string(),
+            data: None,
+        })
+    }
+
+    /// The app's keys for every subscription on this websocket.
+    pub fn keys(&self) -> impl Iterator<Item = &Ulid> {
+        self.ids.values()
+    }
+}
+
+/// The id from `eth_unsubscribe`'s params, lowercased.
+fn unsubscribe_id(params: &serde_json::Value) -> Option<String> {
+    let id = match params {
+        serde_json::Value::Array(x) if x.len() == 1 => x[0].as_str()?,
+        serde_json::Value::String(x) => x.as_str(),
+        _ => return None,
+    };
+
+    Some(id.to_lowercase())
+}
+
+/// The channels that frontend subscriptions read from.
+/// Config reloads change the rpcs inside `balanced_rpcs` instead of replacing it, so these stay the same and subscriptions carry on.
+pub struct SubscriptionSources {
+    pub head_block_receiver: watch::Receiver<ArcBlock>,
+    pub pending_tx_sender: broadcast::Sender<TxStatus>,
+    pub reorg_sender: broadcast::Sender<Arc<Reorg>>,
+    /// `logs` subscriptions ask these for each new block's logs
+    pub balanced_rpcs: Arc<Web3Connections>,
+}
+
+/// What is needed to notify or stop a subscription.
+struct ActiveSubscription {
+    subscription_id: SubscriptionId,
+    response_sender: flume::Sender<Message>,
+    abort_handle: AbortHandle,
+}
+
+/// Every active frontend subscription. Keyed by a unique id instead of the subscription id since those are only unique per websocket.
+#[derive(Default)]
+pub struct SubscriptionRegistry {
+    subscriptions: Mutex<HashMap<Ulid, ActiveSubscription>>,
+}
+
+impl SubscriptionRegistry {
+    /// the subscription's task exited on its own
+    fn remove_finished(&self, key: &Ulid) {
+        self.subscriptions.lock().remove(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscriptions.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.lock().is_empty()
+    }
+}
 
 impl Web3ProxyApp {
     // TODO: #[measure([ErrorCount, HitCount, ResponseTime, Throughput])]
-    pub async fn eth_subscribe<'a>(
-        self: &'a Arc<Self>,
+    pub async fn eth_subscribe(
+        self: &Arc<Self>,
         authorization: Arc<Authorization>,
         request_json: JsonRpcRequest,
-        subscription_count: &'a AtomicUsize,
+        subscription_id: SubscriptionId,
         // TODO: taking a sender for Message instead of the exact json we are planning to send feels wrong, but its easier for now
         response_sender: flume::Sender<Message>,
-    ) -> anyhow::Result<(AbortHandle, JsonRpcForwardedResponse)> {
+    ) -> anyhow::Result<(Ulid, JsonRpcForwardedResponse)> {
         // TODO: this is not efficient
         let request_bytes = serde_json::to_string(&request_json)
             .context("finding request size")?
@@ -36,177 +252,358 @@ impl Web3ProxyApp {
         let request_metadata =
             Arc::new(RequestMetadata::new(REQUEST_PERIOD, request_bytes).unwrap());
 
-        let (subscription_abort_handle, subscription_registration) = AbortHandle::new_pair();
+        let kind =
+            SubscriptionKind::from_params(request_json.params.as_ref()).context("unimplemented")?;
 
-        // TODO: this only needs to be unique per connection. we don't need it globably unique
-        let subscription_id = subscription_count.fetch_add(1, atomic::Ordering::SeqCst);
-        let subscription_id = U64::from(subscription_id);
+        let filter = match kind {
+            SubscriptionKind::Logs => Some(
+                SubscriptionKind::log_filter(request_json.params.as_ref())?
+                    .unwrap_or_else(|| json!({})),
+            ),
+            SubscriptionKind::ProxyTx => Some(json!(SubscriptionKind::tx_hash(
+                request_json.params.as_ref()
+            )?)),
+            _ => None,
+        };
 
         // save the id so we can use it in the response
         let id = request_json.id.clone();
 
-        // TODO: calling json! on every request is probably not fast. but we can only match against
-        // TODO: i think we need a stricter EthSubscribeRequest type that JsonRpcRequest can turn into
-        match request_json.params.as_ref() {
-            Some(x) if x == &json!(["newHeads"]) => {
-                let authorization = authorization.clone();
-                let head_block_receiver = self.head_block_receiver.clone();
-                let stat_sender = self.stat_sender.clone();
+        let key = Ulid::new();
+
+        {
+            // hold the lock while spawning so that a task that exits immediately can't try to remove itself before it is registered
+            let mut subscriptions = self.subscriptions.subscriptions.lock();
+
+            let abort_handle = self.spawn_subscription(
+                key,
+                kind,
+                filter,
+                subscription_id,
+                authorization.clone(),
+                response_sender.clone(),
+            );
+
+            subscriptions.insert(
+                key,
+                ActiveSubscription {
+                    subscription_id,
+                    response_sender,
+                    abort_handle,
+                },
+            );
+        }
+
+        trace!("{:?} subscription {:?}", kind, subscription_id);
+
+        let response = JsonRpcForwardedResponse::from_value(json!(subscription_id), id);
+
+        if let Some(stat_sender) = self.stat_sender.as_ref() {
+            let response_stat = ProxyResponseStat::new(
+                request_json.method.clone(),
+                authorization.clone(),
+                request_metadata,
+                response.num_bytes(),
+            );
+
+            if let Err(err) = stat_sender.send_async(response_stat.into()).await {
+                // TODO: what should we do?
+                warn!("stat_sender failed inside websocket: {:?}", err);
+            }
+        }
+
+        Ok((key, response))
+    }
+
+    /// Stop a subscription started by `eth_subscribe`. Returns false if the subscription was not found.
+    pub fn eth_unsubscribe(&self, key: &Ulid) -> bool {
+        match self.subscriptions.subscriptions.lock().remove(key) {
+            None => false,
+            Some(x) => {
+                x.abort_handle.abort();
+                true
+            }
+        }
+    }
+
+    /// Tell every subscriber when the proxy loses all of its synced backends.
+    /// Otherwise their subscriptions would silently stall.
+    pub fn spawn_sync_watcher(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.sync_watcher_loop())
+    }
+
+    async fn sync_watcher_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = interval(Duration::from_secs(1));
+
+        let mut was_synced = false;
+
+        loop {
+            interval.tick().await;
+
+            let synced = self.balanced_rpcs.synced();
+
+            if was_synced && !synced {
+                warn!(
+                    "no servers synced! notifying {} subscriptions",
+                    self.subscriptions.len()
+                );
+
+                self.notify_subscriptions_not_synced();
+            }
+
+            was_synced = synced;
+        }
+    }
+
+    fn notify_subscriptions_not_synced(&self) {
+        for x in self.subscriptions.subscriptions.lock().values() {
+            // TODO: make a struct for this? this matches send_subscription_message except for the error
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {
+                    "subscription": x.subscription_id,
+                    "error": {
+                        "code": NO_SERVERS_SYNCED_ERROR_CODE,
+                        "message": NO_SERVERS_SYNCED_MESSAGE,
+                    },
+                },
+            });
+
+            let notification =
+                serde_json::to_string(&notification).expect("this should always be valid json");
+
+            // if the client is gone, the subscription's task will clean up
+            let _ = x.response_sender.send(Message::Text(notification));
+        }
+    }
+
+    fn spawn_subscription(
+        &self,
+        key: Ulid,
+        kind: SubscriptionKind,
+        filter: Option<serde_json::Value>,
+        subscription_id: SubscriptionId,
+        authorization: Arc<Authorization>,
+        response_sender: flume::Sender<Message>,
+    ) -> AbortHandle {
+        let (subscription_abort_handle, subscription_registration) = AbortHandle::new_pair();
+
+        let sources = self.subscription_sources.clone();
+        let registry = self.subscriptions.clone();
+        let stat_sender = self.stat_sender.clone();
+
+        match kind {
+            SubscriptionKind::NewHeads => {
+                let mut head_block_receiver = Abortable::new(
+                    WatchStream::new(sources.head_block_receiver.clone()),
+                    subscription_registration,
+                );
+
+                let rpcs = sources.balanced_rpcs.clone();
 
-                trace!("newHeads subscription {:?}", subscription_id);
                 tokio::spawn(async move {
-                    let mut head_block_receiver = Abortable::new(
-                        WatchStream::new(head_block_receiver),
-                        subscription_registration,
-                    );
-
-                    while let Some(new_head) = head_block_receiver.next().await {
-                        // TODO: what should the payload for RequestMetadata be?
-                        let request_metadata =
-                            Arc::new(RequestMetadata::new(REQUEST_PERIOD, 0).unwrap());
-
-                        // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
-                        let response_json = json!({
-                            "jsonrpc": "2.0",
-                            "method":"eth_subscription",
-                            "params": {
-                                "subscription": subscription_id,
-                                // TODO: option to include full transaction objects instead of just the hashes?
-                                "result": new_head.as_ref(),
-                            },
-                        });
-
-                        let response_str = serde_json::to_string(&response_json)
-                            .expect("this should always be valid json");
-
-                        // we could use response.num_bytes() here, but since we already have the string, this is easier
-                        let response_bytes = response_str.len();
-
-                        // TODO: do clients support binary messages?
-                        let response_msg = Message::Text(response_str);
-
-                        if response_sender.send_async(response_msg).await.is_err() {
-                            // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
-                            break;
-                        };
+                    let rpcs = &rpcs;
+                    let authorization = &authorization;
 
-                        if let Some(stat_sender) = stat_sender.as_ref() {
-                            let response_stat = ProxyResponseStat::new(
-                                "eth_subscription(newHeads)".to_string(),
-                                authorization.clone(),
-                                request_metadata.clone(),
-                                response_bytes,
-                            );
+                    let mut sent_heads = SentHeads::default();
 
-                            if let Err(err) = stat_sender.send_async(response_stat.into()).await {
-                                // TODO: what should we do?
-                                warn!(
-                                    "stat_sender failed inside newPendingTransactions: {:?}",
-                                    err
-                                );
+                    'heads: while let Some(new_head) = head_block_receiver.next().await {
+                        let heads = sent_heads
+                            .new_head(new_head, move |hash| async move {
+                                rpcs.block(authorization, &hash, None).await
+                            })
+                            .await;
+
+                        for head in heads {
+                            // TODO: option to include full transaction objects instead of just the hashes?
+                            if !send_subscription_message(
+                                &response_sender,
+                                stat_sender.as_ref(),
+                                authorization,
+                                kind,
+                                subscription_id,
+                                json!(head.as_ref()),
+                            )
+                            .await
+                            {
+                                break 'heads;
                             }
                         }
                     }
 
+                    registry.remove_finished(&key);
+
                     trace!("closed newHeads subscription {:?}", subscription_id);
                 });
             }
-            Some(x) if x == &json!(["newPendingTransactions"]) => {
-                let pending_tx_receiver = self.pending_tx_sender.subscribe();
-                let stat_sender = self.stat_sender.clone();
-                let authorization = authorization.clone();
-
-                let mut pending_tx_receiver = Abortable::new(
-                    BroadcastStream::new(pending_tx_receiver),
+            SubscriptionKind::Logs => {
+                let mut head_block_receiver = Abortable::new(
+                    WatchStream::new(sources.head_block_receiver.clone()),
                     subscription_registration,
                 );
 
-                trace!(
-                    "pending newPendingTransactions subscription id: {:?}",
-                    subscription_id
-                );
+                let rpcs = sources.balanced_rpcs.clone();
+                let allowed_lag = self.allowed_lag;
 
-                // TODO: do something with this handle?
-                tokio::spawn(async move {
-                    while let Some(Ok(new_tx_state)) = pending_tx_receiver.next().await {
-                        let request_metadata =
-                            Arc::new(RequestMetadata::new(REQUEST_PERIOD, 0).unwrap());
+                let mut logs = LogSubscription::new(filter.unwrap_or_else(|| json!({})));
 
-                        let new_tx = match new_tx_state {
-                            TxStatus::Pending(tx) => tx,
-                            TxStatus::Confirmed(..) => continue,
-                            TxStatus::Orphaned(tx) => tx,
+                tokio::spawn(async move {
+                    'heads: while let Some(new_head) = head_block_receiver.next().await {
+                        let notifications = match logs
+                            .new_head(&rpcs, allowed_lag, &authorization, new_head)
+                            .await
+                        {
+                            Ok(x) => x,
+                            Err(err) => {
+                                // the blocks we couldn't walk are tried again with the next head
+                                warn!("logs subscription {:?} failed: {:?}", subscription_id, err);
+                                continue;
+                            }
                         };
 
-                        // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
-                        let response_json = json!({
-                            "jsonrpc": "2.0",
-                            "method": "eth_subscription",
-                            "params": {
-                                "subscription": subscription_id,
-                                "result": new_tx.hash,
-                            },
-                        });
+                        for log in notifications {
+                            if !send_subscription_message(
+                                &response_sender,
+                                stat_sender.as_ref(),
+                                &authorization,
+                                kind,
+                                subscription_id,
+                                log,
+                            )
+                            .await
+                            {
+                                break 'heads;
+                            }
+                        }
+                    }
 
-                        let response_str = serde_json::to_string(&response_json)
-                            .expect("this should always be valid json");
+                    registry.remove_finished(&key);
 
-                        // we could use response.num_bytes() here, but since we already have the string, this is easier
-                        let response_bytes = response_str.len();
+                    trace!("closed logs subscription {:?}", subscription_id);
+                });
+            }
+            SubscriptionKind::ProxyReorgs => {
+                let mut reorg_receiver = Abortable::new(
+                    BroadcastStream::new(sources.reorg_sender.subscribe()),
+                    subscription_registration,
+                );
 
-                        // TODO: do clients support binary messages?
-                        let response_msg = Message::Text(response_str);
+                tokio::spawn(async move {
+                    while let Some(reorg) = reorg_receiver.next().await {
+                        let reorg = match reorg {
+                            Ok(x) => x,
+                            Err(err) => {
+                                // a slow client missed some reorgs. keep sending the new ones
+                                warn!("reorg subscription {:?} lagged: {:?}", subscription_id, err);
+                                continue;
+                            }
+                        };
 
-                        if response_sender.send_async(response_msg).await.is_err() {
-                            // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
+                        if !send_subscription_message(
+                            &response_sender,
+                            stat_sender.as_ref(),
+                            &authorization,
+                            kind,
+                            subscription_id,
+                            json!(reorg.as_ref()),
+                        )
+                        .await
+                        {
                             break;
-                        };
+                        }
+                    }
+
+                    registry.remove_finished(&key);
+
+                    trace!("closed proxy_reorgs subscription {:?}", subscription_id);
+                });
+            }
+            SubscriptionKind::ProxyTx => {
+                let tx_hash: TxHash = filter
+                    .and_then(|x| serde_json::from_value(x).ok())
+                    .expect("proxy_tx subscriptions always have a tx hash");
 
-                        if let Some(stat_sender) = stat_sender.as_ref() {
-                            let response_stat = ProxyResponseStat::new(
-                                "eth_subscription(newPendingTransactions)".to_string(),
-                                authorization.clone(),
-                                request_metadata.clone(),
-                                response_bytes,
-                            );
+                // subscribe before reading the current state so that no change is missed
+                let mut tx_receiver = Abortable::new(
+                    BroadcastStream::new(self.tracked_txs.sender.subscribe()),
+                    subscription_registration,
+                );
+
+                let current = self.tracked_tx(&tx_hash);
+
+                tokio::spawn(async move {
+                    // the tx might have been sent before the subscription
+                    if let Some(tx) = current {
+                        if !send_subscription_message(
+                            &response_sender,
+                            stat_sender.as_ref(),
+                            &authorization,
+                            kind,
+                            subscription_id,
+                            json!(tx),
+                        )
+                        .await
+                        {
+                            registry.remove_finished(&key);
+                            return;
+                        }
+                    }
 
-                            if let Err(err) = stat_sender.send_async(response_stat.into()).await {
-                                // TODO: what should we do?
+                    while let Some(tx) = tx_receiver.next().await {
+                        let tx = match tx {
+                            Ok(x) if x.tx_hash == tx_hash => x,
+                            Ok(_) => continue,
+                            Err(err) => {
                                 warn!(
-                                    "stat_sender failed inside newPendingTransactions: {:?}",
-                                    err
+                                    "proxy_tx subscription {:?} lagged: {:?}",
+                                    subscription_id, err
                                 );
+                                continue;
                             }
+                        };
+
+                        if !send_subscription_message(
+                            &response_sender,
+                            stat_sender.as_ref(),
+                            &authorization,
+                            kind,
+                            subscription_id,
+                            json!(tx),
+                        )
+                        .await
+                        {
+                            break;
                         }
                     }
 
-                    trace!(
-                        "closed newPendingTransactions subscription: {:?}",
-                        subscription_id
-                    );
+                    registry.remove_finished(&key);
+
+                    trace!("closed proxy_tx subscription {:?}", subscription_id);
                 });
             }
-            Some(x) if x == &json!(["newPendingFullTransactions"]) => {
-                // TODO: too much copy/pasta with newPendingTransactions
-                let authorization = authorization.clone();
-                let pending_tx_receiver = self.pending_tx_sender.subscribe();
-                let stat_sender = self.stat_sender.clone();
-
+            _ => {
                 let mut pending_tx_receiver = Abortable::new(
-                    BroadcastStream::new(pending_tx_receiver),
+                    BroadcastStream::new(sources.pending_tx_sender.subscribe()),
                     subscription_registration,
                 );
 
-                trace!(
-                    "pending newPendingFullTransactions subscription: {:?}",
-                    subscription_id
-                );
-
                 // TODO: do something with this handle?
                 tokio::spawn(async move {
-                    while let Some(Ok(new_tx_state)) = pending_tx_receiver.next().await {
-                        let request_metadata =
-                            Arc::new(RequestMetadata::new(REQUEST_PERIOD, 0).unwrap());
+                    while let Some(new_tx_state) = pending_tx_receiver.next().await {
+                        let new_tx_state = match new_tx_state {
+                            Ok(x) => x,
+                            Err(err) => {
+                                // a slow client missed some transactions. keep sending the new ones
+                                trace!(
+                                    "{:?} subscription {:?} lagged: {:?}",
+                                    kind,
+                                    subscription_id,
+                                    err
+                                );
+                                continue;
+                            }
+                        };
 
                         let new_tx = match new_tx_state {
                             TxStatus::Pending(tx) => tx,
@@ -214,154 +611,702 @@ impl Web3ProxyApp {
                             TxStatus::Orphaned(tx) => tx,
                         };
 
-                        // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
-                        let response_json = json!({
-                            "jsonrpc": "2.0",
-                            "method": "eth_subscription",
-                            "params": {
-                                "subscription": subscription_id,
-                                // upstream just sends the txid, but we want to send the whole transaction
-                                "result": new_tx,
-                            },
-                        });
+                        let result = match kind {
+                            // upstream just sends the txid
+                            SubscriptionKind::NewPendingTransactions => json!(new_tx.hash),
+                            // we can also send the whole transaction
+                            SubscriptionKind::NewPendingFullTransactions => json!(new_tx),
+                            // or the raw transaction
+                            SubscriptionKind::NewPendingRawTransactions => json!(new_tx.rlp()),
+                            SubscriptionKind::NewHeads
+                            | SubscriptionKind::Logs
+                            | SubscriptionKind::ProxyReorgs
+                            | SubscriptionKind::ProxyTx => unreachable!(),
+                        };
 
-                        let response_str = serde_json::to_string(&response_json)
-                            .expect("this should always be valid json");
+                        if !send_subscription_message(
+                            &response_sender,
+                            stat_sender.as_ref(),
+                            &authorization,
+                            kind,
+                            subscription_id,
+                            result,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
 
-                        // we could use response.num_bytes() here, but since we already have the string, this is easier
-                        let response_bytes = response_str.len();
+                    registry.remove_finished(&key);
 
-                        // TODO: do clients support binary messages?
-                        let response_msg = Message::Text(response_str);
+                    trace!("closed {:?} subscription: {:?}", kind, subscription_id);
+                });
+            }
+        }
 
-                        if response_sender.send_async(response_msg).await.is_err() {
-                            // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
-                            break;
-                        };
+        subscription_abort_handle
+    }
+}
 
-                        if let Some(stat_sender) = stat_sender.as_ref() {
-                            let response_stat = ProxyResponseStat::new(
-                                "eth_subscription(newPendingFullTransactions)".to_string(),
-                                authorization.clone(),
-                                request_metadata.clone(),
-                                response_bytes,
-                            );
+/// The heads already sent to a `newHeads` subscriber.
+/// When the consensus head moves to another backend, it can repeat a head, fall behind, or skip ahead.
+/// Subscribers only ever see each head once and in order.
+#[derive(Default)]
+struct SentHeads {
+    /// oldest first. at most `MAX_REORG_DEPTH` blocks
+    sent: VecDeque<(U64, H256)>,
+}
 
-                            if let Err(err) = stat_sender.send_async(response_stat.into()).await {
-                                // TODO: what should we do?
-                                warn!(
-                                    "stat_sender failed inside newPendingFullTransactions: {:?}",
-                                    err
-                                );
-                            }
-                        }
-                    }
+impl SentHeads {
+    fn contains(&self, hash: &H256) -> bool {
+        self.sent.iter().any(|x| &x.1 == hash)
+    }
 
-                    trace!(
-                        "closed newPendingFullTransactions subscription: {:?}",
-                        subscription_id
-                    );
-                });
+    /// The heads to send for a new consensus head, oldest first.
+    /// Heads that were already sent or are behind the last one sent are skipped.
+    /// Heads that a failover skipped over, or that replaced sent heads in a reorg, are queued before the new head.
+    async fn new_head<F, Fut>(&mut self, new_head: ArcBlock, mut block_by_hash: F) -> Vec<ArcBlock>
+    where
+        F: FnMut(H256) -> Fut,
+        Fut: Future<Output = anyhow::Result<ArcBlock>>,
+    {
+        // the channel starts with an empty block
+        let (num, hash) = match (new_head.number, new_head.hash) {
+            (Some(num), Some(hash)) => (num, hash),
+            _ => return vec![],
+        };
+
+        if self.contains(&hash) {
+            return vec![];
+        }
+
+        match self.sent.back() {
+            // the first head is sent without its history
+            None => {
+                self.sent.push_back((num, hash));
+                return vec![new_head];
             }
-            Some(x) if x == &json!(["newPendingRawTransactions"]) => {
-                // TODO: too much copy/pasta with newPendingTransactions
-                let authorization = authorization.clone();
-                let pending_tx_receiver = self.pending_tx_sender.subscribe();
-                let stat_sender = self.stat_sender.clone();
+            // a backend that is behind. the heads are sent once it catches up
+            Some((last_num, _)) if num < *last_num => return vec![],
+            Some(_) => {}
+        }
 
-                let mut pending_tx_receiver = Abortable::new(
-                    BroadcastStream::new(pending_tx_receiver),
-                    subscription_registration,
-                );
+        let oldest_num = self.sent.front().map(|x| x.0).unwrap_or_default();
 
-                trace!(
-                    "pending transactions subscription id: {:?}",
-                    subscription_id
-                );
+        let mut heads = vec![new_head];
 
-                // TODO: do something with this handle?
-                tokio::spawn(async move {
-                    while let Some(Ok(new_tx_state)) = pending_tx_receiver.next().await {
-                        let request_metadata =
-                            Arc::new(RequestMetadata::new(REQUEST_PERIOD, 0).unwrap());
+        loop {
+            let head = heads.last().expect("heads is never empty");
 
-                        let new_tx = match new_tx_state {
-                            TxStatus::Pending(tx) => tx,
-                            TxStatus::Confirmed(..) => continue,
-                            TxStatus::Orphaned(tx) => tx,
-                        };
+            if self.contains(&head.parent_hash) || heads.len() as u64 >= MAX_REORG_DEPTH {
+                break;
+            }
+
+            match head.number {
+                // the parent is older than any head that we remember
+                Some(x) if x > oldest_num => {}
+                _ => break,
+            }
 
-                        // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
-                        let response_json = json!({
-                            "jsonrpc": "2.0",
-                            "method": "eth_subscription",
-                            "params": {
-                                "subscription": subscription_id,
-                                // upstream just sends the txid, but we want to send the raw transaction
-                                "result": new_tx.rlp(),
-                            },
-                        });
+            match block_by_hash(head.parent_hash).await {
+                Ok(parent) => heads.push(parent),
+                Err(err) => {
+                    // better a gap than nothing
+                    warn!("unable to fill in skipped heads: {:?}", err);
+                    heads.truncate(1);
+                    break;
+                }
+            }
+        }
 
-                        let response_str = serde_json::to_string(&response_json)
-                            .expect("this should always be valid json");
+        heads.reverse();
 
-                        // we could use response.num_bytes() here, but since we already have the string, this is easier
-                        let response_bytes = response_str.len();
+        for head in heads.iter() {
+            if let (Some(num), Some(hash)) = (head.number, head.hash) {
+                self.sent.push_back((num, hash));
+            }
+        }
 
-                        // TODO: do clients support binary messages?
-                        let response_msg = Message::Text(response_str);
+        while self.sent.len() as u64 > MAX_REORG_DEPTH {
+            self.sent.pop_front();
+        }
 
-                        if response_sender.send_async(response_msg).await.is_err() {
-                            // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
-                            break;
-                        };
+        heads
+    }
+}
 
-                        if let Some(stat_sender) = stat_sender.as_ref() {
-                            let response_stat = ProxyResponseStat::new(
-                                "eth_subscription(newPendingRawTransactions)".to_string(),
-                                authorization.clone(),
-                                request_metadata.clone(),
-                                response_bytes,
-                            );
+/// The logs already sent to a `logs` subscriber for one block.
+/// They are kept so that they can be sent again as removed if the block is orphaned.
+struct SentBlockLogs {
+    num: U64,
+    hash: H256,
+    logs: Vec<serde_json::Value>,
+}
 
-                            if let Err(err) = stat_sender.send_async(response_stat.into()).await {
-                                // TODO: what should we do?
-                                warn!(
-                                    "stat_sender failed inside newPendingRawTransactions: {:?}",
-                                    err
-                                );
-                            }
-                        }
-                    }
+/// The state of one `logs` subscription.
+struct LogSubscription {
+    filter: serde_json::Value,
+    /// oldest first. at most `MAX_REORG_DEPTH` blocks
+    sent: VecDeque<SentBlockLogs>,
+}
 
-                    trace!(
-                        "closed newPendingRawTransactions subscription: {:?}",
-                        subscription_id
-                    );
-                });
+impl LogSubscription {
+    fn new(filter: serde_json::Value) -> Self {
+        Self {
+            filter,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// The notifications for a new head block.
+    /// Logs from blocks that are no longer on the chain come first with `removed` set to true.
+    async fn new_head(
+        &mut self,
+        rpcs: &Web3Connections,
+        allowed_lag: u64,
+        authorization: &Arc<Authorization>,
+        new_head: ArcBlock,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        if self.sent.is_empty() {
+            // like geth, only logs from blocks after the subscription started are sent
+            self.sent.push_back(SentBlockLogs {
+                num: new_head.number.context("head block has no number")?,
+                hash: new_head.hash.context("head block has no hash")?,
+                logs: vec![],
+            });
+
+            return Ok(vec![]);
+        }
+
+        // walk back from the new head until we find a block that we already sent
+        // nothing is changed until the walk is done so that a failed request can be tried again on the next head
+        let mut new_blocks = vec![];
+        let mut block = new_head;
+
+        let keep = loop {
+            let hash = block.hash.context("block has no hash")?;
+            let num = block.number.context("block has no number")?;
+
+            if let Some(i) = self.sent.iter().rposition(|x| x.hash == hash) {
+                break i + 1;
             }
-            _ => return Err(anyhow::anyhow!("unimplemented")),
+
+            new_blocks.push((hash, num));
+
+            if self.sent.front().map(|x| num <= x.num).unwrap_or(true) {
+                // none of the blocks that we sent are ancestors of the new head
+                break 0;
+            }
+
+            if new_blocks.len() as u64 >= MAX_REORG_DEPTH {
+                // the head jumped ahead further than any reorg we track. assume the old blocks are still on the chain
+                break self.sent.len();
+            }
+
+            block = rpcs.block(authorization, &block.parent_hash, None).await?;
+        };
+
+        let mut notifications = vec![];
+
+        // newest first so that clients can undo the logs in the opposite order that they were added
+        for orphaned in self.sent.drain(keep..).rev() {
+            notifications.extend(orphaned.logs.into_iter().rev().map(|mut log| {
+                log["removed"] = json!(true);
+                log
+            }));
         }
 
-        // TODO: do something with subscription_join_handle?
+        for (hash, num) in new_blocks.into_iter().rev() {
+            let logs = match rpcs
+                .block_logs(allowed_lag, authorization, &self.filter, hash, num)
+                .await
+            {
+                Ok(x) => x,
+                Err(err) => {
+                    // this block and the ones after it are tried again on the next head
+                    warn!("unable to get logs for block {}: {:?}", num, err);
+                    break;
+                }
+            };
 
-        let response = JsonRpcForwardedResponse::from_value(json!(subscription_id), id);
+            notifications.extend(logs.iter().cloned());
 
-        if let Some(stat_sender) = self.stat_sender.as_ref() {
-            let response_stat = ProxyResponseStat::new(
-                request_json.method.clone(),
-                authorization.clone(),
-                request_metadata,
-                response.num_bytes(),
-            );
+            self.sent.push_back(SentBlockLogs { num, hash, logs });
+        }
 
-            if let Err(err) = stat_sender.send_async(response_stat.into()).await {
-                // TODO: what should we do?
-                warn!("stat_sender failed inside websocket: {:?}", err);
+        while self.sent.len() as u64 > MAX_REORG_DEPTH {
+            self.sent.pop_front();
+        }
+
+        Ok(notifications)
+    }
+}
+
+/// Returns false if the client has gone away and the subscription should stop.
+async fn send_subscription_message(
+    response_sender: &flume::Sender<Message>,
+    stat_sender: Option<&StatSender>,
+    authorization: &Arc<Authorization>,
+    kind: SubscriptionKind,
+    subscription_id: SubscriptionId,
+    result: serde_json::Value,
+) -> bool {
+    // TODO: what should the payload for RequestMetadata be?
+    let request_metadata = Arc::new(RequestMetadata::new(REQUEST_PERIOD, 0).unwrap());
+
+    // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
+    let response_json = json!({
+        "jsonrpc": "2.0",
+        "method": "eth_subscription",
+        "params": {
+            "subscription": subscription_id,
+            "result": result,
+        },
+    });
+
+    let response_str =
+        serde_json::to_string(&response_json).expect("this should always be valid json");
+
+    // we could use response.num_bytes() here, but since we already have the string, this is easier
+    let response_bytes = response_str.len();
+
+    // TODO: do clients support binary messages?
+    let response_msg = Message::Text(response_str);
+
+    if response_sender.send_async(response_msg).await.is_err() {
+        // TODO: cancel this subscription earlier? select on the receiver and an abort handle?
+        return false;
+    };
+
+    if let Some(stat_sender) = stat_sender {
+        let response_stat = ProxyResponseStat::new(
+            kind.stat_method().to_string(),
+            authorization.clone(),
+            request_metadata,
+            response_bytes,
+        );
+
+        if let Err(err) = stat_sender.send_async(response_stat.into()).await {
+            // TODO: what should we do?
+            warn!("stat_sender failed inside {:?}: {:?}", kind, err);
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopConfig;
+    use ethers::prelude::Block;
+    use http::StatusCode;
+    use std::sync::atomic::{self, AtomicBool, AtomicU64};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn block(num: u64, hash: u64, parent_hash: u64) -> ArcBlock {
+        Arc::new(Block {
+            number: Some(num.into()),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent_hash),
+            ..Default::default()
+        })
+    }
+
+    fn nums(heads: &[ArcBlock]) -> Vec<u64> {
+        heads.iter().map(|x| x.number.unwrap().as_u64()).collect()
+    }
+
+    /// every block on the chain, by hash. blocks 1-10 have hashes 1-10 and reorged blocks have hashes 100+
+    fn chain() -> HashMap<H256, ArcBlock> {
+        (1..=10)
+            .map(|x| block(x, x, x - 1))
+            .chain([block(3, 103, 2), block(4, 104, 103)])
+            .map(|x| (x.hash.unwrap(), x))
+            .collect()
+    }
+
+    async fn new_head(
+        sent_heads: &mut SentHeads,
+        chain: &HashMap<H256, ArcBlock>,
+        hash: u64,
+    ) -> Vec<u64> {
+        let new_head = chain[&H256::from_low_u64_be(hash)].clone();
+
+        let heads = sent_heads
+            .new_head(new_head, |hash| async move {
+                chain.get(&hash).cloned().context("unknown block")
+            })
+            .await;
+
+        nums(&heads)
+    }
+
+    #[tokio::test]
+    async fn test_new_heads_failover() {
+        let chain = chain();
+        let mut sent_heads = SentHeads::default();
+
+        // the channel's first value is empty
+        let heads = sent_heads
+            .new_head(Default::default(), |_| async { unreachable!() })
+            .await;
+        assert!(heads.is_empty());
+
+        // the first backend
+        assert_eq!(new_head(&mut sent_heads, &chain, 1).await, [1]);
+        assert_eq!(new_head(&mut sent_heads, &chain, 2).await, [2]);
+        assert_eq!(new_head(&mut sent_heads, &chain, 3).await, [3]);
+
+        // failover to a backend that is behind. nothing until it catches up
+        assert!(new_head(&mut sent_heads, &chain, 2).await.is_empty());
+        assert!(new_head(&mut sent_heads, &chain, 3).await.is_empty());
+        assert_eq!(new_head(&mut sent_heads, &chain, 4).await, [4]);
+
+        // failover to a backend that is ahead. the skipped heads are sent first
+        assert_eq!(new_head(&mut sent_heads, &chain, 7).await, [5, 6, 7]);
+
+        // the watch channel can send the current head again
+        assert!(new_head(&mut sent_heads, &chain, 7).await.is_empty());
+        assert_eq!(new_head(&mut sent_heads, &chain, 8).await, [8]);
+    }
+
+    #[tokio::test]
+    async fn test_new_heads_reorg() {
+        let chain = chain();
+        let mut sent_heads = SentHeads::default();
+
+        for x in 1..=3 {
+            new_head(&mut sent_heads, &chain, x).await;
+        }
+
+        // the new side of a reorg is sent like geth does
+        assert_eq!(new_head(&mut sent_heads, &chain, 104).await, [3, 4]);
+        assert!(new_head(&mut sent_heads, &chain, 103).await.is_empty());
+
+        // a missing block leaves a gap instead of stopping the subscription
+        let mut sent_heads = SentHeads::default();
+        new_head(&mut sent_heads, &chain, 1).await;
+
+        let heads = sent_heads
+            .new_head(chain[&H256::from_low_u64_be(4)].clone(), |_| async {
+                Err(anyhow::anyhow!("backend failed"))
+            })
+            .await;
+        assert_eq!(nums(&heads), [4]);
+    }
+
+    #[test]
+    fn test_subscription_ids() {
+        let mut subscriptions = WebsocketSubscriptions::default();
+
+        let id = subscriptions.new_id(SubscriptionIdFormat::Random);
+        let id_str = id.to_string();
+
+        assert_eq!(id_str.len(), 34);
+        assert!(id_str.starts_with("0x"));
+        assert_eq!(json!(id), json!(id_str));
+        assert_ne!(subscriptions.new_id(SubscriptionIdFormat::Random), id);
+
+        assert_eq!(
+            subscriptions
+                .new_id(SubscriptionIdFormat::Sequential)
+                .to_string(),
+            "0x1"
+        );
+        assert_eq!(
+            subscriptions
+                .new_id(SubscriptionIdFormat::Sequential)
+                .to_string(),
+            "0x2"
+        );
+    }
+
+    #[test]
+    fn test_unsubscribe_array() {
+        let mut subscriptions = WebsocketSubscriptions::default();
+
+        let id = subscriptions.new_id(SubscriptionIdFormat::Random);
+        let key = Ulid::new();
+        subscriptions.insert(id, key);
+
+        // ids are matched case-insensitively
+        let upper = format!("0x{}", id.to_string()[2..].to_uppercase());
+
+        assert_eq!(subscriptions.remove(Some(&json!([upper]))).unwrap(), key);
+
+        // only once
+        let err = subscriptions.remove(Some(&json!([id]))).unwrap_err();
+        assert_eq!(err.code, SUBSCRIPTION_NOT_FOUND_ERROR_CODE);
+    }
+
+    #[test]
+    fn test_unsubscribe_string() {
+        let mut subscriptions = WebsocketSubscriptions::default();
+
+        let id = subscriptions.new_id(SubscriptionIdFormat::Sequential);
+        let key = Ulid::new();
+        subscriptions.insert(id, key);
+
+        assert_eq!(subscriptions.remove(Some(&json!("0x1"))).unwrap(), key);
+    }
+
+    #[test]
+    fn test_unsubscribe_invalid() {
+        let mut subscriptions = WebsocketSubscriptions::default();
+
+        let id = subscriptions.new_id(SubscriptionIdFormat::Sequential);
+        subscriptions.insert(id, Ulid::new());
+
+        for params in [
+            None,
+            Some(json!([])),
+            Some(json!(["0x1", "0x2"])),
+            Some(json!([1])),
+            Some(json!({ "id": "0x1" })),
+        ] {
+            let err = subscriptions.remove(params.as_ref()).unwrap_err();
+
+            assert_eq!(err.code, INVALID_PARAMS_ERROR_CODE, "{:?}", params);
+        }
+
+        let err = subscriptions.remove(Some(&json!(["0x2"]))).unwrap_err();
+        assert_eq!(err.code, SUBSCRIPTION_NOT_FOUND_ERROR_CODE);
+
+        // the invalid requests didn't remove anything
+        assert!(subscriptions.remove(Some(&json!(["0x1"]))).is_ok());
+    }
+
+    /// A chain on arbitrum's chain id so that http backends are polled every 500ms.
+    /// It only grows when the test mines a block.
+    #[derive(Clone, Default)]
+    struct MockChain {
+        head: Arc<AtomicU64>,
+    }
+
+    impl MockChain {
+        fn mine(&self) -> u64 {
+            self.head.fetch_add(1, atomic::Ordering::SeqCst) + 1
+        }
+
+        fn block(&self, num: u64) -> serde_json::Value {
+            let head = self.head.load(atomic::Ordering::SeqCst);
+
+            if num > head {
+                return serde_json::Value::Null;
             }
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            json!(Block::<TxHash> {
+                number: Some(num.into()),
+                hash: Some(H256::from_low_u64_be(num + 1)),
+                parent_hash: H256::from_low_u64_be(num),
+                timestamp: (now - (head - num)).into(),
+                ..Default::default()
+            })
+        }
+
+        fn result(&self, method: &str, params: &serde_json::Value) -> Option<serde_json::Value> {
+            let head = self.head.load(atomic::Ordering::SeqCst);
+
+            let result = match method {
+                "eth_chainId" => json!(U64::from(42161)),
+                "web3_clientVersion" => json!("mock/v0.1.0"),
+                "eth_blockNumber" => json!(U64::from(head)),
+                "eth_getBalance" => json!(U64::zero()),
+                "eth_getBlockByNumber" => match params[0].as_str()? {
+                    "latest" => self.block(head),
+                    x => self.block(serde_json::from_value::<U64>(json!(x)).ok()?.as_u64()),
+                },
+                "eth_getBlockByHash" => {
+                    let hash: H256 = serde_json::from_value(params[0].clone()).ok()?;
+
+                    self.block(hash.to_low_u64_be().checked_sub(1)?)
+                }
+                _ => return None,
+            };
+
+            Some(result)
+        }
+
+        /// Serve the chain over http. Clearing the returned flag makes the backend fail every request.
+        fn serve(&self) -> (String, Arc<AtomicBool>) {
+            let online = Arc::new(AtomicBool::new(true));
+
+            let chain = self.clone();
+            let online_copy = online.clone();
+
+            let router = axum::Router::new().route(
+                "/",
+                axum::routing::post(
+                    move |axum::Json(request): axum::Json<serde_json::Value>| async move {
+                        if !online_copy.load(atomic::Ordering::SeqCst) {
+                            return Err(StatusCode::SERVICE_UNAVAILABLE);
+                        }
+
+                        let method = request["method"].as_str().unwrap_or_default();
+
+                        let response = match chain.result(method, &request["params"]) {
+                            Some(result) => json!({
+                                "jsonrpc": "2.0",
+                                "id": request["id"],
+                                "result": result,
+                            }),
+                            None => json!({
+                                "jsonrpc": "2.0",
+                                "id": request["id"],
+                                "error": {"code": -32601, "message": "method not found"},
+                            }),
+                        };
+
+                        Ok(axum::Json(response))
+                    },
+                ),
+            );
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            tokio::spawn(
+                axum::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(router.into_make_service()),
+            );
+
+            (format!("http://{}", addr), online)
+        }
+    }
+
+    fn top_config(balanced_rpcs: &[(&str, &str)]) -> TopConfig {
+        let mut top_config =
+            "[app]\nchain_id = 42161\nmin_sum_soft_limit = 1\nmin_synced_rpcs = 1\n".to_string();
+
+        for (name, url) in balanced_rpcs {
+            top_config.push_str(&format!(
+                "\n[balanced_rpcs.{}]\nurl = \"{}\"\nsoft_limit = 100\nslow_start_seconds = 0\n",
+                name, url
+            ));
         }
 
-        // TODO: make a `SubscriptonHandle(AbortHandle, JoinHandle)` struct?
-        Ok((subscription_abort_handle, response))
+        toml::from_str(&top_config).unwrap()
+    }
+
+    /// Subscribe to newHeads like a websocket would
+    async fn subscribe_new_heads(app: &Arc<Web3ProxyApp>) -> flume::Receiver<Message> {
+        let (response_sender, response_receiver) = flume::unbounded();
+
+        let request = serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_subscribe",
+            "params": ["newHeads"],
+        }))
+        .unwrap();
+
+        app.eth_subscribe(
+            Arc::new(Authorization::internal(None).unwrap()),
+            request,
+            SubscriptionId::Sequential(1.into()),
+            response_sender,
+        )
+        .await
+        .unwrap();
+
+        response_receiver
+    }
+
+    /// Wait for the subscription to send this block number
+    async fn wait_for_head(response_receiver: &flume::Receiver<Message>, num: u64) {
+        let f = async {
+            loop {
+                let message = match response_receiver.recv_async().await.unwrap() {
+                    Message::Text(x) => x,
+                    x => panic!("unexpected message: {:?}", x),
+                };
+
+                let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+
+                let head: Block<TxHash> =
+                    serde_json::from_value(message["params"]["result"].clone()).unwrap();
+
+                if head.number.unwrap().as_u64() >= num {
+                    assert_eq!(head.number.unwrap().as_u64(), num);
+                    break;
+                }
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(30), f)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for block {}", num));
+    }
+
+    #[tokio::test]
+    async fn test_new_heads_survive_reload() {
+        let chain = MockChain::default();
+        chain.mine();
+
+        let (old_url, old_online) = chain.serve();
+        let (new_url, _) = chain.serve();
+
+        let (_shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        let app = Web3ProxyApp::spawn(top_config(&[("old", &old_url)]), 1, shutdown_receiver)
+            .await
+            .unwrap()
+            .app;
+
+        let response_receiver = subscribe_new_heads(&app).await;
+
+        wait_for_head(&response_receiver, chain.mine()).await;
+
+        let reloaded = app
+            .reload_rpcs(top_config(&[("new", &new_url)]))
+            .await
+            .unwrap();
+
+        assert_eq!(reloaded.balanced_rpcs.added, ["new"]);
+        assert_eq!(reloaded.balanced_rpcs.removed, ["old"]);
+
+        // only the new rpc can see these
+        old_online.store(false, atomic::Ordering::SeqCst);
+
+        wait_for_head(&response_receiver, chain.mine()).await;
+        wait_for_head(&response_receiver, chain.mine()).await;
+    }
+
+    #[tokio::test]
+    async fn test_new_heads_survive_backend_disconnect() {
+        let chain = MockChain::default();
+        chain.mine();
+
+        let (first_url, first_online) = chain.serve();
+        let (second_url, _) = chain.serve();
+
+        let (_shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        let app = Web3ProxyApp::spawn(
+            top_config(&[("first", &first_url), ("second", &second_url)]),
+            1,
+            shutdown_receiver,
+        )
+        .await
+        .unwrap()
+        .app;
+
+        let response_receiver = subscribe_new_heads(&app).await;
+
+        wait_for_head(&response_receiver, chain.mine()).await;
+
+        first_online.store(false, atomic::Ordering::SeqCst);
+
+        // the subscription follows the consensus head, so the other backend keeps it going without a resubscribe
+        wait_for_head(&response_receiver, chain.mine()).await;
+        wait_for_head(&response_receiver, chain.mine()).await;
     }
 }
@@ -1,21 +1,36 @@
-use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::audit_log::save_audit_log;
+use crate::config::StatRollupsConfig;
+use crate::frontend::authorization::{
+    Authorization, AuthorizationType, RequestMetadata, RequestTransport,
+};
+use crate::influxdb::{self, InfluxDb};
+use crate::stat_rollups::StatRollups;
 use axum::headers::Origin;
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use derive_more::From;
-use entities::rpc_accounting;
 use entities::sea_orm_active_enums::LogLevel;
+use entities::{rpc_accounting, rpc_key};
 use hashbrown::HashMap;
 use hdrhistogram::{Histogram, RecordError};
-use log::{error, info};
-use migration::sea_orm::{self, ActiveModelTrait, DatabaseConnection, DbErr};
+use log::{error, info, warn};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter,
+};
+use migration::Expr;
+use serde::Serialize;
+use serde_json::json;
+use std::net::IpAddr;
 use std::num::NonZeroU64;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tokio::time::{interval_at, Instant};
 
+/// Points that are waiting for a slow influxdb are dropped past this many bytes
+const MAX_INFLUXDB_BUFFER: usize = 64 * 1024 * 1024;
+
 /// TODO: where should this be defined?
 /// TODO: can we use something inside sea_orm instead?
 #[derive(Debug)]
@@ -29,6 +44,7 @@ pub struct ProxyResponseStat {
     backend_requests: u64,
     response_bytes: u64,
     response_millis: u64,
+    compute_units: u64,
 }
 
 impl ProxyResponseStat {
@@ -76,6 +92,7 @@ impl ProxyResponseStat {
             method,
             origin,
             rpc_key_id,
+            websocket_request: self.authorization.transport == RequestTransport::Websocket,
         }
     }
 }
@@ -110,6 +127,33 @@ struct ProxyResponseAggregateKey {
     method: Option<String>,
     /// TODO: should this be Origin or String?
     origin: Option<Origin>,
+    websocket_request: bool,
+}
+
+impl ProxyResponseAggregateKey {
+    /// influxdb tags follow the same privacy rules as the database.
+    /// origin is not a tag. anyone can send any origin and every new one would be a new series
+    fn influxdb_tags(&self, chain_id: u64, instance: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("chain_id", chain_id.to_string()),
+            ("instance", instance.to_string()),
+            (
+                "rpc_key_id",
+                self.rpc_key_id.map(|x| x.to_string()).unwrap_or_default(),
+            ),
+            ("method", self.method.clone().unwrap_or_default()),
+            ("archive_request", self.archive_request.to_string()),
+            ("error_response", self.error_response.to_string()),
+            ("websocket_request", self.websocket_request.to_string()),
+        ]
+    }
+
+    /// the origin is saved as a field instead
+    fn push_influxdb_origin(&self, fields: &mut Vec<(&'static str, influxdb::FieldValue)>) {
+        if let Some(origin) = self.origin.as_ref() {
+            fields.push(("origin", origin.to_string().into()));
+        }
+    }
 }
 
 #[derive(Default)]
@@ -125,6 +169,7 @@ pub struct ProxyResponseAggregate {
     sum_request_bytes: u64,
     sum_response_bytes: u64,
     sum_response_millis: u64,
+    sum_compute_units: u64,
     histograms: ProxyResponseHistograms,
 }
 
@@ -135,17 +180,98 @@ pub enum Web3ProxyStat {
     Response(ProxyResponseStat),
 }
 
+/// Counters for the prometheus endpoint. A stuck stat loop shows up as a growing queue and an old `last_flush_timestamp`.
+#[derive(Debug, Default, Serialize)]
+pub struct StatEmitterMetrics {
+    /// stats taken off the queue
+    pub received: AtomicU64,
+    /// stats that were not queued because the queue was full
+    pub dropped: AtomicU64,
+    /// aggregates that could not be saved to the database
+    pub save_errors: AtomicU64,
+    /// batches that could not be written to influxdb
+    pub influxdb_errors: AtomicU64,
+    /// how long saving the last period took
+    pub last_flush_millis: AtomicU64,
+    /// unix timestamp of the last save. this is updated every period even if there were no stats
+    pub last_flush_timestamp: AtomicU64,
+}
+
+/// The stat emitter's health for the prometheus endpoint
+#[derive(Serialize)]
+pub struct StatEmitterHealth<'a> {
+    queue_depth: usize,
+    queue_full: bool,
+    counters: &'a StatEmitterMetrics,
+}
+
+/// Sends stats to the stat loop. The queue is bounded by `max_queue` so that a stuck stat loop doesn't lose stats quietly.
+#[derive(Clone)]
+pub struct StatSender {
+    sender: flume::Sender<Web3ProxyStat>,
+    /// 0 = no limit
+    max_queue: usize,
+    metrics: Arc<StatEmitterMetrics>,
+}
+
+impl StatSender {
+    pub fn is_full(&self) -> bool {
+        self.max_queue > 0 && self.sender.len() >= self.max_queue
+    }
+
+    /// Past `max_queue`, the stat is dropped, counted, and logged
+    pub async fn send_async(&self, stat: Web3ProxyStat) -> anyhow::Result<()> {
+        if self.is_full() {
+            let dropped = self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+
+            if dropped.is_multiple_of(1000) {
+                error!(
+                    "stat queue is full! {} stats dropped. queue_depth={}",
+                    dropped + 1,
+                    self.sender.len()
+                );
+            }
+
+            return Ok(());
+        }
+
+        self.sender.send_async(stat).await?;
+
+        Ok(())
+    }
+
+    pub fn health(&self) -> StatEmitterHealth<'_> {
+        StatEmitterHealth {
+            queue_depth: self.sender.len(),
+            queue_full: self.is_full(),
+            counters: &self.metrics,
+        }
+    }
+}
+
 #[derive(From)]
 pub struct StatEmitterSpawn {
-    pub stat_sender: flume::Sender<Web3ProxyStat>,
+    pub stat_sender: StatSender,
     /// these handles are important and must be allowed to finish
     pub background_handle: JoinHandle<anyhow::Result<()>>,
 }
 
 pub struct StatEmitter {
     chain_id: u64,
-    db_conn: DatabaseConnection,
+    db_conn: Option<DatabaseConnection>,
+    influxdb: Option<InfluxDb>,
+    metrics: Arc<StatEmitterMetrics>,
     period_seconds: u64,
+    /// deactivate keys that haven't been used in this many days
+    rpc_key_dormant_days: Option<u64>,
+    /// roll old stats into longer periods and delete them
+    rollups: Option<StatRollups>,
+}
+
+/// the most recent use of an rpc key. these are saved once per period instead of on every request
+struct RpcKeyLastUsed {
+    at: DateTime<Utc>,
+    ip: IpAddr,
 }
 
 // TODO: impl `+=<ProxyResponseStat>` for ProxyResponseAggregate?
@@ -168,6 +294,7 @@ impl ProxyResponseAggregate {
         self.sum_request_bytes += stat.request_bytes;
         self.sum_response_bytes += stat.response_bytes;
         self.sum_response_millis += stat.response_millis;
+        self.sum_compute_units += stat.compute_units;
 
         // TODO: use `record_correct`?
         self.histograms.request_bytes.record(stat.request_bytes)?;
@@ -179,6 +306,50 @@ impl ProxyResponseAggregate {
         Ok(())
     }
 
+    fn influxdb_line(
+        &self,
+        chain_id: u64,
+        instance: &str,
+        key: &ProxyResponseAggregateKey,
+        period_timestamp: u64,
+    ) -> String {
+        let response_millis = &self.histograms.response_millis;
+
+        let mut fields: Vec<(&str, influxdb::FieldValue)> = vec![
+            ("frontend_requests", self.frontend_requests.into()),
+            ("backend_requests", self.backend_requests.into()),
+            ("cache_hits", self.cache_hits.into()),
+            ("cache_misses", self.cache_misses.into()),
+            ("sum_request_bytes", self.sum_request_bytes.into()),
+            ("sum_response_bytes", self.sum_response_bytes.into()),
+            ("sum_response_millis", self.sum_response_millis.into()),
+            ("sum_compute_units", self.sum_compute_units.into()),
+            (
+                "p50_response_millis",
+                response_millis.value_at_quantile(0.50).into(),
+            ),
+            (
+                "p90_response_millis",
+                response_millis.value_at_quantile(0.90).into(),
+            ),
+            (
+                "p99_response_millis",
+                response_millis.value_at_quantile(0.99).into(),
+            ),
+            ("max_response_millis", response_millis.max().into()),
+        ];
+
+        key.push_influxdb_origin(&mut fields);
+
+        // aggregates are unique per instance and period
+        influxdb::line(
+            "proxy_response_aggregate",
+            &key.influxdb_tags(chain_id, instance),
+            &fields,
+            period_timestamp as i64 * 1_000_000_000,
+        )
+    }
+
     // TODO? help to turn this plus the key into a database model?
     // TODO: take a db transaction instead so that we can batch
     async fn save(
@@ -228,6 +399,7 @@ impl ProxyResponseAggregate {
             method: sea_orm::Set(key.method),
             archive_request: sea_orm::Set(key.archive_request),
             error_response: sea_orm::Set(key.error_response),
+            websocket_request: sea_orm::Set(key.websocket_request),
             period_datetime: sea_orm::Set(period_datetime),
             frontend_requests: sea_orm::Set(self.frontend_requests),
             backend_requests: sea_orm::Set(self.backend_requests),
@@ -259,6 +431,8 @@ impl ProxyResponseAggregate {
             p90_response_bytes: sea_orm::Set(p90_response_bytes),
             p99_response_bytes: sea_orm::Set(p99_response_bytes),
             max_response_bytes: sea_orm::Set(max_response_bytes),
+
+            sum_compute_units: sea_orm::Set(self.sum_compute_units),
         };
 
         aggregated_stat_model.save(db_conn).await?;
@@ -268,6 +442,28 @@ impl ProxyResponseAggregate {
 }
 
 impl ProxyResponseStat {
+    fn influxdb_line(
+        &self,
+        chain_id: u64,
+        influxdb: &InfluxDb,
+        key: &ProxyResponseAggregateKey,
+    ) -> String {
+        let mut tags = key.influxdb_tags(chain_id, &influxdb.instance);
+        tags.push(("cache_hit", (self.backend_requests == 0).to_string()));
+
+        let mut fields: Vec<(&str, influxdb::FieldValue)> = vec![
+            ("backend_requests", self.backend_requests.into()),
+            ("request_bytes", self.request_bytes.into()),
+            ("response_bytes", self.response_bytes.into()),
+            ("response_millis", self.response_millis.into()),
+            ("compute_units", self.compute_units.into()),
+        ];
+
+        key.push_influxdb_origin(&mut fields);
+
+        influxdb::line("proxy_request", &tags, &fields, influxdb.unique_timestamp())
+    }
+
     pub fn new(
         method: String,
         authorization: Arc<Authorization>,
@@ -281,6 +477,7 @@ impl ProxyResponseStat {
         //     (metadata.start_datetime.timestamp() as u64) / period_seconds * period_seconds;
         let request_bytes = metadata.request_bytes;
         let error_response = metadata.error_response.load(Ordering::Acquire);
+        let compute_units = metadata.compute_units.load(Ordering::Acquire);
 
         // TODO: timestamps could get confused by leap seconds. need tokio time instead
         let response_millis = metadata.start_instant.elapsed().as_millis() as u64;
@@ -296,23 +493,53 @@ impl ProxyResponseStat {
             error_response,
             response_bytes,
             response_millis,
+            compute_units,
         }
     }
 }
 
 impl StatEmitter {
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         chain_id: u64,
-        db_conn: DatabaseConnection,
+        db_conn: Option<DatabaseConnection>,
+        influxdb: Option<InfluxDb>,
         period_seconds: u64,
+        rpc_key_dormant_days: Option<u64>,
+        stat_rollups: Option<StatRollupsConfig>,
+        stat_queue_max: usize,
         shutdown_receiver: broadcast::Receiver<()>,
     ) -> anyhow::Result<StatEmitterSpawn> {
-        let (stat_sender, stat_receiver) = flume::unbounded();
+        // the queue is unbounded so that sending never waits. StatSender drops stats past stat_queue_max instead
+        let (sender, stat_receiver) = flume::unbounded();
+
+        let metrics = Arc::new(StatEmitterMetrics::default());
+
+        let stat_sender = StatSender {
+            sender,
+            max_queue: stat_queue_max,
+            metrics: metrics.clone(),
+        };
+
+        // rollups only apply to the database
+        let rollups = match (stat_rollups, db_conn.as_ref()) {
+            (Some(x), Some(db_conn)) => Some(StatRollups::new(
+                chain_id,
+                db_conn.clone(),
+                x,
+                period_seconds,
+            )?),
+            _ => None,
+        };
 
         let mut new = Self {
             chain_id,
             db_conn,
+            influxdb,
+            metrics,
             period_seconds,
+            rpc_key_dormant_days,
+            rollups,
         };
 
         // TODO: send any errors somewhere
@@ -327,11 +554,7 @@ impl StatEmitter {
         stat_receiver: flume::Receiver<Web3ProxyStat>,
         mut shutdown_receiver: broadcast::Receiver<()>,
     ) -> anyhow::Result<()> {
-        let system_now = SystemTime::now();
-
-        let duration_since_epoch = system_now
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("time machines don't exist");
+        let duration_since_epoch = virtual_clock::unix_now();
 
         // TODO: change period_seconds from u64 to u32
         let current_period = duration_since_epoch
@@ -350,14 +573,44 @@ impl StatEmitter {
         let mut period_timestamp = current_period.as_secs();
         let mut response_aggregate_map =
             HashMap::<ProxyResponseAggregateKey, ProxyResponseAggregate>::new();
+        let mut rpc_key_last_used_map = HashMap::<NonZeroU64, RpcKeyLastUsed>::new();
+
+        // dormant keys don't need to be checked often
+        let mut dormant_interval = interval_at(start_instant, Duration::from_secs(3600));
+
+        let mut rollup_interval = interval_at(start_instant, Duration::from_secs(300));
+
+        // per-request points are buffered and written in batches
+        let mut influxdb_lines = String::new();
+        let mut influxdb_interval = interval_at(start_instant, Duration::from_secs(10));
 
         loop {
             tokio::select! {
                 stat = stat_receiver.recv_async() => {
+                    self.metrics.received.fetch_add(1, Ordering::Relaxed);
+
                     match stat? {
+                        Web3ProxyStat::Response(stat) if matches!(stat.authorization.authorization_type, AuthorizationType::Internal) => {
+                            // our own tools are not customers. prometheus counts them instead
+                        }
                         Web3ProxyStat::Response(stat) => {
+                            // track last used for all keys, even ones without logging. this helps security reviews of old keys
+                            if let Some(rpc_key_id) = stat.authorization.checks.rpc_key_id {
+                                rpc_key_last_used_map.insert(rpc_key_id, RpcKeyLastUsed {
+                                    at: Utc::now(),
+                                    ip: stat.authorization.ip,
+                                });
+                            }
+
                             let key = stat.key();
 
+                            if let Some(influxdb) = self.influxdb.as_ref() {
+                                if influxdb.per_request {
+                                    influxdb_lines.push_str(&stat.influxdb_line(self.chain_id, influxdb, &key));
+                                    influxdb_lines.push('\n');
+                                }
+                            }
+
                             // TODO: does hashmap have get_or_insert?
                             if ! response_aggregate_map.contains_key(&key) {
                                 response_aggregate_map.insert(key.clone(), Default::default());
@@ -375,16 +628,34 @@ impl StatEmitter {
                 }
                 _ = interval.tick() => {
                     // save all the aggregated stats
-                    // TODO: batch these saves
-                    for (key, aggregate) in response_aggregate_map.drain() {
-                        if let Err(err) = aggregate.save(self.chain_id, &self.db_conn, key, period_timestamp).await {
-                            error!("Unable to save stat while shutting down! {:?}", err);
-                        };
-                    }
+                    let flush_start = Instant::now();
+
+                    self.save_aggregates(&mut response_aggregate_map, period_timestamp, &mut influxdb_lines).await;
+                    self.save_rpc_key_last_used(&mut rpc_key_last_used_map).await;
+
+                    self.record_flush(flush_start.elapsed());
+
+                    self.flush_influxdb(&mut influxdb_lines);
+
                     // advance to the next period
                     // TODO: is this safe? what if there is drift?
                     period_timestamp += self.period_seconds;
                 }
+                _ = influxdb_interval.tick(), if self.influxdb.is_some() => {
+                    self.flush_influxdb(&mut influxdb_lines);
+                }
+                _ = dormant_interval.tick(), if self.rpc_key_dormant_days.is_some() => {
+                    if let Err(err) = self.deactivate_dormant_rpc_keys().await {
+                        error!("Unable to deactivate dormant rpc keys! err={:?}", err);
+                    }
+                }
+                _ = rollup_interval.tick(), if self.rollups.is_some() => {
+                    if let Some(rollups) = self.rollups.as_ref() {
+                        if let Err(err) = rollups.run().await {
+                            error!("Unable to roll up stats! err={:?}", err);
+                        }
+                    }
+                }
                 x = shutdown_receiver.recv() => {
                     match x {
                         Ok(_) => {
@@ -400,16 +671,201 @@ impl StatEmitter {
 
         info!("saving {} pending stats", response_aggregate_map.len());
 
+        self.save_aggregates(
+            &mut response_aggregate_map,
+            period_timestamp,
+            &mut influxdb_lines,
+        )
+        .await;
+
+        self.save_rpc_key_last_used(&mut rpc_key_last_used_map)
+            .await;
+
+        // wait for this write since we are shutting down
+        if let Some(influxdb) = self.influxdb.as_ref() {
+            if !influxdb_lines.is_empty() {
+                if let Err(err) = influxdb.write(influxdb_lines).await {
+                    self.metrics.influxdb_errors.fetch_add(1, Ordering::Relaxed);
+                    error!(
+                        "Unable to write stats to influxdb while shutting down! err={:?}",
+                        err
+                    );
+                }
+            }
+        }
+
+        info!("aggregated stat_loop shut down");
+
+        Ok(())
+    }
+
+    /// save the aggregated stats to the database and queue them for influxdb
+    /// TODO: batch these saves
+    async fn save_aggregates(
+        &self,
+        response_aggregate_map: &mut HashMap<ProxyResponseAggregateKey, ProxyResponseAggregate>,
+        period_timestamp: u64,
+        influxdb_lines: &mut String,
+    ) {
         for (key, aggregate) in response_aggregate_map.drain() {
-            if let Err(err) = aggregate
-                .save(self.chain_id, &self.db_conn, key, period_timestamp)
+            if let Some(influxdb) = self.influxdb.as_ref() {
+                influxdb_lines.push_str(&aggregate.influxdb_line(
+                    self.chain_id,
+                    &influxdb.instance,
+                    &key,
+                    period_timestamp,
+                ));
+                influxdb_lines.push('\n');
+            }
+
+            if let Some(db_conn) = self.db_conn.as_ref() {
+                if let Err(err) = aggregate
+                    .save(self.chain_id, db_conn, key, period_timestamp)
+                    .await
+                {
+                    self.metrics.save_errors.fetch_add(1, Ordering::Relaxed);
+                    error!("Unable to save stat! err={:?}", err);
+                };
+            }
+        }
+    }
+
+    /// the heartbeat. a save that takes longer than the period means the queue is growing
+    fn record_flush(&self, elapsed: Duration) {
+        self.metrics
+            .last_flush_millis
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+        self.metrics
+            .last_flush_timestamp
+            .store(Utc::now().timestamp() as u64, Ordering::Relaxed);
+
+        if elapsed > Duration::from_secs(self.period_seconds) {
+            warn!(
+                "saving stats took {:?}. that is longer than the {}s period",
+                elapsed, self.period_seconds
+            );
+        }
+    }
+
+    /// write the buffered points in the background so that a slow influxdb doesn't back up the stat loop.
+    /// if too many writes are already running, the points wait for the next flush. past `MAX_INFLUXDB_BUFFER` they are dropped
+    fn flush_influxdb(&self, influxdb_lines: &mut String) {
+        if influxdb_lines.is_empty() {
+            return;
+        }
+
+        if let Some(influxdb) = self.influxdb.clone() {
+            let permit = match influxdb.try_write_permit() {
+                Some(x) => x,
+                None => {
+                    if influxdb_lines.len() > MAX_INFLUXDB_BUFFER {
+                        self.metrics.influxdb_errors.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            "influxdb is too slow! dropping {} bytes of points",
+                            influxdb_lines.len()
+                        );
+                        influxdb_lines.clear();
+                    }
+
+                    return;
+                }
+            };
+
+            let lines = std::mem::take(influxdb_lines);
+            let metrics = self.metrics.clone();
+
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                if let Err(err) = influxdb.write(lines).await {
+                    metrics.influxdb_errors.fetch_add(1, Ordering::Relaxed);
+                    error!("Unable to write stats to influxdb! err={:?}", err);
+                }
+            });
+        }
+    }
+
+    /// save when and where each key was last used
+    /// TODO: batch these into one query
+    async fn save_rpc_key_last_used(
+        &self,
+        rpc_key_last_used_map: &mut HashMap<NonZeroU64, RpcKeyLastUsed>,
+    ) {
+        let db_conn = match self.db_conn.as_ref() {
+            None => {
+                rpc_key_last_used_map.clear();
+                return;
+            }
+            Some(x) => x,
+        };
+
+        for (rpc_key_id, last_used) in rpc_key_last_used_map.drain() {
+            let rpc_key_id: u64 = rpc_key_id.into();
+
+            if let Err(err) = rpc_key::Entity::update_many()
+                .col_expr(rpc_key::Column::LastUsedAt, Expr::value(last_used.at))
+                .col_expr(
+                    rpc_key::Column::LastUsedIp,
+                    Expr::value(last_used.ip.to_string()),
+                )
+                .filter(rpc_key::Column::Id.eq(rpc_key_id))
+                .exec(db_conn)
                 .await
             {
-                error!("Unable to save stat while shutting down! err={:?}", err);
-            };
+                error!(
+                    "Unable to save last used for rpc key #{}! err={:?}",
+                    rpc_key_id, err
+                );
+            }
         }
+    }
 
-        info!("aggregated stat_loop shut down");
+    /// deactivate keys that have not been used in `rpc_key_dormant_days`.
+    /// keys that have never been used are left alone.
+    /// TODO: the rpc_secret_key_cache will keep dormant keys working until it expires
+    async fn deactivate_dormant_rpc_keys(&self) -> anyhow::Result<()> {
+        let (dormant_days, db_conn) = match (self.rpc_key_dormant_days, self.db_conn.as_ref()) {
+            (Some(x), Some(db_conn)) => (x, db_conn),
+            _ => return Ok(()),
+        };
+
+        let dormant_at = Utc::now() - chrono::Duration::days(dormant_days as i64);
+
+        // find the ids first so that the audit log knows which keys were changed
+        let rpc_key_ids: Vec<u64> = rpc_key::Entity::find()
+            .filter(rpc_key::Column::Active.eq(true))
+            .filter(rpc_key::Column::LastUsedAt.lt(dormant_at))
+            .all(db_conn)
+            .await?
+            .into_iter()
+            .map(|x| x.id)
+            .collect();
+
+        if rpc_key_ids.is_empty() {
+            return Ok(());
+        }
+
+        let result = rpc_key::Entity::update_many()
+            .col_expr(rpc_key::Column::Active, Expr::value(false))
+            .filter(rpc_key::Column::Id.is_in(rpc_key_ids.clone()))
+            .exec(db_conn)
+            .await?;
+
+        info!(
+            "deactivated {} rpc keys unused for {} days",
+            result.rows_affected, dormant_days
+        );
+
+        save_audit_log(
+            db_conn,
+            None,
+            "rpc_key.deactivate_dormant",
+            "rpc_key",
+            None,
+            Some(json!({ "active": true, "rpc_key_ids": rpc_key_ids })),
+            Some(json!({ "active": false, "rpc_key_ids": rpc_key_ids })),
+        )
+        .await?;
 
         Ok(())
     }
@@ -0,0 +1,47 @@
+//! Record admin and self-serve changes so we know who changed what and when.
+use anyhow::Context;
+use chrono::Utc;
+use entities::{audit_log, rpc_key};
+use migration::sea_orm::{self, ActiveModelTrait, DatabaseConnection};
+use serde_json::json;
+
+/// Save one change. `before` is None for creates and `after` is None for deletes.
+/// `user_id` is None if the proxy made the change itself.
+pub async fn save_audit_log(
+    db_conn: &DatabaseConnection,
+    user_id: Option<u64>,
+    action: &str,
+    target_type: &str,
+    target_id: Option<u64>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+) -> anyhow::Result<()> {
+    let entry = audit_log::ActiveModel {
+        timestamp: sea_orm::Set(Utc::now()),
+        user_id: sea_orm::Set(user_id),
+        action: sea_orm::Set(action.to_string()),
+        target_type: sea_orm::Set(target_type.to_string()),
+        target_id: sea_orm::Set(target_id),
+        before: sea_orm::Set(before),
+        after: sea_orm::Set(after),
+        ..Default::default()
+    };
+
+    entry
+        .save(db_conn)
+        .await
+        .with_context(|| format!("saving audit log for {}", action))?;
+
+    Ok(())
+}
+
+/// rpc keys are secrets. never put them in the audit log
+pub fn rpc_key_audit_json(rpc_key: &rpc_key::Model) -> serde_json::Value {
+    let mut x = json!(rpc_key);
+
+    if let Some(x) = x.as_object_mut() {
+        x.remove("secret_key");
+    }
+
+    x
+}
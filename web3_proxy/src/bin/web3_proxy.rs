@@ -9,20 +9,23 @@
 #![forbid(unsafe_code)]
 
 use anyhow::Context;
-use futures::StreamExt;
-use log::{debug, error, info, warn};
-use num::Zero;
+use log::{debug, error, info};
 use parking_lot::deadlock;
+use sha2::{Digest, Sha256};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
 use std::thread;
 use tokio::runtime;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::broadcast;
 use tokio::time::Duration;
-use web3_proxy::app::{flatten_handle, flatten_handles, Web3ProxyApp};
+use web3_proxy::app::{flatten_handle, Web3ProxyApp};
+use web3_proxy::builder::Web3ProxyBuilder;
 use web3_proxy::config::{CliConfig, TopConfig};
-use web3_proxy::{frontend, metrics_frontend};
+use web3_proxy::dry_run::readiness_report;
+use web3_proxy::telemetry;
 
 fn run(
     shutdown_sender: broadcast::Sender<()>,
@@ -32,6 +35,10 @@ fn run(
     debug!("{:?}", cli_config);
     debug!("{:?}", top_config);
 
+    if cli_config.dry_run {
+        return dry_run(top_config);
+    }
+
     let mut shutdown_receiver = shutdown_sender.subscribe();
 
     // spawn a thread for deadlock detection
@@ -75,43 +82,38 @@ fn run(
     let num_workers = rt.metrics().num_workers();
     info!("num_workers: {}", num_workers);
 
-    rt.block_on(async {
-        let app_frontend_port = cli_config.port;
-        let app_prometheus_port = cli_config.prometheus_port;
+    let result = rt.block_on(async {
+        // the batch exporter runs on the tokio runtime
+        telemetry::init_tracing(&top_config.app)?;
 
-        // start the main app
-        let mut spawned_app =
-            Web3ProxyApp::spawn(top_config, num_workers, shutdown_sender.subscribe()).await?;
+        let config_path = PathBuf::from(&cli_config.config);
 
-        let frontend_handle =
-            tokio::spawn(frontend::serve(app_frontend_port, spawned_app.app.clone()));
+        // start the main app and the frontends
+        let mut proxy = Web3ProxyBuilder::new(top_config)
+            .port(cli_config.port)
+            .prometheus_port(cli_config.prometheus_port)
+            .num_workers(num_workers)
+            .shutdown_sender(shutdown_sender)
+            .spawn()
+            .await?;
 
-        let prometheus_handle = tokio::spawn(metrics_frontend::serve(
-            spawned_app.app,
-            app_prometheus_port,
-        ));
+        let mut sigterm = signal(SignalKind::terminate()).context("listening for SIGTERM")?;
+
+        let reload_handle = tokio::spawn(reload_on_sighup(config_path, proxy.app.clone()));
 
-        // if everything is working, these should both run forever
+        let app = proxy.app.clone();
+
+        // SIGTERM and `POST /admin/drain` let open connections finish before shutting down
+        let mut drain = false;
+
+        // if everything is working, these should all run forever
         tokio::select! {
-            x = flatten_handles(spawned_app.app_handles) => {
-                match x {
-                    Ok(_) => info!("app_handle exited"),
-                    Err(e) => {
-                        return Err(e);
-                    }
-                }
-            }
-            x = flatten_handle(frontend_handle) => {
-                match x {
-                    Ok(_) => info!("frontend exited"),
-                    Err(e) => {
-                        return Err(e);
-                    }
-                }
+            x = proxy.stopped() => {
+                x?;
             }
-            x = flatten_handle(prometheus_handle) => {
+            x = flatten_handle(reload_handle) => {
                 match x {
-                    Ok(_) => info!("prometheus exited"),
+                    Ok(_) => info!("reload exited"),
                     Err(e) => {
                         return Err(e);
                     }
@@ -133,39 +135,89 @@ fn run(
                     }
                 }
             }
+            _ = sigterm.recv() => {
+                info!("draining from SIGTERM");
+                drain = true;
+            }
+            _ = app.draining() => {
+                info!("draining from the admin api");
+                drain = true;
+            }
         };
 
-        // one of the handles stopped. send a value so the others know to shut down
-        if let Err(err) = shutdown_sender.send(()) {
-            warn!("shutdown sender err={:?}", err);
-        };
-
-        // wait for things like saving stats to the database to complete
-        info!("waiting on important background tasks");
-        let mut background_errors = 0;
-        while let Some(x) = spawned_app.background_handles.next().await {
-            match x {
-                Err(e) => {
-                    error!("{:?}", e);
-                    background_errors += 1;
-                }
-                Ok(Err(e)) => {
-                    error!("{:?}", e);
-                    background_errors += 1;
-                }
-                Ok(Ok(_)) => continue,
-            }
+        if drain {
+            proxy.drain().await;
         }
 
-        if background_errors.is_zero() {
-            info!("finished");
-        } else {
+        // one of the handles stopped. tell the others to shut down and wait for things like saving stats to the database
+        if let Err(err) = proxy.shutdown().await {
             // TODO: collect instead?
-            error!("finished with errors!")
+            error!("finished with errors! {:?}", err);
+        }
+
+        Ok(())
+    });
+
+    // send any buffered spans. this blocks, so it is done outside the runtime
+    telemetry::shutdown_tracing();
+
+    result
+}
+
+/// Read and parse the config file.
+fn read_config(config_path: &Path) -> anyhow::Result<TopConfig> {
+    let top_config: String = fs::read_to_string(config_path)
+        .context(format!("reading config at {}", config_path.display()))?;
+    let config_hash = hex::encode(Sha256::digest(&top_config));
+    let mut top_config: TopConfig = toml::from_str(&top_config)
+        .context(format!("parsing config at {}", config_path.display()))?;
+    top_config.config_hash = Some(config_hash);
+
+    Ok(top_config)
+}
+
+/// Re-read the config every time the process gets a SIGHUP and apply any changes to the rpcs.
+/// A config that fails to parse or apply is logged and the running rpcs are kept.
+async fn reload_on_sighup(config_path: PathBuf, app: Arc<Web3ProxyApp>) -> anyhow::Result<()> {
+    let mut sighup = signal(SignalKind::hangup()).context("listening for SIGHUP")?;
+
+    while sighup.recv().await.is_some() {
+        info!("SIGHUP! reloading rpcs from {}", config_path.display());
+
+        let top_config = match read_config(&config_path) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("unable to reload config. err={:?}", err);
+                continue;
+            }
+        };
+
+        match app.reload_rpcs(top_config).await {
+            Ok(x) => info!("reloaded rpcs: {:?}", x),
+            Err(err) => error!("unable to reload rpcs. err={:?}", err),
         }
+    }
+
+    Ok(())
+}
+
+/// Check everything that the proxy would connect to and exit. Nothing is served or written.
+fn dry_run(top_config: TopConfig) -> anyhow::Result<()> {
+    let rt = runtime::Builder::new_multi_thread().enable_all().build()?;
 
+    let report = rt.block_on(readiness_report(&top_config))?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if report.ready {
+        info!("ready");
         Ok(())
-    })
+    } else {
+        Err(anyhow::anyhow!(
+            "not ready. {} problems",
+            report.problems.len()
+        ))
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -191,10 +243,7 @@ fn main() -> anyhow::Result<()> {
         ))?;
 
     // advanced configuration is on disk
-    let top_config: String = fs::read_to_string(config_path.clone())
-        .context(format!("reading config at {}", config_path.display()))?;
-    let top_config: TopConfig = toml::from_str(&top_config)
-        .context(format!("parsing config at {}", config_path.display()))?;
+    let top_config = read_config(&config_path)?;
 
     // TODO: this doesn't seem to do anything
     proctitle::set_title(format!("web3_proxy-{}", top_config.app.chain_id));
@@ -289,6 +338,7 @@ mod tests {
             workers: 4,
             config: "./does/not/exist/test.toml".to_string(),
             cookie_key_filename: "./does/not/exist/development_cookie_key".to_string(),
+            dry_run: false,
         };
 
         // make a test TopConfig
@@ -314,9 +364,14 @@ mod tests {
                         url: anvil.endpoint(),
                         block_data_limit: None,
                         soft_limit: 100,
+                        subscription_soft_limit: None,
                         hard_limit: None,
                         tier: 0,
                         subscribe_txs: Some(false),
+                        timeout_seconds: None,
+                        slow_start_seconds: 0,
+                        pricing: None,
+                        max_logs_range: None,
                         extra: Default::default(),
                     },
                 ),
@@ -328,14 +383,24 @@ mod tests {
                         url: anvil.ws_endpoint(),
                         block_data_limit: None,
                         soft_limit: 100,
+                        subscription_soft_limit: None,
                         hard_limit: None,
                         tier: 0,
                         subscribe_txs: Some(false),
+                        timeout_seconds: None,
+                        slow_start_seconds: 0,
+                        pricing: None,
+                        max_logs_range: None,
                         extra: Default::default(),
                     },
                 ),
             ]),
             private_rpcs: None,
+            archive_rpcs: None,
+            features: Default::default(),
+            tls: None,
+            influxdb: None,
+            config_hash: None,
             extra: Default::default(),
         };
 
@@ -22,6 +22,10 @@ pub struct ChangeUserTierSubCommand {
     /// the amount of concurret requests to allow from a single user
     #[argh(option)]
     max_concurrent_requests: Option<u32>,
+
+    /// the amount of request and response bytes to allow per rate limit period across all of a user's keys
+    #[argh(option)]
+    max_account_bytes_per_period: Option<u64>,
 }
 
 impl ChangeUserTierSubCommand {
@@ -58,6 +62,19 @@ impl ChangeUserTierSubCommand {
             }
         }
 
+        if let Some(max_account_bytes_per_period) = self.max_account_bytes_per_period {
+            if user_tier.max_account_bytes_per_period
+                == sea_orm::Set(Some(max_account_bytes_per_period))
+            {
+                info!("max_account_bytes_per_period already has this value");
+            } else {
+                user_tier.max_account_bytes_per_period =
+                    sea_orm::Set(Some(max_account_bytes_per_period));
+
+                info!("changed max_account_bytes_per_period")
+            }
+        }
+
         let user_tier = user_tier.save(db_conn).await?;
 
         debug!("new user_tier: {:#?}", user_tier);
@@ -50,7 +50,7 @@ impl CreateUserSubCommand {
             let bytes = address.as_bytes();
 
             // convert the slice to a Vec
-            bytes.try_into().expect("Bytes can always be a Vec<u8>")
+            bytes.into()
         };
 
         // TODO: get existing or create a new one
@@ -68,7 +68,7 @@ impl CreateUserSubCommand {
             Address::from_slice(u.address.as_ref())
         );
 
-        let rpc_secret_key = self.rpc_secret_key.unwrap_or_else(RpcSecretKey::new);
+        let rpc_secret_key = self.rpc_secret_key.unwrap_or_default();
 
         // create a key for the new user
         let uk = rpc_key::ActiveModel {
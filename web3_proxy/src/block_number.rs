@@ -41,7 +41,6 @@ pub fn block_num_to_U64(block_num: BlockNumber, latest_block: U64) -> U64 {
 }
 
 /// modify params to always have a block number and not "latest"
-
 pub async fn clean_block_number(
     authorization: &Arc<Authorization>,
     params: &mut serde_json::Value,
@@ -140,6 +139,7 @@ pub async fn block_needed(
     let block_param_id = match method {
         "eth_call" => 1,
         "eth_estimateGas" => 1,
+        "eth_simulateV1" => 1,
         "eth_getBalance" => 1,
         "eth_getBlockByHash" => {
             // TODO: double check that any node can serve this
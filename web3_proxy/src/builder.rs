@@ -0,0 +1,248 @@
+//! Run the proxy inside another program's tokio runtime instead of shelling out to the binary.
+//!
+//! ```no_run
+//! # async fn f(top_config: web3_proxy::config::TopConfig) -> anyhow::Result<()> {
+//! use web3_proxy::builder::Web3ProxyBuilder;
+//!
+//! // port 0 picks any free port
+//! let mut proxy = Web3ProxyBuilder::new(top_config).port(0).spawn().await?;
+//!
+//! println!("proxy is listening on {}", proxy.frontend_addr);
+//!
+//! // ... use it ...
+//!
+//! proxy.drain().await;
+//! proxy.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::app::{flatten_handle, flatten_handles, AnyhowJoinHandle, Web3ProxyApp};
+use crate::config::TopConfig;
+use crate::{frontend, metrics_frontend};
+use futures::future::{Fuse, FusedFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::time::{timeout, Duration};
+
+/// Configure the proxy programmatically. Everything that isn't set here comes from the `TopConfig`.
+pub struct Web3ProxyBuilder {
+    top_config: TopConfig,
+    port: u16,
+    prometheus_port: Option<u16>,
+    num_workers: Option<usize>,
+    shutdown_sender: Option<broadcast::Sender<()>>,
+}
+
+impl Web3ProxyBuilder {
+    /// Listens on 8544 and doesn't serve prometheus metrics unless told otherwise.
+    pub fn new(top_config: TopConfig) -> Self {
+        Self {
+            top_config,
+            port: 8544,
+            prometheus_port: None,
+            num_workers: None,
+            shutdown_sender: None,
+        }
+    }
+
+    /// The port for http and websocket requests. 0 picks any free port.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Also serve prometheus metrics. 0 picks any free port.
+    pub fn prometheus_port(mut self, port: u16) -> Self {
+        self.prometheus_port = Some(port);
+        self
+    }
+
+    /// Sizes the default semaphores and db pool. Defaults to the number of logical processors, like tokio.
+    pub fn num_workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = Some(num_workers);
+        self
+    }
+
+    /// Use an existing channel to stop the app's background tasks. A new channel is made by default.
+    pub fn shutdown_sender(mut self, shutdown_sender: broadcast::Sender<()>) -> Self {
+        self.shutdown_sender = Some(shutdown_sender);
+        self
+    }
+
+    /// Connect to the backends and start serving. This must be called inside a tokio runtime.
+    /// The ports are bound before this returns so that requests can be sent right away.
+    pub async fn spawn(self) -> anyhow::Result<Web3ProxyHandle> {
+        let num_workers = self.num_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1)
+        });
+
+        let shutdown_sender = self
+            .shutdown_sender
+            .unwrap_or_else(|| broadcast::channel(1).0);
+
+        // bind first so that a port in use fails before connecting to everything
+        let frontend_listener = frontend::bind(self.port)?;
+        let frontend_addr = frontend_listener.local_addr()?;
+
+        let prometheus_listener = self.prometheus_port.map(frontend::bind).transpose()?;
+        let prometheus_addr = prometheus_listener
+            .as_ref()
+            .map(|x| x.local_addr())
+            .transpose()?;
+
+        let tls = self.top_config.tls.clone();
+
+        let spawned_app =
+            Web3ProxyApp::spawn(self.top_config, num_workers, shutdown_sender.subscribe()).await?;
+
+        let app = spawned_app.app;
+
+        let frontend_handle = tokio::spawn(frontend::serve_listener(
+            frontend_listener,
+            tls,
+            app.clone(),
+        ))
+        .fuse();
+
+        let prometheus_handle = prometheus_listener
+            .map(|listener| tokio::spawn(metrics_frontend::serve_listener(app.clone(), listener)));
+
+        Ok(Web3ProxyHandle {
+            app,
+            frontend_addr,
+            prometheus_addr,
+            shutdown_sender,
+            app_handles: spawned_app.app_handles,
+            frontend_handle,
+            prometheus_handle,
+            background_handles: spawned_app.background_handles,
+        })
+    }
+}
+
+/// A running proxy.
+pub struct Web3ProxyHandle {
+    pub app: Arc<Web3ProxyApp>,
+    /// where http and websocket requests are served
+    pub frontend_addr: SocketAddr,
+    /// where prometheus metrics are served. None unless a `prometheus_port` was set
+    pub prometheus_addr: Option<SocketAddr>,
+    shutdown_sender: broadcast::Sender<()>,
+    app_handles: FuturesUnordered<AnyhowJoinHandle<()>>,
+    /// fused so that `drain` can still wait on it after `stopped`
+    frontend_handle: Fuse<AnyhowJoinHandle<()>>,
+    prometheus_handle: Option<AnyhowJoinHandle<()>>,
+    /// these are important and must be allowed to finish
+    background_handles: FuturesUnordered<AnyhowJoinHandle<()>>,
+}
+
+impl Web3ProxyHandle {
+    /// A sender for the channel that stops the app's background tasks.
+    pub fn shutdown_sender(&self) -> broadcast::Sender<()> {
+        self.shutdown_sender.clone()
+    }
+
+    /// Completes if the app, the frontend, or prometheus stops. They should run forever, so this is usually an error.
+    /// Call this at most once.
+    pub async fn stopped(&mut self) -> anyhow::Result<()> {
+        let prometheus_handle = self.prometheus_handle.take();
+
+        let prometheus = async move {
+            match prometheus_handle {
+                Some(x) => flatten_handle(x).await,
+                None => futures::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            x = flatten_handles(mem::take(&mut self.app_handles)) => {
+                info!("app_handle exited");
+                x
+            }
+            x = &mut self.frontend_handle => {
+                info!("frontend exited");
+                x?
+            }
+            x = prometheus => {
+                info!("prometheus exited");
+                x
+            }
+        }
+    }
+
+    /// Stop accepting new connections and give open requests and websockets up to `drain_timeout_seconds` to finish.
+    pub async fn drain(&mut self) {
+        let app = &self.app;
+
+        app.start_draining();
+
+        let drain_timeout = Duration::from_secs(app.config.drain_timeout_seconds);
+
+        info!(
+            "waiting up to {:?} for open requests and {} websockets",
+            drain_timeout,
+            app.open_websockets()
+        );
+
+        let frontend_handle = &mut self.frontend_handle;
+
+        let drained = async {
+            // the frontend stopped accepting connections. it returns once its open http requests are done
+            if !frontend_handle.is_terminated() {
+                match frontend_handle.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => error!("frontend failed while draining. err={:?}", err),
+                    Err(err) => error!("frontend failed while draining. err={:?}", err),
+                }
+            }
+
+            app.websockets_closed().await;
+        };
+
+        match timeout(drain_timeout, drained).await {
+            Ok(()) => info!("drained"),
+            Err(_) => warn!(
+                "drain timed out with {} open websockets",
+                app.open_websockets()
+            ),
+        }
+    }
+
+    /// Stop the background tasks and wait for the important ones, like saving stats, to finish.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        // nobody listening is fine. that means everything already stopped
+        let _ = self.shutdown_sender.send(());
+
+        info!("waiting on important background tasks");
+        let mut background_errors = 0;
+        while let Some(x) = self.background_handles.next().await {
+            match x {
+                Err(e) => {
+                    error!("{:?}", e);
+                    background_errors += 1;
+                }
+                Ok(Err(e)) => {
+                    error!("{:?}", e);
+                    background_errors += 1;
+                }
+                Ok(Ok(_)) => continue,
+            }
+        }
+
+        if background_errors == 0 {
+            info!("finished");
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} background tasks failed",
+                background_errors
+            ))
+        }
+    }
+}
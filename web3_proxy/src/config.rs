@@ -1,14 +1,19 @@
+use crate::app::DEFAULT_MULTICALL_ADDRESS;
+use crate::frontend::authorization::RpcKeyFormat;
+use crate::jsonrpc::JsonRpcErrorData;
 use crate::rpcs::blockchain::BlockHashesCache;
 use crate::rpcs::connection::Web3Connection;
 use crate::rpcs::request::OpenRequestHandleMetrics;
 use crate::{app::AnyhowJoinHandle, rpcs::blockchain::ArcBlock};
 use argh::FromArgs;
-use ethers::prelude::TxHash;
+use ethers::prelude::{Address, TxHash};
 use hashbrown::HashMap;
 use log::warn;
 use migration::sea_orm::DatabaseConnection;
 use serde::Deserialize;
+use std::num::NonZeroU64;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast;
 
 pub type BlockAndRpc = (Option<ArcBlock>, Arc<Web3Connection>);
@@ -36,6 +41,10 @@ pub struct CliConfig {
     /// path to a binary file used to encrypt cookies. Should be at least 64 bytes.
     #[argh(option, default = "\"./data/development_cookie_key\".to_string()")]
     pub cookie_key_filename: String,
+
+    /// check the backends, database, and redis, print a readiness report, and exit without serving anything
+    #[argh(switch)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,11 +53,53 @@ pub struct TopConfig {
     pub balanced_rpcs: HashMap<String, Web3ConnectionConfig>,
     // TODO: instead of an option, give it a default
     pub private_rpcs: Option<HashMap<String, Web3ConnectionConfig>>,
+    /// Archive servers (probably paid) that are only used when a block has been pruned from every balanced rpc
+    pub archive_rpcs: Option<HashMap<String, Web3ConnectionConfig>>,
+    /// Default values for feature flags. Admins can override these at runtime.
+    #[serde(default = "HashMap::default")]
+    pub features: HashMap<String, bool>,
+    /// Also serve the frontend over https so that small deployments don't need a reverse proxy
+    pub tls: Option<TlsConfig>,
+    /// Also write stats to InfluxDB v2 so they can be graphed without querying mysql
+    pub influxdb: Option<InfluxDbConfig>,
+    /// sha256 of the config file. set after loading so operators can compare replicas
+    #[serde(skip)]
+    pub config_hash: Option<String>,
     /// unknown config options get put here
     #[serde(flatten, default = "HashMap::default")]
     pub extra: HashMap<String, serde_json::Value>,
 }
 
+/// Https on its own port. The plain http port keeps working for health checks and local tools.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    pub port: u16,
+    /// PEM certificate chain. The server's certificate first
+    pub cert_path: String,
+    /// PEM private key
+    pub key_path: String,
+    /// How often to check the files for a renewed certificate. 0 is rejected when the config is loaded
+    #[serde(default = "default_tls_reload_seconds")]
+    pub reload_seconds: NonZeroU64,
+}
+
+fn default_tls_reload_seconds() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct InfluxDbConfig {
+    /// The base url of the InfluxDB server. `/api/v2/write` is added to it
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// An api token with write access to the bucket
+    pub token: String,
+    /// Write a point for every request in addition to the per-minute aggregates
+    #[serde(default = "default_true")]
+    pub per_request: bool,
+}
+
 /// shared configuration between Web3Connections
 // TODO: no String, only &str
 #[derive(Debug, Default, Deserialize)]
@@ -58,6 +109,11 @@ pub struct AppConfig {
     #[serde(default = "default_allowed_origin_requests_per_period")]
     pub allowed_origin_requests_per_period: HashMap<String, u64>,
 
+    /// Stats page urls for anonymous users with these origins. Used instead of `redirect_public_url`.
+    /// These are handlebars templates. "{{origin}}" is available.
+    #[serde(default = "HashMap::default")]
+    pub allowed_origin_redirect_public_urls: HashMap<String, String>,
+
     /// EVM chain id. 1 for ETH
     /// TODO: better type for chain_id? max of `u64::MAX / 2 - 36` <https://github.com/ethereum/EIPs/issues/2294>
     pub chain_id: u64,
@@ -90,9 +146,29 @@ pub struct AppConfig {
     /// None = allow all requests
     pub default_user_max_requests_per_period: Option<u64>,
 
+    /// Methods that are never sent to the backends. A trailing `*` matches any method with that prefix.
+    /// Each chain's config can have its own list. Admins can grant exceptions to specific rpc keys.
+    #[serde(default = "default_denied_methods")]
+    pub denied_methods: Vec<String>,
+
+    /// Added as a label to all prometheus metrics. Something like "production" or "staging"
+    #[serde(default = "default_deployment_environment")]
+    pub deployment_environment: String,
+
+    /// Ask an external http service about rpc keys that aren't in our database.
+    pub external_auth: Option<ExternalAuthConfig>,
+
+    /// Added as a label to all prometheus metrics. Defaults to the hostname
+    #[serde(default = "default_instance_name")]
+    pub instance_name: String,
+
     /// Restrict user registration.
     /// None = no code needed
     pub invite_code: Option<String>,
+
+    /// Optionally check the reputation of anonymous users' ips.
+    pub ip_reputation: Option<IpReputationConfig>,
+
     pub login_domain: Option<String>,
 
     /// Rate limit for bearer token authenticated entrypoints.
@@ -105,6 +181,41 @@ pub struct AppConfig {
     #[serde(default = "default_login_rate_limit_per_period")]
     pub login_rate_limit_per_period: u64,
 
+    /// The most requests allowed in one JSON-RPC batch.
+    /// Larger batches are rejected instead of being partially run.
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+
+    /// How many requests each method counts as for rate limits and usage stats. Unlisted methods cost 1.
+    /// A trailing `*` matches any method with that prefix. The longest match wins.
+    #[serde(default)]
+    pub method_costs: HashMap<String, u64>,
+
+    /// Save responses that can never change to disk so that restarts keep a warm cache.
+    /// None = only cache in memory
+    pub disk_cache: Option<DiskCacheConfig>,
+
+    /// Relays for `eth_sendBundle`, `eth_callBundle`, and `eth_cancelBundle`.
+    /// None = bundles are not supported
+    pub bundles: Option<BundlesConfig>,
+
+    /// Auth for the prometheus port. It is separate from the main frontend.
+    /// None = anyone that can reach the port can read the metrics
+    pub metrics_auth: Option<MetricsAuthConfig>,
+
+    /// Only these methods get their own prometheus counters. Empty = the first `metrics_max_methods` methods answered
+    /// Other methods are counted as "other"
+    #[serde(default)]
+    pub metrics_methods: Vec<String>,
+
+    /// Clients can send any method name. Past this many, methods are counted as "other"
+    #[serde(default = "default_metrics_max_methods")]
+    pub metrics_max_methods: usize,
+
+    /// Static responses that are returned instead of asking the backends. The first match wins.
+    #[serde(default)]
+    pub method_stubs: Vec<MethodStubConfig>,
+
     /// The soft limit prevents thundering herds as new blocks are seen.
     #[serde(default = "default_min_sum_soft_limit")]
     pub min_sum_soft_limit: u32,
@@ -113,11 +224,27 @@ pub struct AppConfig {
     #[serde(default = "default_min_synced_rpcs")]
     pub min_synced_rpcs: usize,
 
+    /// The Multicall3 contract used by `/rest/multicall`.
+    /// The default address works on most chains.
+    #[serde(default = "default_multicall_address")]
+    pub multicall_address: Address,
+
     /// Concurrent request limit for anonymous users.
     /// Some(0) = block all requests
     /// None = allow all requests
     pub public_max_concurrent_requests: Option<usize>,
 
+    /// Transactions are latency critical. A lone `eth_sendRawTransaction` skips the ip and rpc key concurrency limits
+    /// while fewer than this many are in flight. Each ip or key can have only as many in flight as its own concurrency limit.
+    /// After that, transactions wait like any other request.
+    /// 0 turns this off
+    #[serde(default = "default_max_concurrent_txs")]
+    pub max_concurrent_txs: usize,
+
+    /// What requests without an rpc key can do
+    #[serde(default)]
+    pub anonymous_access: AnonymousAccess,
+
     /// Request limit for anonymous users.
     /// Some(0) = block all requests
     /// None = allow all requests
@@ -126,19 +253,143 @@ pub struct AppConfig {
     /// Salt for hashing recent ips
     pub public_recent_ips_salt: Option<String>,
 
+    /// Requests with this value in the `X-W3P-Internal` header are from our own tools (health checkers, indexers, etc.).
+    /// They skip rate limits and are not saved in the customer stats. Prometheus counts them separately.
+    pub internal_request_secret: Option<String>,
+
+    /// Keep the full bodies of pending transactions streamed by the backends for this many seconds.
+    /// `eth_getTransactionByHash` uses them when the chosen backend has not seen the transaction yet.
+    /// None = only fetch bodies for websocket subscribers
+    pub pending_tx_cache_seconds: Option<u64>,
+
+    /// How rpc keys are shown to users. "ulid" or "uuid". All formats are always accepted.
+    #[serde(default)]
+    pub rpc_key_format: RpcKeyFormat,
+
+    /// Optional vanity prefix for rpc keys (like "wp_live_"). Must end with '_'.
+    /// This makes leaked keys easy to find with credential scanners.
+    pub rpc_key_prefix: Option<String>,
+
+    /// Deactivate keys that have not been used in this many days.
+    /// None = never deactivate keys
+    pub rpc_key_dormant_days: Option<u64>,
+
+    /// Roll the per-minute stats into longer periods and delete old rows.
+    /// Only one proxy per database should have this set.
+    /// None = keep every per-minute row forever
+    pub stat_rollups: Option<StatRollupsConfig>,
+
+    /// Stats waiting to be saved past this many are dropped and counted in the `stats_dropped` metric.
+    /// 0 = no limit
+    #[serde(default = "default_stat_queue_max")]
+    pub stat_queue_max: usize,
+
+    /// What requests with an rpc key do while the stat queue is full
+    #[serde(default)]
+    pub stat_queue_full: StatQueueFull,
+
+    /// Add X-W3P-Backend, X-W3P-Cache, and X-W3P-Block headers to http responses.
+    /// These make debugging much easier, but they tell users which backends we use.
+    #[serde(default = "default_response_headers")]
+    pub response_headers: bool,
+
     /// RPC responses are cached locally
     #[serde(default = "default_response_cache_max_bytes")]
     pub response_cache_max_bytes: usize,
 
+    /// Separate response caches for groups of user tiers, by partition name.
+    /// Free tiers can't evict entries that paid tiers warmed. Tiers that aren't in a partition share the default cache
+    #[serde(default)]
+    pub response_cache_partitions: HashMap<String, ResponseCachePartitionConfig>,
+
+    /// How long open requests and websockets get to finish after a SIGTERM or `POST /admin/drain`.
+    #[serde(default = "default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u64,
+
+    /// How long a client request (including retries on other servers) may take.
+    /// Time spent waiting for rate limits and choosing a server comes out of this budget.
+    #[serde(default = "default_request_timeout_seconds")]
+    pub request_timeout_seconds: u64,
+
+    /// Answer `eth_getTransactionCount` with "pending" using the highest nonce from every synced server and our own private transactions.
+    /// This costs a request to every server.
+    #[serde(default)]
+    pub aggregate_pending_nonce: bool,
+
+    /// Check the shape of responses to common methods and retry malformed ones on another server.
+    /// This costs CPU since every checked response is parsed.
+    #[serde(default)]
+    pub validate_responses: bool,
+
+    /// Every message on a websocket counts as this many requests against the ip or key's rate limit.
+    /// None = only opening the websocket is rate limited
+    pub websocket_rate_limit_multiplier: Option<u64>,
+
+    /// How `eth_subscribe` picks subscription ids. They only need to be unique per websocket.
+    #[serde(default)]
+    pub subscription_ids: SubscriptionIdFormat,
+
+    /// Limit how long requests wait when every balanced rpc is at its hard limit.
+    /// None = wait until a server is available or the request times out
+    pub backend_queue: Option<BackendQueueConfig>,
+
+    /// Safe mode for when the chain stops producing blocks.
+    /// None = never enter safe mode
+    pub chain_halt: Option<ChainHaltConfig>,
+
+    /// Serve `eth_gasPrice` from a short cache and clamp outliers to our own fee history.
+    /// None = every `eth_gasPrice` goes to a backend
+    pub gas_price: Option<GasPriceConfig>,
+
+    /// Send simulations like `eth_simulateV1` only to backends that support them, with their own timeout.
+    /// None = they are forwarded like any other method
+    pub simulation: Option<SimulationConfig>,
+
+    /// How `eth_sendRawTransaction` uses the private_rpcs.
+    #[serde(default)]
+    pub private_txs: PrivateTxsConfig,
+
+    /// How long to follow transactions sent with `eth_sendRawTransaction`. Their status is served at `/tx/:tx_hash`.
+    #[serde(default)]
+    pub tx_tracking: TxTrackingConfig,
+
+    /// Pricing tables for hosted providers, by name. Backends pick one with `pricing`.
+    /// Costs are reported at `/admin/costs` and in the prometheus metrics.
+    #[serde(default)]
+    pub provider_pricing: HashMap<String, ProviderPricingConfig>,
+
+    /// Prepaid credits. Requests with a key spend the user's balance and deposits refill it.
+    /// None = requests are free
+    pub credits: Option<CreditsConfig>,
+
     /// the stats page url for an anonymous user.
     pub redirect_public_url: Option<String>,
 
     /// the stats page url for a logged in user. if set, must contain "{rpc_key_id}"
     pub redirect_rpc_key_url: Option<String>,
 
+    /// CORS headers for browser dapps. By default any origin, method, and header is allowed
+    #[serde(default)]
+    pub cors: CorsConfig,
+
+    /// gzip and brotli responses for clients that accept them
+    #[serde(default)]
+    pub compression: CompressionConfig,
+
+    /// Also serve the frontend on this unix socket. A stale socket left at this path is replaced.
+    /// There is no client ip, so requests count as localhost unless a reverse proxy sets `X-Forwarded-For`.
+    pub unix_socket: Option<String>,
+
     /// Optionally send errors to <https://sentry.io>
     pub sentry_url: Option<String>,
 
+    /// Optionally export OpenTelemetry traces to this OTLP (grpc) collector. Usually "http://localhost:4317"
+    pub otlp_endpoint: Option<String>,
+
+    /// The fraction of requests to trace. 1.0 traces every request
+    #[serde(default = "default_trace_sample_rate")]
+    pub trace_sample_rate: f64,
+
     /// Track rate limits in a redis (or compatible backend)
     /// It is okay if this data is lost.
     pub volatile_redis_url: Option<String>,
@@ -156,6 +407,25 @@ fn default_allowed_origin_requests_per_period() -> HashMap<String, u64> {
     HashMap::new()
 }
 
+fn default_deployment_environment() -> String {
+    "production".to_string()
+}
+
+/// Node administration and signing with the node's keys should never be exposed to the public.
+fn default_denied_methods() -> Vec<String> {
+    ["admin_*", "engine_*", "miner_*", "personal_*"]
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect()
+}
+
+fn default_instance_name() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|x| x.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
 /// This might cause a thundering herd!
 fn default_min_sum_soft_limit() -> u32 {
     1
@@ -166,6 +436,32 @@ fn default_min_synced_rpcs() -> usize {
     1
 }
 
+fn default_multicall_address() -> Address {
+    DEFAULT_MULTICALL_ADDRESS
+        .parse()
+        .expect("the default multicall address should always parse")
+}
+
+/// Tracing every request is expensive on a busy proxy.
+fn default_trace_sample_rate() -> f64 {
+    0.01
+}
+
+/// More than enough for the standard methods and a few chain-specific ones
+fn default_metrics_max_methods() -> usize {
+    100
+}
+
+/// A minute of stats for a busy proxy. A healthy stat loop keeps the queue near empty.
+fn default_stat_queue_max() -> usize {
+    100_000
+}
+
+/// Relays answer quickly. This only has to cover bursts.
+fn default_max_concurrent_txs() -> usize {
+    10
+}
+
 /// Having a low amount of concurrent requests for bearer tokens keeps us from hammering the database.
 fn default_bearer_token_max_concurrent_requests() -> u64 {
     2
@@ -176,26 +472,525 @@ fn default_login_rate_limit_per_period() -> u64 {
     10
 }
 
+fn default_max_batch_size() -> usize {
+    100
+}
+
+fn default_response_headers() -> bool {
+    true
+}
+
 fn default_response_cache_max_bytes() -> usize {
     // TODO: default to some percentage of the system?
     // 100 megabytes
     10_usize.pow(8)
 }
 
+fn default_request_timeout_seconds() -> u64 {
+    120
+}
+
+fn default_drain_timeout_seconds() -> u64 {
+    30
+}
+
+/// A response cache with its own budget for some user tiers.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResponseCachePartitionConfig {
+    pub max_bytes: u64,
+    /// user tier titles. "anonymous" is for requests without an rpc key. a tier can only be in one partition
+    pub tiers: Vec<String>,
+}
+
+/// Wait for a backend's hard limit instead of failing when every backend is rate limited.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BackendQueueConfig {
+    /// the longest a request will wait for a server. requests that would wait longer fail immediately
+    pub max_wait_ms: u64,
+    /// how many requests from each user tier (by title) can wait at once.
+    /// "anonymous" is for requests without an rpc key. tiers that are not listed are not limited
+    #[serde(default)]
+    pub max_depth: HashMap<String, usize>,
+}
+
+/// When the consensus head stops advancing, responses get a "staleSeconds" field and an X-W3P-Stale header.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChainHaltConfig {
+    /// the chain is halted once the consensus head is this many expected block times old
+    #[serde(default = "default_chain_halt_missed_blocks")]
+    pub missed_blocks: u64,
+    /// reject `eth_sendRawTransaction` while the chain is halted
+    #[serde(default = "default_true")]
+    pub pause_txs: bool,
+}
+
+/// Every client gets the same `eth_gasPrice` until the head block changes or `cache_ms` passes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GasPriceConfig {
+    #[serde(default = "default_gas_price_cache_ms")]
+    pub cache_ms: u64,
+    /// a backend's price is clamped to this fraction above or below the fee history's base fee plus median tip. 0.5 = 50%
+    #[serde(default = "default_gas_price_max_spread")]
+    pub max_spread: f64,
+    /// how many blocks of fee history to take the median tip from
+    #[serde(default = "default_gas_price_fee_history_blocks")]
+    pub fee_history_blocks: u64,
+}
+
+/// Simulation methods skip backends that answered them with "method not found".
+#[derive(Clone, Debug, Deserialize)]
+pub struct SimulationConfig {
+    /// add provider-specific simulation methods here
+    #[serde(default = "default_simulation_methods")]
+    pub methods: Vec<String>,
+    /// simulations give up after this long, even if `request_timeout_seconds` is longer
+    #[serde(default = "default_simulation_timeout_ms")]
+    pub timeout_ms: u64,
+    /// only keys that an admin has granted these methods to (with `allowed_methods`) can simulate
+    #[serde(default)]
+    pub require_grant: bool,
+}
+
+fn default_simulation_methods() -> Vec<String> {
+    vec!["eth_simulateV1".to_string()]
+}
+
+fn default_simulation_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_gas_price_cache_ms() -> u64 {
+    1_000
+}
+
+fn default_gas_price_max_spread() -> f64 {
+    0.5
+}
+
+fn default_gas_price_fee_history_blocks() -> u64 {
+    20
+}
+
+/// Each rollup is built from the one before it. The first is built from the per-minute `rpc_accounting` rows.
+#[derive(Clone, Debug, Deserialize)]
+pub struct StatRollupsConfig {
+    /// delete per-minute rows this old once they are rolled up.
+    /// None = keep them forever
+    pub raw_retention_days: Option<u64>,
+    /// shortest period first. each period must be a multiple of the one before it
+    #[serde(default)]
+    pub rollups: Vec<StatRollupConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StatRollupConfig {
+    pub period_seconds: u64,
+    /// delete rows this old once the next rollup has them.
+    /// None = keep them forever
+    pub retention_days: Option<u64>,
+}
+
+fn default_chain_halt_missed_blocks() -> u64 {
+    10
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// An on-disk cache behind the in-memory response cache.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiskCacheConfig {
+    /// directory for the cache's files
+    pub path: String,
+    /// the oldest blocks are evicted when the saved responses are bigger than this. the files on disk can be bigger
+    #[serde(default = "default_disk_cache_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_disk_cache_max_bytes() -> u64 {
+    // 10GB
+    10_000_000_000
+}
+
+/// Flashbots-style bundle relays.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BundlesConfig {
+    /// names of http servers in `private_rpcs`
+    pub relays: Vec<String>,
+    /// private key that signs the X-Flashbots-Signature header.
+    /// Relays use it for reputation. It should not hold any funds
+    pub signing_key: String,
+}
+
+/// Auth for the prometheus port. If both a bearer token and basic auth are set, either works.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MetricsAuthConfig {
+    /// require "Authorization: Bearer $bearer_token"
+    pub bearer_token: Option<String>,
+    /// require basic auth with this username and `basic_password`
+    pub basic_username: Option<String>,
+    pub basic_password: Option<String>,
+    /// only allow connections from these ips or CIDR ranges. X-Forwarded-For is not trusted.
+    /// Empty = allow any ip
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+}
+
+/// A static response for a method. Useful for dev chains or for sunsetting a method.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MethodStubConfig {
+    pub method: String,
+    /// only match requests whose params start with these.
+    /// None = match any params
+    pub params: Option<Vec<serde_json::Value>>,
+    /// only match requests from these rpc keys (by database id).
+    /// Empty = match every request
+    #[serde(default)]
+    pub rpc_key_ids: Vec<u64>,
+    #[serde(flatten)]
+    pub response: MethodStubResponse,
+}
+
+/// Exactly one of `result` or `error`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MethodStubResponse {
+    Result(serde_json::Value),
+    Error(JsonRpcErrorData),
+}
+
+/// How transactions are sent to the private relays in `private_rpcs`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrivateTxsConfig {
+    /// how many relays must accept a transaction for it to be a success
+    #[serde(default = "default_min_relays")]
+    pub min_relays: usize,
+    /// how long to wait for the relays, including waiting on their rate limits.
+    /// None = the request timeout
+    pub relay_timeout_ms: Option<u64>,
+    /// after enough relays accept, how long to wait for the others so that their outcomes are in the response
+    #[serde(default = "default_relay_grace_ms")]
+    pub relay_grace_ms: u64,
+    /// send to the balanced_rpcs at the same time. the transaction will be in the public mempool
+    #[serde(default)]
+    pub also_public: bool,
+    /// what to do when fewer than `min_relays` relays accept a transaction
+    #[serde(default)]
+    pub on_relay_failure: RelayFailure,
+    /// a private transaction that is still not included this many blocks after it was sent is forgotten
+    #[serde(default = "default_private_tx_forget_after_blocks")]
+    pub forget_after_blocks: u64,
+}
+
+impl Default for PrivateTxsConfig {
+    fn default() -> Self {
+        Self {
+            min_relays: default_min_relays(),
+            relay_timeout_ms: None,
+            relay_grace_ms: default_relay_grace_ms(),
+            also_public: false,
+            on_relay_failure: RelayFailure::default(),
+            forget_after_blocks: default_private_tx_forget_after_blocks(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TxTrackingConfig {
+    /// a mined transaction is confirmed once its block is this deep
+    #[serde(default = "default_tx_confirmations")]
+    pub confirmations: u64,
+    /// a transaction that is not mined and not in a mempool this many blocks after it was sent is dropped
+    #[serde(default = "default_tx_dropped_after_blocks")]
+    pub dropped_after_blocks: u64,
+    /// confirmed and dropped transactions are forgotten after this long
+    #[serde(default = "default_tx_forget_after_seconds")]
+    pub forget_after_seconds: u64,
+    /// the most transactions to follow at once. the oldest is forgotten to make room
+    #[serde(default = "default_tx_max_tracked")]
+    pub max_tracked: usize,
+}
+
+impl Default for TxTrackingConfig {
+    fn default() -> Self {
+        Self {
+            confirmations: default_tx_confirmations(),
+            dropped_after_blocks: default_tx_dropped_after_blocks(),
+            forget_after_seconds: default_tx_forget_after_seconds(),
+            max_tracked: default_tx_max_tracked(),
+        }
+    }
+}
+
+fn default_tx_confirmations() -> u64 {
+    12
+}
+
+fn default_tx_dropped_after_blocks() -> u64 {
+    50
+}
+
+fn default_tx_forget_after_seconds() -> u64 {
+    3600
+}
+
+fn default_tx_max_tracked() -> usize {
+    10_000
+}
+
+/// One credit pays for one request. Methods in `method_costs` cost more.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreditsConfig {
+    /// users deposit by sending to this address from the address they log in with
+    pub deposit_address: Address,
+    /// credits for one whole native token (1e18 wei).
+    /// None = the native token is not accepted
+    pub credits_per_native_token: Option<f64>,
+    /// ERC-20 tokens that are accepted, by contract address
+    #[serde(default)]
+    pub tokens: HashMap<Address, DepositTokenConfig>,
+    /// deposits are credited once their block is this deep
+    #[serde(default = "default_deposit_confirmations")]
+    pub confirmations: u64,
+    /// on start, look this many blocks back for deposits that were made while the proxy was down
+    #[serde(default = "default_deposit_lookback_blocks")]
+    pub lookback_blocks: u64,
+    /// spent credits are saved to the database this often
+    #[serde(default = "default_credits_save_seconds")]
+    pub save_seconds: u64,
+    /// keys of users in these tiers don't spend credits
+    #[serde(default)]
+    pub free_user_tiers: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DepositTokenConfig {
+    pub decimals: u8,
+    /// credits for one whole token
+    pub credits_per_token: f64,
+}
+
+fn default_deposit_confirmations() -> u64 {
+    12
+}
+
+fn default_deposit_lookback_blocks() -> u64 {
+    1_000
+}
+
+fn default_credits_save_seconds() -> u64 {
+    10
+}
+
+fn default_min_relays() -> usize {
+    1
+}
+
+fn default_relay_grace_ms() -> u64 {
+    100
+}
+
+fn default_private_tx_forget_after_blocks() -> u64 {
+    1_000
+}
+
+/// What to do with a transaction that not enough private relays accepted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayFailure {
+    /// return a relay's error (or a summary if no relay gave one)
+    #[default]
+    Error,
+    /// send it to the balanced_rpcs instead. the transaction will be in the public mempool
+    Public,
+}
+
+/// What requests without an rpc key can do.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymousAccess {
+    /// anything a key can do. limited by ip
+    #[default]
+    Open,
+    /// anything except sending transactions and bundles
+    ReadOnly,
+    /// nothing. browsers are sent to `redirect_public_url`
+    KeyRequired,
+}
+
+/// What requests with an rpc key do while the stat queue is full.
+/// Stats for anonymous requests are dropped either way.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatQueueFull {
+    /// refuse them with a 503 so that no billing data is lost
+    #[default]
+    Reject,
+    /// serve them and drop their stats
+    Serve,
+}
+
+/// How websocket subscription ids are made.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionIdFormat {
+    /// 16 random bytes like geth. Clients can't guess each other's ids
+    #[default]
+    Random,
+    /// 0x1, 0x2, ... for clients that expect small ids
+    Sequential,
+}
+
+/// Anything that isn't set is copied from the request, which allows it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CorsConfig {
+    /// exact origins like "https://app.example.com". None = any origin
+    pub allowed_origins: Option<Vec<String>>,
+    /// None = any method
+    pub allowed_methods: Option<Vec<String>>,
+    /// None = any header
+    pub allowed_headers: Option<Vec<String>>,
+    #[serde(default = "default_true")]
+    pub allow_credentials: bool,
+    /// how long browsers may cache a preflight response
+    pub max_age_seconds: Option<u64>,
+    /// On `/rpc/:rpc_key`, the key's own allowed origins replace `allowed_origins`
+    #[serde(default = "default_true")]
+    pub rpc_key_origins: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: None,
+            allowed_methods: None,
+            allowed_headers: None,
+            allow_credentials: true,
+            max_age_seconds: None,
+            rpc_key_origins: true,
+        }
+    }
+}
+
+/// Response compression. Clients pick the encoding with their `Accept-Encoding` header.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default = "default_true")]
+    pub gzip: bool,
+    #[serde(default = "default_true")]
+    pub br: bool,
+    /// smaller responses aren't worth compressing
+    #[serde(default = "default_compression_min_bytes")]
+    pub min_bytes: u16,
+    /// cached responses are compressed once and kept this long
+    #[serde(default = "default_compressed_cache_max_bytes")]
+    pub cache_max_bytes: u64,
+}
+
+fn default_compression_min_bytes() -> u16 {
+    1024
+}
+
+fn default_compressed_cache_max_bytes() -> u64 {
+    10_u64.pow(7)
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            gzip: true,
+            br: true,
+            min_bytes: default_compression_min_bytes(),
+            cache_max_bytes: default_compressed_cache_max_bytes(),
+        }
+    }
+}
+
+/// How a hosted provider bills requests.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderPricingConfig {
+    /// compute units per request, by method
+    #[serde(default)]
+    pub compute_units: HashMap<String, u64>,
+    /// compute units for methods that aren't in `compute_units`
+    #[serde(default = "default_compute_units")]
+    pub default_compute_units: u64,
+    #[serde(default)]
+    pub usd_per_million_compute_units: f64,
+    /// flat prices per request, by method. These replace the compute unit price for their methods
+    #[serde(default)]
+    pub usd_per_request: HashMap<String, f64>,
+}
+
+fn default_compute_units() -> u64 {
+    1
+}
+
+/// An existing api gateway's identity system. Its keys work here without copying them into our database.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExternalAuthConfig {
+    /// Unknown keys are POSTed here as json like `{"rpc_key": "...", "rpc_key_uuid": "...", "chain_id": 1}`.
+    /// The response should be json like `{"allowed": true, "user": "acme", "max_requests_per_period": 1000}`
+    pub url: String,
+    /// sent to the authorizer in the `Authorization` header
+    pub bearer_token: Option<String>,
+    /// Give up on the authorizer after this long. The request fails instead of being allowed
+    #[serde(default = "default_external_auth_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_external_auth_timeout_seconds() -> u64 {
+    5
+}
+
+/// Reputation checks for anonymous users. Flagged ips get reduced limits or are rejected.
+#[derive(Debug, Default, Deserialize)]
+pub struct IpReputationConfig {
+    /// path to a file with one ip or CIDR range per line. Lines starting with '#' are ignored.
+    pub blocklist_file: Option<String>,
+
+    /// url to query for an ip's reputation. If set, must contain "{{ip}}"
+    /// The response should be json like `{"flagged": true}`
+    pub lookup_url: Option<String>,
+
+    /// Request limit for flagged ips.
+    /// None = reject all requests from flagged ips
+    pub flagged_requests_per_period: Option<u64>,
+
+    /// How long to cache lookup results in redis
+    #[serde(default = "default_ip_reputation_cache_seconds")]
+    pub cache_seconds: usize,
+
+    /// unknown config options get put here
+    #[serde(flatten, default = "HashMap::default")]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// Reputations don't change often. An hour of caching keeps us from hammering the lookup hook.
+fn default_ip_reputation_cache_seconds() -> usize {
+    3600
+}
+
 /// Configuration for a backend web3 RPC server
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Web3ConnectionConfig {
     /// simple way to disable a connection without deleting the row
     #[serde(default)]
     pub disabled: bool,
     /// a name used in /status and other user facing messages
     pub display_name: Option<String>,
-    /// websocket (or http if no websocket)
+    /// websocket (or http if no websocket). `ipc:///path/to/geth.ipc` for a node on the same host
     pub url: String,
-    /// block data limit. If None, will be queried
+    /// How many blocks back the server has data for.
+    /// If None, this is found by probing the server when it connects and again every hour
     pub block_data_limit: Option<u64>,
     /// the requests per second at which the server starts slowing down
     pub soft_limit: u32,
+    /// like soft_limit, but for following new heads, pending transactions, and `logs` subscriptions.
+    /// These are counted separately so that a server busy with heavy requests can still keep up with the chain.
+    /// If None, this is the same as soft_limit
+    pub subscription_soft_limit: Option<u32>,
     /// the requests per second at which the server throws errors (rate limit or otherwise)
     pub hard_limit: Option<u64>,
     /// All else equal, a server with a lower tier receives all requests
@@ -205,6 +1000,18 @@ pub struct Web3ConnectionConfig {
     /// Don't do this with free rpcs
     #[serde(default)]
     pub subscribe_txs: Option<bool>,
+    /// Give up on a request to this server after this many seconds and retry on another.
+    /// The client's remaining time is used if it is shorter.
+    pub timeout_seconds: Option<u64>,
+    /// After connecting or reconnecting, the soft limit ramps up from 10% over this many seconds.
+    /// This gives the server time to warm its caches. 0 = full soft limit immediately
+    #[serde(default = "default_slow_start_seconds")]
+    pub slow_start_seconds: u64,
+    /// name of a table in `app.provider_pricing`. Requests to this server are counted either way
+    pub pricing: Option<String>,
+    /// The widest `eth_getLogs` block range this server allows. Wider requests go to other servers.
+    /// If None, the default for the server's node software is used (5,000 for besu)
+    pub max_logs_range: Option<u64>,
     /// unknown config options get put here
     #[serde(flatten, default = "HashMap::default")]
     pub extra: HashMap<String, serde_json::Value>,
@@ -214,6 +1021,10 @@ fn default_tier() -> u64 {
     0
 }
 
+fn default_slow_start_seconds() -> u64 {
+    30
+}
+
 impl Web3ConnectionConfig {
     /// Create a Web3Connection from config
     /// TODO: move this into Web3Connection? (just need to make things pub(crate))
@@ -267,12 +1078,16 @@ impl Web3ConnectionConfig {
             http_interval_sender,
             hard_limit,
             self.soft_limit,
+            self.subscription_soft_limit.unwrap_or(self.soft_limit),
             self.block_data_limit,
             block_map,
             block_sender,
             tx_id_sender,
             true,
             self.tier,
+            self.timeout_seconds.map(Duration::from_secs),
+            Duration::from_secs(self.slow_start_seconds),
+            self.max_logs_range,
             open_request_handle_metrics,
         )
         .await
@@ -0,0 +1,336 @@
+//! Responses that can never change, saved to disk so that a restart doesn't lose the warm cache.
+//!
+//! The in-memory response cache is checked first.
+//! Only successful responses for blocks older than `MAX_REORG_DEPTH` are saved.
+//! sled blocks, so it is only used from `spawn_blocking`.
+
+use crate::app::AnyhowJoinHandle;
+use crate::config::DiskCacheConfig;
+use crate::jsonrpc::JsonRpcForwardedResponse;
+use crate::rpcs::blockchain::MAX_REORG_DEPTH;
+use anyhow::Context;
+use ethers::prelude::U64;
+use ethers::utils::keccak256;
+use log::{info, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+
+/// An entry's key in `responses` plus its key in `by_block`.
+/// `size_on_disk` includes sled's logs and garbage, so the budget is for these bytes instead
+const ENTRY_OVERHEAD: u64 = 32 + 8 + 32;
+
+pub struct DiskCache {
+    db: sled::Db,
+    /// keccak256 of the request -> the response
+    responses: sled::Tree,
+    /// block number (big endian) ++ key. the oldest blocks are evicted first
+    by_block: sled::Tree,
+    /// the bytes of every response and its keys
+    bytes: AtomicU64,
+    max_bytes: u64,
+}
+
+fn entry_bytes(value_len: usize) -> u64 {
+    ENTRY_OVERHEAD + value_len as u64
+}
+
+impl DiskCache {
+    /// This blocks while the existing responses are counted.
+    pub fn open(config: &DiskCacheConfig) -> anyhow::Result<Self> {
+        let db = sled::Config::new()
+            .path(&config.path)
+            .open()
+            .with_context(|| format!("opening disk cache at {}", config.path))?;
+
+        let responses = db.open_tree("responses")?;
+        let by_block = db.open_tree("by_block")?;
+
+        let mut bytes = 0;
+
+        for x in responses.iter() {
+            let (_, value) = x?;
+
+            bytes += entry_bytes(value.len());
+        }
+
+        info!(
+            "disk cache at {} has {} responses ({} bytes)",
+            config.path,
+            responses.len(),
+            bytes
+        );
+
+        Ok(Self {
+            db,
+            responses,
+            by_block,
+            bytes: bytes.into(),
+            max_bytes: config.max_bytes,
+        })
+    }
+
+    /// Responses are saved by method and params. Block tags are already replaced with numbers.
+    pub fn key(method: &str, params: Option<&serde_json::Value>) -> [u8; 32] {
+        let params = params.map(|x| x.to_string()).unwrap_or_default();
+
+        keccak256(format!("{}:{}", method, params))
+    }
+
+    pub async fn get(self: &Arc<Self>, key: [u8; 32]) -> Option<JsonRpcForwardedResponse> {
+        let this = self.clone();
+
+        let x = tokio::task::spawn_blocking(move || this.responses.get(key)).await;
+
+        match x {
+            Ok(Ok(Some(x))) => serde_json::from_slice(&x).ok(),
+            Ok(Ok(None)) => None,
+            Ok(Err(err)) => {
+                warn!("disk cache read failed. err={:?}", err);
+                None
+            }
+            Err(err) => {
+                warn!("disk cache read panicked. err={:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Save the response in the background if it can never change.
+    pub fn insert(
+        self: &Arc<Self>,
+        key: &[u8; 32],
+        params: Option<&serde_json::Value>,
+        request_block_num: Option<U64>,
+        response: &JsonRpcForwardedResponse,
+        head_block_num: U64,
+    ) {
+        if response.error.is_some() {
+            return;
+        }
+
+        let result = match response
+            .result
+            .as_ref()
+            .and_then(|x| serde_json::from_str(x.get()).ok())
+        {
+            Some(x) => x,
+            None => return,
+        };
+
+        let block_num = match final_block(params, request_block_num, &result, head_block_num) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let value = match serde_json::to_vec(response) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        let mut by_block_key = block_num.as_u64().to_be_bytes().to_vec();
+        by_block_key.extend_from_slice(key);
+
+        let key = *key;
+        let this = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let new_bytes = entry_bytes(value.len());
+
+            match this
+                .responses
+                .insert(key, value)
+                .and_then(|old| this.by_block.insert(by_block_key, vec![]).map(|_| old))
+            {
+                Ok(old) => {
+                    this.bytes.fetch_add(new_bytes, Ordering::Relaxed);
+
+                    if let Some(old) = old {
+                        this.bytes
+                            .fetch_sub(entry_bytes(old.len()), Ordering::Relaxed);
+                    }
+                }
+                Err(err) => warn!("disk cache write failed. err={:?}", err),
+            }
+        });
+    }
+
+    /// Evict the oldest blocks whenever the cache is over its size budget.
+    pub fn spawn_evictor(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.evictor_loop())
+    }
+
+    async fn evictor_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = interval(Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+
+            if self.bytes.load(Ordering::Relaxed) <= self.max_bytes {
+                continue;
+            }
+
+            let this = self.clone();
+
+            let evicted = tokio::task::spawn_blocking(move || this.evict()).await??;
+
+            info!(
+                "evicted {} responses from the disk cache. {} bytes left",
+                evicted,
+                self.bytes.load(Ordering::Relaxed)
+            );
+
+            self.db.flush_async().await?;
+        }
+    }
+
+    /// Remove the oldest blocks until the cache is under budget. Blocks
+    fn evict(&self) -> anyhow::Result<u64> {
+        let mut evicted = 0;
+
+        while self.bytes.load(Ordering::Relaxed) > self.max_bytes {
+            let (by_block_key, _) = match self.by_block.pop_min()? {
+                Some(x) => x,
+                // nothing left to evict
+                None => break,
+            };
+
+            // a response that was saved again for another block has a second by_block entry. it might already be gone
+            if let Some(old) = self.responses.remove(&by_block_key[8..])? {
+                self.bytes
+                    .fetch_sub(entry_bytes(old.len()), Ordering::Relaxed);
+
+                evicted += 1;
+            }
+        }
+
+        Ok(evicted)
+    }
+}
+
+/// The block that makes a response final. None if the response might still change.
+fn final_block(
+    params: Option<&serde_json::Value>,
+    request_block_num: Option<U64>,
+    result: &serde_json::Value,
+    head_block_num: U64,
+) -> Option<U64> {
+    if result.is_null() {
+        // not found yet. it might be found later
+        return None;
+    }
+
+    // these are replaced with numbers for most methods, but not all
+    if let Some(params) = params {
+        let params = params.to_string();
+
+        if ["latest", "pending", "safe", "finalized"]
+            .iter()
+            .any(|x| params.contains(&format!("\"{}\"", x)))
+        {
+            return None;
+        }
+    }
+
+    // blocks, transactions, and receipts say which block they are in
+    let result_block_num = ["blockNumber", "number"]
+        .iter()
+        .find_map(|x| result.get(x))
+        .and_then(|x| serde_json::from_value::<U64>(x.clone()).ok());
+
+    let block_num = result_block_num.or(request_block_num)?;
+
+    if block_num + U64::from(MAX_REORG_DEPTH) <= head_block_num {
+        Some(block_num)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_evict_by_bytes() {
+        let dir = std::env::temp_dir().join(format!("web3_proxy_disk_cache_{}", ulid::Ulid::new()));
+
+        let config = DiskCacheConfig {
+            path: dir.to_string_lossy().to_string(),
+            max_bytes: 0,
+        };
+
+        let cache = Arc::new(DiskCache::open(&config).unwrap());
+
+        let response: JsonRpcForwardedResponse =
+            serde_json::from_str(r#"{"jsonrpc": "2.0", "id": 1, "result": {"number": "0x1"}}"#)
+                .unwrap();
+
+        for i in 0..3u64 {
+            let key = DiskCache::key("eth_getBlockByNumber", Some(&json!([i])));
+
+            cache.insert(&key, None, None, &response, 1_000.into());
+        }
+
+        // inserts happen in the background. each one holds the cache until it is done
+        while Arc::strong_count(&cache) > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(cache.responses.len(), 3);
+
+        let entry = cache.bytes.load(Ordering::Relaxed) / 3;
+
+        assert!(entry > ENTRY_OVERHEAD);
+
+        // room for one response. the two oldest are evicted
+        let cache = DiskCache {
+            max_bytes: entry,
+            ..Arc::try_unwrap(cache).ok().unwrap()
+        };
+
+        assert_eq!(cache.evict().unwrap(), 2);
+        assert_eq!(cache.responses.len(), 1);
+        assert_eq!(cache.bytes.load(Ordering::Relaxed), entry);
+
+        drop(cache);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_final_block() {
+        let head: U64 = 1_000.into();
+
+        // a receipt deep enough to be safe from reorgs
+        let receipt = json!({"blockNumber": "0x64", "status": "0x1"});
+        assert_eq!(
+            final_block(None, Some(head), &receipt, head),
+            Some(100.into())
+        );
+
+        // a receipt that could still be reorged
+        let receipt = json!({"blockNumber": "0x3e7", "status": "0x1"});
+        assert_eq!(final_block(None, Some(head), &receipt, head), None);
+
+        // a balance at an old block
+        let params = json!(["0x0000000000000000000000000000000000000000", "0x64"]);
+        assert_eq!(
+            final_block(Some(&params), Some(100.into()), &json!("0x0"), head),
+            Some(100.into())
+        );
+
+        // tags follow the head
+        let params = json!(["latest", false]);
+        let block = json!({"number": "0x64"});
+        assert_eq!(final_block(Some(&params), None, &block, head), None);
+
+        // not found yet
+        assert_eq!(
+            final_block(None, Some(100.into()), &json!(null), head),
+            None
+        );
+    }
+}
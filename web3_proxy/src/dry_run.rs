@@ -0,0 +1,342 @@
+//! Readiness checks for `--dry-run`.
+//!
+//! Everything in the config is connected to and checked, but no ports are bound and nothing is written.
+//! Useful in CI and before a deploy.
+
+use crate::app::get_db;
+use crate::config::{TopConfig, Web3ConnectionConfig};
+use crate::rpcs::provider::Web3Provider;
+use anyhow::Context;
+use ethers::prelude::{Address, U256, U64};
+use futures::future::join_all;
+use migration::{Migrator, MigratorTrait};
+use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// How long each backend, the database, and redis get to answer.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Backends this many blocks behind the best head don't count as synced.
+const MAX_HEAD_LAG_BLOCKS: u64 = 2;
+
+/// The database or redis.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ServiceReport {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// migrations that would run when the proxy starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_migrations: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BackendReport {
+    pub name: String,
+    /// "balanced", "private", or "archive"
+    pub group: &'static str,
+    pub ok: bool,
+    /// set once the backend's head is compared to the others
+    pub synced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_block: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    /// the backend said it is still syncing
+    pub syncing: bool,
+    /// the backend has state for block 1
+    pub archive: bool,
+    /// websocket and ipc backends can send new heads instead of being polled
+    pub subscriptions: bool,
+    pub soft_limit: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub chain_id: u64,
+    /// false if there are any problems
+    pub ready: bool,
+    pub problems: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<ServiceReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redis: Option<ServiceReport>,
+    pub backends: Vec<BackendReport>,
+}
+
+/// Check every backend, the database, and redis.
+pub async fn readiness_report(top_config: &TopConfig) -> anyhow::Result<ReadinessReport> {
+    let chain_id = top_config.app.chain_id;
+
+    let mut problems = vec![];
+
+    let database = match top_config.app.db_url.clone() {
+        None => None,
+        Some(db_url) => Some(check_database(db_url).await),
+    };
+
+    if let Some(x) = database.as_ref() {
+        if !x.ok {
+            problems.push("database is not available".to_string());
+        }
+    }
+
+    let redis = match top_config.app.volatile_redis_url.as_ref() {
+        None => None,
+        Some(redis_url) => Some(check_redis(redis_url).await),
+    };
+
+    if let Some(x) = redis.as_ref() {
+        if !x.ok {
+            problems.push("redis is not available".to_string());
+        }
+    }
+
+    let http_client = reqwest::ClientBuilder::new()
+        .connect_timeout(CHECK_TIMEOUT)
+        .timeout(CHECK_TIMEOUT)
+        .build()?;
+
+    let groups = [
+        ("balanced", Some(&top_config.balanced_rpcs)),
+        ("private", top_config.private_rpcs.as_ref()),
+        ("archive", top_config.archive_rpcs.as_ref()),
+    ];
+
+    let futures = groups
+        .into_iter()
+        .filter_map(|(group, rpcs)| rpcs.map(|x| (group, x)))
+        .flat_map(|(group, rpcs)| {
+            rpcs.iter()
+                .filter(|(_, config)| !config.disabled)
+                .map(move |(name, config)| (group, name, config))
+        })
+        .map(|(group, name, config)| check_backend(group, name, config, &http_client));
+
+    let mut backends = join_all(futures).await;
+
+    // sort for stable output
+    backends.sort_by(|a, b| (a.group, &a.name).cmp(&(b.group, &b.name)));
+
+    let best_head = backends
+        .iter()
+        .filter(|x| x.ok)
+        .filter_map(|x| x.head_block)
+        .max()
+        .unwrap_or_default();
+
+    for backend in backends.iter_mut() {
+        if !backend.ok {
+            problems.push(format!("{} rpc {} failed", backend.group, backend.name));
+            continue;
+        }
+
+        if backend.chain_id != Some(chain_id.into()) {
+            backend.ok = false;
+            problems.push(format!(
+                "{} rpc {} is on chain {:?}",
+                backend.group, backend.name, backend.chain_id
+            ));
+            continue;
+        }
+
+        backend.synced = !backend.syncing
+            && backend
+                .head_block
+                .map(|x| x + MAX_HEAD_LAG_BLOCKS >= best_head)
+                .unwrap_or(false);
+    }
+
+    let synced_balanced = backends
+        .iter()
+        .filter(|x| x.group == "balanced" && x.synced);
+
+    let num_synced = synced_balanced.clone().count();
+    let sum_soft_limit: u32 = synced_balanced.map(|x| x.soft_limit).sum();
+
+    if num_synced < top_config.app.min_synced_rpcs {
+        problems.push(format!(
+            "only {}/{} balanced rpcs are synced",
+            num_synced, top_config.app.min_synced_rpcs
+        ));
+    }
+
+    if sum_soft_limit < top_config.app.min_sum_soft_limit {
+        problems.push(format!(
+            "only {}/{} soft limit is synced",
+            sum_soft_limit, top_config.app.min_sum_soft_limit
+        ));
+    }
+
+    Ok(ReadinessReport {
+        chain_id,
+        ready: problems.is_empty(),
+        problems,
+        database,
+        redis,
+        backends,
+    })
+}
+
+/// Connect and count the pending migrations. Nothing is migrated.
+async fn check_database(db_url: String) -> ServiceReport {
+    let start = Instant::now();
+
+    let f = async {
+        let db_conn = get_db(db_url, 1, 1).await?;
+
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        let pending_migrations = Migrator::get_pending_migrations(&db_conn).await?.len();
+
+        anyhow::Ok((latency_ms, pending_migrations))
+    };
+
+    match timeout(CHECK_TIMEOUT, f).await {
+        Ok(Ok((latency_ms, pending_migrations))) => ServiceReport {
+            ok: true,
+            latency_ms: Some(latency_ms),
+            pending_migrations: Some(pending_migrations),
+            error: None,
+        },
+        Ok(Err(err)) => ServiceReport {
+            error: Some(format!("{:#}", err)),
+            ..Default::default()
+        },
+        Err(_) => ServiceReport {
+            error: Some("timed out".to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+async fn check_redis(redis_url: &str) -> ServiceReport {
+    let start = Instant::now();
+
+    let f = async {
+        let redis_pool = RedisConfig::from_url(redis_url)
+            .builder()?
+            .max_size(1)
+            .runtime(DeadpoolRuntime::Tokio1)
+            .build()?;
+
+        let mut redis_conn = redis_pool.get().await?;
+
+        let _: String = redis::cmd("PING").query_async(&mut redis_conn).await?;
+
+        anyhow::Ok(start.elapsed().as_millis() as u64)
+    };
+
+    match timeout(CHECK_TIMEOUT, f).await {
+        Ok(Ok(latency_ms)) => ServiceReport {
+            ok: true,
+            latency_ms: Some(latency_ms),
+            ..Default::default()
+        },
+        Ok(Err(err)) => ServiceReport {
+            error: Some(format!("{:#}", err)),
+            ..Default::default()
+        },
+        Err(_) => ServiceReport {
+            error: Some("timed out".to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+async fn check_backend(
+    group: &'static str,
+    name: &str,
+    config: &Web3ConnectionConfig,
+    http_client: &reqwest::Client,
+) -> BackendReport {
+    // the url often has an api key in it. only the name is reported
+    let mut report = BackendReport {
+        name: config
+            .display_name
+            .clone()
+            .unwrap_or_else(|| name.to_string()),
+        group,
+        soft_limit: config.soft_limit,
+        subscriptions: config.url.starts_with("ws") || config.url.starts_with("ipc"),
+        ..Default::default()
+    };
+
+    match timeout(
+        CHECK_TIMEOUT,
+        check_backend_inner(&mut report, config, http_client),
+    )
+    .await
+    {
+        Ok(Ok(())) => report.ok = true,
+        Ok(Err(err)) => report.error = Some(format!("{:#}", err)),
+        Err(_) => report.error = Some("timed out".to_string()),
+    }
+
+    report
+}
+
+async fn check_backend_inner(
+    report: &mut BackendReport,
+    config: &Web3ConnectionConfig,
+    http_client: &reqwest::Client,
+) -> anyhow::Result<()> {
+    let provider = Web3Provider::from_str(&config.url, Some(http_client.clone()))
+        .await
+        .context("connecting")?;
+
+    let start = Instant::now();
+
+    report.chain_id = Some(request(&provider, "eth_chainId", json!([])).await?);
+
+    report.latency_ms = Some(start.elapsed().as_millis() as u64);
+
+    // not every client supports this
+    report.client_version = request(&provider, "web3_clientVersion", json!([]))
+        .await
+        .ok();
+
+    report.head_block = Some(request(&provider, "eth_blockNumber", json!([])).await?);
+
+    let syncing: serde_json::Value = request(&provider, "eth_syncing", json!([])).await?;
+
+    report.syncing = syncing != json!(false);
+
+    report.archive = match config.block_data_limit {
+        // 0 means the proxy would probe it
+        Some(x) if x > 0 => x == u64::MAX,
+        _ => request::<U256>(&provider, "eth_getBalance", json!([Address::zero(), "0x1"]))
+            .await
+            .is_ok(),
+    };
+
+    Ok(())
+}
+
+async fn request<R: Debug + DeserializeOwned + Send + Serialize>(
+    provider: &Web3Provider,
+    method: &str,
+    params: serde_json::Value,
+) -> anyhow::Result<R> {
+    let x = match provider {
+        Web3Provider::Http(provider) => provider.request(method, params).await?,
+        Web3Provider::Ws(provider) => provider.request(method, params).await?,
+        Web3Provider::Ipc(provider) => provider.request(method, params).await?,
+        Web3Provider::Mock => return Err(anyhow::anyhow!("mock providers can't be checked")),
+    };
+
+    Ok(x)
+}
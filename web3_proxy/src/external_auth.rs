@@ -0,0 +1,215 @@
+//! Optional http hook for rpc keys that are managed by another system.
+//!
+//! Keys that aren't in our database are sent to the authorizer. Its answer is cached along with our own keys.
+//! Its keys and users aren't in our database, so they get made up ids and their stats are saved without the key.
+use crate::app::AuthorizationChecks;
+use crate::config::ExternalAuthConfig;
+use crate::frontend::authorization::RpcSecretKey;
+use anyhow::Context;
+use axum::headers::{Header, Origin};
+use entities::sea_orm_active_enums::LogLevel;
+use http::HeaderValue;
+use ipnet::IpNet;
+use log::trace;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::iter;
+use std::num::NonZeroU64;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Ids from our database never get this high, so made up ids can't collide with them.
+const EXTERNAL_ID_BIT: u64 = 1 << 63;
+
+/// the json response from the authorizer
+#[derive(Debug, Deserialize)]
+struct ExternalAuthResponse {
+    allowed: bool,
+    /// Keys with the same user share the account limits. Defaults to the key
+    user: Option<String>,
+    tier: Option<String>,
+    max_requests_per_period: Option<u64>,
+    max_concurrent_requests: Option<u32>,
+    max_account_requests_per_period: Option<u64>,
+    allowed_origins: Option<Vec<String>>,
+    allowed_ips: Option<Vec<String>>,
+    method_allowlist: Option<Vec<String>>,
+}
+
+pub struct ExternalAuthorizer {
+    bearer_token: Option<String>,
+    chain_id: u64,
+    http_client: reqwest::Client,
+    timeout: Duration,
+    url: String,
+}
+
+impl ExternalAuthorizer {
+    pub fn try_new(
+        config: &ExternalAuthConfig,
+        chain_id: u64,
+        http_client: Option<reqwest::Client>,
+    ) -> anyhow::Result<Self> {
+        let http_client = http_client.context("external_auth requires an http client")?;
+
+        reqwest::Url::parse(&config.url).context("parsing external_auth.url")?;
+
+        Ok(Self {
+            bearer_token: config.bearer_token.clone(),
+            chain_id,
+            http_client,
+            timeout: Duration::from_secs(config.timeout_seconds),
+            url: config.url.clone(),
+        })
+    }
+
+    /// Ask the authorizer about a key. Denied keys get the default checks, which have no rpc_key_id.
+    pub async fn check(&self, rpc_secret_key: RpcSecretKey) -> anyhow::Result<AuthorizationChecks> {
+        let rpc_key = rpc_secret_key.to_string();
+        let rpc_key_uuid: Uuid = rpc_secret_key.into();
+
+        let mut request = self
+            .http_client
+            .post(&self.url)
+            .timeout(self.timeout)
+            .json(&json!({
+                "rpc_key": rpc_key,
+                "rpc_key_uuid": rpc_key_uuid.to_string(),
+                "chain_id": self.chain_id,
+            }));
+
+        if let Some(bearer_token) = self.bearer_token.as_ref() {
+            request = request.bearer_auth(bearer_token);
+        }
+
+        let response = request.send().await.context("querying external_auth")?;
+
+        // gateways commonly answer unknown keys like this instead of with json
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+        ) {
+            trace!("external auth denied {}", rpc_key);
+            return Ok(AuthorizationChecks::default());
+        }
+
+        let response: ExternalAuthResponse = response
+            .error_for_status()
+            .context("external_auth error")?
+            .json()
+            .await
+            .context("parsing external_auth response")?;
+
+        external_authorization_checks(&rpc_key, response)
+    }
+}
+
+/// Ids from the authorizer are not in our database.
+pub fn is_external_id(id: u64) -> bool {
+    id & EXTERNAL_ID_BIT != 0
+}
+
+/// A stable id with the high bit set. `namespace` keeps key ids and user ids apart.
+fn external_id(namespace: &str, x: &str) -> NonZeroU64 {
+    let hash = Sha256::digest(format!("{}:{}", namespace, x));
+
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+
+    let id = u64::from_be_bytes(bytes) | EXTERNAL_ID_BIT;
+
+    NonZeroU64::new(id).expect("the high bit is set")
+}
+
+fn external_authorization_checks(
+    rpc_key: &str,
+    response: ExternalAuthResponse,
+) -> anyhow::Result<AuthorizationChecks> {
+    if !response.allowed {
+        return Ok(AuthorizationChecks::default());
+    }
+
+    let allowed_origins = response
+        .allowed_origins
+        .map(|x| {
+            x.iter()
+                .map(|x| {
+                    let x = HeaderValue::from_str(x.trim())?;
+
+                    Origin::decode(&mut iter::once(&x)).map_err(anyhow::Error::from)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .transpose()
+        .context("parsing external_auth allowed_origins")?;
+
+    let allowed_ips = response
+        .allowed_ips
+        .map(|x| {
+            x.iter()
+                .map(|x| x.trim().parse::<IpNet>())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .context("parsing external_auth allowed_ips")?;
+
+    let user_id = external_id("user", response.user.as_deref().unwrap_or(rpc_key));
+
+    Ok(AuthorizationChecks {
+        user_id: user_id.get(),
+        rpc_key_id: Some(external_id("rpc_key", rpc_key)),
+        max_requests_per_period: response.max_requests_per_period,
+        max_concurrent_requests: response.max_concurrent_requests,
+        max_account_requests_per_period: response.max_account_requests_per_period,
+        user_tier_title: response.tier,
+        allowed_origins,
+        allowed_ips,
+        method_allowlist: response.method_allowlist,
+        // the key isn't in our database, so its stats can't reference it
+        log_level: LogLevel::None,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_id() {
+        let a = external_id("rpc_key", "a");
+
+        assert!(a.get() & EXTERNAL_ID_BIT != 0);
+        assert_eq!(a, external_id("rpc_key", "a"));
+        assert_ne!(a, external_id("rpc_key", "b"));
+        assert_ne!(a, external_id("user", "a"));
+    }
+
+    #[test]
+    fn test_external_authorization_checks() {
+        let response: ExternalAuthResponse = serde_json::from_value(json!({
+            "allowed": true,
+            "user": "acme",
+            "max_requests_per_period": 1000,
+            "allowed_origins": ["https://app.acme.com"],
+        }))
+        .unwrap();
+
+        let checks = external_authorization_checks("key_a", response).unwrap();
+
+        assert!(checks.rpc_key_id.is_some());
+        assert_eq!(checks.user_id, external_id("user", "acme").get());
+        assert_eq!(checks.max_requests_per_period, Some(1000));
+        assert_eq!(checks.allowed_origins.unwrap().len(), 1);
+        assert_eq!(checks.log_level, LogLevel::None);
+
+        let response: ExternalAuthResponse =
+            serde_json::from_value(json!({ "allowed": false })).unwrap();
+
+        let checks = external_authorization_checks("key_a", response).unwrap();
+
+        assert!(checks.rpc_key_id.is_none());
+    }
+}
@@ -0,0 +1,136 @@
+//! Flags for risky behaviors that can be flipped at runtime without a deploy.
+//!
+//! Defaults come from the `[features]` table in the config.
+//! Overrides are saved in redis so that every proxy for the chain sees them.
+use crate::app::AnyhowJoinHandle;
+use hashbrown::HashMap;
+use log::{info, warn};
+use parking_lot::RwLock;
+use redis_rate_limiter::redis::AsyncCommands;
+use redis_rate_limiter::RedisPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+
+/// how often to load overrides set by other proxies
+const REFRESH_SECONDS: u64 = 10;
+
+/// send transactions that not enough private relays accepted to the balanced rpcs, whatever `on_relay_failure` says
+pub const PRIVATE_FALLBACK: &str = "private_fallback";
+
+pub struct FeatureFlags {
+    chain_id: u64,
+    defaults: HashMap<String, bool>,
+    overrides: RwLock<HashMap<String, bool>>,
+    redis_pool: Option<RedisPool>,
+}
+
+impl FeatureFlags {
+    pub fn new(
+        chain_id: u64,
+        defaults: HashMap<String, bool>,
+        redis_pool: Option<RedisPool>,
+    ) -> Self {
+        Self {
+            chain_id,
+            defaults,
+            overrides: Default::default(),
+            redis_pool,
+        }
+    }
+
+    fn redis_key(&self) -> String {
+        format!("feature_flags:{}", self.chain_id)
+    }
+
+    /// Unknown flags are disabled.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        if let Some(enabled) = self.overrides.read().get(name) {
+            return *enabled;
+        }
+
+        self.defaults.get(name).copied().unwrap_or(false)
+    }
+
+    /// Every known flag and its current value.
+    pub fn all(&self) -> HashMap<String, bool> {
+        let mut flags = self.defaults.clone();
+
+        flags.extend(self.overrides.read().iter().map(|(k, v)| (k.clone(), *v)));
+
+        flags
+    }
+
+    pub fn defaults(&self) -> &HashMap<String, bool> {
+        &self.defaults
+    }
+
+    pub fn overrides(&self) -> HashMap<String, bool> {
+        self.overrides.read().clone()
+    }
+
+    /// Override a flag. None removes the override and the flag goes back to its default.
+    pub async fn set(&self, name: &str, enabled: Option<bool>) -> anyhow::Result<()> {
+        if let Some(redis_pool) = self.redis_pool.as_ref() {
+            let mut redis_conn = redis_pool.get().await?;
+
+            match enabled {
+                Some(enabled) => {
+                    redis_conn
+                        .hset::<_, _, _, ()>(self.redis_key(), name, enabled)
+                        .await?
+                }
+                None => redis_conn.hdel::<_, _, ()>(self.redis_key(), name).await?,
+            }
+        }
+
+        let mut overrides = self.overrides.write();
+
+        match enabled {
+            Some(enabled) => {
+                overrides.insert(name.to_string(), enabled);
+            }
+            None => {
+                overrides.remove(name);
+            }
+        }
+
+        info!("feature flag {} override set to {:?}", name, enabled);
+
+        Ok(())
+    }
+
+    /// Load the overrides that any proxy for this chain has saved.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let redis_pool = match self.redis_pool.as_ref() {
+            None => return Ok(()),
+            Some(x) => x,
+        };
+
+        let mut redis_conn = redis_pool.get().await?;
+
+        let overrides: std::collections::HashMap<String, bool> =
+            redis_conn.hgetall(self.redis_key()).await?;
+
+        *self.overrides.write() = overrides.into_iter().collect();
+
+        Ok(())
+    }
+
+    /// Keep the overrides in sync with redis.
+    pub fn spawn(self: Arc<Self>) -> AnyhowJoinHandle<()> {
+        tokio::spawn(self.refresh_loop())
+    }
+
+    async fn refresh_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = interval(Duration::from_secs(REFRESH_SECONDS));
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = self.refresh().await {
+                warn!("unable to refresh feature flags. err={:?}", err);
+            }
+        }
+    }
+}
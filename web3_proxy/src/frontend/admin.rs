@@ -0,0 +1,820 @@
+//! Handle admin helper logic
+
+use super::authorization::{Authorization as InternalAuthorization, RpcSecretKey};
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use super::users::kick_websockets;
+use crate::app::Web3ProxyApp;
+use crate::audit_log::save_audit_log;
+use crate::config::Web3ConnectionConfig;
+use crate::rpcs::costs::Cost;
+use crate::rpcs::probe::sort_probes;
+use crate::rpcs::reload::ConfigDiff;
+use crate::user_queries::{get_page_from_params, get_query_start_from_params};
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query},
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use chrono::{TimeZone, Utc};
+use entities::{audit_log, rpc_key};
+use futures::future::join_all;
+use hashbrown::HashMap;
+use http::StatusCode;
+use log::info;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter,
+    QueryOrder,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use ulid::Ulid;
+
+/// the JSON input to the `admin_rpc_key_limits_post` handler.
+/// A limit left as None inherits the limit from the user's tier.
+#[derive(Debug, Deserialize)]
+pub struct RpcKeyLimits {
+    max_requests_per_period: Option<u64>,
+    max_concurrent_requests: Option<u32>,
+    /// unix timestamp. if None, the custom limits never expire
+    expires_at: Option<i64>,
+}
+
+/// `GET /admin/keys/:rpc_key_id/limits` -- Use a bearer token to get the custom limits on any rpc key.
+#[debug_handler]
+pub async fn admin_rpc_key_limits_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> FrontendResult {
+    let (_admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let db_replica = app.db_replica().context("getting db for rpc key limits")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(db_replica.conn())
+        .await
+        .context("failed loading rpc key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    Ok(Json(rpc_key_limits_json(&rpc_key)).into_response())
+}
+
+/// `POST /admin/keys/:rpc_key_id/limits` -- Use a bearer token to set custom limits on any rpc key.
+///
+/// This lets us temporarily boost a key without changing the user's tier.
+#[debug_handler]
+pub async fn admin_rpc_key_limits_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    Json(payload): Json<RpcKeyLimits>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let limits_expire_at = match payload.expires_at {
+        None => None,
+        Some(expires_at) => {
+            let expires_at = Utc
+                .timestamp_opt(expires_at, 0)
+                .single()
+                .context("expires_at is not a valid unix timestamp")?;
+
+            if expires_at <= Utc::now() {
+                return Err(FrontendErrorResponse::StatusCode(
+                    StatusCode::BAD_REQUEST,
+                    "expires_at must be in the future".to_string(),
+                    None,
+                ));
+            }
+
+            Some(expires_at)
+        }
+    };
+
+    let db_conn = app
+        .db_conn()
+        .context("setting rpc key limits requires a db")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(&db_conn)
+        .await
+        .context("failed loading rpc key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    info!(
+        "admin #{} setting custom limits on rpc key #{}: {:?}",
+        admin.id, rpc_key_id, payload
+    );
+
+    let before = rpc_key_limits_json(&rpc_key);
+
+    let mut rpc_key = rpc_key.into_active_model();
+
+    rpc_key.max_requests_per_period = sea_orm::Set(payload.max_requests_per_period);
+    rpc_key.max_concurrent_requests = sea_orm::Set(payload.max_concurrent_requests);
+    rpc_key.limits_expire_at = sea_orm::Set(limits_expire_at);
+
+    let rpc_key = rpc_key
+        .update(&db_conn)
+        .await
+        .context("failed saving rpc key limits")?;
+
+    save_audit_log(
+        &db_conn,
+        Some(admin.id),
+        "rpc_key.limits.set",
+        "rpc_key",
+        Some(rpc_key.id),
+        Some(before),
+        Some(rpc_key_limits_json(&rpc_key)),
+    )
+    .await?;
+
+    clear_rpc_key_caches(&app, &rpc_key).await?;
+
+    Ok(Json(rpc_key_limits_json(&rpc_key)).into_response())
+}
+
+/// `DELETE /admin/keys/:rpc_key_id/limits` -- Use a bearer token to remove custom limits from any rpc key.
+///
+/// The key goes back to using the limits from the user's tier.
+#[debug_handler]
+pub async fn admin_rpc_key_limits_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let db_conn = app
+        .db_conn()
+        .context("removing rpc key limits requires a db")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(&db_conn)
+        .await
+        .context("failed loading rpc key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    info!(
+        "admin #{} removing custom limits from rpc key #{}",
+        admin.id, rpc_key_id
+    );
+
+    let before = rpc_key_limits_json(&rpc_key);
+
+    let mut rpc_key = rpc_key.into_active_model();
+
+    rpc_key.max_requests_per_period = sea_orm::Set(None);
+    rpc_key.max_concurrent_requests = sea_orm::Set(None);
+    rpc_key.limits_expire_at = sea_orm::Set(None);
+
+    let rpc_key = rpc_key
+        .update(&db_conn)
+        .await
+        .context("failed removing rpc key limits")?;
+
+    save_audit_log(
+        &db_conn,
+        Some(admin.id),
+        "rpc_key.limits.remove",
+        "rpc_key",
+        Some(rpc_key.id),
+        Some(before),
+        Some(rpc_key_limits_json(&rpc_key)),
+    )
+    .await?;
+
+    clear_rpc_key_caches(&app, &rpc_key).await?;
+
+    Ok(Json(rpc_key_limits_json(&rpc_key)).into_response())
+}
+
+/// the JSON input to the `admin_rpc_key_methods_post` handler.
+#[derive(Debug, Deserialize)]
+pub struct RpcKeyMethods {
+    /// exceptions to the config's `denied_methods`. A trailing `*` matches any method with that prefix.
+    /// An empty list removes all exceptions.
+    allowed_methods: Vec<String>,
+}
+
+/// `POST /admin/keys/:rpc_key_id/methods` -- Use a bearer token to let an rpc key use denied methods.
+///
+/// This replaces any exceptions that the key already had.
+#[debug_handler]
+pub async fn admin_rpc_key_methods_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    Json(payload): Json<RpcKeyMethods>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    if payload
+        .allowed_methods
+        .iter()
+        .any(|x| x.is_empty() || x.contains(','))
+    {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::BAD_REQUEST,
+            "allowed_methods must not be empty or contain commas".to_string(),
+            None,
+        ));
+    }
+
+    let db_conn = app
+        .db_conn()
+        .context("setting rpc key methods requires a db")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(&db_conn)
+        .await
+        .context("failed loading rpc key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    info!(
+        "admin #{} setting allowed methods on rpc key #{}: {:?}",
+        admin.id, rpc_key_id, payload
+    );
+
+    let before = json!({ "rpc_key_id": rpc_key.id, "allowed_methods": rpc_key.allowed_methods });
+
+    let allowed_methods = if payload.allowed_methods.is_empty() {
+        None
+    } else {
+        Some(payload.allowed_methods.join(","))
+    };
+
+    let mut rpc_key = rpc_key.into_active_model();
+
+    rpc_key.allowed_methods = sea_orm::Set(allowed_methods);
+
+    let rpc_key = rpc_key
+        .update(&db_conn)
+        .await
+        .context("failed saving rpc key methods")?;
+
+    let after = json!({ "rpc_key_id": rpc_key.id, "allowed_methods": rpc_key.allowed_methods });
+
+    save_audit_log(
+        &db_conn,
+        Some(admin.id),
+        "rpc_key.methods.set",
+        "rpc_key",
+        Some(rpc_key.id),
+        Some(before),
+        Some(after.clone()),
+    )
+    .await?;
+
+    clear_rpc_key_caches(&app, &rpc_key).await?;
+
+    Ok(Json(after).into_response())
+}
+
+/// `POST /admin/keys/:rpc_key_id/kick` -- Use a bearer token to close every websocket authorized by an rpc key.
+///
+/// The key's cached authorization is cleared too, so changes to the key (like deactivating it) apply to new connections.
+#[debug_handler]
+pub async fn admin_rpc_key_kick_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("kicking rpc keys requires a db")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(&db_conn)
+        .await
+        .context("failed loading rpc key")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    info!(
+        "admin #{} closing websockets for rpc key #{}",
+        admin.id, rpc_key_id
+    );
+
+    let kicked = json!({ "rpc_key_id": rpc_key.id });
+
+    save_audit_log(
+        &db_conn,
+        Some(admin.id),
+        "rpc_key.kick",
+        "rpc_key",
+        Some(rpc_key.id),
+        None,
+        Some(kicked.clone()),
+    )
+    .await?;
+
+    clear_rpc_key_caches(&app, &rpc_key).await?;
+
+    kick_websockets(&app.websocket_kick_sender, rpc_key.id)?;
+
+    Ok(Json(kicked).into_response())
+}
+
+/// `POST /admin/drain` -- Use a bearer token to start a graceful shutdown.
+///
+/// New connections are refused and `/health` and `/ready` fail. Open requests and websockets get `drain_timeout_seconds` to finish and then the proxy saves its stats and exits.
+#[debug_handler]
+pub async fn admin_drain_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("draining requires a db")?;
+
+    let already_draining = app.is_draining();
+
+    // save the audit log first. draining can't be undone
+    if !already_draining {
+        info!("admin #{} started draining", admin.id);
+
+        save_audit_log(
+            &db_conn,
+            Some(admin.id),
+            "app.drain",
+            "app",
+            None,
+            None,
+            None,
+        )
+        .await?;
+    }
+
+    app.start_draining();
+
+    let response = json!({
+        "draining": true,
+        "already_draining": already_draining,
+        "open_websockets": app.open_websockets(),
+        "drain_timeout_seconds": app.config.drain_timeout_seconds,
+    });
+
+    Ok(Json(response).into_response())
+}
+
+/// `GET /admin/audit_log` -- Use a bearer token to see who changed what.
+///
+/// Filter with `?user_id=$x`, `?target_type=$x`, and `?target_id=$x`.
+/// Paginate with `?page=$x` and `?query_start=$x`.
+#[debug_handler]
+pub async fn admin_audit_log_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> FrontendResult {
+    let (_admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let query_start = get_query_start_from_params(&params)?;
+    let page = get_page_from_params(&params)?;
+
+    // TODO: page size from config
+    let page_size = 1_000;
+
+    let mut response = HashMap::new();
+
+    response.insert("page", json!(page));
+    response.insert("page_size", json!(page_size));
+    response.insert("query_start", json!(query_start.timestamp() as u64));
+
+    let db_replica = app.db_replica().context("getting db for audit log")?;
+
+    let mut q = audit_log::Entity::find()
+        .filter(audit_log::Column::Timestamp.gte(query_start))
+        .order_by_asc(audit_log::Column::Timestamp);
+
+    if let Some(user_id) = params.get("user_id") {
+        let user_id: u64 = user_id.parse().context("parsing user_id param")?;
+
+        q = q.filter(audit_log::Column::UserId.eq(user_id));
+    }
+
+    if let Some(target_type) = params.get("target_type") {
+        q = q.filter(audit_log::Column::TargetType.eq(target_type.as_str()));
+    }
+
+    if let Some(target_id) = params.get("target_id") {
+        let target_id: u64 = target_id.parse().context("parsing target_id param")?;
+
+        q = q.filter(audit_log::Column::TargetId.eq(target_id));
+    }
+
+    // query the database for number of items and pages
+    let pages_result = q
+        .clone()
+        .paginate(db_replica.conn(), page_size)
+        .num_items_and_pages()
+        .await?;
+
+    response.insert("num_items", pages_result.number_of_items.into());
+    response.insert("num_pages", pages_result.number_of_pages.into());
+
+    let audit_logs = q
+        .paginate(db_replica.conn(), page_size)
+        .fetch_page(page)
+        .await?;
+
+    response.insert("audit_logs", json!(audit_logs));
+
+    Ok(Json(response).into_response())
+}
+
+/// the JSON input to the `admin_feature_flag_post` handler.
+#[derive(Debug, Deserialize)]
+pub struct FeatureFlagPost {
+    enabled: bool,
+}
+
+/// `GET /admin/features` -- Use a bearer token to see the feature flags for this chain.
+#[debug_handler]
+pub async fn admin_feature_flags_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (_admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    Ok(Json(json!({
+        "chain_id": app.config.chain_id,
+        "defaults": app.feature_flags.defaults(),
+        "overrides": app.feature_flags.overrides(),
+        "features": app.feature_flags.all(),
+    }))
+    .into_response())
+}
+
+/// `POST /admin/features/:name` -- Use a bearer token to turn a feature on or off for every proxy on this chain.
+#[debug_handler]
+pub async fn admin_feature_flag_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+    Json(payload): Json<FeatureFlagPost>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    set_feature_flag(&app, admin.id, &name, Some(payload.enabled)).await
+}
+
+/// `DELETE /admin/features/:name` -- Use a bearer token to remove a feature's override.
+///
+/// The feature goes back to the value in the config.
+#[debug_handler]
+pub async fn admin_feature_flag_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    set_feature_flag(&app, admin.id, &name, None).await
+}
+
+async fn set_feature_flag(
+    app: &Web3ProxyApp,
+    admin_id: u64,
+    name: &str,
+    enabled: Option<bool>,
+) -> FrontendResult {
+    let before = app.feature_flags.is_enabled(name);
+
+    app.feature_flags
+        .set(name, enabled)
+        .await
+        .context("failed saving feature flag")?;
+
+    let after = app.feature_flags.is_enabled(name);
+
+    info!(
+        "admin #{} set feature {} on chain {} to {:?}",
+        admin_id, name, app.config.chain_id, enabled
+    );
+
+    let db_conn = app
+        .db_conn()
+        .context("saving the audit log requires a db")?;
+
+    save_audit_log(
+        &db_conn,
+        Some(admin_id),
+        if enabled.is_some() {
+            "feature_flag.set"
+        } else {
+            "feature_flag.reset"
+        },
+        "feature_flag",
+        None,
+        Some(json!({ "chain_id": app.config.chain_id, "name": name, "enabled": before })),
+        Some(json!({ "chain_id": app.config.chain_id, "name": name, "enabled": after })),
+    )
+    .await?;
+
+    Ok(Json(json!({
+        "chain_id": app.config.chain_id,
+        "name": name,
+        "enabled": after,
+        "override": enabled,
+    }))
+    .into_response())
+}
+
+/// the JSON input to the `admin_rpc_post` handler. Anything left as None is unchanged.
+#[derive(Debug, Deserialize)]
+pub struct AdminRpcPost {
+    disabled: Option<bool>,
+    soft_limit: Option<u32>,
+    /// 0 goes back to using soft_limit
+    subscription_soft_limit: Option<u32>,
+    tier: Option<u64>,
+}
+
+/// `GET /admin/rpcs` -- Use a bearer token to see every backend rpc and its live stats.
+///
+/// Urls are not included since they often have api keys in them.
+#[debug_handler]
+pub async fn admin_rpcs_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (_admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let mut response = HashMap::new();
+
+    for (group, rpcs) in app.rpc_groups() {
+        let configs = rpcs.configs().await?;
+        let conns = rpcs.conns.load();
+
+        let mut names: Vec<_> = configs.keys().collect();
+        names.sort();
+
+        let group_json: Vec<_> = names
+            .into_iter()
+            .map(|name| {
+                let mut x = rpc_json(group, name, &configs[name]);
+
+                // live stats. None if disabled or not connected
+                x["connection"] = json!(conns.get(name).map(|x| x.as_ref()));
+
+                x
+            })
+            .collect();
+
+        response.insert(group, group_json);
+    }
+
+    Ok(Json(response).into_response())
+}
+
+/// `GET /admin/costs` -- Use a bearer token to see what requests each backend rpc has been sent and what they cost.
+///
+/// Prices come from the pricing table in each rpc's config. Rpcs without one count every request as 1 compute unit and $0.
+/// Counts start when the rpc connects. The most expensive rpcs and methods are first.
+#[debug_handler]
+pub async fn admin_costs_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (_admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let rpcs = app.upstream_costs().await;
+
+    let mut total = Cost::default();
+
+    for x in rpcs.iter() {
+        total.add(&x.total);
+    }
+
+    let response = json!({
+        "total": total,
+        "rpcs": rpcs,
+    });
+
+    Ok(Json(response).into_response())
+}
+
+/// `GET /admin/latency_probe` -- Use a bearer token to time the same requests on every connected backend rpc.
+///
+/// Each backend gets `eth_blockNumber`, `eth_getBlockByNumber`, and a 2 block `eth_getLogs`. All of the backends are probed at once.
+/// The fastest backends are first. Backends with a failed probe are last.
+#[debug_handler]
+pub async fn admin_latency_probe_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (_admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    let head_block_num = app
+        .balanced_rpcs
+        .head_block_num()
+        .ok_or(FrontendErrorResponse::NoServersSynced)?;
+
+    let authorization = Arc::new(InternalAuthorization::internal(app.db_conn())?);
+
+    let rpcs: Vec<_> = app
+        .rpc_groups()
+        .into_iter()
+        .flat_map(|(group, rpcs)| {
+            rpcs.conns
+                .load()
+                .values()
+                .map(|rpc| (group, rpc.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let futures = rpcs
+        .iter()
+        .map(|(group, rpc)| rpc.latency_probe(&authorization, group, head_block_num));
+
+    let mut probes = join_all(futures).await;
+
+    sort_probes(&mut probes);
+
+    let response = json!({
+        "head_block_num": head_block_num,
+        "probes": probes,
+    });
+
+    Ok(Json(response).into_response())
+}
+
+/// `PUT /admin/rpcs/:group/:name` -- Use a bearer token to add a backend rpc or replace its config.
+///
+/// The body has the same fields as an rpc in the config file. The group is "balanced", "private", or "archive".
+/// Changes are not saved to the config file. Reloading the config file undoes them.
+#[debug_handler]
+pub async fn admin_rpc_put(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path((group, name)): Path<(String, String)>,
+    Json(payload): Json<Web3ConnectionConfig>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    info!(
+        "admin #{} setting {} rpc {}: {}",
+        admin.id,
+        group,
+        name,
+        rpc_json(&group, &name, &payload)
+    );
+
+    update_rpc(&app, admin.id, "rpc.set", true, &group, &name, |_| {
+        Ok(payload)
+    })
+    .await
+}
+
+/// `POST /admin/rpcs/:group/:name` -- Use a bearer token to disable or enable a backend rpc or to change its soft limit or tier.
+///
+/// Soft limit and tier changes apply without reconnecting.
+/// Changes are not saved to the config file. Reloading the config file undoes them.
+#[debug_handler]
+pub async fn admin_rpc_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path((group, name)): Path<(String, String)>,
+    Json(payload): Json<AdminRpcPost>,
+) -> FrontendResult {
+    let (admin, _semaphore) = app.admin_bearer_is_authorized(bearer).await?;
+
+    info!(
+        "admin #{} updating {} rpc {}: {:?}",
+        admin.id, group, name, payload
+    );
+
+    update_rpc(
+        &app,
+        admin.id,
+        "rpc.update",
+        false,
+        &group,
+        &name,
+        |config| {
+            let mut config = config.cloned().context("rpc not found")?;
+
+            if let Some(x) = payload.disabled {
+                config.disabled = x;
+            }
+            if let Some(x) = payload.soft_limit {
+                config.soft_limit = x;
+            }
+            if let Some(x) = payload.subscription_soft_limit {
+                config.subscription_soft_limit = Some(x).filter(|x| *x > 0);
+            }
+            if let Some(x) = payload.tier {
+                config.tier = x;
+            }
+
+            Ok(config)
+        },
+    )
+    .await
+}
+
+async fn update_rpc<F>(
+    app: &Web3ProxyApp,
+    admin_id: u64,
+    action: &str,
+    // false if the rpc must already exist
+    create: bool,
+    group: &str,
+    name: &str,
+    f: F,
+) -> FrontendResult
+where
+    F: FnOnce(Option<&Web3ConnectionConfig>) -> anyhow::Result<Web3ConnectionConfig>,
+{
+    let rpcs = app
+        .rpc_group(group)
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    let configs = rpcs.configs().await?;
+
+    let before = configs.get(name).map(|x| rpc_json(group, name, x));
+
+    if before.is_none() && !create {
+        return Err(FrontendErrorResponse::NotFound);
+    }
+
+    let diff: ConfigDiff = rpcs.update_config(name, f).await.map_err(|err| {
+        FrontendErrorResponse::StatusCode(StatusCode::BAD_REQUEST, format!("{:#}", err), None)
+    })?;
+
+    let configs = rpcs.configs().await?;
+
+    let after = rpc_json(group, name, &configs[name]);
+
+    let db_conn = app
+        .db_conn()
+        .context("saving the audit log requires a db")?;
+
+    save_audit_log(
+        &db_conn,
+        Some(admin_id),
+        action,
+        "rpc",
+        None,
+        before,
+        Some(after.clone()),
+    )
+    .await?;
+
+    Ok(Json(json!({
+        "rpc": after,
+        "diff": diff,
+    }))
+    .into_response())
+}
+
+/// the url is left out because it often has an api key in it
+fn rpc_json(group: &str, name: &str, config: &Web3ConnectionConfig) -> serde_json::Value {
+    json!({
+        "group": group,
+        "name": name,
+        "display_name": config.display_name,
+        "disabled": config.disabled,
+        "soft_limit": config.soft_limit,
+        "subscription_soft_limit": config.subscription_soft_limit,
+        "hard_limit": config.hard_limit,
+        "tier": config.tier,
+    })
+}
+
+/// the key's limits are cached. clear them so the new limits apply immediately
+async fn clear_rpc_key_caches(app: &Web3ProxyApp, rpc_key: &rpc_key::Model) -> anyhow::Result<()> {
+    let rpc_secret_key: RpcSecretKey = rpc_key.secret_key.into();
+    let rpc_secret_key: Ulid = rpc_secret_key.into();
+
+    app.rpc_secret_key_cache.invalidate(&rpc_secret_key).await;
+    app.rpc_key_id_cache.invalidate(&rpc_key.id).await;
+
+    // the concurrency semaphore is sized when it is created, so it needs to be cleared too
+    // TODO: the semaphore is per user, not per key. a custom limit on one key affects all of the user's keys
+    let user_id: NonZeroU64 = rpc_key
+        .user_id
+        .try_into()
+        .context("user ids should always be non-zero")?;
+
+    app.registered_user_semaphores.invalidate(&user_id).await;
+
+    Ok(())
+}
+
+fn rpc_key_limits_json(rpc_key: &rpc_key::Model) -> serde_json::Value {
+    json!({
+        "rpc_key_id": rpc_key.id,
+        "max_requests_per_period": rpc_key.max_requests_per_period,
+        "max_concurrent_requests": rpc_key.max_concurrent_requests,
+        "limits_expire_at": rpc_key.limits_expire_at.map(|x| x.timestamp()),
+    })
+}
@@ -1,7 +1,9 @@
 //! Utilities for authorization of logged in and anonymous users.
 
 use super::errors::FrontendErrorResponse;
-use crate::app::{AuthorizationChecks, Web3ProxyApp, APP_USER_AGENT};
+use crate::app::{parse_pinned_ips, AuthorizationChecks, Web3ProxyApp, APP_USER_AGENT};
+use crate::config::AnonymousAccess;
+use crate::ip_reputation::IpReputation;
 use crate::rpcs::connection::Web3Connection;
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
@@ -9,26 +11,90 @@ use axum::headers::authorization::Bearer;
 use axum::headers::{Header, Origin, Referer, UserAgent};
 use chrono::Utc;
 use deferred_rate_limiter::DeferredRateLimitResult;
-use entities::{login, rpc_key, user, user_tier};
-use ethers::types::Bytes;
+use entities::sea_orm_active_enums::Role;
+use entities::{admin, login, org, org_member, rpc_key, user, user_tier};
+use ethers::types::{Address, Bytes};
 use ethers::utils::keccak256;
 use futures::TryFutureExt;
 use hashbrown::HashMap;
-use http::HeaderValue;
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderValue, StatusCode};
 use ipnet::IpNet;
-use log::{error, warn};
+use log::{error, trace, warn};
 use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use parking_lot::Mutex;
-use redis_rate_limiter::redis::AsyncCommands;
+use redis_rate_limiter::redis::{self, AsyncCommands};
 use redis_rate_limiter::RedisRateLimitResult;
+use sha2::Sha256;
 use std::fmt::Display;
+use std::num::NonZeroU64;
 use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::{net::IpAddr, str::FromStr, sync::Arc};
+use subtle::ConstantTimeEq;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 use ulid::Ulid;
 use uuid::Uuid;
 
+/// header for clients that can't put their rpc key in the url path
+pub static RPC_KEY_HEADER: &str = "x-api-key";
+
+/// query parameter for clients that can't put their rpc key in the url path
+pub static RPC_KEY_QUERY_PARAM: &str = "key";
+
+/// header that marks requests from our own tools. the value must match `internal_request_secret`
+pub static INTERNAL_REQUEST_HEADER: &str = "x-w3p-internal";
+
+/// how many seconds a signed request is valid for
+pub const SIGNATURE_MAX_AGE_SECONDS: i64 = 30;
+
+/// failed login signatures allowed (per ip and per address) before logins are locked out
+const LOGIN_FAILURES_BEFORE_LOCKOUT: u64 = 3;
+
+/// the first lockout. each failure after that doubles it
+const LOGIN_LOCKOUT_BASE_SECONDS: u64 = 30;
+
+/// lockouts never last longer than this
+const LOGIN_LOCKOUT_MAX_SECONDS: u64 = 86_400;
+
+/// failures are forgotten after this long without another failure
+const LOGIN_FAILURES_TTL_SECONDS: usize = 86_400;
+
+/// How long to lock out logins after this many failed signatures.
+fn login_lockout_seconds(failures: u64) -> Option<u64> {
+    let extra_failures = failures.checked_sub(LOGIN_FAILURES_BEFORE_LOCKOUT)?;
+
+    let seconds = 2u64
+        .checked_pow(extra_failures.min(32) as u32)
+        .and_then(|x| x.checked_mul(LOGIN_LOCKOUT_BASE_SECONDS))
+        .unwrap_or(LOGIN_LOCKOUT_MAX_SECONDS);
+
+    Some(seconds.min(LOGIN_LOCKOUT_MAX_SECONDS))
+}
+
+/// Logins are throttled by ip and by the address that is logging in.
+fn login_subjects(ip: IpAddr, address: Option<Address>) -> Vec<String> {
+    let mut subjects = vec![ip.to_string()];
+
+    if let Some(address) = address {
+        subjects.push(format!("{:?}", address));
+    }
+
+    subjects
+}
+
+/// Bad signatures lock out the ip and the ip's attempts at that address.
+/// The address alone is never locked out. Otherwise anyone could lock a user out of their own account.
+fn lockout_subjects(ip: IpAddr, address: Option<Address>) -> Vec<String> {
+    let mut subjects = vec![ip.to_string()];
+
+    if let Some(address) = address {
+        subjects.push(format!("{}:{:?}", ip, address));
+    }
+
+    subjects
+}
+
 /// This lets us use UUID and ULID while we transition to only ULIDs
 /// TODO: include the key's description.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
@@ -37,10 +103,43 @@ pub enum RpcSecretKey {
     Uuid(Uuid),
 }
 
+/// How rpc keys are shown to users. All formats are always accepted.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RpcKeyFormat {
+    #[default]
+    Ulid,
+    Uuid,
+}
+
+/// Whose concurrency limit a request counts against.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ConcurrencyKey {
+    Ip(IpAddr),
+    User(NonZeroU64),
+}
+
+/// Held while a request runs. Dropping it lets the client's next request start.
+#[derive(Debug)]
+pub struct RequestPermit {
+    _client: OwnedSemaphorePermit,
+    /// transactions in the priority lane also hold one of the lane's `max_concurrent_txs` permits
+    _priority: Option<OwnedSemaphorePermit>,
+}
+
+impl From<OwnedSemaphorePermit> for RequestPermit {
+    fn from(client: OwnedSemaphorePermit) -> Self {
+        Self {
+            _client: client,
+            _priority: None,
+        }
+    }
+}
+
 /// TODO: should this have IpAddr and Origin or AuthorizationChecks?
 #[derive(Debug)]
 pub enum RateLimitResult {
-    Allowed(Authorization, Option<OwnedSemaphorePermit>),
+    Allowed(Authorization, Option<RequestPermit>),
     RateLimited(
         Authorization,
         /// when their rate limit resets and they can try more requests
@@ -56,6 +155,14 @@ pub enum AuthorizationType {
     Frontend,
 }
 
+/// How the request reached us. Websocket users are tracked separately from http users.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum RequestTransport {
+    #[default]
+    Http,
+    Websocket,
+}
+
 /// TODO: include the authorization checks in this?
 #[derive(Clone, Debug)]
 pub struct Authorization {
@@ -67,12 +174,15 @@ pub struct Authorization {
     pub referer: Option<Referer>,
     pub user_agent: Option<UserAgent>,
     pub authorization_type: AuthorizationType,
+    pub transport: RequestTransport,
 }
 
 #[derive(Debug)]
 pub struct RequestMetadata {
     pub start_datetime: chrono::DateTime<Utc>,
     pub start_instant: tokio::time::Instant,
+    /// the client has given up by this time. None for requests without a client waiting on them
+    pub deadline: Option<Instant>,
     // TODO: better name for this
     pub period_seconds: u64,
     pub request_bytes: u64,
@@ -85,6 +195,8 @@ pub struct RequestMetadata {
     pub error_response: AtomicBool,
     pub response_bytes: AtomicU64,
     pub response_millis: AtomicU64,
+    /// how many requests this counts as. see the config's `method_costs`
+    pub compute_units: AtomicU64,
 }
 
 impl RequestMetadata {
@@ -95,6 +207,7 @@ impl RequestMetadata {
         let new = Self {
             start_instant: Instant::now(),
             start_datetime: Utc::now(),
+            deadline: None,
             period_seconds,
             request_bytes,
             archive_request: false.into(),
@@ -103,16 +216,42 @@ impl RequestMetadata {
             error_response: false.into(),
             response_bytes: 0.into(),
             response_millis: 0.into(),
+            compute_units: 1.into(),
         };
 
         Ok(new)
     }
+
+    /// Stop working on the request once it has taken `max_time`.
+    pub fn with_deadline(mut self, max_time: Duration) -> Self {
+        self.deadline = Some(self.start_instant + max_time);
+        self
+    }
+
+    /// How much of the request's budget is left. None if there is no deadline.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|x| x.saturating_duration_since(Instant::now()))
+    }
 }
 
 impl RpcSecretKey {
     pub fn new() -> Self {
         Ulid::new().into()
     }
+
+    /// Format the key for users. A vanity prefix makes leaked keys easy to identify.
+    pub fn to_formatted_string(&self, format: RpcKeyFormat, prefix: Option<&str>) -> String {
+        let key = match format {
+            RpcKeyFormat::Ulid => Ulid::from(*self).to_string(),
+            RpcKeyFormat::Uuid => Uuid::from(*self).to_string(),
+        };
+
+        match prefix {
+            None => key,
+            Some(prefix) => format!("{}{}", prefix, key),
+        }
+    }
 }
 
 impl Default for RpcSecretKey {
@@ -134,6 +273,9 @@ impl FromStr for RpcSecretKey {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // strip any vanity prefix. ULIDs and UUIDs never contain '_'
+        let s = s.rsplit_once('_').map(|(_, key)| key).unwrap_or(s);
+
         if let Ok(ulid) = s.parse::<Ulid>() {
             Ok(ulid.into())
         } else if let Ok(uuid) = s.parse::<Uuid>() {
@@ -243,12 +385,9 @@ impl Authorization {
         authorization_type: AuthorizationType,
     ) -> anyhow::Result<Self> {
         // check ip
-        match &authorization_checks.allowed_ips {
-            None => {}
-            Some(allowed_ips) => {
-                if !allowed_ips.iter().any(|x| x.contains(&ip)) {
-                    return Err(anyhow::anyhow!("IP ({}) is not allowed!", ip));
-                }
+        if let Some(allowed_ips) = &authorization_checks.allowed_ips {
+            if !allowed_ips.iter().any(|x| x.contains(&ip)) {
+                return Err(anyhow::anyhow!("IP ({}) is not allowed!", ip));
             }
         }
 
@@ -299,44 +438,190 @@ impl Authorization {
             referer,
             user_agent,
             authorization_type,
+            transport: RequestTransport::Http,
         })
     }
+
+    /// Mark requests from this authorization as coming over a websocket.
+    pub fn websocket(mut self) -> Self {
+        self.transport = RequestTransport::Websocket;
+        self
+    }
+
+    /// The user tier's title. "anonymous" for requests without an rpc key and "internal" for our own requests.
+    pub fn user_tier(&self) -> &str {
+        match self.authorization_type {
+            AuthorizationType::Internal => "internal",
+            AuthorizationType::Frontend => self
+                .checks
+                .user_tier_title
+                .as_deref()
+                .unwrap_or("anonymous"),
+        }
+    }
+}
+
+/// Some SDKs can't customize the url path, but they can add headers or query params.
+/// The header is checked before the query param.
+pub fn rpc_key_from_request(
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<Option<RpcSecretKey>, FrontendErrorResponse> {
+    let rpc_key = if let Some(rpc_key) = headers.get(RPC_KEY_HEADER) {
+        rpc_key.to_str()?
+    } else if let Some(rpc_key) = params.get(RPC_KEY_QUERY_PARAM) {
+        rpc_key.as_str()
+    } else {
+        return Ok(None);
+    };
+
+    let rpc_key = rpc_key.parse()?;
+
+    Ok(Some(rpc_key))
+}
+
+/// Authorize a JSON-RPC request. The HTTP and websocket entrypoints both use this so that their auth and rate limits match.
+/// A key in the url path is checked first, then the `X-API-Key` header or `key` query param, then the internal request header, and then the ip.
+#[allow(clippy::too_many_arguments)]
+pub async fn rpc_is_authorized(
+    app: &Arc<Web3ProxyApp>,
+    ip: IpAddr,
+    path_rpc_key: Option<String>,
+    origin: Option<Origin>,
+    referer: Option<Referer>,
+    user_agent: Option<UserAgent>,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    priority: bool,
+) -> Result<(Authorization, Option<RequestPermit>), FrontendErrorResponse> {
+    let rpc_key = match path_rpc_key {
+        Some(rpc_key) => Some(rpc_key.parse()?),
+        None => rpc_key_from_request(headers, params)?,
+    };
+
+    match rpc_key {
+        Some(rpc_key) => {
+            key_is_authorized(app, rpc_key, ip, origin, referer, user_agent, priority).await
+        }
+        None => match internal_is_authorized(app, headers, ip)? {
+            Some(authorization) => Ok((authorization, None)),
+            None => ip_is_authorized(app, ip, origin, priority).await,
+        },
+    }
+}
+
+/// Requests from our own tools skip rate limits and customer stats.
+/// Returns None if this is not an internal request.
+pub fn internal_is_authorized(
+    app: &Web3ProxyApp,
+    headers: &HeaderMap,
+    ip: IpAddr,
+) -> Result<Option<Authorization>, FrontendErrorResponse> {
+    match (
+        headers.get(INTERNAL_REQUEST_HEADER),
+        app.config.internal_request_secret.as_ref(),
+    ) {
+        (Some(header), Some(secret)) => {
+            // constant time so that the secret can't be guessed a byte at a time
+            if !bool::from(header.as_bytes().ct_eq(secret.as_bytes())) {
+                // TODO: emit a stat
+                return Err(FrontendErrorResponse::AccessDenied);
+            }
+        }
+        _ => return Ok(None),
+    }
+
+    trace!("internal request from {}", ip);
+
+    let authorization = Authorization::try_new(
+        AuthorizationChecks::default(),
+        app.db_conn(),
+        ip,
+        None,
+        None,
+        None,
+        AuthorizationType::Internal,
+    )?;
+
+    Ok(Some(authorization))
 }
 
-/// rate limit logins only by ip.
+/// rate limit logins by ip and by the address logging in.
 /// we want all origins and referers and user agents to count together
+/// ips and addresses with too many bad signatures are locked out.
 pub async fn login_is_authorized(
     app: &Web3ProxyApp,
     ip: IpAddr,
+    address: Option<Address>,
 ) -> Result<Authorization, FrontendErrorResponse> {
-    let authorization = match app.rate_limit_login(ip).await? {
+    let authorization = match app.rate_limit_login(ip, address).await? {
         RateLimitResult::Allowed(authorization, None) => authorization,
         RateLimitResult::RateLimited(authorization, retry_at) => {
-            return Err(FrontendErrorResponse::RateLimited(authorization, retry_at));
+            return Err(FrontendErrorResponse::RateLimited(
+                Box::new(authorization),
+                retry_at,
+            ));
         }
         // TODO: don't panic. give the user an error
         x => unimplemented!("rate_limit_login shouldn't ever see these: {:?}", x),
     };
 
+    if let Some(retry_at) = app.login_locked_until(ip, address).await {
+        return Err(FrontendErrorResponse::RateLimited(
+            Box::new(authorization),
+            Some(retry_at),
+        ));
+    }
+
     Ok(authorization)
 }
 
 /// semaphore won't ever be None, but its easier if key auth and ip auth work the same way
+/// `priority` requests skip the concurrency limit while `max_concurrent_txs` has room
 pub async fn ip_is_authorized(
     app: &Arc<Web3ProxyApp>,
     ip: IpAddr,
     origin: Option<Origin>,
-) -> Result<(Authorization, Option<OwnedSemaphorePermit>), FrontendErrorResponse> {
+    priority: bool,
+) -> Result<(Authorization, Option<RequestPermit>), FrontendErrorResponse> {
+    if app.config.anonymous_access == AnonymousAccess::KeyRequired {
+        return Err(FrontendErrorResponse::KeyRequired);
+    }
+
+    // flagged ips get reduced limits or are rejected
+    let ip_reputation = app.ip_reputation(ip).await;
+
+    if ip_reputation == IpReputation::Flagged {
+        let flagged_requests_per_period = app
+            .ip_reputation
+            .as_ref()
+            .and_then(|x| x.flagged_requests_per_period);
+
+        if flagged_requests_per_period.is_none() {
+            // TODO: emit a stat
+            return Err(FrontendErrorResponse::AccessDenied);
+        }
+    }
+
     // TODO: i think we could write an `impl From` for this
     // TODO: move this to an AuthorizedUser extrator
     let (authorization, semaphore) = match app
-        .rate_limit_by_ip(&app.config.allowed_origin_requests_per_period, ip, origin)
+        .rate_limit_by_ip(
+            &app.config.allowed_origin_requests_per_period,
+            ip,
+            origin,
+            ip_reputation,
+            priority,
+        )
         .await?
     {
         RateLimitResult::Allowed(authorization, semaphore) => (authorization, semaphore),
         RateLimitResult::RateLimited(authorization, retry_at) => {
             // TODO: in the background, emit a stat (maybe simplest to use a channel?)
-            return Err(FrontendErrorResponse::RateLimited(authorization, retry_at));
+            return Err(FrontendErrorResponse::RateLimited(
+                Box::new(authorization),
+                retry_at,
+            ));
         }
         // TODO: don't panic. give the user an error
         x => unimplemented!("rate_limit_by_ip shouldn't ever see these: {:?}", x),
@@ -362,7 +647,7 @@ pub async fn ip_is_authorized(
                 let recent_ip_key = format!("recent_users:ip:{}", app.config.chain_id);
 
                 redis_conn
-                    .zadd(recent_ip_key, hashed_ip.to_string(), now)
+                    .zadd::<_, _, _, ()>(recent_ip_key, hashed_ip.to_string(), now)
                     .await?;
             };
 
@@ -388,16 +673,20 @@ pub async fn key_is_authorized(
     origin: Option<Origin>,
     referer: Option<Referer>,
     user_agent: Option<UserAgent>,
-) -> Result<(Authorization, Option<OwnedSemaphorePermit>), FrontendErrorResponse> {
+    priority: bool,
+) -> Result<(Authorization, Option<RequestPermit>), FrontendErrorResponse> {
     // check the rate limits. error if over the limit
     // TODO: i think this should be in an "impl From" or "impl Into"
     let (authorization, semaphore) = match app
-        .rate_limit_by_rpc_key(ip, origin, referer, rpc_key, user_agent)
+        .rate_limit_by_rpc_key(ip, origin, referer, rpc_key, user_agent, priority)
         .await?
     {
         RateLimitResult::Allowed(authorization, semaphore) => (authorization, semaphore),
         RateLimitResult::RateLimited(authorization, retry_at) => {
-            return Err(FrontendErrorResponse::RateLimited(authorization, retry_at));
+            return Err(FrontendErrorResponse::RateLimited(
+                Box::new(authorization),
+                retry_at,
+            ));
         }
         RateLimitResult::UnknownKey => return Err(FrontendErrorResponse::UnknownKey),
     };
@@ -424,7 +713,7 @@ pub async fn key_is_authorized(
                 let recent_user_id_key = format!("recent_users:id:{}", app.config.chain_id);
 
                 redis_conn
-                    .zadd(recent_user_id_key, hashed_user_id.to_string(), now)
+                    .zadd::<_, _, _, ()>(recent_user_id_key, hashed_user_id.to_string(), now)
                     .await?;
             }
 
@@ -442,10 +731,173 @@ pub async fn key_is_authorized(
     Ok((authorization, semaphore))
 }
 
+/// Signed requests outside of this window are too old or too far in the future.
+fn signature_is_fresh(timestamp: i64, now: i64) -> bool {
+    timestamp.abs_diff(now) <= SIGNATURE_MAX_AGE_SECONDS as u64
+}
+
+/// `signature` is a hex encoded HMAC-SHA256 of `"{timestamp}.{body}"` keyed with `formatted_key`.
+fn signature_is_valid(formatted_key: &str, timestamp: i64, signature: &str, body: &[u8]) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(formatted_key.as_bytes())
+        .expect("hmac can take a key of any size");
+
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    // verify_slice is constant time
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Clients that can't send their rpc key in the url can sign the request with it instead.
+/// The signature is a hex encoded HMAC-SHA256 of `"{timestamp}.{body}"` keyed with the rpc key exactly as the user was shown it.
+/// With redis, each signature is only accepted once. Without redis, a signature can be replayed until it is `SIGNATURE_MAX_AGE_SECONDS` old.
+#[allow(clippy::too_many_arguments)]
+pub async fn signature_is_authorized(
+    app: &Arc<Web3ProxyApp>,
+    rpc_key_id: u64,
+    timestamp: i64,
+    signature: &str,
+    body: &[u8],
+    ip: IpAddr,
+    origin: Option<Origin>,
+    referer: Option<Referer>,
+    user_agent: Option<UserAgent>,
+    priority: bool,
+) -> Result<(Authorization, Option<RequestPermit>), FrontendErrorResponse> {
+    // old signatures are rejected to limit replay attacks
+    if !signature_is_fresh(timestamp, virtual_clock::utc_now().timestamp()) {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::UNAUTHORIZED,
+            "signature timestamp is too old or too far in the future".to_string(),
+            None,
+        ));
+    }
+
+    let rpc_key = app
+        .rpc_secret_key_by_id(rpc_key_id)
+        .await?
+        .ok_or(FrontendErrorResponse::UnknownKey)?;
+
+    // users only ever see the formatted key, so that is what they sign with
+    let formatted_key = rpc_key.to_formatted_string(
+        app.config.rpc_key_format,
+        app.config.rpc_key_prefix.as_deref(),
+    );
+
+    if !signature_is_valid(&formatted_key, timestamp, signature, body) {
+        return Err(FrontendErrorResponse::AccessDenied);
+    }
+
+    if let Some(mut redis_conn) = app.redis_conn().await? {
+        // a timestamp can be up to SIGNATURE_MAX_AGE_SECONDS in the future, so remember the signature for twice that
+        let redis_key = format!("signed_request:{}:{}", rpc_key_id, signature.to_lowercase());
+
+        let first_use: Option<String> = redis::cmd("SET")
+            .arg(redis_key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(SIGNATURE_MAX_AGE_SECONDS * 2)
+            .query_async(&mut redis_conn)
+            .await
+            .context("saving the request signature")?;
+
+        if first_use.is_none() {
+            return Err(FrontendErrorResponse::StatusCode(
+                StatusCode::UNAUTHORIZED,
+                "signature was already used".to_string(),
+                None,
+            ));
+        }
+    }
+
+    key_is_authorized(app, rpc_key, ip, origin, referer, user_agent, priority).await
+}
+
 impl Web3ProxyApp {
+    /// Transactions skip the ip and user semaphores while `max_concurrent_txs` has room.
+    /// Each client can still only have `max_concurrent` transactions in the lane.
+    async fn priority_permit(
+        &self,
+        priority: bool,
+        key: ConcurrencyKey,
+        max_concurrent: usize,
+    ) -> Option<RequestPermit> {
+        if !priority {
+            return None;
+        }
+
+        let priority = self.tx_semaphore.clone().try_acquire_owned().ok()?;
+
+        let client = self
+            .tx_client_semaphores
+            .get_with(key, async move { Arc::new(Semaphore::new(max_concurrent)) })
+            .await
+            .try_acquire_owned()
+            .ok()?;
+
+        Some(RequestPermit {
+            _client: client,
+            _priority: Some(priority),
+        })
+    }
+
+    /// More permits from the rpc key's or ip's semaphore so that a batch can run more than one request at once.
+    /// The batch's request already holds one permit. Only permits that are free right now are taken so that batches never wait on each other.
+    /// None if no semaphore limits this authorization.
+    pub fn batch_permits(
+        &self,
+        authorization: &Authorization,
+        wanted: usize,
+    ) -> Option<Vec<OwnedSemaphorePermit>> {
+        let semaphore = match authorization.checks.rpc_key_id {
+            Some(_) => {
+                authorization.checks.max_concurrent_requests?;
+
+                let user_id = NonZeroU64::new(authorization.checks.user_id)?;
+
+                self.registered_user_semaphores.get(&user_id)
+            }
+            None => {
+                self.config.public_max_concurrent_requests?;
+
+                self.ip_semaphores.get(&authorization.ip)
+            }
+        };
+
+        // the semaphore was evicted. the batch runs one request at a time
+        let semaphore = match semaphore {
+            Some(x) => x,
+            None => return Some(vec![]),
+        };
+
+        Some(
+            (0..wanted)
+                .map_while(|_| semaphore.clone().try_acquire_owned().ok())
+                .collect(),
+        )
+    }
+
     /// Limit the number of concurrent requests from the given ip address.
-    pub async fn ip_semaphore(&self, ip: IpAddr) -> anyhow::Result<Option<OwnedSemaphorePermit>> {
+    pub async fn ip_semaphore(
+        &self,
+        ip: IpAddr,
+        priority: bool,
+    ) -> anyhow::Result<Option<RequestPermit>> {
         if let Some(max_concurrent_requests) = self.config.public_max_concurrent_requests {
+            if let Some(permit) = self
+                .priority_permit(priority, ConcurrencyKey::Ip(ip), max_concurrent_requests)
+                .await
+            {
+                return Ok(Some(permit));
+            }
+
             let semaphore = self
                 .ip_semaphores
                 .get_with(ip, async move {
@@ -462,7 +914,7 @@ impl Web3ProxyApp {
 
             let semaphore_permit = semaphore.acquire_owned().await?;
 
-            Ok(Some(semaphore_permit))
+            Ok(Some(semaphore_permit.into()))
         } else {
             Ok(None)
         }
@@ -472,13 +924,25 @@ impl Web3ProxyApp {
     pub async fn registered_user_semaphore(
         &self,
         authorization_checks: &AuthorizationChecks,
-    ) -> anyhow::Result<Option<OwnedSemaphorePermit>> {
+        priority: bool,
+    ) -> anyhow::Result<Option<RequestPermit>> {
         if let Some(max_concurrent_requests) = authorization_checks.max_concurrent_requests {
             let user_id = authorization_checks
                 .user_id
                 .try_into()
                 .context("user ids should always be non-zero")?;
 
+            if let Some(permit) = self
+                .priority_permit(
+                    priority,
+                    ConcurrencyKey::User(user_id),
+                    max_concurrent_requests as usize,
+                )
+                .await
+            {
+                return Ok(Some(permit));
+            }
+
             let semaphore = self
                 .registered_user_semaphores
                 .get_with(user_id, async move {
@@ -495,7 +959,7 @@ impl Web3ProxyApp {
 
             let semaphore_permit = semaphore.acquire_owned().await?;
 
-            Ok(Some(semaphore_permit))
+            Ok(Some(semaphore_permit.into()))
         } else {
             // unlimited requests allowed
             Ok(None)
@@ -529,9 +993,11 @@ impl Web3ProxyApp {
 
         let user_bearer_uuid: Uuid = user_bearer_token.into();
 
+        // logins expire 4 weeks after they are made
         let user = user::Entity::find()
             .left_join(login::Entity)
             .filter(login::Column::BearerToken.eq(user_bearer_uuid))
+            .filter(login::Column::ExpiresAt.gt(Utc::now()))
             .one(db_replica.conn())
             .await
             .context("fetching user from db by bearer token")?
@@ -540,7 +1006,103 @@ impl Web3ProxyApp {
         Ok((user, semaphore_permit))
     }
 
-    pub async fn rate_limit_login(&self, ip: IpAddr) -> anyhow::Result<RateLimitResult> {
+    /// Verify that the given bearer token belongs to an admin.
+    pub async fn admin_bearer_is_authorized(
+        &self,
+        bearer: Bearer,
+    ) -> Result<(user::Model, OwnedSemaphorePermit), FrontendErrorResponse> {
+        let (user, semaphore_permit) = self.bearer_is_authorized(bearer).await?;
+
+        let db_replica = self
+            .db_replica()
+            .context("checking if bearer token is an admin")?;
+
+        if admin::Entity::find()
+            .filter(admin::Column::UserId.eq(user.id))
+            .one(db_replica.conn())
+            .await
+            .context("fetching admin from db by user id")?
+            .is_none()
+        {
+            return Err(FrontendErrorResponse::AccessDenied);
+        }
+
+        Ok((user, semaphore_permit))
+    }
+
+    /// Find the bearer's role in an org. The org's own user is always an owner.
+    pub async fn org_role(
+        &self,
+        org: &org::Model,
+        user_id: u64,
+    ) -> Result<Option<Role>, FrontendErrorResponse> {
+        if org.user_id == user_id {
+            return Ok(Some(Role::Owner));
+        }
+
+        // the primary so that a member who was just removed or demoted loses their old role immediately
+        let db_conn = self
+            .db_conn()
+            .context("checking if user is an org member")?;
+
+        let role = org_member::Entity::find()
+            .filter(org_member::Column::OrgId.eq(org.id))
+            .filter(org_member::Column::UserId.eq(user_id))
+            .one(&db_conn)
+            .await
+            .context("fetching org member from db")?
+            .map(|x| x.role);
+
+        Ok(role)
+    }
+
+    /// Verify that the given bearer token can act for an org with at least `required_role`.
+    ///
+    /// Returns the bearer's user and the user that holds the org's keys, stats, and balance.
+    /// If `org_id` is None, the bearer is acting for themselves and both users are the same.
+    pub async fn bearer_is_authorized_for_org(
+        &self,
+        bearer: Bearer,
+        org_id: Option<u64>,
+        required_role: Role,
+    ) -> Result<(user::Model, user::Model, OwnedSemaphorePermit), FrontendErrorResponse> {
+        let (user, semaphore_permit) = self.bearer_is_authorized(bearer).await?;
+
+        let org_id = match org_id {
+            None => return Ok((user.clone(), user, semaphore_permit)),
+            Some(x) => x,
+        };
+
+        // the primary so that membership changes apply immediately
+        let db_conn = self
+            .db_conn()
+            .context("checking if bearer token is authorized for an org")?;
+
+        let org = org::Entity::find_by_id(org_id)
+            .one(&db_conn)
+            .await
+            .context("fetching org from db")?
+            .ok_or(FrontendErrorResponse::NotFound)?;
+
+        match self.org_role(&org, user.id).await? {
+            Some(role) if role_allows(&role, &required_role) => {}
+            _ => return Err(FrontendErrorResponse::AccessDenied),
+        }
+
+        let org_user = user::Entity::find_by_id(org.user_id)
+            .one(&db_conn)
+            .await
+            .context("fetching org's user from db")?
+            .context("org's user does not exist")?;
+
+        Ok((user, org_user, semaphore_permit))
+    }
+
+    pub async fn rate_limit_login(
+        &self,
+        ip: IpAddr,
+        address: Option<Address>,
+    ) -> anyhow::Result<RateLimitResult> {
         // TODO: dry this up with rate_limit_by_rpc_key?
 
         // we don't care about user agent or origin or referer
@@ -558,47 +1120,169 @@ impl Web3ProxyApp {
         let semaphore = None;
 
         if let Some(rate_limiter) = &self.login_rate_limiter {
-            match rate_limiter.throttle_label(&ip.to_string(), None, 1).await {
-                Ok(RedisRateLimitResult::Allowed(_)) => {
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
-                }
-                Ok(RedisRateLimitResult::RetryAt(retry_at, _)) => {
-                    // TODO: set headers so they know when they can retry
-                    // TODO: debug or trace?
-                    // this is too verbose, but a stat might be good
-                    // // trace!(?ip, "login rate limit exceeded until {:?}", retry_at);
-
-                    Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
-                }
-                Ok(RedisRateLimitResult::RetryNever) => {
-                    // TODO: i don't think we'll get here. maybe if we ban an IP forever? seems unlikely
-                    // // trace!(?ip, "login rate limit is 0");
-                    Ok(RateLimitResult::RateLimited(authorization, None))
-                }
-                Err(err) => {
-                    // internal error, not rate limit being hit
-                    // TODO: i really want axum to do this for us in a single place.
-                    error!("login rate limiter is unhappy. allowing ip. err={:?}", err);
+            // the ip and the address each have their own limit
+            for label in login_subjects(ip, address) {
+                match rate_limiter.throttle_label(&label, None, 1).await {
+                    Ok(RedisRateLimitResult::Allowed(_)) => {}
+                    Ok(RedisRateLimitResult::RetryAt(retry_at, _)) => {
+                        // TODO: set headers so they know when they can retry
+                        // TODO: debug or trace?
+                        // this is too verbose, but a stat might be good
+                        // // trace!(?ip, "login rate limit exceeded until {:?}", retry_at);
+
+                        return Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)));
+                    }
+                    Ok(RedisRateLimitResult::RetryNever) => {
+                        // TODO: i don't think we'll get here. maybe if we ban an IP forever? seems unlikely
+                        // // trace!(?ip, "login rate limit is 0");
+                        return Ok(RateLimitResult::RateLimited(authorization, None));
+                    }
+                    Err(err) => {
+                        // internal error, not rate limit being hit
+                        // TODO: i really want axum to do this for us in a single place.
+                        error!("login rate limiter is unhappy. allowing ip. err={:?}", err);
 
-                    Ok(RateLimitResult::Allowed(authorization, None))
+                        return Ok(RateLimitResult::Allowed(authorization, None));
+                    }
                 }
             }
+
+            Ok(RateLimitResult::Allowed(authorization, semaphore))
         } else {
             // TODO: if no redis, rate limit with a local cache? "warn!" probably isn't right
             Ok(RateLimitResult::Allowed(authorization, None))
         }
     }
 
+    /// If the ip (or the ip for this address) has too many failed logins, the time they can try again.
+    /// Redis errors are logged and the login is allowed.
+    pub async fn login_locked_until(
+        &self,
+        ip: IpAddr,
+        address: Option<Address>,
+    ) -> Option<Instant> {
+        let mut redis_conn = match self.redis_conn().await {
+            Ok(Some(x)) => x,
+            Ok(None) => return None,
+            Err(err) => {
+                warn!("unable to get redis for login lockouts. err={:?}", err);
+                return None;
+            }
+        };
+
+        let mut locked_seconds = 0;
+
+        for subject in lockout_subjects(ip, address) {
+            // -2 if the key does not exist. -1 if it has no expiration
+            match redis_conn
+                .ttl::<_, i64>(format!("login_lockout:{}", subject))
+                .await
+            {
+                Ok(x) if x > 0 => locked_seconds = locked_seconds.max(x as u64),
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("unable to check login lockout. err={:?}", err);
+                    return None;
+                }
+            }
+        }
+
+        if locked_seconds == 0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_secs(locked_seconds))
+        }
+    }
+
+    /// Count a bad login signature against the ip and the ip+address. Enough of them lock out logins.
+    pub async fn record_login_failure(&self, ip: IpAddr, address: Address) -> anyhow::Result<()> {
+        let mut redis_conn = match self.redis_conn().await? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        for subject in lockout_subjects(ip, Some(address)) {
+            let failures_key = format!("login_failures:{}", subject);
+
+            let failures: u64 = redis_conn.incr(&failures_key, 1).await?;
+
+            redis_conn
+                .expire::<_, ()>(&failures_key, LOGIN_FAILURES_TTL_SECONDS)
+                .await?;
+
+            if let Some(lockout_seconds) = login_lockout_seconds(failures) {
+                warn!(
+                    "locking out logins from {} for {}s after {} failures",
+                    subject, lockout_seconds, failures
+                );
+
+                redis_conn
+                    .set_ex::<_, _, ()>(
+                        format!("login_lockout:{}", subject),
+                        failures,
+                        lockout_seconds as usize,
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A good signature resets the ip+address's failures. The ip's failures expire on their own.
+    pub async fn clear_login_failures(&self, ip: IpAddr, address: Address) -> anyhow::Result<()> {
+        if let Some(mut redis_conn) = self.redis_conn().await? {
+            redis_conn
+                .del::<_, ()>(format!("login_failures:{}:{:?}", ip, address))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the ip's reputation. Errors are logged and the ip is treated as clean.
+    pub async fn ip_reputation(&self, ip: IpAddr) -> IpReputation {
+        let ip_reputation = match self.ip_reputation.as_ref() {
+            None => return IpReputation::Clean,
+            Some(x) => x,
+        };
+
+        // most requests are answered without a redis connection
+        if let Some(x) = ip_reputation.check_local(ip) {
+            return x;
+        }
+
+        let redis_conn = match self.redis_conn().await {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("unable to get redis for ip reputation. err={:?}", err);
+                None
+            }
+        };
+
+        match ip_reputation.check(ip, redis_conn).await {
+            Ok(x) => x,
+            Err(err) => {
+                // TODO: should we fail closed instead?
+                warn!("ip reputation check failed. allowing ip. err={:?}", err);
+                IpReputation::Clean
+            }
+        }
+    }
+
     /// origin is included because it can override the default rate limits
+    /// flagged ips get the reduced limits from the ip_reputation config
     pub async fn rate_limit_by_ip(
         &self,
         allowed_origin_requests_per_period: &HashMap<String, u64>,
         ip: IpAddr,
         origin: Option<Origin>,
+        ip_reputation: IpReputation,
+        priority: bool,
     ) -> anyhow::Result<RateLimitResult> {
         // ip rate limits don't check referer or user agent
         // the do check
-        let authorization = Authorization::external(
+        let mut authorization = Authorization::external(
             allowed_origin_requests_per_period,
             self.db_conn.clone(),
             ip,
@@ -607,6 +1291,14 @@ impl Web3ProxyApp {
             None,
         )?;
 
+        if ip_reputation == IpReputation::Flagged {
+            // flagged ips don't get any bonus from their origin
+            authorization.checks.max_requests_per_period = self
+                .ip_reputation
+                .as_ref()
+                .and_then(|x| x.flagged_requests_per_period);
+        }
+
         if let Some(rate_limiter) = &self.frontend_ip_rate_limiter {
             match rate_limiter
                 .throttle(ip, authorization.checks.max_requests_per_period, 1)
@@ -614,7 +1306,7 @@ impl Web3ProxyApp {
             {
                 Ok(DeferredRateLimitResult::Allowed) => {
                     // rate limit allowed us. check concurrent request limits
-                    let semaphore = self.ip_semaphore(ip).await?;
+                    let semaphore = self.ip_semaphore(ip, priority).await?;
 
                     Ok(RateLimitResult::Allowed(authorization, semaphore))
                 }
@@ -634,20 +1326,42 @@ impl Web3ProxyApp {
                     error!("rate limiter is unhappy. allowing ip. err={:?}", err);
 
                     // at least we can still check the semaphore
-                    let semaphore = self.ip_semaphore(ip).await?;
+                    let semaphore = self.ip_semaphore(ip, priority).await?;
 
                     Ok(RateLimitResult::Allowed(authorization, semaphore))
                 }
             }
         } else {
             // no redis, but we can still check the ip semaphore
-            let semaphore = self.ip_semaphore(ip).await?;
+            let semaphore = self.ip_semaphore(ip, priority).await?;
 
             // TODO: if no redis, rate limit with a local cache? "warn!" probably isn't right
             Ok(RateLimitResult::Allowed(authorization, semaphore))
         }
     }
 
+    /// signed requests only include the rpc key's id. check the local cache for the secret, or query the database
+    pub(crate) async fn rpc_secret_key_by_id(
+        &self,
+        rpc_key_id: u64,
+    ) -> anyhow::Result<Option<RpcSecretKey>> {
+        let rpc_secret_key: Result<_, Arc<anyhow::Error>> = self
+            .rpc_key_id_cache
+            .try_get_with(rpc_key_id, async move {
+                let db_replica = self.db_replica().context("Getting database connection")?;
+
+                let rpc_key_model = rpc_key::Entity::find_by_id(rpc_key_id)
+                    .filter(rpc_key::Column::Active.eq(true))
+                    .one(db_replica.conn())
+                    .await?;
+
+                Ok(rpc_key_model.map(|x| x.secret_key.into()))
+            })
+            .await;
+
+        rpc_secret_key.map_err(|err| anyhow::anyhow!(err))
+    }
+
     // check the local cache for user data, or query the database
     pub(crate) async fn authorization_checks(
         &self,
@@ -655,110 +1369,193 @@ impl Web3ProxyApp {
     ) -> anyhow::Result<AuthorizationChecks> {
         let authorization_checks: Result<_, Arc<anyhow::Error>> = self
             .rpc_secret_key_cache
-            .try_get_with(rpc_secret_key.into(), async move {
-                // trace!(?rpc_secret_key, "user cache miss");
+            .try_get_with(
+                rpc_secret_key.into(),
+                self.query_authorization_checks(rpc_secret_key),
+            )
+            .await;
 
-                let db_replica = self.db_replica().context("Getting database connection")?;
+        // TODO: what's the best way to handle this arc? try_unwrap will not work
+        let authorization_checks = authorization_checks.map_err(|err| anyhow::anyhow!(err))?;
 
-                let rpc_secret_key: Uuid = rpc_secret_key.into();
+        if let Some(limits_expire_at) = authorization_checks.limits_expire_at {
+            if limits_expire_at <= virtual_clock::utc_now() {
+                // the key's custom limits have expired. reload so that the user tier's limits apply
+                let authorization_checks = self.query_authorization_checks(rpc_secret_key).await?;
 
-                // TODO: join the user table to this to return the User? we don't always need it
-                // TODO: join on secondary users
-                // TODO: join on user tier
-                match rpc_key::Entity::find()
-                    .filter(rpc_key::Column::SecretKey.eq(rpc_secret_key))
-                    .filter(rpc_key::Column::Active.eq(true))
+                self.rpc_secret_key_cache
+                    .insert(rpc_secret_key.into(), authorization_checks.clone())
+                    .await;
+
+                // the semaphore was sized with the custom max_concurrent_requests
+                if let Ok(user_id) = NonZeroU64::try_from(authorization_checks.user_id) {
+                    self.registered_user_semaphores.invalidate(&user_id).await;
+                }
+
+                return Ok(authorization_checks);
+            }
+        }
+
+        Ok(authorization_checks)
+    }
+
+    /// query the database for the key's authorization checks. this skips the cache!
+    async fn query_authorization_checks(
+        &self,
+        rpc_secret_key: RpcSecretKey,
+    ) -> anyhow::Result<AuthorizationChecks> {
+        // trace!(?rpc_secret_key, "user cache miss");
+
+        let db_replica = match (self.db_replica(), self.external_auth.as_ref()) {
+            (Some(x), _) => x,
+            // without a database, every key belongs to the external system
+            (None, Some(external_auth)) => return external_auth.check(rpc_secret_key).await,
+            (None, None) => return Err(anyhow::anyhow!("Getting database connection")),
+        };
+
+        let secret_key_uuid: Uuid = rpc_secret_key.into();
+
+        // TODO: join the user table to this to return the User? we don't always need it
+        // TODO: join on user tier
+        match rpc_key::Entity::find()
+            .filter(rpc_key::Column::SecretKey.eq(secret_key_uuid))
+            .filter(rpc_key::Column::Active.eq(true))
+            .one(db_replica.conn())
+            .await?
+        {
+            Some(rpc_key_model) => {
+                // TODO: move these splits into helper functions
+                // TODO: can we have sea orm handle this for us?
+                let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
                     .one(db_replica.conn())
                     .await?
-                {
-                    Some(rpc_key_model) => {
-                        // TODO: move these splits into helper functions
-                        // TODO: can we have sea orm handle this for us?
-                        let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
-                            .one(db_replica.conn())
-                            .await?
-                            .expect("related user");
-
-                        let user_tier_model =
-                            user_tier::Entity::find_by_id(user_model.user_tier_id)
-                                .one(db_replica.conn())
-                                .await?
-                                .expect("related user tier");
-
-                        let allowed_ips: Option<Vec<IpNet>> =
-                            if let Some(allowed_ips) = rpc_key_model.allowed_ips {
-                                let x = allowed_ips
-                                    .split(',')
-                                    .map(|x| x.trim().parse::<IpNet>())
-                                    .collect::<Result<Vec<_>, _>>()?;
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_origins: Option<Vec<Origin>> =
-                            if let Some(allowed_origins) = rpc_key_model.allowed_origins {
-                                // TODO: do this without collecting twice?
-                                let x = allowed_origins
-                                    .split(',')
-                                    .map(|x| HeaderValue::from_str(x.trim()))
-                                    .collect::<Result<Vec<_>, _>>()?
-                                    .into_iter()
-                                    .map(|x| Origin::decode(&mut [x].iter()))
-                                    .collect::<Result<Vec<_>, _>>()?;
-
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_referers: Option<Vec<Referer>> =
-                            if let Some(allowed_referers) = rpc_key_model.allowed_referers {
-                                let x = allowed_referers
-                                    .split(',')
-                                    .map(|x| x.trim().parse::<Referer>())
-                                    .collect::<Result<Vec<_>, _>>()?;
-
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_user_agents: Option<Vec<UserAgent>> =
-                            if let Some(allowed_user_agents) = rpc_key_model.allowed_user_agents {
-                                let x: Result<Vec<_>, _> = allowed_user_agents
-                                    .split(',')
-                                    .map(|x| x.trim().parse::<UserAgent>())
-                                    .collect();
-
-                                Some(x?)
-                            } else {
-                                None
-                            };
-
-                        let rpc_key_id =
-                            Some(rpc_key_model.id.try_into().expect("db ids are never 0"));
-
-                        Ok(AuthorizationChecks {
-                            user_id: rpc_key_model.user_id,
-                            rpc_key_id,
-                            allowed_ips,
-                            allowed_origins,
-                            allowed_referers,
-                            allowed_user_agents,
-                            log_level: rpc_key_model.log_level,
-                            log_revert_chance: rpc_key_model.log_revert_chance,
-                            max_concurrent_requests: user_tier_model.max_concurrent_requests,
-                            max_requests_per_period: user_tier_model.max_requests_per_period,
-                        })
-                    }
-                    None => Ok(AuthorizationChecks::default()),
-                }
-            })
-            .await;
+                    .expect("related user");
 
-        // TODO: what's the best way to handle this arc? try_unwrap will not work
-        authorization_checks.map_err(|err| anyhow::anyhow!(err))
+                let user_tier_model = user_tier::Entity::find_by_id(user_model.user_tier_id)
+                    .one(db_replica.conn())
+                    .await?
+                    .expect("related user tier");
+
+                let allowed_ips: Option<Vec<IpNet>> =
+                    if let Some(allowed_ips) = rpc_key_model.allowed_ips {
+                        let x = allowed_ips
+                            .split(',')
+                            .map(|x| x.trim().parse::<IpNet>())
+                            .collect::<Result<Vec<_>, _>>()?;
+                        Some(x)
+                    } else {
+                        None
+                    };
+
+                let allowed_origins: Option<Vec<Origin>> =
+                    if let Some(allowed_origins) = rpc_key_model.allowed_origins {
+                        // TODO: do this without collecting twice?
+                        let x = allowed_origins
+                            .split(',')
+                            .map(|x| HeaderValue::from_str(x.trim()))
+                            .collect::<Result<Vec<_>, _>>()?
+                            .into_iter()
+                            .map(|x| Origin::decode(&mut [x].iter()))
+                            .collect::<Result<Vec<_>, _>>()?;
+
+                        Some(x)
+                    } else {
+                        None
+                    };
+
+                let allowed_referers: Option<Vec<Referer>> =
+                    if let Some(allowed_referers) = rpc_key_model.allowed_referers {
+                        let x = allowed_referers
+                            .split(',')
+                            .map(|x| x.trim().parse::<Referer>())
+                            .collect::<Result<Vec<_>, _>>()?;
+
+                        Some(x)
+                    } else {
+                        None
+                    };
+
+                let allowed_user_agents: Option<Vec<UserAgent>> =
+                    if let Some(allowed_user_agents) = rpc_key_model.allowed_user_agents {
+                        let x: Result<Vec<_>, _> = allowed_user_agents
+                            .split(',')
+                            .map(|x| x.trim().parse::<UserAgent>())
+                            .collect();
+
+                        Some(x?)
+                    } else {
+                        None
+                    };
+
+                // all three are comma separated method patterns
+                let method_patterns = |x: Option<String>| -> Option<Vec<String>> {
+                    x.map(|x| x.split(',').map(|x| x.trim().to_string()).collect())
+                };
+
+                let allowed_methods = method_patterns(rpc_key_model.allowed_methods);
+                let method_allowlist = method_patterns(rpc_key_model.method_allowlist);
+                let method_denylist = method_patterns(rpc_key_model.method_denylist);
+
+                let rpc_key_id = Some(rpc_key_model.id.try_into().expect("db ids are never 0"));
+
+                // admins can give a key custom limits without changing the user's tier
+                let custom_limits_active = rpc_key_model
+                    .limits_expire_at
+                    .map(|x| x > virtual_clock::utc_now())
+                    .unwrap_or(true);
+
+                let (max_requests_per_period, max_concurrent_requests, limits_expire_at) =
+                    if custom_limits_active {
+                        (
+                            rpc_key_model
+                                .max_requests_per_period
+                                .or(user_tier_model.max_requests_per_period),
+                            rpc_key_model
+                                .max_concurrent_requests
+                                .or(user_tier_model.max_concurrent_requests),
+                            rpc_key_model.limits_expire_at,
+                        )
+                    } else {
+                        (
+                            user_tier_model.max_requests_per_period,
+                            user_tier_model.max_concurrent_requests,
+                            None,
+                        )
+                    };
+
+                let pinned_ips = parse_pinned_ips(rpc_key_model.pinned_ips.as_deref())?;
+
+                Ok(AuthorizationChecks {
+                    user_id: rpc_key_model.user_id,
+                    rpc_key_id,
+                    allowed_ips,
+                    pin_ips: rpc_key_model.pin_ips,
+                    pinned_ips,
+                    alert_webhook_url: rpc_key_model.alert_webhook_url,
+                    allowed_origins,
+                    allowed_referers,
+                    allowed_user_agents,
+                    allowed_methods,
+                    method_allowlist,
+                    method_denylist,
+                    rebroadcast_blocks: rpc_key_model.rebroadcast_blocks,
+                    log_level: rpc_key_model.log_level,
+                    log_revert_chance: rpc_key_model.log_revert_chance,
+                    latest_uncached: !rpc_key_model.cache_latest,
+                    max_concurrent_requests,
+                    max_requests_per_period,
+                    max_account_requests_per_period: user_tier_model
+                        .max_account_requests_per_period,
+                    max_account_bytes_per_period: user_tier_model.max_account_bytes_per_period,
+                    limits_expire_at,
+                    user_tier_title: Some(user_tier_model.title),
+                })
+            }
+            None => match self.external_auth.as_ref() {
+                Some(external_auth) => external_auth.check(rpc_secret_key).await,
+                None => Ok(AuthorizationChecks::default()),
+            },
+        }
     }
 
     /// Authorized the ip/origin/referer/useragent and rate limit and concurrency
@@ -769,6 +1566,7 @@ impl Web3ProxyApp {
         referer: Option<Referer>,
         rpc_key: RpcSecretKey,
         user_agent: Option<UserAgent>,
+        priority: bool,
     ) -> anyhow::Result<RateLimitResult> {
         let authorization_checks = self.authorization_checks(rpc_key).await?;
 
@@ -777,12 +1575,16 @@ impl Web3ProxyApp {
             return Ok(RateLimitResult::UnknownKey);
         }
 
+        // allowed and pinned ips. rejections alert the key's owner
+        self.check_key_ip(rpc_key, &authorization_checks, ip)
+            .await?;
+
         // TODO: rpc_key should have an option to rate limit by ip instead of by key
 
         // only allow this rpc_key to run a limited amount of concurrent requests
         // TODO: rate limit should be BEFORE the semaphore!
         let semaphore = self
-            .registered_user_semaphore(&authorization_checks)
+            .registered_user_semaphore(&authorization_checks, priority)
             .await?;
 
         let authorization = Authorization::try_new(
@@ -795,52 +1597,305 @@ impl Web3ProxyApp {
             AuthorizationType::Frontend,
         )?;
 
-        let user_max_requests_per_period = match authorization.checks.max_requests_per_period {
-            None => {
-                return Ok(RateLimitResult::Allowed(authorization, semaphore));
-            }
-            Some(x) => x,
-        };
+        let rpc_key_id = authorization
+            .checks
+            .rpc_key_id
+            .expect("rpc_key_id was checked above")
+            .get();
 
         // user key is valid. now check rate limits
-        if let Some(rate_limiter) = &self.frontend_registered_user_rate_limiter {
+        // first the limit for this one key
+        if let (Some(rate_limiter), Some(key_max_requests_per_period)) = (
+            &self.frontend_registered_user_rate_limiter,
+            authorization.checks.max_requests_per_period,
+        ) {
+            match rate_limiter
+                .throttle(rpc_key_id, Some(key_max_requests_per_period), 1)
+                .await
+            {
+                Ok(DeferredRateLimitResult::Allowed) => {}
+                Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
+                    // TODO: set headers so they know when they can retry
+                    // TODO: debug or trace?
+                    // this is too verbose, but a stat might be good
+                    // TODO: emit a stat
+                    // // trace!(?rpc_key_id, "rate limit exceeded until {:?}", retry_at);
+                    return Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)));
+                }
+                Ok(DeferredRateLimitResult::RetryNever) => {
+                    // // trace!(?rpc_key_id, "rate limit is 0");
+                    // TODO: emit a stat
+                    return Ok(RateLimitResult::RateLimited(authorization, None));
+                }
+                Err(err) => {
+                    // internal error, not rate limit being hit
+                    // TODO: i really want axum to do this for us in a single place.
+                    error!("rate limiter is unhappy. allowing key. err={:?}", err);
+                }
+            }
+        }
+
+        // then the limit shared by all of the user's keys. this stops users from multiplying their limits by making more keys
+        if let (Some(rate_limiter), Some(account_max_requests_per_period)) = (
+            &self.frontend_account_rate_limiter,
+            authorization.checks.max_account_requests_per_period,
+        ) {
             match rate_limiter
                 .throttle(
                     authorization.checks.user_id,
-                    Some(user_max_requests_per_period),
+                    Some(account_max_requests_per_period),
                     1,
                 )
                 .await
             {
-                Ok(DeferredRateLimitResult::Allowed) => {
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
+                Ok(DeferredRateLimitResult::Allowed) => {}
+                Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
+                    // TODO: emit a stat
+                    return Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)));
+                }
+                Ok(DeferredRateLimitResult::RetryNever) => {
+                    // TODO: emit a stat
+                    return Ok(RateLimitResult::RateLimited(authorization, None));
+                }
+                Err(err) => {
+                    // internal error, not rate limit being hit
+                    error!(
+                        "account rate limiter is unhappy. allowing key. err={:?}",
+                        err
+                    );
                 }
+            }
+        }
+
+        // then the bandwidth shared by all of the user's keys. bytes are charged after the response, so just check that they aren't over already
+        if let (Some(rate_limiter), Some(account_max_bytes_per_period)) = (
+            &self.frontend_bandwidth_rate_limiter,
+            authorization.checks.max_account_bytes_per_period,
+        ) {
+            match rate_limiter
+                .throttle(
+                    authorization.checks.user_id,
+                    Some(account_max_bytes_per_period),
+                    0,
+                )
+                .await
+            {
+                Ok(DeferredRateLimitResult::Allowed) => {}
                 Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
-                    // TODO: set headers so they know when they can retry
-                    // TODO: debug or trace?
-                    // this is too verbose, but a stat might be good
-                    // TODO: keys are secrets! use the id instead
                     // TODO: emit a stat
-                    // // trace!(?rpc_key, "rate limit exceeded until {:?}", retry_at);
-                    Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
+                    return Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)));
                 }
                 Ok(DeferredRateLimitResult::RetryNever) => {
-                    // TODO: keys are secret. don't log them!
-                    // // trace!(?rpc_key, "rate limit is 0");
                     // TODO: emit a stat
-                    Ok(RateLimitResult::RateLimited(authorization, None))
+                    return Ok(RateLimitResult::RateLimited(authorization, None));
                 }
                 Err(err) => {
                     // internal error, not rate limit being hit
-                    // TODO: i really want axum to do this for us in a single place.
-                    error!("rate limiter is unhappy. allowing ip. err={:?}", err);
+                    error!(
+                        "bandwidth rate limiter is unhappy. allowing key. err={:?}",
+                        err
+                    );
+                }
+            }
+        }
 
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
+        // TODO: if no redis, rate limit with just a local cache?
+        Ok(RateLimitResult::Allowed(authorization, semaphore))
+    }
+
+    /// Count a message on an open websocket against the ip or key's rate limit and the key's account limit.
+    /// Opening the websocket was already rate limited. Without `websocket_rate_limit_multiplier` messages are free.
+    pub async fn rate_limit_websocket_request(
+        &self,
+        authorization: &Authorization,
+    ) -> DeferredRateLimitResult {
+        match self.config.websocket_rate_limit_multiplier {
+            Some(x) if x > 0 => self.rate_limit_units(authorization, x).await,
+            _ => DeferredRateLimitResult::Allowed,
+        }
+    }
+
+    /// Count a request that costs more than 1 against the ip or key's rate limit and the key's account limit.
+    /// Authorizing the request already counted it once.
+    pub async fn rate_limit_compute_units(
+        &self,
+        authorization: &Authorization,
+        compute_units: u64,
+    ) -> DeferredRateLimitResult {
+        match compute_units.saturating_sub(1) {
+            0 => DeferredRateLimitResult::Allowed,
+            extra => self.rate_limit_units(authorization, extra).await,
+        }
+    }
+
+    /// Count `units` requests against the ip or key's rate limit. Keys also count them against their account's limit.
+    async fn rate_limit_units(
+        &self,
+        authorization: &Authorization,
+        units: u64,
+    ) -> DeferredRateLimitResult {
+        let max_requests_per_period = authorization.checks.max_requests_per_period;
+
+        let result = match (
+            &authorization.authorization_type,
+            authorization.checks.rpc_key_id,
+        ) {
+            (AuthorizationType::Internal, _) => return DeferredRateLimitResult::Allowed,
+            (AuthorizationType::Frontend, Some(rpc_key_id)) => {
+                let key_result = match (
+                    &self.frontend_registered_user_rate_limiter,
+                    max_requests_per_period,
+                ) {
+                    (Some(rate_limiter), Some(max_requests_per_period)) => {
+                        rate_limiter
+                            .throttle(rpc_key_id.get(), Some(max_requests_per_period), units)
+                            .await
+                    }
+                    // keys without a limit are unlimited
+                    _ => Ok(DeferredRateLimitResult::Allowed),
+                };
+
+                match key_result {
+                    Ok(DeferredRateLimitResult::Allowed) => {
+                        // spreading expensive methods across keys still counts against the account
+                        match (
+                            &self.frontend_account_rate_limiter,
+                            authorization.checks.max_account_requests_per_period,
+                        ) {
+                            (Some(rate_limiter), Some(account_max_requests_per_period)) => {
+                                rate_limiter
+                                    .throttle(
+                                        authorization.checks.user_id,
+                                        Some(account_max_requests_per_period),
+                                        units,
+                                    )
+                                    .await
+                            }
+                            _ => Ok(DeferredRateLimitResult::Allowed),
+                        }
+                    }
+                    x => x,
                 }
             }
-        } else {
-            // TODO: if no redis, rate limit with just a local cache?
-            Ok(RateLimitResult::Allowed(authorization, semaphore))
+            (AuthorizationType::Frontend, None) => match &self.frontend_ip_rate_limiter {
+                Some(rate_limiter) => {
+                    rate_limiter
+                        .throttle(authorization.ip, max_requests_per_period, units)
+                        .await
+                }
+                None => return DeferredRateLimitResult::Allowed,
+            },
+        };
+
+        result.unwrap_or_else(|err| {
+            // internal error, not rate limit being hit
+            error!("rate limiter is unhappy. allowing. err={:?}", err);
+
+            DeferredRateLimitResult::Allowed
+        })
+    }
+
+    /// Count a request's bytes against the user's bandwidth limit.
+    /// This happens after the response so that big responses are charged for their full size.
+    pub async fn charge_bandwidth(&self, authorization: &Authorization, num_bytes: u64) {
+        if let (Some(rate_limiter), Some(account_max_bytes_per_period)) = (
+            &self.frontend_bandwidth_rate_limiter,
+            authorization.checks.max_account_bytes_per_period,
+        ) {
+            // going over is fine here. the next request will be rate limited
+            if let Err(err) = rate_limiter
+                .throttle(
+                    authorization.checks.user_id,
+                    Some(account_max_bytes_per_period),
+                    num_bytes,
+                )
+                .await
+            {
+                error!("unable to charge bandwidth. err={:?}", err);
+            }
+        }
+    }
+}
+
+/// owners can do everything admins can do. admins can do everything viewers can do
+pub fn role_allows(role: &Role, required_role: &Role) -> bool {
+    fn rank(role: &Role) -> u8 {
+        match role {
+            Role::Owner => 2,
+            Role::Admin => 1,
+            Role::Viewer => 0,
         }
     }
+
+    rank(role) >= rank(required_role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(formatted_key: &str, timestamp: i64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(formatted_key.as_bytes()).unwrap();
+
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_request_signatures() {
+        let key = "llama_01GZHMCXHXHPGAABAQQTXKMSM3";
+        let body = br#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"}"#;
+        let timestamp = 1_700_000_000;
+
+        let signature = sign(key, timestamp, body);
+
+        assert!(signature_is_valid(key, timestamp, &signature, body));
+        // clients may send uppercase hex
+        assert!(signature_is_valid(
+            key,
+            timestamp,
+            &signature.to_uppercase(),
+            body
+        ));
+
+        // tampered body
+        assert!(!signature_is_valid(
+            key,
+            timestamp,
+            &signature,
+            br#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId"}"#
+        ));
+
+        // tampered timestamp
+        assert!(!signature_is_valid(key, timestamp + 1, &signature, body));
+
+        // wrong key. the unprefixed key is a different key
+        assert!(!signature_is_valid(
+            "01GZHMCXHXHPGAABAQQTXKMSM3",
+            timestamp,
+            &signature,
+            body
+        ));
+
+        // not hex
+        assert!(!signature_is_valid(key, timestamp, "not hex", body));
+
+        // stale and future timestamps
+        assert!(signature_is_fresh(timestamp, timestamp));
+        assert!(signature_is_fresh(
+            timestamp,
+            timestamp + SIGNATURE_MAX_AGE_SECONDS
+        ));
+        assert!(!signature_is_fresh(
+            timestamp,
+            timestamp + SIGNATURE_MAX_AGE_SECONDS + 1
+        ));
+        assert!(!signature_is_fresh(
+            timestamp + SIGNATURE_MAX_AGE_SECONDS + 1,
+            timestamp
+        ));
+        assert!(!signature_is_fresh(i64::MIN, timestamp));
+    }
 }
@@ -0,0 +1,245 @@
+//! Compress responses for clients that accept gzip or brotli.
+//!
+//! The compression layer compresses every response as it is sent. Responses from a cache are sent many times,
+//! so `compress_cached` compresses them once and keeps the compressed body. The compression layer skips responses
+//! that already have a `Content-Encoding`.
+
+use crate::app::Web3ProxyApp;
+use crate::config::CompressionConfig;
+use axum::body::{boxed, Bytes, Full};
+use axum::response::{IntoResponse, Response};
+use axum::{middleware::Next, Extension};
+use flate2::write::GzEncoder;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use http::{HeaderValue, Request, StatusCode};
+use log::warn;
+use moka::future::Cache;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+use std::sync::Arc;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+
+/// Brotli is slow at its best quality. This is still much smaller than gzip for JSON
+const BROTLI_QUALITY: u32 = 9;
+
+const BROTLI_LGWIN: u32 = 22;
+
+/// Handlers add this to responses that came from a cache.
+#[derive(Clone, Copy, Debug)]
+pub struct CachedResponse;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Encoding {
+    Br,
+    Gzip,
+}
+
+impl Encoding {
+    fn header_value(self) -> HeaderValue {
+        match self {
+            Self::Br => HeaderValue::from_static("br"),
+            Self::Gzip => HeaderValue::from_static("gzip"),
+        }
+    }
+}
+
+/// Compressed bodies by encoding and the sha256 of the uncompressed body.
+pub type CompressedResponses =
+    Cache<(Encoding, [u8; 32]), Bytes, hashbrown::hash_map::DefaultHashBuilder>;
+
+pub fn compression_layer(config: &CompressionConfig) -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(config.min_bytes)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES);
+
+    CompressionLayer::new()
+        .br(config.br)
+        .gzip(config.gzip)
+        .compress_when(predicate)
+}
+
+pub fn compressed_responses(config: &CompressionConfig) -> CompressedResponses {
+    Cache::builder()
+        .max_capacity(config.cache_max_bytes)
+        .weigher(|_k, v: &Bytes| v.len().try_into().unwrap_or(u32::MAX))
+        .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default())
+}
+
+/// The enabled encoding with the highest q-value. Ties go to brotli.
+fn preferred_encoding(accept_encoding: &str, config: &CompressionConfig) -> Option<Encoding> {
+    let mut preferred = None;
+    let mut max_q = 0.0;
+
+    for x in accept_encoding.split(',') {
+        let mut x = x.split(';');
+
+        let encoding = match x.next().map(|x| x.trim()) {
+            Some("br") if config.br => Encoding::Br,
+            Some("gzip") if config.gzip => Encoding::Gzip,
+            _ => continue,
+        };
+
+        let q = x
+            .find_map(|x| x.trim().strip_prefix("q="))
+            .map(|x| x.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+
+        if q > max_q || (q == max_q && q > 0.0 && encoding == Encoding::Br) {
+            preferred = Some(encoding);
+            max_q = q;
+        }
+    }
+
+    preferred
+}
+
+fn compress(encoding: Encoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Br => {
+            let mut writer =
+                brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_LGWIN);
+
+            writer.write_all(body)?;
+
+            Ok(writer.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+
+            encoder.write_all(body)?;
+
+            encoder.finish()
+        }
+    }
+}
+
+/// Middleware that runs inside the compression layer. Cached responses are compressed here once.
+pub async fn compress_cached<B>(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(compressed_responses): Extension<CompressedResponses>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let encoding = request
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| preferred_encoding(x, &app.config.compression));
+
+    let response = next.run(request).await;
+
+    let encoding = match encoding {
+        Some(x) => x,
+        None => return response,
+    };
+
+    if response.extensions().get::<CachedResponse>().is_none()
+        || response.headers().contains_key(CONTENT_ENCODING)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    // cached responses are already in memory. this doesn't wait on anything
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(x) => x,
+        Err(err) => {
+            warn!("unable to read cached response. err={:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if body.len() < app.config.compression.min_bytes as usize {
+        return Response::from_parts(parts, boxed(Full::from(body)));
+    }
+
+    let key = (encoding, Sha256::digest(&body).into());
+
+    let compressed = {
+        let body = body.clone();
+
+        compressed_responses
+            .try_get_with(key, async move {
+                let compressed =
+                    tokio::task::spawn_blocking(move || compress(encoding, &body)).await??;
+
+                Ok::<_, anyhow::Error>(Bytes::from(compressed))
+            })
+            .await
+    };
+
+    match compressed {
+        Ok(compressed) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, encoding.header_value());
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+            Response::from_parts(parts, boxed(Full::from(compressed)))
+        }
+        Err(err) => {
+            // the compression layer will try again
+            warn!("unable to compress cached response. err={:?}", err);
+
+            Response::from_parts(parts, boxed(Full::from(body)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_preferred_encoding() {
+        let config = CompressionConfig::default();
+
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br", &config),
+            Some(Encoding::Br)
+        );
+        assert_eq!(
+            preferred_encoding("gzip;q=1.0, br;q=0.5", &config),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(preferred_encoding("gzip;q=0", &config), None);
+        assert_eq!(preferred_encoding("identity", &config), None);
+
+        let config = CompressionConfig {
+            br: false,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br", &config),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let body = br#"{"jsonrpc":"2.0","id":1,"result":[]}"#.repeat(100);
+
+        let gzip = compress(Encoding::Gzip, &body).unwrap();
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let br = compress(Encoding::Br, &body).unwrap();
+        let mut decoded = vec![];
+        brotli::Decompressor::new(&br[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        assert!(br.len() < body.len());
+    }
+}
@@ -0,0 +1,172 @@
+//! The CORS policy for browser dapps.
+//!
+//! The cors layer answers preflight requests before any handler runs. So that an rpc key's allowed origins apply to them too,
+//! `rpc_key_origins` looks up the key first and leaves its origins in the request's extensions.
+
+use super::authorization::RpcSecretKey;
+use crate::app::Web3ProxyApp;
+use crate::config::CorsConfig;
+use anyhow::Context;
+use axum::headers::{Header, Origin};
+use axum::{middleware::Next, response::Response, Extension};
+use http::header::HeaderName;
+use http::header::ORIGIN;
+use http::{HeaderValue, Method, Request};
+use log::debug;
+use std::iter;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+/// The allowed origins of the rpc key in the request's path.
+#[derive(Clone)]
+struct RpcKeyOrigins(Vec<Origin>);
+
+pub fn cors_layer(config: &CorsConfig) -> anyhow::Result<CorsLayer> {
+    let allowed_origins = config
+        .allowed_origins
+        .as_ref()
+        .map(|x| {
+            x.iter()
+                .map(|x| {
+                    HeaderValue::from_str(x).with_context(|| format!("invalid cors origin: {}", x))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .transpose()?;
+
+    let allow_origin = AllowOrigin::predicate(move |origin, parts| {
+        let key_origins = parts.extensions.get::<RpcKeyOrigins>();
+
+        origin_allowed(
+            origin,
+            allowed_origins.as_deref(),
+            key_origins.map(|x| x.0.as_slice()),
+        )
+    });
+
+    let allow_methods = match config.allowed_methods.as_ref() {
+        None => AllowMethods::mirror_request(),
+        Some(x) => AllowMethods::list(
+            x.iter()
+                .map(|x| {
+                    x.parse::<Method>()
+                        .with_context(|| format!("invalid cors method: {}", x))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        ),
+    };
+
+    let allow_headers = match config.allowed_headers.as_ref() {
+        None => AllowHeaders::mirror_request(),
+        Some(x) => AllowHeaders::list(
+            x.iter()
+                .map(|x| {
+                    x.parse::<HeaderName>()
+                        .with_context(|| format!("invalid cors header: {}", x))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        ),
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_credentials(config.allow_credentials)
+        .allow_headers(allow_headers)
+        .allow_methods(allow_methods)
+        .allow_origin(allow_origin);
+
+    if let Some(x) = config.max_age_seconds {
+        layer = layer.max_age(Duration::from_secs(x));
+    }
+
+    Ok(layer)
+}
+
+/// A key's own origins replace the configured ones.
+fn origin_allowed(
+    origin: &HeaderValue,
+    allowed_origins: Option<&[HeaderValue]>,
+    key_origins: Option<&[Origin]>,
+) -> bool {
+    if let Some(key_origins) = key_origins {
+        return match Origin::decode(&mut iter::once(origin)) {
+            Ok(origin) => key_origins.contains(&origin),
+            Err(_) => false,
+        };
+    }
+
+    match allowed_origins {
+        None => true,
+        Some(allowed_origins) => allowed_origins.contains(origin),
+    }
+}
+
+/// `/rpc/:rpc_key`. `/rpc/signed` and other paths are None
+fn rpc_key_from_path(path: &str) -> Option<RpcSecretKey> {
+    path.strip_prefix("/rpc/")?
+        .trim_end_matches('/')
+        .parse()
+        .ok()
+}
+
+/// Middleware that runs before the cors layer. Cross-origin requests for a key that limits its origins get them in their extensions.
+pub async fn rpc_key_origins<B>(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if app.config.cors.rpc_key_origins && request.headers().contains_key(ORIGIN) {
+        if let Some(rpc_secret_key) = rpc_key_from_path(request.uri().path()) {
+            match app.authorization_checks(rpc_secret_key).await {
+                Ok(authorization_checks) => {
+                    if let Some(x) = authorization_checks.allowed_origins {
+                        request.extensions_mut().insert(RpcKeyOrigins(x));
+                    }
+                }
+                Err(err) => {
+                    // the handler will hit this too and return the error
+                    debug!("unable to check rpc key origins: {:?}", err);
+                }
+            }
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_allowed() {
+        let dapp = HeaderValue::from_static("https://dapp.example.com");
+        let other = HeaderValue::from_static("https://other.example.com");
+
+        assert!(origin_allowed(&dapp, None, None));
+
+        let allowed = [dapp.clone()];
+        assert!(origin_allowed(&dapp, Some(&allowed[..]), None));
+        assert!(!origin_allowed(&other, Some(&allowed[..]), None));
+
+        // the key's origins replace the configured ones
+        let key_origins = [Origin::try_from_parts("https", "other.example.com", None).unwrap()];
+        assert!(origin_allowed(
+            &other,
+            Some(&allowed[..]),
+            Some(&key_origins[..])
+        ));
+        assert!(!origin_allowed(&dapp, None, Some(&key_origins[..])));
+    }
+
+    #[test]
+    fn test_rpc_key_from_path() {
+        let key = "01GQ4Y0B1ZG3GQ0ZHP3CYRHNGH";
+
+        assert!(rpc_key_from_path(&format!("/rpc/{}", key)).is_some());
+        assert!(rpc_key_from_path(&format!("/rpc/{}/", key)).is_some());
+        assert!(rpc_key_from_path("/rpc/signed").is_none());
+        assert!(rpc_key_from_path("/rpc").is_none());
+        assert!(rpc_key_from_path(&format!("/user/{}", key)).is_none());
+    }
+}
@@ -1,10 +1,11 @@
 //! Utlities for logging errors for admins and displaying errors to users.
 
 use super::authorization::Authorization;
+use crate::app::{NO_SERVERS_SYNCED_ERROR_CODE, NO_SERVERS_SYNCED_MESSAGE};
 use crate::jsonrpc::JsonRpcForwardedResponse;
 use axum::{
     headers,
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -32,10 +33,14 @@ pub enum FrontendErrorResponse {
     InvalidHeaderValue(InvalidHeaderValue),
     IpAddrParse(AddrParseError),
     JoinError(JoinError),
+    /// `anonymous_access` is `key_required` and the request has no key
+    KeyRequired,
+    /// none of the backends are at the head block
+    NoServersSynced,
     NotFound,
-    RateLimited(Authorization, Option<Instant>),
+    RateLimited(Box<Authorization>, Option<Instant>),
     Redis(RedisError),
-    Response(Response),
+    Response(Box<Response>),
     /// simple way to return an error message to the user and an anyhow to our logs
     StatusCode(StatusCode, String, Option<anyhow::Error>),
     /// TODO: what should be attached to the timout?
@@ -149,6 +154,34 @@ impl IntoResponse for FrontendErrorResponse {
                     ),
                 )
             }
+            Self::NoServersSynced => {
+                trace!("no servers synced");
+
+                let response = JsonRpcForwardedResponse::from_str(
+                    NO_SERVERS_SYNCED_MESSAGE,
+                    Some(NO_SERVERS_SYNCED_ERROR_CODE),
+                    None,
+                );
+
+                // this is usually very brief. tell clients to try again soon
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, "1")],
+                    Json(response),
+                )
+                    .into_response();
+            }
+            Self::KeyRequired => {
+                trace!("key required");
+                (
+                    StatusCode::UNAUTHORIZED,
+                    JsonRpcForwardedResponse::from_str(
+                        "an rpc key is required",
+                        Some(StatusCode::UNAUTHORIZED.as_u16().into()),
+                        None,
+                    ),
+                )
+            }
             Self::NotFound => {
                 // TODO: emit a stat?
                 // TODO: instead of an error, show a normal html page for 404
@@ -174,14 +207,13 @@ impl IntoResponse for FrontendErrorResponse {
                 };
 
                 // create a string with either the IP or the rpc_key_id
-                let msg = if authorization.checks.rpc_key_id.is_none() {
-                    format!("too many requests from {}.{}", authorization.ip, retry_msg)
-                } else {
+                let msg = if let Some(rpc_key_id) = authorization.checks.rpc_key_id {
                     format!(
                         "too many requests from rpc key #{}.{}",
-                        authorization.checks.rpc_key_id.unwrap(),
-                        retry_msg
+                        rpc_key_id, retry_msg
                     )
+                } else {
+                    format!("too many requests from {}.{}", authorization.ip, retry_msg)
                 };
 
                 (
@@ -206,7 +238,7 @@ impl IntoResponse for FrontendErrorResponse {
             }
             Self::Response(r) => {
                 debug_assert_ne!(r.status(), StatusCode::OK);
-                return r;
+                return *r;
             }
             Self::SemaphoreAcquireError(err) => {
                 warn!("semaphore acquire err={:?}", err);
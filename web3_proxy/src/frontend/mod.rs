@@ -1,7 +1,12 @@
 //! `frontend` contains HTTP and websocket endpoints for use by users and admins.
 
+pub mod admin;
 pub mod authorization;
+pub mod compression;
+pub mod cors;
 pub mod errors;
+pub mod orgs;
+pub mod rest;
 // TODO: these are only public so docs are generated. What's a better way to do this?
 pub mod rpc_proxy_http;
 pub mod rpc_proxy_ws;
@@ -9,17 +14,30 @@ pub mod status;
 pub mod users;
 
 use crate::app::Web3ProxyApp;
+use crate::config::TlsConfig;
+use anyhow::Context;
 use axum::{
-    routing::{get, post, put},
+    extract::ConnectInfo,
+    middleware,
+    routing::{delete, get, post, put},
     Extension, Router,
 };
-use http::header::AUTHORIZATION;
-use log::info;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use futures::future::{try_join_all, BoxFuture, FutureExt};
+use http::header::{HeaderName, AUTHORIZATION};
+use log::{info, warn};
 use moka::future::Cache;
-use std::net::SocketAddr;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
 use std::sync::Arc;
-use std::{iter::once, time::Duration};
-use tower_http::cors::CorsLayer;
+use std::time::{Duration, SystemTime};
+use tokio::net::UnixListener;
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_stream::wrappers::UnixListenerStream;
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -31,8 +49,33 @@ pub enum FrontendResponseCaches {
 pub type FrontendResponseCache =
     Cache<FrontendResponseCaches, Arc<serde_json::Value>, hashbrown::hash_map::DefaultHashBuilder>;
 
+/// Listen on all interfaces. Binding before serving lets callers read the address. Port 0 picks any free port.
+pub fn bind(port: u16) -> anyhow::Result<TcpListener> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+    let listener = TcpListener::bind(addr).with_context(|| format!("binding to {}", addr))?;
+
+    // tokio and hyper need non-blocking sockets
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
 /// Start the frontend server.
-pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()> {
+pub async fn serve(
+    port: u16,
+    tls: Option<TlsConfig>,
+    proxy_app: Arc<Web3ProxyApp>,
+) -> anyhow::Result<()> {
+    serve_listener(bind(port)?, tls, proxy_app).await
+}
+
+/// Start the frontend server on an already bound listener.
+pub async fn serve_listener(
+    listener: TcpListener,
+    tls: Option<TlsConfig>,
+    proxy_app: Arc<Web3ProxyApp>,
+) -> anyhow::Result<()> {
     // setup caches for whatever the frontend needs
     // TODO: a moka cache is probably way overkill for this.
     // no need for max items. only expire because of time to live
@@ -40,20 +83,38 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
         .time_to_live(Duration::from_secs(1))
         .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
+    let cors_layer = cors::cors_layer(&proxy_app.config.cors).context("invalid cors config")?;
+
+    let compression_layer = compression::compression_layer(&proxy_app.config.compression);
+
+    let compressed_responses = compression::compressed_responses(&proxy_app.config.compression);
+
     // build our axum Router
     let app = Router::new()
         // routes should be ordered most to least common
-        .route("/", post(rpc_proxy_http::proxy_web3_rpc))
-        .route("/", get(rpc_proxy_ws::websocket_handler))
+        // every rpc route takes POSTed JSON-RPC and websocket upgrades with the same auth
         .route(
-            "/rpc/:rpc_key",
-            post(rpc_proxy_http::proxy_web3_rpc_with_key),
+            "/",
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
+        )
+        .route(
+            "/rpc",
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         .route(
             "/rpc/:rpc_key",
-            get(rpc_proxy_ws::websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc_with_key)
+                .get(rpc_proxy_ws::websocket_handler_with_key),
         )
+        .route(
+            "/rpc/signed",
+            post(rpc_proxy_http::proxy_web3_rpc_with_signature),
+        )
+        .route("/rest/multicall", post(rest::multicall_post))
         .route("/health", get(status::health))
+        .route("/ready", get(status::ready))
+        .route("/live", get(status::live))
+        .route("/startup", get(status::startup))
         .route("/user/login/:user_address", get(users::user_login_get))
         .route(
             "/user/login/:user_address/:message_eip",
@@ -67,7 +128,17 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
         .route("/user/keys", get(users::rpc_keys_get))
         .route("/user/keys", post(users::rpc_keys_management))
         .route("/user/keys", put(users::rpc_keys_management))
+        .route("/user/keys", delete(users::rpc_keys_delete))
         .route("/user/revert_logs", get(users::user_revert_logs_get))
+        .route("/user/orgs", get(orgs::orgs_get).post(orgs::org_post))
+        .route(
+            "/user/orgs/:org_id/members",
+            get(orgs::org_members_get).post(orgs::org_members_post),
+        )
+        .route(
+            "/user/orgs/:org_id/members/:user_id",
+            delete(orgs::org_members_delete),
+        )
         .route(
             "/user/stats/aggregate",
             get(users::user_stats_aggregated_get),
@@ -77,25 +148,65 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
             get(users::user_stats_aggregated_get),
         )
         .route("/user/stats/detailed", get(users::user_stats_detailed_get))
+        .route("/user/stats/methods", get(users::user_stats_methods_get))
         .route("/user/logout", post(users::user_logout_post))
+        .route(
+            "/admin/keys/:rpc_key_id/limits",
+            get(admin::admin_rpc_key_limits_get)
+                .post(admin::admin_rpc_key_limits_post)
+                .delete(admin::admin_rpc_key_limits_delete),
+        )
+        .route(
+            "/admin/keys/:rpc_key_id/methods",
+            post(admin::admin_rpc_key_methods_post),
+        )
+        .route(
+            "/admin/keys/:rpc_key_id/kick",
+            post(admin::admin_rpc_key_kick_post),
+        )
+        .route("/admin/audit_log", get(admin::admin_audit_log_get))
+        .route("/admin/drain", post(admin::admin_drain_post))
+        .route("/admin/rpcs", get(admin::admin_rpcs_get))
+        .route("/admin/latency_probe", get(admin::admin_latency_probe_get))
+        .route("/admin/costs", get(admin::admin_costs_get))
+        .route(
+            "/admin/rpcs/:group/:name",
+            put(admin::admin_rpc_put).post(admin::admin_rpc_post),
+        )
+        .route("/admin/features", get(admin::admin_feature_flags_get))
+        .route(
+            "/admin/features/:name",
+            post(admin::admin_feature_flag_post).delete(admin::admin_feature_flag_delete),
+        )
         .route("/status", get(status::status))
+        .route("/status/version", get(status::status_version))
+        .route("/status/backends", get(status::status_backends))
+        .route("/tx/:tx_hash", get(status::tx_status))
         // layers are ordered bottom up
         // the last layer is first for requests and last for responses
-        // Mark the `Authorization` request header as sensitive so it doesn't show in logs
-        .layer(SetSensitiveRequestHeadersLayer::new(once(AUTHORIZATION)))
+        // Mark the `Authorization` and `X-API-Key` request headers as sensitive so they don't show in logs
+        .layer(SetSensitiveRequestHeadersLayer::new([
+            AUTHORIZATION,
+            HeaderName::from_static(authorization::RPC_KEY_HEADER),
+        ]))
+        // cached responses are compressed once. everything else is compressed as it is sent
+        .layer(middleware::from_fn(compression::compress_cached))
+        .layer(compression_layer)
         // handle cors
-        .layer(CorsLayer::very_permissive())
+        .layer(cors_layer)
+        // the cors layer needs the rpc key's allowed origins for preflight requests
+        .layer(middleware::from_fn(cors::rpc_key_origins))
         // application state
         .layer(Extension(proxy_app.clone()))
         // frontend caches
         .layer(Extension(response_cache))
+        .layer(Extension(compressed_responses))
         // 404 for any unknown routes
         .fallback(errors::handler_404);
 
     // run our app with hyper
     // TODO: allow only listening on localhost? top_config.app.host.parse()?
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    info!("listening on port {}", port);
+    info!("listening on {}", listener.local_addr()?);
 
     // TODO: into_make_service is enough if we always run behind a proxy. make into_make_service_with_connect_info optional?
     /*
@@ -105,13 +216,163 @@ pub async fn serve(port: u16, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()
       - forwarded header (new standard)
       - axum::extract::ConnectInfo (if not behind proxy)
     */
-    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let service = app
+        .clone()
+        .into_make_service_with_connect_info::<SocketAddr>();
     // let service = app.into_make_service();
 
+    let draining_app = proxy_app.clone();
+
     // `axum::Server` is a re-export of `hyper::Server`
-    axum::Server::bind(&addr)
+    // once draining starts, new connections are refused and this returns when the open http requests are done
+    let tcp_server = axum::Server::from_tcp(listener)?
         // TODO: option to use with_connect_info. we want it in dev, but not when running behind a proxy, but not
         .serve(service)
+        .with_graceful_shutdown(async move { draining_app.draining().await });
+
+    let mut servers: Vec<BoxFuture<anyhow::Result<()>>> =
+        vec![async move { tcp_server.await.map_err(Into::into) }.boxed()];
+
+    if let Some(path) = proxy_app.config.unix_socket.clone() {
+        let app = app.clone();
+        let proxy_app = proxy_app.clone();
+
+        servers.push(async move { serve_unix(Path::new(&path), app, proxy_app).await }.boxed());
+    }
+
+    if let Some(tls) = tls {
+        servers.push(serve_tls(tls, app, proxy_app).boxed());
+    }
+
+    try_join_all(servers).await.map(|_| ())
+}
+
+/// Serve the same routes over https. The certificate is reloaded when its files change.
+async fn serve_tls(
+    tls: TlsConfig,
+    app: Router,
+    proxy_app: Arc<Web3ProxyApp>,
+) -> anyhow::Result<()> {
+    let rustls_config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "loading tls cert {} and key {}",
+                tls.cert_path, tls.key_path
+            )
+        })?;
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], tls.port));
+    info!("listening for https on port {}", tls.port);
+
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let handle = Handle::new();
+
+    let server = axum_server::bind_rustls(addr, rustls_config.clone())
+        .handle(handle.clone())
+        .serve(service);
+    tokio::pin!(server);
+
+    let reloader = tokio::spawn(reload_tls_on_change(tls, rustls_config));
+
+    // axum_server doesn't take a shutdown future, so its handle is told when draining starts
+    let x = tokio::select! {
+        biased;
+        x = &mut server => x,
+        _ = proxy_app.draining() => {
+            handle.graceful_shutdown(None);
+
+            server.await
+        }
+    };
+
+    reloader.abort();
+
+    x.map_err(Into::into)
+}
+
+async fn tls_files_modified(tls: &TlsConfig) -> io::Result<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(&tls.cert_path).await?.modified()?;
+    let key = tokio::fs::metadata(&tls.key_path).await?.modified()?;
+
+    Ok((cert, key))
+}
+
+/// Certificates are renewed in place (certbot and friends), so the files are checked every `reload_seconds`.
+async fn reload_tls_on_change(tls: TlsConfig, rustls_config: RustlsConfig) {
+    let mut last_modified = tls_files_modified(&tls).await.ok();
+
+    let mut interval = interval(Duration::from_secs(tls.reload_seconds.get()));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // the first tick is immediate
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let modified = match tls_files_modified(&tls).await {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("unable to check tls cert and key for changes: {:?}", err);
+                continue;
+            }
+        };
+
+        if Some(modified) == last_modified {
+            continue;
+        }
+
+        match rustls_config
+            .reload_from_pem_file(&tls.cert_path, &tls.key_path)
+            .await
+        {
+            Ok(()) => {
+                info!("reloaded tls cert from {}", tls.cert_path);
+                last_modified = Some(modified);
+            }
+            Err(err) => {
+                // a renewal might be half written. the old cert is kept and this is tried again next time
+                warn!("unable to reload tls cert. keeping the old one: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Serve the same routes on a unix socket for services on the same host.
+async fn serve_unix(path: &Path, app: Router, proxy_app: Arc<Web3ProxyApp>) -> anyhow::Result<()> {
+    // a socket left behind by a previous run would make binding fail. anything else at the path is left alone
+    match fs::symlink_metadata(path) {
+        Ok(x) if x.file_type().is_socket() => fs::remove_file(path)
+            .with_context(|| format!("removing old unix socket at {}", path.display()))?,
+        Ok(_) => {
+            return Err(anyhow::anyhow!(
+                "{} exists and is not a unix socket",
+                path.display()
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err).context(format!("checking {}", path.display())),
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("binding unix socket at {}", path.display()))?;
+
+    info!("listening on {}", path.display());
+
+    let incoming = hyper::server::accept::from_stream(UnixListenerStream::new(listener));
+
+    // unix sockets have no peer ip. the ip extractors fall back to this when there are no forwarding headers
+    let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+
+    let service = app
+        .layer(Extension(ConnectInfo(localhost)))
+        .into_make_service();
+
+    axum::Server::builder(incoming)
+        .serve(service)
+        .with_graceful_shutdown(async move { proxy_app.draining().await })
         .await
         .map_err(Into::into)
 }
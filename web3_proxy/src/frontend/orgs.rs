@@ -0,0 +1,344 @@
+//! Handle organizations. Many logins can share one user's keys, stats, and balance.
+
+use super::authorization::role_allows;
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use crate::app::Web3ProxyApp;
+use crate::audit_log::save_audit_log;
+use anyhow::Context;
+use axum::{
+    extract::Path,
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use entities::sea_orm_active_enums::Role;
+use entities::{org, org_member, user};
+use ethers::prelude::Address;
+use http::StatusCode;
+use log::info;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
+
+/// the JSON input to the `org_post` handler.
+#[derive(Debug, Deserialize)]
+pub struct OrgPost {
+    name: String,
+}
+
+/// the JSON input to the `org_members_post` handler.
+#[derive(Debug, Deserialize)]
+pub struct OrgMemberPost {
+    address: Address,
+    role: Role,
+}
+
+/// `GET /user/orgs` -- Use a bearer token to get the orgs the user belongs to and their role in each.
+#[debug_handler]
+pub async fn orgs_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app.db_replica().context("getting db for user's orgs")?;
+
+    let mut orgs = vec![];
+
+    if let Some(own_org) = org::Entity::find()
+        .filter(org::Column::UserId.eq(user.id))
+        .one(db_replica.conn())
+        .await
+        .context("failed loading user's org")?
+    {
+        orgs.push(json!({ "org": own_org, "role": Role::Owner }));
+    }
+
+    let memberships = org_member::Entity::find()
+        .filter(org_member::Column::UserId.eq(user.id))
+        .find_also_related(org::Entity)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading user's org memberships")?;
+
+    for (member, org) in memberships {
+        if let Some(org) = org {
+            orgs.push(json!({ "org": org, "role": member.role }));
+        }
+    }
+
+    Ok(Json(json!({ "user_id": user.id, "orgs": orgs })).into_response())
+}
+
+/// `POST /user/orgs` -- Use a bearer token to turn the user's account into an org.
+///
+/// The user's keys, stats, and balance are shared with the org's members.
+#[debug_handler]
+pub async fn org_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<OrgPost>,
+) -> FrontendResult {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    if payload.name.is_empty() {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::BAD_REQUEST,
+            "org name must not be empty".to_string(),
+            None,
+        ));
+    }
+
+    let db_conn = app.db_conn().context("creating an org requires a db")?;
+
+    if org::Entity::find()
+        .filter(org::Column::UserId.eq(user.id))
+        .one(&db_conn)
+        .await
+        .context("failed loading user's org")?
+        .is_some()
+    {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::CONFLICT,
+            "this user already has an org".to_string(),
+            None,
+        ));
+    }
+
+    let org = org::ActiveModel {
+        name: sea_orm::Set(payload.name),
+        user_id: sea_orm::Set(user.id),
+        ..Default::default()
+    };
+
+    let org = org.insert(&db_conn).await.context("failed saving org")?;
+
+    save_audit_log(
+        &db_conn,
+        Some(user.id),
+        "org.create",
+        "org",
+        Some(org.id),
+        None,
+        Some(json!(org)),
+    )
+    .await?;
+
+    info!("user #{} created org #{}", user.id, org.id);
+
+    Ok(Json(org).into_response())
+}
+
+/// `GET /user/orgs/:org_id/members` -- Use a bearer token to list an org's members.
+#[debug_handler]
+pub async fn org_members_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+) -> FrontendResult {
+    let (_caller, org_user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, Some(org_id), Role::Viewer)
+        .await?;
+
+    let db_replica = app.db_replica().context("getting db for org members")?;
+
+    let members = org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .find_also_related(user::Entity)
+        .all(db_replica.conn())
+        .await
+        .context("failed loading org members")?;
+
+    let members: Vec<_> = members
+        .into_iter()
+        .filter_map(|(member, user)| user.map(|user| json!({ "user": user, "role": member.role })))
+        .collect();
+
+    Ok(Json(json!({
+        "org_id": org_id,
+        "owner": org_user,
+        "members": members,
+    }))
+    .into_response())
+}
+
+/// `POST /user/orgs/:org_id/members` -- Use a bearer token to add a member to an org or change their role.
+///
+/// The new member must have logged in at least once.
+/// Admins can add admins and viewers. Only owners can add owners.
+#[debug_handler]
+pub async fn org_members_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+    Json(payload): Json<OrgMemberPost>,
+) -> FrontendResult {
+    let (caller, org, caller_role, _semaphore) = org_and_role(&app, bearer, org_id).await?;
+
+    if !role_allows(&caller_role, &Role::Admin) || !role_allows(&caller_role, &payload.role) {
+        return Err(FrontendErrorResponse::AccessDenied);
+    }
+
+    let db_conn = app.db_conn().context("adding org members requires a db")?;
+
+    let member_user = user::Entity::find()
+        .filter(user::Column::Address.eq(payload.address.as_bytes()))
+        .one(&db_conn)
+        .await
+        .context("failed loading user by address")?
+        .ok_or_else(|| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "members must log in once before they can be added to an org".to_string(),
+                None,
+            )
+        })?;
+
+    if member_user.id == org.user_id {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::BAD_REQUEST,
+            "the org's own user is always an owner".to_string(),
+            None,
+        ));
+    }
+
+    let existing = org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(org.id))
+        .filter(org_member::Column::UserId.eq(member_user.id))
+        .one(&db_conn)
+        .await
+        .context("failed loading org member")?;
+
+    let before = existing.as_ref().map(|x| json!(x));
+
+    let member = match existing {
+        Some(existing) => {
+            // admins cannot demote owners
+            if !role_allows(&caller_role, &existing.role) {
+                return Err(FrontendErrorResponse::AccessDenied);
+            }
+
+            let mut member = existing.into_active_model();
+
+            member.role = sea_orm::Set(payload.role);
+
+            member
+                .update(&db_conn)
+                .await
+                .context("failed saving org member")?
+        }
+        None => {
+            let member = org_member::ActiveModel {
+                org_id: sea_orm::Set(org.id),
+                user_id: sea_orm::Set(member_user.id),
+                role: sea_orm::Set(payload.role),
+                ..Default::default()
+            };
+
+            member
+                .insert(&db_conn)
+                .await
+                .context("failed saving org member")?
+        }
+    };
+
+    info!(
+        "user #{} added to org #{} as {:?}",
+        member.user_id, org.id, member.role
+    );
+
+    save_audit_log(
+        &db_conn,
+        Some(caller.id),
+        "org_member.set",
+        "org_member",
+        Some(member.id),
+        before,
+        Some(json!(member)),
+    )
+    .await?;
+
+    Ok(Json(member).into_response())
+}
+
+/// `DELETE /user/orgs/:org_id/members/:user_id` -- Use a bearer token to remove a member from an org.
+///
+/// Members can always remove themselves. Only owners can remove owners.
+#[debug_handler]
+pub async fn org_members_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path((org_id, user_id)): Path<(u64, u64)>,
+) -> FrontendResult {
+    let (caller, org, caller_role, _semaphore) = org_and_role(&app, bearer, org_id).await?;
+
+    let db_conn = app
+        .db_conn()
+        .context("removing org members requires a db")?;
+
+    let member = org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(org.id))
+        .filter(org_member::Column::UserId.eq(user_id))
+        .one(&db_conn)
+        .await
+        .context("failed loading org member")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    if caller.id != member.user_id
+        && !(role_allows(&caller_role, &Role::Admin) && role_allows(&caller_role, &member.role))
+    {
+        return Err(FrontendErrorResponse::AccessDenied);
+    }
+
+    member
+        .clone()
+        .into_active_model()
+        .delete(&db_conn)
+        .await
+        .context("failed removing org member")?;
+
+    info!("user #{} removed from org #{}", member.user_id, org.id);
+
+    save_audit_log(
+        &db_conn,
+        Some(caller.id),
+        "org_member.remove",
+        "org_member",
+        Some(member.id),
+        Some(json!(member)),
+        None,
+    )
+    .await?;
+
+    Ok(Json(member).into_response())
+}
+
+/// load the bearer's user, the org, and the bearer's role in the org
+async fn org_and_role(
+    app: &Web3ProxyApp,
+    bearer: Bearer,
+    org_id: u64,
+) -> Result<(user::Model, org::Model, Role, OwnedSemaphorePermit), FrontendErrorResponse> {
+    let (caller, semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    // the primary so that membership changes apply immediately
+    let db_conn = app.db_conn().context("getting db for org")?;
+
+    let org = org::Entity::find_by_id(org_id)
+        .one(&db_conn)
+        .await
+        .context("failed loading org")?
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    let caller_role = app
+        .org_role(&org, caller.id)
+        .await?
+        .ok_or(FrontendErrorResponse::AccessDenied)?;
+
+    Ok((caller, org, caller_role, semaphore))
+}
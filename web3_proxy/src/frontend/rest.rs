@@ -0,0 +1,97 @@
+//! REST-style endpoints for things that are awkward with plain JSON-RPC.
+
+use super::authorization::{
+    internal_is_authorized, ip_is_authorized, key_is_authorized, rpc_key_from_request,
+};
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use super::rpc_proxy_http::add_response_headers;
+use crate::app::{MulticallCall, Web3ProxyApp};
+use axum::extract::Query;
+use axum::headers::{Origin, Referer, UserAgent};
+use axum::TypedHeader;
+use axum::{response::IntoResponse, Extension, Json};
+use axum_client_ip::ClientIp;
+use axum_macros::debug_handler;
+use hashbrown::HashMap;
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+/// The most calls accepted by one `/rest/multicall` request.
+pub const MAX_MULTICALL_CALLS: usize = 5_000;
+
+fn default_block() -> serde_json::Value {
+    json!("latest")
+}
+
+/// the JSON input to the `multicall_post` handler.
+#[derive(Debug, Deserialize)]
+pub struct MulticallPost {
+    calls: Vec<MulticallCall>,
+    /// a block number, hash, or tag. defaults to "latest"
+    #[serde(default = "default_block")]
+    block: serde_json::Value,
+}
+
+/// `POST /rest/multicall` -- Run many `eth_call`s through the Multicall3 contract.
+///
+/// Results are returned in the same order as the calls. A failed call does not fail the others.
+/// Authorized the same way as `POST /rpc`.
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn multicall_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ClientIp(ip): ClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    Json(payload): Json<MulticallPost>,
+) -> FrontendResult {
+    if payload.calls.len() > MAX_MULTICALL_CALLS {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("at most {} calls are allowed", MAX_MULTICALL_CALLS),
+            None,
+        ));
+    }
+
+    let origin = origin.map(|x| x.0);
+
+    let (authorization, _semaphore) = match rpc_key_from_request(&headers, &params)? {
+        Some(rpc_key) => {
+            key_is_authorized(
+                &app,
+                rpc_key,
+                ip,
+                origin,
+                referer.map(|x| x.0),
+                user_agent.map(|x| x.0),
+                false,
+            )
+            .await?
+        }
+        None => match internal_is_authorized(&app, &headers, ip)? {
+            Some(authorization) => (authorization, None),
+            None => ip_is_authorized(&app, ip, origin, false).await?,
+        },
+    };
+
+    let authorization = Arc::new(authorization);
+
+    let (results, rpcs) = app
+        .multicall(&authorization, payload.calls, payload.block.clone())
+        .await?;
+
+    let mut response = Json(json!({
+        "block": payload.block,
+        "results": results,
+    }))
+    .into_response();
+
+    add_response_headers(&app, &mut response, rpcs);
+
+    Ok(response)
+}
@@ -1,32 +1,102 @@
 //! Take a user's HTTP JSON-RPC requests and either respond from local data or proxy the request to a backend rpc server.
 
-use super::authorization::{ip_is_authorized, key_is_authorized};
-use super::errors::FrontendResult;
+use super::authorization::{rpc_is_authorized, signature_is_authorized};
+use super::compression::CachedResponse;
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use crate::rpcs::connection::Web3Connection;
 use crate::{app::Web3ProxyApp, jsonrpc::JsonRpcRequestEnum};
-use axum::extract::Path;
+use axum::body::Bytes;
+use axum::extract::{Path, Query};
 use axum::headers::{Origin, Referer, UserAgent};
+use axum::response::{IntoResponse, Response};
 use axum::TypedHeader;
-use axum::{response::IntoResponse, Extension, Json};
+use axum::{Extension, Json};
 use axum_client_ip::ClientIp;
 use axum_macros::debug_handler;
+use hashbrown::HashMap;
+use http::{HeaderMap, HeaderValue, StatusCode};
 use itertools::Itertools;
+use std::net::IpAddr;
 use std::sync::Arc;
 
-/// POST /rpc -- Public entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
+/// `POST /` or `POST /rpc` -- Public entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
 /// Defaults to rate limiting by IP address, but can also read the Authorization header for a bearer token.
+/// An rpc key can be given with the `X-API-Key` header or the `key` query param.
 /// If possible, please use a WebSocket instead.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> FrontendResult {
-    // TODO: benchmark spawning this
-    // TODO: do we care about keeping the TypedHeader wrapper?
-    let origin = origin.map(|x| x.0);
+    authorize_and_proxy(
+        app, ip, None, origin, referer, user_agent, headers, params, payload,
+    )
+    .await
+}
 
-    let (authorization, semaphore) = ip_is_authorized(&app, ip, origin).await?;
+/// `POST /rpc/:rpc_key` -- Authenticated entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
+/// Rate limit and billing based on the api key in the url.
+/// Can optionally authorized based on origin, referer, or user agent.
+/// If possible, please use a WebSocket instead.
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn proxy_web3_rpc_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ClientIp(ip): ClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    Path(rpc_key): Path<String>,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> FrontendResult {
+    authorize_and_proxy(
+        app,
+        ip,
+        Some(rpc_key),
+        origin,
+        referer,
+        user_agent,
+        headers,
+        params,
+        payload,
+    )
+    .await
+}
+
+/// The websocket handlers authorize the same way. See `rpc_is_authorized`.
+#[allow(clippy::too_many_arguments)]
+async fn authorize_and_proxy(
+    app: Arc<Web3ProxyApp>,
+    ip: IpAddr,
+    rpc_key: Option<String>,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    params: HashMap<String, String>,
+    payload: JsonRpcRequestEnum,
+) -> FrontendResult {
+    let (authorization, semaphore) = rpc_is_authorized(
+        &app,
+        ip,
+        rpc_key,
+        origin.map(|x| x.0),
+        referer.map(|x| x.0),
+        user_agent.map(|x| x.0),
+        &headers,
+        &params,
+        payload.is_priority(),
+    )
+    .await?;
 
     let authorization = Arc::new(authorization);
 
@@ -37,45 +107,67 @@ pub async fn proxy_web3_rpc(
 
     let mut response = Json(&response).into_response();
 
-    let headers = response.headers_mut();
-
-    // TODO: this might be slow. think about this more
-    // TODO: special string if no rpcs were used (cache hit)?
-    let rpcs: String = rpcs.into_iter().map(|x| x.name.clone()).join(",");
-
-    headers.insert(
-        "W3P-BACKEND-RPCs",
-        rpcs.parse().expect("W3P-BACKEND-RPCS should always parse"),
-    );
+    add_response_headers(&app, &mut response, rpcs);
 
     Ok(response)
 }
 
-/// Authenticated entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
-/// Rate limit and billing based on the api key in the url.
-/// Can optionally authorized based on origin, referer, or user agent.
-/// If possible, please use a WebSocket instead.
+/// `POST /rpc/signed` -- Authenticated entrypoint for HTTP JSON-RPC requests from clients that can't put their key in the url.
+/// Requires the `X-Rpc-Key-Id`, `X-Timestamp`, and `X-Signature` headers.
+/// The signature is a hex encoded HMAC-SHA256 of `"{timestamp}.{body}"` keyed with the rpc key as `/user/keys` shows it, including any vanity prefix.
+/// Rate limits and billing are the same as `proxy_web3_rpc_with_key`.
 #[debug_handler]
-pub async fn proxy_web3_rpc_with_key(
+pub async fn proxy_web3_rpc_with_signature(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
-    Path(rpc_key): Path<String>,
-    Json(payload): Json<JsonRpcRequestEnum>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> FrontendResult {
-    // TODO: DRY w/ proxy_web3_rpc
-    // the request can take a while, so we spawn so that we can start serving another request
-    let rpc_key = rpc_key.parse()?;
-
-    let (authorization, semaphore) = key_is_authorized(
+    let rpc_key_id = required_header(&headers, "X-Rpc-Key-Id")?
+        .parse::<u64>()
+        .map_err(|err| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "X-Rpc-Key-Id must be an integer".to_string(),
+                Some(err.into()),
+            )
+        })?;
+
+    let timestamp = required_header(&headers, "X-Timestamp")?
+        .parse::<i64>()
+        .map_err(|err| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::BAD_REQUEST,
+                "X-Timestamp must be a unix timestamp".to_string(),
+                Some(err.into()),
+            )
+        })?;
+
+    let signature = required_header(&headers, "X-Signature")?;
+
+    let payload: JsonRpcRequestEnum = serde_json::from_slice(&body).map_err(|err| {
+        FrontendErrorResponse::StatusCode(
+            StatusCode::BAD_REQUEST,
+            "invalid json-rpc request".to_string(),
+            Some(err.into()),
+        )
+    })?;
+
+    // the signature has to be checked against the raw body, so we can't use the Json extractor
+    let (authorization, semaphore) = signature_is_authorized(
         &app,
-        rpc_key,
+        rpc_key_id,
+        timestamp,
+        signature,
+        &body,
         ip,
         origin.map(|x| x.0),
         referer.map(|x| x.0),
         user_agent.map(|x| x.0),
+        payload.is_priority(),
     )
     .await?;
 
@@ -88,15 +180,76 @@ pub async fn proxy_web3_rpc_with_key(
 
     let mut response = Json(&response).into_response();
 
+    add_response_headers(&app, &mut response, rpcs);
+
+    Ok(response)
+}
+
+/// Tell the client how their request was served. Useful for debugging.
+/// Deployments that care about privacy can turn this off with `response_headers = false`.
+pub fn add_response_headers(
+    app: &Web3ProxyApp,
+    response: &mut Response,
+    rpcs: Vec<Arc<Web3Connection>>,
+) {
+    // if no backends were used, the response came from a cache or from the proxy itself
+    if rpcs.is_empty() {
+        response.extensions_mut().insert(CachedResponse);
+    }
+
     let headers = response.headers_mut();
 
-    // TODO: special string if no rpcs were used (cache hit)? or is an empty string fine? maybe the rpc name + "cached"
-    let rpcs: String = rpcs.into_iter().map(|x| x.name.clone()).join(",");
+    // this is a warning, not debugging info. send it even if the other headers are off
+    if let Some(age) = app.chain_halted() {
+        headers.insert("X-W3P-Stale", HeaderValue::from(age));
+    }
+
+    if !app.config.response_headers {
+        return;
+    }
+
+    // TODO: this might be slow. think about this more
+    let rpcs: String = rpcs.into_iter().map(|x| x.name.clone()).unique().join(",");
+
+    // if no backends were used, the response came from a cache or from the proxy itself
+    let cache = if rpcs.is_empty() { "hit" } else { "miss" };
 
+    let head_block_num = app
+        .balanced_rpcs
+        .head_block_num()
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+
+    headers.insert(
+        "X-W3P-Backend",
+        rpcs.parse().expect("X-W3P-Backend should always parse"),
+    );
+    headers.insert("X-W3P-Cache", HeaderValue::from_static(cache));
+    headers.insert(
+        "X-W3P-Block",
+        head_block_num
+            .parse()
+            .expect("X-W3P-Block should always parse"),
+    );
+
+    // older clients read this name
     headers.insert(
         "W3P-BACKEND-RPCs",
         rpcs.parse().expect("W3P-BACKEND-RPCS should always parse"),
     );
+}
 
-    Ok(response)
+fn required_header<'a>(
+    headers: &'a HeaderMap,
+    name: &str,
+) -> Result<&'a str, FrontendErrorResponse> {
+    let value = headers.get(name).ok_or_else(|| {
+        FrontendErrorResponse::StatusCode(
+            StatusCode::BAD_REQUEST,
+            format!("{} header required", name),
+            None,
+        )
+    })?;
+
+    Ok(value.to_str()?)
 }
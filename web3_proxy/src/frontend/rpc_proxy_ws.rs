@@ -2,74 +2,72 @@
 //!
 //! WebSockets are the preferred method of receiving requests, but not all clients have good support.
 
-use super::authorization::{ip_is_authorized, key_is_authorized, Authorization, RequestMetadata};
+use super::authorization::{rpc_is_authorized, Authorization, RequestMetadata};
 use super::errors::{FrontendErrorResponse, FrontendResult};
-use crate::app::REQUEST_PERIOD;
+use crate::app::{
+    WebsocketGuard, WebsocketSubscriptions, NO_SERVERS_SYNCED_ERROR_CODE,
+    NO_SERVERS_SYNCED_MESSAGE, REQUEST_PERIOD,
+};
 use crate::app_stats::ProxyResponseStat;
 use crate::{
     app::Web3ProxyApp,
-    jsonrpc::{JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest},
+    jsonrpc::{
+        JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest, JsonRpcRequestEnum,
+    },
 };
 use axum::headers::{Origin, Referer, UserAgent};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::Path,
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{Path, Query},
     response::{IntoResponse, Redirect},
     Extension, TypedHeader,
 };
 use axum_client_ip::ClientIp;
 use axum_macros::debug_handler;
+use deferred_rate_limiter::DeferredRateLimitResult;
+use futures::stream::{SplitSink, SplitStream, StreamExt};
 use futures::SinkExt;
-use futures::{
-    future::AbortHandle,
-    stream::{SplitSink, SplitStream, StreamExt},
-};
 use handlebars::Handlebars;
 use hashbrown::HashMap;
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use log::{error, info, trace, warn};
 use serde_json::json;
-use serde_json::value::to_raw_value;
+use serde_json::value::{to_raw_value, RawValue};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::IpAddr;
+use std::str::from_utf8_mut;
 use std::sync::Arc;
-use std::{str::from_utf8_mut, sync::atomic::AtomicUsize};
+use tokio::time::Instant;
+
+/// how many messages a client can send while it waits for an answer before it is disconnected
+const MAX_QUEUED_MESSAGES: usize = 100;
 
-/// Public entrypoint for WebSocket JSON-RPC requests.
+/// `GET /` or `GET /rpc` -- Public entrypoint for WebSocket JSON-RPC requests.
+/// An rpc key can be given with the `X-API-Key` header or the `key` query param.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
-    let origin = origin.map(|x| x.0);
-
-    let (authorization, _semaphore) = ip_is_authorized(&app, ip, origin).await?;
-
-    let authorization = Arc::new(authorization);
-
-    match ws_upgrade {
-        Some(ws) => Ok(ws
-            .on_upgrade(|socket| proxy_web3_socket(app, authorization, socket))
-            .into_response()),
-        None => {
-            if let Some(redirect) = &app.config.redirect_public_url {
-                // this is not a websocket. redirect to a friendly page
-                Ok(Redirect::permanent(redirect).into_response())
-            } else {
-                // TODO: do not use an anyhow error. send the user a 400
-                Err(
-                    anyhow::anyhow!("redirect_public_url not set. only websockets work here")
-                        .into(),
-                )
-            }
-        }
-    }
+    authorize_and_upgrade(
+        app, ip, None, origin, referer, user_agent, headers, params, ws_upgrade,
+    )
+    .await
 }
 
-/// Authenticated entrypoint for WebSocket JSON-RPC requests. Web3 wallets use this.
+/// `GET /rpc/:rpc_key` -- Authenticated entrypoint for WebSocket JSON-RPC requests. Web3 wallets use this.
 /// Rate limit and billing based on the api key in the url.
 /// Can optionally authorized based on origin, referer, or user agent.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ClientIp(ip): ClientIp,
@@ -77,23 +75,79 @@ pub async fn websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> FrontendResult {
-    let rpc_key = rpc_key.parse()?;
+    authorize_and_upgrade(
+        app,
+        ip,
+        Some(rpc_key),
+        origin,
+        referer,
+        user_agent,
+        headers,
+        params,
+        ws_upgrade,
+    )
+    .await
+}
+
+/// The HTTP handlers authorize the same way. See `rpc_is_authorized`.
+#[allow(clippy::too_many_arguments)]
+async fn authorize_and_upgrade(
+    app: Arc<Web3ProxyApp>,
+    ip: IpAddr,
+    rpc_key: Option<String>,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    params: HashMap<String, String>,
+    ws_upgrade: Option<WebSocketUpgrade>,
+) -> FrontendResult {
+    if app.is_draining() {
+        return Err(FrontendErrorResponse::StatusCode(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting down".to_string(),
+            None,
+        ));
+    }
 
-    let (authorization, _semaphore) = key_is_authorized(
+    let origin = origin.map(|x| x.0);
+
+    // saved for the redirect
+    let redirect_origin = origin.clone();
+
+    let authorization = rpc_is_authorized(
         &app,
-        rpc_key,
         ip,
-        origin.map(|x| x.0),
+        rpc_key,
+        origin,
         referer.map(|x| x.0),
         user_agent.map(|x| x.0),
+        &headers,
+        &params,
+        false,
     )
-    .await?;
+    .await;
+
+    let (authorization, _semaphore) = match authorization {
+        Err(FrontendErrorResponse::KeyRequired) if ws_upgrade.is_none() => {
+            // browsers still get the friendly page
+            return match redirect_public_url(&app, redirect_origin.as_ref()) {
+                Some(redirect_public_url) => {
+                    Ok(Redirect::permanent(&redirect_public_url).into_response())
+                }
+                None => Err(FrontendErrorResponse::KeyRequired),
+            };
+        }
+        x => x?,
+    };
 
-    trace!("websocket_handler_with_key {:?}", authorization);
+    trace!("websocket authorized {:?}", authorization);
 
-    let authorization = Arc::new(authorization);
+    let authorization = Arc::new(authorization.websocket());
 
     match ws_upgrade {
         Some(ws_upgrade) => {
@@ -102,42 +156,26 @@ pub async fn websocket_handler_with_key(
         None => {
             // if no websocket upgrade, this is probably a user loading the url with their browser
 
-            // TODO: rate limit here? key_is_authorized might be enough
+            // TODO: rate limit here? rpc_is_authorized might be enough
 
             match (
-                &app.config.redirect_public_url,
+                redirect_public_url(&app, redirect_origin.as_ref()),
                 &app.config.redirect_rpc_key_url,
                 authorization.checks.rpc_key_id,
             ) {
-                (None, None, _) => Err(FrontendErrorResponse::StatusCode(
-                    StatusCode::BAD_REQUEST,
-                    "this page is for rpcs".to_string(),
-                    None,
-                )),
                 (Some(redirect_public_url), _, None) => {
-                    Ok(Redirect::permanent(redirect_public_url).into_response())
+                    // this is not a websocket. redirect to a friendly page
+                    Ok(Redirect::permanent(&redirect_public_url).into_response())
                 }
-                (_, Some(redirect_rpc_key_url), rpc_key_id) => {
+                (_, Some(redirect_rpc_key_url), Some(rpc_key_id)) => {
                     let reg = Handlebars::new();
 
-                    if authorization.checks.rpc_key_id.is_none() {
-                        // i don't think this is possible
-                        Err(FrontendErrorResponse::StatusCode(
-                            StatusCode::UNAUTHORIZED,
-                            "AUTHORIZATION header required".to_string(),
-                            None,
-                        ))
-                    } else {
-                        let redirect_rpc_key_url = reg
-                            .render_template(
-                                redirect_rpc_key_url,
-                                &json!({ "rpc_key_id": rpc_key_id }),
-                            )
-                            .expect("templating should always work");
-
-                        // this is not a websocket. redirect to a page for this user
-                        Ok(Redirect::permanent(&redirect_rpc_key_url).into_response())
-                    }
+                    let redirect_rpc_key_url = reg
+                        .render_template(redirect_rpc_key_url, &json!({ "rpc_key_id": rpc_key_id }))
+                        .expect("templating should always work");
+
+                    // this is not a websocket. redirect to a page for this user
+                    Ok(Redirect::permanent(&redirect_rpc_key_url).into_response())
                 }
                 // any other combinations get a simple error
                 _ => Err(FrontendErrorResponse::StatusCode(
@@ -150,6 +188,26 @@ pub async fn websocket_handler_with_key(
     }
 }
 
+/// The stats page for an anonymous user. Some origins get their own page.
+fn redirect_public_url(app: &Web3ProxyApp, origin: Option<&Origin>) -> Option<String> {
+    if let Some(origin) = origin {
+        let origin = origin.to_string();
+
+        if let Some(template) = app.config.allowed_origin_redirect_public_urls.get(&origin) {
+            let reg = Handlebars::new();
+
+            match reg.render_template(template, &json!({ "origin": origin })) {
+                Ok(x) => return Some(x),
+                Err(err) => {
+                    warn!("bad redirect template for {}. err={:?}", origin, err);
+                }
+            }
+        }
+    }
+
+    app.config.redirect_public_url.clone()
+}
+
 async fn proxy_web3_socket(
     app: Arc<Web3ProxyApp>,
     authorization: Arc<Authorization>,
@@ -161,7 +219,10 @@ async fn proxy_web3_socket(
     // create a channel for our reader and writer can communicate. todo: benchmark different channels
     let (response_sender, response_receiver) = flume::unbounded::<Message>();
 
-    tokio::spawn(write_web3_socket(response_receiver, ws_tx));
+    // the writer is the last to finish, so it holds the guard
+    let websocket_guard = app.websocket_opened();
+
+    tokio::spawn(write_web3_socket(response_receiver, ws_tx, websocket_guard));
     tokio::spawn(read_web3_socket(app, authorization, ws_rx, response_sender));
 }
 
@@ -171,119 +232,225 @@ async fn handle_socket_payload(
     authorization: &Arc<Authorization>,
     payload: &str,
     response_sender: &flume::Sender<Message>,
-    subscription_count: &AtomicUsize,
-    subscriptions: &mut HashMap<String, AbortHandle>,
+    subscriptions: &mut WebsocketSubscriptions,
 ) -> Message {
-    // TODO: do any clients send batches over websockets?
-    let (id, response) = match serde_json::from_str::<JsonRpcRequest>(payload) {
-        Ok(json_request) => {
-            let id = json_request.id.clone();
-
-            let response: anyhow::Result<JsonRpcForwardedResponseEnum> = match &json_request.method
-                [..]
-            {
-                "eth_subscribe" => {
-                    match app
-                        .eth_subscribe(
-                            authorization.clone(),
-                            json_request,
-                            subscription_count,
-                            response_sender.clone(),
-                        )
-                        .await
+    let response = match serde_json::from_str::<JsonRpcRequestEnum>(payload) {
+        Ok(JsonRpcRequestEnum::Single(json_request)) => {
+            handle_socket_request(
+                &app,
+                authorization,
+                json_request,
+                response_sender,
+                subscriptions,
+            )
+            .await
+        }
+        Ok(JsonRpcRequestEnum::Batch(json_requests)) => {
+            if json_requests.is_empty() || json_requests.len() > app.config.max_batch_size {
+                JsonRpcForwardedResponse::from_string(
+                    format!(
+                        "batches must have 1 to {} requests",
+                        app.config.max_batch_size
+                    ),
+                    Some(-32600),
+                    None,
+                )
+                .into()
+            } else {
+                let mut responses = Vec::with_capacity(json_requests.len());
+
+                // one at a time because eth_subscribe and eth_unsubscribe change `subscriptions`
+                for json_request in json_requests {
+                    match handle_socket_request(
+                        &app,
+                        authorization,
+                        json_request,
+                        response_sender,
+                        subscriptions,
+                    )
+                    .await
                     {
-                        Ok((handle, response)) => {
-                            // TODO: better key
-                            subscriptions.insert(
-                                response
-                                    .result
-                                    .as_ref()
-                                    // TODO: what if there is an error?
-                                    .expect("response should always have a result, not an error")
-                                    .to_string(),
-                                handle,
-                            );
-
-                            Ok(response.into())
-                        }
-                        Err(err) => Err(err),
+                        JsonRpcForwardedResponseEnum::Single(x) => responses.push(x),
+                        JsonRpcForwardedResponseEnum::Batch(x) => responses.extend(x),
                     }
                 }
-                "eth_unsubscribe" => {
-                    // TODO: move this logic into the app?
-                    let request_bytes = json_request.num_bytes();
 
-                    let request_metadata =
-                        Arc::new(RequestMetadata::new(REQUEST_PERIOD, request_bytes).unwrap());
+                JsonRpcForwardedResponseEnum::Batch(responses)
+            }
+        }
+        Err(err) => {
+            let id =
+                to_raw_value(&json!(None::<Option::<()>>)).expect("None can always be a RawValue");
+
+            JsonRpcForwardedResponse::from_anyhow_error(err.into(), None, Some(id)).into()
+        }
+    };
 
-                    let subscription_id = json_request.params.unwrap().to_string();
+    let response_str = serde_json::to_string(&response).expect("to_string should always work here");
 
-                    // TODO: is this the right response?
-                    let partial_response = match subscriptions.remove(&subscription_id) {
-                        None => false,
-                        Some(handle) => {
-                            handle.abort();
-                            true
-                        }
-                    };
+    Message::Text(response_str)
+}
 
-                    let response =
-                        JsonRpcForwardedResponse::from_value(json!(partial_response), id.clone());
+/// One request from a websocket. Batches call this for each of their requests.
+async fn handle_socket_request(
+    app: &Arc<Web3ProxyApp>,
+    authorization: &Arc<Authorization>,
+    json_request: JsonRpcRequest,
+    response_sender: &flume::Sender<Message>,
+    subscriptions: &mut WebsocketSubscriptions,
+) -> JsonRpcForwardedResponseEnum {
+    let id = json_request.id.clone();
 
-                    if let Some(stat_sender) = app.stat_sender.as_ref() {
-                        let response_stat = ProxyResponseStat::new(
-                            json_request.method.clone(),
-                            authorization.clone(),
-                            request_metadata,
-                            response.num_bytes(),
-                        );
+    if let Some(response) = websocket_rate_limited(app, authorization, &id).await {
+        return response.into();
+    }
 
-                        if let Err(err) = stat_sender.send_async(response_stat.into()).await {
-                            // TODO: what should we do?
-                            warn!("stat_sender failed during eth_unsubscribe: {:?}", err);
-                        }
-                    }
+    let response: anyhow::Result<JsonRpcForwardedResponseEnum> = match &json_request.method[..] {
+        "eth_subscribe" => {
+            let subscription_id = subscriptions.new_id(app.config.subscription_ids);
+
+            match app
+                .eth_subscribe(
+                    authorization.clone(),
+                    json_request,
+                    subscription_id,
+                    response_sender.clone(),
+                )
+                .await
+            {
+                Ok((subscription_key, response)) => {
+                    subscriptions.insert(subscription_id, subscription_key);
 
                     Ok(response.into())
                 }
-                _ => app
-                    .proxy_web3_rpc(authorization.clone(), json_request.into())
-                    .await
-                    .map_or_else(
-                        |err| match err {
-                            FrontendErrorResponse::Anyhow(err) => Err(err),
-                            _ => {
-                                error!("handle this better! {:?}", err);
-                                Err(anyhow::anyhow!("unexpected error! {:?}", err))
-                            }
-                        },
-                        |(response, _)| Ok(response),
-                    ),
+                Err(err) => Err(err),
+            }
+        }
+        "eth_unsubscribe" => {
+            // TODO: move this logic into the app?
+            let request_bytes = json_request.num_bytes();
+
+            let request_metadata =
+                Arc::new(RequestMetadata::new(REQUEST_PERIOD, request_bytes).unwrap());
+
+            let response = match subscriptions.remove(json_request.params.as_ref()) {
+                Ok(subscription_key) => {
+                    // the subscription's task may have already stopped. it's gone either way
+                    app.eth_unsubscribe(&subscription_key);
+
+                    JsonRpcForwardedResponse::from_value(json!(true), id.clone())
+                }
+                Err(err) => JsonRpcForwardedResponse::from_string(
+                    err.message,
+                    Some(err.code),
+                    Some(id.clone()),
+                ),
             };
 
-            (id, response)
-        }
-        Err(err) => {
-            // TODO: move this logic somewhere else and just set id to None here
-            let id =
-                to_raw_value(&json!(None::<Option::<()>>)).expect("None can always be a RawValue");
-            (id, Err(err.into()))
+            if let Some(stat_sender) = app.stat_sender.as_ref() {
+                let response_stat = ProxyResponseStat::new(
+                    json_request.method.clone(),
+                    authorization.clone(),
+                    request_metadata,
+                    response.num_bytes(),
+                );
+
+                if let Err(err) = stat_sender.send_async(response_stat.into()).await {
+                    // TODO: what should we do?
+                    warn!("stat_sender failed during eth_unsubscribe: {:?}", err);
+                }
+            }
+
+            Ok(response.into())
         }
+        _ => app
+            .proxy_web3_rpc(authorization.clone(), json_request.into())
+            .await
+            .map_or_else(
+                |err| match err {
+                    FrontendErrorResponse::Anyhow(err) => Err(err),
+                    FrontendErrorResponse::NoServersSynced => {
+                        Ok(JsonRpcForwardedResponse::from_str(
+                            NO_SERVERS_SYNCED_MESSAGE,
+                            Some(NO_SERVERS_SYNCED_ERROR_CODE),
+                            Some(id.clone()),
+                        )
+                        .into())
+                    }
+                    _ => {
+                        error!("handle this better! {:?}", err);
+                        Err(anyhow::anyhow!("unexpected error! {:?}", err))
+                    }
+                },
+                |(response, _)| Ok(response),
+            ),
     };
 
-    let response_str = match response {
-        Ok(x) => serde_json::to_string(&x).expect("to_string should always work here"),
+    match response {
+        Ok(x) => x,
         Err(err) => {
             // we have an anyhow error. turn it into a response
-            let response = JsonRpcForwardedResponse::from_anyhow_error(err, None, Some(id));
-
-            serde_json::to_string(&response).expect("to_string should always work here")
+            JsonRpcForwardedResponse::from_anyhow_error(err, None, Some(id)).into()
         }
+    }
+}
+
+/// An error for a message that is over the rate limit. The websocket stays open.
+async fn websocket_rate_limited(
+    app: &Web3ProxyApp,
+    authorization: &Authorization,
+    id: &RawValue,
+) -> Option<JsonRpcForwardedResponse> {
+    let retry_msg = match app.rate_limit_websocket_request(authorization).await {
+        DeferredRateLimitResult::Allowed => return None,
+        DeferredRateLimitResult::RetryAt(retry_at) => format!(
+            " Retry in {} seconds",
+            retry_at.saturating_duration_since(Instant::now()).as_secs()
+        ),
+        DeferredRateLimitResult::RetryNever => "".to_string(),
     };
 
-    // TODO: what error should this be?
+    let response = JsonRpcForwardedResponse::from_string(
+        format!("too many requests.{}", retry_msg),
+        Some(StatusCode::TOO_MANY_REQUESTS.as_u16().into()),
+        Some(id.to_owned()),
+    );
 
-    Message::Text(response_str)
+    Some(response)
+}
+
+/// Run `f` unless the client disconnects first. Messages that arrive in the meantime are queued.
+/// A client that sends more than `MAX_QUEUED_MESSAGES` without waiting for answers is disconnected.
+/// Returning None drops `f`, which cancels its upstream requests.
+async fn unless_disconnected<F: Future>(
+    f: F,
+    ws_rx: &mut SplitStream<WebSocket>,
+    queued: &mut VecDeque<Message>,
+    response_sender: &flume::Sender<Message>,
+) -> Option<F::Output> {
+    tokio::pin!(f);
+
+    loop {
+        tokio::select! {
+            x = &mut f => return Some(x),
+            msg = ws_rx.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) if queued.len() >= MAX_QUEUED_MESSAGES => {
+                    warn!("closing websocket with {} queued messages", queued.len());
+
+                    let close = Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "too many queued requests".into(),
+                    }));
+
+                    let _ = response_sender.send_async(close).await;
+
+                    return None;
+                }
+                Some(Ok(msg)) => queued.push_back(msg),
+            },
+        }
+    }
 }
 
 async fn read_web3_socket(
@@ -292,23 +459,62 @@ async fn read_web3_socket(
     mut ws_rx: SplitStream<WebSocket>,
     response_sender: flume::Sender<Message>,
 ) {
-    let mut subscriptions = HashMap::new();
-    let subscription_count = AtomicUsize::new(1);
+    let mut subscriptions = WebsocketSubscriptions::default();
+    // messages that arrived while we were busy with a request
+    let mut queued = VecDeque::new();
+
+    // admins can close every websocket for a key
+    let rpc_key_id = authorization.checks.rpc_key_id;
+    let mut kick_receiver = app.websocket_kick_sender.subscribe();
+
+    loop {
+        let msg = match queued.pop_front() {
+            Some(msg) => msg,
+            None => tokio::select! {
+                msg = ws_rx.next() => match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => break,
+                },
+                kicked = kick_receiver.recv() => {
+                    match kicked {
+                        Ok(kicked) if Some(kicked) == rpc_key_id => {
+                            info!("closing websocket for rpc key #{}", kicked);
+
+                            let close = Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "closed by an admin".into(),
+                            }));
+
+                            let _ = response_sender.send_async(close).await;
+
+                            break;
+                        }
+                        // a different key. or we lagged behind and missed some kicks
+                        _ => continue,
+                    }
+                }
+            },
+        };
 
-    while let Some(Ok(msg)) = ws_rx.next().await {
         // TODO: spawn this?
         // new message from our client. forward to a backend and then send it through response_tx
         let response_msg = match msg {
             Message::Text(payload) => {
-                handle_socket_payload(
+                let f = handle_socket_payload(
                     app.clone(),
                     &authorization,
                     &payload,
                     &response_sender,
-                    &subscription_count,
                     &mut subscriptions,
-                )
-                .await
+                );
+
+                match unless_disconnected(f, &mut ws_rx, &mut queued, &response_sender).await {
+                    Some(x) => x,
+                    None => {
+                        trace!("websocket client disconnected during a request");
+                        break;
+                    }
+                }
             }
             Message::Ping(x) => {
                 trace!("ping: {:?}", x);
@@ -324,17 +530,39 @@ async fn read_web3_socket(
             }
             Message::Binary(mut payload) => {
                 // TODO: poke rate limit for the user/ip
-                let payload = from_utf8_mut(&mut payload).unwrap();
+                match from_utf8_mut(&mut payload) {
+                    Ok(payload) => {
+                        let f = handle_socket_payload(
+                            app.clone(),
+                            &authorization,
+                            payload,
+                            &response_sender,
+                            &mut subscriptions,
+                        );
 
-                handle_socket_payload(
-                    app.clone(),
-                    &authorization,
-                    payload,
-                    &response_sender,
-                    &subscription_count,
-                    &mut subscriptions,
-                )
-                .await
+                        match unless_disconnected(f, &mut ws_rx, &mut queued, &response_sender)
+                            .await
+                        {
+                            Some(x) => x,
+                            None => {
+                                trace!("websocket client disconnected during a request");
+                                break;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        let response = JsonRpcForwardedResponse::from_string(
+                            format!("binary messages must be utf8. err={}", err),
+                            Some(-32700),
+                            None,
+                        );
+
+                        Message::Text(
+                            serde_json::to_string(&response)
+                                .expect("to_string should always work here"),
+                        )
+                    }
+                }
             }
         };
 
@@ -346,14 +574,19 @@ async fn read_web3_socket(
             }
         };
     }
+
+    // the app keeps subscriptions alive across backend changes, so they need to be stopped here
+    for subscription_key in subscriptions.keys() {
+        app.eth_unsubscribe(subscription_key);
+    }
 }
 
 async fn write_web3_socket(
     response_rx: flume::Receiver<Message>,
     mut ws_tx: SplitSink<WebSocket, Message>,
+    // counts this websocket as open until it is dropped
+    _websocket_guard: WebsocketGuard,
 ) {
-    // TODO: increment counter for open websockets
-
     // TODO: is there any way to make this stream receive.
     while let Ok(msg) = response_rx.recv_async().await {
         // a response is ready
@@ -367,6 +600,4 @@ async fn write_web3_socket(
             break;
         };
     }
-
-    // TODO: decrement counter for open websockets
 }
@@ -3,25 +3,87 @@
 //! For ease of development, users can currently access these endponts.
 //! They will eventually move to another port.
 
+use super::authorization::rpc_is_authorized;
+use super::compression::CachedResponse;
+use super::errors::{FrontendErrorResponse, FrontendResult};
 use super::{FrontendResponseCache, FrontendResponseCaches};
 use crate::app::Web3ProxyApp;
+use axum::extract::{Path, Query};
+use axum::headers::Origin;
+use axum::http::HeaderMap;
+use axum::TypedHeader;
 use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use axum_client_ip::ClientIp;
 use axum_macros::debug_handler;
+use ethers::prelude::TxHash;
+use hashbrown::HashMap;
 use moka::future::ConcurrentCacheExt;
 use serde_json::json;
 use std::sync::Arc;
 
-/// Health check page for load balancers to use.
+/// `GET /health` -- Health check page for load balancers to use.
+/// Only draining or having no synced balanced rpcs fails it. Use `/ready` to also check the database and redis.
 #[debug_handler]
 pub async fn health(Extension(app): Extension<Arc<Web3ProxyApp>>) -> impl IntoResponse {
-    // TODO: add a check that we aren't shutting down
-    if app.balanced_rpcs.synced() {
+    if app.is_draining() {
+        // load balancers should stop sending us traffic
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else if app.balanced_rpcs.synced() {
         (StatusCode::OK, "OK")
     } else {
         (StatusCode::SERVICE_UNAVAILABLE, ":(")
     }
 }
 
+/// `GET /ready` -- Readiness probe for kubernetes.
+/// A 503 while draining or while a dependency is down. The body says which one.
+#[debug_handler]
+pub async fn ready(Extension(app): Extension<Arc<Web3ProxyApp>>) -> impl IntoResponse {
+    let report = app.readiness().await;
+
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        // load balancers should stop sending us traffic
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report))
+}
+
+/// `GET /live` -- Liveness probe. The process is answering. Failing dependencies are for `/ready`, not a restart.
+#[debug_handler]
+pub async fn live(Extension(app): Extension<Arc<Web3ProxyApp>>) -> impl IntoResponse {
+    Json(json!({
+        "live": true,
+        "uptime_seconds": app.start.elapsed().as_secs(),
+    }))
+}
+
+/// `GET /startup` -- Startup probe. Passes once the proxy has been ready, so slow backends don't get it killed.
+#[debug_handler]
+pub async fn startup(Extension(app): Extension<Arc<Web3ProxyApp>>) -> impl IntoResponse {
+    if app.started() {
+        return (StatusCode::OK, Json(json!({ "started": true })));
+    }
+
+    let report = app.readiness().await;
+
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "started": report.ready,
+            "readiness": report,
+        })),
+    )
+}
+
 /// Very basic status page.
 ///
 /// TODO: replace this with proper stats and monitoring
@@ -44,11 +106,88 @@ pub async fn status(
                 "user_cache_size": app.rpc_secret_key_cache.weighted_size(),
                 "balanced_rpcs": app.balanced_rpcs,
                 "private_rpcs": app.private_rpcs,
+                "archive_rpcs": app.archive_rpcs,
             });
 
             Arc::new(body)
         })
         .await;
 
+    let mut response = Json(body).into_response();
+
+    response.extensions_mut().insert(CachedResponse);
+
+    response
+}
+
+/// `GET /status/backends` -- Every configured backend's head block, limits, load, recent error rate, and whether it is synced.
+#[debug_handler]
+pub async fn status_backends(Extension(app): Extension<Arc<Web3ProxyApp>>) -> FrontendResult {
+    let mut response = HashMap::new();
+
+    for (group, rpcs) in app.rpc_groups() {
+        response.insert(group, rpcs.backend_statuses().await?);
+    }
+
+    Ok(Json(response).into_response())
+}
+
+/// Exactly what is running. Useful for checking replicas during a rollout.
+#[debug_handler]
+pub async fn status_version(Extension(app): Extension<Arc<Web3ProxyApp>>) -> impl IntoResponse {
+    let mut features = vec![];
+
+    if cfg!(feature = "deadlock_detection") {
+        features.push("deadlock_detection");
+    }
+
+    if cfg!(feature = "test-clock") {
+        features.push("test-clock");
+    }
+
+    let body = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("WEB3_PROXY_GIT_SHA"),
+        "build_timestamp": env!("WEB3_PROXY_BUILD_TIMESTAMP").parse::<u64>().ok(),
+        "rustc_version": env!("WEB3_PROXY_RUSTC_VERSION"),
+        "features": features,
+        "uptime_seconds": app.start.elapsed().as_secs(),
+        "config_hash": app.config_hash,
+        "chain_id": app.config.chain_id,
+        "instance": app.config.instance_name,
+        "deployment_environment": app.config.deployment_environment,
+    });
+
     Json(body)
 }
+
+/// `GET /tx/:tx_hash` -- The proxy's view of a transaction that was sent through it.
+/// Rate limited like rpc requests. An rpc key can be given with the `X-API-Key` header or the `key` query param.
+#[debug_handler]
+pub async fn tx_status(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ClientIp(ip): ClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    Path(tx_hash): Path<TxHash>,
+) -> FrontendResult {
+    let (_authorization, _semaphore) = rpc_is_authorized(
+        &app,
+        ip,
+        None,
+        origin.map(|x| x.0),
+        None,
+        None,
+        &headers,
+        &params,
+        false,
+    )
+    .await?;
+
+    let tx = app
+        .tracked_tx(&tx_hash)
+        .ok_or(FrontendErrorResponse::NotFound)?;
+
+    Ok(Json(tx).into_response())
+}
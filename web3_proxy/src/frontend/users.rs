@@ -1,12 +1,15 @@
 //! Handle registration, logins, and managing account data.
 
-use super::authorization::{login_is_authorized, RpcSecretKey};
-use super::errors::FrontendResult;
-use crate::app::Web3ProxyApp;
-use crate::user_queries::get_page_from_params;
+use super::authorization::{
+    login_is_authorized, Authorization as InternalAuthorization, RpcSecretKey,
+};
+use super::errors::{FrontendErrorResponse, FrontendResult};
+use crate::app::{check_webhook_url, Web3ProxyApp};
+use crate::audit_log::{rpc_key_audit_json, save_audit_log};
 use crate::user_queries::{
     get_chain_id_from_params, get_query_start_from_params, query_user_stats, StatResponse,
 };
+use crate::user_queries::{get_org_id_from_params, get_page_from_params};
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
 use axum::headers::{Header, Origin, Referer, UserAgent};
@@ -19,9 +22,12 @@ use axum::{
 use axum_client_ip::ClientIp;
 use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
-use entities::sea_orm_active_enums::LogLevel;
-use entities::{login, pending_login, revert_log, rpc_key, user};
-use ethers::{prelude::Address, types::Bytes};
+use entities::sea_orm_active_enums::{LogLevel, Role};
+use entities::{deposit, login, pending_login, revert_log, rpc_key, user};
+use ethers::{
+    prelude::{Address, TxHash},
+    types::Bytes,
+};
 use hashbrown::HashMap;
 use http::{HeaderValue, StatusCode};
 use ipnet::IpNet;
@@ -35,10 +41,12 @@ use migration::sea_orm::{
 use serde::Deserialize;
 use serde_json::json;
 use siwe::{Message, VerificationOpts};
+use std::num::NonZeroU64;
 use std::ops::Add;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{atomic, Arc};
 use time::{Duration, OffsetDateTime};
+use tokio::sync::broadcast;
 use ulid::Ulid;
 
 /// `GET /user/login/:user_address` or `GET /user/login/:user_address/:message_eip` -- Start the "Sign In with Ethereum" (siwe) login flow.
@@ -65,8 +73,6 @@ pub async fn user_login_get(
     // TODO: what does axum's error handling look like if the path fails to parse?
     Path(mut params): Path<HashMap<String, String>>,
 ) -> FrontendResult {
-    login_is_authorized(&app, ip).await?;
-
     // create a message and save it in redis
     // TODO: how many seconds? get from config?
     let expire_seconds: usize = 20 * 60;
@@ -86,6 +92,8 @@ pub async fn user_login_get(
         // TODO: map_err so this becomes a 401
         .context("unable to parse address")?;
 
+    login_is_authorized(&app, ip, Some(user_address)).await?;
+
     let login_domain = app
         .config
         .login_domain
@@ -94,16 +102,17 @@ pub async fn user_login_get(
 
     // TODO: get most of these from the app config
     let message = Message {
-        // TODO: don't unwrap
         // TODO: accept a login_domain from the request?
-        domain: login_domain.parse().unwrap(),
+        domain: login_domain.parse().context("invalid login_domain")?,
         address: user_address.to_fixed_bytes(),
         // TODO: config for statement
         statement: Some("🦙🦙🦙🦙🦙".to_string()),
-        // TODO: don't unwrap
-        uri: format!("https://{}/", login_domain).parse().unwrap(),
+        uri: format!("https://{}/", login_domain)
+            .parse()
+            .context("invalid login_domain")?,
         version: siwe::Version::V1,
-        chain_id: 1,
+        // wallets show this. sign in on the chain that the keys are for
+        chain_id: app.config.chain_id,
         expiration_time: Some(expiration_time.into()),
         issued_at: issued_at.into(),
         nonce: nonce.to_string(),
@@ -182,8 +191,6 @@ pub async fn user_login_post(
     Query(query): Query<PostLoginQuery>,
     Json(payload): Json<PostLogin>,
 ) -> FrontendResult {
-    login_is_authorized(&app, ip).await?;
-
     // TODO: this seems too verbose. how can we simply convert a String into a [u8; 65]
     let their_sig_bytes = Bytes::from_str(&payload.sig).context("parsing sig")?;
     if their_sig_bytes.len() != 65 {
@@ -210,6 +217,11 @@ pub async fn user_login_post(
             .context("parsing string message")?
     };
 
+    // the address isn't trusted yet, but it is still useful for throttling
+    let their_address = Address::from(their_msg.address);
+
+    login_is_authorized(&app, ip, Some(their_address)).await?;
+
     // the only part of the message we will trust is their nonce
     // TODO: this is fragile. have a helper function/struct for redis keys
     let login_nonce = UserBearerToken::from_str(&their_msg.nonce)?;
@@ -246,6 +258,13 @@ pub async fn user_login_post(
             .verify_eip191(&their_sig)
             .context("verifying eip191 signature against our local message")
         {
+            if let Err(err) = app
+                .record_login_failure(ip, Address::from(our_msg.address))
+                .await
+            {
+                warn!("unable to record login failure. err={:?}", err);
+            }
+
             let db_conn = app
                 .db_conn()
                 .context("deleting expired pending logins requires a db")?;
@@ -269,6 +288,13 @@ pub async fn user_login_post(
         }
     }
 
+    if let Err(err) = app
+        .clear_login_failures(ip, Address::from(our_msg.address))
+        .await
+    {
+        warn!("unable to clear login failures. err={:?}", err);
+    }
+
     // TODO: limit columns or load whole user?
     let u = user::Entity::find()
         .filter(user::Column::Address.eq(our_msg.address.as_ref()))
@@ -345,7 +371,7 @@ pub async fn user_login_post(
     let response_json = json!({
         "rpc_keys": uks
             .into_iter()
-            .map(|uk| (uk.id, uk))
+            .map(|uk| (uk.id, rpc_key_json(&app, uk)))
             .collect::<HashMap<_, _>>(),
         "bearer_token": user_bearer_token,
         "user": u,
@@ -426,8 +452,6 @@ pub async fn user_logout_post(
 /// `GET /user` -- Use a bearer token to get the user's profile.
 ///
 /// - the email address of a user if they opted in to get contacted via email
-///
-/// TODO: this will change as we add better support for secondary users.
 #[debug_handler]
 pub async fn user_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
@@ -484,49 +508,110 @@ pub async fn user_post(
     Ok(Json(user).into_response())
 }
 
-/// `GET /user/balance` -- Use a bearer token to get the user's balance and spend.
+/// `GET /user/balance` -- Use a bearer token to get the user's credit balance and deposits.
 ///
-/// - show balance in USD
-/// - show deposits history (currency, amounts, transaction id)
+/// Every key spends the user's balance. Deposits are newest first.
 ///
-/// TODO: one key per request? maybe /user/balance/:rpc_key?
-/// TODO: this will change as we add better support for secondary users.
+/// Use `?org_id=$x` to see an org's balance.
 #[debug_handler]
 pub async fn user_balance_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let org_id = get_org_id_from_params(&params)?;
+
+    let (_caller, user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, org_id, Role::Viewer)
+        .await?;
 
-    todo!("user_balance_get");
+    let page = get_page_from_params(&params)?;
+
+    // TODO: page size from config
+    let page_size = 100;
+
+    // includes credits that are spent but not saved yet
+    let balance = app
+        .user_balance(user.id)
+        .await?
+        .load(atomic::Ordering::Acquire);
+
+    let db_replica = app
+        .db_replica()
+        .context("getting replica db for user's deposits")?;
+
+    let deposits = deposit::Entity::find()
+        .filter(deposit::Column::UserId.eq(user.id))
+        .order_by_desc(deposit::Column::Id)
+        .paginate(db_replica.conn(), page_size)
+        .fetch_page(page)
+        .await?;
+
+    let response = json!({
+        "user_id": user.id,
+        "balance": balance,
+        "deposit_address": app.config.credits.as_ref().map(|x| x.deposit_address),
+        "page": page,
+        "page_size": page_size,
+        "deposits": deposits,
+    });
+
+    Ok(Json(response).into_response())
 }
 
 /// `POST /user/balance/:txhash` -- Manually process a confirmed txid to update a user's balance.
 ///
-/// We will subscribe to events to watch for any user deposits, but sometimes events can be missed.
-///
-/// TODO: rate limit by user
-/// TODO: one key per request? maybe /user/balance/:rpc_key?
-/// TODO: this will change as we add better support for secondary users.
+/// Deposits are credited automatically, but one that was missed can be sent here.
+/// Transfers are credited to the user that sent them and are never credited twice.
 #[debug_handler]
 pub async fn user_balance_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(tx_hash): Path<TxHash>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let org_id = get_org_id_from_params(&params)?;
 
-    todo!("user_balance_post");
+    let (_caller, _user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, org_id, Role::Admin)
+        .await?;
+
+    let authorization = Arc::new(InternalAuthorization::internal(app.db_conn())?);
+
+    let deposits = app
+        .credit_deposits(&authorization, tx_hash)
+        .await
+        .map_err(|err| {
+            FrontendErrorResponse::StatusCode(
+                StatusCode::BAD_REQUEST,
+                format!("unable to credit deposit: {}", err),
+                Some(err),
+            )
+        })?;
+
+    let response = json!({
+        "deposits": deposits,
+    });
+
+    Ok(Json(response).into_response())
 }
 
 /// `GET /user/keys` -- Use a bearer token to get the user's api keys and their settings.
 ///
+/// Use `?org_id=$x` to see an org's keys.
+///
 /// TODO: one key per request? maybe /user/keys/:rpc_key?
 #[debug_handler]
 pub async fn rpc_keys_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let org_id = get_org_id_from_params(&params)?;
+
+    let (_caller, user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, org_id, Role::Viewer)
+        .await?;
 
     let db_replica = app
         .db_replica()
@@ -543,23 +628,78 @@ pub async fn rpc_keys_get(
         "user_id": user.id,
         "user_rpc_keys": uks
             .into_iter()
-            .map(|uk| (uk.id, uk))
+            .map(|uk| (uk.id, rpc_key_json(&app, uk)))
             .collect::<HashMap::<_, _>>(),
     });
 
     Ok(Json(response_json).into_response())
 }
 
-/// `DELETE /user/keys` -- Use a bearer token to delete an existing key.
+/// `DELETE /user/keys?key_id=$x` -- Use a bearer token to revoke an existing key.
+///
+/// Stats and billing reference the key, so it is deactivated instead of deleted.
+/// Its secret is replaced so that the old secret never works again, even if the key is reactivated.
+///
+/// Use `?org_id=$x` to revoke an org's keys. This requires the admin role.
 #[debug_handler]
 pub async fn rpc_keys_delete(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let org_id = get_org_id_from_params(&params)?;
+
+    let (caller, user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, org_id, Role::Admin)
+        .await?;
+
+    let key_id: u64 = params
+        .get("key_id")
+        .context("key_id is required")?
+        .parse()
+        .context("parsing key_id")?;
+
+    let db_conn = app.db_conn().context("revoking keys requires a db")?;
+
+    let existing_key = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .filter(rpc_key::Column::Id.eq(key_id))
+        .one(&db_conn)
+        .await
+        .context("failed loading user's key")?
+        .context("key does not exist or is not controlled by this bearer token")?;
 
-    // TODO: think about how cascading deletes and billing should work
-    Err(anyhow::anyhow!("work in progress").into())
+    let before = rpc_key_audit_json(&existing_key);
+    let old_secret_key = existing_key.secret_key;
+
+    let mut uk = existing_key.into_active_model();
+
+    uk.active = sea_orm::Set(false);
+    uk.secret_key = sea_orm::Set(RpcSecretKey::new().into());
+
+    let uk = uk
+        .save(&db_conn)
+        .await
+        .context("Failed revoking user key")?
+        .try_into_model()?;
+
+    save_audit_log(
+        &db_conn,
+        Some(caller.id),
+        "rpc_key.revoke",
+        "rpc_key",
+        Some(uk.id),
+        Some(before),
+        Some(rpc_key_audit_json(&uk)),
+    )
+    .await?;
+
+    forget_cached_key(&app, uk.id, old_secret_key).await;
+
+    // websockets authorized with the old secret stay open until they are kicked
+    kick_websockets(&app.websocket_kick_sender, uk.id)?;
+
+    Ok(Json(rpc_key_json(&app, uk)).into_response())
 }
 
 /// the JSON input to the `rpc_keys_management` handler.
@@ -579,31 +719,60 @@ pub struct UserKeyManagement {
     log_level: Option<LogLevel>,
     // TODO: enable log_revert_trace: Option<f64>,
     private_txs: Option<bool>,
+    cache_latest: Option<bool>,
+    method_allowlist: Option<String>,
+    method_denylist: Option<String>,
+    /// 0 never sends transactions again
+    rebroadcast_blocks: Option<u32>,
+    /// pin the key to the first N ips that use it. 0 turns pinning off
+    pin_ips: Option<u32>,
+    /// forget the pinned ips so that the key can pin new ones
+    reset_pinned_ips: Option<bool>,
+    /// https url that is POSTed rejected ips. empty turns alerts off
+    alert_webhook_url: Option<String>,
+    /// give an existing key a new secret. the old secret stops working immediately
+    rotate_secret_key: Option<bool>,
 }
 
 /// `POST /user/keys` or `PUT /user/keys` -- Use a bearer token to create or update an existing key.
+///
+/// Use `?org_id=$x` to manage an org's keys. This requires the admin role.
 #[debug_handler]
 pub async fn rpc_keys_management(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
     Json(payload): Json<UserKeyManagement>,
 ) -> FrontendResult {
     // TODO: is there a way we can know if this is a PUT or POST? right now we can modify or create keys with either. though that probably doesn't matter
 
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let org_id = get_org_id_from_params(&params)?;
+
+    let (caller, user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, org_id, Role::Admin)
+        .await?;
 
     let db_replica = app.db_replica().context("getting db for user's keys")?;
 
+    // saved for the audit log
+    let mut before = None;
+    // the cache has to forget the old secret if it is rotated
+    let mut old_secret_key = None;
+
     let mut uk = if let Some(existing_key_id) = payload.key_id {
         // get the key and make sure it belongs to the user
-        rpc_key::Entity::find()
+        let existing_key = rpc_key::Entity::find()
             .filter(rpc_key::Column::UserId.eq(user.id))
             .filter(rpc_key::Column::Id.eq(existing_key_id))
             .one(db_replica.conn())
             .await
             .context("failed loading user's key")?
-            .context("key does not exist or is not controlled by this bearer token")?
-            .into_active_model()
+            .context("key does not exist or is not controlled by this bearer token")?;
+
+        before = Some(rpc_key_audit_json(&existing_key));
+        old_secret_key = Some(existing_key.secret_key);
+
+        existing_key.into_active_model()
     } else {
         // make a new key
         // TODO: limit to 10 keys?
@@ -634,6 +803,10 @@ pub async fn rpc_keys_management(
         uk.private_txs = sea_orm::Set(private_txs);
     }
 
+    if let Some(cache_latest) = payload.cache_latest {
+        uk.cache_latest = sea_orm::Set(cache_latest);
+    }
+
     if let Some(active) = payload.active {
         uk.active = sea_orm::Set(active);
     }
@@ -739,27 +912,172 @@ pub async fn rpc_keys_management(
         }
     }
 
+    if let Some(method_allowlist) = payload.method_allowlist {
+        uk.method_allowlist = sea_orm::Set(method_list(&method_allowlist)?);
+    }
+
+    if let Some(method_denylist) = payload.method_denylist {
+        uk.method_denylist = sea_orm::Set(method_list(&method_denylist)?);
+    }
+
+    if let Some(rebroadcast_blocks) = payload.rebroadcast_blocks {
+        if rebroadcast_blocks == 0 {
+            uk.rebroadcast_blocks = sea_orm::Set(None);
+        } else {
+            uk.rebroadcast_blocks = sea_orm::Set(Some(rebroadcast_blocks));
+        }
+    }
+
+    if let Some(pin_ips) = payload.pin_ips {
+        if pin_ips == 0 {
+            uk.pin_ips = sea_orm::Set(None);
+        } else {
+            uk.pin_ips = sea_orm::Set(Some(pin_ips));
+        }
+    }
+
+    if payload.reset_pinned_ips == Some(true) {
+        uk.pinned_ips = sea_orm::Set(None);
+    }
+
+    if let Some(alert_webhook_url) = payload.alert_webhook_url {
+        if alert_webhook_url.is_empty() {
+            uk.alert_webhook_url = sea_orm::Set(None);
+        } else {
+            // the proxy makes this request. don't let users point it at services on our network
+            check_webhook_url(&alert_webhook_url).await?;
+
+            uk.alert_webhook_url = sea_orm::Set(Some(alert_webhook_url));
+        }
+    }
+
+    let mut rotated = false;
+
+    if payload.rotate_secret_key == Some(true) && before.is_some() {
+        uk.secret_key = sea_orm::Set(RpcSecretKey::new().into());
+        rotated = true;
+    }
+
     let uk = if uk.is_changed() {
         let db_conn = app.db_conn().context("login requires a db")?;
 
-        uk.save(&db_conn).await.context("Failed saving user key")?
-    } else {
+        let uk = uk.save(&db_conn).await.context("Failed saving user key")?;
+
+        let uk = uk.try_into_model()?;
+
+        let action = if rotated {
+            "rpc_key.rotate"
+        } else if before.is_some() {
+            "rpc_key.update"
+        } else {
+            "rpc_key.create"
+        };
+
+        save_audit_log(
+            &db_conn,
+            Some(caller.id),
+            action,
+            "rpc_key",
+            Some(uk.id),
+            before,
+            Some(rpc_key_audit_json(&uk)),
+        )
+        .await?;
+
+        // the key's checks are cached. clear them so restrictions apply immediately
+        if let Some(old_secret_key) = old_secret_key {
+            forget_cached_key(&app, uk.id, old_secret_key).await;
+        }
+
+        forget_cached_key(&app, uk.id, uk.secret_key).await;
+
+        if rotated {
+            // websockets authorized with the old secret stay open until they are kicked
+            kick_websockets(&app.websocket_kick_sender, uk.id)?;
+        }
+
         uk
+    } else {
+        uk.try_into_model()?
     };
 
-    let uk = uk.try_into_model()?;
+    Ok(Json(rpc_key_json(&app, uk)).into_response())
+}
+
+/// The key's checks and the secret that signed requests are checked against are cached.
+/// Forget them so that changes apply to the next request.
+async fn forget_cached_key(app: &Web3ProxyApp, rpc_key_id: u64, secret_key: Uuid) {
+    let rpc_secret_key: RpcSecretKey = secret_key.into();
+    let rpc_secret_key: Ulid = rpc_secret_key.into();
+
+    app.rpc_secret_key_cache.invalidate(&rpc_secret_key).await;
+    app.rpc_key_id_cache.invalidate(&rpc_key_id).await;
+}
+
+/// Close every open websocket for the key. Returns how many websocket loops were listening.
+pub(crate) fn kick_websockets(
+    kick_sender: &broadcast::Sender<NonZeroU64>,
+    rpc_key_id: u64,
+) -> anyhow::Result<usize> {
+    let rpc_key_id: NonZeroU64 = rpc_key_id
+        .try_into()
+        .context("rpc key ids should always be non-zero")?;
+
+    // this errors if no websockets are open. that's fine
+    Ok(kick_sender.send(rpc_key_id).unwrap_or_default())
+}
+
+/// Clean up a comma separated list of methods like "eth_call, debug_*". An empty string clears the list.
+fn method_list(methods: &str) -> anyhow::Result<Option<String>> {
+    if methods.is_empty() {
+        return Ok(None);
+    }
+
+    let methods = methods
+        .split(',')
+        .map(|x| x.trim())
+        .map(|x| {
+            if x.is_empty() || x.contains(char::is_whitespace) {
+                Err(anyhow::anyhow!("invalid method: {:?}", x))
+            } else {
+                Ok(x.to_string())
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(Some(
+        Itertools::intersperse(methods.into_iter(), ", ".to_string()).collect(),
+    ))
+}
+
+/// serialize the key with the secret in the operator's configured format
+fn rpc_key_json(app: &Web3ProxyApp, uk: rpc_key::Model) -> serde_json::Value {
+    let rpc_secret_key: RpcSecretKey = uk.secret_key.into();
+
+    let mut x = json!(uk);
+
+    x["secret_key"] = json!(rpc_secret_key.to_formatted_string(
+        app.config.rpc_key_format,
+        app.config.rpc_key_prefix.as_deref()
+    ));
 
-    Ok(Json(uk).into_response())
+    x
 }
 
 /// `GET /user/revert_logs` -- Use a bearer token to get the user's revert logs.
+///
+/// Use `?org_id=$x` to see an org's revert logs.
 #[debug_handler]
 pub async fn user_revert_logs_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> FrontendResult {
-    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let org_id = get_org_id_from_params(&params)?;
+
+    let (_caller, user, _semaphore) = app
+        .bearer_is_authorized_for_org(bearer, org_id, Role::Viewer)
+        .await?;
 
     let chain_id = get_chain_id_from_params(app.as_ref(), &params)?;
     let query_start = get_query_start_from_params(&params)?;
@@ -837,12 +1155,11 @@ pub async fn user_stats_aggregated_get(
 /// `GET /user/stats/detailed` -- Use a bearer token to get the user's key stats such as bandwidth used and methods requested.
 ///
 /// If no bearer is provided, detailed stats for all users will be shown.
-/// View a single user with `?user_id=$x`.
+/// View a single user with `?user_id=$x`. Members of an org can view the org's user.
+/// View an org with `?org_id=$x`.
 /// View a single chain with `?chain_id=$x`.
 ///
 /// Set `$x` to zero to see all.
-///
-/// TODO: this will change as we add better support for secondary users.
 #[debug_handler]
 pub async fn user_stats_detailed_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
@@ -853,3 +1170,38 @@ pub async fn user_stats_detailed_get(
 
     Ok(response)
 }
+
+/// `GET /user/stats/methods` -- Use a bearer token to get the user's stats with one row per method.
+///
+/// Filters are the same as `/user/stats/detailed`. Add `?rpc_key_id=$x` for a single key.
+#[debug_handler]
+pub async fn user_stats_methods_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> FrontendResult {
+    let response = query_user_stats(&app, bearer, &params, StatResponse::Methods).await?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::kick_websockets;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn test_kick_websockets() {
+        let (kick_sender, mut kick_receiver) = broadcast::channel(16);
+
+        assert_eq!(kick_websockets(&kick_sender, 5).unwrap(), 1);
+        assert_eq!(kick_receiver.try_recv().unwrap().get(), 5);
+
+        drop(kick_receiver);
+
+        // nobody listening is not an error
+        assert_eq!(kick_websockets(&kick_sender, 5).unwrap(), 0);
+
+        assert!(kick_websockets(&kick_sender, 0).is_err());
+    }
+}
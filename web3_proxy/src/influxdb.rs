@@ -0,0 +1,252 @@
+//! Write stats to InfluxDB v2 so that they can be graphed in Grafana.
+//!
+//! Points are written with the line protocol over http. Writes are batched by the stat emitter.
+//!
+//! InfluxDB overwrites points that have the same tags and timestamp.
+//! Every point is tagged with the proxy's instance name and per-request points get unique nanosecond timestamps.
+use crate::config::InfluxDbConfig;
+use anyhow::Context;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Only this many batches are written at once. A slow influxdb shouldn't pile up tasks
+const MAX_CONCURRENT_WRITES: usize = 4;
+
+#[derive(Clone)]
+pub struct InfluxDb {
+    http_client: reqwest::Client,
+    token: String,
+    write_url: reqwest::Url,
+    /// Added as the `instance` tag on every point so that proxies don't overwrite each other's aggregates
+    pub instance: String,
+    /// Write a point for every request in addition to the per-period aggregates
+    pub per_request: bool,
+    last_timestamp: Arc<AtomicI64>,
+    writes: Arc<Semaphore>,
+}
+
+pub enum FieldValue {
+    Bool(bool),
+    Float(f64),
+    Int(i64),
+    String(String),
+}
+
+impl From<bool> for FieldValue {
+    fn from(x: bool) -> Self {
+        Self::Bool(x)
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(x: f64) -> Self {
+        Self::Float(x)
+    }
+}
+
+impl From<u64> for FieldValue {
+    fn from(x: u64) -> Self {
+        Self::Int(x.try_into().unwrap_or(i64::MAX))
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(x: String) -> Self {
+        Self::String(x)
+    }
+}
+
+/// Commas, equals signs, and spaces are escaped in tag keys and values. Measurements only escape commas and spaces.
+fn escape(x: &str, escape_equals: bool) -> String {
+    let mut escaped = String::with_capacity(x.len());
+
+    for c in x.chars() {
+        if c == ',' || c == ' ' || (escape_equals && c == '=') || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// String field values are quoted. Only double quotes and backslashes are escaped.
+fn escape_string_field(x: &str) -> String {
+    x.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// One line of the line protocol. Empty tag values are skipped. `timestamp` is in nanoseconds.
+pub fn line(
+    measurement: &str,
+    tags: &[(&str, impl AsRef<str>)],
+    fields: &[(&str, FieldValue)],
+    timestamp: i64,
+) -> String {
+    let mut line = escape(measurement, false);
+
+    for (k, v) in tags.iter().filter(|(_, v)| !v.as_ref().is_empty()) {
+        write!(line, ",{}={}", escape(k, true), escape(v.as_ref(), true)).unwrap();
+    }
+
+    for (i, (k, v)) in fields.iter().enumerate() {
+        let sep = if i == 0 { ' ' } else { ',' };
+
+        match v {
+            FieldValue::Bool(x) => write!(line, "{}{}={}", sep, escape(k, true), x),
+            FieldValue::Float(x) => write!(line, "{}{}={}", sep, escape(k, true), x),
+            FieldValue::Int(x) => write!(line, "{}{}={}i", sep, escape(k, true), x),
+            FieldValue::String(x) => write!(
+                line,
+                "{}{}=\"{}\"",
+                sep,
+                escape(k, true),
+                escape_string_field(x)
+            ),
+        }
+        .unwrap();
+    }
+
+    write!(line, " {}", timestamp).unwrap();
+
+    line
+}
+
+impl InfluxDb {
+    pub fn try_new(
+        config: &InfluxDbConfig,
+        instance: String,
+        http_client: reqwest::Client,
+    ) -> anyhow::Result<Self> {
+        let mut write_url = reqwest::Url::parse(&config.url).context("parsing influxdb.url")?;
+
+        // without a trailing slash, join would replace the last segment of the base path
+        if !write_url.path().ends_with('/') {
+            let path = format!("{}/", write_url.path());
+            write_url.set_path(&path);
+        }
+
+        let mut write_url = write_url.join("api/v2/write")?;
+
+        write_url
+            .query_pairs_mut()
+            .append_pair("org", &config.org)
+            .append_pair("bucket", &config.bucket)
+            .append_pair("precision", "ns");
+
+        Ok(Self {
+            http_client,
+            token: config.token.clone(),
+            write_url,
+            instance,
+            per_request: config.per_request,
+            last_timestamp: Default::default(),
+            writes: Arc::new(Semaphore::new(MAX_CONCURRENT_WRITES)),
+        })
+    }
+
+    /// The current time in nanoseconds. Never the same value twice so that points from the same millisecond are all kept
+    pub fn unique_timestamp(&self) -> i64 {
+        let now = chrono::Utc::now().timestamp_nanos();
+
+        let last = self
+            .last_timestamp
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+
+        now.max(last + 1)
+    }
+
+    /// None if `MAX_CONCURRENT_WRITES` batches are already being written
+    pub fn try_write_permit(&self) -> Option<OwnedSemaphorePermit> {
+        self.writes.clone().try_acquire_owned().ok()
+    }
+
+    /// Write newline separated points
+    pub async fn write(&self, lines: String) -> anyhow::Result<()> {
+        self.http_client
+            .post(self.write_url.clone())
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(lines)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        assert_eq!(
+            line(
+                "proxy_request",
+                &[("chain_id", "1"), ("method", "eth_call"), ("origin", "")],
+                &[("cache_hit", true.into()), ("response_millis", 12u64.into())],
+                1_700_000_000_000_000_000,
+            ),
+            "proxy_request,chain_id=1,method=eth_call cache_hit=true,response_millis=12i 1700000000000000000"
+        );
+
+        assert_eq!(
+            line(
+                "a b,c",
+                &[("origin", "https://x.com/a=b c,d")],
+                &[("mean", 1.5.into())],
+                0,
+            ),
+            r"a\ b\,c,origin=https://x.com/a\=b\ c\,d mean=1.5 0"
+        );
+
+        assert_eq!(
+            line(
+                "proxy_request",
+                &[("chain_id", "1")],
+                &[("origin", r#"https://x.com/"a"\"#.to_string().into())],
+                0,
+            ),
+            r#"proxy_request,chain_id=1 origin="https://x.com/\"a\"\\" 0"#
+        );
+    }
+
+    #[test]
+    fn test_write_url_and_timestamps() {
+        let config = InfluxDbConfig {
+            url: "http://influxdb:8086/proxy".to_string(),
+            org: "llamanodes".to_string(),
+            bucket: "web3_proxy".to_string(),
+            token: "token".to_string(),
+            per_request: true,
+        };
+
+        let influxdb = InfluxDb::try_new(&config, "a".to_string(), reqwest::Client::new()).unwrap();
+
+        assert_eq!(
+            influxdb.write_url.as_str(),
+            "http://influxdb:8086/proxy/api/v2/write?org=llamanodes&bucket=web3_proxy&precision=ns"
+        );
+
+        let first = influxdb.unique_timestamp();
+        let second = influxdb.unique_timestamp();
+
+        assert!(second > first);
+
+        let permits: Vec<_> = (0..MAX_CONCURRENT_WRITES)
+            .map(|_| influxdb.try_write_permit().unwrap())
+            .collect();
+
+        assert!(influxdb.try_write_permit().is_none());
+
+        drop(permits);
+
+        assert!(influxdb.try_write_permit().is_some());
+    }
+}
@@ -0,0 +1,200 @@
+//! Optional reputation checks for anonymous traffic.
+//!
+//! Ips are checked against a local blocklist and then against an http lookup hook.
+use crate::config::IpReputationConfig;
+use anyhow::Context;
+use handlebars::Handlebars;
+use ipnet::IpNet;
+use log::{info, trace, warn};
+use moka::future::Cache;
+use redis_rate_limiter::redis::AsyncCommands;
+use redis_rate_limiter::RedisConnection;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// the name of the lookup_url template in its `Handlebars` registry
+const LOOKUP_URL_TEMPLATE: &str = "lookup_url";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpReputation {
+    Clean,
+    Flagged,
+}
+
+/// the json response from the lookup hook
+#[derive(Debug, Deserialize)]
+struct IpReputationLookup {
+    flagged: bool,
+}
+
+pub struct IpReputationChecker {
+    blocklist: Vec<IpNet>,
+    /// verdicts from the lookup hook. checked before redis so that most requests don't leave the process
+    cache: Cache<IpAddr, IpReputation, hashbrown::hash_map::DefaultHashBuilder>,
+    cache_seconds: usize,
+    http_client: Option<reqwest::Client>,
+    /// the lookup_url template. None if there is no lookup hook
+    lookup_url: Option<Handlebars<'static>>,
+    /// Request limit for flagged ips. None = reject flagged ips
+    pub flagged_requests_per_period: Option<u64>,
+}
+
+impl IpReputationChecker {
+    pub fn try_new(
+        config: &IpReputationConfig,
+        http_client: Option<reqwest::Client>,
+    ) -> anyhow::Result<Self> {
+        let blocklist = match config.blocklist_file.as_ref() {
+            None => vec![],
+            Some(blocklist_file) => {
+                let blocklist = std::fs::read_to_string(blocklist_file)
+                    .with_context(|| format!("reading ip blocklist {}", blocklist_file))?;
+
+                parse_blocklist(&blocklist)
+                    .with_context(|| format!("parsing ip blocklist {}", blocklist_file))?
+            }
+        };
+
+        info!("{} ranges in the ip blocklist", blocklist.len());
+
+        let lookup_url = match config.lookup_url.as_ref() {
+            None => None,
+            Some(lookup_url) => {
+                if !lookup_url.contains("{{ip}}") {
+                    return Err(anyhow::anyhow!(
+                        "ip_reputation.lookup_url must contain {{{{ip}}}}"
+                    ));
+                }
+
+                let mut template = Handlebars::new();
+
+                template
+                    .register_template_string(LOOKUP_URL_TEMPLATE, lookup_url)
+                    .context("parsing ip_reputation.lookup_url")?;
+
+                Some(template)
+            }
+        };
+
+        let cache = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(Duration::from_secs(config.cache_seconds as u64))
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Ok(Self {
+            blocklist,
+            cache,
+            cache_seconds: config.cache_seconds,
+            http_client,
+            lookup_url,
+            flagged_requests_per_period: config.flagged_requests_per_period,
+        })
+    }
+
+    /// The blocklist and the local cache. None if redis or the lookup hook need to be asked.
+    pub fn check_local(&self, ip: IpAddr) -> Option<IpReputation> {
+        if self.blocklist.iter().any(|x| x.contains(&ip)) {
+            return Some(IpReputation::Flagged);
+        }
+
+        if self.lookup_url.is_none() {
+            return Some(IpReputation::Clean);
+        }
+
+        self.cache.get(&ip)
+    }
+
+    /// check the blocklist first since it is cheap. then check the local cache, redis, and the lookup hook
+    pub async fn check(
+        &self,
+        ip: IpAddr,
+        redis_conn: Option<RedisConnection>,
+    ) -> anyhow::Result<IpReputation> {
+        if let Some(reputation) = self.check_local(ip) {
+            return Ok(reputation);
+        }
+
+        // concurrent requests from the same ip share one lookup
+        self.cache
+            .try_get_with(ip, self.check_remote(ip, redis_conn))
+            .await
+            .map_err(|err| anyhow::anyhow!(err))
+    }
+
+    async fn check_remote(
+        &self,
+        ip: IpAddr,
+        mut redis_conn: Option<RedisConnection>,
+    ) -> anyhow::Result<IpReputation> {
+        let redis_key = format!("ip_reputation:{}", ip);
+
+        if let Some(redis_conn) = redis_conn.as_mut() {
+            if let Ok(Some(flagged)) = redis_conn.get::<_, Option<bool>>(&redis_key).await {
+                trace!("ip reputation cache hit for {}", ip);
+
+                return Ok(flagged.into());
+            }
+        }
+
+        let lookup_url = self
+            .lookup_url
+            .as_ref()
+            .context("ip reputation lookups require a lookup_url")?;
+
+        let http_client = self
+            .http_client
+            .as_ref()
+            .context("ip reputation lookups require an http client")?;
+
+        let lookup_url = lookup_url
+            .render(LOOKUP_URL_TEMPLATE, &json!({ "ip": ip.to_string() }))
+            .context("templating ip_reputation.lookup_url")?;
+
+        let lookup: IpReputationLookup = http_client
+            .get(lookup_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if let Some(redis_conn) = redis_conn.as_mut() {
+            // the lookup already answered. a cache failure shouldn't let a flagged ip through
+            if let Err(err) = redis_conn
+                .set_ex::<_, _, ()>(redis_key, lookup.flagged, self.cache_seconds)
+                .await
+            {
+                warn!("unable to cache ip reputation for {}. err={:?}", ip, err);
+            }
+        }
+
+        Ok(lookup.flagged.into())
+    }
+}
+
+impl From<bool> for IpReputation {
+    fn from(flagged: bool) -> Self {
+        if flagged {
+            Self::Flagged
+        } else {
+            Self::Clean
+        }
+    }
+}
+
+/// one ip or CIDR range per line. Empty lines and lines starting with '#' are ignored.
+fn parse_blocklist(blocklist: &str) -> anyhow::Result<Vec<IpNet>> {
+    blocklist
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| {
+            // single ips are allowed, too
+            x.parse::<IpNet>()
+                .or_else(|_| x.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("invalid ip or range: {}", x))
+        })
+        .collect()
+}
@@ -12,7 +12,13 @@ fn default_jsonrpc() -> String {
     "2.0".to_string()
 }
 
+/// ids are usually small numbers or uuids. anything bigger than this is probably abuse
+pub const MAX_ID_BYTES: usize = 1_024;
+/// the longest real method names are around 40 characters
+pub const MAX_METHOD_BYTES: usize = 128;
+
 #[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "UncheckedJsonRpcRequest")]
 pub struct JsonRpcRequest {
     // TODO: skip jsonrpc entirely? its against spec to drop it, but some servers bad
     #[serde(default = "default_jsonrpc")]
@@ -23,6 +29,49 @@ pub struct JsonRpcRequest {
     pub params: Option<serde_json::Value>,
 }
 
+/// A request that has not had its limits checked yet.
+/// Deep nesting is already rejected by serde_json's recursion limit.
+#[derive(Deserialize)]
+struct UncheckedJsonRpcRequest {
+    #[serde(default = "default_jsonrpc")]
+    jsonrpc: String,
+    id: Box<RawValue>,
+    method: String,
+    params: Option<serde_json::Value>,
+}
+
+impl TryFrom<UncheckedJsonRpcRequest> for JsonRpcRequest {
+    type Error = String;
+
+    fn try_from(x: UncheckedJsonRpcRequest) -> Result<Self, Self::Error> {
+        if x.id.get().len() > MAX_ID_BYTES {
+            return Err(format!("id must be at most {} bytes", MAX_ID_BYTES));
+        }
+
+        if x.method.is_empty() || x.method.len() > MAX_METHOD_BYTES {
+            return Err(format!(
+                "method must be between 1 and {} bytes",
+                MAX_METHOD_BYTES
+            ));
+        }
+
+        match x.params {
+            None
+            | Some(serde_json::Value::Null)
+            | Some(serde_json::Value::Array(_))
+            | Some(serde_json::Value::Object(_)) => {}
+            Some(_) => return Err("params must be an array or an object".to_string()),
+        }
+
+        Ok(Self {
+            jsonrpc: x.jsonrpc,
+            id: x.id,
+            method: x.method,
+            params: x.params,
+        })
+    }
+}
+
 impl fmt::Debug for JsonRpcRequest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO: the default formatter takes forever to write. this is too quiet though
@@ -41,6 +90,16 @@ pub enum JsonRpcRequestEnum {
     Single(JsonRpcRequest),
 }
 
+impl JsonRpcRequestEnum {
+    /// Transactions get their own concurrency budget so they don't wait behind reads.
+    pub fn is_priority(&self) -> bool {
+        match self {
+            Self::Single(x) => x.method == "eth_sendRawTransaction",
+            Self::Batch(_) => false,
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -73,7 +132,8 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                 let mut batch: Vec<JsonRpcRequest> =
                     Vec::with_capacity(seq.size_hint().unwrap_or(10));
 
-                while let Ok(Some(s)) = seq.next_element::<JsonRpcRequest>() {
+                // an invalid request fails the whole batch. silently dropping the rest of the batch would be confusing
+                while let Some(s) = seq.next_element::<JsonRpcRequest>()? {
                     batch.push(s);
                 }
 
@@ -131,12 +191,13 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                     Some(x) => Some(x),
                 };
 
-                let single = JsonRpcRequest {
+                let single = JsonRpcRequest::try_from(UncheckedJsonRpcRequest {
                     jsonrpc,
                     id,
                     method,
                     params,
-                };
+                })
+                .map_err(de::Error::custom)?;
 
                 Ok(JsonRpcRequestEnum::Single(single))
             }
@@ -173,6 +234,10 @@ pub struct JsonRpcForwardedResponse {
     pub result: Option<Box<RawValue>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<JsonRpcErrorData>,
+    /// Not part of the JSON-RPC spec. Extra details about how the proxy handled the request
+    /// (like which private relays accepted a transaction).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
 }
 
 impl JsonRpcRequest {
@@ -213,6 +278,7 @@ impl JsonRpcForwardedResponse {
                 message,
                 data: None,
             }),
+            data: None,
         }
     }
 
@@ -223,6 +289,7 @@ impl JsonRpcForwardedResponse {
             // TODO: since we only use the result here, should that be all we return from try_send_request?
             result: Some(partial_response),
             error: None,
+            data: None,
         }
     }
 
@@ -235,6 +302,7 @@ impl JsonRpcForwardedResponse {
             id,
             result: Some(partial_response),
             error: None,
+            data: None,
         }
     }
 
@@ -278,7 +346,7 @@ impl JsonRpcForwardedResponse {
                             }
                         }
                     } else {
-                        unimplemented!();
+                        return Err(anyhow::anyhow!("unexpected JsonRpcClientError"));
                     }
                 }
             }
@@ -294,6 +362,7 @@ impl JsonRpcForwardedResponse {
                 message,
                 data,
             }),
+            data: None,
         })
     }
 
@@ -313,6 +382,16 @@ impl JsonRpcForwardedResponse {
             .expect("this should always be valid json")
             .len()
     }
+
+    /// Add a key to the proxy's `data` object.
+    pub fn add_data(&mut self, key: &str, value: serde_json::Value) {
+        match self.data.as_mut().and_then(|x| x.as_object_mut()) {
+            Some(data) => {
+                data.insert(key.to_string(), value);
+            }
+            None => self.data = Some(json!({ key: value })),
+        }
+    }
 }
 
 /// JSONRPC Responses can include one or many response objects.
@@ -342,6 +421,14 @@ mod tests {
         let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
 
         assert!(matches!(output, JsonRpcRequestEnum::Single(_)));
+        assert!(!output.is_priority());
+
+        let input =
+            r#"{"jsonrpc":"2.0","method":"eth_sendRawTransaction","params":["0x00"],"id":1}"#;
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+
+        assert!(output.is_priority());
     }
 
     #[test]
@@ -368,4 +455,31 @@ mod tests {
 
         assert!(matches!(output, JsonRpcRequestEnum::Batch(_)));
     }
+
+    #[test]
+    fn this_deserialize_invalid() {
+        let huge_id = format!(
+            r#"{{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":"{}"}}"#,
+            "a".repeat(MAX_ID_BYTES)
+        );
+        let deep_params = format!(
+            r#"{{"jsonrpc":"2.0","method":"eth_call","params":{}{},"id":1}}"#,
+            "[".repeat(10_000),
+            "]".repeat(10_000)
+        );
+
+        let inputs = [
+            huge_id.as_str(),
+            deep_params.as_str(),
+            r#"{"jsonrpc":"2.0","method":1,"params":[],"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"","params":[],"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":"0x1","id":1}"#,
+            r#"[{"jsonrpc":"2.0","method":"eth_blockNumber","id":1},{"method":null,"id":2}]"#,
+        ];
+
+        for input in inputs {
+            assert!(serde_json::from_str::<JsonRpcRequest>(input).is_err());
+            assert!(serde_json::from_str::<JsonRpcRequestEnum>(input).is_err());
+        }
+    }
 }
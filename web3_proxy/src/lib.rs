@@ -1,11 +1,21 @@
 pub mod app;
 pub mod app_stats;
+pub mod audit_log;
 pub mod block_number;
+pub mod builder;
 pub mod config;
+pub mod disk_cache;
+pub mod dry_run;
+pub mod external_auth;
+pub mod feature_flags;
 pub mod frontend;
+pub mod influxdb;
+pub mod ip_reputation;
 pub mod jsonrpc;
 pub mod metered;
 pub mod metrics_frontend;
 pub mod rpcs;
+pub mod stat_rollups;
+pub mod telemetry;
 pub mod user_queries;
 pub mod user_token;
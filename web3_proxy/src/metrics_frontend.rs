@@ -1,51 +1,163 @@
-use axum::headers::HeaderName;
-use axum::http::HeaderValue;
+use axum::extract::ConnectInfo;
+use axum::headers::authorization::{Basic, Bearer};
+use axum::headers::{Authorization, HeaderName};
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::{routing::get, Extension, Router};
-use log::info;
-use std::net::SocketAddr;
+use axum::{routing::get, Extension, Router, TypedHeader};
+use ipnet::IpNet;
+use log::{info, warn};
+use std::net::{SocketAddr, TcpListener};
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 
 use crate::app::Web3ProxyApp;
+use crate::config::MetricsAuthConfig;
+use crate::frontend;
 
-/// Run a prometheus metrics server on the given port.
+/// `MetricsAuthConfig` with the ips parsed
+#[derive(Default)]
+struct MetricsAuth {
+    bearer_token: Option<String>,
+    basic: Option<(String, String)>,
+    allowed_ips: Vec<IpNet>,
+}
+
+impl MetricsAuth {
+    fn try_new(config: Option<&MetricsAuthConfig>) -> anyhow::Result<Self> {
+        let config = match config {
+            None => return Ok(Self::default()),
+            Some(x) => x,
+        };
+
+        let basic = match (&config.basic_username, &config.basic_password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "metrics_auth needs both basic_username and basic_password"
+                ))
+            }
+        };
+
+        let allowed_ips = config
+            .allowed_ips
+            .iter()
+            .map(|x| {
+                // single ips are allowed too
+                x.parse::<IpNet>()
+                    .or_else(|_| x.parse::<std::net::IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow::anyhow!("invalid ip in metrics_auth: {}", x))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            bearer_token: config.bearer_token.clone(),
+            basic,
+            allowed_ips,
+        })
+    }
+
+    fn check(
+        &self,
+        addr: SocketAddr,
+        bearer: Option<&Bearer>,
+        basic: Option<&Basic>,
+    ) -> Result<(), StatusCode> {
+        if !self.allowed_ips.is_empty() && !self.allowed_ips.iter().any(|x| x.contains(&addr.ip()))
+        {
+            return Err(StatusCode::FORBIDDEN);
+        }
 
+        if self.bearer_token.is_none() && self.basic.is_none() {
+            return Ok(());
+        }
+
+        // constant time so that the secrets can't be guessed a byte at a time
+        let bearer_ok: bool = match (&self.bearer_token, bearer) {
+            (Some(token), Some(bearer)) => bearer.token().as_bytes().ct_eq(token.as_bytes()).into(),
+            _ => false,
+        };
+
+        let basic_ok: bool = match (&self.basic, basic) {
+            (Some((username, password)), Some(basic)) => {
+                (basic.username().as_bytes().ct_eq(username.as_bytes())
+                    & basic.password().as_bytes().ct_eq(password.as_bytes()))
+                .into()
+            }
+            _ => false,
+        };
+
+        if bearer_ok || basic_ok {
+            Ok(())
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Run a prometheus metrics server on the given port.
+/// The config's `metrics_auth` can require a token or basic auth and limit the allowed ips.
 pub async fn serve(app: Arc<Web3ProxyApp>, port: u16) -> anyhow::Result<()> {
+    serve_listener(app, frontend::bind(port)?).await
+}
+
+/// Serve prometheus metrics on an already bound listener.
+pub async fn serve_listener(app: Arc<Web3ProxyApp>, listener: TcpListener) -> anyhow::Result<()> {
+    let auth = Arc::new(MetricsAuth::try_new(app.config.metrics_auth.as_ref())?);
+
+    if app.config.metrics_auth.is_none() {
+        warn!("prometheus has no metrics_auth. anyone that can reach the port can read metrics");
+    }
+
     // build our application with a route
     // order most to least common
     // TODO: 404 any unhandled routes?
-    let app = Router::new().route("/", get(root)).layer(Extension(app));
+    let app = Router::new()
+        .route("/", get(root))
+        .route("/openmetrics", get(openmetrics))
+        .layer(Extension(app))
+        .layer(Extension(auth));
 
     // run our app with hyper
     // TODO: allow only listening on localhost?
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    info!("prometheus listening on port {}", port);
-    // TODO: into_make_service is enough if we always run behind a proxy. make into_make_service_with_connect_info optional?
-
-    /*
-    It sequentially looks for an IP in:
-      - x-forwarded-for header (de-facto standard)
-      - x-real-ip header
-      - forwarded header (new standard)
-      - axum::extract::ConnectInfo (if not behind proxy)
-
-    So we probably won't need into_make_service_with_connect_info, but it shouldn't hurt
-    */
+    info!("prometheus listening on {}", listener.local_addr()?);
+
+    // connect info is required. allowed_ips are checked against the connecting ip because headers are spoofable
     let service = app.into_make_service_with_connect_info::<SocketAddr>();
-    // let service = app.into_make_service();
 
     // `axum::Server` is a re-export of `hyper::Server`
-    axum::Server::bind(&addr)
-        // TODO: option to use with_connect_info. we want it in dev, but not when running behind a proxy, but not
+    axum::Server::from_tcp(listener)?
         .serve(service)
         .await
         .map_err(Into::into)
 }
 
-async fn root(Extension(app): Extension<Arc<Web3ProxyApp>>) -> Response {
-    let serialized = app.prometheus_metrics().await;
+/// The response for a request that `metrics_auth` rejects
+fn rejected(
+    auth: &MetricsAuth,
+    addr: SocketAddr,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+) -> Option<Response> {
+    let bearer = bearer.as_ref().map(|x| &x.0 .0);
+    let basic = basic.as_ref().map(|x| &x.0 .0);
+
+    auth.check(addr, bearer, basic).err().map(|status| {
+        let mut r = status.into_response();
+
+        if status == StatusCode::UNAUTHORIZED && auth.basic.is_some() {
+            r.headers_mut().insert(
+                HeaderName::from_static("www-authenticate"),
+                HeaderValue::from_static("Basic realm=\"metrics\""),
+            );
+        }
+
+        r
+    })
+}
 
-    let mut r = serialized.into_response();
+fn with_openmetrics_content_type(body: String) -> Response {
+    let mut r = body.into_response();
 
     // // TODO: is there an easier way to do this?
     r.headers_mut().insert(
@@ -55,3 +167,33 @@ async fn root(Extension(app): Extension<Arc<Web3ProxyApp>>) -> Response {
 
     r
 }
+
+async fn root(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(auth): Extension<Arc<MetricsAuth>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+) -> Response {
+    if let Some(r) = rejected(&auth, addr, bearer, basic) {
+        return r;
+    }
+
+    with_openmetrics_content_type(app.prometheus_metrics().await)
+}
+
+/// The backend latency histograms with trace id exemplars.
+/// `serde_prometheus` can't write exemplars, so these are on their own page.
+async fn openmetrics(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(auth): Extension<Arc<MetricsAuth>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+) -> Response {
+    if let Some(r) = rejected(&auth, addr, bearer, basic) {
+        return r;
+    }
+
+    with_openmetrics_content_type(app.latency_openmetrics())
+}
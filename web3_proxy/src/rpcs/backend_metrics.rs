@@ -0,0 +1,599 @@
+//! Latency histograms, error counters, and head block lag for each backend rpc.
+//!
+//! These are exported as native prometheus histograms so that operators can alert on a single slow provider:
+//! `web3_proxy_backend_latency_millis_bucket{group="balanced", rpc="foo", le="100"}`
+//!
+//! The latency histograms are also written in the OpenMetrics format with a trace id exemplar on each bucket.
+use super::connection::Web3Connection;
+use super::connections::Web3Connections;
+use super::payload_size::PayloadMetrics;
+use ethers::types::U64;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets in milliseconds. Slower requests only count in `+Inf`.
+const LATENCY_BUCKETS_MILLIS: [u64; 12] = [
+    5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000,
+];
+
+/// The most recent traced request in a bucket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub millis: u64,
+    /// unix time in milliseconds
+    pub timestamp_ms: u64,
+}
+
+/// Request latency with fixed buckets. Recording never blocks.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// not cumulative. the last bucket is for requests slower than all of `LATENCY_BUCKETS_MILLIS`
+    buckets: [AtomicU64; LATENCY_BUCKETS_MILLIS.len() + 1],
+    /// one for each of `buckets`
+    exemplars: [Mutex<Option<Exemplar>>; LATENCY_BUCKETS_MILLIS.len() + 1],
+    sum_millis: AtomicU64,
+    count: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// `trace_id` becomes the bucket's exemplar. Only give it for traces that are exported
+    pub fn record(&self, latency: Duration, trace_id: Option<String>) {
+        let millis = latency.as_millis() as u64;
+
+        let i = LATENCY_BUCKETS_MILLIS
+            .iter()
+            .position(|le| millis <= *le)
+            .unwrap_or(LATENCY_BUCKETS_MILLIS.len());
+
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_millis.fetch_add(millis, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(trace_id) = trace_id {
+            // any recent trace is a fine exemplar, so skip this one instead of waiting
+            if let Some(mut exemplar) = self.exemplars[i].try_lock() {
+                *exemplar = Some(Exemplar {
+                    trace_id,
+                    millis,
+                    timestamp_ms: virtual_clock::unix_now().as_millis() as u64,
+                });
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let mut cumulative = 0;
+
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|x| {
+                cumulative += x.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+
+        LatencySnapshot {
+            buckets,
+            exemplars: self.exemplars.iter().map(|x| x.lock().clone()).collect(),
+            sum: self.sum_millis.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts for the current and the previous minute.
+#[derive(Debug, Default)]
+pub struct MinuteWindow<T> {
+    minute: u64,
+    /// [previous minute, current minute]
+    counts: [T; 2],
+}
+
+impl<T: Default> MinuteWindow<T> {
+    /// Move the window forward to `minute`. Counts that are more than a minute old are forgotten
+    fn roll(&mut self, minute: u64) {
+        if minute == self.minute {
+            return;
+        }
+
+        if minute == self.minute + 1 {
+            self.counts[0] = std::mem::take(&mut self.counts[1]);
+        } else {
+            self.counts = Default::default();
+        }
+
+        self.minute = minute;
+    }
+
+    /// The counts to add to during `minute`
+    pub fn current(&mut self, minute: u64) -> &mut T {
+        self.roll(minute);
+
+        &mut self.counts[1]
+    }
+
+    /// The previous and the current minute's counts as of `minute`
+    pub fn recent(&mut self, minute: u64) -> &[T; 2] {
+        self.roll(minute);
+
+        &self.counts
+    }
+}
+
+pub fn current_minute() -> u64 {
+    virtual_clock::unix_now().as_secs() / 60
+}
+
+/// Requests and provider errors in the current and the previous minute.
+#[derive(Debug, Default)]
+pub struct RecentErrors(Mutex<MinuteWindow<ErrorCounts>>);
+
+#[derive(Debug, Default)]
+struct ErrorCounts {
+    requests: u64,
+    errors: u64,
+}
+
+impl RecentErrors {
+    pub fn add_request(&self) {
+        self.add_request_at(current_minute())
+    }
+
+    pub fn add_error(&self) {
+        self.add_error_at(current_minute())
+    }
+
+    /// Provider errors and timeouts per request over the last one to two minutes. None if there were no requests
+    pub fn error_rate(&self) -> Option<f64> {
+        self.error_rate_at(current_minute())
+    }
+
+    fn add_request_at(&self, minute: u64) {
+        self.0.lock().current(minute).requests += 1;
+    }
+
+    fn add_error_at(&self, minute: u64) {
+        self.0.lock().current(minute).errors += 1;
+    }
+
+    fn error_rate_at(&self, minute: u64) -> Option<f64> {
+        let mut window = self.0.lock();
+
+        let recent = window.recent(minute);
+
+        let requests: u64 = recent.iter().map(|x| x.requests).sum();
+        let errors: u64 = recent.iter().map(|x| x.errors).sum();
+
+        if requests == 0 {
+            None
+        } else {
+            Some(errors as f64 / requests as f64)
+        }
+    }
+}
+
+/// Counters for one backend. These start over when the rpc reconnects with a changed config.
+#[derive(Debug, Default)]
+pub struct BackendMetrics {
+    pub latency: LatencyHistogram,
+    /// timeouts, connection errors, and responses that aren't valid json-rpc. these are the provider's fault
+    pub errors: AtomicU64,
+    /// valid json-rpc error responses like reverts. these are usually the user's fault
+    pub jsonrpc_errors: AtomicU64,
+    /// for the error rate on `/status/backends`
+    pub recent: RecentErrors,
+    /// request and response sizes and empty results
+    pub payload: PayloadMetrics,
+}
+
+/// A point in time copy of a `LatencyHistogram`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// cumulative counts for each of `LATENCY_BUCKETS_MILLIS` and then `+Inf`
+    pub buckets: Vec<u64>,
+    /// one for each of `buckets`. only the OpenMetrics format can show these
+    pub exemplars: Vec<Option<Exemplar>>,
+    pub sum: u64,
+    pub count: u64,
+}
+
+/// What prometheus shows for one backend.
+/// The field names become metric names and the group and rpc become labels.
+#[derive(Clone, Debug, Serialize)]
+pub struct BackendRpcMetrics {
+    #[serde(serialize_with = "rpc_histogram")]
+    pub latency_millis: LatencySnapshot,
+    #[serde(serialize_with = "rpc_labels")]
+    pub errors: u64,
+    #[serde(serialize_with = "rpc_labels")]
+    pub jsonrpc_errors: u64,
+    /// blocks behind the consensus head. None if either head is unknown
+    #[serde(serialize_with = "rpc_labels")]
+    pub head_block_lag: Option<u64>,
+    #[serde(serialize_with = "rpc_labels")]
+    pub request_header_bytes: u64,
+    #[serde(serialize_with = "rpc_labels")]
+    pub request_body_bytes: u64,
+    #[serde(serialize_with = "rpc_labels")]
+    pub response_bytes: u64,
+    #[serde(serialize_with = "rpc_labels")]
+    pub empty_responses: u64,
+    /// 1 while the backend is demoted for sending back far more empty results than its peers
+    #[serde(serialize_with = "rpc_labels")]
+    pub payload_anomaly: u64,
+    #[serde(serialize_with = "rpc_labels")]
+    pub payload_anomalies: u64,
+}
+
+// serde_prometheus reads these "newtype names" as instructions for turning the path into a name and labels.
+// The path to a field is `backend/{group}/{rpc}/{field}`.
+// `<` moves the next part of the path into the metric name. `!` drops it.
+// `label==!<` skips one part and uses the next as the label's value.
+
+/// `backend_{field}{group, rpc}`
+fn rpc_labels<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct("<!!<.|rpc==!<,group==!!<", value)
+}
+
+/// `backend_{field}_sum{group, rpc}`
+struct HistogramValue(u64);
+
+impl Serialize for HistogramValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("<<!!<.|rpc==!!<,group==!!!<", &self.0)
+    }
+}
+
+/// `backend_{field}_bucket{group, rpc, le}`
+struct BucketValue(u64);
+
+impl Serialize for BucketValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("!<<!!<.|le==<,rpc==!!!<,group==!!!!<", &self.0)
+    }
+}
+
+/// Buckets as a map keyed by their upper bound
+struct Buckets<'a>(&'a [u64]);
+
+impl Serialize for Buckets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+
+        for (i, x) in self.0.iter().enumerate() {
+            match LATENCY_BUCKETS_MILLIS.get(i) {
+                Some(le) => map.serialize_entry(le, &BucketValue(*x))?,
+                None => map.serialize_entry("+Inf", &BucketValue(*x))?,
+            }
+        }
+
+        map.end()
+    }
+}
+
+fn rpc_histogram<S: Serializer>(value: &LatencySnapshot, serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Histogram<'a> {
+        bucket: Buckets<'a>,
+        sum: HistogramValue,
+        count: HistogramValue,
+    }
+
+    Histogram {
+        bucket: Buckets(&value.buckets),
+        sum: HistogramValue(value.sum),
+        count: HistogramValue(value.count),
+    }
+    .serialize(serializer)
+}
+
+/// Escape a label value for the prometheus and OpenMetrics text formats
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The latency histograms in the OpenMetrics text format with each bucket's exemplar.
+/// `serde_prometheus` can't write exemplars. `labels` are added to every series like the prometheus globals.
+pub fn latency_openmetrics(
+    prefix: &str,
+    labels: &[(&str, &str)],
+    groups: &HashMap<&str, HashMap<String, BackendRpcMetrics>>,
+) -> String {
+    let name = format!("{}_backend_latency_millis", prefix);
+
+    let mut out = String::new();
+
+    // writing to a String can't fail
+    writeln!(out, "# TYPE {} histogram", name).unwrap();
+    writeln!(
+        out,
+        "# HELP {} Backend request latency in milliseconds.",
+        name
+    )
+    .unwrap();
+
+    let mut group_names: Vec<_> = groups.keys().collect();
+    group_names.sort();
+
+    for group in group_names {
+        let rpcs = &groups[group];
+
+        let mut rpc_names: Vec<_> = rpcs.keys().collect();
+        rpc_names.sort();
+
+        for rpc in rpc_names {
+            let latency = &rpcs[rpc].latency_millis;
+
+            let series_labels = labels
+                .iter()
+                .copied()
+                .chain([("group", *group), ("rpc", rpc.as_str())])
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            for (i, count) in latency.buckets.iter().enumerate() {
+                let le = match LATENCY_BUCKETS_MILLIS.get(i) {
+                    Some(le) => le.to_string(),
+                    None => "+Inf".to_string(),
+                };
+
+                write!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, series_labels, le, count
+                )
+                .unwrap();
+
+                if let Some(Some(exemplar)) = latency.exemplars.get(i) {
+                    write!(
+                        out,
+                        " # {{trace_id=\"{}\"}} {} {}.{:03}",
+                        exemplar.trace_id,
+                        exemplar.millis,
+                        exemplar.timestamp_ms / 1000,
+                        exemplar.timestamp_ms % 1000
+                    )
+                    .unwrap();
+                }
+
+                out.push('\n');
+            }
+
+            writeln!(out, "{}_sum{{{}}} {}", name, series_labels, latency.sum).unwrap();
+            writeln!(out, "{}_count{{{}}} {}", name, series_labels, latency.count).unwrap();
+        }
+    }
+
+    out.push_str("# EOF\n");
+
+    out
+}
+
+impl Web3Connection {
+    pub fn backend_metrics(&self, consensus_head_num: Option<U64>) -> BackendRpcMetrics {
+        let head_block_num = self.head_block.read().as_ref().map(|x| x.number());
+
+        let head_block_lag = match (consensus_head_num, head_block_num) {
+            (Some(consensus), Some(ours)) => Some(consensus.saturating_sub(ours).as_u64()),
+            _ => None,
+        };
+
+        let payload = &self.backend_metrics.payload;
+
+        BackendRpcMetrics {
+            latency_millis: self.backend_metrics.latency.snapshot(),
+            errors: self.backend_metrics.errors.load(Ordering::Relaxed),
+            jsonrpc_errors: self.backend_metrics.jsonrpc_errors.load(Ordering::Relaxed),
+            head_block_lag,
+            request_header_bytes: payload.request_header_bytes.load(Ordering::Relaxed),
+            request_body_bytes: payload.request_body_bytes.load(Ordering::Relaxed),
+            response_bytes: payload.response_bytes.load(Ordering::Relaxed),
+            empty_responses: payload.empty_responses.load(Ordering::Relaxed),
+            payload_anomaly: payload.anomaly.load(Ordering::Relaxed) as u64,
+            payload_anomalies: payload.anomalies.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Web3Connections {
+    /// Metrics for every connected rpc in this group, keyed by rpc name.
+    pub fn backend_metrics(
+        &self,
+        consensus_head_num: Option<U64>,
+    ) -> HashMap<String, BackendRpcMetrics> {
+        self.conns
+            .load()
+            .values()
+            .map(|rpc| (rpc.name.clone(), rpc.backend_metrics(consensus_head_num)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let x = LatencyHistogram::default();
+
+        x.record(Duration::from_millis(3), None);
+        x.record(Duration::from_millis(5), None);
+        x.record(Duration::from_millis(80), None);
+        x.record(Duration::from_secs(60), None);
+
+        let snapshot = x.snapshot();
+
+        assert_eq!(
+            snapshot.buckets,
+            vec![2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3, 4]
+        );
+        assert_eq!(snapshot.sum, 60_088);
+        assert_eq!(snapshot.count, 4);
+        assert!(snapshot.exemplars.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_latency_exemplars() {
+        let x = LatencyHistogram::default();
+
+        x.record(Duration::from_millis(80), Some("a".repeat(32)));
+        x.record(Duration::from_millis(90), Some("b".repeat(32)));
+        x.record(Duration::from_millis(3), None);
+
+        let snapshot = x.snapshot();
+
+        // the newest trace in the 100ms bucket wins
+        let exemplar = snapshot.exemplars[4].clone().unwrap();
+        assert_eq!(exemplar.trace_id, "b".repeat(32));
+        assert_eq!(exemplar.millis, 90);
+        assert_eq!(snapshot.exemplars[0], None);
+
+        let metrics = BackendRpcMetrics {
+            latency_millis: snapshot,
+            errors: 0,
+            jsonrpc_errors: 0,
+            head_block_lag: None,
+            request_header_bytes: 0,
+            request_body_bytes: 0,
+            response_bytes: 0,
+            empty_responses: 0,
+            payload_anomaly: 0,
+            payload_anomalies: 0,
+        };
+
+        let groups = HashMap::from([(
+            "balanced",
+            HashMap::from([("foo\"bar".to_string(), metrics)]),
+        )]);
+
+        let text = latency_openmetrics("web3_proxy", &[("chain_id", "1")], &groups);
+
+        assert!(text.starts_with("# TYPE web3_proxy_backend_latency_millis histogram\n"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let labels = r#"chain_id="1",group="balanced",rpc="foo\"bar""#;
+
+        let bucket = format!(
+            "web3_proxy_backend_latency_millis_bucket{{{},le=\"100\"}} 3 # {{trace_id=\"{}\"}} 90 {}.{:03}\n",
+            labels,
+            "b".repeat(32),
+            exemplar.timestamp_ms / 1000,
+            exemplar.timestamp_ms % 1000,
+        );
+        assert!(text.contains(&bucket), "{}", text);
+
+        // buckets without a traced request have no exemplar
+        assert!(text.contains(&format!(
+            "web3_proxy_backend_latency_millis_bucket{{{},le=\"5\"}} 1\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "web3_proxy_backend_latency_millis_sum{{{}}} 173\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "web3_proxy_backend_latency_millis_count{{{}}} 3\n",
+            labels
+        )));
+    }
+
+    #[test]
+    fn test_recent_errors() {
+        let x = RecentErrors::default();
+
+        assert_eq!(x.error_rate_at(100), None);
+
+        x.add_request_at(100);
+        x.add_request_at(100);
+        x.add_error_at(100);
+
+        assert_eq!(x.error_rate_at(100), Some(0.5));
+
+        // the previous minute still counts
+        x.add_request_at(101);
+        x.add_request_at(101);
+
+        assert_eq!(x.error_rate_at(101), Some(0.25));
+
+        // minute 100 is forgotten
+        assert_eq!(x.error_rate_at(102), Some(0.0));
+
+        // a gap forgets everything
+        assert_eq!(x.error_rate_at(110), None);
+    }
+
+    #[test]
+    fn test_prometheus_names_and_labels() {
+        let metrics = BackendRpcMetrics {
+            latency_millis: LatencySnapshot {
+                buckets: vec![1; LATENCY_BUCKETS_MILLIS.len() + 1],
+                exemplars: vec![None; LATENCY_BUCKETS_MILLIS.len() + 1],
+                sum: 5,
+                count: 1,
+            },
+            errors: 2,
+            jsonrpc_errors: 3,
+            head_block_lag: Some(4),
+            request_header_bytes: 0,
+            request_body_bytes: 0,
+            response_bytes: 0,
+            empty_responses: 0,
+            payload_anomaly: 0,
+            payload_anomalies: 0,
+        };
+
+        // the same path that the app's metrics use
+        let backend = HashMap::from([(
+            "backend",
+            HashMap::from([("balanced", HashMap::from([("foo".to_string(), metrics)]))]),
+        )]);
+
+        let text =
+            serde_prometheus::to_string(&backend, Some("web3_proxy"), HashMap::<&str, &str>::new())
+                .unwrap()
+                // label formatting isn't what this is testing
+                .replace(' ', "");
+
+        let has = |name: &str, labels: &[&str], value: &str| {
+            text.lines().any(|line| {
+                line.starts_with(&format!("{}{{", name))
+                    && labels.iter().all(|x| line.contains(x))
+                    && line.ends_with(&format!("}}{}", value))
+            })
+        };
+
+        let rpc = [r#"rpc="foo""#, r#"group="balanced""#];
+
+        assert!(
+            has(
+                "web3_proxy_backend_latency_millis_bucket",
+                &[r#"le="5""#, rpc[0], rpc[1]],
+                "1"
+            ),
+            "{}",
+            text
+        );
+        assert!(has(
+            "web3_proxy_backend_latency_millis_bucket",
+            &[r#"le="+Inf""#, rpc[0], rpc[1]],
+            "1"
+        ));
+        assert!(has("web3_proxy_backend_latency_millis_sum", &rpc, "5"));
+        assert!(has("web3_proxy_backend_latency_millis_count", &rpc, "1"));
+        assert!(has("web3_proxy_backend_errors", &rpc, "2"));
+        assert!(has("web3_proxy_backend_jsonrpc_errors", &rpc, "3"));
+        assert!(has("web3_proxy_backend_head_block_lag", &rpc, "4"));
+    }
+}
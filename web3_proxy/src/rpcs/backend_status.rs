@@ -0,0 +1,120 @@
+//! Every configured backend's sync state for `/status/backends`. A machine-readable version of the debug logs.
+use super::client::NodeClient;
+use super::connections::Web3Connections;
+use ethers::types::{H256, U64};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+pub struct BackendStatus {
+    pub name: String,
+    pub display_name: Option<String>,
+    /// the node software. None if unknown
+    pub client: Option<NodeClient>,
+    pub disabled: bool,
+    /// false while connecting and after the connection dies
+    pub connected: bool,
+    /// in the set of rpcs that agree on the consensus head and can serve requests
+    pub synced: bool,
+    pub head_block_num: Option<U64>,
+    pub head_block_hash: Option<H256>,
+    /// blocks behind the consensus head. None if either head is unknown
+    pub head_block_lag: Option<u64>,
+    pub tier: u64,
+    pub soft_limit: u32,
+    pub hard_limit: Option<u64>,
+    pub active_requests: u32,
+    /// provider errors per request over the last one to two minutes. None without any recent requests
+    pub error_rate: Option<f64>,
+    /// recent response latency. None before the first response
+    pub latency_ewma_ms: Option<f64>,
+    /// demoted for sending back far more empty results than the other backends
+    pub payload_anomaly: bool,
+    /// tracked methods that this backend answered with "method not found" since it connected
+    pub unsupported_methods: Vec<String>,
+}
+
+impl Web3Connections {
+    /// One status for each configured rpc, including disabled ones, sorted by name.
+    pub async fn backend_statuses(&self) -> anyhow::Result<Vec<BackendStatus>> {
+        let configs = self.configs().await?;
+        // load_full because arc_swap's guards shouldn't be held across awaits
+        let conns = self.conns.load_full();
+        let synced_connections = self.synced_connections.load_full();
+
+        let consensus_head_num = synced_connections.head_block.as_ref().map(|x| x.number());
+
+        let mut statuses = Vec::with_capacity(configs.len());
+
+        for (name, config) in configs.iter() {
+            let conn = conns.get(name);
+
+            let connected = match conn {
+                Some(conn) => conn
+                    .provider_state
+                    .read()
+                    .await
+                    .provider(false)
+                    .await
+                    .is_some(),
+                None => false,
+            };
+
+            let synced = conn
+                .map(|conn| {
+                    synced_connections
+                        .conns
+                        .iter()
+                        .any(|x| Arc::ptr_eq(x, conn))
+                })
+                .unwrap_or(false);
+
+            let head_block = conn.and_then(|x| x.head_block.read().clone());
+
+            let head_block_num = head_block.as_ref().map(|x| x.number());
+
+            let head_block_lag = match (consensus_head_num, head_block_num) {
+                (Some(consensus), Some(ours)) => Some(consensus.saturating_sub(ours).as_u64()),
+                _ => None,
+            };
+
+            let status = BackendStatus {
+                name: name.clone(),
+                display_name: config.display_name.clone(),
+                client: conn.and_then(|x| x.client.read().clone()),
+                disabled: config.disabled,
+                connected,
+                synced,
+                head_block_num,
+                head_block_hash: head_block.as_ref().map(|x| x.hash()),
+                head_block_lag,
+                tier: config.tier,
+                soft_limit: config.soft_limit,
+                hard_limit: config.hard_limit,
+                active_requests: conn
+                    .map(|x| x.active_requests.load(Ordering::Relaxed))
+                    .unwrap_or_default(),
+                error_rate: conn.and_then(|x| x.backend_metrics.recent.error_rate()),
+                latency_ewma_ms: conn.and_then(|x| x.latency_ewma.millis()),
+                payload_anomaly: conn.map(|x| x.has_payload_anomaly()).unwrap_or_default(),
+                unsupported_methods: conn
+                    .map(|x| {
+                        let mut methods: Vec<_> =
+                            x.unsupported_methods.read().iter().cloned().collect();
+
+                        methods.sort();
+
+                        methods
+                    })
+                    .unwrap_or_default(),
+            };
+
+            statuses.push(status);
+        }
+
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(statuses)
+    }
+}
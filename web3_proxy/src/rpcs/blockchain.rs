@@ -1,6 +1,7 @@
-///! Keep track of the blockchain as seen by a Web3Connections.
+//! Keep track of the blockchain as seen by a Web3Connections.
 use super::connection::Web3Connection;
 use super::connections::Web3Connections;
+use super::request::Traffic;
 use super::transactions::TxStatus;
 use crate::frontend::authorization::Authorization;
 use crate::{
@@ -14,7 +15,6 @@ use log::{debug, warn, Level};
 use moka::future::Cache;
 use serde::Serialize;
 use serde_json::json;
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::{cmp::Ordering, fmt::Display, sync::Arc};
 use tokio::sync::{broadcast, watch};
 use tokio::time::Duration;
@@ -24,6 +24,23 @@ pub type ArcBlock = Arc<Block<TxHash>>;
 
 pub type BlockHashesCache = Cache<H256, ArcBlock, hashbrown::hash_map::DefaultHashBuilder>;
 
+/// How far back to look for the block that an old and a new consensus head share.
+pub const MAX_REORG_DEPTH: u64 = 64;
+
+/// The consensus head moved to a block that does not descend from the previous consensus head.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reorg {
+    pub old_head: H256,
+    pub new_head: H256,
+    pub new_head_number: U64,
+    /// the newest block on both chains. None if it is more than `MAX_REORG_DEPTH` blocks back
+    pub common_ancestor: Option<H256>,
+    pub common_ancestor_number: Option<U64>,
+    /// blocks that were on the consensus chain and no longer are. newest first
+    pub orphaned: Vec<ArcBlock>,
+}
+
 /// A block's hash and number.
 #[derive(Clone, Debug, Default, From, Serialize)]
 pub struct SavedBlock {
@@ -55,19 +72,17 @@ impl SavedBlock {
     }
 
     pub fn lag(&self) -> u64 {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("there should always be time");
+        self.lag_duration().as_secs()
+    }
+
+    /// Like `lag`, but without rounding down to whole seconds. Needed on chains with sub-second blocks
+    pub fn lag_duration(&self) -> Duration {
+        let now = virtual_clock::unix_now();
 
         let block_timestamp = Duration::from_secs(self.block.timestamp.as_u64());
 
-        if block_timestamp < now {
-            // this server is still syncing from too far away to serve requests
-            // u64 is safe because ew checked equality above
-            (now - block_timestamp).as_secs() as u64
-        } else {
-            0
-        }
+        // this server is still syncing from too far away to serve requests
+        now.saturating_sub(block_timestamp)
     }
 
     pub fn hash(&self) -> H256 {
@@ -146,8 +161,14 @@ impl Web3Connections {
         let get_block_params = (*hash, false);
         // TODO: if error, retry?
         let block: ArcBlock = match rpc {
+            // the rpc told us about this block. fetching it is part of following its head
             Some(rpc) => rpc
-                .wait_for_request_handle(authorization, Duration::from_secs(30), false)
+                .wait_for_request_handle(
+                    authorization,
+                    Duration::from_secs(30),
+                    false,
+                    Traffic::Subscription,
+                )
                 .await?
                 .request::<_, Option<_>>(
                     "eth_getBlockByHash",
@@ -165,7 +186,14 @@ impl Web3Connections {
                 // TODO: request_metadata? maybe we should put it in the authorization?
                 // TODO: don't hard code allowed lag
                 let response = self
-                    .try_send_best_upstream_server(60, authorization, request, None, None)
+                    .try_send_best_upstream_server(
+                        60,
+                        authorization,
+                        request,
+                        None,
+                        None,
+                        Traffic::Request,
+                    )
                     .await?;
 
                 let block = response.result.context("failed fetching block")?;
@@ -183,6 +211,105 @@ impl Web3Connections {
         Ok(block)
     }
 
+    /// Compare the old and new consensus heads. None if the new head descends from the old head.
+    async fn find_reorg(
+        &self,
+        authorization: &Arc<Authorization>,
+        old_head: &SavedBlock,
+        new_head: &SavedBlock,
+    ) -> anyhow::Result<Option<Reorg>> {
+        if new_head.number() > old_head.number() + U64::from(MAX_REORG_DEPTH) {
+            // we jumped too far to check cheaply. this happens when the proxy catches up after being unsynced
+            return Ok(None);
+        }
+
+        let mut old_block = old_head.block.clone();
+        let mut new_block = new_head.block.clone();
+        let mut orphaned = vec![];
+
+        for _ in 0..MAX_REORG_DEPTH * 2 {
+            if old_block.hash == new_block.hash {
+                if orphaned.is_empty() {
+                    return Ok(None);
+                }
+
+                return Ok(Some(Reorg {
+                    old_head: old_head.hash(),
+                    new_head: new_head.hash(),
+                    new_head_number: new_head.number(),
+                    common_ancestor: old_block.hash,
+                    common_ancestor_number: old_block.number,
+                    orphaned,
+                }));
+            }
+
+            let old_num = old_block.number.context("no block num")?;
+            let new_num = new_block.number.context("no block num")?;
+
+            // walk back whichever chain is higher. if they are at the same height, walk back both
+            if new_num >= old_num {
+                new_block = self
+                    .block(authorization, &new_block.parent_hash, None)
+                    .await?;
+            }
+
+            if old_num >= new_num {
+                orphaned.push(old_block.clone());
+
+                old_block = self
+                    .block(authorization, &old_block.parent_hash, None)
+                    .await?;
+            }
+        }
+
+        // the chains split too long ago to find where
+        Ok(Some(Reorg {
+            old_head: old_head.hash(),
+            new_head: new_head.hash(),
+            new_head_number: new_head.number(),
+            common_ancestor: None,
+            common_ancestor_number: None,
+            orphaned,
+        }))
+    }
+
+    /// If the consensus head re-rooted, remember the orphaned blocks and tell `reorg_sender`'s subscribers.
+    async fn check_reorg(
+        &self,
+        authorization: &Arc<Authorization>,
+        old_head: &SavedBlock,
+        new_head: &SavedBlock,
+    ) {
+        match self.find_reorg(authorization, old_head, new_head).await {
+            Ok(None) => {}
+            Ok(Some(reorg)) => {
+                warn!(
+                    "reorg! {} blocks orphaned. old_head={} new_head={}",
+                    reorg.orphaned.len(),
+                    old_head,
+                    new_head
+                );
+
+                for block in reorg.orphaned.iter() {
+                    if let Some(hash) = block.hash {
+                        self.orphaned_blocks.insert(hash, block.clone()).await;
+                    }
+                }
+
+                // an error here just means that nobody is subscribed
+                let _ = self.reorg_sender.send(Arc::new(reorg));
+            }
+            Err(err) => {
+                warn!("unable to check for a reorg. err={:?}", err);
+            }
+        }
+    }
+
+    /// A block that was recently orphaned by a reorg. Backends might not serve these anymore.
+    pub fn orphaned_block(&self, hash: &H256) -> Option<ArcBlock> {
+        self.orphaned_blocks.get(hash)
+    }
+
     /// Convenience method to get the cannonical block at a given block height.
     pub async fn block_hash(
         &self,
@@ -241,7 +368,14 @@ impl Web3Connections {
         // TODO: if error, retry?
         // TODO: request_metadata or authorization?
         let response = self
-            .try_send_best_upstream_server(60, authorization, request, None, Some(num))
+            .try_send_best_upstream_server(
+                60,
+                authorization,
+                request,
+                None,
+                Some(num),
+                Traffic::Request,
+            )
             .await?;
 
         let raw_block = response.result.context("no block result")?;
@@ -254,6 +388,55 @@ impl Web3Connections {
         Ok((block, archive_needed))
     }
 
+    /// The consensus head block with all of its fields.
+    /// Blocks from `newHeads` subscriptions are only headers. Those are fetched by hash once and saved.
+    pub async fn full_head_block(
+        &self,
+        authorization: &Arc<Authorization>,
+    ) -> anyhow::Result<ArcBlock> {
+        let head_block = self.head_block().context("no servers synced")?;
+
+        // only full blocks have a size
+        if head_block.block.size.is_some() {
+            return Ok(head_block.block);
+        }
+
+        let head_block_hash = head_block.hash();
+
+        if let Some(block) = self.block_hashes.get(&head_block_hash) {
+            if block.size.is_some() {
+                return Ok(block);
+            }
+        }
+
+        let request = json!({ "jsonrpc": "2.0", "id": "1", "method": "eth_getBlockByHash", "params": (head_block_hash, false) });
+        let request: JsonRpcRequest = serde_json::from_value(request)?;
+
+        let response = self
+            .try_send_best_upstream_server(
+                60,
+                authorization,
+                request,
+                None,
+                Some(&head_block.number()),
+                Traffic::Request,
+            )
+            .await?;
+
+        let raw_block = response.result.context("no block result")?;
+
+        let block: Option<ArcBlock> = serde_json::from_str(raw_block.get())?;
+
+        let block = block.context("no head block!")?;
+
+        // replace the header with the full block
+        self.block_hashes
+            .insert(head_block_hash, block.clone())
+            .await;
+
+        Ok(block)
+    }
+
     pub(super) async fn process_incoming_blocks(
         &self,
         authorization: &Arc<Authorization>,
@@ -267,6 +450,9 @@ impl Web3Connections {
         // TODO: this will grow unbounded. prune old heads on this at the same time we prune the graph?
         let mut connection_heads = HashMap::new();
 
+        // sending fails without any receivers. keep one so that groups that nobody watches still track their heads
+        let _head_block_receiver = head_block_sender.subscribe();
+
         while let Ok((new_block, rpc)) = block_receiver.recv_async().await {
             let new_block = new_block.map(Into::into);
 
@@ -303,8 +489,11 @@ impl Web3Connections {
         rpc_head_block: Option<SavedBlock>,
         rpc: Arc<Web3Connection>,
         head_block_sender: &watch::Sender<ArcBlock>,
-        pending_tx_sender: &Option<broadcast::Sender<TxStatus>>,
+        _pending_tx_sender: &Option<broadcast::Sender<TxStatus>>,
     ) -> anyhow::Result<()> {
+        // bad blocks are not saved. the rpc is quarantined and treated like it has no head until then
+        let rpc_head_block = rpc_head_block.filter(|x| self.sane_block_from_rpc(&x.block, &rpc));
+
         // add the rpc's block to connection_heads, or remove the rpc from connection_heads
         let rpc_head_block = match rpc_head_block {
             Some(rpc_head_block) => {
@@ -370,10 +559,10 @@ impl Web3Connections {
                 warn!("Missing connection_head_block in block_hashes. Fetching now. hash={}. other={}. rpc={}", connection_head_hash, conn_name, rpc);
 
                 // this option should always be populated
-                let conn_rpc = self.conns.get(conn_name);
+                let conn_rpc = self.get(conn_name);
 
                 match self
-                    .block(authorization, connection_head_hash, conn_rpc)
+                    .block(authorization, connection_head_hash, conn_rpc.as_ref())
                     .await
                 {
                     Ok(block) => block,
@@ -435,9 +624,9 @@ impl Web3Connections {
                         continue;
                     }
 
-                    if let Some(rpc) = self.conns.get(conn_name) {
+                    if let Some(rpc) = self.get(conn_name) {
                         highest_rpcs.insert(conn_name);
-                        highest_rpcs_sum_soft_limit += rpc.soft_limit;
+                        highest_rpcs_sum_soft_limit += rpc.soft_limit();
                     } else {
                         warn!("connection missing")
                     }
@@ -473,7 +662,7 @@ impl Web3Connections {
             // TODO: if consensus_head_rpcs.is_empty, try another method of finding the head block
 
             let num_connection_heads = connection_heads.len();
-            let total_conns = self.conns.len();
+            let total_conns = self.conns.load().len();
 
             // we've done all the searching for the heaviest block that we can
             if highest_rpcs.is_empty() {
@@ -497,7 +686,7 @@ impl Web3Connections {
                 // success! this block has enough soft limit and nodes on it (or on later blocks)
                 let conns: Vec<Arc<Web3Connection>> = highest_rpcs
                     .into_iter()
-                    .filter_map(|conn_name| self.conns.get(conn_name).cloned())
+                    .filter_map(|conn_name| self.get(conn_name))
                     .collect();
 
                 // TODO: DEBUG only check
@@ -578,9 +767,11 @@ impl Web3Connections {
                                         .await
                                         .context("save consensus_head_block as heaviest chain")?;
 
-                                    head_block_sender.send(consensus_head_block.block).context(
-                                        "head_block_sender sending consensus_head_block",
-                                    )?;
+                                    head_block_sender
+                                        .send(consensus_head_block.block.clone())
+                                        .context(
+                                            "head_block_sender sending consensus_head_block",
+                                        )?;
                                 }
                             }
                             Ordering::Less => {
@@ -596,7 +787,7 @@ impl Web3Connections {
                                     )?;
 
                                 head_block_sender
-                                    .send(consensus_head_block.block)
+                                    .send(consensus_head_block.block.clone())
                                     .context("head_block_sender sending consensus_head_block")?;
                             }
                             Ordering::Greater => {
@@ -612,9 +803,12 @@ impl Web3Connections {
 
                                 self.save_block(&consensus_head_block.block, true).await?;
 
-                                head_block_sender.send(consensus_head_block.block)?;
+                                head_block_sender.send(consensus_head_block.block.clone())?;
                             }
                         }
+
+                        self.check_reorg(authorization, old_head_block, &consensus_head_block)
+                            .await;
                     }
                 }
             }
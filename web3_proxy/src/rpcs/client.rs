@@ -0,0 +1,187 @@
+//! Which node software a backend runs, from `web3_clientVersion`, and the quirks that come with it.
+//!
+//! Client batches are split into single requests in `proxy_web3_rpc_requests` before they are routed,
+//! so limits like erigon's `--rpc.batch.limit` never apply to the requests that backends get.
+use ethers::types::U64;
+use serde::Serialize;
+use std::fmt;
+
+/// besu's default `--rpc-max-logs-range`
+const BESU_MAX_LOGS_RANGE: u64 = 5_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    Geth,
+    Erigon,
+    Nethermind,
+    Besu,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct NodeClient {
+    pub kind: ClientKind,
+    /// without a leading "v". None if the client version didn't have one
+    pub version: Option<String>,
+    /// exactly what `web3_clientVersion` returned
+    pub client_version: String,
+}
+
+impl NodeClient {
+    /// Client versions look like "Geth/v1.10.26-stable-e5eb32ac/linux-amd64/go1.18.5" or "erigon/2.30.0/linux-amd64/go1.19.3".
+    pub fn parse(client_version: &str) -> Self {
+        let mut parts = client_version.split('/');
+
+        let kind = match parts.next().map(|x| x.to_lowercase()).as_deref() {
+            Some("geth") => ClientKind::Geth,
+            Some("erigon") => ClientKind::Erigon,
+            Some("nethermind") => ClientKind::Nethermind,
+            Some("besu") => ClientKind::Besu,
+            _ => ClientKind::Other,
+        };
+
+        let version = parts
+            .next()
+            .map(|x| x.trim_start_matches('v'))
+            .filter(|x| x.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|x| x.to_string());
+
+        Self {
+            kind,
+            version,
+            client_version: client_version.to_string(),
+        }
+    }
+
+    /// The widest `eth_getLogs` block range that this client allows by default. None = no limit
+    pub fn max_logs_range(&self) -> Option<u64> {
+        match self.kind {
+            ClientKind::Besu => Some(BESU_MAX_LOGS_RANGE),
+            _ => None,
+        }
+    }
+
+    /// false for methods that this client doesn't have at all
+    pub fn has_method(&self, method: &str) -> bool {
+        match self.kind {
+            // geth traces with debug_trace*. only the parity-style clients have the trace_ namespace
+            ClientKind::Geth => !method.starts_with("trace_"),
+            _ => true,
+        }
+    }
+
+    /// false if this client is known to reject the request.
+    /// `max_logs_range` is the server's configured limit. If None, the client's default is used
+    pub fn can_serve(
+        &self,
+        method: &str,
+        params: Option<&serde_json::Value>,
+        head: U64,
+        max_logs_range: Option<u64>,
+    ) -> bool {
+        if !self.has_method(method) {
+            return false;
+        }
+
+        match max_logs_range.or_else(|| self.max_logs_range()) {
+            None => true,
+            Some(max) => within_logs_range(method, params, head, max),
+        }
+    }
+}
+
+impl fmt::Display for NodeClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.client_version)
+    }
+}
+
+/// false for an `eth_getLogs` request that covers more than `max` blocks
+pub fn within_logs_range(
+    method: &str,
+    params: Option<&serde_json::Value>,
+    head: U64,
+    max: u64,
+) -> bool {
+    if method != "eth_getLogs" {
+        return true;
+    }
+
+    match logs_block_range(params, head) {
+        None => true,
+        Some(range) => range <= max,
+    }
+}
+
+/// How many blocks an `eth_getLogs` filter covers. Tags are treated as `head`.
+/// None for a `blockHash` filter or params that can't be read.
+pub fn logs_block_range(params: Option<&serde_json::Value>, head: U64) -> Option<u64> {
+    let filter = params?.get(0)?.as_object()?;
+
+    if filter.contains_key("blockHash") {
+        return None;
+    }
+
+    let block_num = |key: &str| match filter.get(key) {
+        None => Some(head),
+        Some(x) => match x.as_str() {
+            Some(x) if x.starts_with("0x") => U64::from_str_radix(&x[2..], 16).ok(),
+            // "latest", "safe", "pending", etc. "earliest" is rare enough to not matter
+            Some(_) => Some(head),
+            None => serde_json::from_value(x.clone()).ok(),
+        },
+    };
+
+    let from_block = block_num("fromBlock")?;
+    let to_block = block_num("toBlock")?;
+
+    Some(to_block.saturating_sub(from_block).as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_client_version() {
+        let x = NodeClient::parse("Geth/v1.10.26-stable-e5eb32ac/linux-amd64/go1.18.5");
+        assert_eq!(x.kind, ClientKind::Geth);
+        assert_eq!(x.version.as_deref(), Some("1.10.26-stable-e5eb32ac"));
+
+        let x = NodeClient::parse("erigon/2.30.0/linux-amd64/go1.19.3");
+        assert_eq!(x.kind, ClientKind::Erigon);
+        assert_eq!(x.version.as_deref(), Some("2.30.0"));
+
+        let x = NodeClient::parse("besu/v22.10.3/linux-x86_64/openjdk-java-17");
+        assert_eq!(x.kind, ClientKind::Besu);
+
+        let x = NodeClient::parse("some-hosted-provider");
+        assert_eq!(x.kind, ClientKind::Other);
+        assert_eq!(x.version, None);
+    }
+
+    #[test]
+    fn test_quirks() {
+        let head = U64::from(100_000);
+
+        let geth = NodeClient::parse("Geth/v1.10.26-stable/linux-amd64/go1.18.5");
+        assert!(!geth.can_serve("trace_block", None, head, None));
+        assert!(geth.can_serve("debug_traceTransaction", None, head, None));
+
+        let besu = NodeClient::parse("besu/v22.10.3/linux-x86_64/openjdk-java-17");
+        let wide = json!([{ "fromBlock": "0x0", "toBlock": "latest" }]);
+        let narrow = json!([{ "fromBlock": "0x1869f" }]);
+
+        assert!(!besu.can_serve("eth_getLogs", Some(&wide), head, None));
+        assert!(besu.can_serve("eth_getLogs", Some(&narrow), head, None));
+        assert!(geth.can_serve("eth_getLogs", Some(&wide), head, None));
+
+        // operators that raised `--rpc-max-logs-range` can say so in the config
+        assert!(besu.can_serve("eth_getLogs", Some(&wide), head, Some(u64::MAX)));
+        assert!(!geth.can_serve("eth_getLogs", Some(&wide), head, Some(5_000)));
+        assert!(within_logs_range("eth_getLogs", Some(&narrow), head, 5_000));
+        assert!(within_logs_range("eth_call", Some(&wide), head, 0));
+    }
+}
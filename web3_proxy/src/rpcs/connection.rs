@@ -1,15 +1,22 @@
-///! Rate-limited communication with a web3 provider.
+//! Rate-limited communication with a web3 provider.
+use super::backend_metrics::BackendMetrics;
 use super::blockchain::{ArcBlock, BlockHashesCache, SavedBlock};
+use super::client::{within_logs_range, NodeClient};
+use super::costs::MethodCounts;
+use super::ewma::EwmaLatency;
 use super::provider::Web3Provider;
-use super::request::{OpenRequestHandle, OpenRequestHandleMetrics, OpenRequestResult};
+use super::request::{OpenRequestHandle, OpenRequestHandleMetrics, OpenRequestResult, Traffic};
 use crate::app::{flatten_handle, AnyhowJoinHandle};
 use crate::config::BlockAndRpc;
 use crate::frontend::authorization::Authorization;
+use crate::jsonrpc::JsonRpcRequest;
+use crate::telemetry;
 use anyhow::Context;
-use ethers::prelude::{Bytes, Middleware, ProviderError, TxHash, H256, U64};
+use ethers::prelude::{ProviderError, TxHash, H256, U64};
 use ethers::types::U256;
-use futures::future::try_join_all;
+use futures::future::{try_join_all, AbortHandle, Abortable};
 use futures::StreamExt;
+use hashbrown::HashSet;
 use log::{debug, error, info, trace, warn, Level};
 use migration::sea_orm::DatabaseConnection;
 use parking_lot::RwLock;
@@ -19,14 +26,60 @@ use serde::Serialize;
 use serde_json::json;
 use std::cmp::min;
 use std::fmt;
+use std::future::Future;
 use std::hash::{Hash, Hasher};
 use std::sync::atomic::{self, AtomicU32, AtomicU64};
+use std::sync::Weak;
 use std::{cmp::Ordering, sync::Arc};
 use thread_fast_rng::rand::Rng;
 use thread_fast_rng::thread_fast_rng;
 use tokio::sync::{broadcast, oneshot, RwLock as AsyncRwLock};
 use tokio::time::{interval, sleep, sleep_until, timeout, Duration, Instant, MissedTickBehavior};
 
+/// Aborts the tasks when dropped. Stopping `subscribe` also stops everything that it spawned.
+#[derive(Default)]
+struct AbortOnDrop(Vec<AbortHandle>);
+
+impl AbortOnDrop {
+    fn spawn<F>(&mut self, f: F) -> AnyhowJoinHandle<()>
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        self.0.push(abort_handle);
+
+        tokio::spawn(async move {
+            // an aborted task was stopped on purpose
+            Abortable::new(f, abort_registration)
+                .await
+                .unwrap_or(Ok(()))
+        })
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for x in self.0.iter() {
+            x.abort();
+        }
+    }
+}
+
+/// Ramp a soft limit from 10% to 100% over `slow_start`.
+fn slow_start_soft_limit(soft_limit: u32, elapsed: Duration, slow_start: Duration) -> u32 {
+    if elapsed >= slow_start {
+        return soft_limit;
+    }
+
+    let ramp = 0.1 + 0.9 * elapsed.as_secs_f64() / slow_start.as_secs_f64();
+
+    ((soft_limit as f64 * ramp) as u32).max(1)
+}
+
+/// How often automatic block data limits are checked again.
+const BLOCK_DATA_LIMIT_RECHECK: Duration = Duration::from_secs(60 * 60);
+
 // TODO: maybe provider state should have the block data limit in it. but it is inside an async lock and we can't Serialize then
 #[derive(Clone, Debug)]
 pub enum ProviderState {
@@ -70,6 +123,8 @@ pub struct Web3Connection {
     pub(super) http_client: Option<reqwest::Client>,
     /// keep track of currently open requests. We sort on this
     pub(super) active_requests: AtomicU32,
+    /// keep track of currently open subscription traffic. This has its own soft limit
+    pub(super) active_subscriptions: AtomicU32,
     /// keep track of total requests from the frontend
     pub(super) frontend_requests: AtomicU64,
     /// keep track of total requests from web3-proxy itself
@@ -80,17 +135,42 @@ pub struct Web3Connection {
     /// rate limits are stored in a central redis so that multiple proxies can share their rate limits
     /// We do not use the deferred rate limiter because going over limits would cause errors
     pub(super) hard_limit: Option<RedisRateLimiter>,
-    /// used for load balancing to the least loaded server
-    pub(super) soft_limit: u32,
+    /// hosted providers tell us when they are rate limiting us. don't send them anything until then
+    pub(super) hard_limit_until: RwLock<Option<Instant>>,
+    /// sent bad chain data. ignored until then
+    pub(super) quarantined_until: RwLock<Option<Instant>>,
+    /// used for load balancing to the least loaded server. admins can change this while connected
+    pub(super) soft_limit: AtomicU32,
+    /// like soft_limit, but for subscription traffic. admins can change this while connected
+    pub(super) subscription_soft_limit: AtomicU32,
     /// use web3 queries to find the block data limit for archive/pruned nodes
     pub(super) automatic_block_limit: bool,
     /// TODO: have an enum for this so that "no limit" prints pretty?
     pub(super) block_data_limit: AtomicU64,
-    /// Lower tiers are higher priority when sending requests
-    pub(super) tier: u64,
+    /// Lower tiers are higher priority when sending requests. admins can change this while connected
+    pub(super) tier: AtomicU64,
+    /// give up on a request after this long and retry on another server
+    pub(super) request_timeout: Option<Duration>,
+    /// after connecting, the soft limit ramps up over this long
+    pub(super) slow_start: Duration,
+    /// when the current provider connected
+    pub(super) connected_at: RwLock<Option<Instant>>,
     /// TODO: should this be an AsyncRwLock?
     pub(super) head_block: RwLock<Option<SavedBlock>>,
     pub(super) open_request_handle_metrics: Arc<OpenRequestHandleMetrics>,
+    /// requests sent to this server by method. used to attribute costs
+    pub(super) method_counts: MethodCounts,
+    /// latency and errors for prometheus
+    pub(super) backend_metrics: BackendMetrics,
+    /// recent response latency. faster servers get more requests
+    pub(super) latency_ewma: EwmaLatency,
+    /// the node software, from web3_clientVersion. None until connected or if the server doesn't say
+    pub(super) client: RwLock<Option<NodeClient>>,
+    /// the widest `eth_getLogs` block range this server allows. None = the default for its node software
+    pub(super) max_logs_range: Option<u64>,
+    /// methods that answered "method not found". only methods that the connections track are added.
+    /// cleared when the server reconnects because it might have been upgraded
+    pub(super) unsupported_methods: RwLock<HashSet<String>>,
 }
 
 impl Web3Connection {
@@ -111,12 +191,16 @@ impl Web3Connection {
         hard_limit: Option<(u64, RedisPool)>,
         // TODO: think more about this type
         soft_limit: u32,
+        subscription_soft_limit: u32,
         block_data_limit: Option<u64>,
         block_map: BlockHashesCache,
         block_sender: Option<flume::Sender<BlockAndRpc>>,
         tx_id_sender: Option<flume::Sender<(TxHash, Arc<Self>)>>,
         reconnect: bool,
         tier: u64,
+        request_timeout: Option<Duration>,
+        slow_start: Duration,
+        max_logs_range: Option<u64>,
         open_request_handle_metrics: Arc<OpenRequestHandleMetrics>,
     ) -> anyhow::Result<(Arc<Web3Connection>, AnyhowJoinHandle<()>)> {
         let hard_limit = hard_limit.map(|(hard_rate_limit, redis_pool)| {
@@ -143,20 +227,42 @@ impl Web3Connection {
             http_client,
             url: url_str,
             active_requests: 0.into(),
+            active_subscriptions: 0.into(),
             frontend_requests: 0.into(),
             internal_requests: 0.into(),
             provider_state: AsyncRwLock::new(ProviderState::None),
             hard_limit,
-            soft_limit,
+            hard_limit_until: RwLock::new(None),
+            quarantined_until: RwLock::new(None),
+            soft_limit: soft_limit.into(),
+            subscription_soft_limit: subscription_soft_limit.into(),
             automatic_block_limit,
             block_data_limit,
             head_block: RwLock::new(Default::default()),
-            tier,
+            tier: tier.into(),
+            request_timeout,
+            slow_start,
+            connected_at: RwLock::new(None),
             open_request_handle_metrics,
+            method_counts: Default::default(),
+            backend_metrics: Default::default(),
+            latency_ewma: Default::default(),
+            client: Default::default(),
+            max_logs_range,
+            unsupported_methods: Default::default(),
         };
 
         let new_connection = Arc::new(new_connection);
 
+        if new_connection.automatic_block_limit {
+            let authorization = Arc::new(Authorization::internal(db_conn.clone())?);
+
+            tokio::spawn(Self::recheck_block_data_limit(
+                Arc::downgrade(&new_connection),
+                authorization,
+            ));
+        }
+
         // subscribe to new blocks and new transactions
         // subscribing starts the connection (with retries)
         // TODO: make transaction subscription optional (just pass None for tx_id_sender)
@@ -182,6 +288,7 @@ impl Web3Connection {
     }
 
     // TODO: would be great if rpcs exposed this. see https://github.com/ledgerwatch/erigon/issues/6391
+    /// Binary search for the oldest block that this rpc has state and block data for.
     async fn check_block_data_limit(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
@@ -193,7 +300,12 @@ impl Web3Connection {
 
         // check if we are synced
         let head_block: ArcBlock = self
-            .wait_for_request_handle(authorization, Duration::from_secs(30), true)
+            .wait_for_request_handle(
+                authorization,
+                Duration::from_secs(30),
+                true,
+                Traffic::Request,
+            )
             .await?
             .request::<_, Option<_>>(
                 "eth_getBlockByNumber",
@@ -204,87 +316,243 @@ impl Web3Connection {
             .await?
             .context("no block during check_block_data_limit!")?;
 
-        if SavedBlock::from(head_block).syncing(60) {
+        let head_block = SavedBlock::from(head_block);
+
+        if head_block.syncing(60) {
             // if the node is syncing, we can't check its block data limit
             return Ok(None);
         }
 
+        let head_block_num = head_block.number();
+
         // TODO: add SavedBlock to self? probably best not to. we might not get marked Ready
 
-        let mut limit = None;
+        // block 0 is skipped because some pruned nodes keep the genesis state
+        let limit = if self.has_archive_data(authorization, U64::one()).await? {
+            u64::MAX
+        } else if !self.has_archive_data(authorization, head_block_num).await? {
+            0
+        } else {
+            // `missing` never has data. `oldest` always does
+            let mut missing = U64::one();
+            let mut oldest = head_block_num;
 
-        // TODO: binary search between 90k and max?
-        // TODO: start at 0 or 1?
-        for block_data_limit in [0, 32, 64, 128, 256, 512, 1024, 90_000, u64::MAX] {
-            let handle = self
-                .wait_for_request_handle(authorization, Duration::from_secs(30), true)
-                .await?;
+            while oldest - missing > U64::one() {
+                let mid = missing + (oldest - missing) / 2;
 
-            let head_block_num_future = handle.request::<Option<()>, U256>(
-                "eth_blockNumber",
-                &None,
-                // error here are expected, so keep the level low
-                Level::Debug.into(),
-            );
+                if self.has_archive_data(authorization, mid).await? {
+                    oldest = mid;
+                } else {
+                    missing = mid;
+                }
+            }
 
-            let head_block_num = timeout(Duration::from_secs(5), head_block_num_future)
-                .await
-                .context("timeout fetching eth_blockNumber")?
-                .context("provider error")?;
+            (head_block_num - oldest).as_u64()
+        };
 
-            let maybe_archive_block = head_block_num.saturating_sub((block_data_limit).into());
+        let previous_limit = self.block_data_limit.load(atomic::Ordering::Acquire);
 
-            trace!(
-                "checking maybe_archive_block on {}: {}",
-                self,
-                maybe_archive_block
-            );
+        if limit < previous_limit {
+            // one failed probe is not enough to demote a server. it has to fail the oldest block it had before too
+            let previous_oldest = head_block_num
+                .saturating_sub(previous_limit.into())
+                .max(U64::one());
 
-            // TODO: wait for the handle BEFORE we check the current block number. it might be delayed too!
-            // TODO: what should the request be?
-            let handle = self
-                .wait_for_request_handle(authorization, Duration::from_secs(30), true)
-                .await?;
-
-            let archive_result: Result<Bytes, _> = handle
-                .request(
-                    "eth_getCode",
-                    &json!((
-                        "0xdead00000000000000000000000000000000beef",
-                        maybe_archive_block,
-                    )),
-                    // error here are expected, so keep the level low
-                    Level::Trace.into(),
-                )
-                .await;
-
-            trace!(
-                "archive_result on {} for {} ({}): {:?}",
-                self,
-                block_data_limit,
-                maybe_archive_block,
-                archive_result
-            );
+            if self
+                .has_archive_data(authorization, previous_oldest)
+                .await?
+            {
+                debug!(
+                    "{} still has block {}. keeping block data limit {}",
+                    self, previous_oldest, previous_limit
+                );
 
-            if archive_result.is_err() {
-                break;
+                return Ok(Some(previous_limit));
             }
+        }
+
+        if limit == 0 {
+            warn!("{} is unable to serve requests", self);
+        }
+
+        self.block_data_limit
+            .store(limit, atomic::Ordering::Release);
+
+        info!("block data limit on {}: {:?}", self, limit);
+
+        Ok(Some(limit))
+    }
+
+    /// true if the rpc can serve `eth_getBalance` and `eth_getBlockByNumber` for this block.
+    /// Errors from the rpc mean it doesn't have the data. Errors getting a request handle are returned.
+    async fn has_archive_data(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        block_num: U64,
+    ) -> anyhow::Result<bool> {
+        trace!("checking archive data on {}: {}", self, block_num);
+
+        let handle = self
+            .wait_for_request_handle(
+                authorization,
+                Duration::from_secs(30),
+                true,
+                Traffic::Request,
+            )
+            .await?;
+
+        let params = json!(("0xdead00000000000000000000000000000000beef", block_num));
 
-            limit = Some(block_data_limit);
+        let balance_future = handle.request::<_, U256>(
+            "eth_getBalance",
+            &params,
+            // error here are expected, so keep the level low
+            Level::Trace.into(),
+        );
+
+        match timeout(Duration::from_secs(5), balance_future).await {
+            Ok(Ok(_)) => {}
+            _ => return Ok(false),
         }
 
-        if let Some(limit) = limit {
-            if limit == 0 {
-                warn!("{} is unable to serve requests", self);
+        let handle = self
+            .wait_for_request_handle(
+                authorization,
+                Duration::from_secs(30),
+                true,
+                Traffic::Request,
+            )
+            .await?;
+
+        let params = json!((block_num, false));
+
+        let block_future = handle.request::<_, Option<ArcBlock>>(
+            "eth_getBlockByNumber",
+            &params,
+            // error here are expected, so keep the level low
+            Level::Trace.into(),
+        );
+
+        let has_block = matches!(
+            timeout(Duration::from_secs(5), block_future).await,
+            Ok(Ok(Some(_)))
+        );
+
+        trace!("archive data on {} for {}: {}", self, block_num, has_block);
+
+        Ok(has_block)
+    }
+
+    /// Pruning nodes forget old blocks as they run and nodes can be resynced with different settings.
+    /// Check the block data limit again every `BLOCK_DATA_LIMIT_RECHECK` until the connection is dropped.
+    async fn recheck_block_data_limit(conn: Weak<Self>, authorization: Arc<Authorization>) {
+        let mut interval = interval(BLOCK_DATA_LIMIT_RECHECK);
+
+        // the first tick is immediate. connecting already checked the limit
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let conn = match conn.upgrade() {
+                Some(x) => x,
+                None => break,
+            };
+
+            if let Err(err) = conn.check_block_data_limit(&authorization).await {
+                warn!("failed rechecking block limit on {}. err={:?}", conn, err);
             }
+        }
+    }
 
-            self.block_data_limit
-                .store(limit, atomic::Ordering::Release);
+    /// The client and url for sending requests directly. None if this server uses a websocket.
+    pub fn http(&self) -> Option<(&reqwest::Client, &str)> {
+        if !self.url.starts_with("http") {
+            return None;
         }
 
-        info!("block data limit on {}: {:?}", self, limit);
+        self.http_client.as_ref().map(|x| (x, self.url.as_str()))
+    }
 
-        Ok(limit)
+    /// false if this server answered "method not found" for the method since it connected
+    pub fn supports_method(&self, method: &str) -> bool {
+        !self.unsupported_methods.read().contains(method)
+    }
+
+    /// false if this server's node software or configured limits are known to reject the request
+    pub fn client_can_serve(&self, request: &JsonRpcRequest) -> bool {
+        let head_block_num = self
+            .head_block
+            .read()
+            .as_ref()
+            .map(|x| x.number())
+            .unwrap_or_default();
+
+        match self.client.read().as_ref() {
+            Some(client) => client.can_serve(
+                &request.method,
+                request.params.as_ref(),
+                head_block_num,
+                self.max_logs_range,
+            ),
+            // unknown software. only the configured limit applies
+            None => match self.max_logs_range {
+                None => true,
+                Some(max) => within_logs_range(
+                    &request.method,
+                    request.params.as_ref(),
+                    head_block_num,
+                    max,
+                ),
+            },
+        }
+    }
+
+    pub(super) fn mark_unsupported(&self, method: &str) {
+        if self.unsupported_methods.write().insert(method.to_string()) {
+            info!("{} does not support {}", self, method);
+        }
+    }
+
+    pub fn soft_limit(&self) -> u32 {
+        self.soft_limit.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn subscription_soft_limit(&self) -> u32 {
+        self.subscription_soft_limit.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn tier(&self) -> u64 {
+        self.tier.load(atomic::Ordering::Relaxed)
+    }
+
+    /// The soft limit to balance load with. It is reduced for a while after connecting.
+    pub fn effective_soft_limit(&self) -> u32 {
+        match *self.connected_at.read() {
+            Some(connected_at) => {
+                slow_start_soft_limit(self.soft_limit(), connected_at.elapsed(), self.slow_start)
+            }
+            None => self.soft_limit(),
+        }
+    }
+
+    /// How much more of this kind of traffic the server can take before it slows down. Negative when overloaded.
+    /// Subscriptions are cheap, so they skip the slow start.
+    pub fn available(&self, traffic: Traffic) -> f64 {
+        let soft_limit = match traffic {
+            Traffic::Request => self.effective_soft_limit(),
+            Traffic::Subscription => self.subscription_soft_limit(),
+        };
+
+        soft_limit as f64 - self.active(traffic).load(atomic::Ordering::Acquire) as f64
+    }
+
+    /// The counter of open handles for this kind of traffic.
+    pub(super) fn active(&self, traffic: Traffic) -> &AtomicU32 {
+        match traffic {
+            Traffic::Request => &self.active_requests,
+            Traffic::Subscription => &self.active_subscriptions,
+        }
     }
 
     /// TODO: this might be too simple. different nodes can prune differently. its possible we will have a block range
@@ -299,6 +567,13 @@ impl Web3Connection {
         }
     }
 
+    /// The oldest block this rpc can serve. None if we don't know its head block yet.
+    pub fn oldest_block(&self) -> Option<U64> {
+        let head_block_num = self.head_block.read().as_ref()?.number();
+
+        Some(head_block_num.saturating_sub(self.block_data_limit()))
+    }
+
     pub fn has_block_data(&self, needed_block_num: &U64) -> bool {
         let head_block_num = match self.head_block.read().clone() {
             None => return false,
@@ -447,7 +722,12 @@ impl Web3Connection {
         // TODO: what should the timeout be? should there be a request timeout?
         // trace!("waiting on chain id for {}", self);
         let found_chain_id: Result<U64, _> = self
-            .wait_for_request_handle(&authorization, Duration::from_secs(30), true)
+            .wait_for_request_handle(
+                &authorization,
+                Duration::from_secs(30),
+                true,
+                Traffic::Request,
+            )
             .await?
             .request(
                 "eth_chainId",
@@ -474,6 +754,31 @@ impl Web3Connection {
             }
         }
 
+        // the node software decides some routing. hosted providers might not answer this
+        let client = self
+            .wait_for_request_handle(
+                &authorization,
+                Duration::from_secs(30),
+                true,
+                Traffic::Request,
+            )
+            .await?
+            .request::<_, String>(
+                "web3_clientVersion",
+                &json!(Option::None::<()>),
+                Level::Trace.into(),
+            )
+            .await
+            .ok()
+            .map(|x| NodeClient::parse(&x));
+
+        match client.as_ref() {
+            Some(client) => info!("{} is running {}", self, client),
+            None => debug!("{} did not give its client version", self),
+        }
+
+        *self.client.write() = client;
+
         self.check_block_data_limit(&authorization).await?;
 
         {
@@ -492,6 +797,12 @@ impl Web3Connection {
             // trace!("unlocked for ready...");
         }
 
+        // start the slow start ramp over. the server might have restarted with cold caches
+        *self.connected_at.write() = Some(Instant::now());
+
+        // the server might have been upgraded
+        self.unsupported_methods.write().clear();
+
         info!("successfully connected to {}", self);
 
         Ok(())
@@ -596,6 +907,7 @@ impl Web3Connection {
             let http_interval_receiver = http_interval_sender.as_ref().map(|x| x.subscribe());
 
             let mut futures = vec![];
+            let mut children = AbortOnDrop::default();
 
             {
                 // health check
@@ -675,7 +987,7 @@ impl Web3Connection {
                     }
                 };
 
-                futures.push(flatten_handle(tokio::spawn(f)));
+                futures.push(flatten_handle(children.spawn(f)));
 
                 // wait on the initial connection
                 ready_rx.await?;
@@ -689,7 +1001,7 @@ impl Web3Connection {
                     block_map.clone(),
                 );
 
-                futures.push(flatten_handle(tokio::spawn(f)));
+                futures.push(flatten_handle(children.spawn(f)));
             }
 
             if let Some(tx_id_sender) = &tx_id_sender {
@@ -697,7 +1009,7 @@ impl Web3Connection {
                     .clone()
                     .subscribe_pending_transactions(authorization.clone(), tx_id_sender.clone());
 
-                futures.push(flatten_handle(tokio::spawn(f)));
+                futures.push(flatten_handle(children.spawn(f)));
             }
 
             match try_join_all(futures).await {
@@ -763,7 +1075,12 @@ impl Web3Connection {
                     loop {
                         // TODO: what should the max_wait be?
                         match self
-                            .wait_for_request_handle(&authorization, Duration::from_secs(30), false)
+                            .wait_for_request_handle(
+                                &authorization,
+                                Duration::from_secs(30),
+                                false,
+                                Traffic::Subscription,
+                            )
                             .await
                         {
                             Ok(active_request_handle) => {
@@ -846,10 +1163,15 @@ impl Web3Connection {
                         }
                     }
                 }
-                Web3Provider::Ws(provider) => {
+                Web3Provider::Ws(_) | Web3Provider::Ipc(_) => {
                     // todo: move subscribe_blocks onto the request handle?
                     let active_request_handle = self
-                        .wait_for_request_handle(&authorization, Duration::from_secs(30), false)
+                        .wait_for_request_handle(
+                            &authorization,
+                            Duration::from_secs(30),
+                            false,
+                            Traffic::Subscription,
+                        )
                         .await;
                     let mut stream = provider.subscribe_blocks().await?;
                     drop(active_request_handle);
@@ -859,7 +1181,12 @@ impl Web3Connection {
                     // all it does is print "new block" for the same block as current block
                     // TODO: how does this get wrapped in an arc? does ethers handle that?
                     let block: Result<Option<ArcBlock>, _> = self
-                        .wait_for_request_handle(&authorization, Duration::from_secs(30), false)
+                        .wait_for_request_handle(
+                            &authorization,
+                            Duration::from_secs(30),
+                            false,
+                            Traffic::Subscription,
+                        )
                         .await?
                         .request(
                             "eth_getBlockByNumber",
@@ -930,7 +1257,7 @@ impl Web3Connection {
             trace!("watching pending transactions on {}", self);
             match provider.as_ref() {
                 Web3Provider::Mock => unimplemented!(),
-                Web3Provider::Http(provider) => {
+                Web3Provider::Http(_provider) => {
                     // there is a "watch_pending_transactions" function, but a lot of public nodes do not support the necessary rpc endpoints
                     // TODO: what should this interval be? probably automatically set to some fraction of block time
                     // TODO: maybe it would be better to have one interval for all of the http providers, but this works for now
@@ -957,10 +1284,15 @@ impl Web3Connection {
                         interval.tick().await;
                     }
                 }
-                Web3Provider::Ws(provider) => {
+                Web3Provider::Ws(_) | Web3Provider::Ipc(_) => {
                     // TODO: maybe the subscribe_pending_txs function should be on the active_request_handle
                     let active_request_handle = self
-                        .wait_for_request_handle(&authorization, Duration::from_secs(30), false)
+                        .wait_for_request_handle(
+                            &authorization,
+                            Duration::from_secs(30),
+                            false,
+                            Traffic::Subscription,
+                        )
                         .await;
 
                     let mut stream = provider.subscribe_pending_txs().await?;
@@ -999,12 +1331,13 @@ impl Web3Connection {
         authorization: &Arc<Authorization>,
         max_wait: Duration,
         allow_not_ready: bool,
+        traffic: Traffic,
     ) -> anyhow::Result<OpenRequestHandle> {
         let max_wait = Instant::now() + max_wait;
 
         loop {
             match self
-                .try_request_handle(authorization, allow_not_ready)
+                .try_request_handle(authorization, allow_not_ready, traffic)
                 .await
             {
                 Ok(OpenRequestResult::Handle(handle)) => return Ok(handle),
@@ -1031,11 +1364,45 @@ impl Web3Connection {
         }
     }
 
+    /// The provider told us that it is rate limiting us. Keep the latest time we are allowed to retry.
+    pub(super) fn set_hard_limit_until(&self, retry_at: Instant) {
+        let mut hard_limit_until = self.hard_limit_until.write();
+
+        if hard_limit_until.map(|x| x < retry_at).unwrap_or(true) {
+            warn!(
+                "{} is rate limited by the provider. Retry at {:?}",
+                self, retry_at
+            );
+
+            *hard_limit_until = Some(retry_at);
+        }
+    }
+
+    /// A request to this server was given up on after `timeout`.
+    /// Cancelled requests never finish `OpenRequestHandle::request`, so they are counted here instead
+    pub(super) fn record_timeout(&self, timeout: Duration) {
+        // as slow as it was allowed to be. otherwise a server that hangs would look fast
+        self.latency_ewma.record(timeout);
+        // the timed out request is still in its trace
+        self.backend_metrics.latency.record(
+            timeout,
+            telemetry::sampled_trace_id(&opentelemetry::Context::current()),
+        );
+
+        self.backend_metrics
+            .errors
+            .fetch_add(1, atomic::Ordering::Relaxed);
+
+        self.backend_metrics.recent.add_request();
+        self.backend_metrics.recent.add_error();
+    }
+
     pub async fn try_request_handle(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
         // TODO? ready_provider: Option<&Arc<Web3Provider>>,
         allow_not_ready: bool,
+        traffic: Traffic,
     ) -> anyhow::Result<OpenRequestResult> {
         // TODO: think more about this read block
         if !allow_not_ready
@@ -1050,7 +1417,25 @@ impl Web3Connection {
             return Ok(OpenRequestResult::NotReady);
         }
 
+        if self.quarantined() {
+            return Ok(OpenRequestResult::NotReady);
+        }
+
+        // check rate limits that the provider told us about
+        if let Some(retry_at) = *self.hard_limit_until.read() {
+            if retry_at > Instant::now() {
+                trace!(
+                    "{} is rate limited by the provider until {:?}",
+                    self,
+                    retry_at
+                );
+
+                return Ok(OpenRequestResult::RetryAt(retry_at));
+            }
+        }
+
         // check rate limits
+        // this is the provider's limit, so subscription traffic counts too
         if let Some(ratelimiter) = self.hard_limit.as_ref() {
             // TODO: how should we know if we should set expire or not?
             match ratelimiter.throttle().await? {
@@ -1072,7 +1457,7 @@ impl Web3Connection {
             }
         };
 
-        let handle = OpenRequestHandle::new(authorization.clone(), self.clone()).await;
+        let handle = OpenRequestHandle::new(authorization.clone(), self.clone(), traffic).await;
 
         Ok(OpenRequestResult::Handle(handle))
     }
@@ -1085,6 +1470,46 @@ impl fmt::Debug for Web3Provider {
     }
 }
 
+#[cfg(test)]
+impl Web3Connection {
+    /// A connected archive server with a mock provider and no head block.
+    /// Tests change what they need with struct update syntax so that new fields only go here.
+    pub(crate) fn mock(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            allowed_lag: 10,
+            db_conn: None,
+            display_name: None,
+            url: format!("ws://example.com/{}", name),
+            http_client: None,
+            active_requests: 0.into(),
+            active_subscriptions: 0.into(),
+            frontend_requests: 0.into(),
+            internal_requests: 0.into(),
+            provider_state: AsyncRwLock::new(ProviderState::Ready(Arc::new(Web3Provider::Mock))),
+            hard_limit: None,
+            hard_limit_until: RwLock::new(None),
+            quarantined_until: RwLock::new(None),
+            soft_limit: 1_000.into(),
+            subscription_soft_limit: 1_000.into(),
+            automatic_block_limit: false,
+            block_data_limit: u64::MAX.into(),
+            tier: 0.into(),
+            request_timeout: None,
+            slow_start: Duration::ZERO,
+            connected_at: RwLock::new(None),
+            head_block: RwLock::new(None),
+            open_request_handle_metrics: Arc::new(Default::default()),
+            method_counts: Default::default(),
+            backend_metrics: Default::default(),
+            latency_ewma: Default::default(),
+            client: Default::default(),
+            max_logs_range: None,
+            unsupported_methods: Default::default(),
+        }
+    }
+}
+
 impl Hash for Web3Connection {
     fn hash<H: Hasher>(&self, state: &mut H) {
         // TODO: is this enough?
@@ -1118,7 +1543,7 @@ impl Serialize for Web3Connection {
         S: Serializer,
     {
         // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("Web3Connection", 9)?;
+        let mut state = serializer.serialize_struct("Web3Connection", 14)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1134,19 +1559,34 @@ impl Serialize for Web3Connection {
             }
         }
 
-        state.serialize_field("tier", &self.tier)?;
+        let tier = self.tier();
+
+        state.serialize_field("tier", &tier)?;
 
-        let faked_weight = 100u64.saturating_sub(self.tier) as f64 / 100.0;
+        let faked_weight = 100u64.saturating_sub(tier) as f64 / 100.0;
 
         state.serialize_field("weight", &faked_weight)?;
 
-        state.serialize_field("soft_limit", &self.soft_limit)?;
+        state.serialize_field("soft_limit", &self.soft_limit())?;
+
+        state.serialize_field("subscription_soft_limit", &self.subscription_soft_limit())?;
+
+        state.serialize_field("quarantined", &self.quarantined())?;
+
+        state.serialize_field("client", &*self.client.read())?;
+
+        state.serialize_field("latency_ewma_ms", &self.latency_ewma.millis())?;
 
         state.serialize_field(
             "active_requests",
             &self.active_requests.load(atomic::Ordering::Relaxed),
         )?;
 
+        state.serialize_field(
+            "active_subscriptions",
+            &self.active_subscriptions.load(atomic::Ordering::Relaxed),
+        )?;
+
         state.serialize_field(
             "total_requests",
             &self.frontend_requests.load(atomic::Ordering::Relaxed),
@@ -1212,26 +1652,10 @@ mod tests {
         let head_block = SavedBlock::new(random_block);
         let block_data_limit = u64::MAX;
 
-        let metrics = OpenRequestHandleMetrics::default();
-
         let x = Web3Connection {
-            name: "name".to_string(),
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::None),
-            hard_limit: None,
-            soft_limit: 1_000,
-            automatic_block_limit: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
             head_block: RwLock::new(Some(head_block.clone())),
-            open_request_handle_metrics: Arc::new(metrics),
+            ..Web3Connection::mock("name")
         };
 
         assert!(x.has_block_data(&0.into()));
@@ -1259,27 +1683,10 @@ mod tests {
 
         let block_data_limit = 64;
 
-        let metrics = OpenRequestHandleMetrics::default();
-
-        // TODO: this is getting long. have a `impl Default`
         let x = Web3Connection {
-            name: "name".to_string(),
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::None),
-            hard_limit: None,
-            soft_limit: 1_000,
-            automatic_block_limit: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
             head_block: RwLock::new(Some(head_block.clone())),
-            open_request_handle_metrics: Arc::new(metrics),
+            ..Web3Connection::mock("name")
         };
 
         assert!(!x.has_block_data(&0.into()));
@@ -1312,26 +1719,10 @@ mod tests {
         let head_block = SavedBlock::new(head_block);
         let block_data_limit = u64::MAX;
 
-        let metrics = OpenRequestHandleMetrics::default();
-
         let x = Web3Connection {
-            name: "name".to_string(),
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::None),
-            hard_limit: None,
-            soft_limit: 1_000,
-            automatic_block_limit: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
             head_block: RwLock::new(Some(head_block.clone())),
-            open_request_handle_metrics: Arc::new(metrics),
+            ..Web3Connection::mock("name")
         };
 
         assert!(!x.has_block_data(&0.into()));
@@ -1340,4 +1731,66 @@ mod tests {
         assert!(!x.has_block_data(&(head_block.number() + 1)));
         assert!(!x.has_block_data(&(head_block.number() + 1000)));
     }
+
+    #[tokio::test]
+    async fn test_hard_limit_applies_to_subscriptions() {
+        use redis_rate_limiter::{DeadpoolRuntime, RedisConfig};
+
+        // a limit of 0 is refused before asking redis, so this pool never connects
+        let redis_pool = RedisConfig::from_url("redis://127.0.0.1:1")
+            .builder()
+            .unwrap()
+            .runtime(DeadpoolRuntime::Tokio1)
+            .build()
+            .unwrap();
+
+        let x = Arc::new(Web3Connection {
+            hard_limit: Some(RedisRateLimiter::new(
+                "web3_proxy",
+                "test:limited",
+                0,
+                60.0,
+                redis_pool,
+            )),
+            ..Web3Connection::mock("limited")
+        });
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        for traffic in [Traffic::Request, Traffic::Subscription] {
+            let result = x
+                .try_request_handle(&authorization, false, traffic)
+                .await
+                .unwrap();
+
+            assert!(
+                matches!(result, OpenRequestResult::NotReady),
+                "{:?} skipped the hard limit",
+                traffic
+            );
+        }
+    }
+
+    #[test]
+    fn test_slow_start_soft_limit() {
+        let slow_start = Duration::from_secs(30);
+
+        assert_eq!(
+            slow_start_soft_limit(1_000, Duration::ZERO, slow_start),
+            100
+        );
+        assert_eq!(
+            slow_start_soft_limit(1_000, Duration::from_secs(15), slow_start),
+            550
+        );
+        assert_eq!(
+            slow_start_soft_limit(1_000, Duration::from_secs(30), slow_start),
+            1_000
+        );
+        assert_eq!(
+            slow_start_soft_limit(1_000, Duration::from_secs(5), Duration::ZERO),
+            1_000
+        );
+        assert_eq!(slow_start_soft_limit(1, Duration::ZERO, slow_start), 1);
+    }
 }
@@ -1,26 +1,37 @@
-///! Load balanced communication with a group of web3 providers
-use super::blockchain::{ArcBlock, BlockHashesCache};
+//! Load balanced communication with a group of web3 providers
+use super::blockchain::{ArcBlock, BlockHashesCache, Reorg};
 use super::connection::Web3Connection;
+use super::ewma::latency_factor;
+use super::filters::FilterRegistry;
+use super::payload_size::{PAYLOAD_ANOMALY_CHECK_INTERVAL, PAYLOAD_ANOMALY_FACTOR};
+use super::queue::BackendQueue;
+use super::reload::ConnectionSpawner;
 use super::request::{
-    OpenRequestHandle, OpenRequestHandleMetrics, OpenRequestResult, RequestErrorHandler,
+    OpenRequestHandle, OpenRequestHandleMetrics, OpenRequestResult, RequestErrorHandler, Traffic,
 };
 use super::synced_connections::SyncedConnections;
+use super::validation::validate_response;
 use crate::app::{flatten_handle, AnyhowJoinHandle};
-use crate::config::{BlockAndRpc, TxHashAndRpc, Web3ConnectionConfig};
+use crate::config::{BackendQueueConfig, BlockAndRpc, TxHashAndRpc, Web3ConnectionConfig};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
-use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
-use crate::rpcs::transactions::TxStatus;
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::rpcs::transactions::{SeenTxIds, TxStatus};
+use crate::telemetry;
+use anyhow::Context;
 use arc_swap::ArcSwap;
 use counter::Counter;
 use derive_more::From;
-use ethers::prelude::{ProviderError, TxHash, H256, U64};
+use ethers::prelude::{Address, ProviderError, TxHash, H256, U256, U64};
 use futures::future::{join_all, try_join_all};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use log::{debug, error, info, trace, warn, Level};
 use migration::sea_orm::DatabaseConnection;
 use moka::future::{Cache, ConcurrentCacheExt};
+use opentelemetry::trace::{FutureExt as _, TraceContextExt as _};
+use opentelemetry::KeyValue;
+use parking_lot::RwLock;
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use serde_json::json;
@@ -32,23 +43,72 @@ use std::sync::Arc;
 use thread_fast_rng::rand::seq::SliceRandom;
 use tokio::sync::{broadcast, watch};
 use tokio::task;
-use tokio::time::{interval, sleep, sleep_until, Duration, Instant, MissedTickBehavior};
+use tokio::time::{interval, sleep, sleep_until, timeout, Duration, Instant, MissedTickBehavior};
 
 /// A collection of web3 connections. Sends requests either the current best server or all servers.
+/// Errors that mean a node has not caught up to the block the request needs.
+/// These are common right after a new head block. Another node can probably serve the request.
+fn is_node_not_ready(error: &JsonRpcErrorData) -> bool {
+    if error.code != -32000 {
+        return false;
+    }
+
+    // TODO: regex?
+    let not_ready_prefixes = [
+        "header not found",
+        "header for hash not found",
+        "missing trie node",
+        "node not started",
+        "RPC timeout",
+    ];
+
+    not_ready_prefixes
+        .iter()
+        .any(|x| error.message.starts_with(x))
+}
+
+/// The server doesn't have this method at all. Geth says "the method X does not exist/is not available"
+pub fn is_method_not_found(error: &JsonRpcErrorData) -> bool {
+    error.code == -32601 || error.message.to_lowercase().contains("method not found")
+}
+
 #[derive(From)]
 pub struct Web3Connections {
-    pub(crate) conns: HashMap<String, Arc<Web3Connection>>,
+    /// swapped when the config is reloaded
+    pub(crate) conns: ArcSwap<HashMap<String, Arc<Web3Connection>>>,
+    /// connects new rpcs when the config is reloaded. None if these connections can't be reloaded
+    pub(super) spawner: Option<ConnectionSpawner>,
     /// any requests will be forwarded to one (or more) of these connections
     pub(super) synced_connections: ArcSwap<SyncedConnections>,
     pub(super) pending_transactions:
         Cache<TxHash, TxStatus, hashbrown::hash_map::DefaultHashBuilder>,
+    /// fetch every pending transaction even if nothing is subscribed so that they can be served from pending_transactions
+    pub(super) cache_pending_txs: bool,
+    /// transaction ids announced by any backend with `subscribe_txs`. dedupes before the transactions are fetched
+    pub(super) seen_tx_ids: SeenTxIds,
     /// TODO: this map is going to grow forever unless we do some sort of pruning. maybe store pruned in redis?
     /// all blocks, including orphans
     pub(super) block_hashes: BlockHashesCache,
     /// blocks on the heaviest chain
     pub(super) block_numbers: Cache<U64, H256, hashbrown::hash_map::DefaultHashBuilder>,
+    /// blocks that recently left the heaviest chain. these are not canonical
+    pub(super) orphaned_blocks: BlockHashesCache,
     pub(super) min_head_rpcs: usize,
     pub(super) min_sum_soft_limit: u32,
+    /// check the shape of responses and retry malformed ones on another server
+    pub(super) validate_responses: bool,
+    /// requests waiting because every server is at its hard limit
+    pub backend_queue: BackendQueue,
+    /// filters from `eth_newFilter` and friends. these live here so that any server can answer polls
+    pub(super) filters: FilterRegistry,
+    /// the consensus head re-rooted. used by the `proxy_reorgs` subscription
+    pub reorg_sender: broadcast::Sender<Arc<Reorg>>,
+    /// methods that only go to servers that haven't answered them with "method not found"
+    pub(super) capability_methods: RwLock<HashSet<String>>,
+    /// how often this chain should make a block
+    pub expected_block_time: Duration,
+    /// transactions for other chains are bad data
+    pub(super) chain_id: u64,
 }
 
 impl Web3Connections {
@@ -66,7 +126,10 @@ impl Web3Connections {
         min_head_rpcs: usize,
         pending_tx_sender: Option<broadcast::Sender<TxStatus>>,
         pending_transactions: Cache<TxHash, TxStatus, hashbrown::hash_map::DefaultHashBuilder>,
+        cache_pending_txs: bool,
         open_request_handle_metrics: Arc<OpenRequestHandleMetrics>,
+        validate_responses: bool,
+        backend_queue: Option<BackendQueueConfig>,
     ) -> anyhow::Result<(Arc<Self>, AnyhowJoinHandle<()>)> {
         let (pending_tx_id_sender, pending_tx_id_receiver) = flume::unbounded();
         let (block_sender, block_receiver) = flume::unbounded::<BlockAndRpc>();
@@ -126,75 +189,58 @@ impl Web3Connections {
             None
         };
 
-        // turn configs into connections (in parallel)
-        // TODO: move this into a helper function. then we can use it when configs change (will need a remove function too)
-        let spawn_handles: Vec<_> = server_configs
-            .into_iter()
-            .filter_map(|(server_name, server_config)| {
-                if server_config.disabled {
-                    return None;
-                }
-
-                let db_conn = db_conn.clone();
-                let http_client = http_client.clone();
-                let redis_pool = redis_pool.clone();
-                let http_interval_sender = http_interval_sender.clone();
+        let mut spawner = ConnectionSpawner {
+            chain_id,
+            allowed_lag,
+            db_conn: db_conn.clone(),
+            http_client,
+            redis_pool,
+            http_interval_sender,
+            block_map,
+            block_sender: head_block_sender.as_ref().map(|_| block_sender),
+            tx_id_sender: pending_tx_id_sender,
+            open_request_handle_metrics,
+            state: Default::default(),
+        };
 
-                let block_sender = if head_block_sender.is_some() {
-                    Some(block_sender.clone())
-                } else {
-                    None
-                };
+        // turn configs into connections (in parallel)
+        let spawn_futures = server_configs
+            .iter()
+            .filter(|(_, server_config)| !server_config.disabled)
+            .map(|(server_name, server_config)| {
+                let spawner = &spawner;
 
-                let pending_tx_id_sender = Some(pending_tx_id_sender.clone());
-                let block_map = block_map.clone();
-                let open_request_handle_metrics = open_request_handle_metrics.clone();
-
-                let handle = tokio::spawn(async move {
-                    server_config
-                        .spawn(
-                            server_name,
-                            allowed_lag,
-                            db_conn,
-                            redis_pool,
-                            chain_id,
-                            http_client,
-                            http_interval_sender,
-                            block_map,
-                            block_sender,
-                            pending_tx_id_sender,
-                            open_request_handle_metrics,
-                        )
-                        .await
-                });
+                async move {
+                    let x = spawner
+                        .spawn_connection(server_name.clone(), server_config.clone())
+                        .await;
 
-                Some(handle)
-            })
-            .collect();
+                    (server_name, x)
+                }
+            });
 
         // map of connection names to their connection
         let mut connections = HashMap::new();
-        let mut handles = vec![];
+        let mut handles = HashMap::new();
 
-        // TODO: do we need to join this?
-        for x in join_all(spawn_handles).await {
+        for (server_name, x) in join_all(spawn_futures).await {
             // TODO: how should we handle errors here? one rpc being down shouldn't cause the program to exit
             match x {
-                Ok(Ok((connection, handle))) => {
+                Ok((connection, handle)) => {
                     connections.insert(connection.name.clone(), connection);
-                    handles.push(handle);
-                }
-                Ok(Err(err)) => {
-                    // if we got an error here, it is not retryable
-                    // TODO: include context about which connection failed
-                    error!("Unable to create connection. err={:?}", err);
+                    handles.insert(server_name.clone(), handle);
                 }
                 Err(err) => {
-                    return Err(err.into());
+                    // if we got an error here, it is not retryable
+                    error!("Unable to create connection {}. err={:?}", server_name, err);
                 }
             }
         }
 
+        let state = spawner.state.get_mut();
+        state.configs = server_configs;
+        state.handles = handles;
+
         let synced_connections = SyncedConnections::default();
 
         // TODO: max_capacity and time_to_idle from config
@@ -209,14 +255,31 @@ impl Web3Connections {
             .max_capacity(10_000)
             .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
 
+        // orphans are only interesting shortly after the reorg
+        let orphaned_blocks = Cache::builder()
+            .time_to_live(Duration::from_secs(600))
+            .max_capacity(1_000)
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
         let connections = Arc::new(Self {
-            conns: connections,
+            conns: ArcSwap::from_pointee(connections),
+            spawner: Some(spawner),
             synced_connections: ArcSwap::new(Arc::new(synced_connections)),
             pending_transactions,
+            cache_pending_txs,
+            seen_tx_ids: SeenTxIds::default(),
             block_hashes,
             block_numbers,
+            orphaned_blocks,
             min_sum_soft_limit,
             min_head_rpcs,
+            validate_responses,
+            backend_queue: BackendQueue::new(backend_queue),
+            filters: FilterRegistry::new(pending_tx_sender.clone()),
+            reorg_sender: broadcast::channel(16).0,
+            capability_methods: Default::default(),
+            expected_block_time: Duration::from_millis(expected_block_time_ms),
+            chain_id,
         });
 
         let authorization = Arc::new(Authorization::internal(db_conn.clone())?);
@@ -241,8 +304,16 @@ impl Web3Connections {
         Ok((connections, handle))
     }
 
-    pub fn get(&self, conn_name: &str) -> Option<&Arc<Web3Connection>> {
-        self.conns.get(conn_name)
+    /// Only send these methods to servers that support them.
+    /// A server that answers one with "method not found" doesn't get it again until it reconnects.
+    pub fn track_method_support(&self, methods: &[String]) {
+        self.capability_methods
+            .write()
+            .extend(methods.iter().cloned());
+    }
+
+    pub fn get(&self, conn_name: &str) -> Option<Arc<Web3Connection>> {
+        self.conns.load().get(conn_name).cloned()
     }
 
     /// subscribe to blocks and transactions from all the backend rpcs.
@@ -317,6 +388,26 @@ impl Web3Connections {
             futures.push(flatten_handle(handle));
         }
 
+        // comparing every rpc's recent results locks all of them. keep that off the request path
+        {
+            let connections = Arc::clone(&self);
+
+            let handle = task::Builder::default()
+                .name("check_payload_anomalies")
+                .spawn(async move {
+                    let mut interval = interval(PAYLOAD_ANOMALY_CHECK_INTERVAL);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                    loop {
+                        interval.tick().await;
+
+                        connections.check_payload_anomalies();
+                    }
+                })?;
+
+            futures.push(flatten_handle(handle));
+        }
+
         if let Err(e) = try_join_all(futures).await {
             error!("subscriptions over: {:?}", self);
             return Err(e);
@@ -375,7 +466,7 @@ impl Web3Connections {
                 count_map.insert(s.clone(), response);
             }
 
-            counts.update([s].into_iter());
+            counts.update([s]);
         }
 
         for (most_common, _) in counts.most_common_ordered() {
@@ -414,6 +505,7 @@ impl Web3Connections {
         request_metadata: Option<&Arc<RequestMetadata>>,
         skip: &[Arc<Web3Connection>],
         min_block_needed: Option<&U64>,
+        traffic: Traffic,
     ) -> anyhow::Result<OpenRequestResult> {
         let usable_rpcs_by_head_num_and_weight: BTreeMap<
             (Option<U64>, u64),
@@ -424,6 +516,7 @@ impl Web3Connections {
 
             for x in self
                 .conns
+                .load_full()
                 .values()
                 .filter(|x| !skip.contains(x))
                 .filter(|x| x.has_block_data(min_block_needed))
@@ -434,7 +527,7 @@ impl Web3Connections {
                 match x_head_block {
                     None => continue,
                     Some(x_head) => {
-                        let key = (Some(x_head.number()), u64::MAX - x.tier);
+                        let key = (Some(x_head.number()), u64::MAX - x.tier());
 
                         m.entry(key).or_insert_with(Vec::new).push(x);
                     }
@@ -463,7 +556,7 @@ impl Web3Connections {
                 .iter()
                 .filter(|x| !skip.contains(x))
             {
-                let key = (None, u64::MAX - x.tier);
+                let key = (None, u64::MAX - x.tier());
 
                 m.entry(key).or_insert_with(Vec::new).push(x.clone());
             }
@@ -485,10 +578,8 @@ impl Web3Connections {
                     // TODO: are active requests what we want? do we want a counter for requests in the last second + any actives longer than that?
                     // TODO: get active requests out of redis (that's definitely too slow)
                     // TODO: do something with hard limit instead? (but that is hitting redis too much)
-                    let active_requests = rpc.active_requests() as f64;
-                    let soft_limit = rpc.soft_limit as f64;
-
-                    let available_requests = soft_limit - active_requests;
+                    // requests and subscriptions are weighed against their own soft limits
+                    let available_requests = rpc.available(traffic);
 
                     trace!("available requests on {}: {}", rpc, available_requests);
 
@@ -523,10 +614,25 @@ impl Web3Connections {
                     .collect()
             }
 
+            // faster servers get more of the requests. recent latency counts most so that spikes wear off
+            let fastest_millis = usable_rpcs
+                .iter()
+                .filter_map(|rpc| rpc.latency_ewma.millis())
+                .reduce(f64::min);
+
+            for (rpc, weight) in available_request_map.iter_mut() {
+                *weight *= latency_factor(rpc.latency_ewma.millis(), fastest_millis);
+
+                // a backend that is quietly failing with empty results keeps only a little traffic until it recovers
+                if rpc.has_payload_anomaly() {
+                    *weight *= PAYLOAD_ANOMALY_FACTOR;
+                }
+            }
+
             let sorted_rpcs = {
                 if usable_rpcs.len() == 1 {
                     // TODO: return now instead? we shouldn't need another alloc
-                    vec![usable_rpcs.get(0).expect("there should be 1")]
+                    vec![usable_rpcs.first().expect("there should be 1")]
                 } else {
                     let mut rng = thread_fast_rng::thread_fast_rng();
 
@@ -546,7 +652,7 @@ impl Web3Connections {
             for best_rpc in sorted_rpcs.into_iter() {
                 // increment our connection counter
                 match best_rpc
-                    .try_request_handle(authorization, min_block_needed.is_none())
+                    .try_request_handle(authorization, min_block_needed.is_none(), traffic)
                     .await
                 {
                     Ok(OpenRequestResult::Handle(handle)) => {
@@ -608,7 +714,7 @@ impl Web3Connections {
         // TODO: with capacity?
         let mut selected_rpcs = vec![];
 
-        for connection in self.conns.values() {
+        for connection in self.conns.load_full().values() {
             if let Some(block_needed) = block_needed {
                 if !connection.has_block_data(block_needed) {
                     continue;
@@ -619,7 +725,7 @@ impl Web3Connections {
 
             // check rate limits and increment our connection counter
             match connection
-                .try_request_handle(authorization, block_needed.is_none())
+                .try_request_handle(authorization, block_needed.is_none(), Traffic::Request)
                 .await
             {
                 Ok(OpenRequestResult::RetryAt(retry_at)) => {
@@ -656,25 +762,85 @@ impl Web3Connections {
         request: JsonRpcRequest,
         request_metadata: Option<&Arc<RequestMetadata>>,
         min_block_needed: Option<&U64>,
+        traffic: Traffic,
     ) -> anyhow::Result<JsonRpcForwardedResponse> {
-        let mut skip_rpcs = vec![];
+        let tracks_support = self.capability_methods.read().contains(&request.method);
+
+        // skip servers that don't have the method or whose node software would reject the request
+        let mut skip_rpcs: Vec<_> = self
+            .conns
+            .load()
+            .values()
+            .filter(|x| {
+                (tracks_support && !x.supports_method(&request.method))
+                    || !x.client_can_serve(&request)
+            })
+            .cloned()
+            .collect();
+
+        if !skip_rpcs.is_empty() && skip_rpcs.len() == self.conns.load().len() {
+            return Err(anyhow::anyhow!(
+                "no servers can serve this {} request",
+                request.method
+            ));
+        }
+
+        let mut not_ready_error = None;
+        // the last "method not found" response. returned if no server has the method
+        let mut method_not_found = None;
+        // set the first time every server is rate limited
+        let mut queue_wait_until = None;
 
         // TODO: maximum retries? right now its the total number of servers
         loop {
-            if skip_rpcs.len() == self.conns.len() {
+            if skip_rpcs.len() == self.conns.load().len() {
                 // no servers to try
                 break;
             }
-            match self
+
+            // the client has given up. don't do any more work for them
+            if request_metadata.and_then(|x| x.time_remaining()) == Some(Duration::ZERO) {
+                return Err(anyhow::anyhow!("request deadline exceeded"));
+            }
+
+            let mut span_attributes = vec![
+                KeyValue::new("rpc.method", request.method.clone()),
+                KeyValue::new("web3_proxy.skipped_rpcs", skip_rpcs.len() as i64),
+            ];
+
+            if let Some(min_block_needed) = min_block_needed {
+                span_attributes.push(KeyValue::new(
+                    "web3_proxy.min_block_needed",
+                    min_block_needed.as_u64() as i64,
+                ));
+            }
+
+            let route_cx = telemetry::start_span("route", span_attributes);
+
+            let open_request_result = self
                 .best_synced_backend_connection(
                     allowed_lag,
                     authorization,
                     request_metadata,
                     &skip_rpcs,
                     min_block_needed,
+                    traffic,
                 )
-                .await?
-            {
+                .with_context(route_cx.clone())
+                .await?;
+
+            // end the routing span. the backend request is a sibling
+            route_cx.span().set_attribute(KeyValue::new(
+                "web3_proxy.route",
+                match &open_request_result {
+                    OpenRequestResult::Handle(x) => x.clone_connection().name.clone(),
+                    OpenRequestResult::RetryAt(_) => "retry_at".to_string(),
+                    OpenRequestResult::NotReady => "not_ready".to_string(),
+                },
+            ));
+            drop(route_cx);
+
+            match open_request_result {
                 OpenRequestResult::Handle(active_request_handle) => {
                     // save the rpc in case we get an error and want to retry on another server
                     skip_rpcs.push(active_request_handle.clone_connection());
@@ -687,14 +853,46 @@ impl Web3Connections {
                             .push(active_request_handle.clone_connection());
                     }
 
+                    // the backend gets whatever is shorter: its own timeout or what is left of the client's budget
+                    let upstream_timeout = [
+                        active_request_handle.clone_connection().request_timeout,
+                        request_metadata.and_then(|x| x.time_remaining()),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .min();
+
+                    let params = json!(request.params);
+
                     // TODO: get the log percent from the user data
-                    let response_result = active_request_handle
-                        .request(
-                            &request.method,
-                            &json!(request.params),
-                            RequestErrorHandler::SaveReverts,
-                        )
-                        .await;
+                    let response_future = active_request_handle.request(
+                        &request.method,
+                        &params,
+                        RequestErrorHandler::SaveReverts,
+                    );
+
+                    let response_result = match upstream_timeout {
+                        None => response_future.await,
+                        Some(upstream_timeout) => {
+                            match timeout(upstream_timeout, response_future).await {
+                                Ok(x) => x,
+                                Err(_) => {
+                                    let rpc = skip_rpcs
+                                        .last()
+                                        .expect("there must have been a provider if we timed out");
+
+                                    debug!(
+                                        "{} timed out after {:?}. Retrying on another",
+                                        rpc, upstream_timeout
+                                    );
+
+                                    rpc.record_timeout(upstream_timeout);
+
+                                    continue;
+                                }
+                            }
+                        }
+                    };
 
                     match JsonRpcForwardedResponse::try_from_response_result(
                         response_result,
@@ -704,32 +902,55 @@ impl Web3Connections {
                             if let Some(error) = &response.error {
                                 // trace!(?response, "rpc error");
 
+                                // remember that this server can't do this method and try another
+                                if tracks_support && is_method_not_found(error) {
+                                    let rpc = skip_rpcs.last().expect(
+                                        "there must have been a provider if we got a response",
+                                    );
+
+                                    rpc.mark_unsupported(&request.method);
+
+                                    method_not_found = Some(response);
+
+                                    continue;
+                                }
+
+                                // some errors should be retried on other nodes
+                                if is_node_not_ready(error) {
+                                    let rpc = skip_rpcs.last().expect(
+                                        "there must have been a provider if we got a response",
+                                    );
+
+                                    debug!(
+                                        "{} is not ready for this request! Retrying on another. err={}",
+                                        rpc, error.message
+                                    );
+
+                                    not_ready_error = Some(error.message.clone());
+
+                                    continue;
+                                }
+
                                 if let Some(request_metadata) = request_metadata {
                                     request_metadata
                                         .error_response
                                         .store(true, Ordering::Release);
                                 }
-
-                                // some errors should be retried on other nodes
-                                if error.code == -32000 {
-                                    let error_msg = error.message.as_str();
-
-                                    // TODO: regex?
-                                    let retry_prefixes = [
-                                        "header not found",
-                                        "header for hash not found",
-                                        "missing trie node",
-                                        "node not started",
-                                        "RPC timeout",
-                                    ];
-                                    for retry_prefix in retry_prefixes {
-                                        if error_msg.starts_with(retry_prefix) {
-                                            continue;
-                                        }
+                            } else if self.validate_responses {
+                                if let Some(result) = response.result.as_ref() {
+                                    if let Err(err) = validate_response(&request.method, result) {
+                                        let rpc = skip_rpcs.last().expect(
+                                            "there must have been a provider if we got a response",
+                                        );
+
+                                        warn!(
+                                            "Malformed response from {}! Retrying on another. err={:?}",
+                                            rpc, err
+                                        );
+
+                                        continue;
                                     }
                                 }
-                            } else {
-                                // trace!(?response, "rpc success");
                             }
 
                             return Ok(response);
@@ -764,6 +985,30 @@ impl Web3Connections {
                         request_metadata.no_servers.fetch_add(1, Ordering::Release);
                     }
 
+                    if queue_wait_until.is_none() {
+                        queue_wait_until = Some(self.backend_queue.wait_until(Instant::now()));
+                    }
+
+                    // a short wait is better than an error. a long wait is not
+                    let _queue_slot = match self.backend_queue.try_enter(
+                        authorization,
+                        retry_at,
+                        queue_wait_until.flatten(),
+                    ) {
+                        Some(x) => x,
+                        None => {
+                            return Err(anyhow::anyhow!(
+                                "all servers are rate limited. try again soon"
+                            ))
+                        }
+                    };
+
+                    // don't sleep past the client's deadline
+                    let retry_at = request_metadata
+                        .and_then(|x| x.deadline)
+                        .map(|x| x.min(retry_at))
+                        .unwrap_or(retry_at);
+
                     sleep_until(retry_at).await;
 
                     continue;
@@ -785,13 +1030,84 @@ impl Web3Connections {
                 .store(true, Ordering::Release);
         }
 
-        let num_conns = self.conns.len();
+        if let Some(response) = method_not_found {
+            // every server that we tried is missing the method
+            return Ok(response);
+        }
+
+        if let Some(not_ready_error) = not_ready_error {
+            // return an error instead of a response so that this isn't cached
+            // a server will likely be ready soon
+            return Err(anyhow::anyhow!(
+                "no servers are ready for this request. last error: {}",
+                not_ready_error
+            ));
+        }
+
+        let num_conns = self.conns.load().len();
 
         error!("No servers synced ({} known)", num_conns);
 
         Err(anyhow::anyhow!("No servers synced ({} known)", num_conns))
     }
 
+    /// Ask every synced rpc for an address's pending nonce and return the highest.
+    /// Mempools differ between servers, so any single answer might be too low.
+    pub async fn max_pending_nonce(
+        &self,
+        authorization: &Arc<Authorization>,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        address: Address,
+    ) -> anyhow::Result<U256> {
+        let active_request_handles = self
+            .all_backend_connections(authorization, None)
+            .await
+            .map_err(|_| anyhow::anyhow!("no servers available for the pending nonce"))?;
+
+        if let Some(request_metadata) = request_metadata {
+            request_metadata
+                .backend_requests
+                .lock()
+                .extend(active_request_handles.iter().map(|x| x.clone_connection()));
+        }
+
+        let params = json!([address, "pending"]);
+
+        let nonces = join_all(active_request_handles.into_iter().map(|handle| {
+            let params = params.clone();
+
+            async move {
+                handle
+                    .request::<_, U256>("eth_getTransactionCount", &params, Level::Trace.into())
+                    .await
+            }
+        }))
+        .await;
+
+        nonces
+            .into_iter()
+            .filter_map(|x| x.ok())
+            .max()
+            .context("no servers returned a pending nonce")
+    }
+
+    /// The oldest block that any of these rpcs can serve. None if no rpcs know their head block yet.
+    pub fn oldest_block(&self) -> Option<U64> {
+        self.conns
+            .load()
+            .values()
+            .filter_map(|x| x.oldest_block())
+            .min()
+    }
+
+    /// False if the block has been pruned from every rpc. True if we don't know yet.
+    pub fn has_history(&self, block_num: &U64) -> bool {
+        match self.oldest_block() {
+            None => true,
+            Some(oldest_block) => block_num >= &oldest_block,
+        }
+    }
+
     /// be sure there is a timeout on this or it might loop forever
     pub async fn try_send_all_upstream_servers(
         &self,
@@ -801,16 +1117,89 @@ impl Web3Connections {
         block_needed: Option<&U64>,
         error_level: Level,
     ) -> anyhow::Result<JsonRpcForwardedResponse> {
+        // TODO: benchmark this compared to waiting on unbounded futures
+        // TODO: do something with this handle?
+        // TODO: this is not working right. simplify
+        let active_request_handles = self
+            .wait_for_all_backend_connections(
+                authorization,
+                request_metadata.as_ref(),
+                block_needed,
+            )
+            .await;
+
+        self.try_send_parallel_requests(
+            active_request_handles,
+            request.id.clone(),
+            request.method.as_ref(),
+            request.params.as_ref(),
+            error_level,
+        )
+        .await
+    }
+
+    /// Send a request to every server at once. Each server's response can be awaited separately.
+    /// The requests are spawned so that they finish even if the caller stops waiting for them.
+    /// Servers that don't answer within `server_timeout` get an error.
+    /// be sure there is a timeout on this or it might loop forever
+    pub async fn spawn_to_all_individually(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        server_timeout: Duration,
+    ) -> Vec<(
+        Arc<Web3Connection>,
+        task::JoinHandle<anyhow::Result<JsonRpcForwardedResponse>>,
+    )> {
+        let active_request_handles = self
+            .wait_for_all_backend_connections(authorization, request_metadata, None)
+            .await;
+
+        let params = json!(request.params);
+
+        active_request_handles
+            .into_iter()
+            .map(|handle| {
+                let rpc = handle.clone_connection();
+
+                let method = request.method.clone();
+                let params = params.clone();
+                let id = request.id.clone();
+
+                let f = async move {
+                    match timeout(
+                        server_timeout,
+                        handle.request::<_, Box<RawValue>>(&method, &params, Level::Trace.into()),
+                    )
+                    .await
+                    {
+                        Ok(result) => {
+                            JsonRpcForwardedResponse::try_from_response_result(result, id)
+                        }
+                        Err(_) => Err(anyhow::anyhow!("timed out after {:?}", server_timeout)),
+                    }
+                };
+
+                (rpc, tokio::spawn(f))
+            })
+            .collect()
+    }
+
+    /// Open a request handle on every usable server. Waits for rate limits and for servers to sync.
+    /// be sure there is a timeout on this or it might loop forever
+    async fn wait_for_all_backend_connections(
+        &self,
+        authorization: &Arc<Authorization>,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        block_needed: Option<&U64>,
+    ) -> Vec<OpenRequestHandle> {
         loop {
             match self
                 .all_backend_connections(authorization, block_needed)
                 .await
             {
                 Ok(active_request_handles) => {
-                    // TODO: benchmark this compared to waiting on unbounded futures
-                    // TODO: do something with this handle?
-                    // TODO: this is not working right. simplify
-
                     if let Some(request_metadata) = request_metadata {
                         request_metadata
                             .backend_requests
@@ -818,15 +1207,7 @@ impl Web3Connections {
                             .extend(active_request_handles.iter().map(|x| x.clone_connection()));
                     }
 
-                    return self
-                        .try_send_parallel_requests(
-                            active_request_handles,
-                            request.id.clone(),
-                            request.method.as_ref(),
-                            request.params.as_ref(),
-                            error_level,
-                        )
-                        .await;
+                    return active_request_handles;
                 }
                 Err(None) => {
                     warn!("No servers in sync on {:?}! Retrying", self);
@@ -867,7 +1248,7 @@ impl fmt::Debug for Web3Connections {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO: the default formatter takes forever to write. this is too quiet though
         f.debug_struct("Web3Connections")
-            .field("conns", &self.conns)
+            .field("conns", &**self.conns.load())
             .finish_non_exhaustive()
     }
 }
@@ -879,7 +1260,8 @@ impl Serialize for Web3Connections {
     {
         let mut state = serializer.serialize_struct("Web3Connections", 6)?;
 
-        let conns: Vec<&Web3Connection> = self.conns.values().map(|x| x.as_ref()).collect();
+        let conns = self.conns.load();
+        let conns: Vec<&Web3Connection> = conns.values().map(|x| x.as_ref()).collect();
         state.serialize_field("conns", &conns)?;
 
         let synced_connections = &**self.synced_connections.load();
@@ -895,6 +1277,45 @@ impl Serialize for Web3Connections {
     }
 }
 
+#[cfg(test)]
+impl Web3Connections {
+    /// A group with no spawner and no subscriptions. Tests change what they need with struct update syntax
+    pub(super) fn mock(conns: HashMap<String, Arc<Web3Connection>>) -> Self {
+        // small caches are plenty for tests
+        fn cache<K, V>() -> Cache<K, V, hashbrown::hash_map::DefaultHashBuilder>
+        where
+            K: std::hash::Hash + Eq + Send + Sync + 'static,
+            V: Clone + Send + Sync + 'static,
+        {
+            Cache::builder()
+                .max_capacity(100)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default())
+        }
+
+        Self {
+            conns: ArcSwap::from_pointee(conns),
+            spawner: None,
+            synced_connections: Default::default(),
+            pending_transactions: cache(),
+            cache_pending_txs: false,
+            seen_tx_ids: SeenTxIds::default(),
+            block_hashes: cache(),
+            block_numbers: cache(),
+            orphaned_blocks: cache(),
+            min_head_rpcs: 1,
+            min_sum_soft_limit: 1,
+            validate_responses: false,
+            backend_queue: Default::default(),
+            filters: FilterRegistry::new(None),
+            reorg_sender: broadcast::channel(16).0,
+            capability_methods: Default::default(),
+            expected_block_time: Duration::from_secs(12),
+            chain_id: 1,
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
     // TODO: why is this allow needed? does tokio::test get in the way somehow?
     #![allow(unused_imports)]
@@ -946,44 +1367,16 @@ mod tests {
         let block_data_limit = u64::MAX;
 
         let head_rpc = Web3Connection {
-            name: "synced".to_string(),
-            // TODO: what should this be?
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com/synced".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::Ready(Arc::new(Web3Provider::Mock))),
-            hard_limit: None,
-            soft_limit: 1_000,
             automatic_block_limit: true,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
             head_block: RwLock::new(Some(head_block.clone())),
-            open_request_handle_metrics: Arc::new(Default::default()),
+            ..Web3Connection::mock("synced")
         };
 
         let lagged_rpc = Web3Connection {
-            name: "lagged".to_string(),
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com/lagged".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::Ready(Arc::new(Web3Provider::Mock))),
-            hard_limit: None,
-            soft_limit: 1_000,
-            automatic_block_limit: false,
             block_data_limit: block_data_limit.into(),
-            tier: 0,
             head_block: RwLock::new(Some(lagged_block.clone())),
-            open_request_handle_metrics: Arc::new(Default::default()),
+            ..Web3Connection::mock("lagged")
         };
 
         assert!(head_rpc.has_block_data(&lagged_block.number()));
@@ -1000,21 +1393,7 @@ mod tests {
             (lagged_rpc.name.clone(), lagged_rpc.clone()),
         ]);
 
-        let conns = Web3Connections {
-            conns,
-            synced_connections: Default::default(),
-            pending_transactions: Cache::builder()
-                .max_capacity(10_000)
-                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            block_hashes: Cache::builder()
-                .max_capacity(10_000)
-                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            block_numbers: Cache::builder()
-                .max_capacity(10_000)
-                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            min_head_rpcs: 1,
-            min_sum_soft_limit: 1,
-        };
+        let conns = Web3Connections::mock(conns);
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
 
@@ -1062,7 +1441,7 @@ mod tests {
         // best_synced_backend_connection requires servers to be synced with the head block
         // TODO: don't hard code allowed_lag
         let x = conns
-            .best_synced_backend_connection(60, &authorization, None, &[], None)
+            .best_synced_backend_connection(60, &authorization, None, &[], None, Traffic::Request)
             .await
             .unwrap();
 
@@ -1117,21 +1496,42 @@ mod tests {
 
         assert!(matches!(
             conns
-                .best_synced_backend_connection(60, &authorization, None, &[], None)
+                .best_synced_backend_connection(
+                    60,
+                    &authorization,
+                    None,
+                    &[],
+                    None,
+                    Traffic::Request
+                )
                 .await,
             Ok(OpenRequestResult::Handle(_))
         ));
 
         assert!(matches!(
             conns
-                .best_synced_backend_connection(60, &authorization, None, &[], Some(&0.into()))
+                .best_synced_backend_connection(
+                    60,
+                    &authorization,
+                    None,
+                    &[],
+                    Some(&0.into()),
+                    Traffic::Request
+                )
                 .await,
             Ok(OpenRequestResult::Handle(_))
         ));
 
         assert!(matches!(
             conns
-                .best_synced_backend_connection(60, &authorization, None, &[], Some(&1.into()))
+                .best_synced_backend_connection(
+                    60,
+                    &authorization,
+                    None,
+                    &[],
+                    Some(&1.into()),
+                    Traffic::Request
+                )
                 .await,
             Ok(OpenRequestResult::Handle(_))
         ));
@@ -1139,7 +1539,14 @@ mod tests {
         // future block should not get a handle
         assert!(matches!(
             conns
-                .best_synced_backend_connection(60, &authorization, None, &[], Some(&2.into()))
+                .best_synced_backend_connection(
+                    60,
+                    &authorization,
+                    None,
+                    &[],
+                    Some(&2.into()),
+                    Traffic::Request
+                )
                 .await,
             Ok(OpenRequestResult::NotReady)
         ));
@@ -1171,43 +1578,18 @@ mod tests {
         let head_block: SavedBlock = Arc::new(head_block).into();
 
         let pruned_rpc = Web3Connection {
-            name: "pruned".to_string(),
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com/pruned".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::Ready(Arc::new(Web3Provider::Mock))),
-            hard_limit: None,
-            soft_limit: 3_000,
-            automatic_block_limit: false,
+            soft_limit: 3_000.into(),
+            subscription_soft_limit: 3_000.into(),
             block_data_limit: 64.into(),
-            tier: 1,
+            tier: 1.into(),
             head_block: RwLock::new(Some(head_block.clone())),
-            open_request_handle_metrics: Arc::new(Default::default()),
+            ..Web3Connection::mock("pruned")
         };
 
         let archive_rpc = Web3Connection {
-            name: "archive".to_string(),
-            allowed_lag: 10,
-            db_conn: None,
-            display_name: None,
-            url: "ws://example.com/archive".to_string(),
-            http_client: None,
-            active_requests: 0.into(),
-            frontend_requests: 0.into(),
-            internal_requests: 0.into(),
-            provider_state: AsyncRwLock::new(ProviderState::Ready(Arc::new(Web3Provider::Mock))),
-            hard_limit: None,
-            soft_limit: 1_000,
-            automatic_block_limit: false,
-            block_data_limit: u64::MAX.into(),
-            tier: 2,
+            tier: 2.into(),
             head_block: RwLock::new(Some(head_block.clone())),
-            open_request_handle_metrics: Arc::new(Default::default()),
+            ..Web3Connection::mock("archive")
         };
 
         assert!(pruned_rpc.has_block_data(&head_block.number()));
@@ -1224,19 +1606,8 @@ mod tests {
         ]);
 
         let conns = Web3Connections {
-            conns,
-            synced_connections: Default::default(),
-            pending_transactions: Cache::builder()
-                .max_capacity(10)
-                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            block_hashes: Cache::builder()
-                .max_capacity(10)
-                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            block_numbers: Cache::builder()
-                .max_capacity(10)
-                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
-            min_head_rpcs: 1,
             min_sum_soft_limit: 3_000,
+            ..Web3Connections::mock(conns)
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1278,6 +1649,7 @@ mod tests {
                 None,
                 &[],
                 Some(&head_block.number()),
+                Traffic::Request,
             )
             .await;
 
@@ -1287,7 +1659,14 @@ mod tests {
         ));
 
         let best_archive_server = conns
-            .best_synced_backend_connection(60, &authorization, None, &[], Some(&1.into()))
+            .best_synced_backend_connection(
+                60,
+                &authorization,
+                None,
+                &[],
+                Some(&1.into()),
+                Traffic::Request,
+            )
             .await;
 
         match best_archive_server {
@@ -1299,4 +1678,258 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_pruned_block_goes_to_archive_rpcs() {
+        let now: U256 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .into();
+
+        let head_block: SavedBlock = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        })
+        .into();
+
+        let pruned_rpc = Arc::new(Web3Connection {
+            block_data_limit: 64.into(),
+            head_block: RwLock::new(Some(head_block.clone())),
+            ..Web3Connection::mock("pruned")
+        });
+
+        let archive_rpc = Arc::new(Web3Connection {
+            head_block: RwLock::new(Some(head_block.clone())),
+            ..Web3Connection::mock("archive")
+        });
+
+        let balanced_rpcs = Web3Connections::mock(HashMap::from([(
+            pruned_rpc.name.clone(),
+            pruned_rpc.clone(),
+        )]));
+
+        let archive_rpcs = Arc::new(Web3Connections {
+            min_head_rpcs: 0,
+            min_sum_soft_limit: 0,
+            ..Web3Connections::mock(HashMap::from([(
+                archive_rpc.name.clone(),
+                archive_rpc.clone(),
+            )]))
+        });
+
+        let old_block = U64::from(1);
+
+        // the app sends this request to the archive rpcs
+        assert!(!balanced_rpcs.has_history(&old_block));
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        // like the app's archive rpcs, nothing watches this group's head
+        let (block_sender, block_receiver) = flume::unbounded();
+        let (head_block_sender, _) = watch::channel::<ArcBlock>(Default::default());
+
+        {
+            let archive_rpcs = archive_rpcs.clone();
+            let authorization = authorization.clone();
+
+            tokio::spawn(async move {
+                archive_rpcs
+                    .process_incoming_blocks(
+                        &authorization,
+                        block_receiver,
+                        head_block_sender,
+                        None,
+                    )
+                    .await
+            });
+        }
+
+        block_sender
+            .send_async((Some(head_block.block.clone()), archive_rpc.clone()))
+            .await
+            .unwrap();
+
+        for _ in 0..100 {
+            if archive_rpcs.num_synced_rpcs() > 0 {
+                break;
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(archive_rpcs.num_synced_rpcs(), 1);
+
+        match archive_rpcs
+            .best_synced_backend_connection(
+                60,
+                &authorization,
+                None,
+                &[],
+                Some(&old_block),
+                Traffic::Request,
+            )
+            .await
+        {
+            Ok(OpenRequestResult::Handle(x)) => assert_eq!(x.clone_connection().name, "archive"),
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_node_not_ready() {
+        let error = |code, message: &str| JsonRpcErrorData {
+            code,
+            message: message.to_string(),
+            data: None,
+        };
+
+        assert!(is_node_not_ready(&error(-32000, "header not found")));
+        assert!(is_node_not_ready(&error(
+            -32000,
+            "missing trie node 0000000000000000000000000000000000000000000000000000000000000000"
+        )));
+        assert!(!is_node_not_ready(&error(-32000, "execution reverted")));
+        assert!(!is_node_not_ready(&error(-32601, "header not found")));
+    }
+
+    /// serve the same json body to every POST
+    async fn spawn_json_backend(body: serde_json::Value) -> String {
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::post(move || async move { axum::Json(body) }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_method_not_found_retries_on_another() {
+        let _ = env_logger::builder()
+            .filter_level(LevelFilter::Error)
+            .filter_module("web3_proxy", LevelFilter::Trace)
+            .is_test(true)
+            .try_init();
+
+        let missing_url = spawn_json_backend(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32601, "message": "the method eth_simulateV1 does not exist/is not available"},
+        }))
+        .await;
+
+        let supported_url = spawn_json_backend(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": "0x1",
+        }))
+        .await;
+
+        let now: U256 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: SavedBlock = Arc::new(head_block).into();
+
+        let http_rpc = |name: &str, url: String, tier: u64| {
+            let provider =
+                ethers::providers::Provider::<ethers::providers::Http>::try_from(url.as_str())
+                    .unwrap();
+
+            Arc::new(Web3Connection {
+                http_client: Some(reqwest::Client::new()),
+                url,
+                provider_state: AsyncRwLock::new(ProviderState::Ready(Arc::new(
+                    Web3Provider::Http(provider),
+                ))),
+                head_block: RwLock::new(Some(head_block.clone())),
+                tier: tier.into(),
+                ..Web3Connection::mock(name)
+            })
+        };
+
+        // the lower tier is tried first, so the server without the method always gets the request first
+        let missing_rpc = http_rpc("missing", missing_url, 0);
+        let supported_rpc = http_rpc("supported", supported_url, 1);
+
+        let conns = HashMap::from([
+            (missing_rpc.name.clone(), missing_rpc.clone()),
+            (supported_rpc.name.clone(), supported_rpc.clone()),
+        ]);
+
+        let conns = Web3Connections::mock(conns);
+
+        conns.track_method_support(&["eth_simulateV1".to_string()]);
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        let (head_block_sender, _head_block_receiver) =
+            watch::channel::<ArcBlock>(Default::default());
+        let mut connection_heads = HashMap::new();
+
+        conns.save_block(&head_block.block, true).await.unwrap();
+
+        for rpc in [missing_rpc.clone(), supported_rpc.clone()] {
+            conns
+                .process_block_from_rpc(
+                    &authorization,
+                    &mut connection_heads,
+                    Some(head_block.clone()),
+                    rpc,
+                    &head_block_sender,
+                    &None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(conns.num_synced_rpcs(), 2);
+
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: RawValue::from_string("1".to_string()).unwrap(),
+            method: "eth_simulateV1".to_string(),
+            params: None,
+        };
+
+        let response = conns
+            .try_send_best_upstream_server(
+                60,
+                &authorization,
+                request,
+                None,
+                None,
+                Traffic::Request,
+            )
+            .await
+            .unwrap();
+
+        assert!(response.error.is_none());
+        assert_eq!(response.result.unwrap().get(), r#""0x1""#);
+
+        // the failing server is remembered and skipped next time
+        assert!(!missing_rpc.supports_method("eth_simulateV1"));
+        assert!(supported_rpc.supports_method("eth_simulateV1"));
+    }
 }
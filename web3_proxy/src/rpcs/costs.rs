@@ -0,0 +1,269 @@
+//! Count requests to each backend by method and price them with the provider's pricing table.
+//!
+//! Counts start when the rpc connects. Reloading an rpc with a changed config starts them over.
+
+use super::connection::Web3Connection;
+use super::connections::Web3Connections;
+use crate::config::ProviderPricingConfig;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+/// Clients can send any method name. Past this many, new methods are counted as "other".
+const MAX_COUNTED_METHODS: usize = 256;
+
+const OTHER_METHODS: &str = "other";
+
+#[derive(Debug, Default)]
+pub struct MethodCounts(Mutex<HashMap<String, u64>>);
+
+impl MethodCounts {
+    pub fn count(&self, method: &str) {
+        let mut counts = self.0.lock();
+
+        if let Some(x) = counts.get_mut(method) {
+            *x += 1;
+        } else if counts.len() < MAX_COUNTED_METHODS {
+            counts.insert(method.to_string(), 1);
+        } else {
+            *counts.entry(OTHER_METHODS.to_string()).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, u64> {
+        self.0.lock().clone()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Cost {
+    pub requests: u64,
+    pub compute_units: u64,
+    /// 0 if the rpc has no pricing
+    pub usd: f64,
+}
+
+impl Cost {
+    pub fn add(&mut self, other: &Self) {
+        self.requests += other.requests;
+        self.compute_units += other.compute_units;
+        self.usd += other.usd;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MethodCost {
+    pub method: String,
+    #[serde(flatten)]
+    pub cost: Cost,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcCosts {
+    pub name: String,
+    /// "balanced", "private", or "archive"
+    pub group: &'static str,
+    pub pricing: Option<String>,
+    pub total: Cost,
+    /// most expensive first
+    pub methods: Vec<MethodCost>,
+}
+
+/// Price `requests` requests of `method`. Without pricing, every request is 1 compute unit and free.
+pub fn method_cost(pricing: Option<&ProviderPricingConfig>, method: &str, requests: u64) -> Cost {
+    let pricing = match pricing {
+        Some(x) => x,
+        None => {
+            return Cost {
+                requests,
+                compute_units: requests,
+                usd: 0.0,
+            }
+        }
+    };
+
+    let compute_units = requests
+        * pricing
+            .compute_units
+            .get(method)
+            .copied()
+            .unwrap_or(pricing.default_compute_units);
+
+    let usd = match pricing.usd_per_request.get(method) {
+        Some(usd_per_request) => requests as f64 * usd_per_request,
+        None => compute_units as f64 * pricing.usd_per_million_compute_units / 1_000_000.0,
+    };
+
+    Cost {
+        requests,
+        compute_units,
+        usd,
+    }
+}
+
+/// Most expensive first. Unpriced costs are compared by compute units.
+fn cmp_cost(a: &Cost, b: &Cost) -> Ordering {
+    b.usd
+        .partial_cmp(&a.usd)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| b.compute_units.cmp(&a.compute_units))
+}
+
+fn sort_method_costs(methods: &mut [MethodCost]) {
+    methods.sort_by(|a, b| cmp_cost(&a.cost, &b.cost).then_with(|| a.method.cmp(&b.method)));
+}
+
+/// Most expensive first.
+pub fn sort_rpc_costs(rpcs: &mut [RpcCosts]) {
+    rpcs.sort_by(|a, b| {
+        cmp_cost(&a.total, &b.total).then_with(|| (a.group, &a.name).cmp(&(b.group, &b.name)))
+    });
+}
+
+impl Web3Connection {
+    pub fn costs(
+        &self,
+        group: &'static str,
+        pricing_name: Option<String>,
+        pricing: Option<&ProviderPricingConfig>,
+    ) -> RpcCosts {
+        let mut total = Cost::default();
+
+        let mut methods: Vec<_> = self
+            .method_counts
+            .snapshot()
+            .into_iter()
+            .map(|(method, requests)| {
+                let cost = method_cost(pricing, &method, requests);
+
+                total.add(&cost);
+
+                MethodCost { method, cost }
+            })
+            .collect();
+
+        sort_method_costs(&mut methods);
+
+        RpcCosts {
+            name: self.name.clone(),
+            group,
+            pricing: pricing_name,
+            total,
+            methods,
+        }
+    }
+}
+
+impl Web3Connections {
+    /// Costs of every connected rpc in this group.
+    pub async fn costs(
+        &self,
+        group: &'static str,
+        provider_pricing: &HashMap<String, ProviderPricingConfig>,
+    ) -> Vec<RpcCosts> {
+        // rpcs that can't be reloaded don't keep their configs. they are counted but unpriced
+        let configs = self.configs().await.unwrap_or_default();
+
+        self.conns
+            .load()
+            .values()
+            .map(|rpc| {
+                let pricing_name = configs.get(&rpc.name).and_then(|x| x.pricing.clone());
+
+                let pricing = pricing_name.as_ref().and_then(|x| provider_pricing.get(x));
+
+                rpc.costs(group, pricing_name, pricing)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alchemy() -> ProviderPricingConfig {
+        ProviderPricingConfig {
+            compute_units: HashMap::from([
+                ("eth_call".to_string(), 26),
+                ("eth_getLogs".to_string(), 75),
+            ]),
+            default_compute_units: 10,
+            usd_per_million_compute_units: 1.2,
+            usd_per_request: HashMap::from([("eth_sendRawTransaction".to_string(), 0.001)]),
+        }
+    }
+
+    #[test]
+    fn test_method_cost() {
+        let pricing = alchemy();
+
+        let cost = method_cost(Some(&pricing), "eth_getLogs", 1_000);
+        assert_eq!(cost.compute_units, 75_000);
+        assert!((cost.usd - 0.09).abs() < 1e-9);
+
+        // methods that aren't in the table use the default
+        let cost = method_cost(Some(&pricing), "eth_chainId", 1_000);
+        assert_eq!(cost.compute_units, 10_000);
+
+        // flat prices replace the compute unit price
+        let cost = method_cost(Some(&pricing), "eth_sendRawTransaction", 10);
+        assert_eq!(cost.compute_units, 100);
+        assert!((cost.usd - 0.01).abs() < 1e-9);
+
+        let cost = method_cost(None, "eth_getLogs", 5);
+        assert_eq!(
+            cost,
+            Cost {
+                requests: 5,
+                compute_units: 5,
+                usd: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_sort_method_costs() {
+        let pricing = alchemy();
+
+        let mut methods: Vec<_> = [
+            ("eth_chainId", 100),
+            ("eth_getLogs", 20),
+            ("eth_call", 100),
+            ("eth_blockNumber", 100),
+        ]
+        .into_iter()
+        .map(|(method, requests)| MethodCost {
+            method: method.to_string(),
+            cost: method_cost(Some(&pricing), method, requests),
+        })
+        .collect();
+
+        sort_method_costs(&mut methods);
+
+        let names: Vec<_> = methods.iter().map(|x| x.method.as_str()).collect();
+
+        assert_eq!(
+            names,
+            ["eth_call", "eth_getLogs", "eth_blockNumber", "eth_chainId"]
+        );
+    }
+
+    #[test]
+    fn test_method_counts_limit() {
+        let counts = MethodCounts::default();
+
+        for i in 0..MAX_COUNTED_METHODS + 10 {
+            counts.count(&format!("method_{}", i));
+        }
+
+        counts.count("method_0");
+
+        let snapshot = counts.snapshot();
+
+        assert_eq!(snapshot.len(), MAX_COUNTED_METHODS + 1);
+        assert_eq!(snapshot["method_0"], 2);
+        assert_eq!(snapshot[OTHER_METHODS], 10);
+    }
+}
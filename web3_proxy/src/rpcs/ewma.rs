@@ -0,0 +1,104 @@
+//! Exponentially weighted moving average of a backend's response latency. Used to send more requests to faster servers.
+//!
+//! Old samples fade with time instead of with the number of requests. A spike on a server that then gets less traffic still wears off.
+use parking_lot::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// After this long, a sample has about 37% (1/e) of its original weight
+const DECAY: Duration = Duration::from_secs(10);
+
+/// Slow servers keep at least this share of their weight so that they get enough requests to show that they recovered
+const MIN_LATENCY_FACTOR: f64 = 0.1;
+
+#[derive(Debug, Default)]
+pub struct EwmaLatency(Mutex<Option<Ewma>>);
+
+#[derive(Debug)]
+struct Ewma {
+    millis: f64,
+    updated: Instant,
+}
+
+impl EwmaLatency {
+    pub fn record(&self, latency: Duration) {
+        self.record_at(latency, Instant::now())
+    }
+
+    /// None until the first response
+    pub fn millis(&self) -> Option<f64> {
+        self.0.lock().as_ref().map(|x| x.millis)
+    }
+
+    fn record_at(&self, latency: Duration, now: Instant) {
+        let sample = latency.as_secs_f64() * 1000.0;
+
+        let mut ewma = self.0.lock();
+
+        match ewma.as_mut() {
+            None => {
+                *ewma = Some(Ewma {
+                    millis: sample,
+                    updated: now,
+                })
+            }
+            Some(ewma) => {
+                let elapsed = now.saturating_duration_since(ewma.updated);
+
+                // the longer since the last sample, the less the old average counts
+                let old_weight = (-elapsed.as_secs_f64() / DECAY.as_secs_f64()).exp();
+
+                ewma.millis = ewma.millis * old_weight + sample * (1.0 - old_weight);
+                ewma.updated = now;
+            }
+        }
+    }
+}
+
+/// How much of its weight a server keeps compared to the fastest server. Servers without any samples keep all of it.
+pub fn latency_factor(millis: Option<f64>, fastest_millis: Option<f64>) -> f64 {
+    match (millis, fastest_millis) {
+        (Some(millis), Some(fastest_millis)) if millis > 0.0 => {
+            (fastest_millis / millis).clamp(MIN_LATENCY_FACTOR, 1.0)
+        }
+        _ => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ewma_latency() {
+        let x = EwmaLatency::default();
+        let start = Instant::now();
+
+        assert_eq!(x.millis(), None);
+
+        x.record_at(Duration::from_millis(100), start);
+        assert_eq!(x.millis(), Some(100.0));
+
+        // a spike right away barely moves the average
+        x.record_at(
+            Duration::from_millis(5_000),
+            start + Duration::from_millis(1),
+        );
+        assert!(x.millis().unwrap() < 101.0);
+
+        // a sample long after the last one mostly replaces it
+        x.record_at(Duration::from_millis(50), start + DECAY * 10);
+        assert!((x.millis().unwrap() - 50.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_latency_factor() {
+        assert_eq!(latency_factor(Some(10.0), Some(10.0)), 1.0);
+        assert_eq!(latency_factor(Some(20.0), Some(10.0)), 0.5);
+        assert_eq!(
+            latency_factor(Some(10_000.0), Some(10.0)),
+            MIN_LATENCY_FACTOR
+        );
+        // new servers get a chance
+        assert_eq!(latency_factor(None, Some(10.0)), 1.0);
+    }
+}
@@ -0,0 +1,529 @@
+//! Filters (`eth_newFilter` and friends) that live in the proxy instead of on a single backend.
+//!
+//! A filter on a backend only works if every poll goes to that same backend.
+//! Keeping the filter here lets any synced backend serve the underlying blocks and logs.
+use super::connections::Web3Connections;
+use super::request::Traffic;
+use super::transactions::TxStatus;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::jsonrpc::JsonRpcRequest;
+use anyhow::Context;
+use ethers::prelude::{TxHash, H256, U256, U64};
+use moka::future::Cache;
+use parking_lot::Mutex;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use thread_fast_rng::rand::Rng;
+use thread_fast_rng::thread_fast_rng;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// Filters that are not polled for this long are removed. geth uses the same timeout.
+const FILTER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The most blocks that one `eth_getFilterChanges` covers. Clients that poll less often than this miss changes.
+const MAX_FILTER_BLOCKS: u64 = 1_000;
+
+/// The most pending transaction hashes that a filter holds between polls.
+const MAX_FILTER_PENDING_TXS: usize = 10_000;
+
+/// The most filters that one rpc key or ip can have at once. One client shouldn't be able to fill the registry.
+const MAX_FILTERS_PER_CLIENT: usize = 100;
+
+/// Who created a filter. Requests with an rpc key count against the key. Others count against their ip.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FilterOwner {
+    RpcKey(NonZeroU64),
+    Ip(IpAddr),
+}
+
+impl From<&Authorization> for FilterOwner {
+    fn from(authorization: &Authorization) -> Self {
+        match authorization.checks.rpc_key_id {
+            Some(rpc_key_id) => Self::RpcKey(rpc_key_id),
+            None => Self::Ip(authorization.ip),
+        }
+    }
+}
+
+enum FilterKind {
+    Blocks,
+    /// the filter object from `eth_newFilter`
+    Logs(serde_json::Value),
+    /// hashes are pushed by a task that follows the pending transactions
+    PendingTransactions {
+        hashes: Arc<Mutex<Vec<TxHash>>>,
+        handle: JoinHandle<()>,
+    },
+}
+
+struct Filter {
+    kind: FilterKind,
+    owner: FilterOwner,
+    /// the last block that `eth_getFilterChanges` included
+    last_block: Mutex<U64>,
+}
+
+impl Filter {
+    /// Claim the blocks after `last_block` up to `head_block_num` so that concurrent polls don't return the same changes.
+    /// Returns the previous `last_block`. None if there is nothing new.
+    fn claim_blocks(&self, head_block_num: U64) -> Option<U64> {
+        let mut last_block = self.last_block.lock();
+
+        if head_block_num <= *last_block {
+            return None;
+        }
+
+        Some(std::mem::replace(&mut *last_block, head_block_num))
+    }
+
+    /// Give back blocks from `claim_blocks` that could not be fetched. Later polls will try them again.
+    fn unclaim_blocks(&self, head_block_num: U64, previous: U64) {
+        let mut last_block = self.last_block.lock();
+
+        // a newer poll already moved past these blocks
+        if *last_block == head_block_num {
+            *last_block = previous;
+        }
+    }
+}
+
+impl Drop for Filter {
+    fn drop(&mut self) {
+        if let FilterKind::PendingTransactions { handle, .. } = &self.kind {
+            handle.abort();
+        }
+    }
+}
+
+/// Every filter that clients have created on this proxy.
+pub struct FilterRegistry {
+    filters: Cache<U256, Arc<Filter>, hashbrown::hash_map::DefaultHashBuilder>,
+    pending_tx_sender: Option<broadcast::Sender<TxStatus>>,
+}
+
+impl FilterRegistry {
+    pub fn new(pending_tx_sender: Option<broadcast::Sender<TxStatus>>) -> Self {
+        let filters = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_idle(FILTER_TIMEOUT)
+            .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default());
+
+        Self {
+            filters,
+            pending_tx_sender,
+        }
+    }
+
+    async fn insert(&self, filter: Filter) -> anyhow::Result<U256> {
+        let num_owned = self
+            .filters
+            .iter()
+            .filter(|(_, x)| x.owner == filter.owner)
+            .count();
+
+        if num_owned >= MAX_FILTERS_PER_CLIENT {
+            return Err(anyhow::anyhow!(
+                "too many filters. uninstall some with eth_uninstallFilter"
+            ));
+        }
+
+        let id = U256::from(thread_fast_rng().gen::<u128>());
+
+        self.filters.insert(id, Arc::new(filter)).await;
+
+        Ok(id)
+    }
+}
+
+/// Parse a block parameter. None if it follows the head ("latest", "pending", or missing).
+fn filter_block(value: Option<&serde_json::Value>) -> anyhow::Result<Option<U64>> {
+    match value.and_then(|x| x.as_str()) {
+        None | Some("latest") | Some("pending") => Ok(None),
+        Some("earliest") => Ok(Some(U64::zero())),
+        Some(_) => {
+            let num = serde_json::from_value(value.cloned().expect("checked above"))
+                .context("invalid block number in filter")?;
+
+            Ok(Some(num))
+        }
+    }
+}
+
+impl Web3Connections {
+    fn filter_head_num(&self) -> anyhow::Result<U64> {
+        self.head_block_num()
+            .context("no servers synced. unable to use filters")
+    }
+
+    /// `eth_newBlockFilter`
+    pub async fn new_block_filter(&self, authorization: &Authorization) -> anyhow::Result<U256> {
+        let last_block = self.filter_head_num()?;
+
+        let filter = Filter {
+            kind: FilterKind::Blocks,
+            owner: authorization.into(),
+            last_block: Mutex::new(last_block),
+        };
+
+        self.filters.insert(filter).await
+    }
+
+    /// `eth_newFilter`
+    pub async fn new_log_filter(
+        &self,
+        authorization: &Authorization,
+        params: Option<&serde_json::Value>,
+    ) -> anyhow::Result<U256> {
+        let filter = params
+            .and_then(|x| x.get(0))
+            .filter(|x| x.is_object())
+            .cloned()
+            .context("a filter object is required")?;
+
+        // check these now so that polling doesn't fail later
+        filter_block(filter.get("fromBlock"))?;
+        filter_block(filter.get("toBlock"))?;
+
+        let last_block = self.filter_head_num()?;
+
+        let filter = Filter {
+            kind: FilterKind::Logs(filter),
+            owner: authorization.into(),
+            last_block: Mutex::new(last_block),
+        };
+
+        self.filters.insert(filter).await
+    }
+
+    /// `eth_newPendingTransactionFilter`
+    pub async fn new_pending_transaction_filter(
+        &self,
+        authorization: &Authorization,
+    ) -> anyhow::Result<U256> {
+        // check everything before the task is spawned so that it can't be left running
+        let last_block = self.filter_head_num()?;
+
+        let mut pending_tx_receiver = self
+            .filters
+            .pending_tx_sender
+            .as_ref()
+            .context("pending transactions are not available")?
+            .subscribe();
+
+        let hashes: Arc<Mutex<Vec<TxHash>>> = Default::default();
+
+        let handle = {
+            let hashes = hashes.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    let tx = match pending_tx_receiver.recv().await {
+                        Ok(TxStatus::Pending(tx)) => tx,
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+
+                    let mut hashes = hashes.lock();
+
+                    // a client that stopped polling shouldn't use unlimited memory
+                    if hashes.len() < MAX_FILTER_PENDING_TXS {
+                        hashes.push(tx.hash);
+                    }
+                }
+            })
+        };
+
+        // if this fails, dropping the filter aborts the task
+        let filter = Filter {
+            kind: FilterKind::PendingTransactions { hashes, handle },
+            owner: authorization.into(),
+            last_block: Mutex::new(last_block),
+        };
+
+        self.filters.insert(filter).await
+    }
+
+    /// `eth_uninstallFilter`
+    pub async fn uninstall_filter(&self, id: U256) -> bool {
+        let existed = self.filters.filters.get(&id).is_some();
+
+        self.filters.filters.invalidate(&id).await;
+
+        existed
+    }
+
+    /// `eth_getFilterChanges`. Everything new since the last poll.
+    pub async fn filter_changes(
+        &self,
+        allowed_lag: u64,
+        authorization: &Arc<Authorization>,
+        request_metadata: &Arc<RequestMetadata>,
+        id: U256,
+    ) -> anyhow::Result<serde_json::Value> {
+        let filter = self.filters.filters.get(&id).context("filter not found")?;
+
+        let head_block_num = self.filter_head_num()?;
+
+        if let FilterKind::PendingTransactions { hashes, .. } = &filter.kind {
+            let hashes = std::mem::take(&mut *hashes.lock());
+
+            return Ok(json!(hashes));
+        }
+
+        let last_block = match filter.claim_blocks(head_block_num) {
+            Some(x) => x,
+            None => return Ok(json!([])),
+        };
+
+        let from_block =
+            (last_block + U64::one()).max(head_block_num.saturating_sub(MAX_FILTER_BLOCKS.into()));
+
+        let changes = match &filter.kind {
+            FilterKind::Blocks => {
+                let mut hashes = vec![];
+
+                let mut num = from_block;
+                let mut result = Ok(());
+                while num <= head_block_num {
+                    match self.block_hash(authorization, &num).await {
+                        Ok((hash, _)) => hashes.push(hash),
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    }
+
+                    num += U64::one();
+                }
+
+                result.map(|_| json!(hashes))
+            }
+            FilterKind::Logs(filter) => {
+                self.filter_get_logs(
+                    allowed_lag,
+                    authorization,
+                    request_metadata,
+                    filter,
+                    Some(from_block),
+                    head_block_num,
+                )
+                .await
+            }
+            FilterKind::PendingTransactions { .. } => unreachable!(),
+        };
+
+        if changes.is_err() {
+            filter.unclaim_blocks(head_block_num, last_block);
+        }
+
+        changes
+    }
+
+    /// `eth_getFilterLogs`. Every log that matches the filter.
+    pub async fn filter_logs(
+        &self,
+        allowed_lag: u64,
+        authorization: &Arc<Authorization>,
+        request_metadata: &Arc<RequestMetadata>,
+        id: U256,
+    ) -> anyhow::Result<serde_json::Value> {
+        let filter = self.filters.filters.get(&id).context("filter not found")?;
+
+        let filter = match &filter.kind {
+            FilterKind::Logs(filter) => filter,
+            _ => return Err(anyhow::anyhow!("filter is not a log filter")),
+        };
+
+        let head_block_num = self.filter_head_num()?;
+
+        self.filter_get_logs(
+            allowed_lag,
+            authorization,
+            request_metadata,
+            filter,
+            None,
+            head_block_num,
+        )
+        .await
+    }
+
+    /// Run the filter's `eth_getLogs` on any synced backend, limited to `from_block..=head_block_num`.
+    async fn filter_get_logs(
+        &self,
+        allowed_lag: u64,
+        authorization: &Arc<Authorization>,
+        request_metadata: &Arc<RequestMetadata>,
+        filter: &serde_json::Value,
+        from_block: Option<U64>,
+        head_block_num: U64,
+    ) -> anyhow::Result<serde_json::Value> {
+        let filter_from = filter_block(filter.get("fromBlock"))?;
+        let filter_to = filter_block(filter.get("toBlock"))?;
+
+        let from_block = match (from_block, filter_from) {
+            (Some(a), Some(b)) => a.max(b),
+            (a, b) => a.or(b).unwrap_or(head_block_num),
+        };
+
+        let to_block = filter_to.unwrap_or(head_block_num).min(head_block_num);
+
+        if from_block > to_block {
+            return Ok(json!([]));
+        }
+
+        let mut params = filter.clone();
+        params["fromBlock"] = json!(from_block);
+        params["toBlock"] = json!(to_block);
+
+        self.get_logs(
+            allowed_lag,
+            authorization,
+            Some(request_metadata),
+            params,
+            to_block,
+            Traffic::Request,
+        )
+        .await
+    }
+
+    /// The logs in one block that match an `eth_subscribe("logs")` filter.
+    /// Asking by hash means the logs can't come from a different block at the same height.
+    pub async fn block_logs(
+        &self,
+        allowed_lag: u64,
+        authorization: &Arc<Authorization>,
+        filter: &serde_json::Value,
+        block_hash: H256,
+        block_num: U64,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut params = filter.clone();
+        params["blockHash"] = json!(block_hash);
+
+        let logs = self
+            .get_logs(
+                allowed_lag,
+                authorization,
+                None,
+                params,
+                block_num,
+                Traffic::Subscription,
+            )
+            .await?;
+
+        serde_json::from_value(logs).context("eth_getLogs result is not a list")
+    }
+
+    async fn get_logs(
+        &self,
+        allowed_lag: u64,
+        authorization: &Arc<Authorization>,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        params: serde_json::Value,
+        min_block_needed: U64,
+        traffic: Traffic,
+    ) -> anyhow::Result<serde_json::Value> {
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: to_raw_value(&json!(1)).expect("numbers should always be valid json"),
+            method: "eth_getLogs".to_string(),
+            params: Some(json!([params])),
+        };
+
+        let response = self
+            .try_send_best_upstream_server(
+                allowed_lag,
+                authorization,
+                request,
+                request_metadata,
+                Some(&min_block_needed),
+                traffic,
+            )
+            .await?;
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("eth_getLogs failed: {}", err.message));
+        }
+
+        let logs = response
+            .result
+            .context("no result in eth_getLogs response")?;
+
+        serde_json::from_str(logs.get()).context("parsing eth_getLogs result")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashMap;
+
+    fn block_filter(owner: FilterOwner) -> Filter {
+        Filter {
+            kind: FilterKind::Blocks,
+            owner,
+            last_block: Mutex::new(1.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filters_per_client() {
+        let registry = FilterRegistry::new(None);
+
+        let ip = FilterOwner::Ip([1, 2, 3, 4].into());
+        let rpc_key = FilterOwner::RpcKey(NonZeroU64::new(1).unwrap());
+
+        for _ in 0..MAX_FILTERS_PER_CLIENT {
+            registry.insert(block_filter(ip)).await.unwrap();
+        }
+
+        assert!(registry.insert(block_filter(ip)).await.is_err());
+
+        // other clients aren't affected
+        registry.insert(block_filter(rpc_key)).await.unwrap();
+    }
+
+    #[test]
+    fn test_claim_blocks() {
+        let filter = block_filter(FilterOwner::Ip([1, 2, 3, 4].into()));
+
+        assert_eq!(filter.claim_blocks(1.into()), None);
+
+        // a concurrent poll at the same head gets nothing
+        assert_eq!(filter.claim_blocks(5.into()), Some(1.into()));
+        assert_eq!(filter.claim_blocks(5.into()), None);
+
+        // a failed poll gives its blocks back
+        filter.unclaim_blocks(5.into(), 1.into());
+        assert_eq!(filter.claim_blocks(6.into()), Some(1.into()));
+
+        // unless a newer poll already moved on
+        filter.unclaim_blocks(5.into(), 1.into());
+        assert_eq!(*filter.last_block.lock(), U64::from(6));
+    }
+
+    #[tokio::test]
+    async fn test_pending_filter_needs_head() {
+        let (pending_tx_sender, _) = broadcast::channel(16);
+
+        let rpcs = Web3Connections {
+            filters: FilterRegistry::new(Some(pending_tx_sender.clone())),
+            ..Web3Connections::mock(HashMap::new())
+        };
+
+        let authorization = Authorization::internal(None).unwrap();
+
+        assert!(rpcs
+            .new_pending_transaction_filter(&authorization)
+            .await
+            .is_err());
+
+        // no task was left subscribed
+        assert_eq!(pending_tx_sender.receiver_count(), 0);
+    }
+}
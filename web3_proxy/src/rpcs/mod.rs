@@ -1,8 +1,20 @@
 // TODO: all pub, or export useful things here instead?
+pub mod backend_metrics;
+pub mod backend_status;
 pub mod blockchain;
+pub mod client;
 pub mod connection;
 pub mod connections;
+pub mod costs;
+pub mod ewma;
+pub mod filters;
+pub mod payload_size;
+pub mod probe;
 pub mod provider;
+pub mod queue;
+pub mod reload;
 pub mod request;
+pub mod sanity;
 pub mod synced_connections;
 pub mod transactions;
+pub mod validation;
@@ -0,0 +1,345 @@
+//! Request and response sizes for each backend, and alerts when a backend starts sending back empty results.
+//!
+//! A provider that is failing quietly often answers with `null` or `[]` instead of an error.
+//! Those are valid responses, so the only way to notice is to compare the backend with its peers.
+//! `eth_getLogs`, receipts, and `eth_getCode` are often empty, so each method is only compared with the same method on the peers.
+use super::backend_metrics::{current_minute, MinuteWindow};
+use super::connection::Web3Connection;
+use super::connections::Web3Connections;
+use hashbrown::HashMap;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A backend with an anomaly keeps this share of its weight. It still gets some requests so that it can show that it recovered
+pub const PAYLOAD_ANOMALY_FACTOR: f64 = 0.1;
+
+/// How often the backends in a group are compared
+pub const PAYLOAD_ANOMALY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Fewer responses than this in the window aren't enough to tell
+const MIN_RESPONSES: u64 = 20;
+
+/// An anomaly needs at least this share of empty results
+const MIN_EMPTY_RATE: f64 = 0.5;
+
+/// and this much more than the other backends in the group
+const MIN_EMPTY_RATE_INCREASE: f64 = 0.3;
+
+/// What a result looks like when there is nothing in it
+const EMPTY_RESULTS: [&[u8]; 5] = [b"null", b"[]", b"{}", b"\"\"", b"\"0x\""];
+
+/// The longest of `EMPTY_RESULTS`
+const EMPTY_RESULT_MAX_LEN: usize = 4;
+
+/// Methods past this many in one minute aren't compared. Clients choose the method names
+const MAX_RECENT_METHODS: usize = 100;
+
+/// Counts json bytes without keeping them. Only the first few are kept to check for empty results
+#[derive(Default)]
+struct ByteCounter {
+    len: usize,
+    prefix: Vec<u8>,
+}
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len < EMPTY_RESULT_MAX_LEN {
+            let keep = (EMPTY_RESULT_MAX_LEN - self.len).min(buf.len());
+
+            self.prefix.extend_from_slice(&buf[..keep]);
+        }
+
+        self.len += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The size of a json-rpc result
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResultSize {
+    pub bytes: u64,
+    pub empty: bool,
+}
+
+impl ResultSize {
+    /// Serializes `result` to count its bytes. Nothing is allocated for large results
+    pub fn of<T: Serialize>(result: &T) -> Self {
+        let mut counter = ByteCounter::default();
+
+        // writing to the counter can't fail. a result that can't be serialized counts as what was written so far
+        let _ = serde_json::to_writer(&mut counter, result);
+
+        let empty = counter.len <= EMPTY_RESULT_MAX_LEN
+            && EMPTY_RESULTS.contains(&counter.prefix.as_slice());
+
+        Self {
+            bytes: counter.len as u64,
+            empty,
+        }
+    }
+}
+
+/// Responses and empty results for each method in the current and the previous minute.
+#[derive(Debug, Default)]
+pub struct RecentEmptyResults(Mutex<MinuteWindow<HashMap<String, (u64, u64)>>>);
+
+impl RecentEmptyResults {
+    pub fn add(&self, method: &str, empty: bool) {
+        self.add_at(method, empty, current_minute())
+    }
+
+    /// (responses, empty results) for each method over the last one to two minutes
+    pub fn counts(&self) -> HashMap<String, (u64, u64)> {
+        self.counts_at(current_minute())
+    }
+
+    fn add_at(&self, method: &str, empty: bool, minute: u64) {
+        let mut window = self.0.lock();
+
+        let methods = window.current(minute);
+
+        if methods.len() >= MAX_RECENT_METHODS && !methods.contains_key(method) {
+            return;
+        }
+
+        let counts = methods.entry_ref(method).or_default();
+
+        counts.0 += 1;
+
+        if empty {
+            counts.1 += 1;
+        }
+    }
+
+    fn counts_at(&self, minute: u64) -> HashMap<String, (u64, u64)> {
+        let mut window = self.0.lock();
+
+        let mut counts = HashMap::new();
+
+        for methods in window.recent(minute) {
+            for (method, x) in methods {
+                let sum: &mut (u64, u64) = counts.entry_ref(method.as_str()).or_default();
+
+                sum.0 += x.0;
+                sum.1 += x.1;
+            }
+        }
+
+        counts
+    }
+}
+
+/// Payload sizes for one backend. Request sizes are only known for http backends
+#[derive(Debug, Default)]
+pub struct PayloadMetrics {
+    pub request_header_bytes: AtomicU64,
+    pub request_body_bytes: AtomicU64,
+    /// serialized json-rpc results. errors aren't counted
+    pub response_bytes: AtomicU64,
+    pub empty_responses: AtomicU64,
+    /// for comparing with the other backends in the group
+    pub recent: RecentEmptyResults,
+    /// true while this backend sends back far more empty results than its peers
+    pub anomaly: AtomicBool,
+    /// how many times an anomaly started
+    pub anomalies: AtomicU64,
+}
+
+impl PayloadMetrics {
+    pub fn record_request(&self, header_bytes: u64, body_bytes: u64) {
+        self.request_header_bytes
+            .fetch_add(header_bytes, Ordering::Relaxed);
+        self.request_body_bytes
+            .fetch_add(body_bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_result(&self, method: &str, size: ResultSize) {
+        self.response_bytes.fetch_add(size.bytes, Ordering::Relaxed);
+
+        if size.empty {
+            self.empty_responses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.recent.add(method, size.empty);
+    }
+}
+
+/// True if a backend's share of empty results is far above everyone else's.
+/// Takes (responses, empty results) for the backend and for all of its peers together
+pub fn is_payload_anomaly(own: (u64, u64), others: (u64, u64)) -> bool {
+    let (own_responses, own_empty) = own;
+    let (other_responses, other_empty) = others;
+
+    // without enough traffic on both sides, there is nothing to compare
+    if own_responses < MIN_RESPONSES || other_responses < MIN_RESPONSES {
+        return false;
+    }
+
+    let own_rate = own_empty as f64 / own_responses as f64;
+    let other_rate = other_empty as f64 / other_responses as f64;
+
+    own_rate >= MIN_EMPTY_RATE && own_rate - other_rate >= MIN_EMPTY_RATE_INCREASE
+}
+
+impl Web3Connection {
+    /// Demoted backends keep only `PAYLOAD_ANOMALY_FACTOR` of their weight
+    pub fn has_payload_anomaly(&self) -> bool {
+        self.backend_metrics.payload.anomaly.load(Ordering::Relaxed)
+    }
+}
+
+impl Web3Connections {
+    /// Compare every rpc in the group with the rest, method by method, and flag the ones sending back far more empty results.
+    /// Logs a warning when an anomaly starts and again when it ends.
+    pub fn check_payload_anomalies(&self) {
+        let conns = self.conns.load();
+
+        let counts: Vec<_> = conns
+            .values()
+            .map(|rpc| (rpc, rpc.backend_metrics.payload.recent.counts()))
+            .collect();
+
+        let mut totals: HashMap<&str, (u64, u64)> = HashMap::new();
+
+        for (_, methods) in counts.iter() {
+            for (method, x) in methods {
+                let total = totals.entry(method.as_str()).or_default();
+
+                total.0 += x.0;
+                total.1 += x.1;
+            }
+        }
+
+        for (rpc, methods) in counts.iter() {
+            let found = methods.iter().find_map(|(method, own)| {
+                let total = totals[method.as_str()];
+
+                let others = (total.0 - own.0, total.1 - own.1);
+
+                is_payload_anomaly(*own, others).then_some((method, own, others))
+            });
+
+            let anomaly = found.is_some();
+
+            let payload = &rpc.backend_metrics.payload;
+
+            match (payload.anomaly.swap(anomaly, Ordering::Relaxed), found) {
+                (false, Some((method, own, others))) => {
+                    payload.anomalies.fetch_add(1, Ordering::Relaxed);
+
+                    warn!(
+                        "{} sent {}/{} empty results for {} recently. others sent {}/{}. demoting it",
+                        rpc, own.1, own.0, method, others.1, others.0
+                    );
+                }
+                (true, None) => {
+                    info!("{} is no longer sending mostly empty results", rpc);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use serde_json::value::RawValue;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_result_size() {
+        assert_eq!(
+            ResultSize::of(&json!(null)),
+            ResultSize {
+                bytes: 4,
+                empty: true
+            }
+        );
+        assert!(ResultSize::of(&json!([])).empty);
+        assert!(ResultSize::of(&json!("0x")).empty);
+
+        let x = ResultSize::of(&json!("0x1"));
+        assert_eq!(x.bytes, 5);
+        assert!(!x.empty);
+
+        let raw = RawValue::from_string(r#"[{"blockNumber":"0x1"}]"#.to_string()).unwrap();
+        let x = ResultSize::of(&raw);
+        assert_eq!(x.bytes, 23);
+        assert!(!x.empty);
+    }
+
+    #[test]
+    fn test_recent_empty_results() {
+        let x = RecentEmptyResults::default();
+
+        x.add_at("eth_call", true, 100);
+        x.add_at("eth_call", false, 100);
+        x.add_at("eth_call", true, 101);
+        x.add_at("eth_getLogs", true, 101);
+
+        let counts = x.counts_at(101);
+        assert_eq!(counts["eth_call"], (3, 2));
+        assert_eq!(counts["eth_getLogs"], (1, 1));
+
+        // minute 100 is forgotten
+        assert_eq!(x.counts_at(102)["eth_call"], (1, 1));
+
+        // a gap forgets everything
+        assert!(x.counts_at(110).is_empty());
+    }
+
+    #[test]
+    fn test_payload_anomalies_by_method() {
+        let a = Arc::new(Web3Connection::mock("a"));
+        let b = Arc::new(Web3Connection::mock("b"));
+
+        let rpcs = Web3Connections::mock(
+            [a.clone(), b.clone()]
+                .into_iter()
+                .map(|x| (x.name.clone(), x))
+                .collect(),
+        );
+
+        // different traffic. eth_getLogs is often empty
+        for _ in 0..100 {
+            a.backend_metrics.payload.recent.add("eth_getLogs", true);
+            b.backend_metrics.payload.recent.add("eth_call", false);
+        }
+
+        rpcs.check_payload_anomalies();
+        assert!(!a.has_payload_anomaly());
+        assert!(!b.has_payload_anomaly());
+
+        // the same traffic. a is the only one that is empty
+        for _ in 0..100 {
+            b.backend_metrics.payload.recent.add("eth_getLogs", false);
+        }
+
+        rpcs.check_payload_anomalies();
+        assert!(a.has_payload_anomaly());
+        assert!(!b.has_payload_anomaly());
+    }
+
+    #[test]
+    fn test_is_payload_anomaly() {
+        // too few responses to tell
+        assert!(!is_payload_anomaly((10, 10), (100, 0)));
+        // no peers to compare with
+        assert!(!is_payload_anomaly((100, 100), (0, 0)));
+        // everyone is getting empty results. probably the requests
+        assert!(!is_payload_anomaly((100, 90), (100, 80)));
+
+        assert!(is_payload_anomaly((100, 90), (300, 30)));
+    }
+}
@@ -0,0 +1,174 @@
+//! Time the same few requests on every backend. Useful when comparing providers.
+
+use super::connection::Web3Connection;
+use super::request::{RequestErrorHandler, Traffic};
+use crate::frontend::authorization::Authorization;
+use ethers::prelude::U64;
+use serde::Serialize;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// How long each probe gets, including waiting for the backend's rate limits.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Probes ask for a block this far behind the head so that backends that are slightly behind still have it.
+const PROBE_BLOCK_LAG: u64 = 2;
+
+/// How many blocks the `eth_getLogs` probe covers.
+const PROBE_LOGS_BLOCKS: u64 = 2;
+
+#[derive(Debug, Serialize)]
+pub struct ProbeResult {
+    pub method: &'static str,
+    /// only the request. waiting for rate limits isn't included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatencyProbe {
+    pub name: String,
+    /// "balanced", "private", or "archive"
+    pub group: &'static str,
+    pub tier: u64,
+    /// every probe's latency added up. None if any probe failed
+    pub total_ms: Option<u64>,
+    pub probes: Vec<ProbeResult>,
+}
+
+/// The same requests for every backend.
+fn probe_set(head_block_num: U64) -> [(&'static str, serde_json::Value); 3] {
+    let block_num = head_block_num.saturating_sub(PROBE_BLOCK_LAG.into());
+    let from_block = block_num.saturating_sub((PROBE_LOGS_BLOCKS - 1).into());
+
+    [
+        ("eth_blockNumber", json!([])),
+        ("eth_getBlockByNumber", json!([block_num, false])),
+        (
+            "eth_getLogs",
+            json!([{ "fromBlock": from_block, "toBlock": block_num }]),
+        ),
+    ]
+}
+
+/// Fastest first. Backends with a failed probe go last.
+pub fn sort_probes(probes: &mut [LatencyProbe]) {
+    probes.sort_by(|a, b| {
+        let total = match (a.total_ms, b.total_ms) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+
+        total.then_with(|| (a.group, &a.name).cmp(&(b.group, &b.name)))
+    });
+}
+
+impl Web3Connection {
+    /// Send each probe and time it. They are sent one after another so that they don't slow each other down.
+    pub async fn latency_probe(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        group: &'static str,
+        head_block_num: U64,
+    ) -> LatencyProbe {
+        let mut probes = vec![];
+
+        for (method, params) in probe_set(head_block_num) {
+            let (latency_ms, error) =
+                match timeout(PROBE_TIMEOUT, self.probe(authorization, method, params)).await {
+                    Ok(Ok(x)) => (Some(x), None),
+                    Ok(Err(err)) => (None, Some(format!("{:#}", err))),
+                    Err(_) => (None, Some("timed out".to_string())),
+                };
+
+            probes.push(ProbeResult {
+                method,
+                latency_ms,
+                error,
+            });
+        }
+
+        let total_ms = probes.iter().map(|x| x.latency_ms).sum();
+
+        LatencyProbe {
+            name: self.name.clone(),
+            group,
+            tier: self.tier(),
+            total_ms,
+            probes,
+        }
+    }
+
+    async fn probe(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        method: &'static str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<u64> {
+        let handle = self
+            .wait_for_request_handle(authorization, PROBE_TIMEOUT, false, Traffic::Request)
+            .await?;
+
+        let start = Instant::now();
+
+        let _: serde_json::Value = handle
+            .request(method, &params, RequestErrorHandler::DebugLevel)
+            .await?;
+
+        Ok(start.elapsed().as_millis() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(name: &str, total_ms: Option<u64>) -> LatencyProbe {
+        LatencyProbe {
+            name: name.to_string(),
+            group: "balanced",
+            tier: 0,
+            total_ms,
+            probes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_sort_probes() {
+        let mut probes = vec![
+            probe("failed", None),
+            probe("slow", Some(300)),
+            probe("fast", Some(20)),
+            probe("also_failed", None),
+        ];
+
+        sort_probes(&mut probes);
+
+        let names: Vec<_> = probes.iter().map(|x| x.name.as_str()).collect();
+
+        assert_eq!(names, ["fast", "slow", "also_failed", "failed"]);
+    }
+
+    #[test]
+    fn test_probe_set() {
+        let probes = probe_set(100.into());
+
+        assert_eq!(probes[1].1, json!(["0x62", false]));
+        assert_eq!(
+            probes[2].1,
+            json!([{ "fromBlock": "0x61", "toBlock": "0x62" }])
+        );
+
+        // a brand new chain doesn't underflow
+        let probes = probe_set(1.into());
+
+        assert_eq!(probes[1].1, json!(["0x0", false]));
+    }
+}
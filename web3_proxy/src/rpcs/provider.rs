@@ -1,13 +1,17 @@
 use anyhow::Context;
 use derive_more::From;
+use ethers::prelude::{Block, Middleware, TxHash};
+use futures::stream::{BoxStream, StreamExt};
 use std::time::Duration;
 
-/// Use HTTP and WS providers.
+/// Use HTTP, WS, and IPC providers.
 // TODO: instead of an enum, I tried to use Box<dyn Provider>, but hit <https://github.com/gakonst/ethers-rs/issues/592>
 #[derive(From)]
 pub enum Web3Provider {
     Http(ethers::providers::Provider<ethers::providers::Http>),
     Ws(ethers::providers::Provider<ethers::providers::Ws>),
+    /// a node on the same host. configured as `ipc:///path/to/geth.ipc`
+    Ipc(ethers::providers::Provider<ethers::providers::Ipc>),
     // TODO: only include this for tests.
     Mock,
 }
@@ -18,6 +22,26 @@ impl Web3Provider {
             Self::Mock => true,
             Self::Http(_) => true,
             Self::Ws(provider) => provider.as_ref().ready(),
+            // the socket is connected as soon as the provider exists
+            Self::Ipc(_) => true,
+        }
+    }
+
+    /// New heads from a websocket or ipc subscription.
+    pub async fn subscribe_blocks(&self) -> anyhow::Result<BoxStream<'_, Block<TxHash>>> {
+        match self {
+            Self::Ws(provider) => Ok(provider.subscribe_blocks().await?.boxed()),
+            Self::Ipc(provider) => Ok(provider.subscribe_blocks().await?.boxed()),
+            Self::Http(_) | Self::Mock => Err(anyhow::anyhow!("subscriptions are not supported")),
+        }
+    }
+
+    /// Pending transaction hashes from a websocket or ipc subscription.
+    pub async fn subscribe_pending_txs(&self) -> anyhow::Result<BoxStream<'_, TxHash>> {
+        match self {
+            Self::Ws(provider) => Ok(provider.subscribe_pending_txs().await?.boxed()),
+            Self::Ipc(provider) => Ok(provider.subscribe_pending_txs().await?.boxed()),
+            Self::Http(_) | Self::Mock => Err(anyhow::anyhow!("subscriptions are not supported")),
         }
     }
 
@@ -42,9 +66,17 @@ impl Web3Provider {
 
             // TODO: dry this up (needs https://github.com/gakonst/ethers-rs/issues/592)
             // TODO: i don't think this interval matters
+            ethers::providers::Provider::new(provider).into()
+        } else if let Some(path) = url_str.strip_prefix("ipc://") {
+            let provider = ethers::providers::Ipc::connect(path)
+                .await
+                .with_context(|| format!("connecting to {}", path))?;
+
             ethers::providers::Provider::new(provider).into()
         } else {
-            return Err(anyhow::anyhow!("only http and ws servers are supported"));
+            return Err(anyhow::anyhow!(
+                "only http, ws, and ipc servers are supported"
+            ));
         };
 
         Ok(provider)
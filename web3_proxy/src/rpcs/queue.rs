@@ -0,0 +1,105 @@
+//! Requests waiting for a backend's hard limit when every backend is rate limited.
+//!
+//! Many clients would rather wait a little than get an error.
+use crate::config::BackendQueueConfig;
+use crate::frontend::authorization::Authorization;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant};
+
+/// Counters for the prometheus endpoint.
+#[derive(Debug, Default, Serialize)]
+pub struct BackendQueueMetrics {
+    /// requests that waited for a backend
+    pub waited: AtomicU64,
+    /// total time spent waiting
+    pub wait_millis: AtomicU64,
+    /// requests that would have waited too long or found their tier's queue full
+    pub rejected: AtomicU64,
+}
+
+/// Without a config, requests wait as long as their deadline allows.
+#[derive(Debug, Default)]
+pub struct BackendQueue {
+    config: Option<BackendQueueConfig>,
+    depths: Mutex<HashMap<String, usize>>,
+    pub metrics: BackendQueueMetrics,
+}
+
+/// A place in the queue. Dropping it leaves the queue.
+pub struct QueueSlot<'a> {
+    queue: &'a BackendQueue,
+    tier: String,
+    start: Instant,
+}
+
+impl BackendQueue {
+    pub fn new(config: Option<BackendQueueConfig>) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// The latest a request that first queued at `first_queued` is allowed to wait until.
+    pub fn wait_until(&self, first_queued: Instant) -> Option<Instant> {
+        self.config
+            .as_ref()
+            .map(|x| first_queued + Duration::from_millis(x.max_wait_ms))
+    }
+
+    /// Join the queue for the request's user tier.
+    /// None if the backend won't be available before `wait_until` or if the tier's queue is full.
+    pub fn try_enter(
+        &self,
+        authorization: &Authorization,
+        retry_at: Instant,
+        wait_until: Option<Instant>,
+    ) -> Option<QueueSlot<'_>> {
+        let tier = authorization.user_tier();
+
+        if wait_until.map(|x| retry_at > x).unwrap_or(false) {
+            self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let max_depth = self
+            .config
+            .as_ref()
+            .and_then(|x| x.max_depth.get(tier).copied());
+
+        let mut depths = self.depths.lock();
+
+        let depth = depths.entry(tier.to_string()).or_default();
+
+        if max_depth.map(|x| *depth >= x).unwrap_or(false) {
+            self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        *depth += 1;
+
+        Some(QueueSlot {
+            queue: self,
+            tier: tier.to_string(),
+            start: Instant::now(),
+        })
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        let metrics = &self.queue.metrics;
+
+        metrics.waited.fetch_add(1, Ordering::Relaxed);
+        metrics
+            .wait_millis
+            .fetch_add(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        if let Some(depth) = self.queue.depths.lock().get_mut(&self.tier) {
+            *depth = depth.saturating_sub(1);
+        }
+    }
+}
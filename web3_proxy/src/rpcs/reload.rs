@@ -0,0 +1,342 @@
+//! Add, remove, and replace backend rpcs without restarting.
+//!
+//! Removed rpcs stop following heads immediately, but requests that were already sent to them are allowed to finish.
+use super::blockchain::BlockHashesCache;
+use super::connection::Web3Connection;
+use super::connections::Web3Connections;
+use super::request::OpenRequestHandleMetrics;
+use crate::app::AnyhowJoinHandle;
+use crate::config::{BlockAndRpc, TxHashAndRpc, Web3ConnectionConfig};
+use anyhow::Context;
+use futures::future::join_all;
+use hashbrown::HashMap;
+use log::{error, info};
+use migration::sea_orm::DatabaseConnection;
+use redis_rate_limiter::RedisPool;
+use serde::Serialize;
+use std::sync::atomic;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex as AsyncMutex};
+
+/// Everything needed to connect another rpc to a `Web3Connections`.
+pub(super) struct ConnectionSpawner {
+    pub(super) chain_id: u64,
+    pub(super) allowed_lag: u64,
+    pub(super) db_conn: Option<DatabaseConnection>,
+    pub(super) http_client: Option<reqwest::Client>,
+    pub(super) redis_pool: Option<RedisPool>,
+    pub(super) http_interval_sender: Option<Arc<broadcast::Sender<()>>>,
+    pub(super) block_map: BlockHashesCache,
+    /// None if these rpcs don't decide the head block
+    pub(super) block_sender: Option<flume::Sender<BlockAndRpc>>,
+    pub(super) tx_id_sender: flume::Sender<TxHashAndRpc>,
+    pub(super) open_request_handle_metrics: Arc<OpenRequestHandleMetrics>,
+    /// locked for the whole reload so reloads don't overlap
+    pub(super) state: AsyncMutex<ConnectionsState>,
+}
+
+#[derive(Default)]
+pub(super) struct ConnectionsState {
+    /// every configured rpc, including disabled rpcs and rpcs that failed to connect
+    pub(super) configs: HashMap<String, Web3ConnectionConfig>,
+    /// the subscription task of every connected rpc
+    pub(super) handles: HashMap<String, AnyhowJoinHandle<()>>,
+}
+
+/// The names of the rpcs that a new config adds, removes, or changes.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// reconnected with the new config
+    pub changed: Vec<String>,
+    /// only the soft limit or tier changed. these are updated without reconnecting
+    pub updated: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.updated.is_empty()
+    }
+}
+
+/// Compare the running configs to new ones. Disabled rpcs count as removed.
+fn diff_configs(
+    running: &HashMap<&str, &Web3ConnectionConfig>,
+    new: &HashMap<String, Web3ConnectionConfig>,
+) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+
+    for (name, config) in new.iter().filter(|(_, x)| !x.disabled) {
+        let old = match running.get(name.as_str()) {
+            None => {
+                diff.added.push(name.clone());
+                continue;
+            }
+            Some(old) if *old == config => continue,
+            Some(old) => old,
+        };
+
+        let mut updated = (*old).clone();
+        updated.soft_limit = config.soft_limit;
+        updated.subscription_soft_limit = config.subscription_soft_limit;
+        updated.tier = config.tier;
+
+        if updated == *config {
+            diff.updated.push(name.clone());
+        } else {
+            diff.changed.push(name.clone());
+        }
+    }
+
+    for name in running.keys() {
+        if new.get(*name).map(|x| x.disabled).unwrap_or(true) {
+            diff.removed.push(name.to_string());
+        }
+    }
+
+    // sorted for readable logs
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    diff.updated.sort();
+
+    diff
+}
+
+impl ConnectionSpawner {
+    pub(super) async fn spawn_connection(
+        &self,
+        name: String,
+        config: Web3ConnectionConfig,
+    ) -> anyhow::Result<(Arc<Web3Connection>, AnyhowJoinHandle<()>)> {
+        config
+            .spawn(
+                name,
+                self.allowed_lag,
+                self.db_conn.clone(),
+                self.redis_pool.clone(),
+                self.chain_id,
+                self.http_client.clone(),
+                self.http_interval_sender.clone(),
+                self.block_map.clone(),
+                self.block_sender.clone(),
+                Some(self.tx_id_sender.clone()),
+                self.open_request_handle_metrics.clone(),
+            )
+            .await
+    }
+}
+
+impl Web3Connections {
+    fn spawner(&self) -> anyhow::Result<&ConnectionSpawner> {
+        self.spawner
+            .as_ref()
+            .context("these rpcs can't be reloaded")
+    }
+
+    /// The config of every rpc, including disabled ones.
+    pub async fn configs(&self) -> anyhow::Result<HashMap<String, Web3ConnectionConfig>> {
+        let state = self.spawner()?.state.lock().await;
+
+        Ok(state.configs.clone())
+    }
+
+    /// Connect to rpcs that are new in `server_configs`, disconnect from rpcs that are gone, and reconnect to rpcs whose config changed.
+    /// Unchanged rpcs keep their connections.
+    pub async fn apply_configs(
+        &self,
+        server_configs: HashMap<String, Web3ConnectionConfig>,
+    ) -> anyhow::Result<ConfigDiff> {
+        let spawner = self.spawner()?;
+
+        let mut state = spawner.state.lock().await;
+
+        self.apply_configs_locked(spawner, &mut state, server_configs)
+            .await
+    }
+
+    /// Add, replace, or modify one rpc's config. `f` gets the current config (if any) and returns the new one.
+    pub async fn update_config<F>(&self, name: &str, f: F) -> anyhow::Result<ConfigDiff>
+    where
+        F: FnOnce(Option<&Web3ConnectionConfig>) -> anyhow::Result<Web3ConnectionConfig>,
+    {
+        let spawner = self.spawner()?;
+
+        let mut state = spawner.state.lock().await;
+
+        let mut server_configs = state.configs.clone();
+
+        let config = f(server_configs.get(name))?;
+
+        server_configs.insert(name.to_string(), config);
+
+        self.apply_configs_locked(spawner, &mut state, server_configs)
+            .await
+    }
+
+    async fn apply_configs_locked(
+        &self,
+        spawner: &ConnectionSpawner,
+        state: &mut ConnectionsState,
+        server_configs: HashMap<String, Web3ConnectionConfig>,
+    ) -> anyhow::Result<ConfigDiff> {
+        // the same safety checks as on startup
+        let enabled = server_configs.values().filter(|x| !x.disabled);
+
+        let num_enabled = enabled.clone().count();
+        if num_enabled < self.min_head_rpcs {
+            return Err(anyhow::anyhow!(
+                "Only {}/{} rpcs! Add more rpcs or reduce min_synced_rpcs.",
+                num_enabled,
+                self.min_head_rpcs
+            ));
+        }
+
+        let sum_soft_limit: u32 = enabled.map(|x| x.soft_limit).sum();
+        if sum_soft_limit < self.min_sum_soft_limit {
+            return Err(anyhow::anyhow!(
+                "Only {}/{} soft limit! Add more rpcs, increase soft limits, or reduce min_sum_soft_limit.",
+                sum_soft_limit,
+                self.min_sum_soft_limit
+            ));
+        }
+
+        let diff = {
+            let running_configs = state
+                .handles
+                .keys()
+                .filter_map(|name| Some((name.as_str(), state.configs.get(name)?)))
+                .collect();
+
+            diff_configs(&running_configs, &server_configs)
+        };
+
+        let mut conns = (**self.conns.load()).clone();
+
+        for name in diff.updated.iter() {
+            if let Some(rpc) = conns.get(name) {
+                let config = &server_configs[name];
+
+                rpc.soft_limit
+                    .store(config.soft_limit, atomic::Ordering::Relaxed);
+                rpc.subscription_soft_limit.store(
+                    config.subscription_soft_limit.unwrap_or(config.soft_limit),
+                    atomic::Ordering::Relaxed,
+                );
+                rpc.tier.store(config.tier, atomic::Ordering::Relaxed);
+            }
+        }
+
+        // stop the old connections first so that a changed rpc's old head is forgotten before the new connection sends one
+        let mut retired = vec![];
+
+        for name in diff.removed.iter().chain(diff.changed.iter()) {
+            if let Some(handle) = state.handles.remove(name) {
+                handle.abort();
+            }
+
+            if let Some(rpc) = conns.remove(name) {
+                retired.push(rpc);
+            }
+        }
+
+        if !retired.is_empty() {
+            self.conns.store(Arc::new(conns.clone()));
+
+            if let Some(block_sender) = spawner.block_sender.as_ref() {
+                for rpc in retired {
+                    // no block removes the rpc from the consensus head
+                    // an error means nothing is following heads anymore
+                    let _ = block_sender.send_async((None, rpc)).await;
+                }
+            }
+        }
+
+        let spawned = join_all(diff.added.iter().chain(diff.changed.iter()).map(|name| {
+            let config = server_configs[name].clone();
+
+            async move { (name, spawner.spawn_connection(name.clone(), config).await) }
+        }))
+        .await;
+
+        if !spawned.is_empty() {
+            for (name, x) in spawned {
+                match x {
+                    Ok((rpc, handle)) => {
+                        conns.insert(name.clone(), rpc);
+                        state.handles.insert(name.clone(), handle);
+                    }
+                    Err(err) => {
+                        // it isn't running, so the next reload tries again
+                        error!("Unable to create connection {}. err={:?}", name, err);
+                    }
+                }
+            }
+
+            self.conns.store(Arc::new(conns));
+        }
+
+        state.configs = server_configs;
+
+        if !diff.is_empty() {
+            info!("applied rpc configs: {:?}", diff);
+        }
+
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(url: &str) -> Web3ConnectionConfig {
+        toml::from_str(&format!("url = \"{}\"\nsoft_limit = 100", url)).unwrap()
+    }
+
+    #[test]
+    fn test_diff_configs() {
+        let a = config("ws://a");
+        let b = config("ws://b");
+        let c = config("ws://c");
+
+        let running = HashMap::from([("a", &a), ("b", &b), ("c", &c)]);
+
+        let mut disabled_c = c.clone();
+        disabled_c.disabled = true;
+
+        let mut cheaper_a = a.clone();
+        cheaper_a.soft_limit = 200;
+        cheaper_a.subscription_soft_limit = Some(50);
+        cheaper_a.tier = 1;
+
+        let new = HashMap::from([
+            ("a".to_string(), cheaper_a),
+            ("b".to_string(), config("ws://b2")),
+            ("c".to_string(), disabled_c),
+            ("d".to_string(), config("ws://d")),
+        ]);
+
+        assert_eq!(
+            diff_configs(&running, &new),
+            ConfigDiff {
+                added: vec!["d".to_string()],
+                removed: vec!["c".to_string()],
+                changed: vec!["b".to_string()],
+                updated: vec!["a".to_string()],
+            }
+        );
+
+        let same = HashMap::from([
+            ("a".to_string(), a.clone()),
+            ("b".to_string(), b.clone()),
+            ("c".to_string(), c.clone()),
+        ]);
+
+        assert!(diff_configs(&running, &same).is_empty());
+    }
+}
@@ -1,12 +1,15 @@
 use super::connection::Web3Connection;
+use super::payload_size::ResultSize;
 use super::provider::Web3Provider;
 use crate::frontend::authorization::{Authorization, AuthorizationType};
+use crate::jsonrpc::JsonRpcErrorData;
 use crate::metered::{JsonRpcErrorCount, ProviderErrorCount};
+use crate::telemetry;
 use anyhow::Context;
 use chrono::Utc;
 use entities::revert_log;
 use entities::sea_orm_active_enums::Method;
-use ethers::providers::{HttpClientError, ProviderError, WsClientError};
+use ethers::providers::{HttpClientError, IpcError, ProviderError, WsClientError};
 use ethers::types::{Address, Bytes};
 use log::{debug, error, trace, warn, Level};
 use metered::metered;
@@ -14,13 +17,34 @@ use metered::HitCount;
 use metered::ResponseTime;
 use metered::Throughput;
 use migration::sea_orm::{self, ActiveEnum, ActiveModelTrait};
+use opentelemetry::trace::FutureExt as _;
+use opentelemetry::KeyValue;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 use serde_json::json;
+use serde_json::value::RawValue;
 use std::fmt;
 use std::sync::atomic::{self, AtomicBool, Ordering};
 use std::sync::Arc;
 use thread_fast_rng::rand::Rng;
 use tokio::time::{sleep, Duration, Instant};
 
+/// How long to back off when a provider rate limits us without saying for how long.
+const DEFAULT_PROVIDER_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Providers can ask for any backoff they want, but we check on them at least this often.
+const MAX_PROVIDER_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Backends have separate soft limits for requests and for subscriptions.
+/// A server that is busy with heavy requests can still keep up with new heads and the other way around.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Traffic {
+    /// requests from users and most internal queries
+    Request,
+    /// keeping up with new heads, pending transactions, and `logs` subscriptions
+    Subscription,
+}
+
 #[derive(Debug)]
 pub enum OpenRequestResult {
     Handle(OpenRequestHandle),
@@ -38,6 +62,7 @@ pub struct OpenRequestHandle {
     // TODO: this is the same metrics on the conn. use a reference?
     metrics: Arc<OpenRequestHandleMetrics>,
     provider: Arc<Web3Provider>,
+    traffic: Traffic,
     used: AtomicBool,
 }
 
@@ -65,6 +90,107 @@ struct EthCallFirstParams {
     data: Option<Bytes>,
 }
 
+/// The JSON-RPC envelope from an http backend. The result is parsed once we know there was no error.
+/// `E` is always ethers' `JsonRpcError`. ethers doesn't export it, so it is inferred from `HttpClientError::JsonRpcError`.
+#[derive(serde::Deserialize)]
+struct HttpJsonRpcResponse<E> {
+    result: Option<Box<RawValue>>,
+    error: Option<E>,
+}
+
+/// Parse a header as seconds. Some providers send fractions.
+fn header_seconds(headers: &HeaderMap, name: &str) -> Option<f64> {
+    let x: f64 = headers.get(name)?.to_str().ok()?.trim().parse().ok()?;
+
+    if x.is_finite() && x >= 0.0 {
+        Some(x)
+    } else {
+        None
+    }
+}
+
+/// A backoff in seconds from a provider, capped at `MAX_PROVIDER_BACKOFF`.
+/// Huge values would overflow `Duration` and `Instant`.
+fn provider_backoff(seconds: f64) -> Duration {
+    if seconds.is_nan() || seconds <= 0.0 {
+        Duration::ZERO
+    } else if seconds >= MAX_PROVIDER_BACKOFF.as_secs_f64() {
+        MAX_PROVIDER_BACKOFF
+    } else {
+        Duration::from_secs_f64(seconds)
+    }
+}
+
+/// How long a hosted provider wants us to back off, from the headers on its response.
+///
+/// Providers disagree about the format of these headers:
+/// - `Retry-After` is a number of seconds (we do not support the http-date form)
+/// - `x-ratelimit-reset` and `ratelimit-reset` are only honored once `*-remaining` is 0.
+///   Some providers send seconds until the reset, others send a unix timestamp in seconds or milliseconds.
+pub fn retry_after_from_headers(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if let Some(x) = header_seconds(headers, "retry-after") {
+        return Some(provider_backoff(x));
+    }
+
+    for prefix in ["x-ratelimit", "ratelimit"] {
+        let remaining = header_seconds(headers, &format!("{}-remaining", prefix));
+
+        if remaining != Some(0.0) {
+            continue;
+        }
+
+        let reset = match header_seconds(headers, &format!("{}-reset", prefix)) {
+            Some(x) => x,
+            None => break,
+        };
+
+        let now = virtual_clock::unix_now().as_secs_f64();
+
+        let wait = if reset > 1_000_000_000_000.0 {
+            // unix timestamp in milliseconds
+            reset / 1000.0 - now
+        } else if reset > 1_000_000_000.0 {
+            // unix timestamp in seconds
+            reset - now
+        } else {
+            reset
+        };
+
+        return Some(provider_backoff(wait));
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Some(DEFAULT_PROVIDER_BACKOFF)
+    } else {
+        None
+    }
+}
+
+/// Some providers only mention rate limits inside the JSON-RPC error.
+///
+/// - Infura uses -32005 and puts `backoff_seconds` in `data.rate`
+/// - Alchemy uses 429
+pub fn retry_after_from_error(err: &JsonRpcErrorData) -> Option<Duration> {
+    if let Some(x) = err
+        .data
+        .as_ref()
+        .and_then(|x| x.get("rate"))
+        .and_then(|x| x.get("backoff_seconds"))
+        .and_then(|x| x.as_f64())
+    {
+        if x.is_finite() && x >= 0.0 {
+            return Some(provider_backoff(x));
+        }
+    }
+
+    // -32005 is not checked. some providers also use it for "query returned more than 10000 results"
+    if err.code == 429 || err.message.contains("rate limit") {
+        Some(DEFAULT_PROVIDER_BACKOFF)
+    } else {
+        None
+    }
+}
+
 impl From<Level> for RequestErrorHandler {
     fn from(level: Level) -> Self {
         match level {
@@ -98,11 +224,7 @@ impl Authorization {
         // we intentionally use "now" and not the time the request started
         // why? because we aggregate stats and setting one in the past could cause confusion
         let timestamp = Utc::now();
-        let to: Vec<u8> = params
-            .to
-            .as_bytes()
-            .try_into()
-            .expect("address should always convert to a Vec<u8>");
+        let to: Vec<u8> = params.to.as_bytes().into();
         let call_data = params.data.map(|x| format!("{}", x));
 
         let rl = revert_log::ActiveModel {
@@ -130,13 +252,17 @@ impl Authorization {
 
 #[metered(registry = OpenRequestHandleMetrics, visibility = pub)]
 impl OpenRequestHandle {
-    pub async fn new(authorization: Arc<Authorization>, conn: Arc<Web3Connection>) -> Self {
+    pub async fn new(
+        authorization: Arc<Authorization>,
+        conn: Arc<Web3Connection>,
+        traffic: Traffic,
+    ) -> Self {
         // TODO: take request_id as an argument?
         // TODO: attach a unique id to this? customer requests have one, but not internal queries
         // TODO: what ordering?!
         // TODO: should we be using metered, or not? i think not because we want stats for each handle
         // TODO: these should maybe be sent to an influxdb instance?
-        conn.active_requests.fetch_add(1, atomic::Ordering::Relaxed);
+        conn.active(traffic).fetch_add(1, atomic::Ordering::Relaxed);
 
         let mut provider = None;
         let mut logged = false;
@@ -191,6 +317,7 @@ impl OpenRequestHandle {
             conn,
             metrics,
             provider,
+            traffic,
             used,
         }
     }
@@ -204,6 +331,84 @@ impl OpenRequestHandle {
         self.conn.clone()
     }
 
+    /// Send a request with our own http client so that the provider's rate limit headers are visible.
+    /// ethers' `Http` provider drops the headers.
+    async fn http_request<P, R>(&self, method: &str, params: &P) -> Result<R, ProviderError>
+    where
+        P: fmt::Debug + serde::Serialize + Send + Sync,
+        R: serde::de::DeserializeOwned,
+    {
+        let http_client = self
+            .conn
+            .http_client
+            .as_ref()
+            .ok_or_else(|| ProviderError::CustomError("no http_client".to_string()))?;
+
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+
+        let request = http_client
+            .post(&self.conn.url)
+            .json(&body)
+            .build()
+            .map_err(HttpClientError::from)?;
+
+        let header_bytes = request
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+
+        let body_bytes = request
+            .body()
+            .and_then(|x| x.as_bytes())
+            .map(|x| x.len())
+            .unwrap_or_default();
+
+        self.conn
+            .backend_metrics
+            .payload
+            .record_request(header_bytes as u64, body_bytes as u64);
+
+        let response = http_client
+            .execute(request)
+            .await
+            .map_err(HttpClientError::from)?;
+
+        if let Some(wait) = retry_after_from_headers(response.status(), response.headers()) {
+            self.conn.set_hard_limit_until(Instant::now() + wait);
+        }
+
+        let text = response.text().await.map_err(HttpClientError::from)?;
+
+        let response: HttpJsonRpcResponse<_> = match serde_json::from_str(&text) {
+            Ok(x) => x,
+            Err(err) => return Err(HttpClientError::SerdeJson { err, text }.into()),
+        };
+
+        if let Some(err) = response.error {
+            return Err(HttpClientError::JsonRpcError(err).into());
+        }
+
+        let result = response
+            .result
+            .as_deref()
+            .map(|x| x.get())
+            .unwrap_or("null");
+
+        serde_json::from_str(result).map_err(|err| {
+            HttpClientError::SerdeJson {
+                err,
+                text: result.to_string(),
+            }
+            .into()
+        })
+    }
+
     /// Send a web3 request
     /// By having the request method here, we ensure that the rate limiter was called and connection counts were properly incremented
     /// TODO: we no longer take self because metered doesn't like that
@@ -225,20 +430,53 @@ impl OpenRequestHandle {
             unimplemented!("a request handle should only be used once");
         }
 
-        // TODO: use tracing spans
-        // TODO: requests from customers have request ids, but we should add
         // TODO: including params in this is way too verbose
-        // the authorization field is already on a parent span
-        // trace!(rpc=%self.conn, %method, "request");
+        // the authorization is already on the parent span
+        let span_cx = telemetry::start_span(
+            "backend_request",
+            vec![
+                KeyValue::new("rpc.method", method.to_string()),
+                KeyValue::new("web3_proxy.rpc", self.conn.name.clone()),
+            ],
+        );
 
-        // trace!("got provider for {:?}", self);
+        self.conn.method_counts.count(method);
+
+        let start = Instant::now();
 
         // TODO: really sucks that we have to clone here
-        let response = match &*self.provider {
-            Web3Provider::Mock => unimplemented!(),
-            Web3Provider::Http(provider) => provider.request(method, params).await,
-            Web3Provider::Ws(provider) => provider.request(method, params).await,
-        };
+        let response = async {
+            match &*self.provider {
+                Web3Provider::Mock => unimplemented!(),
+                Web3Provider::Http(_) => self.http_request(method, params).await,
+                Web3Provider::Ws(provider) => provider.request(method, params).await,
+                Web3Provider::Ipc(provider) => provider.request(method, params).await,
+            }
+        }
+        .with_context(span_cx.clone())
+        .await;
+
+        self.conn
+            .backend_metrics
+            .latency
+            .record(start.elapsed(), telemetry::sampled_trace_id(&span_cx));
+        self.conn.backend_metrics.recent.add_request();
+
+        if let Ok(result) = &response {
+            // errors are often instant. counting them would make a failing server look fast
+            self.conn.latency_ewma.record(start.elapsed());
+
+            self.conn
+                .backend_metrics
+                .payload
+                .record_result(method, ResultSize::of(result));
+        }
+
+        if let Err(err) = &response {
+            telemetry::set_span_error(&span_cx, err);
+        }
+
+        drop(span_cx);
 
         // TODO: i think ethers already has trace logging (and does it much more fancy)
         trace!(
@@ -284,16 +522,19 @@ impl OpenRequestHandle {
                 error_handler
             };
 
-            // check for "execution reverted" here
-            let is_revert = if let ProviderError::JsonRpcClientError(err) = err {
-                // Http and Ws errors are very similar, but different types
-                let msg = match &*self.provider {
+            // Http, Ws, and Ipc errors are very similar, but different types. ethers doesn't export their JsonRpcError
+            let json_rpc_error = if let ProviderError::JsonRpcClientError(err) = err {
+                match &*self.provider {
                     Web3Provider::Mock => unimplemented!(),
                     Web3Provider::Http(_) => {
                         if let Some(HttpClientError::JsonRpcError(err)) =
                             err.downcast_ref::<HttpClientError>()
                         {
-                            Some(&err.message)
+                            Some(JsonRpcErrorData {
+                                code: err.code,
+                                message: err.message.clone(),
+                                data: err.data.clone(),
+                            })
                         } else {
                             None
                         }
@@ -302,22 +543,55 @@ impl OpenRequestHandle {
                         if let Some(WsClientError::JsonRpcError(err)) =
                             err.downcast_ref::<WsClientError>()
                         {
-                            Some(&err.message)
+                            Some(JsonRpcErrorData {
+                                code: err.code,
+                                message: err.message.clone(),
+                                data: err.data.clone(),
+                            })
+                        } else {
+                            None
+                        }
+                    }
+                    Web3Provider::Ipc(_) => {
+                        if let Some(IpcError::JsonRpcError(err)) = err.downcast_ref::<IpcError>() {
+                            Some(JsonRpcErrorData {
+                                code: err.code,
+                                message: err.message.clone(),
+                                data: err.data.clone(),
+                            })
                         } else {
                             None
                         }
                     }
-                };
-
-                if let Some(msg) = msg {
-                    msg.starts_with("execution reverted")
-                } else {
-                    false
                 }
             } else {
-                false
+                None
             };
 
+            if json_rpc_error.is_some() {
+                self.conn
+                    .backend_metrics
+                    .jsonrpc_errors
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+            } else {
+                self.conn
+                    .backend_metrics
+                    .errors
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+
+                self.conn.backend_metrics.recent.add_error();
+            }
+
+            if let Some(wait) = json_rpc_error.as_ref().and_then(retry_after_from_error) {
+                self.conn.set_hard_limit_until(Instant::now() + wait);
+            }
+
+            // check for "execution reverted" here
+            let is_revert = json_rpc_error
+                .as_ref()
+                .map(|x| x.message.starts_with("execution reverted"))
+                .unwrap_or(false);
+
             if is_revert {
                 trace!("revert from {}", self.conn);
             }
@@ -388,7 +662,83 @@ impl OpenRequestHandle {
 impl Drop for OpenRequestHandle {
     fn drop(&mut self) {
         self.conn
-            .active_requests
+            .active(self.traffic)
             .fetch_sub(1, atomic::Ordering::AcqRel);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after_from_headers() {
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(retry_after_from_headers(StatusCode::OK, &headers), None);
+        assert_eq!(
+            retry_after_from_headers(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(DEFAULT_PROVIDER_BACKOFF)
+        );
+
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("5"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("3"));
+        assert_eq!(retry_after_from_headers(StatusCode::OK, &headers), None);
+
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        assert_eq!(
+            retry_after_from_headers(StatusCode::OK, &headers),
+            Some(Duration::from_secs(3))
+        );
+
+        headers.insert("retry-after", HeaderValue::from_static("10"));
+        assert_eq!(
+            retry_after_from_headers(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(Duration::from_secs(10))
+        );
+
+        headers.insert("retry-after", HeaderValue::from_static("1e300"));
+        assert_eq!(
+            retry_after_from_headers(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(MAX_PROVIDER_BACKOFF)
+        );
+
+        headers.remove("retry-after");
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1e300"));
+        assert_eq!(
+            retry_after_from_headers(StatusCode::OK, &headers),
+            Some(MAX_PROVIDER_BACKOFF)
+        );
+    }
+
+    #[test]
+    fn test_retry_after_from_error() {
+        let infura = JsonRpcErrorData {
+            code: -32005,
+            message: "daily request count exceeded, request rate limited".to_string(),
+            data: Some(json!({"rate": {"backoff_seconds": 30}})),
+        };
+
+        assert_eq!(
+            retry_after_from_error(&infura),
+            Some(Duration::from_secs(30))
+        );
+
+        let revert = JsonRpcErrorData {
+            code: -32000,
+            message: "execution reverted".to_string(),
+            data: None,
+        };
+
+        assert_eq!(retry_after_from_error(&revert), None);
+
+        let too_many_logs = JsonRpcErrorData {
+            code: -32005,
+            message: "query returned more than 10000 results".to_string(),
+            data: None,
+        };
+
+        assert_eq!(retry_after_from_error(&too_many_logs), None);
+    }
+}
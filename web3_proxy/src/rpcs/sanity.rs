@@ -0,0 +1,204 @@
+//! Checks that chain data from a backend makes sense before we use it.
+//!
+//! `validation` checks the shape of responses. This checks blocks and transactions against the chain we already know.
+//! A backend that fails is quarantined: its head blocks are ignored and it gets no requests for a while.
+use super::blockchain::ArcBlock;
+use super::connection::Web3Connection;
+use super::connections::Web3Connections;
+use anyhow::Context;
+use ethers::prelude::{Block, Transaction, TxHash, U256};
+use log::warn;
+use tokio::time::{Duration, Instant};
+
+/// How long a backend that sent bad data is ignored.
+const QUARANTINE: Duration = Duration::from_secs(5 * 60);
+
+/// Block timestamps can be this far ahead of our clock.
+const MAX_FUTURE_SECONDS: u64 = 60;
+
+/// Check a block against its parent, if we have it. `now` is a unix timestamp.
+pub fn check_block(
+    block: &Block<TxHash>,
+    parent: Option<&Block<TxHash>>,
+    now: u64,
+) -> anyhow::Result<()> {
+    block.hash.context("block has no hash")?;
+    let num = block.number.context("block has no number")?;
+
+    if block.timestamp > U256::from(now + MAX_FUTURE_SECONDS) {
+        return Err(anyhow::anyhow!(
+            "block {} is from the future. timestamp={}",
+            num,
+            block.timestamp
+        ));
+    }
+
+    if let Some(parent) = parent {
+        let parent_num = parent.number.context("parent has no number")?;
+
+        if parent_num.as_u64() + 1 != num.as_u64() {
+            return Err(anyhow::anyhow!(
+                "block {} has parent {} from block {}",
+                num,
+                block.parent_hash,
+                parent_num
+            ));
+        }
+
+        // some chains make multiple blocks per second, so equal timestamps are fine
+        if block.timestamp < parent.timestamp {
+            return Err(anyhow::anyhow!(
+                "block {} is older than its parent. timestamp={} parent_timestamp={}",
+                num,
+                block.timestamp,
+                parent.timestamp
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Transactions from before EIP-155 have no chain id.
+pub fn check_transaction(tx: &Transaction, chain_id: u64) -> anyhow::Result<()> {
+    match tx.chain_id {
+        Some(x) if x != U256::from(chain_id) => Err(anyhow::anyhow!(
+            "transaction {:?} is for chain {}",
+            tx.hash,
+            x
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Web3Connection {
+    pub(super) fn quarantine(&self, err: &anyhow::Error) {
+        warn!(
+            "quarantining {} for {:?} because it sent bad data: {:#}",
+            self, QUARANTINE, err
+        );
+
+        *self.quarantined_until.write() = Some(Instant::now() + QUARANTINE);
+    }
+
+    pub fn quarantined(&self) -> bool {
+        self.quarantined_until
+            .read()
+            .map(|x| x > Instant::now())
+            .unwrap_or(false)
+    }
+}
+
+impl Web3Connections {
+    /// Returns false if the block should be ignored. The rpc is quarantined if the block is bad.
+    pub(super) fn sane_block_from_rpc(&self, block: &ArcBlock, rpc: &Web3Connection) -> bool {
+        if rpc.quarantined() {
+            return false;
+        }
+
+        let parent = self.block_hashes.get(&block.parent_hash);
+
+        let now = virtual_clock::unix_now().as_secs();
+
+        match check_block(block, parent.as_deref(), now) {
+            Ok(()) => true,
+            Err(err) => {
+                rpc.quarantine(&err);
+                false
+            }
+        }
+    }
+
+    /// Returns false if the transaction should be ignored. The rpc is quarantined if the transaction is bad.
+    pub(super) fn sane_transaction_from_rpc(&self, tx: &Transaction, rpc: &Web3Connection) -> bool {
+        match check_transaction(tx, self.chain_id) {
+            Ok(()) => true,
+            Err(err) => {
+                rpc.quarantine(&err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::prelude::H256;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn block(num: u64, hash: u64, parent_hash: u64, timestamp: u64) -> Block<TxHash> {
+        Block {
+            number: Some(num.into()),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent_hash),
+            timestamp: timestamp.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_block() {
+        let parent = block(9, 9, 8, NOW - 12);
+
+        assert!(check_block(&block(10, 10, 9, NOW), Some(&parent), NOW).is_ok());
+        // we don't always have the parent
+        assert!(check_block(&block(10, 10, 9, NOW), None, NOW).is_ok());
+        // equal timestamps are allowed
+        assert!(check_block(&block(10, 10, 9, NOW - 12), Some(&parent), NOW).is_ok());
+
+        // the parent is from the wrong height
+        assert!(check_block(&block(11, 11, 9, NOW), Some(&parent), NOW).is_err());
+        // older than its parent
+        assert!(check_block(&block(10, 10, 9, NOW - 13), Some(&parent), NOW).is_err());
+        // from the future
+        assert!(check_block(&block(10, 10, 9, NOW + 61), Some(&parent), NOW).is_err());
+        // pending blocks don't have a hash
+        assert!(check_block(&Block::default(), None, NOW).is_err());
+    }
+
+    #[test]
+    fn test_check_transaction() {
+        let mut tx = Transaction::default();
+
+        // legacy transactions have no chain id
+        assert!(check_transaction(&tx, 1).is_ok());
+
+        tx.chain_id = Some(1.into());
+        assert!(check_transaction(&tx, 1).is_ok());
+
+        tx.chain_id = Some(137.into());
+        assert!(check_transaction(&tx, 1).is_err());
+    }
+
+    #[cfg(feature = "test-clock")]
+    #[tokio::test]
+    async fn test_quarantine_cooldown() {
+        use hashbrown::HashMap;
+        use std::sync::Arc;
+
+        virtual_clock::pause();
+
+        let rpc = Web3Connection::mock("a");
+        let rpcs = Web3Connections::mock(HashMap::new());
+
+        let now = virtual_clock::unix_now().as_secs();
+
+        // a block from the future gets the rpc quarantined
+        let bad = Arc::new(block(2, 2, 1, now + 2 * MAX_FUTURE_SECONDS));
+        assert!(!rpcs.sane_block_from_rpc(&bad, &rpc));
+        assert!(rpc.quarantined());
+
+        // even good blocks are ignored during the quarantine
+        let good = Arc::new(block(2, 3, 1, now));
+        assert!(!rpcs.sane_block_from_rpc(&good, &rpc));
+
+        tokio::time::advance(QUARANTINE - Duration::from_secs(1)).await;
+        assert!(rpc.quarantined());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!rpc.quarantined());
+        assert!(rpcs.sane_block_from_rpc(&good, &rpc));
+    }
+}
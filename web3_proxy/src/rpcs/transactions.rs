@@ -1,14 +1,20 @@
-use crate::frontend::authorization::Authorization;
-
-///! Load balanced communication with a group of web3 providers
+//! Load balanced communication with a group of web3 providers
 use super::connection::Web3Connection;
 use super::connections::Web3Connections;
-use super::request::OpenRequestResult;
+use super::request::{OpenRequestResult, Traffic};
+use crate::frontend::authorization::Authorization;
 use ethers::prelude::{ProviderError, Transaction, TxHash};
+use hashbrown::HashSet;
 use log::{debug, trace, Level};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
+/// How many recently announced transaction ids to remember.
+/// Every backend with `subscribe_txs` announces the same transactions, usually within a few seconds of each other.
+const SEEN_TX_IDS_CAPACITY: usize = 100_000;
+
 // TODO: think more about TxState
 #[derive(Clone)]
 pub enum TxStatus {
@@ -17,6 +23,40 @@ pub enum TxStatus {
     Orphaned(Transaction),
 }
 
+/// Transaction ids that a backend recently announced. The oldest ids are forgotten first.
+/// This dedupes before the transaction is fetched, so only the first backend to announce a transaction is queried.
+#[derive(Default)]
+pub struct SeenTxIds {
+    inner: Mutex<(HashSet<TxHash>, VecDeque<TxHash>)>,
+}
+
+impl SeenTxIds {
+    /// true the first time an id is seen
+    pub fn first_sighting(&self, tx_id: TxHash) -> bool {
+        let mut inner = self.inner.lock();
+        let (set, order) = &mut *inner;
+
+        if !set.insert(tx_id) {
+            return false;
+        }
+
+        order.push_back(tx_id);
+
+        while order.len() > SEEN_TX_IDS_CAPACITY {
+            if let Some(old) = order.pop_front() {
+                set.remove(&old);
+            }
+        }
+
+        true
+    }
+
+    /// the first backend couldn't give us the transaction. let the next backend that announces it try
+    pub fn forget(&self, tx_id: &TxHash) {
+        self.inner.lock().0.remove(tx_id);
+    }
+}
+
 impl Web3Connections {
     async fn query_transaction_status(
         &self,
@@ -28,7 +68,10 @@ impl Web3Connections {
         // TODO: might not be a race. might be a nonce thats higher than the current account nonce. geth discards chains
         // TODO: yearn devs have had better luck with batching these, but i think that's likely just adding a delay itself
         // TODO: if one rpc fails, try another?
-        let tx: Transaction = match rpc.try_request_handle(authorization, false).await {
+        let tx: Transaction = match rpc
+            .try_request_handle(authorization, false, Traffic::Subscription)
+            .await
+        {
             Ok(OpenRequestResult::Handle(handle)) => {
                 handle
                     .request(
@@ -53,6 +96,10 @@ impl Web3Connections {
             }
         };
 
+        if !self.sane_transaction_from_rpc(&tx, &rpc) {
+            return Ok(None);
+        }
+
         match &tx.block_hash {
             Some(_block_hash) => {
                 // the transaction is already confirmed. no need to save in the pending_transactions map
@@ -74,8 +121,8 @@ impl Web3Connections {
         // TODO: after more investigation, i don't think retries will help. i think this is because chains of transactions get dropped from memory
         // TODO: also check the "confirmed transactions" mapping? maybe one shared mapping with TxState in it?
 
-        if pending_tx_sender.receiver_count() == 0 {
-            // no receivers, so no point in querying to get the full transaction
+        if pending_tx_sender.receiver_count() == 0 && !self.cache_pending_txs {
+            // no receivers and no cache, so no point in querying to get the full transaction
             return Ok(());
         }
 
@@ -85,6 +132,11 @@ impl Web3Connections {
             return Ok(());
         }
 
+        if !self.seen_tx_ids.first_sighting(pending_tx_id) {
+            // another backend announced this transaction first. it is already being fetched
+            return Ok(());
+        }
+
         // query the rpc for this transaction
         // it is possible that another rpc is also being queried. thats fine. we want the fastest response
         match self
@@ -92,6 +144,13 @@ impl Web3Connections {
             .await
         {
             Ok(Some(tx_state)) => {
+                if let TxStatus::Pending(_) = tx_state {
+                    // remember the full transaction. this dedupes and lets eth_getTransactionByHash answer before every backend has it
+                    self.pending_transactions
+                        .insert(pending_tx_id, tx_state.clone())
+                        .await;
+                }
+
                 let _ = pending_tx_sender.send(tx_state);
 
                 trace!("sent tx {:?}", pending_tx_id);
@@ -108,6 +167,8 @@ impl Web3Connections {
             }
         }
 
+        self.seen_tx_ids.forget(&pending_tx_id);
+
         // warn is too loud. this is somewhat common
         // "There is a Pending txn with a lower account nonce. This txn can only be executed after confirmation of the earlier Txn Hash#"
         // sometimes it's been pending for many hours
@@ -116,3 +177,25 @@ impl Web3Connections {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_tx_ids() {
+        let seen = SeenTxIds::default();
+
+        let a = TxHash::from_low_u64_be(1);
+        let b = TxHash::from_low_u64_be(2);
+
+        assert!(seen.first_sighting(a));
+        assert!(!seen.first_sighting(a));
+        assert!(seen.first_sighting(b));
+
+        seen.forget(&a);
+
+        assert!(seen.first_sighting(a));
+        assert!(!seen.first_sighting(b));
+    }
+}
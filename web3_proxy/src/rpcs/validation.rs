@@ -0,0 +1,202 @@
+//! Structural checks on backend responses.
+//!
+//! These catch garbage from a broken provider (truncated hashes, missing fields, non-hex numbers) before it reaches a user.
+//! They do not check that the data is correct.
+use anyhow::Context;
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+/// Check the shape of a successful response's result. Unknown methods always pass.
+pub fn validate_response(method: &str, result: &RawValue) -> anyhow::Result<()> {
+    let check: fn(&Value) -> anyhow::Result<()> = match method {
+        "eth_blockNumber"
+        | "eth_chainId"
+        | "eth_estimateGas"
+        | "eth_gasPrice"
+        | "eth_getBalance"
+        | "eth_getTransactionCount"
+        | "eth_maxPriorityFeePerGas" => check_quantity,
+        "eth_getBlockTransactionCountByHash" | "eth_getBlockTransactionCountByNumber" => {
+            |x| nullable(x, check_quantity)
+        }
+        "eth_call" | "eth_getCode" => check_data,
+        "eth_getStorageAt" => |x| check_fixed_data(x, 32),
+        "eth_sendRawTransaction" => check_hash,
+        "eth_getBlockByHash" | "eth_getBlockByNumber" => |x| nullable(x, check_block),
+        "eth_getTransactionByHash"
+        | "eth_getTransactionByBlockHashAndIndex"
+        | "eth_getTransactionByBlockNumberAndIndex" => |x| nullable(x, check_transaction),
+        "eth_getTransactionReceipt" => |x| nullable(x, check_receipt),
+        "eth_getLogs" => check_logs,
+        _ => return Ok(()),
+    };
+
+    let result: Value = serde_json::from_str(result.get()).context("result is not valid json")?;
+
+    check(&result).with_context(|| format!("malformed {} result", method))
+}
+
+fn nullable(x: &Value, f: fn(&Value) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    if x.is_null() {
+        Ok(())
+    } else {
+        f(x)
+    }
+}
+
+fn hex_str(x: &Value) -> anyhow::Result<&str> {
+    let x = x.as_str().context("expected a string")?;
+
+    x.strip_prefix("0x")
+        .with_context(|| format!("{:?} is missing the 0x prefix", x))
+}
+
+fn check_quantity(x: &Value) -> anyhow::Result<()> {
+    let digits = hex_str(x)?;
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("{} is not a hex quantity", x));
+    }
+
+    Ok(())
+}
+
+fn check_data(x: &Value) -> anyhow::Result<()> {
+    let digits = hex_str(x)?;
+
+    if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("{} is not hex data", x));
+    }
+
+    Ok(())
+}
+
+fn check_fixed_data(x: &Value, num_bytes: usize) -> anyhow::Result<()> {
+    check_data(x)?;
+
+    if hex_str(x)?.len() != num_bytes * 2 {
+        return Err(anyhow::anyhow!("{} is not {} bytes", x, num_bytes));
+    }
+
+    Ok(())
+}
+
+fn check_hash(x: &Value) -> anyhow::Result<()> {
+    check_fixed_data(x, 32)
+}
+
+fn check_address(x: &Value) -> anyhow::Result<()> {
+    check_fixed_data(x, 20)
+}
+
+/// check that a required field is present and has the right shape
+fn field(x: &Value, name: &str, f: fn(&Value) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let value = x
+        .get(name)
+        .with_context(|| format!("missing field {}", name))?;
+
+    f(value).with_context(|| format!("bad field {}", name))
+}
+
+fn check_block(x: &Value) -> anyhow::Result<()> {
+    // pending blocks have a null hash and number
+    field(x, "hash", |x| nullable(x, check_hash))?;
+    field(x, "number", |x| nullable(x, check_quantity))?;
+    field(x, "parentHash", check_hash)?;
+    field(x, "timestamp", check_quantity)?;
+
+    let transactions = x
+        .get("transactions")
+        .and_then(|x| x.as_array())
+        .context("missing transactions")?;
+
+    for tx in transactions {
+        // the transactions are hashes unless full transactions were requested
+        if tx.is_string() {
+            check_hash(tx)?;
+        } else {
+            check_transaction(tx)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_transaction(x: &Value) -> anyhow::Result<()> {
+    field(x, "hash", check_hash)?;
+    field(x, "from", check_address)?;
+    field(x, "nonce", check_quantity)?;
+    field(x, "input", check_data)?;
+    // pending transactions have a null block
+    field(x, "blockHash", |x| nullable(x, check_hash))?;
+    // contract creation has a null to
+    field(x, "to", |x| nullable(x, check_address))?;
+
+    Ok(())
+}
+
+fn check_receipt(x: &Value) -> anyhow::Result<()> {
+    field(x, "transactionHash", check_hash)?;
+    field(x, "blockHash", check_hash)?;
+    field(x, "blockNumber", check_quantity)?;
+    field(x, "logs", check_logs)?;
+
+    Ok(())
+}
+
+fn check_logs(x: &Value) -> anyhow::Result<()> {
+    let logs = x.as_array().context("expected an array of logs")?;
+
+    for log in logs {
+        field(log, "address", check_address)?;
+        field(log, "data", check_data)?;
+
+        let topics = log
+            .get("topics")
+            .and_then(|x| x.as_array())
+            .context("missing topics")?;
+
+        for topic in topics {
+            check_hash(topic)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_response;
+    use serde_json::value::RawValue;
+
+    fn raw(x: &str) -> Box<RawValue> {
+        RawValue::from_string(x.to_string()).unwrap()
+    }
+
+    #[test]
+    fn quantities() {
+        assert!(validate_response("eth_blockNumber", &raw(r#""0xe0e6a4""#)).is_ok());
+        assert!(validate_response("eth_blockNumber", &raw(r#""0x""#)).is_err());
+        assert!(validate_response("eth_blockNumber", &raw(r#""14739108""#)).is_err());
+        assert!(validate_response("eth_blockNumber", &raw("14739108")).is_err());
+    }
+
+    #[test]
+    fn hashes() {
+        let hash = r#""0x88df016429689c079f3b2f6ad39fa052532c56795b733da78a91ebe6a713944b""#;
+
+        assert!(validate_response("eth_sendRawTransaction", &raw(hash)).is_ok());
+        assert!(validate_response("eth_sendRawTransaction", &raw(r#""0x88df01""#)).is_err());
+    }
+
+    #[test]
+    fn nullable_objects() {
+        assert!(validate_response("eth_getTransactionReceipt", &raw("null")).is_ok());
+        assert!(validate_response("eth_getTransactionReceipt", &raw("{}")).is_err());
+    }
+
+    #[test]
+    fn unknown_methods_pass() {
+        assert!(validate_response("web3_clientVersion", &raw(r#""Geth/v1.10.26""#)).is_ok());
+    }
+}
@@ -0,0 +1,563 @@
+//! Roll the per-minute `rpc_accounting` rows into longer periods and delete old rows.
+//!
+//! Billing needs years of history, but not by the minute.
+//! Each rollup is built from the one before it, so rows are only deleted once the next rollup has them.
+//! Rows that are saved late are picked up by rolling the most recent windows again.
+
+use crate::config::StatRollupsConfig;
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use entities::{rpc_accounting, rpc_accounting_rollup};
+use hashbrown::HashMap;
+use log::{debug, info};
+use migration::sea_orm::{
+    self, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, TransactionTrait,
+};
+
+/// Windows are rolled up this long after their last source rows should have been saved.
+const ROLLUP_DELAY_SECONDS: i64 = 60;
+
+/// Catching up on a large backlog is spread across runs so that the stats are saved in between.
+const MAX_WINDOWS_PER_RUN: usize = 24;
+
+/// Proxies save their stats up to a period late. The last few rolled up windows are checked for new rows every run.
+const REROLL_WINDOWS: i64 = 3;
+
+/// Rows with the same key in a window are combined into one.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct RollupKey {
+    rpc_key_id: Option<u64>,
+    method: Option<String>,
+    origin: Option<String>,
+    error_response: bool,
+    archive_request: bool,
+    websocket_request: bool,
+}
+
+/// Percentiles can't be combined. Sums and maxes can.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct RollupTotals {
+    frontend_requests: u64,
+    backend_requests: u64,
+    cache_misses: u64,
+    cache_hits: u64,
+    sum_request_bytes: u64,
+    max_request_bytes: u64,
+    sum_response_millis: u64,
+    max_response_millis: u64,
+    sum_response_bytes: u64,
+    max_response_bytes: u64,
+    sum_compute_units: u64,
+}
+
+impl RollupTotals {
+    fn add(&mut self, other: &Self) {
+        self.frontend_requests += other.frontend_requests;
+        self.backend_requests += other.backend_requests;
+        self.cache_misses += other.cache_misses;
+        self.cache_hits += other.cache_hits;
+        self.sum_request_bytes += other.sum_request_bytes;
+        self.max_request_bytes = self.max_request_bytes.max(other.max_request_bytes);
+        self.sum_response_millis += other.sum_response_millis;
+        self.max_response_millis = self.max_response_millis.max(other.max_response_millis);
+        self.sum_response_bytes += other.sum_response_bytes;
+        self.max_response_bytes = self.max_response_bytes.max(other.max_response_bytes);
+        self.sum_compute_units += other.sum_compute_units;
+    }
+}
+
+fn from_raw(x: rpc_accounting::Model) -> (RollupKey, RollupTotals) {
+    let key = RollupKey {
+        rpc_key_id: x.rpc_key_id,
+        method: x.method,
+        origin: x.origin,
+        error_response: x.error_response,
+        archive_request: x.archive_request,
+        websocket_request: x.websocket_request,
+    };
+
+    let totals = RollupTotals {
+        frontend_requests: x.frontend_requests,
+        backend_requests: x.backend_requests,
+        cache_misses: x.cache_misses,
+        cache_hits: x.cache_hits,
+        sum_request_bytes: x.sum_request_bytes,
+        max_request_bytes: x.max_request_bytes,
+        sum_response_millis: x.sum_response_millis,
+        max_response_millis: x.max_response_millis,
+        sum_response_bytes: x.sum_response_bytes,
+        max_response_bytes: x.max_response_bytes,
+        sum_compute_units: x.sum_compute_units,
+    };
+
+    (key, totals)
+}
+
+fn from_rollup(x: rpc_accounting_rollup::Model) -> (RollupKey, RollupTotals) {
+    let key = RollupKey {
+        rpc_key_id: x.rpc_key_id,
+        method: x.method,
+        origin: x.origin,
+        error_response: x.error_response,
+        archive_request: x.archive_request,
+        websocket_request: x.websocket_request,
+    };
+
+    let totals = RollupTotals {
+        frontend_requests: x.frontend_requests,
+        backend_requests: x.backend_requests,
+        cache_misses: x.cache_misses,
+        cache_hits: x.cache_hits,
+        sum_request_bytes: x.sum_request_bytes,
+        max_request_bytes: x.max_request_bytes,
+        sum_response_millis: x.sum_response_millis,
+        max_response_millis: x.max_response_millis,
+        sum_response_bytes: x.sum_response_bytes,
+        max_response_bytes: x.max_response_bytes,
+        sum_compute_units: x.sum_compute_units,
+    };
+
+    (key, totals)
+}
+
+/// Where a rollup's rows come from.
+#[derive(Clone, Copy, Debug)]
+enum RollupSource {
+    /// the per-minute `rpc_accounting` rows
+    Raw,
+    /// a shorter rollup's period_seconds
+    Rollup(u64),
+}
+
+/// The start of the window that `timestamp` is in.
+fn window_start(timestamp: i64, period_seconds: u64) -> i64 {
+    timestamp - timestamp.rem_euclid(period_seconds as i64)
+}
+
+/// True once every source row in the window should have been saved.
+fn window_complete(start: i64, period_seconds: u64, source_seconds: u64, now: i64) -> bool {
+    start + period_seconds as i64 + source_seconds as i64 + ROLLUP_DELAY_SECONDS <= now
+}
+
+/// The starts of the windows to check for late rows, newest first.
+/// Windows that might have had source rows deleted are skipped.
+fn reroll_starts(next_window: i64, period_seconds: u64, source_kept_since: i64) -> Vec<i64> {
+    (1..=REROLL_WINDOWS)
+        .map(|i| next_window - period_seconds as i64 * i)
+        .take_while(|start| *start >= source_kept_since)
+        .collect()
+}
+
+pub struct StatRollups {
+    chain_id: u64,
+    db_conn: DatabaseConnection,
+    config: StatRollupsConfig,
+    /// how long each `rpc_accounting` row covers
+    stat_period_seconds: u64,
+}
+
+impl StatRollups {
+    pub fn new(
+        chain_id: u64,
+        db_conn: DatabaseConnection,
+        config: StatRollupsConfig,
+        stat_period_seconds: u64,
+    ) -> anyhow::Result<Self> {
+        let mut source_seconds = stat_period_seconds;
+
+        for rollup in config.rollups.iter() {
+            if rollup.period_seconds <= source_seconds
+                || rollup.period_seconds % source_seconds != 0
+            {
+                return Err(anyhow::anyhow!(
+                    "stat rollup period {} must be a multiple of {}",
+                    rollup.period_seconds,
+                    source_seconds
+                ));
+            }
+
+            source_seconds = rollup.period_seconds;
+        }
+
+        Ok(Self {
+            chain_id,
+            db_conn,
+            config,
+            stat_period_seconds,
+        })
+    }
+
+    /// Roll up every complete window and then delete rows past their retention.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut source = RollupSource::Raw;
+
+        for rollup in self.config.rollups.iter() {
+            self.roll_up(source, rollup.period_seconds).await?;
+
+            source = RollupSource::Rollup(rollup.period_seconds);
+        }
+
+        self.prune().await
+    }
+
+    fn source_seconds(&self, source: RollupSource) -> u64 {
+        match source {
+            RollupSource::Raw => self.stat_period_seconds,
+            RollupSource::Rollup(x) => x,
+        }
+    }
+
+    async fn roll_up(&self, source: RollupSource, period_seconds: u64) -> anyhow::Result<()> {
+        let now = Utc::now().timestamp();
+
+        let source_seconds = self.source_seconds(source);
+
+        let mut next_window = self.next_window(period_seconds).await?;
+
+        if let Some(next_window) = next_window {
+            self.reroll_recent(source, period_seconds, next_window)
+                .await?;
+        }
+
+        for _ in 0..MAX_WINDOWS_PER_RUN {
+            // skip over windows without any stats
+            let first = match self
+                .first_source_at_or_after(source, next_window.unwrap_or_else(|| timestamp(0)))
+                .await?
+            {
+                None => break,
+                Some(x) => x,
+            };
+
+            let start = window_start(first.timestamp(), period_seconds);
+
+            if !window_complete(start, period_seconds, source_seconds, now) {
+                break;
+            }
+
+            let end = timestamp(start + period_seconds as i64);
+            let start = timestamp(start);
+
+            let totals = self.source_totals(source, start, end).await?;
+
+            let models = self.models(period_seconds, start, totals);
+
+            debug!(
+                "rolled {:?} into {} rows for the {}s window at {}",
+                source,
+                models.len(),
+                period_seconds,
+                start
+            );
+
+            // a window with a source row always has at least one model
+            rpc_accounting_rollup::Entity::insert_many(models)
+                .exec(&self.db_conn)
+                .await
+                .context("saving stat rollup")?;
+
+            next_window = Some(end);
+        }
+
+        Ok(())
+    }
+
+    /// Roll up the last `REROLL_WINDOWS` windows again if their source rows changed since they were rolled up.
+    async fn reroll_recent(
+        &self,
+        source: RollupSource,
+        period_seconds: u64,
+        next_window: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        // windows with some of their source rows already deleted can't be rolled up again
+        let source_retention_days = match source {
+            RollupSource::Raw => self.config.raw_retention_days,
+            RollupSource::Rollup(x) => self
+                .config
+                .rollups
+                .iter()
+                .find(|rollup| rollup.period_seconds == x)
+                .and_then(|rollup| rollup.retention_days),
+        };
+
+        let source_kept_since = source_retention_days
+            .map(|x| (Utc::now() - chrono::Duration::days(x as i64)).timestamp())
+            .unwrap_or(0);
+
+        for start in reroll_starts(next_window.timestamp(), period_seconds, source_kept_since) {
+            let end = timestamp(start + period_seconds as i64);
+            let start = timestamp(start);
+
+            let totals = self.source_totals(source, start, end).await?;
+
+            let rolled_up: HashMap<_, _> = rpc_accounting_rollup::Entity::find()
+                .filter(rpc_accounting_rollup::Column::ChainId.eq(self.chain_id))
+                .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(period_seconds))
+                .filter(rpc_accounting_rollup::Column::PeriodDatetime.eq(start))
+                .all(&self.db_conn)
+                .await?
+                .into_iter()
+                .map(from_rollup)
+                .collect();
+
+            if totals.is_empty() || totals == rolled_up {
+                continue;
+            }
+
+            info!(
+                "rolling up the {}s window at {} again. its {:?} rows changed",
+                period_seconds, start, source
+            );
+
+            let models = self.models(period_seconds, start, totals);
+
+            let txn = self.db_conn.begin().await?;
+
+            rpc_accounting_rollup::Entity::delete_many()
+                .filter(rpc_accounting_rollup::Column::ChainId.eq(self.chain_id))
+                .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(period_seconds))
+                .filter(rpc_accounting_rollup::Column::PeriodDatetime.eq(start))
+                .exec(&txn)
+                .await?;
+
+            rpc_accounting_rollup::Entity::insert_many(models)
+                .exec(&txn)
+                .await
+                .context("saving stat rollup")?;
+
+            txn.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn source_totals(
+        &self,
+        source: RollupSource,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<HashMap<RollupKey, RollupTotals>> {
+        let mut totals = HashMap::<RollupKey, RollupTotals>::new();
+
+        for (key, x) in self.source_rows(source, start, end).await? {
+            totals.entry(key).or_default().add(&x);
+        }
+
+        Ok(totals)
+    }
+
+    fn models(
+        &self,
+        period_seconds: u64,
+        start: DateTime<Utc>,
+        totals: HashMap<RollupKey, RollupTotals>,
+    ) -> Vec<rpc_accounting_rollup::ActiveModel> {
+        totals
+            .into_iter()
+            .map(|(key, x)| rpc_accounting_rollup::ActiveModel {
+                id: sea_orm::NotSet,
+                rpc_key_id: sea_orm::Set(key.rpc_key_id),
+                chain_id: sea_orm::Set(self.chain_id),
+                period_seconds: sea_orm::Set(period_seconds),
+                period_datetime: sea_orm::Set(start),
+                method: sea_orm::Set(key.method),
+                origin: sea_orm::Set(key.origin),
+                error_response: sea_orm::Set(key.error_response),
+                archive_request: sea_orm::Set(key.archive_request),
+                websocket_request: sea_orm::Set(key.websocket_request),
+                frontend_requests: sea_orm::Set(x.frontend_requests),
+                backend_requests: sea_orm::Set(x.backend_requests),
+                cache_misses: sea_orm::Set(x.cache_misses),
+                cache_hits: sea_orm::Set(x.cache_hits),
+                sum_request_bytes: sea_orm::Set(x.sum_request_bytes),
+                max_request_bytes: sea_orm::Set(x.max_request_bytes),
+                sum_response_millis: sea_orm::Set(x.sum_response_millis),
+                max_response_millis: sea_orm::Set(x.max_response_millis),
+                sum_response_bytes: sea_orm::Set(x.sum_response_bytes),
+                max_response_bytes: sea_orm::Set(x.max_response_bytes),
+                sum_compute_units: sea_orm::Set(x.sum_compute_units),
+            })
+            .collect()
+    }
+
+    /// The start of the first window that has not been rolled up. None if nothing has been rolled up yet.
+    async fn next_window(&self, period_seconds: u64) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let latest = rpc_accounting_rollup::Entity::find()
+            .filter(rpc_accounting_rollup::Column::ChainId.eq(self.chain_id))
+            .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(period_seconds))
+            .order_by_desc(rpc_accounting_rollup::Column::PeriodDatetime)
+            .one(&self.db_conn)
+            .await?;
+
+        Ok(latest.map(|x| x.period_datetime + chrono::Duration::seconds(period_seconds as i64)))
+    }
+
+    async fn first_source_at_or_after(
+        &self,
+        source: RollupSource,
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let first = match source {
+            RollupSource::Raw => rpc_accounting::Entity::find()
+                .filter(rpc_accounting::Column::ChainId.eq(self.chain_id))
+                .filter(rpc_accounting::Column::PeriodDatetime.gte(at))
+                .order_by_asc(rpc_accounting::Column::PeriodDatetime)
+                .one(&self.db_conn)
+                .await?
+                .map(|x| x.period_datetime),
+            RollupSource::Rollup(source_seconds) => rpc_accounting_rollup::Entity::find()
+                .filter(rpc_accounting_rollup::Column::ChainId.eq(self.chain_id))
+                .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(source_seconds))
+                .filter(rpc_accounting_rollup::Column::PeriodDatetime.gte(at))
+                .order_by_asc(rpc_accounting_rollup::Column::PeriodDatetime)
+                .one(&self.db_conn)
+                .await?
+                .map(|x| x.period_datetime),
+        };
+
+        Ok(first)
+    }
+
+    async fn source_rows(
+        &self,
+        source: RollupSource,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<(RollupKey, RollupTotals)>> {
+        let rows = match source {
+            RollupSource::Raw => rpc_accounting::Entity::find()
+                .filter(rpc_accounting::Column::ChainId.eq(self.chain_id))
+                .filter(rpc_accounting::Column::PeriodDatetime.gte(start))
+                .filter(rpc_accounting::Column::PeriodDatetime.lt(end))
+                .all(&self.db_conn)
+                .await?
+                .into_iter()
+                .map(from_raw)
+                .collect(),
+            RollupSource::Rollup(source_seconds) => rpc_accounting_rollup::Entity::find()
+                .filter(rpc_accounting_rollup::Column::ChainId.eq(self.chain_id))
+                .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(source_seconds))
+                .filter(rpc_accounting_rollup::Column::PeriodDatetime.gte(start))
+                .filter(rpc_accounting_rollup::Column::PeriodDatetime.lt(end))
+                .all(&self.db_conn)
+                .await?
+                .into_iter()
+                .map(from_rollup)
+                .collect(),
+        };
+
+        Ok(rows)
+    }
+
+    /// Delete rows past their retention. Rows that the next rollup doesn't have yet are kept.
+    async fn prune(&self) -> anyhow::Result<()> {
+        let mut retentions = vec![(RollupSource::Raw, self.config.raw_retention_days)];
+
+        retentions.extend(
+            self.config
+                .rollups
+                .iter()
+                .map(|x| (RollupSource::Rollup(x.period_seconds), x.retention_days)),
+        );
+
+        for (i, (source, retention_days)) in retentions.iter().enumerate() {
+            let retention_days = match retention_days {
+                None => continue,
+                Some(x) => *x,
+            };
+
+            let mut before = Utc::now() - chrono::Duration::days(retention_days as i64);
+
+            if let Some(next_rollup) = self.config.rollups.get(i) {
+                match self.next_window(next_rollup.period_seconds).await? {
+                    None => continue,
+                    Some(x) => before = before.min(x),
+                }
+            }
+
+            let deleted = match source {
+                RollupSource::Raw => {
+                    rpc_accounting::Entity::delete_many()
+                        .filter(rpc_accounting::Column::ChainId.eq(self.chain_id))
+                        .filter(rpc_accounting::Column::PeriodDatetime.lt(before))
+                        .exec(&self.db_conn)
+                        .await?
+                }
+                RollupSource::Rollup(period_seconds) => {
+                    rpc_accounting_rollup::Entity::delete_many()
+                        .filter(rpc_accounting_rollup::Column::ChainId.eq(self.chain_id))
+                        .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(*period_seconds))
+                        .filter(rpc_accounting_rollup::Column::PeriodDatetime.lt(before))
+                        .exec(&self.db_conn)
+                        .await?
+                }
+            };
+
+            if deleted.rows_affected > 0 {
+                info!(
+                    "deleted {} {:?} stat rows from before {}",
+                    deleted.rows_affected, source, before
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn timestamp(x: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(x, 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows() {
+        assert_eq!(window_start(7_199, 3600), 3600);
+        assert_eq!(window_start(7_200, 3600), 7_200);
+
+        // the last minute of the hour is saved a minute after the hour ends
+        assert!(!window_complete(3600, 3600, 60, 7_200 + 60));
+        assert!(window_complete(
+            3600,
+            3600,
+            60,
+            7_200 + 60 + ROLLUP_DELAY_SECONDS
+        ));
+    }
+
+    #[test]
+    fn test_reroll_starts() {
+        assert_eq!(reroll_starts(36_000, 3600, 0), vec![32_400, 28_800, 25_200]);
+
+        // the oldest window's source rows might be gone
+        assert_eq!(reroll_starts(36_000, 3600, 28_800), vec![32_400, 28_800]);
+
+        assert!(reroll_starts(3600, 3600, 3600).is_empty());
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut totals = RollupTotals::default();
+
+        totals.add(&RollupTotals {
+            frontend_requests: 2,
+            max_response_millis: 50,
+            sum_compute_units: 10,
+            ..Default::default()
+        });
+
+        totals.add(&RollupTotals {
+            frontend_requests: 3,
+            max_response_millis: 20,
+            sum_compute_units: 5,
+            ..Default::default()
+        });
+
+        assert_eq!(totals.frontend_requests, 5);
+        assert_eq!(totals.max_response_millis, 50);
+        assert_eq!(totals.sum_compute_units, 15);
+    }
+}
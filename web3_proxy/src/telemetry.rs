@@ -0,0 +1,97 @@
+//! OpenTelemetry traces exported over OTLP.
+//!
+//! Each frontend request gets a span. Choosing a backend and each backend request are child spans.
+//! Without an `otlp_endpoint`, the global tracer is a no-op and spans cost almost nothing.
+use crate::config::AppConfig;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::sdk::trace::Sampler;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::trace::{Span, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::fmt;
+
+/// The tracer for all of our spans
+pub fn tracer() -> BoxedTracer {
+    global::tracer("web3_proxy")
+}
+
+/// Start a child of the current span. Run the work inside it with `.with_context(cx.clone())`.
+/// The span ends when the last clone of the context is dropped.
+pub fn start_span(name: &'static str, attributes: Vec<KeyValue>) -> Context {
+    let mut span = tracer().start(name);
+
+    for x in attributes {
+        span.set_attribute(x);
+    }
+
+    Context::current_with_span(span)
+}
+
+pub fn set_span_error(cx: &Context, err: &impl fmt::Debug) {
+    cx.span().set_status(Status::error(format!("{:?}", err)));
+}
+
+/// The trace id of `cx`'s span if its trace is exported. Unsampled trace ids would link to nothing
+pub fn sampled_trace_id(cx: &Context) -> Option<String> {
+    let span = cx.span();
+    let span_context = span.span_context();
+
+    if span_context.is_valid() && span_context.is_sampled() {
+        Some(span_context.trace_id().to_string())
+    } else {
+        None
+    }
+}
+
+/// Keep this fraction of traces. Child spans follow their parent so that traces are never partial.
+fn sampler(sample_rate: f64) -> Sampler {
+    let root = if sample_rate >= 1.0 {
+        Sampler::AlwaysOn
+    } else if sample_rate <= 0.0 {
+        Sampler::AlwaysOff
+    } else {
+        Sampler::TraceIdRatioBased(sample_rate)
+    };
+
+    Sampler::ParentBased(Box::new(root))
+}
+
+/// Export spans to the OTLP (grpc) collector at `otlp_endpoint`. This must be called inside the tokio runtime.
+pub fn init_tracing(config: &AppConfig) -> anyhow::Result<()> {
+    let endpoint = match config.otlp_endpoint.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let resource = Resource::new(vec![
+        KeyValue::new("service.name", "web3_proxy"),
+        KeyValue::new("service.instance.id", config.instance_name.clone()),
+        KeyValue::new(
+            "deployment.environment",
+            config.deployment_environment.clone(),
+        ),
+        KeyValue::new("web3_proxy.chain_id", config.chain_id as i64),
+    ]);
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler(config.trace_sample_rate))
+                .with_resource(resource),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    Ok(())
+}
+
+/// Send any buffered spans
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
@@ -1,4 +1,5 @@
 use crate::app::DatabaseReplica;
+use crate::config::StatRollupsConfig;
 use crate::frontend::errors::FrontendErrorResponse;
 use crate::{app::Web3ProxyApp, user_token::UserBearerToken};
 use anyhow::Context;
@@ -9,18 +10,20 @@ use axum::{
     TypedHeader,
 };
 use chrono::{NaiveDateTime, Utc};
-use entities::{login, rpc_accounting, rpc_key};
+use entities::sea_orm_active_enums::Role;
+use entities::{login, org, org_member, rpc_accounting, rpc_accounting_rollup, rpc_key};
 use hashbrown::HashMap;
 use http::StatusCode;
 use log::{debug, warn};
 use migration::sea_orm::{
     ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
-    QuerySelect, Select,
+    QuerySelect, Related, Select,
 };
 use migration::{Condition, Expr, SimpleExpr};
 use redis_rate_limiter::redis;
 use redis_rate_limiter::{redis::AsyncCommands, RedisConnection};
 use serde_json::json;
+use std::str::FromStr;
 
 /// get the attached address for the given bearer token.
 /// First checks redis. Then checks the database.
@@ -85,7 +88,19 @@ pub async fn get_user_id_from_params(
             let user_id: u64 = user_id.parse().context("Parsing user_id param")?;
 
             if bearer_user_id != user_id {
-                return Err(FrontendErrorResponse::AccessDenied);
+                // members of an org can view the stats of the org's user
+                let is_org_member = org::Entity::find()
+                    .filter(org::Column::UserId.eq(user_id))
+                    .inner_join(org_member::Entity)
+                    .filter(org_member::Column::UserId.eq(bearer_user_id))
+                    .one(db_replica.conn())
+                    .await
+                    .context("database error while querying for org members")?
+                    .is_some();
+
+                if !is_org_member {
+                    return Err(FrontendErrorResponse::AccessDenied);
+                }
             }
 
             if save_to_redis {
@@ -93,14 +108,14 @@ pub async fn get_user_id_from_params(
                 const ONE_DAY: usize = 60 * 60 * 24;
 
                 if let Err(err) = redis_conn
-                    .set_ex::<_, _, ()>(user_redis_key, user_id, ONE_DAY)
+                    .set_ex::<_, _, ()>(user_redis_key, bearer_user_id, ONE_DAY)
                     .await
                 {
                     warn!("Unable to save user bearer token to redis: {}", err)
                 }
             }
 
-            Ok(bearer_user_id)
+            Ok(user_id)
         }
         (_, None) => {
             // they have a bearer token. we don't care about it on public pages
@@ -123,7 +138,6 @@ pub async fn get_user_id_from_params(
 /// only allow rpc_key to be set if user_id is also set.
 /// this will keep people from reading someone else's keys.
 /// 0 means none.
-
 pub fn get_rpc_key_id_from_params(
     user_id: u64,
     params: &HashMap<String, String>,
@@ -142,6 +156,14 @@ pub fn get_rpc_key_id_from_params(
     }
 }
 
+/// the org to act for. None means the bearer is acting for themselves.
+pub fn get_org_id_from_params(params: &HashMap<String, String>) -> anyhow::Result<Option<u64>> {
+    params
+        .get("org_id")
+        .map(|x| x.parse().context("parsing org_id param"))
+        .transpose()
+}
+
 pub fn get_chain_id_from_params(
     app: &Web3ProxyApp,
     params: &HashMap<String, String>,
@@ -179,6 +201,22 @@ pub fn get_query_start_from_params(
     )
 }
 
+/// Stats before this are included. Defaults to now.
+pub fn get_query_stop_from_params(
+    params: &HashMap<String, String>,
+) -> anyhow::Result<chrono::NaiveDateTime> {
+    params.get("query_stop").map_or_else(
+        || Ok(chrono::Utc::now().naive_utc()),
+        |x: &String| {
+            let x = x.parse::<i64>().context("parsing query_stop param")?;
+
+            let x = NaiveDateTime::from_timestamp_opt(x, 0).context("parsing query_stop param")?;
+
+            Ok(x)
+        },
+    )
+}
+
 pub fn get_page_from_params(params: &HashMap<String, String>) -> anyhow::Result<u64> {
     params.get("page").map_or_else::<anyhow::Result<u64>, _, _>(
         || {
@@ -217,42 +255,342 @@ pub fn get_query_window_seconds_from_params(
     )
 }
 
-pub fn filter_query_window_seconds(
+/// Group the stats into windows of `query_window_seconds`. 0 aggregates all of them
+pub fn filter_query_window_seconds<E: EntityTrait>(
+    table: &str,
     query_window_seconds: u64,
-    response: &mut HashMap<&str, serde_json::Value>,
-    q: Select<rpc_accounting::Entity>,
-) -> Result<Select<rpc_accounting::Entity>, FrontendErrorResponse> {
+    q: Select<E>,
+) -> Select<E> {
     if query_window_seconds == 0 {
         // TODO: order by more than this?
         // query_window_seconds is not set so we aggregate all records
         // TODO: i am pretty sure we need to filter by something
-        return Ok(q);
+        return q;
     }
 
     // TODO: is there a better way to do this? how can we get "period_datetime" into this with types?
     // TODO: how can we get the first window to start at query_start_timestamp
     let expr = Expr::cust_with_values(
-        "FLOOR(UNIX_TIMESTAMP(rpc_accounting.period_datetime) / ?) * ?",
+        &format!("FLOOR(UNIX_TIMESTAMP({}.period_datetime) / ?) * ?", table),
         [query_window_seconds, query_window_seconds],
     );
 
-    response.insert(
-        "query_window_seconds",
-        serde_json::Value::Number(query_window_seconds.into()),
-    );
-
-    let q = q
-        .column_as(expr, "query_window_timestamp")
+    q.column_as(expr, "query_window_timestamp")
         .group_by(Expr::cust("query_window_timestamp"))
         // TODO: is there a simpler way to order_by?
-        .order_by_asc(SimpleExpr::Custom("query_window_timestamp".to_string()));
+        .order_by_asc(SimpleExpr::Custom("query_window_timestamp".to_string()))
+}
 
-    Ok(q)
+/// Everything about a stats query except the time range
+struct StatFilters<'a> {
+    stat_response_type: StatResponse,
+    query_window_seconds: u64,
+    method: Option<&'a str>,
+    /// 0 = all chains
+    chain_id: u64,
+    /// 0 = all users
+    user_id: u64,
+    rpc_key_id: Option<u64>,
 }
 
+/// `rpc_accounting` and `rpc_accounting_rollup` have the same columns for everything that is summed here
+fn column<E: EntityTrait>(name: &str) -> E::Column
+where
+    E::Column: FromStr,
+{
+    E::Column::from_str(name).unwrap_or_else(|_| panic!("stats tables always have {}", name))
+}
+
+/// The stats query for either `rpc_accounting` or `rpc_accounting_rollup`
+fn stats_select<E>(
+    table: &str,
+    filters: &StatFilters,
+    mut condition: Condition,
+    query_start: NaiveDateTime,
+    query_stop: NaiveDateTime,
+) -> Select<E>
+where
+    E: EntityTrait + Related<rpc_key::Entity>,
+    E::Column: FromStr,
+{
+    let col = column::<E>;
+
+    let mut q = E::find()
+        .select_only()
+        .column_as(col("frontend_requests").sum(), "total_frontend_requests")
+        .column_as(col("backend_requests").sum(), "total_backend_retries")
+        .column_as(col("cache_misses").sum(), "total_cache_misses")
+        .column_as(col("cache_hits").sum(), "total_cache_hits")
+        .column_as(col("sum_request_bytes").sum(), "total_request_bytes")
+        .column_as(col("sum_response_bytes").sum(), "total_response_bytes")
+        .column_as(col("sum_compute_units").sum(), "total_compute_units")
+        .column_as(col("error_response").sum(), "total_error_responses")
+        // total_error_responses counts rows. this counts requests
+        .column_as(
+            Expr::cust(&format!(
+                "SUM(IF({0}.error_response, {0}.frontend_requests, 0))",
+                table
+            )),
+            "total_errors",
+        )
+        .column_as(col("sum_response_millis").sum(), "total_response_millis");
+
+    match filters.stat_response_type {
+        StatResponse::Aggregated => {}
+        StatResponse::Detailed => {
+            // group by the columns that we use as keys in other places of the code
+            q = q
+                .column(col("error_response"))
+                .group_by(col("error_response"))
+                .column(col("method"))
+                .group_by(col("method"))
+                .column(col("archive_request"))
+                .group_by(col("archive_request"))
+                .column(col("websocket_request"))
+                .group_by(col("websocket_request"));
+        }
+        StatResponse::Methods => {
+            // errors are already counted in total_errors
+            q = q.column(col("method")).group_by(col("method"));
+        }
+    }
+
+    q = filter_query_window_seconds(table, filters.query_window_seconds, q);
+
+    condition = condition
+        .add(col("period_datetime").gte(query_start))
+        .add(col("period_datetime").lt(query_stop));
+
+    if let Some(method) = filters.method {
+        condition = condition.add(col("method").eq(method));
+    }
+
+    if filters.chain_id != 0 {
+        condition = condition.add(col("chain_id").eq(filters.chain_id));
+    }
+
+    if filters.user_id != 0 {
+        q = q.left_join(rpc_key::Entity);
+
+        condition = condition.add(rpc_key::Column::UserId.eq(filters.user_id));
+    }
+
+    if let Some(rpc_key_id) = filters.rpc_key_id {
+        condition = condition.add(col("rpc_key_id").eq(rpc_key_id));
+
+        q = q.group_by(col("rpc_key_id"));
+
+        if filters.user_id == 0 {
+            // no user id, we did not join above
+            q = q.left_join(rpc_key::Entity);
+        }
+    }
+
+    q.filter(condition)
+}
+
+/// Where a range of stats is read from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum StatSource {
+    /// the per-minute `rpc_accounting` rows
+    Raw,
+    /// `rpc_accounting_rollup` rows with this period_seconds
+    Rollup(u64),
+}
+
+/// The shortest rollup that still has stats from `query_start`. None if the per-minute rows still have them
+fn pick_rollup(
+    config: &StatRollupsConfig,
+    query_start: NaiveDateTime,
+    now: NaiveDateTime,
+) -> Option<u64> {
+    let kept_since = |retention_days: Option<u64>| {
+        retention_days
+            .map(|x| now - chrono::Duration::days(x as i64))
+            .unwrap_or(NaiveDateTime::MIN)
+    };
+
+    if query_start >= kept_since(config.raw_retention_days) {
+        return None;
+    }
+
+    config
+        .rollups
+        .iter()
+        .find(|x| query_start >= kept_since(x.retention_days))
+        .or_else(|| config.rollups.last())
+        .map(|x| x.period_seconds)
+}
+
+/// Rolled up stats before `rolled_until` and per-minute stats after it
+fn split_stat_range(
+    rollup_period_seconds: u64,
+    rolled_until: NaiveDateTime,
+    query_start: NaiveDateTime,
+    query_stop: NaiveDateTime,
+) -> Vec<(StatSource, NaiveDateTime, NaiveDateTime)> {
+    // not clamp. that panics if the user sent a query_stop before their query_start
+    let split = rolled_until.max(query_start).min(query_stop);
+
+    [
+        (
+            StatSource::Rollup(rollup_period_seconds),
+            query_start,
+            split,
+        ),
+        (StatSource::Raw, split, query_stop),
+    ]
+    .into_iter()
+    .filter(|(_, start, stop)| start < stop)
+    .collect()
+}
+
+/// Per-minute stats are deleted after `raw_retention_days`. Older ranges are read from the rollups.
+async fn stat_sources(
+    app: &Web3ProxyApp,
+    db_replica: &DatabaseReplica,
+    chain_id: u64,
+    query_start: NaiveDateTime,
+    query_stop: NaiveDateTime,
+) -> anyhow::Result<Vec<(StatSource, NaiveDateTime, NaiveDateTime)>> {
+    let raw = vec![(StatSource::Raw, query_start, query_stop)];
+
+    let rollup_period_seconds = match app
+        .config
+        .stat_rollups
+        .as_ref()
+        .and_then(|x| pick_rollup(x, query_start, Utc::now().naive_utc()))
+    {
+        None => return Ok(raw),
+        Some(x) => x,
+    };
+
+    let mut latest = rpc_accounting_rollup::Entity::find()
+        .filter(rpc_accounting_rollup::Column::PeriodSeconds.eq(rollup_period_seconds));
+
+    if chain_id != 0 {
+        latest = latest.filter(rpc_accounting_rollup::Column::ChainId.eq(chain_id));
+    }
+
+    let latest = latest
+        .order_by_desc(rpc_accounting_rollup::Column::PeriodDatetime)
+        .one(db_replica.conn())
+        .await?;
+
+    // per-minute rows are only deleted once they are rolled up. if nothing is rolled up, they are all still there
+    let rolled_until = match latest {
+        None => return Ok(raw),
+        Some(x) => {
+            x.period_datetime.naive_utc() + chrono::Duration::seconds(rollup_period_seconds as i64)
+        }
+    };
+
+    Ok(split_stat_range(
+        rollup_period_seconds,
+        rolled_until,
+        query_start,
+        query_stop,
+    ))
+}
+
+/// Add up rows from different sources that are for the same group. Only the totals are added
+fn merge_stat_rows(rows: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+    let mut merged: Vec<serde_json::Value> = vec![];
+    let mut groups = HashMap::<String, usize>::new();
+
+    for row in rows {
+        let group = match row.as_object() {
+            None => continue,
+            Some(x) => x
+                .iter()
+                .filter(|(k, _)| !k.starts_with("total_"))
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+
+        let existing = match groups.get(&group) {
+            None => {
+                groups.insert(group, merged.len());
+                merged.push(row);
+                continue;
+            }
+            Some(x) => &mut merged[*x],
+        };
+
+        for (k, v) in row.as_object().into_iter().flatten() {
+            if !k.starts_with("total_") {
+                continue;
+            }
+
+            let sum = json_f64(existing.get(k)).unwrap_or_default()
+                + json_f64(Some(v)).unwrap_or_default();
+
+            // sums of integer columns stay integers
+            let sum = if sum.fract() == 0.0 && sum >= 0.0 && sum < u64::MAX as f64 {
+                json!(sum as u64)
+            } else {
+                json!(sum)
+            };
+
+            existing[k] = sum;
+        }
+    }
+
+    // each source was already sorted
+    merged.sort_by(|a, b| {
+        let a = json_f64(a.get("query_window_timestamp")).unwrap_or_default();
+        let b = json_f64(b.get("query_window_timestamp")).unwrap_or_default();
+
+        a.total_cmp(&b)
+    });
+
+    merged
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum StatResponse {
     Aggregated,
     Detailed,
+    /// one row per method
+    Methods,
+}
+
+/// Sums come back as numbers or as decimal strings depending on the database.
+fn json_f64(x: Option<&serde_json::Value>) -> Option<f64> {
+    match x? {
+        serde_json::Value::Number(x) => x.as_f64(),
+        serde_json::Value::String(x) => x.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Add `cache_hit_rate` and `error_rate` to a row of totals. Rows without requests get null.
+fn add_stat_rates(row: &mut serde_json::Value) {
+    let frontend_requests = json_f64(row.get("total_frontend_requests")).unwrap_or_default();
+    let cache_hits = json_f64(row.get("total_cache_hits")).unwrap_or_default();
+    let cache_misses = json_f64(row.get("total_cache_misses")).unwrap_or_default();
+    let errors = json_f64(row.get("total_errors")).unwrap_or_default();
+
+    let row = match row.as_object_mut() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let cache_hit_rate = if cache_hits + cache_misses > 0.0 {
+        json!(cache_hits / (cache_hits + cache_misses))
+    } else {
+        serde_json::Value::Null
+    };
+
+    let error_rate = if frontend_requests > 0.0 {
+        json!(errors / frontend_requests)
+    } else {
+        serde_json::Value::Null
+    };
+
+    row.insert("cache_hit_rate".to_string(), cache_hit_rate);
+    row.insert("error_rate".to_string(), error_rate);
 }
 
 pub async fn query_user_stats<'a>(
@@ -272,12 +610,27 @@ pub async fn query_user_stats<'a>(
         .context("query_user_stats needs a redis")?;
 
     // get the user id first. if it is 0, we should use a cache on the app
-    let user_id =
-        get_user_id_from_params(&mut redis_conn, &db_conn, &db_replica, bearer, params).await?;
+    let user_id = match get_org_id_from_params(params)? {
+        Some(org_id) => {
+            // any member of the org can see its stats
+            let TypedHeader(Authorization(bearer)) =
+                bearer.ok_or(FrontendErrorResponse::AccessDenied)?;
+
+            let (_caller, org_user, _semaphore) = app
+                .bearer_is_authorized_for_org(bearer, Some(org_id), Role::Viewer)
+                .await?;
+
+            org_user.id
+        }
+        None => {
+            get_user_id_from_params(&mut redis_conn, &db_conn, &db_replica, bearer, params).await?
+        }
+    };
     // get the query window seconds now so that we can pick a cache with a good TTL
     // TODO: for now though, just do one cache. its easier
     let query_window_seconds = get_query_window_seconds_from_params(params)?;
     let query_start = get_query_start_from_params(params)?;
+    let query_stop = get_query_stop_from_params(params)?;
     let chain_id = get_chain_id_from_params(app, params)?;
     let page = get_page_from_params(params)?;
 
@@ -289,9 +642,20 @@ pub async fn query_user_stats<'a>(
             None
         } else {
             // TODO: is this a good key?
+            // the default query_stop is "now". only an explicit query_stop goes in the key or the cache would never hit
             let redis_cache_key = format!(
-                "query_user_stats:{}:{}:{}:{}:{}",
-                chain_id, user_id, query_start, query_window_seconds, page,
+                "query_user_stats:{:?}:{}:{}:{}:{}:{}:{}:{}",
+                stat_response_type,
+                chain_id,
+                user_id,
+                query_start,
+                params
+                    .get("query_stop")
+                    .map(|x| x.as_str())
+                    .unwrap_or_default(),
+                query_window_seconds,
+                page,
+                params.get("method").map(|x| x.as_str()).unwrap_or_default(),
             );
 
             let cached_result: Result<(String, u64), _> = redis::pipe()
@@ -329,110 +693,67 @@ pub async fn query_user_stats<'a>(
 
     let mut response_body = HashMap::new();
 
-    let mut q = rpc_accounting::Entity::find()
-        .select_only()
-        .column_as(
-            rpc_accounting::Column::FrontendRequests.sum(),
-            "total_frontend_requests",
-        )
-        .column_as(
-            rpc_accounting::Column::BackendRequests.sum(),
-            "total_backend_retries",
-        )
-        .column_as(
-            rpc_accounting::Column::CacheMisses.sum(),
-            "total_cache_misses",
-        )
-        .column_as(rpc_accounting::Column::CacheHits.sum(), "total_cache_hits")
-        .column_as(
-            rpc_accounting::Column::SumResponseBytes.sum(),
-            "total_response_bytes",
-        )
-        .column_as(
-            rpc_accounting::Column::ErrorResponse.sum(),
-            "total_error_responses",
-        )
-        .column_as(
-            rpc_accounting::Column::SumResponseMillis.sum(),
-            "total_response_millis",
+    if query_window_seconds > 0 {
+        response_body.insert(
+            "query_window_seconds",
+            serde_json::Value::Number(query_window_seconds.into()),
         );
-
-    // TODO: make this and q mutable and clean up the code below. no need for more `let q`
-    let mut condition = Condition::all();
-
-    if let StatResponse::Detailed = stat_response_type {
-        // group by the columns that we use as keys in other places of the code
-        q = q
-            .column(rpc_accounting::Column::ErrorResponse)
-            .group_by(rpc_accounting::Column::ErrorResponse)
-            .column(rpc_accounting::Column::Method)
-            .group_by(rpc_accounting::Column::Method)
-            .column(rpc_accounting::Column::ArchiveRequest)
-            .group_by(rpc_accounting::Column::ArchiveRequest);
     }
 
-    // TODO: have q be &mut?
-    q = filter_query_window_seconds(query_window_seconds, &mut response_body, q)?;
-
     // aggregate stats after query_start
     // TODO: maximum query_start of 90 days ago?
-    // TODO: if no query_start, don't add to response or condition
     response_body.insert(
         "query_start",
         serde_json::Value::Number(query_start.timestamp().into()),
     );
-    condition = condition.add(rpc_accounting::Column::PeriodDatetime.gte(query_start));
+    response_body.insert(
+        "query_stop",
+        serde_json::Value::Number(query_stop.timestamp().into()),
+    );
 
-    if chain_id == 0 {
-        // fetch all the chains
-    } else {
-        // filter on chain_id
-        condition = condition.add(rpc_accounting::Column::ChainId.eq(chain_id));
+    // methods are only saved for users that opted in. see `log_level`
+    let method = params.get("method").map(|x| x.as_str());
 
-        response_body.insert("chain_id", serde_json::Value::Number(chain_id.into()));
+    if let Some(method) = method {
+        response_body.insert("method", json!(method));
     }
 
-    if user_id == 0 {
-        // 0 means everyone. don't filter on user
-    } else {
-        q = q.left_join(rpc_key::Entity);
-
-        condition = condition.add(rpc_key::Column::UserId.eq(user_id));
+    if chain_id != 0 {
+        response_body.insert("chain_id", serde_json::Value::Number(chain_id.into()));
+    }
 
+    if user_id != 0 {
         response_body.insert("user_id", serde_json::Value::Number(user_id.into()));
     }
 
     // filter on rpc_key_id
     // if rpc_key_id, all the requests without a key will be loaded
     // TODO: move getting the param and checking the bearer token into a helper function
-    if let Some(rpc_key_id) = params.get("rpc_key_id") {
-        let rpc_key_id = rpc_key_id.parse::<u64>().map_err(|e| {
-            FrontendErrorResponse::StatusCode(
-                StatusCode::BAD_REQUEST,
-                "Unable to parse rpc_key_id".to_string(),
-                Some(e.into()),
-            )
-        })?;
+    let rpc_key_id = params
+        .get("rpc_key_id")
+        .map(|x| {
+            x.parse::<u64>().map_err(|e| {
+                FrontendErrorResponse::StatusCode(
+                    StatusCode::BAD_REQUEST,
+                    "Unable to parse rpc_key_id".to_string(),
+                    Some(e.into()),
+                )
+            })
+        })
+        .transpose()?;
 
+    if let Some(rpc_key_id) = rpc_key_id {
         response_body.insert("rpc_key_id", serde_json::Value::Number(rpc_key_id.into()));
-
-        condition = condition.add(rpc_accounting::Column::RpcKeyId.eq(rpc_key_id));
-
-        q = q.group_by(rpc_accounting::Column::RpcKeyId);
-
-        if user_id == 0 {
-            // no user id, we did not join above
-            q = q.left_join(rpc_key::Entity);
-        } else {
-            // user_id added a join on rpc_key already. only filter on user_id
-            condition = condition.add(rpc_key::Column::UserId.eq(user_id));
-        }
     }
 
-    // now that all the conditions are set up. add them to the query
-    q = q.filter(condition);
-
-    // TODO: trace log query here? i think sea orm has a useful log level for this
+    let filters = StatFilters {
+        stat_response_type,
+        query_window_seconds,
+        method,
+        chain_id,
+        user_id,
+        rpc_key_id,
+    };
 
     // set up pagination
     response_body.insert("page", serde_json::Value::Number(page.into()));
@@ -441,22 +762,84 @@ pub async fn query_user_stats<'a>(
     let page_size = 1_000;
     response_body.insert("page_size", serde_json::Value::Number(page_size.into()));
 
-    // query the database for number of items and pages
-    let pages_result = q
-        .clone()
-        .paginate(db_replica.conn(), page_size)
-        .num_items_and_pages()
-        .await?;
+    let sources = stat_sources(app, &db_replica, chain_id, query_start, query_stop).await?;
 
-    response_body.insert("num_items", pages_result.number_of_items.into());
-    response_body.insert("num_pages", pages_result.number_of_pages.into());
+    let mut query_response = match sources[..] {
+        [(StatSource::Raw, start, stop)] => {
+            let q = stats_select::<rpc_accounting::Entity>(
+                "rpc_accounting",
+                &filters,
+                Condition::all(),
+                start,
+                stop,
+            );
 
-    // query the database (todo: combine with the pages_result query?)
-    let query_response = q
-        .into_json()
-        .paginate(db_replica.conn(), page_size)
-        .fetch_page(page)
-        .await?;
+            // query the database for number of items and pages
+            let pages_result = q
+                .clone()
+                .paginate(db_replica.conn(), page_size)
+                .num_items_and_pages()
+                .await?;
+
+            response_body.insert("num_items", pages_result.number_of_items.into());
+            response_body.insert("num_pages", pages_result.number_of_pages.into());
+
+            // query the database (todo: combine with the pages_result query?)
+            q.into_json()
+                .paginate(db_replica.conn(), page_size)
+                .fetch_page(page)
+                .await?
+        }
+        _ => {
+            // old stats are only in the rollups. the sources are combined here instead of in the database
+            let mut rows = vec![];
+
+            for (source, start, stop) in sources {
+                let q = match source {
+                    StatSource::Raw => stats_select::<rpc_accounting::Entity>(
+                        "rpc_accounting",
+                        &filters,
+                        Condition::all(),
+                        start,
+                        stop,
+                    )
+                    .into_json()
+                    .all(db_replica.conn()),
+                    StatSource::Rollup(period_seconds) => {
+                        response_body.insert("rollup_period_seconds", period_seconds.into());
+
+                        stats_select::<rpc_accounting_rollup::Entity>(
+                            "rpc_accounting_rollup",
+                            &filters,
+                            Condition::all().add(
+                                rpc_accounting_rollup::Column::PeriodSeconds.eq(period_seconds),
+                            ),
+                            start,
+                            stop,
+                        )
+                        .into_json()
+                        .all(db_replica.conn())
+                    }
+                };
+
+                rows.extend(q.await?);
+            }
+
+            let rows = merge_stat_rows(rows);
+
+            let num_items = rows.len() as u64;
+
+            response_body.insert("num_items", num_items.into());
+            response_body.insert("num_pages", num_items.div_ceil(page_size).into());
+
+            rows.into_iter()
+                .skip((page * page_size) as usize)
+                .take(page_size as usize)
+                .collect()
+        }
+    };
+
+    query_response.iter_mut().for_each(add_stat_rates);
 
     // TODO: be a lot smart about caching
     let ttl = 60;
@@ -497,3 +880,94 @@ pub async fn query_user_stats<'a>(
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StatRollupConfig;
+
+    #[test]
+    fn test_add_stat_rates() {
+        let mut row = json!({
+            "total_frontend_requests": 10,
+            "total_cache_hits": "3",
+            "total_cache_misses": 1,
+            "total_errors": 2,
+        });
+
+        add_stat_rates(&mut row);
+
+        assert_eq!(row["cache_hit_rate"], json!(0.75));
+        assert_eq!(row["error_rate"], json!(0.2));
+
+        let mut row = json!({});
+
+        add_stat_rates(&mut row);
+
+        assert!(row["cache_hit_rate"].is_null());
+        assert!(row["error_rate"].is_null());
+    }
+
+    fn at(timestamp: i64) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn test_stat_sources() {
+        let config = StatRollupsConfig {
+            raw_retention_days: Some(30),
+            rollups: vec![
+                StatRollupConfig {
+                    period_seconds: 3600,
+                    retention_days: Some(365),
+                },
+                StatRollupConfig {
+                    period_seconds: 86400,
+                    retention_days: None,
+                },
+            ],
+        };
+
+        let day = 86_400;
+        let now = at(1_000 * day);
+
+        assert_eq!(pick_rollup(&config, at(990 * day), now), None);
+        assert_eq!(pick_rollup(&config, at(900 * day), now), Some(3600));
+        assert_eq!(pick_rollup(&config, at(day), now), Some(86400));
+
+        // rolled up stats until the last hour. per-minute stats after that
+        assert_eq!(
+            split_stat_range(3600, at(999 * day), at(900 * day), now),
+            vec![
+                (StatSource::Rollup(3600), at(900 * day), at(999 * day)),
+                (StatSource::Raw, at(999 * day), now),
+            ]
+        );
+
+        assert_eq!(
+            split_stat_range(3600, at(999 * day), at(900 * day), at(950 * day)),
+            vec![(StatSource::Rollup(3600), at(900 * day), at(950 * day))]
+        );
+
+        // a backwards range doesn't panic
+        assert!(split_stat_range(3600, at(999 * day), now, at(900 * day)).is_empty());
+    }
+
+    #[test]
+    fn test_merge_stat_rows() {
+        let rows = vec![
+            json!({"query_window_timestamp": 7200, "method": "eth_call", "total_frontend_requests": "5"}),
+            json!({"query_window_timestamp": 3600, "method": "eth_call", "total_frontend_requests": 1}),
+            json!({"query_window_timestamp": 7200, "method": "eth_call", "total_frontend_requests": 2}),
+            json!({"query_window_timestamp": 7200, "method": "eth_getLogs", "total_frontend_requests": 3}),
+        ];
+
+        let merged = merge_stat_rows(rows);
+
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0]["query_window_timestamp"], json!(3600));
+        assert_eq!(merged[1]["method"], json!("eth_call"));
+        assert_eq!(merged[1]["total_frontend_requests"], json!(7));
+        assert_eq!(merged[2]["total_frontend_requests"], json!(3));
+    }
+}